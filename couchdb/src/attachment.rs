@@ -0,0 +1,268 @@
+//! Binary attachments of documents, like user avatars.
+//!
+//! Attachments are read and written apart from their document, with the revision of the
+//! document passed in the `If-Match` header. Their size is checked against a limit before they
+//! are sent or buffered, and large ones can be streamed instead of held in memory.
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Response;
+
+use crate::error::Error;
+use crate::Result;
+
+/// Largest attachment written or buffered by default, in bytes
+pub const DEFAULT_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Content type of attachments CouchDB does not report one for
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Fails if the attachment is over the limit, before it is sent or read
+pub(crate) fn check_size(name: &str, size: u64, limit: usize) -> Result<()> {
+    if size > limit as u64 {
+        return Err(Error::too_large(format!(
+            "attachment {} of {} bytes exceeds the limit of {} bytes",
+            name, size, limit
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn content_type(res: &Response) -> String {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use bytes::Bytes;
+    use futures::{stream, TryStreamExt};
+    use url::Url;
+
+    use crate::Couch;
+
+    use super::*;
+
+    type Attachments = Arc<Mutex<HashMap<String, (String, Vec<u8>)>>>;
+
+    /// Serves the attachment endpoints of CouchDB from memory, for documents at revision `1-a`
+    fn serve_attachments() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let attachments = Attachments::default();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let attachments = attachments.clone();
+                thread::spawn(move || serve(conn.unwrap(), attachments));
+            }
+        });
+        Url::parse(&url).unwrap()
+    }
+
+    fn serve(conn: TcpStream, attachments: Attachments) {
+        let mut reader = BufReader::new(conn.try_clone().unwrap());
+        let mut conn = conn;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_string();
+            let path = parts.next().unwrap().to_string();
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                let (name, value) = header.split_at(header.find(':').unwrap());
+                headers.insert(name.to_lowercase(), value[1..].trim().to_string());
+            }
+            let length = headers
+                .get("content-length")
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut attachments = attachments.lock().unwrap();
+            let rev_matches = headers.get("if-match").map(String::as_str) == Some("1-a");
+            let (status, content_type, body) = match method.as_str() {
+                "PUT" if rev_matches => {
+                    let content_type = headers["content-type"].clone();
+                    attachments.insert(path, (content_type, body));
+                    (201, "application/json".to_string(), ok("2-b"))
+                }
+                "GET" => match attachments.get(&path) {
+                    Some((content_type, data)) => (200, content_type.clone(), data.clone()),
+                    None => (404, "application/json".to_string(), missing()),
+                },
+                "DELETE" if rev_matches => match attachments.remove(&path) {
+                    Some(_) => (200, "application/json".to_string(), ok("3-c")),
+                    None => (404, "application/json".to_string(), missing()),
+                },
+                _ => (409, "application/json".to_string(), conflict()),
+            };
+            write!(
+                conn,
+                "HTTP/1.1 {} Status\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                status,
+                content_type,
+                body.len()
+            )
+            .unwrap();
+            conn.write_all(&body).unwrap();
+        }
+    }
+
+    fn ok(rev: &str) -> Vec<u8> {
+        format!(r#"{{"ok":true,"id":"user:jdoe","rev":"{}"}}"#, rev).into_bytes()
+    }
+
+    fn missing() -> Vec<u8> {
+        br#"{"error":"not_found","reason":"Document is missing attachment"}"#.to_vec()
+    }
+
+    fn conflict() -> Vec<u8> {
+        br#"{"error":"conflict","reason":"Document update conflict."}"#.to_vec()
+    }
+
+    /// Reproducible noise, which would reveal shifted or truncated content
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn couch(limit: usize) -> Couch {
+        let mut couch = Couch::new(
+            serve_attachments(),
+            "enseada".to_string(),
+            "enseada".to_string(),
+        );
+        couch.set_attachment_limit(limit);
+        couch
+    }
+
+    #[test]
+    fn it_rejects_attachments_over_the_limit() {
+        assert!(check_size("avatar", 1024, 1024).is_ok());
+        let err = check_size("avatar", 1025, 1024).unwrap_err();
+        assert!(err.is_too_large());
+
+        let db = couch(1024).database("avatars", true);
+        let res = runtime().block_on(db.put_attachment(
+            "user:jdoe",
+            "1-a",
+            "avatar.png",
+            "image/png",
+            Bytes::from(random_bytes(1025)),
+        ));
+        assert!(res.unwrap_err().is_too_large());
+    }
+
+    #[test]
+    fn it_round_trips_attachments() {
+        let data = random_bytes(3 * 1024 * 1024);
+        let db = couch(4 * 1024 * 1024).database("avatars", true);
+        runtime().block_on(async {
+            let res = db
+                .put_attachment(
+                    "user:jdoe",
+                    "1-a",
+                    "avatar.png",
+                    "image/png",
+                    Bytes::from(data.clone()),
+                )
+                .await
+                .unwrap();
+            assert_eq!(res.rev, "2-b");
+
+            let (content_type, fetched) =
+                db.get_attachment("user:jdoe", "avatar.png").await.unwrap();
+            assert_eq!(content_type, "image/png");
+            assert!(fetched == data, "attachment corrupted");
+        });
+    }
+
+    #[test]
+    fn it_streams_attachments() {
+        let data = random_bytes(5 * 1024 * 1024);
+        let db = couch(8 * 1024 * 1024).database("avatars", true);
+        runtime().block_on(async {
+            let chunks: Vec<std::io::Result<Bytes>> = data
+                .chunks(64 * 1024)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect();
+            db.put_attachment_stream(
+                "user:jdoe",
+                "1-a",
+                "backup.bin",
+                DEFAULT_CONTENT_TYPE,
+                data.len() as u64,
+                stream::iter(chunks),
+            )
+            .await
+            .unwrap();
+
+            let (content_type, content) = db
+                .stream_attachment("user:jdoe", "backup.bin")
+                .await
+                .unwrap();
+            let streamed = content
+                .try_fold(Vec::new(), |mut streamed, chunk| async move {
+                    streamed.extend_from_slice(&chunk);
+                    Ok(streamed)
+                })
+                .await
+                .unwrap();
+            assert_eq!(content_type, DEFAULT_CONTENT_TYPE);
+            assert!(streamed == data, "attachment corrupted");
+        });
+    }
+
+    #[test]
+    fn it_passes_the_revision_when_deleting() {
+        let db = couch(DEFAULT_LIMIT).database("avatars", true);
+        runtime().block_on(async {
+            let data = Bytes::from_static(b"avatar");
+            db.put_attachment("user:jdoe", "1-a", "avatar.png", "image/png", data)
+                .await
+                .unwrap();
+
+            let stale = db.delete_attachment("user:jdoe", "0-z", "avatar.png").await;
+            assert!(matches!(stale, Err(Error::Conflict(_))));
+            let res = db
+                .delete_attachment("user:jdoe", "1-a", "avatar.png")
+                .await
+                .unwrap();
+            assert_eq!(res.rev, "3-c");
+
+            let gone = db.get_attachment("user:jdoe", "avatar.png").await;
+            assert!(matches!(gone, Err(Error::NotFound(_))));
+        });
+    }
+}
@@ -1,5 +1,24 @@
+//! Continuous changes feeds.
+//!
+//! Events of a feed are delivered in the order CouchDB sequences them. A dropped connection is
+//! resumed from the sequence of the last event received, so delivery is at-least-once: events
+//! may be replayed after a reconnection, and consumers must be idempotent.
+use std::pin::Pin;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream, Stream, StreamExt};
 use serde::Deserialize;
 
+use crate::error::Error;
+use crate::Result;
+
+/// How often CouchDB writes an empty line to keep an idle feed open, in milliseconds
+pub(crate) const HEARTBEAT_MILLIS: u64 = 30_000;
+
+/// How long to wait before reconnecting a dropped feed
+pub(crate) const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum ChangeEvent {
@@ -15,7 +34,198 @@ pub enum ChangeEvent {
     },
 }
 
+impl ChangeEvent {
+    /// Sequence of the event, which a feed can be resumed from
+    pub fn seq(&self) -> &str {
+        match self {
+            ChangeEvent::Next { seq, .. } => seq,
+            ChangeEvent::End { last_seq, .. } => last_seq,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Change {
     rev: String,
-}
\ No newline at end of file
+}
+
+impl Change {
+    pub fn rev(&self) -> &str {
+        &self.rev
+    }
+}
+
+pub(crate) type Events = Pin<Box<dyn Stream<Item = Result<ChangeEvent>> + Send>>;
+
+/// Parses the events of a continuous feed out of its chunked body.
+///
+/// Events can span chunks and chunks can hold many events, so lines are buffered until
+/// complete. Heartbeats, empty lines, are skipped, and so are lines that are not events.
+pub fn parse_events<S>(chunks: S) -> impl Stream<Item = Result<ChangeEvent>>
+where
+    S: Stream<Item = reqwest::Result<Bytes>>,
+{
+    chunks
+        .scan(BytesMut::new(), |buf, chunk| {
+            let events = match chunk {
+                Ok(chunk) => {
+                    buf.extend_from_slice(&chunk);
+                    let mut events = Vec::new();
+                    while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                        let line = buf.split_to(pos + 1);
+                        if let Some(event) = parse_line(&line[..pos]) {
+                            events.push(Ok(event));
+                        }
+                    }
+                    events
+                }
+                Err(err) => vec![Err(Error::from(err))],
+            };
+            futures::future::ready(Some(stream::iter(events)))
+        })
+        .flatten()
+}
+
+fn parse_line(line: &[u8]) -> Option<ChangeEvent> {
+    let line = std::str::from_utf8(line).ok()?.trim();
+    if line.is_empty() {
+        return None;
+    }
+    log::trace!("Processing change event: {}", line);
+    match serde_json::from_str(line) {
+        Ok(event) => Some(event),
+        Err(err) => {
+            log::warn!("Skipping malformed change event: {}", err);
+            None
+        }
+    }
+}
+
+struct Feed<C> {
+    connect: C,
+    delay: Duration,
+    since: String,
+    events: Option<Events>,
+    reconnecting: bool,
+}
+
+/// Follows a feed forever, connecting again from the last sequence, after a delay, whenever
+/// it drops
+pub(crate) fn follow<C, F>(
+    since: Option<String>,
+    delay: Duration,
+    connect: C,
+) -> impl Stream<Item = ChangeEvent>
+where
+    C: Fn(String) -> F,
+    F: std::future::Future<Output = Result<Events>>,
+{
+    let feed = Feed {
+        connect,
+        delay,
+        since: since.unwrap_or_else(|| "now".to_string()),
+        events: None,
+        reconnecting: false,
+    };
+    stream::unfold(feed, |mut feed| async move {
+        loop {
+            if feed.events.is_none() {
+                if feed.reconnecting {
+                    tokio::time::delay_for(feed.delay).await;
+                }
+                match (feed.connect)(feed.since.clone()).await {
+                    Ok(events) => feed.events = Some(events),
+                    Err(err) => {
+                        log::warn!("Could not connect to the changes feed: {}", err);
+                        feed.reconnecting = true;
+                        continue;
+                    }
+                }
+            }
+            let events = feed.events.as_mut().expect("connected feed");
+            match events.next().await {
+                Some(Ok(event)) => {
+                    feed.since = event.seq().to_string();
+                    feed.reconnecting = false;
+                    return Some((event, feed));
+                }
+                Some(Err(err)) => log::warn!("Changes feed dropped: {}", err),
+                None => log::debug!("Changes feed ended, reconnecting from {}", &feed.since),
+            }
+            feed.events = None;
+            feed.reconnecting = true;
+        }
+    })
+}
+
+pub(crate) fn boxed<S>(events: S) -> Events
+where
+    S: Stream<Item = Result<ChangeEvent>> + Send + 'static,
+{
+    Box::pin(events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_events_across_chunks_and_heartbeats() {
+        let chunks: Vec<reqwest::Result<Bytes>> = vec![
+            Ok(Bytes::from_static(b"{\"seq\":\"1-a\",\"id\":\"user:jdoe\",")),
+            Ok(Bytes::from_static(
+                b"\"changes\":[{\"rev\":\"1-x\"}]}\n\n{\"seq\":\"2-b\",\"id\":\"user:",
+            )),
+            Ok(Bytes::from_static(b"jane\",\"changes\":[{\"rev\":\"2-y\"}],\"deleted\":true}\n\n")),
+            Ok(Bytes::from_static(b"\n{\"last_seq\":\"2-b\",\"pending\":0}\n")),
+        ];
+        let events: Vec<ChangeEvent> = futures::executor::block_on(
+            parse_events(stream::iter(chunks))
+                .map(|event| event.unwrap())
+                .collect(),
+        );
+
+        assert_eq!(events.len(), 3);
+        match &events[0] {
+            ChangeEvent::Next { id, changes, deleted, .. } => {
+                assert_eq!(id, "user:jdoe");
+                assert_eq!(changes[0].rev(), "1-x");
+                assert_eq!(deleted, &None);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        match &events[1] {
+            ChangeEvent::Next { id, deleted, .. } => {
+                assert_eq!(id, "user:jane");
+                assert_eq!(deleted, &Some(true));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(events[2].seq(), "2-b");
+    }
+
+    #[test]
+    fn it_reconnects_from_the_last_seq() {
+        let connections = std::sync::Mutex::new(Vec::new());
+        let feed = follow(None, Duration::from_millis(1), |since: String| {
+            connections.lock().unwrap().push(since.clone());
+            let chunk = format!(
+                "{{\"seq\":\"{}1\",\"id\":\"doc\",\"changes\":[{{\"rev\":\"1-x\"}}]}}\n",
+                since
+            );
+            let chunks: Vec<reqwest::Result<Bytes>> = vec![Ok(Bytes::from(chunk))];
+            futures::future::ready(Ok(boxed(parse_events(stream::iter(chunks)))))
+        });
+
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        let events: Vec<ChangeEvent> = rt.block_on(feed.take(2).collect());
+
+        assert_eq!(events[0].seq(), "now1");
+        assert_eq!(events[1].seq(), "now11");
+        assert_eq!(*connections.lock().unwrap(), vec!["now", "now1"]);
+    }
+}
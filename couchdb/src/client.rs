@@ -1,12 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
 use bytes::Bytes;
 use derivative::Derivative;
-use reqwest::{Client as HttpClient, Method, RequestBuilder, StatusCode};
+use futures::lock::Mutex;
+use reqwest::header::{HeaderMap, COOKIE, SET_COOKIE};
+use reqwest::{Client as HttpClient, Method, RequestBuilder, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use url::{ParseError, Url};
 
+use crate::error::Error;
 use crate::responses::Ok;
 
+/// Timeouts of the requests to CouchDB. A request timing out fails with [`Error::Timeout`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time to establish a connection
+    pub connect: Duration,
+    /// Time for a whole request, from connecting to reading the response
+    pub request: Duration,
+    /// Time for long-running requests, like following the changes feed or running migrations
+    pub long: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(5),
+            request: Duration::from_secs(30),
+            long: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Connections kept open to CouchDB. The defaults suit a single CouchDB node, for which idle
+/// connections are cheap to keep and costly to open again under load.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pool {
+    /// Idle connections kept open, at least one
+    pub max_idle_per_host: usize,
+    /// Time an idle connection is kept open
+    pub idle_timeout: Duration,
+    /// Interval of TCP keep-alive probes on open connections, none to disable them
+    pub tcp_keepalive: Option<Duration>,
+    /// Whether to speak HTTP/2 right away. CouchDB only speaks HTTP/1.1, so this is only for
+    /// a proxy in front of it speaking HTTP/2.
+    pub http2: bool,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool {
+            max_idle_per_host: 32,
+            idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            http2: false,
+        }
+    }
+}
+
+/// How requests authenticate to CouchDB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// A session cookie, opened once at `/_session` and opened again when it expires. CouchDB
+    /// hashes the password with a deliberately slow function, so it only does it on login.
+    /// Falls back to basic authentication if CouchDB does not open sessions.
+    Session,
+    /// Basic authentication on every request, mostly useful for debugging
+    Basic,
+}
+
+/// Session cookie shared by the clones of a client
+struct Session {
+    cookie: RwLock<Option<String>>,
+    /// Held while logging in, so that a single request logs in at a time
+    login: Mutex<()>,
+    /// Set once CouchDB refused to open a session
+    unsupported: AtomicBool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            cookie: RwLock::new(None),
+            login: Mutex::new(()),
+            unsupported: AtomicBool::new(false),
+        }
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Debug, Clone)]
 pub(super) struct Client {
@@ -15,23 +99,61 @@ pub(super) struct Client {
     username: String,
     #[derivative(Debug = "ignore")]
     password: Option<String>,
+    timeouts: Timeouts,
+    /// Whether every request is long-running
+    long_running: bool,
+    auth: AuthMethod,
+    #[derivative(Debug = "ignore")]
+    session: Arc<Session>,
 }
 
 impl Client {
-    pub fn new(base_url: Url, username: String, password: String) -> Client {
-        let client = HttpClient::builder()
+    pub fn new(
+        base_url: Url,
+        username: String,
+        password: String,
+        timeouts: Timeouts,
+        pool: Pool,
+    ) -> Client {
+        let builder = HttpClient::builder()
             .use_rustls_tls()
-            .build()
-            .expect("HttpClient::build()");
+            .connect_timeout(timeouts.connect)
+            .pool_max_idle_per_host(pool.max_idle_per_host)
+            .pool_idle_timeout(pool.idle_timeout)
+            .tcp_keepalive(pool.tcp_keepalive);
+        let builder = if pool.http2 {
+            builder.http2_prior_knowledge()
+        } else {
+            builder
+        };
+        let client = builder.build().expect("HttpClient::build()");
         Client {
             client,
             base_url,
             username,
             password: Some(password),
+            timeouts,
+            long_running: false,
+            auth: AuthMethod::Session,
+            session: Arc::new(Session::default()),
         }
     }
 
-    pub async fn get<Q: Serialize, T: DeserializeOwned>(&self, path: &str, query: Option<Q>) -> reqwest::Result<T> {
+    pub fn set_auth_method(&mut self, auth: AuthMethod) -> &mut Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Returns a client sharing the connections of this one, whose requests all get the
+    /// long-running timeout
+    pub fn long_running(&self) -> Client {
+        Client {
+            long_running: true,
+            ..self.clone()
+        }
+    }
+
+    pub async fn get<Q: Serialize, T: DeserializeOwned>(&self, path: &str, query: Option<Q>) -> crate::Result<T> {
         self.request(Method::GET, path, None::<bool>, query).await
     }
 
@@ -40,7 +162,7 @@ impl Client {
         path: &str,
         body: Option<B>,
         query: Option<Q>,
-    ) -> reqwest::Result<R> {
+    ) -> crate::Result<R> {
         self.request(Method::PUT, path, body, query).await
     }
 
@@ -49,40 +171,34 @@ impl Client {
         path: &str,
         body: Option<B>,
         query: Option<Q>,
-    ) -> reqwest::Result<R> {
+    ) -> crate::Result<R> {
         self.request(Method::POST, path, body, query).await
     }
 
-    pub async fn delete<Q: Serialize>(&self, path: &str, query: Option<Q>) -> reqwest::Result<()> {
+    pub async fn delete<Q: Serialize>(&self, path: &str, query: Option<Q>) -> crate::Result<()> {
         self.request(Method::DELETE, path, None::<bool>, query).await.map(|_: Ok| ())
     }
 
-    pub async fn exists(&self, path: &str) -> reqwest::Result<bool> {
-        let result = self
-            .client
-            .head(self.build_url(path).unwrap())
-            .basic_auth(&self.username, self.password.as_ref())
-            .send()
-            .await?
-            .error_for_status();
-
-        match result {
+    pub async fn exists(&self, path: &str) -> crate::Result<bool> {
+        match self.send(self.build_req(Method::HEAD, path)).await {
             Ok(_res) => Ok(true),
-            Err(err) => match err.status() {
-                Some(StatusCode::NOT_FOUND) => Ok(false),
-                _ => Err(err),
-            },
+            Err(Error::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
         }
     }
 
-    pub async fn stream<Q: Serialize>(&self, path: &str, query: Option<Q>) -> reqwest::Result<impl futures::Stream<Item=reqwest::Result<Bytes>>> {
-        let req = self.build_req(Method::GET, path);
+    /// Streams the body of the response, within the long-running timeout. A continuous feed
+    /// is cut when it expires, so consumers are expected to resume it.
+    pub async fn stream<Q: Serialize>(&self, path: &str, query: Option<Q>) -> crate::Result<impl futures::Stream<Item=reqwest::Result<Bytes>>> {
+        let req = self
+            .build_req(Method::GET, path)
+            .timeout(self.timeouts.long);
         let req = if let Some(query) = query {
             req.query::<Q>(&query)
         } else {
             req
         };
-        let res = req.send().await?.error_for_status()?;
+        let res = self.send(req).await?;
         Ok(res.bytes_stream())
     }
 
@@ -96,7 +212,7 @@ impl Client {
         path: &str,
         body: Option<B>,
         query: Option<Q>,
-    ) -> reqwest::Result<R> {
+    ) -> crate::Result<R> {
         let req = self.build_req(method, path);
         let req = if let Some(body) = body {
             req.json::<B>(&body)
@@ -110,7 +226,124 @@ impl Client {
             req
         };
 
-        req.send().await?.error_for_status()?.json().await
+        let res = self.send(req).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Sends the request, authenticated, mapping error responses to the [`Error`] of their
+    /// status. If the session expired, it logs in again and sends the request once more, unless
+    /// its body was streamed and cannot be sent again.
+    pub(crate) async fn send(&self, req: RequestBuilder) -> crate::Result<Response> {
+        let cookie = match self.auth {
+            AuthMethod::Session => self.session_cookie().await?,
+            AuthMethod::Basic => None,
+        };
+        let cookie = match cookie {
+            Some(cookie) => cookie,
+            None => return Self::check(self.basic_auth(req).send().await?).await,
+        };
+
+        let retry = req.try_clone();
+        let res = req.header(COOKIE, cookie.as_str()).send().await?;
+        if res.status() != StatusCode::UNAUTHORIZED {
+            self.renew(res.headers());
+            return Self::check(res).await;
+        }
+
+        log::debug!("CouchDB session expired, logging in again");
+        let renewed = self.login(Some(&cookie)).await?;
+        let retry = match retry {
+            Some(retry) => retry,
+            None => return Self::check(res).await,
+        };
+        let retry = match renewed {
+            Some(cookie) => retry.header(COOKIE, cookie),
+            None => self.basic_auth(retry),
+        };
+        let res = retry.send().await?;
+        self.renew(res.headers());
+        Self::check(res).await
+    }
+
+    /// Cookie of the current session, logging in if there is none yet. None if CouchDB does not
+    /// open sessions.
+    async fn session_cookie(&self) -> crate::Result<Option<String>> {
+        if self.session.unsupported.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if let Some(cookie) = self.session.cookie.read().unwrap().clone() {
+            return Ok(Some(cookie));
+        }
+        self.login(None).await
+    }
+
+    /// Opens a session, replacing the stale cookie if any. Requests waiting for another one to
+    /// log in reuse the session it opened instead of opening their own.
+    async fn login(&self, stale: Option<&str>) -> crate::Result<Option<String>> {
+        let _login = self.session.login.lock().await;
+        if self.session.unsupported.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if let Some(cookie) = self.session.cookie.read().unwrap().clone() {
+            if Some(cookie.as_str()) != stale {
+                return Ok(Some(cookie));
+            }
+        }
+
+        log::debug!("Opening a CouchDB session for user {}", &self.username);
+        let credentials = serde_json::json!({
+            "name": &self.username,
+            "password": &self.password,
+        });
+        let res = self
+            .client
+            .post(self.build_url("/_session").unwrap())
+            .timeout(self.timeouts.request)
+            .json(&credentials)
+            .send()
+            .await?;
+        let status = res.status();
+        let cookie = auth_session(res.headers());
+        if status == StatusCode::UNAUTHORIZED || status.is_server_error() {
+            *self.session.cookie.write().unwrap() = None;
+            let body = res.text().await.unwrap_or_default();
+            return Err(Error::from_response(status, &body));
+        }
+        match cookie {
+            Some(cookie) if status.is_success() => {
+                *self.session.cookie.write().unwrap() = Some(cookie.clone());
+                Ok(Some(cookie))
+            }
+            _ => {
+                log::warn!(
+                    "CouchDB did not open a session ({}), falling back to basic authentication",
+                    status
+                );
+                self.session.unsupported.store(true, Ordering::SeqCst);
+                *self.session.cookie.write().unwrap() = None;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Keeps the cookie CouchDB renews when the session is about to expire
+    fn renew(&self, headers: &HeaderMap) {
+        if let Some(cookie) = auth_session(headers) {
+            *self.session.cookie.write().unwrap() = Some(cookie);
+        }
+    }
+
+    fn basic_auth(&self, req: RequestBuilder) -> RequestBuilder {
+        req.basic_auth(&self.username, self.password.as_ref())
+    }
+
+    async fn check(res: Response) -> crate::Result<Response> {
+        let status = res.status();
+        if status.is_client_error() || status.is_server_error() {
+            let body = res.text().await.unwrap_or_default();
+            return Err(Error::from_response(status, &body));
+        }
+        Ok(res)
     }
 
     fn build_req(
@@ -121,6 +354,297 @@ impl Client {
         self
             .client
             .request(method, self.build_url(path).unwrap())
-            .basic_auth(&self.username, self.password.as_ref())
+            .timeout(self.request_timeout())
+    }
+
+    /// Builds a request to the resource at the given path segments, each one percent-encoded,
+    /// for binary content like attachments. Their bodies may be large, so the request gets the
+    /// long-running timeout.
+    pub(crate) fn build_binary_req(&self, method: Method, segments: &[&str]) -> RequestBuilder {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("CouchDB URL cannot be a base")
+            .pop_if_empty()
+            .extend(segments);
+        self.client
+            .request(method, url)
+            .timeout(self.timeouts.long)
+    }
+
+    fn request_timeout(&self) -> Duration {
+        if self.long_running {
+            self.timeouts.long
+        } else {
+            self.timeouts.request
+        }
+    }
+}
+
+/// The `AuthSession` cookie set by a response, as sent back in a `Cookie` header. Logging out
+/// sets it empty, which is not a session.
+fn auth_session(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .filter_map(|header| header.split(';').next())
+        .map(str::trim)
+        .find(|cookie| cookie.starts_with("AuthSession=") && cookie.len() > "AuthSession=".len())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Server {
+        /// Whether `/_session` opens sessions
+        sessions: bool,
+        session: Option<String>,
+        logins: usize,
+        basic_requests: usize,
+    }
+
+    type Shared = Arc<std::sync::Mutex<Server>>;
+
+    /// Serves a CouchDB answering any request authenticated with the current session cookie or
+    /// with basic authentication
+    fn serve_couch(sessions: bool) -> (Url, Shared) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = Shared::new(std::sync::Mutex::new(Server {
+            sessions,
+            ..Server::default()
+        }));
+        let shared = server.clone();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let server = shared.clone();
+                thread::spawn(move || serve(conn.unwrap(), server));
+            }
+        });
+        (Url::parse(&url).unwrap(), server)
+    }
+
+    fn serve(conn: TcpStream, server: Shared) {
+        let mut reader = BufReader::new(conn.try_clone().unwrap());
+        let mut conn = conn;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_string();
+            let path = parts.next().unwrap().to_string();
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                let (name, value) = header.split_at(header.find(':').unwrap());
+                headers.insert(name.to_lowercase(), value[1..].trim().to_string());
+            }
+            let length = headers
+                .get("content-length")
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let (status, cookie, body) = if method == "POST" && path == "/_session" {
+                // Slow like hashing the password, so that concurrent logins would overlap
+                thread::sleep(Duration::from_millis(50));
+                let mut server = server.lock().unwrap();
+                if server.sessions {
+                    server.logins += 1;
+                    let cookie = format!("AuthSession=s{}", server.logins);
+                    server.session = Some(cookie.clone());
+                    (200, Some(cookie), r#"{"ok":true,"name":"enseada"}"#)
+                } else {
+                    (404, None, r#"{"error":"not_found","reason":"missing"}"#)
+                }
+            } else {
+                let mut server = server.lock().unwrap();
+                if headers.contains_key("authorization") {
+                    server.basic_requests += 1;
+                }
+                let session = headers.get("cookie");
+                if (session.is_some() && session == server.session.as_ref())
+                    || headers.contains_key("authorization")
+                {
+                    (200, None, r#"{"ok":true}"#)
+                } else {
+                    (401, None, r#"{"error":"unauthorized","reason":"You are not authorized."}"#)
+                }
+            };
+            let cookie = cookie.map_or(String::new(), |cookie| {
+                format!("Set-Cookie: {}; Version=1; Path=/; HttpOnly\r\n", cookie)
+            });
+            write!(
+                conn,
+                "HTTP/1.1 {} Status\r\n{}Content-Length: {}\r\n\r\n{}",
+                status,
+                cookie,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    }
+
+    fn connect(url: Url) -> Client {
+        Client::new(
+            url,
+            "enseada".to_string(),
+            "enseada".to_string(),
+            Timeouts::default(),
+            Pool::default(),
+        )
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Accepts connections and never responds, keeping them open
+    fn serve_silently() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut conns = Vec::new();
+            for conn in listener.incoming() {
+                conns.push(conn.unwrap());
+            }
+        });
+        Url::parse(&url).unwrap()
+    }
+
+    async fn get(client: &Client) -> crate::Result<serde_json::Value> {
+        client.get("/users/user:jdoe", None::<bool>).await
+    }
+
+    #[test]
+    fn it_logs_in_once_and_reuses_the_session() {
+        let (url, server) = serve_couch(true);
+        let client = connect(url);
+        runtime().block_on(async {
+            for _ in 0..3 {
+                get(&client).await.unwrap();
+            }
+        });
+
+        let server = server.lock().unwrap();
+        assert_eq!(server.logins, 1);
+        assert_eq!(server.basic_requests, 0);
+    }
+
+    #[test]
+    fn it_logs_in_once_again_when_the_session_expires() {
+        let (url, server) = serve_couch(true);
+        let client = connect(url);
+        let mut rt = runtime();
+        rt.block_on(get(&client)).unwrap();
+
+        server.lock().unwrap().session = None;
+        let (first, second) = rt.block_on(async { futures::join!(get(&client), get(&client)) });
+
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+        let server = server.lock().unwrap();
+        assert_eq!(server.logins, 2);
+        assert_eq!(server.basic_requests, 0);
+    }
+
+    #[test]
+    fn it_falls_back_to_basic_auth_without_sessions() {
+        let (url, server) = serve_couch(false);
+        let client = connect(url);
+        runtime().block_on(async {
+            get(&client).await.unwrap();
+            get(&client).await.unwrap();
+        });
+        assert_eq!(server.lock().unwrap().basic_requests, 2);
+
+        let (url, server) = serve_couch(true);
+        let mut client = connect(url);
+        client.set_auth_method(AuthMethod::Basic);
+        runtime().block_on(get(&client)).unwrap();
+        let server = server.lock().unwrap();
+        assert_eq!(server.logins, 0);
+        assert_eq!(server.basic_requests, 1);
+    }
+
+    #[test]
+    fn it_reads_the_session_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, "theme=dark; Path=/".parse().unwrap());
+        headers.append(
+            SET_COOKIE,
+            "AuthSession=ZW5zZWFkYQ; Version=1; Path=/; HttpOnly".parse().unwrap(),
+        );
+        assert_eq!(auth_session(&headers), Some("AuthSession=ZW5zZWFkYQ".to_string()));
+
+        let mut logout = HeaderMap::new();
+        logout.append(SET_COOKIE, "AuthSession=; Version=1; Path=/".parse().unwrap());
+        assert_eq!(auth_session(&logout), None);
+    }
+
+    #[test]
+    fn it_times_out_waiting_for_silent_servers() {
+        let timeouts = Timeouts {
+            request: Duration::from_millis(200),
+            ..Timeouts::default()
+        };
+        let client = Client::new(
+            serve_silently(),
+            "enseada".to_string(),
+            "enseada".to_string(),
+            timeouts,
+            Pool::default(),
+        );
+
+        let started = Instant::now();
+        let res = runtime().block_on(client.get::<_, serde_json::Value>("/_up", None::<bool>));
+
+        assert!(res.unwrap_err().is_timeout());
+        assert!(started.elapsed() < timeouts.connect);
+    }
+
+    #[test]
+    fn it_gives_up_connecting_to_unreachable_hosts() {
+        let timeouts = Timeouts {
+            connect: Duration::from_millis(200),
+            ..Timeouts::default()
+        };
+        // Non-routable, so the connection attempt hangs, unless the network refuses it right away
+        let url = Url::parse("http://10.255.255.1:5984").unwrap();
+        let client = Client::new(
+            url,
+            "enseada".to_string(),
+            "enseada".to_string(),
+            timeouts,
+            Pool::default(),
+        );
+
+        let started = Instant::now();
+        let res = runtime().block_on(client.get::<_, serde_json::Value>("/_up", None::<bool>));
+
+        // Either way, the request fails well before its own timeout
+        assert!(res.is_err());
+        assert!(started.elapsed() < timeouts.request);
     }
 }
@@ -0,0 +1,290 @@
+//! Read-your-writes consistency for clustered CouchDB.
+//!
+//! With more than one replica, a document written by a request may not be visible yet to the
+//! read of the request that follows it, like logging in right after registering. Writes made
+//! with [`Database::put_tracked`] return a [`WriteToken`] and remember it for a short while in
+//! the [`RecentWrites`] shared by every handle of a [`Couch`], and reads made with
+//! [`Database::get_consistent`] briefly retry until they observe that revision.
+//! Plain reads never retry.
+//!
+//! [`Couch`]: crate::Couch
+//! [`Database::put_tracked`]: crate::db::Database::put_tracked
+//! [`Database::get_consistent`]: crate::db::Database::get_consistent
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::data_migration::DocumentStore;
+use crate::responses::PutResponse;
+use crate::Result;
+
+/// How long a write is remembered, well over the replication lag of a healthy cluster
+pub const DEFAULT_TTL: Duration = Duration::from_secs(5);
+
+/// Identifies the revision of a document written by a request
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteToken {
+    id: String,
+    rev: String,
+}
+
+impl WriteToken {
+    pub fn new(id: String, rev: String) -> Self {
+        WriteToken { id, rev }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn rev(&self) -> &str {
+        &self.rev
+    }
+
+    /// Whether a document with the given revision includes this write
+    fn is_visible_in(&self, rev: &str) -> bool {
+        generation(rev) >= generation(&self.rev)
+    }
+}
+
+impl From<&PutResponse> for WriteToken {
+    fn from(res: &PutResponse) -> Self {
+        WriteToken::new(res.id.clone(), res.rev.clone())
+    }
+}
+
+/// Number of the revision, like 3 for `3-917fa23`
+fn generation(rev: &str) -> u64 {
+    rev.split('-')
+        .next()
+        .and_then(|generation| generation.parse().ok())
+        .unwrap_or(0)
+}
+
+/// How many times, and how often, a read is retried until it observes a write
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub attempts: usize,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Reads the document of the write, retrying while it is missing or older than the write.
+/// Gives up after the attempts of the policy, returning the last observed document.
+pub async fn read_your_write<S: DocumentStore + ?Sized>(
+    store: &S,
+    token: &WriteToken,
+    policy: &RetryPolicy,
+) -> Result<Option<Value>> {
+    let mut attempt = 0;
+    loop {
+        let doc = store.get(&token.id).await?;
+        let rev = doc
+            .as_ref()
+            .and_then(|doc| doc.get("_rev"))
+            .and_then(Value::as_str);
+        if rev.is_some_and(|rev| token.is_visible_in(rev)) {
+            return Ok(doc);
+        }
+
+        if attempt >= policy.attempts {
+            log::warn!(
+                "Document {} is still not at revision {} after {} retries",
+                &token.id,
+                &token.rev,
+                attempt
+            );
+            return Ok(doc);
+        }
+        attempt += 1;
+        log::debug!(
+            "Document {} is not at revision {} yet, retrying in {:?}",
+            &token.id,
+            &token.rev,
+            policy.delay
+        );
+        tokio::time::delay_for(policy.delay).await;
+    }
+}
+
+/// Writes made recently through [`Database::put_tracked`], by database and document id
+///
+/// [`Database::put_tracked`]: crate::db::Database::put_tracked
+pub struct RecentWrites {
+    ttl: Duration,
+    writes: Mutex<HashMap<(String, String), (WriteToken, Instant)>>,
+}
+
+impl Default for RecentWrites {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL)
+    }
+}
+
+impl RecentWrites {
+    pub fn new(ttl: Duration) -> Self {
+        RecentWrites {
+            ttl,
+            writes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, db: &str, token: WriteToken) {
+        let now = Instant::now();
+        let mut writes = self.writes.lock().unwrap();
+        writes.retain(|_, (_, written_at)| now.duration_since(*written_at) < self.ttl);
+        writes.insert((db.to_string(), token.id.clone()), (token, now));
+    }
+
+    pub fn forget(&self, db: &str, id: &str) {
+        let mut writes = self.writes.lock().unwrap();
+        writes.remove(&(db.to_string(), id.to_string()));
+    }
+
+    /// Token of the last write of the document, if it was recent
+    pub fn get(&self, db: &str, id: &str) -> Option<WriteToken> {
+        let writes = self.writes.lock().unwrap();
+        writes
+            .get(&(db.to_string(), id.to_string()))
+            .filter(|(_, written_at)| written_at.elapsed() < self.ttl)
+            .map(|(token, _)| token.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use serde_json::json;
+    use tokio::runtime::Builder;
+
+    use crate::error::Error;
+    use crate::responses::FindResponse;
+
+    use super::*;
+
+    /// Serves an old revision of a document until it was read a number of times
+    struct LaggingStore {
+        reads: AtomicUsize,
+        visible_after: usize,
+        stale: Option<Value>,
+    }
+
+    impl LaggingStore {
+        fn new(visible_after: usize, stale: Option<Value>) -> Self {
+            LaggingStore {
+                reads: AtomicUsize::new(0),
+                visible_after,
+                stale,
+            }
+        }
+
+        fn reads(&self) -> usize {
+            self.reads.load(Ordering::Relaxed)
+        }
+    }
+
+    #[async_trait]
+    impl DocumentStore for LaggingStore {
+        async fn find(
+            &self,
+            _selector: Value,
+            _limit: usize,
+            _bookmark: Option<String>,
+        ) -> Result<FindResponse<Value>> {
+            Err(Error::internal("not supported".to_string()))
+        }
+
+        async fn get(&self, id: &str) -> Result<Option<Value>> {
+            let reads = self.reads.fetch_add(1, Ordering::Relaxed);
+            if reads < self.visible_after {
+                Ok(self.stale.clone())
+            } else {
+                Ok(Some(json!({ "_id": id, "_rev": "2-b", "name": "new" })))
+            }
+        }
+
+        async fn put(&self, _id: &str, _doc: &Value) -> Result<PutResponse> {
+            Err(Error::internal("not supported".to_string()))
+        }
+    }
+
+    fn run<F: std::future::Future>(fut: F) -> F::Output {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(fut)
+    }
+
+    fn token() -> WriteToken {
+        WriteToken::new("user:jdoe".to_string(), "2-b".to_string())
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn it_retries_until_a_missing_document_is_visible() {
+        let store = LaggingStore::new(2, None);
+        let doc = run(read_your_write(&store, &token(), &policy())).unwrap();
+        assert_eq!(doc.unwrap()["_rev"], "2-b");
+        assert_eq!(store.reads(), 3);
+    }
+
+    #[test]
+    fn it_retries_until_an_older_revision_is_replaced() {
+        let stale = json!({ "_id": "user:jdoe", "_rev": "1-a", "name": "old" });
+        let store = LaggingStore::new(1, Some(stale));
+        let doc = run(read_your_write(&store, &token(), &policy())).unwrap();
+        assert_eq!(doc.unwrap()["name"], "new");
+        assert_eq!(store.reads(), 2);
+    }
+
+    #[test]
+    fn it_gives_up_after_the_bounded_attempts() {
+        let store = LaggingStore::new(10, None);
+        let doc = run(read_your_write(&store, &token(), &policy())).unwrap();
+        assert!(doc.is_none());
+        assert_eq!(store.reads(), 4);
+    }
+
+    #[test]
+    fn it_does_not_retry_visible_writes() {
+        let store = LaggingStore::new(0, None);
+        let token = WriteToken::new("user:jdoe".to_string(), "1-a".to_string());
+        run(read_your_write(&store, &token, &policy())).unwrap();
+        assert_eq!(store.reads(), 1);
+    }
+
+    #[test]
+    fn it_remembers_writes_for_a_while() {
+        let writes = RecentWrites::new(Duration::from_millis(20));
+        writes.record("users", token());
+        assert_eq!(writes.get("users", "user:jdoe"), Some(token()));
+        assert_eq!(writes.get("oauth", "user:jdoe"), None);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(writes.get("users", "user:jdoe"), None);
+
+        writes.record("users", token());
+        writes.forget("users", "user:jdoe");
+        assert_eq!(writes.get("users", "user:jdoe"), None);
+    }
+}
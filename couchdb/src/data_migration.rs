@@ -0,0 +1,585 @@
+//! One-time migrations of existing documents, like hashing legacy plaintext values.
+//!
+//! A [`DataMigration`] pages through the documents matching a selector, applies a [`Transform`]
+//! to each of them and writes them back, retrying on conflicts. Progress is checkpointed in a
+//! [`MigrationReport`] document after every page, so an interrupted run resumes where it stopped,
+//! and documents that fail are recorded in the report instead of aborting the whole migration.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+
+use crate::db::Database;
+use crate::error::Error;
+use crate::responses::{FindResponse, PutResponse};
+use crate::size::SizeGuard;
+
+const MAX_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Snafu)]
+pub enum DataMigrationError {
+    #[snafu(display("Failed to load report of data migration {}: {}", name, source))]
+    LoadReport { name: String, source: Error },
+    #[snafu(display("Failed to save report of data migration {}: {}", name, source))]
+    SaveReport { name: String, source: Error },
+    #[snafu(display("Failed to find documents for data migration {}: {}", name, source))]
+    FindDocuments { name: String, source: Error },
+}
+
+/// Storage the migration reads documents from and writes them back to
+#[async_trait]
+pub trait DocumentStore: Send + Sync {
+    async fn find(
+        &self,
+        selector: Value,
+        limit: usize,
+        bookmark: Option<String>,
+    ) -> crate::Result<FindResponse<Value>>;
+    async fn get(&self, id: &str) -> crate::Result<Option<Value>>;
+    async fn put(&self, id: &str, doc: &Value) -> crate::Result<PutResponse>;
+}
+
+#[async_trait]
+impl DocumentStore for Database {
+    async fn find(
+        &self,
+        selector: Value,
+        limit: usize,
+        bookmark: Option<String>,
+    ) -> crate::Result<FindResponse<Value>> {
+        Database::find(self, selector, limit, bookmark).await
+    }
+
+    async fn get(&self, id: &str) -> crate::Result<Option<Value>> {
+        Database::get(self, id).await
+    }
+
+    async fn put(&self, id: &str, doc: &Value) -> crate::Result<PutResponse> {
+        Database::put(self, id, doc).await
+    }
+}
+
+#[async_trait]
+impl<S: DocumentStore> DocumentStore for Arc<S> {
+    async fn find(
+        &self,
+        selector: Value,
+        limit: usize,
+        bookmark: Option<String>,
+    ) -> crate::Result<FindResponse<Value>> {
+        self.as_ref().find(selector, limit, bookmark).await
+    }
+
+    async fn get(&self, id: &str) -> crate::Result<Option<Value>> {
+        self.as_ref().get(id).await
+    }
+
+    async fn put(&self, id: &str, doc: &Value) -> crate::Result<PutResponse> {
+        self.as_ref().put(id, doc).await
+    }
+}
+
+/// Change applied to every document of a migration
+pub trait Transform: Send + Sync {
+    /// Updates the document in place, returning false if it needs no change.
+    /// Errors are recorded as failures of the single document.
+    fn apply(&self, doc: &mut Value) -> Result<bool, String>;
+}
+
+impl<F> Transform for F
+where
+    F: Fn(&mut Value) -> Result<bool, String> + Send + Sync,
+{
+    fn apply(&self, doc: &mut Value) -> Result<bool, String> {
+        self(doc)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The transform rejected the document
+    Transform,
+    /// The document kept changing while being written back
+    Conflict,
+    /// The document could not be read or written
+    Storage,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct DocumentFailure {
+    pub id: String,
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStatus {
+    Running,
+    Completed,
+    /// Completed, but some documents could not be migrated. Running it again retries them.
+    CompletedWithFailures,
+}
+
+/// Progress of a data migration, stored alongside the documents it migrates
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MigrationReport {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    pub name: String,
+    pub status: MigrationStatus,
+    /// Checkpoint to resume from, the bookmark of the last completed page
+    pub bookmark: Option<String>,
+    pub processed: u64,
+    pub migrated: u64,
+    pub failures: Vec<DocumentFailure>,
+    /// Whether the stored failures were cut short to fit the maximum document size
+    #[serde(default)]
+    pub truncated: bool,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl MigrationReport {
+    pub fn build_id(name: &str) -> String {
+        format!("data_migration:{}", name)
+    }
+
+    fn new(name: &str) -> Self {
+        let now = Utc::now();
+        MigrationReport {
+            id: Self::build_id(name),
+            rev: None,
+            name: name.to_string(),
+            status: MigrationStatus::Running,
+            bookmark: None,
+            processed: 0,
+            migrated: 0,
+            failures: Vec::new(),
+            truncated: false,
+            started_at: now,
+            updated_at: now,
+        }
+    }
+
+    fn restart(&mut self) {
+        let rev = self.rev.take();
+        *self = Self::new(&self.name);
+        self.rev = rev;
+    }
+}
+
+pub struct DataMigration<S: DocumentStore, T: Transform> {
+    name: String,
+    store: S,
+    selector: Value,
+    transform: T,
+    batch_size: usize,
+    size_guard: Arc<SizeGuard>,
+}
+
+impl<S: DocumentStore, T: Transform> DataMigration<S, T> {
+    /// Creates a migration applying the transform to the documents matching the selector.
+    /// The selector should exclude already migrated documents, so they are never read again.
+    pub fn new(name: &str, store: S, selector: Value, transform: T) -> Self {
+        DataMigration {
+            name: name.to_string(),
+            store,
+            selector,
+            transform,
+            batch_size: 100,
+            size_guard: Arc::new(SizeGuard::default()),
+        }
+    }
+
+    pub fn set_batch_size(&mut self, batch_size: usize) -> &mut Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Sets the guard keeping the report under the maximum document size,
+    /// by truncating its failures if there are too many
+    pub fn set_size_guard(&mut self, size_guard: Arc<SizeGuard>) -> &mut Self {
+        self.size_guard = size_guard;
+        self
+    }
+
+    /// Runs the migration, resuming an interrupted run from its checkpoint.
+    /// A completed migration is run again from the start, retrying its failed documents.
+    pub async fn run(&self) -> Result<MigrationReport, DataMigrationError> {
+        let name = self.name.clone();
+        let report: Option<MigrationReport> = self
+            .load_report()
+            .await
+            .context(LoadReport { name: name.clone() })?;
+        let mut report = match report {
+            Some(mut report) => {
+                if report.status == MigrationStatus::Running {
+                    log::info!(
+                        "Resuming data migration {} after {} documents",
+                        &name,
+                        report.processed
+                    );
+                } else {
+                    report.restart();
+                }
+                report
+            }
+            None => MigrationReport::new(&name),
+        };
+
+        loop {
+            let page = self
+                .store
+                .find(
+                    self.selector.clone(),
+                    self.batch_size,
+                    report.bookmark.clone(),
+                )
+                .await
+                .context(FindDocuments { name: name.clone() })?;
+            let count = page.docs.len();
+
+            for doc in page.docs {
+                self.migrate(doc, &mut report).await;
+            }
+
+            report.bookmark = Some(page.bookmark);
+            if count < self.batch_size {
+                break;
+            }
+            self.save_report(&mut report)
+                .await
+                .context(SaveReport { name: name.clone() })?;
+            log::info!(
+                "Data migration {}: {} documents processed, {} migrated, {} failed",
+                &name,
+                report.processed,
+                report.migrated,
+                report.failures.len()
+            );
+        }
+
+        report.status = if report.failures.is_empty() {
+            MigrationStatus::Completed
+        } else {
+            log::warn!(
+                "Data migration {} failed for {} documents",
+                &name,
+                report.failures.len()
+            );
+            MigrationStatus::CompletedWithFailures
+        };
+        self.save_report(&mut report)
+            .await
+            .context(SaveReport { name })?;
+        Ok(report)
+    }
+
+    async fn migrate(&self, mut doc: Value, report: &mut MigrationReport) {
+        let id = match doc.get("_id").and_then(Value::as_str) {
+            Some(id) => id.to_string(),
+            None => return,
+        };
+        if id == report.id {
+            return;
+        }
+        report.processed += 1;
+
+        for _ in 0..MAX_ATTEMPTS {
+            match self.transform.apply(&mut doc) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(message) => {
+                    return Self::fail(report, id, FailureKind::Transform, message);
+                }
+            }
+
+            match self.store.put(&id, &doc).await {
+                Ok(_) => {
+                    report.migrated += 1;
+                    return;
+                }
+                Err(err) if err.status() == StatusCode::CONFLICT => {
+                    log::debug!("Conflict migrating {}, retrying", &id);
+                    doc = match self.store.get(&id).await {
+                        Ok(Some(doc)) => doc,
+                        // Deleted in the meantime, nothing left to migrate
+                        Ok(None) => return,
+                        Err(err) => {
+                            return Self::fail(report, id, FailureKind::Storage, err.to_string());
+                        }
+                    };
+                }
+                Err(err) => {
+                    return Self::fail(report, id, FailureKind::Storage, err.to_string());
+                }
+            }
+        }
+
+        let message = format!("still conflicting after {} attempts", MAX_ATTEMPTS);
+        Self::fail(report, id, FailureKind::Conflict, message)
+    }
+
+    fn fail(report: &mut MigrationReport, id: String, kind: FailureKind, message: String) {
+        log::warn!("Failed to migrate document {}: {}", &id, &message);
+        report.failures.push(DocumentFailure { id, kind, message });
+    }
+
+    async fn load_report(&self) -> crate::Result<Option<MigrationReport>> {
+        let id = MigrationReport::build_id(&self.name);
+        match self.store.get(&id).await? {
+            Some(doc) => serde_json::from_value(doc)
+                .map(Some)
+                .map_err(|err| Error::internal(err.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    async fn save_report(&self, report: &mut MigrationReport) -> crate::Result<()> {
+        report.updated_at = Utc::now();
+        let mut doc =
+            serde_json::to_value(&*report).map_err(|err| Error::internal(err.to_string()))?;
+        report.truncated = self.size_guard.check(&report.id, &mut doc, &["failures"])?;
+        let res = self.store.put(&report.id, &doc).await?;
+        report.rev = Some(res.rev);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashSet};
+    use std::sync::Mutex;
+
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Documents sorted by id, paged by the last returned id like CouchDB bookmarks
+    #[derive(Default)]
+    struct MemoryStore {
+        docs: Mutex<BTreeMap<String, Value>>,
+        writes: Mutex<Vec<String>>,
+        conflicts: Mutex<HashSet<String>>,
+        fail_finds_after: Mutex<Option<usize>>,
+    }
+
+    impl MemoryStore {
+        fn with_secrets(count: usize) -> Arc<Self> {
+            let store = Arc::new(MemoryStore::default());
+            for i in 0..count {
+                let id = format!("client:{:02}", i);
+                let doc = json!({ "_id": id, "secret": format!("s{}", i) });
+                store.docs.lock().unwrap().insert(id, doc);
+            }
+            store
+        }
+
+        fn writes_to(&self, prefix: &str) -> Vec<String> {
+            let writes = self.writes.lock().unwrap();
+            writes
+                .iter()
+                .filter(|id| id.starts_with(prefix))
+                .cloned()
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl DocumentStore for MemoryStore {
+        async fn find(
+            &self,
+            selector: Value,
+            limit: usize,
+            bookmark: Option<String>,
+        ) -> crate::Result<FindResponse<Value>> {
+            let mut fail = self.fail_finds_after.lock().unwrap();
+            if let Some(remaining) = fail.as_mut() {
+                if *remaining == 0 {
+                    return Err(Error::internal("connection refused".to_string()));
+                }
+                *remaining -= 1;
+            }
+
+            let field = selector.as_object().unwrap().keys().next().unwrap().clone();
+            let start = bookmark.unwrap_or_default();
+            let docs: Vec<Value> = self
+                .docs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(id, doc)| **id > start && doc.get(&field).is_some())
+                .take(limit)
+                .map(|(_, doc)| doc.clone())
+                .collect();
+            let bookmark = docs
+                .last()
+                .map(|doc| doc["_id"].as_str().unwrap().to_string())
+                .unwrap_or(start);
+            Ok(FindResponse {
+                docs,
+                bookmark,
+                warning: None,
+            })
+        }
+
+        async fn get(&self, id: &str) -> crate::Result<Option<Value>> {
+            Ok(self.docs.lock().unwrap().get(id).cloned())
+        }
+
+        async fn put(&self, id: &str, doc: &Value) -> crate::Result<PutResponse> {
+            if self.conflicts.lock().unwrap().contains(id) {
+                return Err(Error::conflict(format!("document {} conflicts", id)));
+            }
+            self.writes.lock().unwrap().push(id.to_string());
+            self.docs
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), doc.clone());
+            Ok(PutResponse {
+                ok: true,
+                id: id.to_string(),
+                rev: "1-abc".to_string(),
+            })
+        }
+    }
+
+    fn hash_secrets(doc: &mut Value) -> Result<bool, String> {
+        let doc = doc.as_object_mut().unwrap();
+        let secret = match doc.remove("secret") {
+            Some(Value::String(secret)) => secret,
+            _ => return Ok(false),
+        };
+        if secret == "s3" || secret == "s7" {
+            return Err(format!("cannot hash {}", secret));
+        }
+        doc.insert("secret_hash".to_string(), json!(format!("hashed-{}", secret)));
+        Ok(true)
+    }
+
+    type HashSecrets = fn(&mut Value) -> Result<bool, String>;
+
+    fn migration(store: &Arc<MemoryStore>) -> DataMigration<Arc<MemoryStore>, HashSecrets> {
+        let mut migration = DataMigration::new(
+            "secrets",
+            store.clone(),
+            json!({ "secret": { "$exists": true } }),
+            hash_secrets as HashSecrets,
+        );
+        migration.set_batch_size(4);
+        migration
+    }
+
+    #[test]
+    fn it_reports_failed_documents() {
+        let store = MemoryStore::with_secrets(10);
+        let report = block_on(migration(&store).run()).unwrap();
+
+        assert_eq!(report.status, MigrationStatus::CompletedWithFailures);
+        assert_eq!(report.processed, 10);
+        assert_eq!(report.migrated, 8);
+        let failed: Vec<&str> = report.failures.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(failed, vec!["client:03", "client:07"]);
+        assert!(report
+            .failures
+            .iter()
+            .all(|f| f.kind == FailureKind::Transform));
+
+        let docs = store.docs.lock().unwrap();
+        assert_eq!(docs["client:00"]["secret_hash"], "hashed-s0");
+        assert_eq!(docs["client:03"]["secret"], "s3");
+        let stored = &docs[&MigrationReport::build_id("secrets")];
+        assert_eq!(stored["status"], "completed_with_failures");
+    }
+
+    #[test]
+    fn it_resumes_from_the_checkpoint() {
+        let store = MemoryStore::with_secrets(10);
+        // The first page succeeds, then the database goes away
+        *store.fail_finds_after.lock().unwrap() = Some(1);
+        assert!(block_on(migration(&store).run()).is_err());
+        assert_eq!(store.writes_to("client:").len(), 3);
+
+        *store.fail_finds_after.lock().unwrap() = None;
+        let report = block_on(migration(&store).run()).unwrap();
+        assert_eq!(report.processed, 10);
+        assert_eq!(report.migrated, 8);
+
+        // Every successful document was written exactly once
+        let writes = store.writes_to("client:");
+        let unique: HashSet<&String> = writes.iter().collect();
+        assert_eq!(writes.len(), 8);
+        assert_eq!(unique.len(), 8);
+    }
+
+    #[test]
+    fn it_only_retries_failed_documents_on_rerun() {
+        let store = MemoryStore::with_secrets(10);
+        block_on(migration(&store).run()).unwrap();
+        store.writes.lock().unwrap().clear();
+
+        let report = block_on(migration(&store).run()).unwrap();
+        assert_eq!(report.processed, 2);
+        assert_eq!(report.migrated, 0);
+        assert_eq!(report.failures.len(), 2);
+        assert!(store.writes_to("client:").is_empty());
+    }
+
+    #[test]
+    fn it_gives_up_on_persistent_conflicts() {
+        let store = MemoryStore::with_secrets(2);
+        store
+            .conflicts
+            .lock()
+            .unwrap()
+            .insert("client:01".to_string());
+
+        let report = block_on(migration(&store).run()).unwrap();
+        assert_eq!(report.migrated, 1);
+        assert_eq!(
+            report.failures,
+            vec![DocumentFailure {
+                id: "client:01".to_string(),
+                kind: FailureKind::Conflict,
+                message: "still conflicting after 3 attempts".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn it_truncates_the_failures_of_an_oversized_report() {
+        let store = MemoryStore::with_secrets(10);
+        let guard = Arc::new(SizeGuard::new(400));
+        let mut migration = migration(&store);
+        migration.set_size_guard(guard.clone());
+        let report = block_on(migration.run()).unwrap();
+        assert_eq!(report.failures.len(), 2);
+        assert!(report.truncated);
+        assert!(guard.truncations() > 0);
+
+        let docs = store.docs.lock().unwrap();
+        let stored = &docs[&MigrationReport::build_id("secrets")];
+        assert_eq!(stored["truncated"], true);
+        assert_eq!(stored["failures"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_fails_with_a_typed_error_when_the_report_cannot_fit() {
+        let store = MemoryStore::with_secrets(10);
+        let mut migration = migration(&store);
+        migration.set_size_guard(Arc::new(SizeGuard::new(100)));
+        match block_on(migration.run()) {
+            Err(DataMigrationError::SaveReport { source, .. }) => assert!(source.is_too_large()),
+            res => panic!("unexpected result {:?}", res.map(|report| report.status)),
+        }
+    }
+}
@@ -1,36 +1,59 @@
-use std::io::{BufRead, Cursor};
-use std::str::from_utf8;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use bytes::Bytes;
-use futures::{stream, StreamExt};
-use reqwest::StatusCode;
+use futures::{Stream, TryStream};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, IF_MATCH};
+use reqwest::{Body, Method, Response};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use crate::changes::ChangeEvent;
+use crate::attachment;
+use crate::changes::{self, ChangeEvent};
 use crate::client::Client;
+use crate::consistency::{self, RecentWrites, RetryPolicy, WriteToken};
 use crate::error::Error;
-use crate::index::JsonIndex;
+use crate::find::FindOptions;
+use crate::index::{IndexInfo, JsonIndex};
 use crate::responses;
 use crate::responses::{
-    FindResponse, JsonIndexResponse, JsonIndexResultStatus, PutResponse, RowsResponse,
+    BulkGetResponse, BulkResult, ExplainResponse, FindResponse, IndexListResponse,
+    JsonIndexResponse, JsonIndexResultStatus, PartitionInfo, PutResponse, RawDocResponse,
+    RowsResponse,
 };
+use crate::update;
+use crate::view::{self, DesignDoc, ViewOptions, ViewResponse};
 use crate::Result;
 
+/// IDs read per request when counting the documents matching a query
+const COUNT_BATCH_SIZE: usize = 1000;
+/// Prefix of the IDs of design documents, which hold indexes instead of data
+const DESIGN_PREFIX: &str = "_design/";
+
+/// Handle to a single database, optionally partitioned
 #[derive(Clone)]
 pub struct Database {
     client: Arc<Client>,
     name: String,
     partitioned: bool,
+    recent_writes: Arc<RecentWrites>,
+    attachment_limit: usize,
 }
 
 impl Database {
-    pub(super) fn new(client: Arc<Client>, name: String, partitioned: bool) -> Database {
+    pub(super) fn new(
+        client: Arc<Client>,
+        name: String,
+        partitioned: bool,
+        recent_writes: Arc<RecentWrites>,
+        attachment_limit: usize,
+    ) -> Database {
         Database {
             client,
             name,
             partitioned,
+            recent_writes,
+            attachment_limit,
         }
     }
 
@@ -38,12 +61,34 @@ impl Database {
         &self.name
     }
 
+    pub fn is_partitioned(&self) -> bool {
+        self.partitioned
+    }
+
+    /// Path of a partition of the database. Partition queries only make sense on partitioned
+    /// databases, and CouchDB would answer them with a confusing error otherwise, so they are
+    /// refused here, as are partition names that would escape the path.
+    fn partition_path(&self, partition: &str) -> Result<String> {
+        if !self.partitioned {
+            return Err(Error::internal(format!(
+                "database {} is not partitioned, cannot query partition {}",
+                &self.name, partition
+            )));
+        }
+        if partition.is_empty() || partition.starts_with('_') || partition.contains('/') {
+            return Err(Error::internal(format!(
+                "invalid partition name '{}' for database {}",
+                partition, &self.name
+            )));
+        }
+        Ok(format!("{}/_partition/{}", &self.name, partition))
+    }
+
     pub async fn get_self(&self) -> Result<responses::DBInfo> {
         log::debug!("Getting info for database {}", self.name);
         self.client
             .get(self.name.as_str(), None::<bool>)
             .await
-            .map_err(Error::from)
     }
 
     pub async fn create_self(&self) -> Result<bool> {
@@ -59,7 +104,21 @@ impl Database {
         Ok(res.ok)
     }
 
-    pub async fn create_index(&self, index: JsonIndex) -> Result<bool> {
+    /// Creates an index of the fields in the `{db}_indexes` design document, returning false
+    /// if an identical one already exists. See [`JsonIndex::fields`] for partial filters.
+    pub async fn create_index(
+        &self,
+        name: &str,
+        fields: &[&str],
+        partial_filter: Option<serde_json::Value>,
+    ) -> Result<bool> {
+        let ddoc = format!("{}_indexes", &self.name);
+        let index = JsonIndex::fields(name, Some(ddoc), fields, partial_filter);
+        self.create_json_index(index).await
+    }
+
+    /// Creates an index, returning false if an identical one already exists
+    pub async fn create_json_index(&self, index: JsonIndex) -> Result<bool> {
         let path = format!("{}/_index", &self.name);
         log::debug!(
             "Creating index {} on database {}",
@@ -69,9 +128,92 @@ impl Database {
 
         let res: JsonIndexResponse = self.client.post(&path, Some(index), None::<bool>).await?;
         match res.result {
-            JsonIndexResultStatus::Created => Ok(true),
-            JsonIndexResultStatus::Exists => Ok(false),
+            JsonIndexResultStatus::Created => {
+                log::info!(
+                    "Created index {} in {} of database {}",
+                    &res.name,
+                    &res.id,
+                    &self.name
+                );
+                Ok(true)
+            }
+            JsonIndexResultStatus::Exists => {
+                log::debug!(
+                    "Index {} already exists in {} of database {}",
+                    &res.name,
+                    &res.id,
+                    &self.name
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// Indexes of the database, including the special `_all_docs` one
+    pub async fn list_indexes(&self) -> Result<Vec<IndexInfo>> {
+        let path = format!("{}/_index", &self.name);
+        let res: IndexListResponse = self.client.get(&path, None::<bool>).await?;
+        Ok(res.indexes)
+    }
+
+    /// Writes the design document, unless the current one has the same content hash, returning
+    /// false if it was left as is. Rewriting a design document rebuilds all of its views.
+    pub async fn put_design_doc(&self, ddoc: &DesignDoc) -> Result<bool> {
+        let id = ddoc.id();
+        let current: Option<serde_json::Value> = self.get(&id).await?;
+        let hash = ddoc.content_hash();
+        let current_hash = current
+            .as_ref()
+            .and_then(|doc| doc.get(view::HASH_FIELD))
+            .and_then(serde_json::Value::as_str);
+        if current_hash == Some(hash.as_str()) {
+            log::debug!("Design document {} of {} is up to date", &id, &self.name);
+            return Ok(false);
         }
+
+        let rev = current
+            .as_ref()
+            .and_then(|doc| doc.get("_rev"))
+            .and_then(serde_json::Value::as_str);
+        let path = format!("{}/{}", &self.name, &id);
+        let _: PutResponse = self
+            .client
+            .put(&path, Some(ddoc.body(rev)), None::<bool>)
+            .await?;
+        log::info!("Installed design document {} of database {}", &id, &self.name);
+        Ok(true)
+    }
+
+    /// Queries a view of the design document, deserializing the keys and values of its rows
+    pub async fn query_view<K: DeserializeOwned, V: DeserializeOwned>(
+        &self,
+        ddoc: &str,
+        view: &str,
+        options: &ViewOptions,
+    ) -> Result<ViewResponse<K, V>> {
+        let path = format!("{}/_design/{}/_view/{}", &self.name, ddoc, view);
+        log::debug!("Querying view {}", &path);
+        self.client.get(&path, Some(options)).await
+    }
+
+    /// Like [`query_view`], within a partition, for the views of partitioned design documents
+    ///
+    /// [`query_view`]: Database::query_view
+    pub async fn query_partition_view<K: DeserializeOwned, V: DeserializeOwned>(
+        &self,
+        partition: &str,
+        ddoc: &str,
+        view: &str,
+        options: &ViewOptions,
+    ) -> Result<ViewResponse<K, V>> {
+        let path = format!(
+            "{}/_design/{}/_view/{}",
+            self.partition_path(partition)?,
+            ddoc,
+            view
+        );
+        log::debug!("Querying view {}", &path);
+        self.client.get(&path, Some(options)).await
     }
 
     pub async fn get<R: DeserializeOwned>(&self, id: &str) -> Result<Option<R>> {
@@ -79,55 +221,126 @@ impl Database {
         log::debug!("Getting {} from couch", &path);
         match self.client.get(&path, None::<bool>).await {
             Ok(r) => Ok(Some(r)),
-            Err(err) => match err.status() {
-                Some(StatusCode::NOT_FOUND) => Ok(None),
-                _ => Err(Error::from(err)),
-            },
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Like [`get`], but if the document was recently written with [`put_tracked`] it retries
+    /// briefly until that write is visible, for reads following a write on a clustered CouchDB.
+    ///
+    /// [`get`]: Database::get
+    /// [`put_tracked`]: Database::put_tracked
+    pub async fn get_consistent<R: DeserializeOwned>(&self, id: &str) -> Result<Option<R>> {
+        match self.recent_writes.get(&self.name, id) {
+            Some(token) => self.get_after(&token).await,
+            None => self.get(id).await,
+        }
+    }
+
+    /// Gets many documents in a single request, in the order of their IDs. Documents that are
+    /// missing or deleted are none.
+    pub async fn bulk_get<R: DeserializeOwned>(&self, ids: &[String]) -> Result<Vec<Option<R>>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
         }
+        let path = format!("{}/_bulk_get", &self.name);
+        log::debug!("Getting {} documents from {}", ids.len(), &self.name);
+        let body = BulkGet {
+            docs: ids.iter().map(|id| BulkGetId { id }).collect(),
+        };
+        let res: BulkGetResponse = self.client.post(&path, Some(body), None::<bool>).await?;
+        docs_in_order(ids, res)
+    }
+
+    /// Gets the document of a write, retrying briefly until the write is visible
+    pub async fn get_after<R: DeserializeOwned>(&self, token: &WriteToken) -> Result<Option<R>> {
+        let doc = consistency::read_your_write(self, token, &RetryPolicy::default()).await?;
+        doc.map(serde_json::from_value)
+            .transpose()
+            .map_err(|err| Error::internal(err.to_string()))
     }
 
-    pub async fn list_partitioned<R: DeserializeOwned + Clone>(
+    /// Lists the documents of a partition in ID order, like [`list`]
+    ///
+    /// [`list`]: Database::list
+    pub async fn list_partitioned<R: DeserializeOwned>(
         &self,
         partition: &str,
         limit: usize,
-        start_key: Option<String>,
+        skip: usize,
+        start_key: Option<&str>,
+        end_key: Option<&str>,
     ) -> Result<RowsResponse<R>> {
-        let path = format!("{}/_partition/{}/_all_docs", &self.name, partition);
-        self.do_list(&path, limit, start_key).await
+        let path = format!("{}/_all_docs", self.partition_path(partition)?);
+        self.do_list(&path, limit, skip, start_key, end_key).await
     }
 
-    pub async fn list<R: DeserializeOwned + Clone>(
+    /// Lists documents in ID order, from `start_key` to `end_key` included, after skipping `skip`
+    /// of them. Design documents are left out, and more are read in their place, so that pages
+    /// are only short when there are no more documents.
+    pub async fn list<R: DeserializeOwned>(
         &self,
         limit: usize,
-        start_key: Option<String>,
+        skip: usize,
+        start_key: Option<&str>,
+        end_key: Option<&str>,
     ) -> Result<RowsResponse<R>> {
         let path = format!("{}/_all_docs", &self.name);
-        self.do_list(&path, limit, start_key).await
+        self.do_list(&path, limit, skip, start_key, end_key).await
     }
 
-    async fn do_list<R: DeserializeOwned + Clone>(
+    async fn do_list<R: DeserializeOwned>(
         &self,
         path: &str,
         limit: usize,
-        start_key: Option<String>,
+        skip: usize,
+        start_key: Option<&str>,
+        end_key: Option<&str>,
     ) -> Result<RowsResponse<R>> {
-        let query = Some(ListQuery {
+        log::debug!("Listing {} from {}", limit, path);
+        let mut query = ListQuery {
             include_docs: true,
             limit,
-            start_key,
-        });
-        self.client.get(path, query).await.map_err(Error::from)
+            skip,
+            start_key: start_key.map(encode_key),
+            end_key: end_key.map(encode_key),
+        };
+        let mut rows = Vec::with_capacity(limit);
+        loop {
+            let res: RowsResponse<serde_json::Value> = self
+                .client
+                .get(path, Some(&query))
+                .await?;
+            let exhausted = res.rows.len() < query.limit;
+            let last_key = res.rows.last().map(|row| row.key.clone());
+            rows.extend(typed_rows(res.rows)?);
+            match last_key {
+                Some(last_key) if !exhausted && rows.len() < limit => {
+                    // Design documents took some rows, the rest is read after the last one
+                    query.limit = limit - rows.len();
+                    query.skip = 1;
+                    query.start_key = Some(encode_key(&last_key));
+                }
+                _ => {
+                    return Ok(RowsResponse {
+                        offset: res.offset,
+                        rows,
+                        total_rows: res.total_rows,
+                    })
+                }
+            }
+        }
     }
 
-    pub async fn list_all_partitioned<R: DeserializeOwned + Clone>(
+    pub async fn list_all_partitioned<R: DeserializeOwned>(
         &self,
         partition: &str,
     ) -> Result<RowsResponse<R>> {
-        let path = format!("{}/_partition/{}/_all_docs", &self.name, partition);
+        let path = format!("{}/_all_docs", self.partition_path(partition)?);
         self.client
             .get(&path, Some(&[("include_docs", true)]))
             .await
-            .map_err(Error::from)
     }
 
     pub async fn put<T: Serialize>(&self, id: &str, entity: T) -> Result<PutResponse> {
@@ -136,12 +349,114 @@ impl Database {
         self.client
             .put(&path, Some(entity), None::<usize>)
             .await
-            .map_err(|err| match err.status() {
-                Some(StatusCode::CONFLICT) => Error::map_message(
-                    err,
-                    &format!("document {} already exists in database {}", &id, &self.name),
-                ),
-                _ => Error::from(err),
+            .map_err(|err| match err {
+                Error::Conflict(_) => Error::conflict(format!(
+                    "document {} already exists in database {}",
+                    &id, &self.name
+                )),
+                Error::TooLarge(_) => Error::too_large(format!(
+                    "document {} exceeds the maximum document size of database {}",
+                    &id, &self.name
+                )),
+                err => err,
+            })
+    }
+
+    /// Like [`put`], but remembers the write for a while so that [`get_consistent`] observes it
+    ///
+    /// [`put`]: Database::put
+    /// [`get_consistent`]: Database::get_consistent
+    pub async fn put_tracked<T: Serialize>(&self, id: &str, entity: T) -> Result<WriteToken> {
+        let res = self.put(id, entity).await?;
+        let token = WriteToken::from(&res);
+        self.recent_writes.record(&self.name, token.clone());
+        Ok(token)
+    }
+
+    /// Applies the change to the latest revision of the document and writes it back, reading it
+    /// again and retrying up to [`update::UPDATE_ATTEMPTS`] times when it was written
+    /// concurrently. Returns the changed document, failing with [`Error::NotFound`] if there is
+    /// no such document.
+    pub async fn update<T, F>(&self, id: &str, mut change: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnMut(&mut T),
+    {
+        let changed = self
+            .update_if(id, |doc| {
+                change(doc);
+                true
+            })
+            .await?;
+        changed.ok_or_else(|| {
+            Error::not_found(format!("document {} not found in database {}", id, &self.name))
+        })
+    }
+
+    /// Like [`update`], skipping the write when the change returns false.
+    /// Returns none if there is no such document.
+    ///
+    /// [`update`]: Database::update
+    pub async fn update_if<T, F>(&self, id: &str, change: F) -> Result<Option<T>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnMut(&mut T) -> bool,
+    {
+        update::update(self, id, change).await
+    }
+
+    /// Saves many documents in a single request. Documents succeed or fail on their own, so the
+    /// request only fails as a whole if CouchDB could not process it; the results tell which
+    /// documents were saved, in the order they were given.
+    pub async fn bulk_save<T: Serialize>(&self, docs: Vec<T>) -> Result<Vec<BulkResult>> {
+        if docs.is_empty() {
+            return Ok(Vec::new());
+        }
+        log::debug!("Saving {} documents into {}", docs.len(), &self.name);
+        self.bulk_docs(BulkDocs { docs }).await
+    }
+
+    /// Deletes many documents by ID and revision in a single request, like [`bulk_save`]
+    ///
+    /// [`bulk_save`]: Database::bulk_save
+    pub async fn bulk_delete(
+        &self,
+        ids_and_revs: Vec<(String, String)>,
+    ) -> Result<Vec<BulkResult>> {
+        if ids_and_revs.is_empty() {
+            return Ok(Vec::new());
+        }
+        log::debug!(
+            "Deleting {} documents from {}",
+            ids_and_revs.len(),
+            &self.name
+        );
+        let docs = ids_and_revs
+            .into_iter()
+            .map(|(id, rev)| Tombstone {
+                id,
+                rev,
+                deleted: true,
+            })
+            .collect();
+        let results = self.bulk_docs(BulkDocs { docs }).await?;
+        for res in results.iter().filter(|res| res.is_saved()) {
+            self.recent_writes.forget(&self.name, res.id());
+        }
+        Ok(results)
+    }
+
+    async fn bulk_docs<T: Serialize>(&self, body: BulkDocs<T>) -> Result<Vec<BulkResult>> {
+        let path = format!("{}/_bulk_docs", &self.name);
+        self.client
+            .post(&path, Some(body), None::<bool>)
+            .await
+            .map_err(|err| match err {
+                Error::TooLarge(_) => Error::too_large(format!(
+                    "bulk request exceeds the maximum request size of database {}",
+                    &self.name
+                )),
+                err => err,
             })
     }
 
@@ -150,9 +465,19 @@ impl Database {
         selector: serde_json::Value,
         limit: usize,
         bookmark: Option<String>,
+    ) -> Result<FindResponse<R>> {
+        let options = FindOptions::new().limit(limit).bookmark(bookmark);
+        self.find_with(selector, &options).await
+    }
+
+    /// Runs a Mango query with sorting, projection or pagination options
+    pub async fn find_with<R: DeserializeOwned>(
+        &self,
+        selector: serde_json::Value,
+        options: &FindOptions,
     ) -> Result<FindResponse<R>> {
         let path = format!("{}/_find", &self.name);
-        self.do_find(&path, selector, limit, bookmark).await
+        self.do_find(&path, selector, options).await
     }
 
     pub async fn find_partitioned<R: DeserializeOwned>(
@@ -162,38 +487,223 @@ impl Database {
         limit: usize,
         bookmark: Option<String>,
     ) -> Result<FindResponse<R>> {
-        let path = format!("{}/_partition/{}/_find", &self.name, partition);
-        self.do_find(&path, selector, limit, bookmark).await
+        let options = FindOptions::new().limit(limit).bookmark(bookmark);
+        self.find_partitioned_with(partition, selector, &options).await
+    }
+
+    /// Like [`find_with`], within a partition
+    ///
+    /// [`find_with`]: Database::find_with
+    pub async fn find_partitioned_with<R: DeserializeOwned>(
+        &self,
+        partition: &str,
+        selector: serde_json::Value,
+        options: &FindOptions,
+    ) -> Result<FindResponse<R>> {
+        let path = format!("{}/_find", self.partition_path(partition)?);
+        self.do_find(&path, selector, options).await
+    }
+
+    /// Number of documents in the partition, read from its metadata
+    pub async fn count_partition(&self, partition: &str) -> Result<usize> {
+        let path = self.partition_path(partition)?;
+        log::debug!("Counting documents of partition {}", &path);
+        let info: PartitionInfo = self.client.get(&path, None::<bool>).await?;
+        Ok(info.doc_count)
+    }
+
+    /// Number of documents matching the Mango selector, or of all documents without one.
+    /// All documents, design documents included, are counted from the metadata of the database,
+    /// matching ones like [`count_partitioned`] does.
+    ///
+    /// [`count_partitioned`]: Database::count_partitioned
+    pub async fn count(&self, selector: Option<serde_json::Value>) -> Result<usize> {
+        match selector {
+            Some(selector) => {
+                let path = format!("{}/_find", &self.name);
+                self.do_count(&path, selector).await
+            }
+            None => {
+                let info = self.get_self().await?;
+                Ok(info.doc_count.max(0) as usize)
+            }
+        }
+    }
+
+    /// Number of documents of the partition matching a Mango selector.
+    /// CouchDB cannot count them, so it pages through their IDs, never reading their bodies.
+    pub async fn count_partitioned(
+        &self,
+        partition: &str,
+        selector: serde_json::Value,
+    ) -> Result<usize> {
+        let path = format!("{}/_find", self.partition_path(partition)?);
+        self.do_count(&path, selector).await
+    }
+
+    /// Plans a partitioned Mango query without running it, telling which index it would use
+    pub async fn explain_partitioned(
+        &self,
+        partition: &str,
+        selector: serde_json::Value,
+    ) -> Result<ExplainResponse> {
+        let path = format!("{}/_explain", self.partition_path(partition)?);
+        let body = serde_json::json!({ "selector": selector });
+
+        log::debug!("Explaining query {} on {}", &body, &self.name);
+
+        self.client
+            .post(&path, Some(body), None::<bool>)
+            .await
+    }
+
+    async fn do_count(&self, path: &str, selector: serde_json::Value) -> Result<usize> {
+        let mut count = 0;
+        let mut bookmark: Option<String> = None;
+        loop {
+            let options = count_options(bookmark);
+            let res: FindResponse<serde_json::Value> =
+                self.do_find(path, selector.clone(), &options).await?;
+            count += res.docs.len();
+            if res.docs.len() < COUNT_BATCH_SIZE {
+                return Ok(count);
+            }
+            bookmark = Some(res.bookmark);
+        }
     }
 
     async fn do_find<R: DeserializeOwned>(
         &self,
         path: &str,
         selector: serde_json::Value,
-        limit: usize,
-        bookmark: Option<String>,
+        options: &FindOptions,
     ) -> Result<FindResponse<R>> {
-        let body = serde_json::json!({
-            "selector": selector,
-            "limit": limit,
-            "bookmark": bookmark
-        });
+        let body = options.body(selector);
 
         log::debug!("Finding from {} with query {}", &self.name, &body);
 
         self.client
             .post(path, Some(body), None::<bool>)
             .await
-            .map_err(Error::from)
     }
 
     pub async fn delete(&self, id: &str, rev: &str) -> Result<()> {
         let path = format!("{}/{}", &self.name, id);
         log::debug!("Deleting {} from couch", &path);
         self.client.delete(&path, Some(&[("rev", rev)])).await?;
+        self.recent_writes.forget(&self.name, id);
         Ok(())
     }
 
+    /// Adds the attachment to the document at the given revision, replacing any attachment with
+    /// the same name. Returns the new revision of the document.
+    pub async fn put_attachment(
+        &self,
+        id: &str,
+        rev: &str,
+        name: &str,
+        content_type: &str,
+        data: Bytes,
+    ) -> Result<PutResponse> {
+        attachment::check_size(name, data.len() as u64, self.attachment_limit)?;
+        self.send_attachment(id, rev, name, content_type, data.len() as u64, Body::from(data))
+            .await
+    }
+
+    /// Like [`put_attachment`], streaming the content instead of holding it in memory.
+    /// The length is the size of the content in bytes, checked against the limit before
+    /// anything is sent.
+    ///
+    /// [`put_attachment`]: Database::put_attachment
+    pub async fn put_attachment_stream<S>(
+        &self,
+        id: &str,
+        rev: &str,
+        name: &str,
+        content_type: &str,
+        length: u64,
+        content: S,
+    ) -> Result<PutResponse>
+    where
+        S: TryStream + Send + Sync + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        Bytes: From<S::Ok>,
+    {
+        attachment::check_size(name, length, self.attachment_limit)?;
+        let body = Body::wrap_stream(content);
+        self.send_attachment(id, rev, name, content_type, length, body)
+            .await
+    }
+
+    async fn send_attachment(
+        &self,
+        id: &str,
+        rev: &str,
+        name: &str,
+        content_type: &str,
+        length: u64,
+        body: Body,
+    ) -> Result<PutResponse> {
+        log::debug!("Putting attachment {} of {}/{} into couch", name, &self.name, id);
+        let req = self
+            .client
+            .build_binary_req(Method::PUT, &[&self.name, id, name])
+            .header(IF_MATCH, rev)
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, length)
+            .body(body);
+        let res = self.client.send(req).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Content type and content of the attachment of the document, failing with
+    /// [`Error::NotFound`] if there is none and with [`Error::TooLarge`] if it is over the
+    /// limit, see [`stream_attachment`] for those.
+    ///
+    /// [`stream_attachment`]: Database::stream_attachment
+    pub async fn get_attachment(&self, id: &str, name: &str) -> Result<(String, Bytes)> {
+        let (content_type, res) = self.fetch_attachment(id, name).await?;
+        if let Some(length) = res.content_length() {
+            attachment::check_size(name, length, self.attachment_limit)?;
+        }
+        let data = res.bytes().await?;
+        attachment::check_size(name, data.len() as u64, self.attachment_limit)?;
+        Ok((content_type, data))
+    }
+
+    /// Like [`get_attachment`], streaming the content of attachments of any size
+    ///
+    /// [`get_attachment`]: Database::get_attachment
+    pub async fn stream_attachment(
+        &self,
+        id: &str,
+        name: &str,
+    ) -> Result<(String, impl Stream<Item = reqwest::Result<Bytes>>)> {
+        let (content_type, res) = self.fetch_attachment(id, name).await?;
+        Ok((content_type, res.bytes_stream()))
+    }
+
+    async fn fetch_attachment(&self, id: &str, name: &str) -> Result<(String, Response)> {
+        log::debug!("Getting attachment {} of {}/{} from couch", name, &self.name, id);
+        let req = self
+            .client
+            .build_binary_req(Method::GET, &[&self.name, id, name]);
+        let res = self.client.send(req).await?;
+        Ok((attachment::content_type(&res), res))
+    }
+
+    /// Removes the attachment from the document at the given revision, returning the new
+    /// revision of the document
+    pub async fn delete_attachment(&self, id: &str, rev: &str, name: &str) -> Result<PutResponse> {
+        log::debug!("Deleting attachment {} of {}/{} from couch", name, &self.name, id);
+        let req = self
+            .client
+            .build_binary_req(Method::DELETE, &[&self.name, id, name])
+            .header(IF_MATCH, rev);
+        let res = self.client.send(req).await?;
+        Ok(res.json().await?)
+    }
+
     pub async fn exists(&self, id: &str) -> Result<bool> {
         let path = format!("{}/{}", &self.name, id);
         log::debug!("Checking {} existence from couch", &path);
@@ -201,37 +711,38 @@ impl Database {
         Ok(res)
     }
 
-    pub async fn changes(&self) -> Result<impl futures::Stream<Item = ChangeEvent>> {
+    /// Follows the continuous changes feed of the database since the given sequence, or from
+    /// now if there is none.
+    ///
+    /// The stream never ends: a dropped connection is resumed from the last sequence received,
+    /// so events are delivered in order but at least once. See [`crate::changes`].
+    pub fn changes(&self, since: Option<String>) -> impl Stream<Item = ChangeEvent> {
+        self.follow_changes(since, changes::RECONNECT_DELAY)
+    }
+
+    fn follow_changes(
+        &self,
+        since: Option<String>,
+        delay: std::time::Duration,
+    ) -> impl Stream<Item = ChangeEvent> {
+        let db = self.clone();
+        changes::follow(since, delay, move |since| {
+            let db = db.clone();
+            async move { db.connect_changes(since).await }
+        })
+    }
+
+    async fn connect_changes(&self, since: String) -> Result<changes::Events> {
         let path = format!("{}/_changes", &self.name);
-        let query = Some(&[("feed", "continuous"), ("since", "now")]);
-        let stream = self.client.stream(&path, query).await?;
-        let stream = stream
-            .map(|res: reqwest::Result<Bytes>| {
-                let bytes = res.unwrap();
-                let payload = from_utf8(bytes.as_ref()).unwrap();
-                let cursor = Cursor::new(payload);
-                let events: Vec<ChangeEvent> = cursor
-                    .lines()
-                    .filter_map(|line| match line {
-                        Ok(line) => {
-                            if line.is_empty() {
-                                None
-                            } else {
-                                log::debug!("Processing event: {}", &line);
-                                let event: ChangeEvent = serde_json::from_str(&line).unwrap();
-                                Some(event)
-                            }
-                        }
-                        Err(err) => {
-                            log::error!("{}", err.to_string());
-                            None
-                        }
-                    })
-                    .collect();
-                stream::iter(events)
-            })
-            .flatten();
-        Ok(stream)
+        log::debug!("Following changes of {} since {}", &self.name, &since);
+        // Owned, as the stream of events outlives this call
+        let query = Some(vec![
+            ("feed", "continuous".to_string()),
+            ("since", since),
+            ("heartbeat", changes::HEARTBEAT_MILLIS.to_string()),
+        ]);
+        let chunks = self.client.stream(&path, query).await?;
+        Ok(changes::boxed(changes::parse_events(chunks)))
     }
 }
 
@@ -239,5 +750,303 @@ impl Database {
 struct ListQuery {
     pub include_docs: bool,
     pub limit: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub skip: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub start_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_key: Option<String>,
+}
+
+/// Page of a count, only projecting the IDs of the matching documents
+fn count_options(bookmark: Option<String>) -> FindOptions {
+    FindOptions::new()
+        .fields(&["_id"])
+        .limit(COUNT_BATCH_SIZE)
+        .bookmark(bookmark)
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// Deserializes the documents of rows, leaving out design documents
+fn typed_rows<R: DeserializeOwned>(
+    rows: Vec<RawDocResponse<serde_json::Value>>,
+) -> Result<Vec<RawDocResponse<R>>> {
+    rows.into_iter()
+        .filter(|row| !row.id.starts_with(DESIGN_PREFIX))
+        .map(|row| {
+            let id = row.id;
+            let doc = serde_json::from_value(row.doc)
+                .map_err(|err| Error::internal(format!("document {}: {}", id, err)))?;
+            Ok(RawDocResponse {
+                id,
+                key: row.key,
+                value: row.value,
+                doc,
+            })
+        })
+        .collect()
+}
+
+/// Deserializes the documents of a `_bulk_get`, in the order of the IDs requested
+fn docs_in_order<R: DeserializeOwned>(
+    ids: &[String],
+    res: BulkGetResponse,
+) -> Result<Vec<Option<R>>> {
+    let mut docs = HashMap::with_capacity(res.results.len());
+    for result in res.results {
+        let id = result.id.clone();
+        docs.insert(id, result.into_doc()?);
+    }
+    ids.iter()
+        .map(|id| match docs.get(id) {
+            Some(Some(doc)) => serde_json::from_value(doc.clone())
+                .map(Some)
+                .map_err(|err| Error::internal(format!("document {}: {}", id, err))),
+            Some(None) => Ok(None),
+            None => Err(Error::internal(format!("no result for document {}", id))),
+        })
+        .collect()
+}
+
+/// Keys of `_all_docs` are sent as JSON strings
+fn encode_key(key: &str) -> String {
+    serde_json::Value::from(key).to_string()
+}
+
+#[derive(Serialize)]
+struct BulkDocs<T: Serialize> {
+    docs: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct BulkGet<'a> {
+    docs: Vec<BulkGetId<'a>>,
+}
+
+#[derive(Serialize)]
+struct BulkGetId<'a> {
+    id: &'a str,
+}
+
+/// Document deleting another in a bulk request
+#[derive(Serialize)]
+struct Tombstone {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(rename = "_rev")]
+    rev: String,
+    #[serde(rename = "_deleted")]
+    deleted: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use serde::Deserialize;
+    use url::Url;
+
+    use crate::client::AuthMethod;
+    use crate::Couch;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct User {
+        #[serde(rename = "_id")]
+        id: String,
+        username: String,
+    }
+
+    /// Serves a changes feed dropping the connection after each event, recording the `since` of
+    /// each connection
+    fn serve_dropping_changes(sinces: Arc<Mutex<Vec<String>>>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for (n, conn) in listener.incoming().enumerate() {
+                let mut conn = conn.unwrap();
+                let mut reader = BufReader::new(conn.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                let since = line
+                    .split(['?', '&', ' '])
+                    .find(|param| param.starts_with("since="))
+                    .map(|param| param["since=".len()..].to_string())
+                    .unwrap();
+                sinces.lock().unwrap().push(since);
+                let event = format!(
+                    "{{\"seq\":\"{}-x\",\"id\":\"user:jdoe\",\"changes\":[{{\"rev\":\"1-a\"}}]}}\n",
+                    n + 1
+                );
+                write!(
+                    conn,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n{}",
+                    event
+                )
+                .unwrap();
+            }
+        });
+        Url::parse(&url).unwrap()
+    }
+
+    #[test]
+    fn it_resumes_dropped_changes_feeds_from_the_last_seq() {
+        let sinces = Arc::new(Mutex::new(Vec::new()));
+        let mut couch = Couch::new(
+            serve_dropping_changes(sinces.clone()),
+            "enseada".to_string(),
+            "enseada".to_string(),
+        );
+        couch.set_auth_method(AuthMethod::Basic);
+        let db = couch.database("users", true);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let feed = db.follow_changes(None, Duration::from_millis(1));
+        let events: Vec<ChangeEvent> = rt.block_on(feed.take(3).collect());
+
+        let seqs: Vec<&str> = events.iter().map(ChangeEvent::seq).collect();
+        assert_eq!(seqs, vec!["1-x", "2-x", "3-x"]);
+        assert_eq!(*sinces.lock().unwrap(), vec!["now", "1-x", "2-x"]);
+    }
+
+    #[test]
+    fn it_keeps_the_order_of_bulk_gets() {
+        let res: BulkGetResponse = serde_json::from_value(serde_json::json!({
+            "results": [
+                {
+                    "id": "user:root",
+                    "docs": [{ "ok": { "_id": "user:root", "_rev": "1-a", "username": "root" } }]
+                },
+                {
+                    "id": "user:gone",
+                    "docs": [{
+                        "error": {
+                            "id": "user:gone",
+                            "rev": "undefined",
+                            "error": "not_found",
+                            "reason": "missing"
+                        }
+                    }]
+                },
+                {
+                    "id": "user:jdoe",
+                    "docs": [{ "ok": { "_id": "user:jdoe", "_rev": "3-c", "username": "jdoe" } }]
+                }
+            ]
+        }))
+        .unwrap();
+        let ids: Vec<String> = vec!["user:jdoe", "user:gone", "user:root"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let users: Vec<Option<User>> = docs_in_order(&ids, res).unwrap();
+        let usernames: Vec<Option<&str>> = users
+            .iter()
+            .map(|user| user.as_ref().map(|user| user.username.as_str()))
+            .collect();
+        assert_eq!(usernames, vec![Some("jdoe"), None, Some("root")]);
+    }
+
+    #[test]
+    fn it_skips_design_documents_when_listing() {
+        let res: RowsResponse<serde_json::Value> = serde_json::from_value(serde_json::json!({
+            "offset": 0,
+            "total_rows": 3,
+            "rows": [
+                {
+                    "id": "_design/users_indexes",
+                    "key": "_design/users_indexes",
+                    "value": { "rev": "1-a" },
+                    "doc": { "_id": "_design/users_indexes", "language": "query" }
+                },
+                {
+                    "id": "user:jdoe",
+                    "key": "user:jdoe",
+                    "value": { "rev": "3-c" },
+                    "doc": { "_id": "user:jdoe", "_rev": "3-c", "username": "jdoe" }
+                }
+            ]
+        }))
+        .unwrap();
+        let rows: Vec<RawDocResponse<User>> = typed_rows(res.rows).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].value.rev, "3-c");
+        assert_eq!(
+            rows[0].doc,
+            User {
+                id: "user:jdoe".to_string(),
+                username: "jdoe".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn it_refuses_partition_queries_on_unpartitioned_databases() {
+        let couch = crate::Couch::new(
+            url::Url::parse("http://localhost:5984").unwrap(),
+            "enseada".to_string(),
+            "enseada".to_string(),
+        );
+
+        let err = couch.database("logs", false).partition_path("user").unwrap_err();
+        assert!(err.to_string().contains("not partitioned"));
+
+        let users = couch.database("users", true);
+        assert_eq!(users.partition_path("user").unwrap(), "users/_partition/user");
+        assert!(users.partition_path("").is_err());
+        assert!(users.partition_path("_design").is_err());
+        assert!(users.partition_path("user/../x").is_err());
+    }
+
+    #[test]
+    fn it_counts_without_reading_documents() {
+        let selector = serde_json::json!({ "revoked": false });
+        assert_eq!(
+            count_options(None).body(selector.clone()),
+            serde_json::json!({
+                "selector": selector,
+                "fields": ["_id"],
+                "limit": COUNT_BATCH_SIZE,
+            })
+        );
+        let next = count_options(Some("g1AAAA".to_string())).body(selector);
+        assert_eq!(next["bookmark"], "g1AAAA");
+        assert_eq!(next["fields"], serde_json::json!(["_id"]));
+    }
+
+    #[test]
+    fn it_sends_keys_as_json() {
+        let query = ListQuery {
+            include_docs: true,
+            limit: 10,
+            skip: 0,
+            start_key: Some(encode_key("user:a")),
+            end_key: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&query).unwrap(),
+            serde_json::json!({ "include_docs": true, "limit": 10, "start_key": "\"user:a\"" })
+        );
+    }
 }
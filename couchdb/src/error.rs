@@ -2,55 +2,201 @@ use std::fmt::Display;
 
 use reqwest::StatusCode;
 use serde::export::Formatter;
+use serde::Deserialize;
 
-use crate::migrator;
+use crate::{data_migration, guid, migration, migrator};
 
 #[derive(Debug, PartialEq, Eq)]
-pub struct Error {
-    message: String,
-    status: StatusCode,
+pub enum Error {
+    /// CouchDB refused the credentials of the client
+    Unauthorized(String),
+    /// The credentials of the client do not allow the request, or a validation function
+    /// refused the document
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// A precondition of the request failed, like creating a database that already exists
+    PreconditionFailed(String),
+    /// The document is over the maximum document size
+    TooLarge(String),
+    TooManyRequests(String),
+    /// CouchDB did not answer in time
+    Timeout(String),
+    /// Any other error answered by CouchDB, with its status
+    Server(StatusCode, String),
+    /// Errors CouchDB did not answer with, like failed connections or unexpected responses
+    Internal(String),
+}
+
+/// Body of the error responses of CouchDB
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: String,
+    reason: String,
 }
 
 impl Error {
     pub fn not_found(message: String) -> Self {
-        Error {
-            message,
-            status: StatusCode::NOT_FOUND,
+        Error::NotFound(message)
+    }
+
+    pub fn conflict(message: String) -> Self {
+        Error::Conflict(message)
+    }
+
+    pub fn internal(message: String) -> Self {
+        Error::Internal(message)
+    }
+
+    pub fn too_large(message: String) -> Self {
+        Error::TooLarge(message)
+    }
+
+    pub fn timeout(message: String) -> Self {
+        Error::Timeout(message)
+    }
+
+    /// Maps an error response of CouchDB, with a `{"error": "...", "reason": "..."}` body,
+    /// to the error of its status
+    pub fn from_response(status: StatusCode, body: &str) -> Self {
+        let message = match serde_json::from_str::<ErrorBody>(body) {
+            Ok(body) => format!("{}: {}", body.error, body.reason),
+            Err(_) if !body.trim().is_empty() => body.trim().to_string(),
+            Err(_) => status
+                .canonical_reason()
+                .unwrap_or("unknown error")
+                .to_string(),
+        };
+        match status {
+            StatusCode::UNAUTHORIZED => Error::Unauthorized(message),
+            StatusCode::FORBIDDEN => Error::Forbidden(message),
+            StatusCode::NOT_FOUND => Error::NotFound(message),
+            StatusCode::CONFLICT => Error::Conflict(message),
+            StatusCode::PRECONDITION_FAILED => Error::PreconditionFailed(message),
+            StatusCode::PAYLOAD_TOO_LARGE => Error::TooLarge(message),
+            StatusCode::TOO_MANY_REQUESTS => Error::TooManyRequests(message),
+            StatusCode::GATEWAY_TIMEOUT => Error::Timeout(message),
+            status => Error::Server(status, message),
         }
     }
 
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout(_))
+    }
+
+    pub fn is_too_large(&self) -> bool {
+        matches!(self, Error::TooLarge(_))
+    }
+
     pub fn status(&self) -> StatusCode {
-        self.status
+        match self {
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Server(status, _) => *status,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 
-    pub(super) fn map_message(err: reqwest::Error, message: &str) -> Error {
-        let mut mapped = Self::from(err);
-        mapped.message = message.to_string();
-        mapped
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::NotFound(message)
+            | Error::Conflict(message)
+            | Error::PreconditionFailed(message)
+            | Error::TooLarge(message)
+            | Error::TooManyRequests(message)
+            | Error::Timeout(message)
+            | Error::Server(_, message)
+            | Error::Internal(message) => message,
+        }
     }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        self.message.fmt(f)
+        self.message().fmt(f)
     }
 }
 
 impl std::error::Error for Error {}
 
+/// Errors of requests CouchDB did not answer with an error response
 impl From<reqwest::Error> for Error {
     fn from(err: reqwest::Error) -> Self {
         let message = err.to_string();
-        let status = err.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-        Error { status, message }
+        if err.is_timeout() {
+            return Error::timeout(message);
+        }
+        match err.status() {
+            Some(status) => Error::from_response(status, &message),
+            None => Error::internal(message),
+        }
     }
 }
 
 impl From<migrator::MigrationError> for Error {
     fn from(err: migrator::MigrationError) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: err.to_string(),
-        }
+        Error::internal(err.to_string())
+    }
+}
+
+impl From<guid::GuidError> for Error {
+    fn from(err: guid::GuidError) -> Self {
+        Error::internal(err.to_string())
+    }
+}
+
+impl From<migration::MigrationsError> for Error {
+    fn from(err: migration::MigrationsError) -> Self {
+        Error::internal(err.to_string())
+    }
+}
+
+impl From<data_migration::DataMigrationError> for Error {
+    fn from(err: data_migration::DataMigrationError) -> Self {
+        Error::internal(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_error_responses() {
+        let body = r#"{"error":"unauthorized","reason":"Name or password is incorrect."}"#;
+        assert_eq!(
+            Error::from_response(StatusCode::UNAUTHORIZED, body),
+            Error::Unauthorized("unauthorized: Name or password is incorrect.".to_string())
+        );
+        let body = r#"{"error":"file_exists","reason":"The database could not be created."}"#;
+        let err = Error::from_response(StatusCode::PRECONDITION_FAILED, body);
+        assert_eq!(err.status(), StatusCode::PRECONDITION_FAILED);
+        assert!(matches!(err, Error::PreconditionFailed(_)));
+        assert!(matches!(
+            Error::from_response(StatusCode::FORBIDDEN, "{}"),
+            Error::Forbidden(_)
+        ));
+    }
+
+    #[test]
+    fn it_keeps_the_status_of_other_server_errors() {
+        let err = Error::from_response(StatusCode::SERVICE_UNAVAILABLE, "");
+        assert_eq!(
+            err,
+            Error::Server(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable".to_string())
+        );
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            Error::from_response(StatusCode::BAD_GATEWAY, "upstream down\n").to_string(),
+            "upstream down"
+        );
     }
 }
@@ -0,0 +1,107 @@
+//! Options of Mango queries, passed along with the selector to `_find`.
+use serde::Serialize;
+
+/// Sorting, projection and pagination of a Mango query, built like
+/// `FindOptions::new().sort_desc("created_at").limit(50)`.
+///
+/// Sorting needs an index of the sorted fields, otherwise CouchDB rejects the query.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FindOptions {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sort: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skip: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bookmark: Option<String>,
+}
+
+impl FindOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sorts by the field in ascending order, after the fields already sorted by
+    pub fn sort_asc(self, field: &str) -> Self {
+        self.sort(field, "asc")
+    }
+
+    /// Sorts by the field in descending order, after the fields already sorted by
+    pub fn sort_desc(self, field: &str) -> Self {
+        self.sort(field, "desc")
+    }
+
+    fn sort(mut self, field: &str, direction: &str) -> Self {
+        let mut order = serde_json::Map::new();
+        order.insert(field.to_string(), direction.into());
+        self.sort.push(serde_json::Value::Object(order));
+        self
+    }
+
+    /// Only returns these fields of the documents
+    pub fn fields(mut self, fields: &[&str]) -> Self {
+        self.fields = Some(fields.iter().map(|field| field.to_string()).collect());
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn skip(mut self, skip: usize) -> Self {
+        self.skip = Some(skip);
+        self
+    }
+
+    /// Resumes from the bookmark of the previous page, if any
+    pub fn bookmark(mut self, bookmark: Option<String>) -> Self {
+        self.bookmark = bookmark;
+        self
+    }
+
+    /// Body of the `_find` request for the selector
+    pub(crate) fn body(&self, selector: serde_json::Value) -> serde_json::Value {
+        let mut body = serde_json::to_value(self).unwrap_or_else(|_| serde_json::json!({}));
+        body["selector"] = selector;
+        body
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_only_sends_the_options_given() {
+        let selector = json!({ "kind": "public" });
+        assert_eq!(
+            FindOptions::new().body(selector.clone()),
+            json!({ "selector": { "kind": "public" } })
+        );
+
+        let options = FindOptions::new()
+            .sort_desc("created_at")
+            .sort_asc("_id")
+            .fields(&["_id", "created_at"])
+            .limit(50)
+            .skip(10)
+            .bookmark(Some("g1AAAA".to_string()));
+        assert_eq!(
+            options.body(selector),
+            json!({
+                "selector": { "kind": "public" },
+                "sort": [{ "created_at": "desc" }, { "_id": "asc" }],
+                "fields": ["_id", "created_at"],
+                "limit": 50,
+                "skip": 10,
+                "bookmark": "g1AAAA"
+            })
+        );
+    }
+}
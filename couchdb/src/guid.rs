@@ -0,0 +1,240 @@
+//! IDs of documents, optionally in a partition, like `user:jdoe`.
+//!
+//! [`Guid::partitioned`] and [`Guid::simple`] validate the IDs they build against the
+//! constraints of CouchDB, so that input like a username cannot address a reserved document
+//! or another partition. IDs read back from CouchDB are parsed as they are with `From<String>`.
+use std::fmt::Display;
+
+use serde::export::Formatter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use snafu::Snafu;
+
+/// Longest ID built, in bytes. CouchDB does not limit IDs by default, but they are repeated in
+/// every index and view row.
+pub const MAX_LENGTH: usize = 512;
+
+#[derive(Debug, PartialEq, Eq, Snafu)]
+pub enum GuidError {
+    #[snafu(display("The {} of a document ID cannot be empty", part))]
+    Empty { part: &'static str },
+    #[snafu(display("The {} '{}' cannot start with '_', reserved by CouchDB", part, value))]
+    Reserved { part: &'static str, value: String },
+    #[snafu(display("The partition '{}' cannot contain ':'", partition))]
+    Separator { partition: String },
+    #[snafu(display("The {} of a document ID cannot contain control characters", part))]
+    ControlCharacter { part: &'static str },
+    #[snafu(display("Document IDs cannot be longer than {} bytes, got {}", MAX_LENGTH, length))]
+    TooLong { length: usize },
+}
+
+#[derive(Clone, Debug)]
+pub struct Guid {
+    partition: Option<String>,
+    id: String,
+}
+
+impl Guid {
+    pub fn simple(id: &str) -> Result<Self, GuidError> {
+        validate("ID", id)?;
+        validate_length(id.len())?;
+        Ok(Guid {
+            partition: None,
+            id: id.to_string(),
+        })
+    }
+
+    pub fn partitioned(partition: &str, id: &str) -> Result<Self, GuidError> {
+        validate("partition", partition)?;
+        if partition.contains(':') {
+            return Err(GuidError::Separator {
+                partition: partition.to_string(),
+            });
+        }
+        validate("ID", id)?;
+        validate_length(partition.len() + 1 + id.len())?;
+        Ok(Guid {
+            partition: Some(partition.to_string()),
+            id: id.to_string(),
+        })
+    }
+
+    pub fn partition(&self) -> Option<&str> {
+        self.partition.as_deref()
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+fn validate(part: &'static str, value: &str) -> Result<(), GuidError> {
+    if value.is_empty() {
+        return Err(GuidError::Empty { part });
+    }
+    if value.starts_with('_') {
+        return Err(GuidError::Reserved {
+            part,
+            value: value.to_string(),
+        });
+    }
+    if value.chars().any(char::is_control) {
+        return Err(GuidError::ControlCharacter { part });
+    }
+    Ok(())
+}
+
+fn validate_length(length: usize) -> Result<(), GuidError> {
+    if length > MAX_LENGTH {
+        Err(GuidError::TooLong { length })
+    } else {
+        Ok(())
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let partition = self
+            .partition()
+            .map(|s| format!("{}:", s))
+            .unwrap_or_default();
+        write!(f, "{}{}", partition, &self.id)
+    }
+}
+
+/// Parses an ID as it is, like one read from CouchDB, without validating it
+impl From<String> for Guid {
+    fn from(s: String) -> Self {
+        if s.contains(':') {
+            let p: Vec<&str> = s.splitn(2, ':').collect();
+            let partition = p.first().cloned().map(String::from);
+            let id = p.get(1).cloned().map(String::from).unwrap();
+            Guid { partition, id }
+        } else {
+            Guid {
+                partition: None,
+                id: s,
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Guid {
+    fn from(s: &'a str) -> Self {
+        Guid::from(s.to_string())
+    }
+}
+
+impl Serialize for Guid {
+    fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
+    where
+        S: Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Guid {
+    fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(Guid::from(s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::guid::{Guid, GuidError, MAX_LENGTH};
+
+    #[test]
+    fn it_converts_from_string_with_partition() {
+        let s = String::from("part:id:id");
+        let guid = Guid::from(s.as_str());
+        assert_eq!(guid.partition(), Some("part"));
+        assert_eq!(guid.id(), &String::from("id:id"));
+    }
+
+    #[test]
+    fn it_converts_from_string_without_partition() {
+        let s = String::from("no-partition");
+        let guid = Guid::from(s.as_str());
+        assert_eq!(guid.partition(), None);
+        assert_eq!(guid.id(), &s);
+    }
+
+    #[test]
+    fn it_converts_from_string_and_to_string() {
+        let s = String::from("part:id");
+        let guid = Guid::from(s.as_str());
+        assert_eq!(guid.to_string(), s);
+    }
+
+    #[test]
+    fn it_serializes() {
+        let s = String::from("part:id");
+        let guid = Guid::from(s.as_str());
+        let json = serde_json::to_string(&guid).unwrap();
+        assert_eq!(json, format!("\"{}\"", &s));
+    }
+
+    #[test]
+    fn it_deserializes() {
+        let s = String::from("part:id");
+        let guid: Guid = serde_json::from_str("\"part:id\"").unwrap();
+
+        assert_eq!(guid.partition(), Some("part"));
+        assert_eq!(guid.id(), &String::from("id"));
+        assert_eq!(guid.to_string(), s);
+    }
+
+    #[test]
+    fn it_builds_valid_ids() {
+        let guid = Guid::partitioned("user", "jdoe").unwrap();
+        assert_eq!(guid.to_string(), "user:jdoe");
+        let guid = Guid::simple("users").unwrap();
+        assert_eq!(guid.partition(), None);
+        assert_eq!(guid.to_string(), "users");
+    }
+
+    #[test]
+    fn it_rejects_invalid_ids() {
+        assert_eq!(
+            Guid::partitioned("", "jdoe").unwrap_err(),
+            GuidError::Empty { part: "partition" }
+        );
+        assert_eq!(Guid::simple("").unwrap_err(), GuidError::Empty { part: "ID" });
+        assert_eq!(
+            Guid::partitioned("user", "_design").unwrap_err(),
+            GuidError::Reserved {
+                part: "ID",
+                value: "_design".to_string()
+            }
+        );
+        assert_eq!(
+            Guid::partitioned("_local", "jdoe").unwrap_err(),
+            GuidError::Reserved {
+                part: "partition",
+                value: "_local".to_string()
+            }
+        );
+        assert_eq!(
+            Guid::partitioned("user:admin", "jdoe").unwrap_err(),
+            GuidError::Separator {
+                partition: "user:admin".to_string()
+            }
+        );
+        assert_eq!(
+            Guid::partitioned("user", "jdoe\n").unwrap_err(),
+            GuidError::ControlCharacter { part: "ID" }
+        );
+        let long = "a".repeat(MAX_LENGTH);
+        assert_eq!(
+            Guid::partitioned("user", &long).unwrap_err(),
+            GuidError::TooLong {
+                length: MAX_LENGTH + 5
+            }
+        );
+        assert!(Guid::simple(&long).is_ok());
+    }
+}
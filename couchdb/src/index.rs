@@ -1,4 +1,4 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize)]
 pub struct JsonIndex {
@@ -19,7 +19,64 @@ impl JsonIndex {
         }
     }
 
+    /// Index of the fields, in order, only holding the documents matching the partial filter
+    /// selector if any. CouchDB only uses partial indexes for queries naming them.
+    pub fn fields(
+        name: &str,
+        ddoc: Option<String>,
+        fields: &[&str],
+        partial_filter: Option<serde_json::Value>,
+    ) -> Self {
+        let mut index = serde_json::json!({ "fields": fields });
+        if let Some(selector) = partial_filter {
+            index["partial_filter_selector"] = selector;
+        }
+        Self::new(name, ddoc, index)
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 }
+
+/// Index of a database, as listed by CouchDB
+#[derive(Debug, Deserialize)]
+pub struct IndexInfo {
+    /// Design document of the index, none for the special `_all_docs` index
+    pub ddoc: Option<String>,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub def: serde_json::Value,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_defines_partial_indexes_of_fields() {
+        let index = JsonIndex::fields(
+            "token_user_idx",
+            Some("oauth_indexes".to_string()),
+            &["session.user_id"],
+            Some(serde_json::json!({ "revoked": { "$ne": true } })),
+        );
+        assert_eq!(
+            serde_json::to_value(&index).unwrap(),
+            serde_json::json!({
+                "index": {
+                    "fields": ["session.user_id"],
+                    "partial_filter_selector": { "revoked": { "$ne": true } }
+                },
+                "name": "token_user_idx",
+                "ddoc": "oauth_indexes",
+                "type": "json"
+            })
+        );
+
+        let index = JsonIndex::fields("expiration_idx", None, &["expiration"], None);
+        let index = serde_json::to_value(&index).unwrap();
+        assert_eq!(index["index"], serde_json::json!({ "fields": ["expiration"] }));
+    }
+}
@@ -1,38 +1,135 @@
+//! Async CouchDB client used by Enseada.
+//!
+//! [`Couch`] is the entrypoint, giving access to [`db::Database`] handles.
+//! Documents are identified by [`guid::Guid`], which supports partitioned databases,
+//! and schema changes can be applied with the [`migrator`] framework.
+//! Other changes are applied once each as versioned [`migration`]s.
+//! Existing documents can be rewritten with a resumable [`data_migration::DataMigration`],
+//! and kept under the maximum document size with a [`size::SizeGuard`].
+//! Reads following a write on a cluster can opt into [`consistency`] with the write.
+//! Changes of a database can be followed with a reconnecting [`changes`] feed.
+//! Documents can carry binary [`attachment`]s.
+//! Documents are changed in place with [`db::Database::update`], which retries on conflicts.
+//! Map/reduce [`view`]s are defined by design documents installed only when they change.
 use std::sync::Arc;
+use std::time::Duration;
 
 use url::Url;
 
-use crate::client::Client;
+use crate::client::{AuthMethod, Client, Pool, Timeouts};
+use crate::consistency::RecentWrites;
 use crate::db::Database;
 use crate::error::Error;
-use crate::status::Status;
+use crate::status::{ServerInfo, Status};
 
+pub mod attachment;
 pub mod changes;
 pub mod client;
+pub mod consistency;
+pub mod data_migration;
 pub mod db;
 pub mod error;
+pub mod find;
+pub mod guid;
 pub mod index;
+pub mod migration;
 pub mod migrator;
 pub mod responses;
+pub mod size;
 pub mod status;
+pub mod update;
+pub mod view;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Connection to a CouchDB server, cheap to share across databases
 pub struct Couch {
     client: Arc<Client>,
+    recent_writes: Arc<RecentWrites>,
+    attachment_limit: usize,
 }
 
 impl Couch {
     pub fn new(url: Url, username: String, password: String) -> Self {
-        let client = Arc::new(Client::new(url, username, password));
-        Couch { client }
+        Self::with_timeouts(url, username, password, Timeouts::default())
     }
 
+    pub fn with_timeouts(url: Url, username: String, password: String, timeouts: Timeouts) -> Self {
+        Self::with_pool(url, username, password, timeouts, Pool::default())
+    }
+
+    pub fn with_pool(
+        url: Url,
+        username: String,
+        password: String,
+        timeouts: Timeouts,
+        pool: Pool,
+    ) -> Self {
+        let client = Arc::new(Client::new(url, username, password, timeouts, pool));
+        Couch {
+            client,
+            recent_writes: Arc::new(RecentWrites::default()),
+            attachment_limit: attachment::DEFAULT_LIMIT,
+        }
+    }
+
+    /// Sets how requests authenticate, with a session cookie by default
+    pub fn set_auth_method(&mut self, auth: AuthMethod) -> &mut Self {
+        Arc::make_mut(&mut self.client).set_auth_method(auth);
+        self
+    }
+
+    /// Sets the size in bytes of the largest attachment written or buffered by the databases
+    pub fn set_attachment_limit(&mut self, limit: usize) -> &mut Self {
+        self.attachment_limit = limit;
+        self
+    }
+
+    /// Returns a connection whose requests all get the long-running timeout, for migrations
+    /// and other maintenance touching many documents at once
+    pub fn long_running(&self) -> Self {
+        Couch {
+            client: Arc::new(self.client.long_running()),
+            recent_writes: self.recent_writes.clone(),
+            attachment_limit: self.attachment_limit,
+        }
+    }
+
+    /// Returns a handle to the named database, which is not created if missing
     pub fn database(&self, name: &str, partitioned: bool) -> Database {
-        Database::new(self.client.clone(), name.to_string(), partitioned)
+        Database::new(
+            self.client.clone(),
+            name.to_string(),
+            partitioned,
+            self.recent_writes.clone(),
+            self.attachment_limit,
+        )
+    }
+
+    /// Opens a connection to CouchDB, and a session if it authenticates with one, so that the
+    /// first requests don't wait for them
+    pub async fn ping(&self) -> Result<()> {
+        self.status().await.map(|_| ())
     }
 
-    pub async fn status(&self) -> reqwest::Result<Status> {
+    /// Status of the server. Fails with [`Error::Unauthorized`] if it refuses the credentials.
+    pub async fn status(&self) -> Result<Status> {
         self.client.get("/_up", None::<bool>).await
     }
+
+    /// Like [`status`], failing with [`Error::Timeout`] if the server does not answer in time,
+    /// opening a session included, for health checks that must answer quickly
+    ///
+    /// [`status`]: Couch::status
+    pub async fn status_within(&self, timeout: Duration) -> Result<Status> {
+        tokio::time::timeout(timeout, self.status())
+            .await
+            .map_err(|_| Error::timeout(format!("CouchDB did not answer within {:?}", timeout)))?
+    }
+
+    /// Version of the server
+    pub async fn version(&self) -> Result<String> {
+        let info: ServerInfo = self.client.get("/", None::<bool>).await?;
+        Ok(info.version)
+    }
 }
@@ -0,0 +1,299 @@
+//! Versioned migrations, each applied once.
+//!
+//! Unlike the operations of the [`migrator`], which are idempotent and run on every startup,
+//! a [`Migration`] runs until it succeeds once. The IDs of the migrations applied to a database
+//! are recorded, with when they were applied, in its `_local/migrations` document, which is not
+//! replicated, so that [`Migrations::run`] skips them afterwards. Migrations run in the order
+//! they are registered, and the first failure stops the run, naming the failing migration.
+//!
+//! [`migrator`]: crate::migrator
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use crate::error::Error;
+use crate::Couch;
+
+/// ID of the document recording the migrations applied to a database
+pub const APPLIED_ID: &str = "_local/migrations";
+
+#[derive(Debug, Snafu)]
+pub enum MigrationsError {
+    #[snafu(display("Failed to read the migrations applied to database {}: {}", database, source))]
+    LoadApplied { database: String, source: Error },
+    #[snafu(display("Migration {} failed: {}", id, source))]
+    Failed { id: String, source: Error },
+    #[snafu(display("Failed to record migration {} as applied: {}", id, source))]
+    Record { id: String, source: Error },
+}
+
+#[async_trait]
+pub trait Migration: Send + Sync {
+    /// Unique ID of the migration, which must never change once released
+    fn id(&self) -> &str;
+
+    /// Database the migration is recorded in, which must exist once it ran
+    fn database(&self) -> &str;
+
+    async fn run(&self, couch: &Couch) -> crate::Result<()>;
+}
+
+/// Migrations applied to a database, oldest first
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Applied {
+    #[serde(rename = "_rev", default, skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    pub migrations: Vec<AppliedMigration>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AppliedMigration {
+    pub id: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+impl Applied {
+    pub fn contains(&self, id: &str) -> bool {
+        self.migrations.iter().any(|migration| migration.id == id)
+    }
+}
+
+/// Where the applied migrations of each database are recorded
+#[async_trait]
+pub(crate) trait Ledger: Send + Sync {
+    /// Migrations applied to the database, none if it does not exist yet
+    async fn applied(&self, database: &str) -> crate::Result<Applied>;
+
+    async fn record(&self, database: &str, applied: &Applied) -> crate::Result<()>;
+}
+
+#[async_trait]
+impl Ledger for Couch {
+    async fn applied(&self, database: &str) -> crate::Result<Applied> {
+        // A missing database reads like a missing document
+        let db = self.database(database, false);
+        Ok(db.get(APPLIED_ID).await?.unwrap_or_default())
+    }
+
+    async fn record(&self, database: &str, applied: &Applied) -> crate::Result<()> {
+        let db = self.database(database, false);
+        db.put(APPLIED_ID, applied).await.map(|_| ())
+    }
+}
+
+/// Registry of the migrations, in the order they run
+#[derive(Default)]
+pub struct Migrations {
+    migrations: Vec<Box<dyn Migration>>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a migration to run after those already registered.
+    /// Panics if another one has the same ID, which would keep one of them from ever running.
+    pub fn register<M: Migration + 'static>(&mut self, migration: M) -> &mut Self {
+        assert!(
+            self.migrations.iter().all(|m| m.id() != migration.id()),
+            "migration {} is registered twice",
+            migration.id()
+        );
+        self.migrations.push(Box::new(migration));
+        self
+    }
+
+    /// IDs of the migrations not applied yet, in the order they would run
+    pub async fn pending(&self, couch: &Couch) -> Result<Vec<String>, MigrationsError> {
+        self.pending_in(couch).await
+    }
+
+    /// Runs the migrations not applied yet, returning their IDs
+    pub async fn run(&self, couch: &Couch) -> Result<Vec<String>, MigrationsError> {
+        self.run_with(couch, couch).await
+    }
+
+    async fn pending_in<L: Ledger>(&self, ledger: &L) -> Result<Vec<String>, MigrationsError> {
+        let mut applied = HashMap::new();
+        let mut pending = Vec::new();
+        for migration in &self.migrations {
+            let database = migration.database();
+            if !applied.contains_key(database) {
+                let record = ledger
+                    .applied(database)
+                    .await
+                    .context(LoadApplied { database })?;
+                applied.insert(database.to_string(), record);
+            }
+            if !applied[database].contains(migration.id()) {
+                pending.push(migration.id().to_string());
+            }
+        }
+        Ok(pending)
+    }
+
+    async fn run_with<L: Ledger>(
+        &self,
+        couch: &Couch,
+        ledger: &L,
+    ) -> Result<Vec<String>, MigrationsError> {
+        let pending = self.pending_in(ledger).await?;
+        for migration in &self.migrations {
+            let id = migration.id();
+            if !pending.iter().any(|pending| pending == id) {
+                log::debug!("Migration {} already applied. Skipping", id);
+                continue;
+            }
+
+            log::info!("Running migration {}", id);
+            migration.run(couch).await.context(Failed { id })?;
+
+            // Read again, as the migration may have created the database
+            let database = migration.database();
+            let mut applied = ledger
+                .applied(database)
+                .await
+                .context(LoadApplied { database })?;
+            applied.migrations.push(AppliedMigration {
+                id: id.to_string(),
+                applied_at: Utc::now(),
+            });
+            ledger
+                .record(database, &applied)
+                .await
+                .context(Record { id })?;
+        }
+        Ok(pending)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use futures::executor::block_on;
+    use url::Url;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryLedger {
+        applied: Mutex<HashMap<String, Vec<AppliedMigration>>>,
+    }
+
+    #[async_trait]
+    impl Ledger for MemoryLedger {
+        async fn applied(&self, database: &str) -> crate::Result<Applied> {
+            let applied = self.applied.lock().unwrap();
+            Ok(Applied {
+                rev: None,
+                migrations: applied.get(database).cloned().unwrap_or_default(),
+            })
+        }
+
+        async fn record(&self, database: &str, applied: &Applied) -> crate::Result<()> {
+            let mut records = self.applied.lock().unwrap();
+            records.insert(database.to_string(), applied.migrations.clone());
+            Ok(())
+        }
+    }
+
+    struct Recorded {
+        id: &'static str,
+        fail: bool,
+        runs: std::sync::Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl Migration for Recorded {
+        fn id(&self) -> &str {
+            self.id
+        }
+
+        fn database(&self) -> &str {
+            "users"
+        }
+
+        async fn run(&self, _couch: &Couch) -> crate::Result<()> {
+            self.runs.lock().unwrap().push(self.id);
+            if self.fail {
+                Err(Error::internal("broke".to_string()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn couch() -> Couch {
+        let url = Url::parse("http://localhost:5984").unwrap();
+        Couch::new(url, "enseada".to_string(), "enseada".to_string())
+    }
+
+    #[test]
+    fn it_runs_pending_migrations_once_in_order() {
+        let runs = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let mut migrations = Migrations::new();
+        for id in &["001_first", "002_second"] {
+            migrations.register(Recorded {
+                id,
+                fail: false,
+                runs: runs.clone(),
+            });
+        }
+        let ledger = MemoryLedger::default();
+        let couch = couch();
+
+        assert_eq!(
+            block_on(migrations.pending_in(&ledger)).unwrap(),
+            vec!["001_first", "002_second"]
+        );
+        let applied = block_on(migrations.run_with(&couch, &ledger)).unwrap();
+        assert_eq!(applied, vec!["001_first", "002_second"]);
+        let applied = block_on(migrations.run_with(&couch, &ledger)).unwrap();
+        assert!(applied.is_empty());
+
+        assert_eq!(*runs.lock().unwrap(), vec!["001_first", "002_second"]);
+        let recorded = block_on(ledger.applied("users")).unwrap();
+        assert!(recorded.contains("001_first") && recorded.contains("002_second"));
+    }
+
+    #[test]
+    fn it_stops_at_the_failing_migration() {
+        let runs = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let mut migrations = Migrations::new();
+        for (id, fail) in &[("001_first", false), ("002_broken", true), ("003_third", false)] {
+            migrations.register(Recorded {
+                id,
+                fail: *fail,
+                runs: runs.clone(),
+            });
+        }
+        let ledger = MemoryLedger::default();
+
+        let err = block_on(migrations.run_with(&couch(), &ledger)).unwrap_err();
+        assert_eq!(err.to_string(), "Migration 002_broken failed: broke");
+        assert_eq!(*runs.lock().unwrap(), vec!["001_first", "002_broken"]);
+        assert_eq!(
+            block_on(migrations.pending_in(&ledger)).unwrap(),
+            vec!["002_broken", "003_third"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "registered twice")]
+    fn it_refuses_duplicate_ids() {
+        let runs = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let mut migrations = Migrations::new();
+        for _ in 0..2 {
+            migrations.register(Recorded {
+                id: "001_first",
+                fail: false,
+                runs: runs.clone(),
+            });
+        }
+    }
+}
@@ -35,11 +35,13 @@ pub enum MigrationOperation {
 }
 
 #[derive(Debug, Deserialize)]
+/// Named list of operations, deserialized from a JSON document
 pub struct Migration {
     name: String,
     operations: Vec<MigrationOperation>,
 }
 
+/// Applies migrations in order. Operations are idempotent, so migrations can be run on every startup.
 pub struct Migrator<'c> {
     client: &'c Couch,
     migrations: Vec<Migration>,
@@ -125,11 +127,9 @@ impl<'c> Migrator<'c> {
         let db = self.client.database(database, true);
 
         let index = JsonIndex::new(name, ddoc, index);
-        let name = index.name().to_string();
-        if !db.create_index(index).await.context(RunError { op })? {
-            log::debug!("Index {} already exists. Skipping", name);
-        }
-
+        db.create_json_index(index)
+            .await
+            .context(RunError { op })?;
         Ok(())
     }
 }
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::index::IndexInfo;
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct DBInfo {
     pub cluster: DBClusterInfo,
@@ -47,22 +49,138 @@ pub struct PutResponse {
     pub rev: String,
 }
 
+/// Outcome of one document of a `_bulk_docs` request, which CouchDB reports in the order the
+/// documents were sent. Each document succeeds or fails on its own.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(from = "RawBulkResult")]
+pub enum BulkResult {
+    Saved { id: String, rev: String },
+    /// The revision sent is not the current one of the document
+    Conflict { id: String },
+    /// Any other error, like `forbidden` when a validation function rejected the document
+    Failed {
+        id: String,
+        error: String,
+        reason: String,
+    },
+}
+
+impl BulkResult {
+    pub fn id(&self) -> &str {
+        match self {
+            BulkResult::Saved { id, .. }
+            | BulkResult::Conflict { id }
+            | BulkResult::Failed { id, .. } => id,
+        }
+    }
+
+    pub fn is_saved(&self) -> bool {
+        matches!(self, BulkResult::Saved { .. })
+    }
+
+    /// New revision of the document, if it was saved
+    pub fn rev(&self) -> Option<&str> {
+        match self {
+            BulkResult::Saved { rev, .. } => Some(rev),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawBulkResult {
+    #[serde(default)]
+    id: String,
+    rev: Option<String>,
+    error: Option<String>,
+    reason: Option<String>,
+}
+
+impl From<RawBulkResult> for BulkResult {
+    fn from(raw: RawBulkResult) -> Self {
+        match (raw.error, raw.rev) {
+            (Some(error), _) if error == "conflict" => BulkResult::Conflict { id: raw.id },
+            (Some(error), _) => BulkResult::Failed {
+                id: raw.id,
+                error,
+                reason: raw.reason.unwrap_or_default(),
+            },
+            (None, Some(rev)) => BulkResult::Saved { id: raw.id, rev },
+            (None, None) => BulkResult::Failed {
+                id: raw.id,
+                error: "unknown_error".to_string(),
+                reason: "CouchDB returned neither a revision nor an error".to_string(),
+            },
+        }
+    }
+}
+
+/// Response of `_bulk_get`, with a result for each requested document
+#[derive(Debug, Deserialize)]
+pub struct BulkGetResponse {
+    pub results: Vec<BulkGetResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkGetResult {
+    pub id: String,
+    /// One for each revision requested, so only the current one when none is
+    pub docs: Vec<BulkGetDoc>,
+}
+
+/// Either the document, or why it could not be read, like `{"ok": {...}}`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkGetDoc {
+    Ok(serde_json::Value),
+    Error(BulkGetError),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkGetError {
+    pub id: String,
+    /// `undefined` when no revision was requested
+    pub rev: Option<String>,
+    pub error: String,
+    pub reason: String,
+}
+
+impl BulkGetResult {
+    /// The current revision of the document, none if it is missing or deleted
+    pub fn into_doc(self) -> crate::Result<Option<serde_json::Value>> {
+        match self.docs.into_iter().next() {
+            Some(BulkGetDoc::Ok(doc)) if doc["_deleted"] == true => Ok(None),
+            Some(BulkGetDoc::Ok(doc)) => Ok(Some(doc)),
+            Some(BulkGetDoc::Error(err)) if err.error == "not_found" => Ok(None),
+            Some(BulkGetDoc::Error(err)) => Err(crate::error::Error::internal(format!(
+                "document {}: {}: {}",
+                err.id, err.error, err.reason
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct FindResponse<T> {
     pub docs: Vec<T>,
+    /// Resumes the query on the next page, with the same selector and options
+    #[serde(default)]
     pub bookmark: String,
+    /// Set by CouchDB when no index serves the query, or the requested one was not used
+    #[serde(default)]
     pub warning: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-pub struct RowsResponse<T: Clone> {
+pub struct RowsResponse<T> {
     pub offset: usize,
     pub rows: Vec<RawDocResponse<T>>,
     pub total_rows: usize,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-pub struct RawDocResponse<T: Clone> {
+pub struct RawDocResponse<T> {
     pub id: String,
     pub key: String,
     pub value: RawDocValue,
@@ -87,3 +205,158 @@ pub struct JsonIndexResponse {
     pub id: String,
     pub name: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct IndexListResponse {
+    pub total_rows: usize,
+    pub indexes: Vec<IndexInfo>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PartitionInfo {
+    pub db_name: String,
+    pub partition: String,
+    pub doc_count: usize,
+    pub doc_del_count: usize,
+}
+
+/// Query plan of a Mango query, telling which index it would use
+#[derive(Debug, Deserialize)]
+pub struct ExplainResponse {
+    pub dbname: String,
+    pub index: ExplainIndex,
+    pub selector: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainIndex {
+    /// Design document of the index, none for the special `_all_docs` index
+    pub ddoc: Option<String>,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+impl ExplainIndex {
+    /// Whether the query reads every document instead of using an index
+    pub fn is_full_scan(&self) -> bool {
+        self.kind == "special"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_tells_full_scans_from_indexed_queries() {
+        let indexed: ExplainResponse = serde_json::from_value(serde_json::json!({
+            "dbname": "users",
+            "index": {
+                "ddoc": "_design/users_indexes",
+                "name": "user_username_lower_idx",
+                "type": "json",
+                "def": { "fields": [{ "username_lower": "asc" }] }
+            },
+            "selector": { "username_lower": { "$gte": "jd" } }
+        }))
+        .unwrap();
+        assert!(!indexed.index.is_full_scan());
+        assert_eq!(indexed.index.name, "user_username_lower_idx");
+
+        let scan: ExplainResponse = serde_json::from_value(serde_json::json!({
+            "dbname": "users",
+            "index": {
+                "ddoc": null,
+                "name": "_all_docs",
+                "type": "special",
+                "def": { "fields": [{ "_id": "asc" }] }
+            },
+            "selector": { "full_name": { "$regex": "^J" } }
+        }))
+        .unwrap();
+        assert!(scan.index.is_full_scan());
+    }
+
+    #[test]
+    fn it_reads_the_documents_of_a_bulk_get() {
+        let res: BulkGetResponse = serde_json::from_value(serde_json::json!({
+            "results": [
+                {
+                    "id": "user:jdoe",
+                    "docs": [{ "ok": { "_id": "user:jdoe", "_rev": "3-c", "username": "jdoe" } }]
+                },
+                {
+                    "id": "user:gone",
+                    "docs": [{
+                        "error": {
+                            "id": "user:gone",
+                            "rev": "undefined",
+                            "error": "not_found",
+                            "reason": "deleted"
+                        }
+                    }]
+                },
+                {
+                    "id": "user:old",
+                    "docs": [{ "ok": { "_id": "user:old", "_rev": "2-d", "_deleted": true } }]
+                },
+                {
+                    "id": "user:secret",
+                    "docs": [{
+                        "error": {
+                            "id": "user:secret",
+                            "rev": "undefined",
+                            "error": "forbidden",
+                            "reason": "not allowed"
+                        }
+                    }]
+                }
+            ]
+        }))
+        .unwrap();
+
+        let mut results = res.results.into_iter();
+        let doc = results.next().unwrap().into_doc().unwrap().unwrap();
+        assert_eq!(doc["username"], "jdoe");
+        assert_eq!(results.next().unwrap().into_doc().unwrap(), None);
+        assert_eq!(results.next().unwrap().into_doc().unwrap(), None);
+        assert_eq!(
+            results.next().unwrap().into_doc().unwrap_err().to_string(),
+            "document user:secret: forbidden: not allowed"
+        );
+    }
+
+    #[test]
+    fn it_tells_which_documents_of_a_bulk_request_failed() {
+        let results: Vec<BulkResult> = serde_json::from_value(serde_json::json!([
+            { "ok": true, "id": "pat:a", "rev": "2-5f0c" },
+            { "id": "pat:b", "error": "conflict", "reason": "Document update conflict." },
+            { "id": "pat:c", "error": "forbidden", "reason": "only admins may edit" },
+            { "ok": true, "id": "pat:d", "rev": "1-9a2b" }
+        ]))
+        .unwrap();
+
+        let saved: Vec<(&str, Option<&str>)> = results
+            .iter()
+            .filter(|res| res.is_saved())
+            .map(|res| (res.id(), res.rev()))
+            .collect();
+        assert_eq!(saved, vec![("pat:a", Some("2-5f0c")), ("pat:d", Some("1-9a2b"))]);
+        assert_eq!(
+            results[1],
+            BulkResult::Conflict {
+                id: "pat:b".to_string()
+            }
+        );
+        assert_eq!(
+            results[2],
+            BulkResult::Failed {
+                id: "pat:c".to_string(),
+                error: "forbidden".to_string(),
+                reason: "only admins may edit".to_string(),
+            }
+        );
+        assert_eq!(results[2].rev(), None);
+    }
+}
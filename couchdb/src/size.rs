@@ -0,0 +1,170 @@
+//! Guards against writing documents over the maximum document size of CouchDB.
+//!
+//! CouchDB rejects oversized documents with a `413 Payload Too Large`, which is hard to recover
+//! from after the fact. A [`SizeGuard`] measures documents before they are written and truncates
+//! their free-form fields instead, marking them with `"truncated": true`.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::Result;
+
+/// Half of the default `max_document_size` of CouchDB, leaving room for the rest of the document
+pub const DEFAULT_LIMIT: usize = 4_000_000;
+
+/// Field set on documents whose free-form fields were truncated
+pub const TRUNCATED: &str = "truncated";
+
+pub struct SizeGuard {
+    limit: usize,
+    truncations: AtomicU64,
+}
+
+impl Default for SizeGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_LIMIT)
+    }
+}
+
+impl SizeGuard {
+    /// Creates a guard for documents up to the given size in bytes, once serialized
+    pub fn new(limit: usize) -> Self {
+        SizeGuard {
+            limit,
+            truncations: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Value of the `couchdb_document_truncated` counter
+    pub fn truncations(&self) -> u64 {
+        self.truncations.load(Ordering::Relaxed)
+    }
+
+    /// Truncates the given top level fields of the document, in order, until it fits the limit.
+    /// Strings are shortened, arrays lose their last elements and any other value is removed.
+    /// Returns whether the document was truncated, or a `413` error if it is still too large.
+    pub fn check(&self, id: &str, doc: &mut Value, fields: &[&str]) -> Result<bool> {
+        let size = size_of(doc);
+        if size <= self.limit {
+            return Ok(false);
+        }
+
+        if let Value::Object(map) = doc {
+            map.insert(TRUNCATED.to_string(), Value::Bool(true));
+            for field in fields {
+                let excess = size_of(&*map).saturating_sub(self.limit);
+                if excess == 0 {
+                    break;
+                }
+                if let Some(value) = map.get_mut(*field) {
+                    if !truncate(value, excess) {
+                        map.remove(*field);
+                    }
+                }
+            }
+        }
+
+        let truncated_size = size_of(doc);
+        if truncated_size > self.limit {
+            return Err(Error::too_large(format!(
+                "document {} is {} bytes, over the limit of {} even when truncated",
+                id, truncated_size, self.limit
+            )));
+        }
+
+        self.truncations.fetch_add(1, Ordering::Relaxed);
+        log::warn!(
+            "Truncated document {} from {} to {} bytes to fit the limit of {}",
+            id,
+            size,
+            truncated_size,
+            self.limit
+        );
+        Ok(true)
+    }
+}
+
+fn size_of<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map(|bytes| bytes.len()).unwrap_or(0)
+}
+
+/// Shrinks the value by at least the excess bytes, returning false if it should be removed instead
+fn truncate(value: &mut Value, excess: usize) -> bool {
+    match value {
+        Value::String(s) => {
+            // Escaped characters take more room serialized, so this can cut more than needed
+            let mut end = s.len().saturating_sub(excess);
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            s.truncate(end);
+            true
+        }
+        Value::Array(values) => {
+            let mut removed = 0;
+            while removed < excess {
+                match values.pop() {
+                    Some(value) => removed += size_of(&value) + 1,
+                    None => break,
+                }
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_leaves_small_documents_alone() {
+        let guard = SizeGuard::new(100);
+        let mut doc = json!({ "_id": "a", "detail": "small" });
+        assert!(!guard.check("a", &mut doc, &["detail"]).unwrap());
+        assert_eq!(doc, json!({ "_id": "a", "detail": "small" }));
+        assert_eq!(guard.truncations(), 0);
+    }
+
+    #[test]
+    fn it_truncates_strings() {
+        let guard = SizeGuard::new(100);
+        let mut doc = json!({ "_id": "a", "detail": "é".repeat(100) });
+        assert!(guard.check("a", &mut doc, &["detail"]).unwrap());
+        assert_eq!(doc["truncated"], true);
+        assert!(doc["detail"].as_str().unwrap().starts_with('é'));
+        assert!(size_of(&doc) <= 100);
+        assert_eq!(guard.truncations(), 1);
+    }
+
+    #[test]
+    fn it_truncates_arrays_and_removes_other_values() {
+        let guard = SizeGuard::new(200);
+        let items: Vec<_> = (0..50).map(|i| json!({ "id": i })).collect();
+        let mut doc = json!({ "_id": "a", "context": { "big": "x".repeat(100) }, "items": items });
+        assert!(guard.check("a", &mut doc, &["context", "items"]).unwrap());
+        assert!(doc.get("context").is_none());
+        let items = doc["items"].as_array().unwrap();
+        assert!(!items.is_empty() && items.len() < 50);
+        assert_eq!(items[0], json!({ "id": 0 }));
+        assert!(size_of(&doc) <= 200);
+    }
+
+    #[test]
+    fn it_rejects_documents_too_large_when_truncated() {
+        let guard = SizeGuard::new(50);
+        let mut doc = json!({ "_id": "a", "name": "x".repeat(100), "detail": "y".repeat(100) });
+        let err = guard.check("a", &mut doc, &["detail"]).unwrap_err();
+        assert!(err.is_too_large());
+        assert_eq!(guard.truncations(), 0);
+    }
+}
@@ -4,3 +4,9 @@ use serde::Deserialize;
 pub struct Status {
     pub status: String,
 }
+
+/// Welcome message of the server
+#[derive(Debug, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+}
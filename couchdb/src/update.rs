@@ -0,0 +1,177 @@
+//! Read-modify-write of documents, retried when a concurrent writer gets there first.
+//!
+//! Each attempt reads the latest revision of the document, applies the change to it and writes
+//! it back, so that the change is never applied to a stale revision and concurrent changes are
+//! not overwritten. The change may run once per attempt, so it must only depend on the document.
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::db::Database;
+use crate::error::Error;
+use crate::Result;
+
+/// Times a document is read, changed and written back before giving up on conflicts
+pub const UPDATE_ATTEMPTS: usize = 5;
+
+/// Storage of the revisions of documents
+#[async_trait]
+pub(crate) trait Revisions: Send + Sync {
+    async fn latest(&self, id: &str) -> Result<Option<Value>>;
+
+    /// Writes the document, returning its new revision
+    async fn write(&self, id: &str, doc: &Value) -> Result<String>;
+}
+
+#[async_trait]
+impl Revisions for Database {
+    async fn latest(&self, id: &str) -> Result<Option<Value>> {
+        self.get_consistent(id).await
+    }
+
+    async fn write(&self, id: &str, doc: &Value) -> Result<String> {
+        let token = self.put_tracked(id, doc).await?;
+        Ok(token.rev().to_string())
+    }
+}
+
+/// Applies the change to the latest revision of the document, skipping the write if it returns
+/// false. Returns the changed document, none if there is no such document.
+pub(crate) async fn update<S, T, F>(store: &S, id: &str, mut change: F) -> Result<Option<T>>
+where
+    S: Revisions,
+    T: Serialize + DeserializeOwned,
+    F: FnMut(&mut T) -> bool,
+{
+    let mut attempt = 1;
+    loop {
+        let doc = match store.latest(id).await? {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+        let mut doc: T = from_value(id, doc)?;
+        if !change(&mut doc) {
+            return Ok(Some(doc));
+        }
+
+        let mut value = serde_json::to_value(&doc)
+            .map_err(|err| Error::internal(format!("could not write {}: {}", id, err)))?;
+        match store.write(id, &value).await {
+            Ok(rev) => {
+                value["_rev"] = Value::String(rev);
+                return from_value(id, value).map(Some);
+            }
+            Err(Error::Conflict(_)) if attempt < UPDATE_ATTEMPTS => {
+                log::debug!("Document {} was modified concurrently, retrying", id);
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn from_value<T: DeserializeOwned>(id: &str, doc: Value) -> Result<T> {
+    serde_json::from_value(doc)
+        .map_err(|err| Error::internal(format!("could not read {}: {}", id, err)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use futures::executor::block_on;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Counter {
+        #[serde(rename = "_id")]
+        id: String,
+        #[serde(rename = "_rev")]
+        rev: String,
+        count: u32,
+    }
+
+    /// Holds one document, and lets another writer sneak in before the first given writes
+    struct ContendedStore {
+        doc: Mutex<Value>,
+        contended_writes: Mutex<usize>,
+        writes: Mutex<usize>,
+    }
+
+    impl ContendedStore {
+        fn new(contended_writes: usize) -> Self {
+            ContendedStore {
+                doc: Mutex::new(json!({ "_id": "counter", "_rev": "1-a", "count": 0 })),
+                contended_writes: Mutex::new(contended_writes),
+                writes: Mutex::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Revisions for ContendedStore {
+        async fn latest(&self, _id: &str) -> Result<Option<Value>> {
+            Ok(Some(self.doc.lock().unwrap().clone()))
+        }
+
+        async fn write(&self, id: &str, doc: &Value) -> Result<String> {
+            let mut current = self.doc.lock().unwrap();
+            let mut contended = self.contended_writes.lock().unwrap();
+            if *contended > 0 {
+                *contended -= 1;
+                current["count"] = json!(current["count"].as_u64().unwrap() + 10);
+                current["_rev"] = json!(format!("{}x", current["_rev"].as_str().unwrap()));
+            }
+            if current["_rev"] != doc["_rev"] {
+                return Err(Error::conflict(format!("document {} conflicts", id)));
+            }
+            *self.writes.lock().unwrap() += 1;
+            let rev = format!("{}+", current["_rev"].as_str().unwrap());
+            *current = doc.clone();
+            current["_rev"] = json!(rev.clone());
+            Ok(rev)
+        }
+    }
+
+    fn increment(counter: &mut Counter) -> bool {
+        counter.count += 1;
+        true
+    }
+
+    #[test]
+    fn it_retries_on_conflicts_with_the_latest_revision() {
+        let store = ContendedStore::new(1);
+        let counter: Counter = block_on(update(&store, "counter", increment))
+            .unwrap()
+            .unwrap();
+
+        // The concurrent change is kept, and the new revision is returned
+        assert_eq!(counter.count, 11);
+        assert_eq!(counter.rev, "1-ax+");
+        assert_eq!(*store.writes.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn it_gives_up_after_the_last_attempt() {
+        let store = ContendedStore::new(UPDATE_ATTEMPTS);
+        let res: Result<Option<Counter>> = block_on(update(&store, "counter", increment));
+
+        assert!(matches!(res, Err(Error::Conflict(_))));
+        assert_eq!(*store.writes.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn it_skips_unchanged_documents() {
+        let store = ContendedStore::new(0);
+        let counter: Counter = block_on(update(&store, "counter", |_: &mut Counter| false))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(counter.count, 0);
+        assert_eq!(*store.writes.lock().unwrap(), 0);
+    }
+}
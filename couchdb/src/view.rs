@@ -0,0 +1,274 @@
+//! Design documents and the map/reduce views they define.
+//!
+//! Views suit queries Mango cannot answer efficiently, like counting documents by type or
+//! scanning them in the order of a computed key. A [`DesignDoc`] carries a hash of its content,
+//! so that [`Database::put_design_doc`] only writes it when it changed: rewriting a design
+//! document makes CouchDB rebuild its views from scratch.
+//!
+//! [`Database::put_design_doc`]: crate::db::Database::put_design_doc
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Field of design documents holding the hash of their content
+pub(crate) const HASH_FIELD: &str = "content_hash";
+
+/// Map function and optional reduce function of a view, in JavaScript
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct View {
+    map: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reduce: Option<String>,
+}
+
+/// Design document of views, built like
+/// `DesignDoc::new("stats").partitioned(false).view("by_type", MAP, Some("_count"))`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DesignDoc {
+    name: String,
+    views: BTreeMap<String, View>,
+    partitioned: Option<bool>,
+}
+
+impl DesignDoc {
+    pub fn new(name: &str) -> Self {
+        DesignDoc {
+            name: name.to_string(),
+            views: BTreeMap::new(),
+            partitioned: None,
+        }
+    }
+
+    /// Adds a view, with a built-in reducer like `_count` or `_sum`, or a JavaScript one
+    pub fn view(mut self, name: &str, map: &str, reduce: Option<&str>) -> Self {
+        self.views.insert(
+            name.to_string(),
+            View {
+                map: map.to_string(),
+                reduce: reduce.map(str::to_string),
+            },
+        );
+        self
+    }
+
+    /// Whether the views are queried one partition at a time. The design documents of a
+    /// partitioned database are partitioned unless set otherwise, so views spanning every
+    /// partition must be set to false.
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = Some(partitioned);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> String {
+        format!("_design/{}", &self.name)
+    }
+
+    pub fn is_partitioned(&self) -> Option<bool> {
+        self.partitioned
+    }
+
+    /// Hash of the views and options, which changes whenever they do
+    pub fn content_hash(&self) -> String {
+        format!("{:016x}", fnv1a(self.content().to_string().as_bytes()))
+    }
+
+    /// The document written to CouchDB, replacing the given revision if any
+    pub(crate) fn body(&self, rev: Option<&str>) -> serde_json::Value {
+        let mut body = self.content();
+        body["_id"] = self.id().into();
+        body[HASH_FIELD] = self.content_hash().into();
+        if let Some(rev) = rev {
+            body["_rev"] = rev.into();
+        }
+        body
+    }
+
+    fn content(&self) -> serde_json::Value {
+        let mut content = serde_json::json!({
+            "language": "javascript",
+            "views": self.views,
+        });
+        if let Some(partitioned) = self.partitioned {
+            content["options"] = serde_json::json!({ "partitioned": partitioned });
+        }
+        content
+    }
+}
+
+/// FNV-1a, stable across builds unlike the hashers of the standard library
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Reduction and pagination of a view query, built like
+/// `ViewOptions::new().group(true).start_key(&"user")?.limit(50)`.
+///
+/// Views are paged by starting the next page at the key of the last row, skipping the rows
+/// of that key already read.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ViewOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reduce: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_level: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    startkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skip: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    descending: Option<bool>,
+}
+
+impl ViewOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to run the reduce function of the view, which it does by default if it has one
+    pub fn reduce(mut self, reduce: bool) -> Self {
+        self.reduce = Some(reduce);
+        self
+    }
+
+    /// Reduces the rows of each key separately instead of all of them together
+    pub fn group(mut self, group: bool) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// Groups array keys by their first elements only
+    pub fn group_level(mut self, level: usize) -> Self {
+        self.group_level = Some(level);
+        self
+    }
+
+    /// Starts from the rows of this key, serialized to JSON like CouchDB expects
+    pub fn start_key<K: Serialize>(mut self, key: &K) -> serde_json::Result<Self> {
+        self.startkey = Some(serde_json::to_string(key)?);
+        Ok(self)
+    }
+
+    /// Ends at the rows of this key, included
+    pub fn end_key<K: Serialize>(mut self, key: &K) -> serde_json::Result<Self> {
+        self.endkey = Some(serde_json::to_string(key)?);
+        Ok(self)
+    }
+
+    pub fn skip(mut self, skip: usize) -> Self {
+        self.skip = Some(skip);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn descending(mut self, descending: bool) -> Self {
+        self.descending = Some(descending);
+        self
+    }
+}
+
+/// Rows of a view, sorted by key. Reduced queries leave out the totals and the IDs.
+#[derive(Debug, Deserialize)]
+pub struct ViewResponse<K, V> {
+    pub total_rows: Option<usize>,
+    pub offset: Option<usize>,
+    pub rows: Vec<ViewRow<K, V>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ViewRow<K, V> {
+    /// ID of the document the row was emitted for, none for reduced rows
+    pub id: Option<String>,
+    pub key: K,
+    pub value: V,
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    const MAP: &str = "function (doc) { emit(doc.type, null); }";
+
+    #[test]
+    fn it_hashes_the_content_of_design_documents() {
+        let ddoc = DesignDoc::new("stats")
+            .partitioned(false)
+            .view("by_type", MAP, Some("_count"));
+        let same = DesignDoc::new("stats")
+            .partitioned(false)
+            .view("by_type", MAP, Some("_count"));
+        let changed = DesignDoc::new("stats")
+            .partitioned(false)
+            .view("by_type", MAP, Some("_sum"));
+
+        assert_eq!(ddoc.content_hash(), same.content_hash());
+        assert_ne!(ddoc.content_hash(), changed.content_hash());
+        assert_eq!(ddoc.content_hash().len(), 16);
+
+        assert_eq!(
+            ddoc.body(Some("1-a")),
+            json!({
+                "_id": "_design/stats",
+                "_rev": "1-a",
+                "language": "javascript",
+                "views": { "by_type": { "map": MAP, "reduce": "_count" } },
+                "options": { "partitioned": false },
+                "content_hash": ddoc.content_hash(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_encodes_view_keys_as_json() {
+        let options = ViewOptions::new()
+            .group(true)
+            .start_key(&"user")
+            .unwrap()
+            .end_key(&json!(["user", {}]))
+            .unwrap()
+            .skip(1)
+            .limit(50);
+        assert_eq!(
+            serde_json::to_value(&options).unwrap(),
+            json!({
+                "group": true,
+                "startkey": "\"user\"",
+                "endkey": "[\"user\",{}]",
+                "skip": 1,
+                "limit": 50,
+            })
+        );
+    }
+
+    #[test]
+    fn it_reads_reduced_rows() {
+        let res: ViewResponse<String, u64> = serde_json::from_value(json!({
+            "rows": [
+                { "key": "apikey", "value": 3 },
+                { "key": "user", "value": 12 },
+            ]
+        }))
+        .unwrap();
+        assert_eq!(res.total_rows, None);
+        assert_eq!(res.rows[1].id, None);
+        assert_eq!(res.rows[1].key, "user");
+        assert_eq!(res.rows[1].value, 12);
+    }
+}
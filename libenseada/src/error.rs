@@ -7,6 +7,10 @@ use snafu::Snafu;
 pub enum Error {
     #[snafu(display("{}", reason))]
     Conflict { reason: String },
+    #[snafu(display("{}", reason))]
+    Forbidden { reason: String },
+    #[snafu(display("{}", reason))]
+    Locked { reason: String },
     #[snafu(display("{} '{}' not found", typ, id))]
     NotFound { typ: String, id: String },
     #[snafu(display("{}", source))]
@@ -15,6 +19,8 @@ pub enum Error {
     Base64Decode { source: base64::DecodeError },
     #[snafu(display("{}", source))]
     Database { source: couchdb::error::Error },
+    #[snafu(display("{}", source))]
+    InvalidGuid { source: couchdb::guid::GuidError },
     #[snafu(display("{}", message))]
     Generic { message: String },
 }
@@ -37,6 +43,14 @@ impl Error {
         Error::Conflict { reason }
     }
 
+    pub fn forbidden(reason: String) -> Self {
+        Error::Forbidden { reason }
+    }
+
+    pub fn locked(reason: String) -> Self {
+        Error::Locked { reason }
+    }
+
     pub fn new(message: &str) -> Self {
         Error::Generic {
             message: message.to_string(),
@@ -46,6 +60,14 @@ impl Error {
     pub fn status(&self) -> StatusCode {
         match self {
             Error::Conflict { .. } => StatusCode::CONFLICT,
+            Error::Forbidden { .. } => StatusCode::FORBIDDEN,
+            Error::Locked { .. } => StatusCode::LOCKED,
+            Error::InvalidGuid { .. } => StatusCode::BAD_REQUEST,
+            // Documents updated concurrently
+            Error::Database { source } if source.status() == StatusCode::CONFLICT => {
+                StatusCode::CONFLICT
+            }
+            Error::Database { source } if source.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -69,6 +91,12 @@ impl From<couchdb::error::Error> for Error {
     }
 }
 
+impl From<couchdb::guid::GuidError> for Error {
+    fn from(err: couchdb::guid::GuidError) -> Self {
+        Error::InvalidGuid { source: err }
+    }
+}
+
 impl From<base64::DecodeError> for Error {
     fn from(err: base64::DecodeError) -> Self {
         Error::Base64Decode { source: err }
@@ -80,3 +108,32 @@ impl From<FromUtf8Error> for Error {
         Error::FromUtf8 { source: err }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_reports_database_conflicts() {
+        let conflict = couchdb::error::Error::conflict("Document update conflict.".to_string());
+        assert_eq!(Error::from(conflict).status(), StatusCode::CONFLICT);
+        let internal = couchdb::error::Error::internal("unavailable".to_string());
+        assert_eq!(
+            Error::from(internal).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn it_reports_database_timeouts() {
+        let timeout = couchdb::error::Error::timeout("operation timed out".to_string());
+        assert_eq!(Error::from(timeout).status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn it_reports_forbidden_errors() {
+        let err = Error::forbidden("user jdoe is disabled".to_string());
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        assert_eq!(err.to_string(), "user jdoe is disabled");
+    }
+}
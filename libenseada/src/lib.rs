@@ -2,6 +2,9 @@
 extern crate lazy_static;
 
 pub mod error;
-pub mod guid;
 pub mod pagination;
 pub mod secure;
+pub mod totp;
+pub mod trust;
+
+pub use couchdb::guid;
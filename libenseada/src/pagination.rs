@@ -11,15 +11,26 @@ use crate::error::Error;
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Page<T> {
     count: usize,
+    /// Most items a page holds
+    #[serde(default)]
+    limit: usize,
+    /// Items across every page, unknown unless counted
+    #[serde(default)]
+    total: Option<usize>,
+    #[serde(default)]
+    has_more: bool,
     next_cursor: Option<Cursor>,
     items: Vec<T>,
 }
 
 impl<T: Clone> Page<T> {
-    pub fn from_slice(items: Vec<T>, next_cursor: Option<Cursor>) -> Self {
+    pub fn from_slice(items: Vec<T>, limit: usize, next_cursor: Option<Cursor>) -> Self {
         let count = items.len();
         Page {
             count,
+            limit,
+            total: None,
+            has_more: next_cursor.is_some(),
             next_cursor,
             items,
         }
@@ -28,13 +39,14 @@ impl<T: Clone> Page<T> {
     pub fn from_rows_response(res: RowsResponse<T>, limit: usize) -> Self {
         if res.rows.len() <= limit {
             let items = res.rows.iter().map(|raw| raw.doc.clone()).collect();
-            Page::from_slice(items, None)
+            Page::from_slice(items, limit, None)
         } else {
             let mut res = res;
             let last = res.rows.remove(res.rows.len() - 1);
             let items = res.rows.iter().map(|raw| raw.doc.clone()).collect();
             Page::from_slice(
                 items,
+                limit,
                 Some(Cursor::b64_encoded(
                     serde_json::to_string(&last.key).unwrap(),
                 )),
@@ -49,7 +61,34 @@ impl<T: Clone> Page<T> {
             Some(Cursor::b64_encoded(res.bookmark))
         };
 
-        Self::from_slice(res.docs, bookmark)
+        Self::from_slice(res.docs, limit, bookmark)
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn total(&self) -> Option<usize> {
+        self.total
+    }
+
+    /// Sets the number of items across every page, counted separately from the page itself
+    pub fn set_total(&mut self, total: usize) -> &mut Self {
+        self.total = Some(total);
+        self
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.has_more
+    }
+
+    /// Cursor of the next page, none on the last one
+    pub fn next_cursor(&self) -> Option<&Cursor> {
+        self.next_cursor.as_ref()
     }
 
     pub fn map<B, F>(self, f: F) -> Page<B>
@@ -58,6 +97,9 @@ impl<T: Clone> Page<T> {
     {
         Page {
             count: self.count,
+            limit: self.limit,
+            total: self.total,
+            has_more: self.has_more,
             next_cursor: self.next_cursor,
             items: self.items.iter().map(f).collect(),
         }
@@ -77,6 +119,11 @@ impl Cursor {
         let encoded = base64::encode(input);
         Cursor(encoded)
     }
+
+    /// Document ID a decoded cursor of [`Page::from_rows_response`] starts the next page from
+    pub fn start_key(&self) -> String {
+        serde_json::from_str(&self.0).unwrap_or_else(|_| self.0.clone())
+    }
 }
 
 impl Display for Cursor {
@@ -108,3 +155,60 @@ impl<'de> Deserialize<'de> for Cursor {
         String::deserialize(deserializer).map(Self::from)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn find_response(docs: Vec<u32>) -> FindResponse<u32> {
+        FindResponse {
+            docs,
+            bookmark: "bookmark".to_string(),
+            warning: None,
+        }
+    }
+
+    #[test]
+    fn it_tells_whether_there_are_more_pages() {
+        let page = Page::from_find_response(find_response(vec![1, 2]), 2);
+        assert!(page.has_more());
+        assert_eq!(page.limit(), 2);
+        assert_eq!(page.total(), None);
+
+        let page = Page::from_find_response(find_response(vec![1]), 2);
+        assert!(!page.has_more());
+        assert!(page.next_cursor().is_none());
+    }
+
+    #[test]
+    fn it_keeps_the_metadata_when_mapping() {
+        let mut page = Page::from_find_response(find_response(vec![1, 2]), 2);
+        page.set_total(5);
+        let page = page.map(|n| n.to_string());
+        assert_eq!(page.items(), &["1".to_string(), "2".to_string()]);
+        assert_eq!(page.total(), Some(5));
+        assert!(page.has_more());
+
+        let json = serde_json::to_value(&page).unwrap();
+        assert_eq!(json["count"], 2);
+        assert_eq!(json["limit"], 2);
+        assert_eq!(json["total"], 5);
+        assert_eq!(json["has_more"], true);
+    }
+
+    #[test]
+    fn it_starts_the_next_page_of_rows_from_the_last_key() {
+        let cursor = Cursor::b64_encoded(serde_json::to_string("user:jdoe").unwrap());
+        let decoded = Cursor::from_b64(cursor.to_string()).unwrap();
+        assert_eq!(decoded.start_key(), "user:jdoe");
+    }
+
+    #[test]
+    fn it_reads_pages_without_metadata() {
+        let page: Page<u32> =
+            serde_json::from_str(r#"{ "count": 1, "next_cursor": null, "items": [1] }"#).unwrap();
+        assert_eq!(page.items(), &[1]);
+        assert_eq!(page.total(), None);
+        assert!(!page.has_more());
+    }
+}
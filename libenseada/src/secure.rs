@@ -1,5 +1,10 @@
 use std::fmt::{self, Display, Formatter};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
+use argon2::{ThreadMode, Variant, Version};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{self, SHA256};
 use ring::hmac::{self, Key, HMAC_SHA512};
 use ring::rand::{SecureRandom, SystemRandom};
 
@@ -8,7 +13,99 @@ lazy_static! {
 }
 
 lazy_static! {
-    static ref ARGON_CONFIG: argon2::Config<'static> = argon2::Config::default();
+    static ref HASH_PARAMS: RwLock<HashParams> = RwLock::new(HashParams::default());
+}
+
+/// Algorithm new passwords are hashed with
+pub const HASH_ALGORITHM: &str = "argon2id";
+
+/// Cost of hashing new passwords with Argon2id, see [`set_hash_params`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashParams {
+    /// Memory used, in KiB
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for HashParams {
+    /// The minimum recommended by OWASP
+    fn default() -> Self {
+        HashParams {
+            memory: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl HashParams {
+    fn config(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: self.memory,
+            time_cost: self.iterations,
+            lanes: self.parallelism,
+            thread_mode: ThreadMode::Sequential,
+            ..argon2::Config::default()
+        }
+    }
+}
+
+/// Algorithm and parameters a hash declares in its PHC string,
+/// like `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HashInfo {
+    pub algorithm: String,
+    pub version: Option<u32>,
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl HashInfo {
+    pub fn parse(hash: &str) -> Result<HashInfo, String> {
+        let invalid = || "invalid password hash".to_string();
+        let mut fields = hash.strip_prefix('$').ok_or_else(invalid)?.split('$');
+        let algorithm = fields.next().filter(|alg| !alg.is_empty()).ok_or_else(invalid)?;
+        let mut field = fields.next().ok_or_else(invalid)?;
+        let version = match field.strip_prefix("v=") {
+            Some(version) => {
+                field = fields.next().ok_or_else(invalid)?;
+                Some(version.parse().map_err(|_| invalid())?)
+            }
+            None => None,
+        };
+
+        let mut info = HashInfo {
+            algorithm: algorithm.to_string(),
+            version,
+            memory: 0,
+            iterations: 0,
+            parallelism: 0,
+        };
+        for param in field.split(',') {
+            let (name, value) = param.split_at(param.find('=').ok_or_else(invalid)?);
+            let value = value[1..].parse().map_err(|_| invalid())?;
+            match name {
+                "m" => info.memory = value,
+                "t" => info.iterations = value,
+                "p" => info.parallelism = value,
+                _ => {}
+            }
+        }
+        Ok(info)
+    }
+
+    /// Whether the hash is weaker than one made with the given parameters,
+    /// or made with another algorithm or version
+    pub fn is_weaker_than(&self, params: &HashParams) -> bool {
+        self.algorithm != HASH_ALGORITHM
+            || self.version != Some(Version::Version13.as_u32())
+            || self.memory < params.memory
+            || self.iterations < params.iterations
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,19 +152,106 @@ pub fn generate_signature(source: &str, key: &str) -> SecureSecret {
     SecureSecret(buf)
 }
 
+/// Whether the hex-encoded signature is the one of the source, compared in constant time
+pub fn verify_signature(source: &str, key: &str, signature: &str) -> bool {
+    let key = Key::new(HMAC_SHA512, key.as_bytes());
+    match hex::decode(signature) {
+        Ok(signature) => hmac::verify(&key, source.as_bytes(), &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// SHA-256 of the source, for lookups by values that are not secret.
+/// Secrets must be signed instead, and passwords hashed.
+pub fn digest(source: &str) -> SecureSecret {
+    SecureSecret(digest::digest(&SHA256, source.as_bytes()).as_ref().to_vec())
+}
+
+/// Encrypts the plaintext with a key derived from the secret (AES-256-GCM).
+/// The result is base64-encoded and carries its random nonce.
+pub fn seal(plaintext: &[u8], secret: &str) -> Result<String, String> {
+    let mut nonce = [0; NONCE_LEN];
+    SECURE_RANDOM.fill(&mut nonce).map_err(|e| e.to_string())?;
+    let mut buf = plaintext.to_vec();
+    sealing_key(secret)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut buf)
+        .map_err(|e| e.to_string())?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(buf);
+    Ok(base64::encode(&sealed))
+}
+
+/// Decrypts what [`seal`] encrypted with the same secret
+pub fn open(sealed: &str, secret: &str) -> Result<Vec<u8>, String> {
+    let mut sealed = base64::decode(sealed).map_err(|e| e.to_string())?;
+    if sealed.len() < NONCE_LEN {
+        return Err("sealed data is too short".to_string());
+    }
+    let mut buf = sealed.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&sealed).map_err(|e| e.to_string())?;
+    let plaintext = sealing_key(secret)?
+        .open_in_place(nonce, Aad::empty(), &mut buf)
+        .map_err(|_| "cannot decrypt, the secret key may have changed".to_string())?;
+    Ok(plaintext.to_vec())
+}
+
+fn sealing_key(secret: &str) -> Result<LessSafeKey, String> {
+    let digest = digest::digest(&SHA256, secret.as_bytes());
+    UnboundKey::new(&AES_256_GCM, digest.as_ref())
+        .map(LessSafeKey::new)
+        .map_err(|e| e.to_string())
+}
+
+/// Sets the parameters new passwords are hashed with, the defaults until called
+pub fn set_hash_params(params: HashParams) {
+    *HASH_PARAMS.write().unwrap() = params;
+}
+
+pub fn hash_params() -> HashParams {
+    *HASH_PARAMS.read().unwrap()
+}
+
+/// Hashes the password with Argon2id and the configured parameters, into a PHC string
 pub fn hash_password(password: &str) -> Result<String, String> {
+    hash_password_with(password, &hash_params())
+}
+
+pub fn hash_password_with(password: &str, params: &HashParams) -> Result<String, String> {
     let salt = generate_token(16)?;
-    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &ARGON_CONFIG)
+    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &params.config())
         .map_err(|err| err.to_string())
 }
 
+/// Verifies the password with the algorithm and parameters the hash declares
 pub fn verify_password(hash: &str, pwd: &str) -> Result<bool, String> {
-    argon2::verify_encoded(hash, pwd.as_bytes()).map_err(|err| err.to_string())
+    let info = HashInfo::parse(hash)?;
+    match info.algorithm.as_str() {
+        "argon2i" | "argon2d" | "argon2id" => {
+            argon2::verify_encoded(hash, pwd.as_bytes()).map_err(|err| err.to_string())
+        }
+        algorithm => Err(format!("unsupported password hash algorithm {}", algorithm)),
+    }
+}
+
+/// Whether the hash should be replaced with one made with the configured parameters,
+/// once the password is known after verifying it
+pub fn needs_rehash(hash: &str) -> bool {
+    match HashInfo::parse(hash) {
+        Ok(info) => info.is_weaker_than(&hash_params()),
+        Err(_) => true,
+    }
+}
+
+/// Time it takes to hash a password with the given parameters, as long as verifying it
+pub fn measure_hash(params: &HashParams) -> Result<Duration, String> {
+    let started = Instant::now();
+    hash_password_with("correct horse battery staple", params)?;
+    Ok(started.elapsed())
 }
 
 #[cfg(test)]
 mod test {
-    use crate::secure::{generate_token, hash_password, verify_password};
+    use crate::secure::*;
 
     #[test]
     fn it_generates_a_token() {
@@ -87,4 +271,78 @@ mod test {
         assert!(r.is_ok());
         assert!(r.unwrap());
     }
+
+    #[test]
+    fn it_seals_with_the_secret() {
+        let sealed = seal(b"plaintext", "secret").unwrap();
+        assert!(!sealed.contains("plaintext"));
+        assert_ne!(seal(b"plaintext", "secret").unwrap(), sealed);
+        assert_eq!(open(&sealed, "secret").unwrap(), b"plaintext".to_vec());
+        assert!(open(&sealed, "other").is_err());
+        assert!(open("AAAA", "secret").is_err());
+    }
+
+    #[test]
+    fn it_hashes_with_argon2id() {
+        let params = HashParams::default();
+        let hash = hash_password_with("supersecretpassword", &params).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+        assert!(!HashInfo::parse(&hash).unwrap().is_weaker_than(&params));
+    }
+
+    #[test]
+    fn it_verifies_hashes_of_older_algorithms() {
+        // Made with the Argon2i defaults of earlier versions
+        let legacy = argon2::Config::default();
+        let hash =
+            argon2::hash_encoded(b"supersecretpassword", b"somesaltsomesalt", &legacy).unwrap();
+        assert!(verify_password(&hash, "supersecretpassword").unwrap());
+        assert!(!verify_password(&hash, "wrong").unwrap());
+
+        let info = HashInfo::parse(&hash).unwrap();
+        assert_eq!(info.algorithm, "argon2i");
+        assert_eq!((info.memory, info.iterations, info.parallelism), (4096, 3, 1));
+        assert!(info.is_weaker_than(&HashParams::default()));
+    }
+
+    #[test]
+    fn it_rehashes_weaker_hashes() {
+        let weak = HashParams {
+            memory: 4096,
+            ..HashParams::default()
+        };
+        let hash = hash_password_with("supersecretpassword", &weak).unwrap();
+        assert!(HashInfo::parse(&hash).unwrap().is_weaker_than(&HashParams::default()));
+        let cheaper = HashParams {
+            iterations: 1,
+            ..HashParams::default()
+        };
+        let hash = hash_password_with("supersecretpassword", &HashParams::default()).unwrap();
+        assert!(!HashInfo::parse(&hash).unwrap().is_weaker_than(&cheaper));
+    }
+
+    #[test]
+    fn it_rejects_unknown_hashes() {
+        let bcrypt = "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW";
+        assert!(verify_password(bcrypt, "pwd").is_err());
+        assert!(verify_password("plaintext", "plaintext").is_err());
+        assert!(needs_rehash("plaintext"));
+    }
+
+    #[test]
+    fn it_verifies_signatures() {
+        let sig = generate_signature("secret", "key").to_string();
+        assert!(verify_signature("secret", "key", &sig));
+        assert!(!verify_signature("other", "key", &sig));
+        assert!(!verify_signature("secret", "other", &sig));
+        assert!(!verify_signature("secret", "key", "not hex"));
+    }
+
+    #[test]
+    fn it_digests_with_sha256() {
+        assert_eq!(
+            digest("abc").to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
 }
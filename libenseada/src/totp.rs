@@ -0,0 +1,211 @@
+//! Time-based one-time passwords (RFC 6238), as shown by authenticator apps, and the recovery
+//! codes that stand in for them when the app is lost.
+//!
+//! Codes are 6 digits derived with HMAC-SHA1 from a shared secret and the current 30 seconds step,
+//! the defaults every authenticator app supports. Secrets are exchanged encoded in base32.
+use ring::constant_time;
+use ring::digest::{self, SHA256};
+use ring::hmac::{self, Key, HMAC_SHA1_FOR_LEGACY_USE_ONLY};
+
+use crate::secure;
+
+/// Digits of a code
+pub const DIGITS: usize = 6;
+/// Seconds a code is shown for
+pub const STEP_SECS: u64 = 30;
+/// Steps before and after the current one whose codes are accepted, for clocks out of sync
+pub const SKEW: u64 = 1;
+/// Bytes of generated secrets, the size of an HMAC-SHA1 key as recommended by RFC 4226
+const SECRET_SIZE: usize = 20;
+/// Random bytes of a recovery code, shown as 20 hex characters
+const RECOVERY_CODE_SIZE: usize = 10;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// New random secret, base32-encoded
+pub fn generate_secret() -> Result<String, String> {
+    let secret = secure::generate_token(SECRET_SIZE)?;
+    Ok(base32_encode(secret.as_bytes()))
+}
+
+/// Step of a UNIX time
+pub fn step_at(unix_secs: u64) -> u64 {
+    unix_secs / STEP_SECS
+}
+
+/// Code of the secret for a step, the HOTP of RFC 4226 with the step as counter
+pub fn code_at(secret: &[u8], step: u64) -> String {
+    let key = Key::new(HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let hash = tag.as_ref();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+/// Step of the code among the current one and those within the allowed skew, if it matches any.
+/// Callers should reject steps already used, as a code stays valid for a few steps.
+pub fn verify(secret: &str, code: &str, unix_secs: u64) -> Option<u64> {
+    let secret = base32_decode(secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let current = step_at(unix_secs);
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| {
+        let expected = code_at(&secret, *step);
+        constant_time::verify_slices_are_equal(expected.as_bytes(), code.as_bytes()).is_ok()
+    })
+}
+
+/// New random recovery code, in groups of 5 characters for readability
+pub fn generate_recovery_code() -> Result<String, String> {
+    let code = secure::generate_token(RECOVERY_CODE_SIZE)?.to_string();
+    let groups: Vec<&str> = (0..code.len())
+        .step_by(5)
+        .map(|i| &code[i..(i + 5).min(code.len())])
+        .collect();
+    Ok(groups.join("-"))
+}
+
+/// Hash a recovery code is stored as, ignoring case and separators.
+/// Codes are random enough not to need a salt or a slow hash.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(digest::digest(&SHA256, normalized.as_bytes()))
+}
+
+/// Unpadded base32 of RFC 4648
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 8 / 5 + 1);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+/// Decodes base32, in any case and ignoring padding and whitespace
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in encoded.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let c = c.to_ascii_uppercase() as u8;
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Secret of the SHA-1 test vectors of RFC 6238
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn it_encodes_base32() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (decoded, encoded) in &vectors {
+            assert_eq!(base32_encode(decoded.as_bytes()), *encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), decoded.as_bytes());
+        }
+        assert_eq!(base32_decode("mzxw 6ytb oi======").unwrap(), b"foobar");
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+
+    #[test]
+    fn it_generates_the_codes_of_the_rfc() {
+        let vectors = [
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_111_111_111, "050471"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+            (20_000_000_000, "353130"),
+        ];
+        for (time, code) in &vectors {
+            assert_eq!(code_at(RFC_SECRET, step_at(*time)), *code, "{}", time);
+        }
+    }
+
+    #[test]
+    fn it_tolerates_one_step_of_skew() {
+        let secret = base32_encode(RFC_SECRET);
+        let now = 1_111_111_111;
+        let current = step_at(now);
+        for step in &[current - 1, current, current + 1] {
+            let code = code_at(RFC_SECRET, *step);
+            assert_eq!(verify(&secret, &code, now), Some(*step));
+        }
+        for step in &[current - 2, current + 2] {
+            let code = code_at(RFC_SECRET, *step);
+            assert_eq!(verify(&secret, &code, now), None);
+        }
+    }
+
+    #[test]
+    fn it_rejects_malformed_codes() {
+        let secret = base32_encode(RFC_SECRET);
+        assert_eq!(verify(&secret, "05047", 1_111_111_111), None);
+        assert_eq!(verify(&secret, "o50471", 1_111_111_111), None);
+        assert_eq!(verify(&secret, " 050471 ", 1_111_111_111), Some(37_037_037));
+        assert_eq!(verify("not base32!", "050471", 1_111_111_111), None);
+    }
+
+    #[test]
+    fn it_hashes_recovery_codes_ignoring_their_format() {
+        let code = generate_recovery_code().unwrap();
+        assert_eq!(code.len(), 23);
+        let hash = hash_recovery_code(&code);
+        assert_eq!(hash_recovery_code(&code.replace('-', "")), hash);
+        assert_eq!(hash_recovery_code(&code.to_uppercase()), hash);
+        assert_ne!(hash_recovery_code(&generate_recovery_code().unwrap()), hash);
+    }
+
+    #[test]
+    fn it_generates_secrets_apps_can_read() {
+        let secret = generate_secret().unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_decode(&secret).unwrap().len(), SECRET_SIZE);
+    }
+}
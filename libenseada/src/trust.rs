@@ -0,0 +1,480 @@
+//! Signing keys of the server and the trust bundles resource servers validate its tokens with.
+//!
+//! Access tokens are JWTs signed with the primary key of a [`KeySet`] (ES256). Rotating the set
+//! makes a new primary key and keeps the previous one, so that tokens it signed stay valid
+//! until they expire.
+//!
+//! A [`TrustBundle`] is a snapshot of everything needed to validate tokens without reaching the
+//! server: its issuer, the public keys of the set, the scope registry and the supported algorithms.
+//! It is exported as a JWS in flattened JSON serialization (RFC 7515), signed with the primary
+//! key and valid for a limited window. Bundles carry the keys they are verified with, so they must
+//! be transported over a trusted channel. A bundle exported before a rotation doesn't know the
+//! new key, so tokens signed after it fail validation until a fresh bundle is exported.
+use std::collections::BTreeMap;
+
+use ring::rand::SystemRandom;
+use ring::signature::{
+    self, EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::secure;
+
+/// Algorithm of the signatures of tokens and bundles
+pub const ALGORITHM: &str = "ES256";
+
+/// Media type of the JWS of a bundle
+const BUNDLE_TYPE: &str = "trust-bundle+json";
+
+/// Private key of the server, with the ID tokens name it by
+pub struct SigningKey {
+    kid: String,
+    pkcs8: Vec<u8>,
+    key_pair: EcdsaKeyPair,
+}
+
+impl SigningKey {
+    /// New random P-256 key
+    pub fn generate() -> Result<SigningKey, String> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .map_err(|err| err.to_string())?;
+        let kid = secure::generate_token(8)?.to_string();
+        SigningKey::from_pkcs8(kid, pkcs8.as_ref())
+    }
+
+    /// Restores a key from its PKCS#8 document, see [`SigningKey::pkcs8`]
+    pub fn from_pkcs8(kid: String, pkcs8: &[u8]) -> Result<SigningKey, String> {
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8)
+            .map_err(|err| format!("invalid signing key '{}': {}", kid, err))?;
+        Ok(SigningKey {
+            kid,
+            pkcs8: pkcs8.to_vec(),
+            key_pair,
+        })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// PKCS#8 document of the key, to store it
+    pub fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    /// PKCS#8 document of the key encrypted with the secret, to store it outside of the server
+    pub fn sealed_pkcs8(&self, secret: &str) -> Result<String, String> {
+        secure::seal(&self.pkcs8, secret)
+    }
+
+    /// Restores a key from its encrypted PKCS#8 document, see [`SigningKey::sealed_pkcs8`]
+    pub fn from_sealed_pkcs8(
+        kid: String,
+        sealed: &str,
+        secret: &str,
+    ) -> Result<SigningKey, String> {
+        let pkcs8 = secure::open(sealed, secret)
+            .map_err(|err| format!("invalid signing key '{}': {}", kid, err))?;
+        SigningKey::from_pkcs8(kid, &pkcs8)
+    }
+
+    pub fn public_jwk(&self) -> PublicJwk {
+        // Uncompressed point, 0x04 followed by the coordinates
+        let point = self.key_pair.public_key().as_ref();
+        PublicJwk {
+            kty: "EC".to_string(),
+            crv: "P-256".to_string(),
+            kid: self.kid.clone(),
+            alg: ALGORITHM.to_string(),
+            key_use: "sig".to_string(),
+            x: encode(&point[1..33]),
+            y: encode(&point[33..]),
+        }
+    }
+
+    /// Compact JWS of the claims (RFC 7515), naming the key in its header
+    pub fn sign_jwt<T: Serialize>(&self, claims: &T) -> Result<String, String> {
+        let header = Header {
+            alg: ALGORITHM.to_string(),
+            kid: self.kid.clone(),
+            typ: None,
+        };
+        let message = format!("{}.{}", encode_json(&header)?, encode_json(claims)?);
+        let sig = self.sign(message.as_bytes())?;
+        Ok(format!("{}.{}", message, encode(&sig)))
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        self.key_pair
+            .sign(&SystemRandom::new(), message)
+            .map(|sig| sig.as_ref().to_vec())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Public key of a [`SigningKey`], as a JSON Web Key (RFC 7517)
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PublicJwk {
+    pub kty: String,
+    pub crv: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub x: String,
+    pub y: String,
+}
+
+impl PublicJwk {
+    fn verify(&self, message: &[u8], sig: &[u8]) -> bool {
+        let (x, y) = match (decode(&self.x), decode(&self.y)) {
+            (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => (x, y),
+            _ => return false,
+        };
+        let mut point = vec![0x04];
+        point.extend(x);
+        point.extend(y);
+        UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+            .verify(message, sig)
+            .is_ok()
+    }
+}
+
+/// Keys of the server: the primary one signs, the previous one only verifies
+pub struct KeySet {
+    primary: SigningKey,
+    previous: Option<SigningKey>,
+}
+
+impl KeySet {
+    pub fn new(primary: SigningKey, previous: Option<SigningKey>) -> Self {
+        KeySet { primary, previous }
+    }
+
+    /// New set of a random primary key
+    pub fn generate() -> Result<KeySet, String> {
+        Ok(KeySet::new(SigningKey::generate()?, None))
+    }
+
+    pub fn primary(&self) -> &SigningKey {
+        &self.primary
+    }
+
+    pub fn previous(&self) -> Option<&SigningKey> {
+        self.previous.as_ref()
+    }
+
+    /// Replaces the primary key with a new one, keeping the current one as previous
+    /// and dropping the one before it
+    pub fn rotate(&mut self) -> Result<(), String> {
+        let primary = std::mem::replace(&mut self.primary, SigningKey::generate()?);
+        self.previous = Some(primary);
+        Ok(())
+    }
+
+    /// Public keys of the set, the primary one first
+    pub fn jwks(&self) -> Vec<PublicJwk> {
+        std::iter::once(&self.primary)
+            .chain(self.previous.as_ref())
+            .map(SigningKey::public_jwk)
+            .collect()
+    }
+
+    /// Signs the claims with the primary key
+    pub fn sign_jwt<T: Serialize>(&self, claims: &T) -> Result<String, String> {
+        self.primary.sign_jwt(claims)
+    }
+}
+
+/// JSON Web Key Set (RFC 7517)
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Jwks {
+    pub keys: Vec<PublicJwk>,
+}
+
+/// Offline copy of the trust material of the server, see the module documentation.
+/// Times are UNIX seconds.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TrustBundle {
+    pub issuer: String,
+    pub jwks: Jwks,
+    /// Descriptions of the registered scopes, by name
+    pub scopes: BTreeMap<String, String>,
+    pub algorithms: Vec<String>,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    kid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+}
+
+/// JWS in flattened JSON serialization
+#[derive(Debug, Deserialize, Serialize)]
+struct FlattenedJws {
+    payload: String,
+    protected: String,
+    signature: String,
+}
+
+/// Claims every token must carry to be validated with a bundle
+#[derive(Debug, Deserialize)]
+struct RegisteredClaims {
+    iss: String,
+    exp: u64,
+}
+
+impl TrustBundle {
+    /// Snapshot of the keys of the set, valid for `validity_secs` from `issued_at`
+    pub fn new(
+        issuer: String,
+        keys: &KeySet,
+        scopes: BTreeMap<String, String>,
+        issued_at: u64,
+        validity_secs: u64,
+    ) -> Self {
+        TrustBundle {
+            issuer,
+            jwks: Jwks { keys: keys.jwks() },
+            scopes,
+            algorithms: vec![ALGORITHM.to_string()],
+            issued_at,
+            expires_at: issued_at + validity_secs,
+        }
+    }
+
+    /// Exports the bundle as a JWS signed with the primary key of the set
+    pub fn sign(&self, keys: &KeySet) -> Result<String, String> {
+        let header = Header {
+            alg: ALGORITHM.to_string(),
+            kid: keys.primary().kid().to_string(),
+            typ: Some(BUNDLE_TYPE.to_string()),
+        };
+        let protected = encode_json(&header)?;
+        let payload = encode_json(self)?;
+        let sig = keys
+            .primary()
+            .sign(format!("{}.{}", protected, payload).as_bytes())?;
+        let jws = FlattenedJws {
+            payload,
+            protected,
+            signature: encode(&sig),
+        };
+        serde_json::to_string(&jws).map_err(|err| err.to_string())
+    }
+
+    /// Reads an exported bundle, checking its signature against the keys it carries
+    /// and that it is valid at `now`
+    pub fn open(document: &str, now: u64) -> Result<TrustBundle, String> {
+        let jws: FlattenedJws =
+            serde_json::from_str(document).map_err(|err| format!("malformed bundle: {}", err))?;
+        let header: Header = decode_json(&jws.protected)?;
+        let bundle: TrustBundle = decode_json(&jws.payload)?;
+        let message = format!("{}.{}", jws.protected, jws.payload);
+        if !bundle.verify(&header, message.as_bytes(), &jws.signature) {
+            return Err("the bundle signature does not match its keys".to_string());
+        }
+        bundle.check_validity(now)?;
+        Ok(bundle)
+    }
+
+    /// Claims of a token signed by a key of the bundle and issued by its issuer,
+    /// if neither the token nor the bundle are expired at `now`
+    pub fn verify_token<T: DeserializeOwned>(&self, token: &str, now: u64) -> Result<T, String> {
+        self.check_validity(now)?;
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err("not a signed JWT".to_string());
+        }
+        let header: Header = decode_json(parts[0])?;
+        let message = &token.as_bytes()[..parts[0].len() + 1 + parts[1].len()];
+        if !self.verify(&header, message, parts[2]) {
+            return Err("the token signature does not match any key of the bundle".to_string());
+        }
+
+        let claims: serde_json::Value = decode_json(parts[1])?;
+        let registered: RegisteredClaims =
+            serde_json::from_value(claims.clone()).map_err(|err| err.to_string())?;
+        if registered.iss != self.issuer {
+            return Err(format!("the token was issued by {}", registered.iss));
+        }
+        if registered.exp <= now {
+            return Err("the token is expired".to_string());
+        }
+        serde_json::from_value(claims).map_err(|err| err.to_string())
+    }
+
+    fn check_validity(&self, now: u64) -> Result<(), String> {
+        if now < self.issued_at || now >= self.expires_at {
+            Err("the bundle is not valid at this time, export a fresh one".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn verify(&self, header: &Header, message: &[u8], sig: &str) -> bool {
+        if header.alg != ALGORITHM || !self.algorithms.iter().any(|alg| alg == ALGORITHM) {
+            return false;
+        }
+        let sig = match decode(sig) {
+            Some(sig) => sig,
+            None => return false,
+        };
+        self.jwks
+            .keys
+            .iter()
+            .filter(|key| key.kid == header.kid)
+            .any(|key| key.verify(message, &sig))
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(part: &str) -> Option<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn encode_json<T: Serialize>(value: &T) -> Result<String, String> {
+    serde_json::to_vec(value)
+        .map(|json| encode(&json))
+        .map_err(|err| err.to_string())
+}
+
+fn decode_json<T: DeserializeOwned>(part: &str) -> Result<T, String> {
+    let bytes = decode(part).ok_or_else(|| "malformed base64url".to_string())?;
+    serde_json::from_slice(&bytes).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    const ISSUER: &str = "https://enseada.example.com";
+    const NOW: u64 = 1_600_000_000;
+
+    fn scopes() -> BTreeMap<String, String> {
+        let mut scopes = BTreeMap::new();
+        scopes.insert("profile".to_string(), "Access your profile".to_string());
+        scopes
+    }
+
+    fn export(keys: &KeySet) -> String {
+        TrustBundle::new(ISSUER.to_string(), keys, scopes(), NOW, 3600)
+            .sign(keys)
+            .unwrap()
+    }
+
+    fn token(keys: &KeySet) -> String {
+        keys.sign_jwt(&json!({ "iss": ISSUER, "sub": "jdoe", "exp": NOW + 300 }))
+            .unwrap()
+    }
+
+    #[test]
+    fn it_validates_tokens_with_an_exported_bundle() {
+        let keys = KeySet::generate().unwrap();
+        let bundle = TrustBundle::open(&export(&keys), NOW + 1).unwrap();
+        assert_eq!(bundle.issuer, ISSUER);
+        assert_eq!(bundle.scopes, scopes());
+        assert_eq!(bundle.algorithms, vec![ALGORITHM.to_string()]);
+
+        let claims: serde_json::Value = bundle.verify_token(&token(&keys), NOW + 1).unwrap();
+        assert_eq!(claims["sub"], "jdoe");
+    }
+
+    #[test]
+    fn it_needs_a_fresh_bundle_after_rotation() {
+        let mut keys = KeySet::generate().unwrap();
+        let old_token = token(&keys);
+        let old_bundle = TrustBundle::open(&export(&keys), NOW).unwrap();
+
+        keys.rotate().unwrap();
+        let new_token = token(&keys);
+        assert!(old_bundle
+            .verify_token::<serde_json::Value>(&new_token, NOW)
+            .is_err());
+
+        let fresh_bundle = TrustBundle::open(&export(&keys), NOW).unwrap();
+        assert_eq!(fresh_bundle.jwks.keys.len(), 2);
+        assert!(fresh_bundle
+            .verify_token::<serde_json::Value>(&new_token, NOW)
+            .is_ok());
+        // Tokens of the previous key stay valid until they expire
+        assert!(fresh_bundle
+            .verify_token::<serde_json::Value>(&old_token, NOW)
+            .is_ok());
+    }
+
+    #[test]
+    fn it_rejects_tampered_bundles() {
+        let keys = KeySet::generate().unwrap();
+        let mut jws: serde_json::Value = serde_json::from_str(&export(&keys)).unwrap();
+        let mut bundle = TrustBundle::new(ISSUER.to_string(), &keys, scopes(), NOW, 3600);
+        bundle.issuer = "https://attacker.example.com".to_string();
+        jws["payload"] = json!(encode_json(&bundle).unwrap());
+        assert!(TrustBundle::open(&jws.to_string(), NOW).is_err());
+
+        // Signed by a key the bundle does not carry
+        let other = KeySet::generate().unwrap();
+        let bundle = TrustBundle::new(ISSUER.to_string(), &keys, scopes(), NOW, 3600);
+        assert!(TrustBundle::open(&bundle.sign(&other).unwrap(), NOW).is_err());
+    }
+
+    #[test]
+    fn it_enforces_the_validity_window() {
+        let keys = KeySet::generate().unwrap();
+        let document = export(&keys);
+        assert!(TrustBundle::open(&document, NOW - 1).is_err());
+        assert!(TrustBundle::open(&document, NOW + 3600).is_err());
+
+        let bundle = TrustBundle::open(&document, NOW).unwrap();
+        assert!(bundle
+            .verify_token::<serde_json::Value>(&token(&keys), NOW + 3600)
+            .is_err());
+    }
+
+    #[test]
+    fn it_checks_the_claims_of_tokens() {
+        let keys = KeySet::generate().unwrap();
+        let bundle = TrustBundle::open(&export(&keys), NOW).unwrap();
+        for claims in &[
+            json!({ "iss": "https://other.example.com", "exp": NOW + 300 }),
+            json!({ "iss": ISSUER, "exp": NOW }),
+            json!({ "iss": ISSUER }),
+        ] {
+            let token = keys.sign_jwt(claims).unwrap();
+            assert!(
+                bundle
+                    .verify_token::<serde_json::Value>(&token, NOW)
+                    .is_err(),
+                "{}",
+                claims
+            );
+        }
+        assert!(bundle
+            .verify_token::<serde_json::Value>("opaque", NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn it_restores_keys_from_pkcs8() {
+        let key = SigningKey::generate().unwrap();
+        let restored = SigningKey::from_pkcs8(key.kid().to_string(), key.pkcs8()).unwrap();
+        assert_eq!(restored.public_jwk(), key.public_jwk());
+        let sealed = key.sealed_pkcs8("secret").unwrap();
+        let restored =
+            SigningKey::from_sealed_pkcs8(key.kid().to_string(), &sealed, "secret").unwrap();
+        assert_eq!(restored.public_jwk(), key.public_jwk());
+        assert!(SigningKey::from_sealed_pkcs8(key.kid().to_string(), &sealed, "other").is_err());
+        assert!(SigningKey::from_pkcs8("broken".to_string(), b"broken").is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Mounts the OAuth endpoints on a bare actix-web application, using the in-memory storage.
+//!
+//! Run it with `cargo run -p enseada-oauth --example memory`, then open
+//! http://127.0.0.1:9623/oauth/authorize?response_type=code&client_id=example&redirect_uri=http://127.0.0.1:9623/callback
+//! to get an authorization code that can be exchanged at `POST /oauth/token`.
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use actix_web::web::{Data, Query};
+use actix_web::{get, App, HttpResponse, HttpServer};
+use url::Url;
+
+use enseada_oauth::client::Client;
+use enseada_oauth::facade::Oauth;
+use enseada_oauth::handler::OAuthHandler;
+use enseada_oauth::memory::MemoryStorage;
+use enseada_oauth::request::AuthorizationRequest;
+use enseada_oauth::routes;
+use enseada_oauth::scope::Scope;
+use enseada_oauth::session::Session;
+use enseada_oauth::storage::ClientStorage;
+
+const ADDRESS: &str = "127.0.0.1:9623";
+
+/// Approves every valid authorization request on behalf of a fixed user.
+/// A real application would authenticate the user and ask for consent first.
+#[get("/authorize")]
+async fn authorize(
+    oauth: Data<Arc<dyn Oauth>>,
+    query: Query<AuthorizationRequest>,
+) -> Result<HttpResponse, enseada_oauth::error::Error> {
+    let req = query.into_inner();
+    let client = oauth.validate(&req, None).await?;
+    let session = &mut Session::for_client(client.client_id().to_string());
+    session.set_user_id("example-user".to_string());
+    let res = oauth.authorize(&req, session).await?;
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    let storage = Arc::new(MemoryStorage::new());
+    let redirect_uri = Url::parse(&format!("http://{}/callback", ADDRESS)).unwrap();
+    let mut redirect_uris = HashSet::new();
+    redirect_uris.insert(redirect_uri);
+    let client = Client::public("example".to_string(), Scope::from("profile"), redirect_uris);
+    storage.save_client(client).await.unwrap();
+
+    let oauth: Arc<dyn Oauth> = Arc::new(OAuthHandler::new(
+        storage.clone(),
+        storage.clone(),
+        storage.clone(),
+        storage,
+        "change-me-to-a-long-random-secret".to_string(),
+    ));
+
+    HttpServer::new(move || {
+        App::new()
+            .data(oauth.clone())
+            .service(routes::scope("/oauth").service(authorize))
+    })
+    .bind(ADDRESS)?
+    .run()
+    .await
+}
@@ -0,0 +1,481 @@
+//! Client authentication with JWTs signed by a key of the client (`private_key_jwt`, RFC 7523).
+//!
+//! Instead of a secret, clients that registered public keys as [`Jwk`]s send a signed assertion
+//! in the `client_assertion` parameter of token requests. Assertions must be issued by the client
+//! for itself, be addressed to the token endpoint, expire within [`max_lifetime`] and can only be
+//! used once. Used assertions are remembered in memory until they expire, so a single
+//! [`ClientAssertions`] must be shared by every worker, and a replay sent to another instance
+//! is not detected. RS256 and ES256 are supported.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::client::Client;
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+
+/// Type of the client assertions the token endpoint accepts
+pub const JWT_BEARER: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Longest an assertion can be valid for, counted from its verification
+pub fn max_lifetime() -> Duration {
+    Duration::minutes(5)
+}
+
+/// Parameters of a token request authenticating the client with an assertion
+#[derive(Debug, Clone)]
+pub struct ClientAssertion {
+    pub assertion_type: Option<String>,
+    pub assertion: String,
+}
+
+/// Public key of a client, as a JSON Web Key (RFC 7517)
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    /// Modulus of RSA keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    /// Exponent of RSA keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    /// Curve of EC keys, only P-256 is supported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// Uncompressed point of a P-256 key
+    P256(Vec<u8>),
+}
+
+impl Jwk {
+    /// Algorithm of the signatures the key verifies
+    pub fn algorithm(&self) -> Result<&'static str> {
+        let algorithm = match (self.kty.as_str(), self.crv.as_deref()) {
+            ("RSA", _) => "RS256",
+            ("EC", Some("P-256")) => "ES256",
+            _ => return Err(invalid_key("only RSA and P-256 EC keys are supported")),
+        };
+        match &self.alg {
+            Some(alg) if alg != algorithm => Err(invalid_key(&format!(
+                "algorithm '{}' is not supported for this key type",
+                alg
+            ))),
+            _ => Ok(algorithm),
+        }
+    }
+
+    /// Checks that the key is a public key of a supported type and size
+    pub fn validate(&self) -> Result<()> {
+        self.public_key().map(|_| ())
+    }
+
+    fn public_key(&self) -> Result<PublicKey> {
+        let param = |value: &Option<String>, name: &str| {
+            value
+                .as_deref()
+                .and_then(decode)
+                .ok_or_else(|| invalid_key(&format!("missing or malformed parameter '{}'", name)))
+        };
+        match self.algorithm()? {
+            "RS256" => {
+                let n = param(&self.n, "n")?;
+                if n.iter().skip_while(|byte| **byte == 0).count() < 256 {
+                    return Err(invalid_key("RSA keys must be at least 2048 bits long"));
+                }
+                Ok(PublicKey::Rsa {
+                    n,
+                    e: param(&self.e, "e")?,
+                })
+            }
+            _ => {
+                let (x, y) = (param(&self.x, "x")?, param(&self.y, "y")?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(invalid_key("P-256 coordinates must be 32 bytes long"));
+                }
+                let mut point = vec![0x04];
+                point.extend(x);
+                point.extend(y);
+                Ok(PublicKey::P256(point))
+            }
+        }
+    }
+
+    fn verify(&self, message: &[u8], sig: &[u8]) -> bool {
+        match self.public_key() {
+            Ok(PublicKey::Rsa { n, e }) => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok(),
+            Ok(PublicKey::P256(point)) => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    jti: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &Url) -> bool {
+        let matches = |aud: &String| Url::parse(aud).is_ok_and(|aud| &aud == audience);
+        match self {
+            Audience::One(aud) => matches(aud),
+            Audience::Many(auds) => auds.iter().any(matches),
+        }
+    }
+}
+
+/// Verifies the client assertions addressed to a token endpoint, remembering the used ones
+pub struct ClientAssertions {
+    audience: Url,
+    used: Mutex<HashMap<(String, String), DateTime<Utc>>>,
+}
+
+impl ClientAssertions {
+    /// The audience is the URL of the token endpoint
+    pub fn new(audience: Url) -> Self {
+        ClientAssertions {
+            audience,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn audience(&self) -> &Url {
+        &self.audience
+    }
+
+    /// Authenticates the client with the assertion, which cannot be used again afterwards
+    pub fn verify(&self, client: &Client, assertion: &ClientAssertion) -> Result<()> {
+        if assertion.assertion_type.as_deref() != Some(JWT_BEARER) {
+            return Err(Error::new(
+                ErrorKind::InvalidClient,
+                "unsupported client_assertion_type".to_string(),
+            ));
+        }
+        if client.jwks().is_empty() {
+            return Err(invalid("the client has no registered keys"));
+        }
+
+        let claims = verify_signature(client, &assertion.assertion)?;
+        let client_id = client.client_id();
+        if claims.iss != client_id || claims.sub != client_id {
+            return Err(invalid("iss and sub must be the client_id"));
+        }
+        if !claims.aud.contains(&self.audience) {
+            return Err(invalid("aud must be the token endpoint"));
+        }
+        let now = Utc::now();
+        let expires_at = match Utc.timestamp_opt(claims.exp, 0).single() {
+            Some(exp) if exp <= now => return Err(invalid("the assertion is expired")),
+            Some(exp) if exp <= now + max_lifetime() => exp,
+            _ => return Err(invalid("the assertion must expire within 5 minutes")),
+        };
+        if claims.jti.is_empty() {
+            return Err(invalid("jti is required"));
+        }
+        if !self.remember(client_id, &claims.jti, expires_at) {
+            return Err(invalid("the assertion was already used"));
+        }
+        Ok(())
+    }
+
+    /// Records the use of an assertion, returning false if it was used before.
+    /// Entries are dropped once expired, as expired assertions are refused anyway.
+    fn remember(&self, client_id: &str, jti: &str, expires_at: DateTime<Utc>) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let now = Utc::now();
+        used.retain(|_, expiration| *expiration > now);
+        used.insert((client_id.to_string(), jti.to_string()), expires_at).is_none()
+    }
+}
+
+/// Client an assertion claims to be issued by, read without verifying it.
+/// Clients authenticating with an assertion may omit the client_id parameter.
+pub fn subject(assertion: &str) -> Option<String> {
+    let claims = assertion.split('.').nth(1)?;
+    let claims: serde_json::Value = serde_json::from_slice(&decode(claims)?).ok()?;
+    claims["sub"].as_str().map(str::to_string)
+}
+
+/// Checks the signature of the assertion against the keys of the client, returning its claims
+fn verify_signature(client: &Client, assertion: &str) -> Result<Claims> {
+    verify_jwt(client.jwks(), assertion).map_err(|reason| invalid(&reason))
+}
+
+/// Claims of a JWT signed by one of the keys, like an ID token of an upstream OpenID provider.
+/// Only keys of the algorithm of the JWT are tried, and only the one it names if it does.
+pub fn verify_jwt<T: DeserializeOwned>(
+    keys: &[Jwk],
+    jwt: &str,
+) -> std::result::Result<T, String> {
+    let parts: Vec<&str> = jwt.split('.').collect();
+    if parts.len() != 3 {
+        return Err("not a signed JWT".to_string());
+    }
+    let header: Header = decode_json(parts[0])?;
+    let sig = decode(parts[2]).ok_or_else(|| "malformed signature".to_string())?;
+    let message = &jwt.as_bytes()[..parts[0].len() + 1 + parts[1].len()];
+    let verified = keys
+        .iter()
+        .filter(|key| header.kid.is_none() || key.kid == header.kid)
+        .filter(|key| key.algorithm().is_ok_and(|alg| alg == header.alg))
+        .any(|key| key.verify(message, &sig));
+    if !verified {
+        return Err("the signature does not match any key".to_string());
+    }
+    decode_json(parts[1])
+}
+
+/// ID of the key a JWT names in its header, read without verifying it
+pub fn key_id(jwt: &str) -> Option<String> {
+    let header = jwt.split('.').next()?;
+    decode_json::<Header>(header).ok()?.kid
+}
+
+fn decode(part: &str) -> Option<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn decode_json<T: DeserializeOwned>(part: &str) -> std::result::Result<T, String> {
+    let bytes = decode(part).ok_or_else(|| "malformed JWT".to_string())?;
+    serde_json::from_slice(&bytes).map_err(|err| err.to_string())
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidClient,
+        format!("invalid client assertion: {}", reason),
+    )
+}
+
+fn invalid_key(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidRequest, format!("invalid key: {}", reason))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair};
+    use serde_json::json;
+
+    use crate::scope::Scope;
+
+    use super::*;
+
+    const TOKEN_ENDPOINT: &str = "https://enseada.example.com/oauth/token";
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .unwrap();
+        EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+            .unwrap()
+    }
+
+    fn jwk(key_pair: &EcdsaKeyPair) -> Jwk {
+        let point = key_pair.public_key().as_ref();
+        Jwk {
+            kty: "EC".to_string(),
+            kid: Some("key-1".to_string()),
+            alg: None,
+            n: None,
+            e: None,
+            crv: Some("P-256".to_string()),
+            x: Some(encode(&point[1..33])),
+            y: Some(encode(&point[33..])),
+        }
+    }
+
+    fn client(key_pair: &EcdsaKeyPair) -> Client {
+        let mut client = Client::confidential_with_hash(
+            "service".to_string(),
+            "unused".to_string(),
+            Scope::from("*"),
+            HashSet::new(),
+        );
+        client.set_jwks(vec![jwk(key_pair)]).unwrap();
+        client
+    }
+
+    fn sign(key_pair: &EcdsaKeyPair, claims: serde_json::Value) -> ClientAssertion {
+        let header = encode(json!({"alg": "ES256", "kid": "key-1"}).to_string().as_bytes());
+        let claims = encode(claims.to_string().as_bytes());
+        let message = format!("{}.{}", header, claims);
+        let sig = key_pair
+            .sign(&SystemRandom::new(), message.as_bytes())
+            .unwrap();
+        ClientAssertion {
+            assertion_type: Some(JWT_BEARER.to_string()),
+            assertion: format!("{}.{}", message, encode(sig.as_ref())),
+        }
+    }
+
+    fn claims(jti: &str) -> serde_json::Value {
+        json!({
+            "iss": "service",
+            "sub": "service",
+            "aud": TOKEN_ENDPOINT,
+            "exp": (Utc::now() + Duration::minutes(1)).timestamp(),
+            "jti": jti,
+        })
+    }
+
+    fn assertions() -> ClientAssertions {
+        ClientAssertions::new(Url::parse(TOKEN_ENDPOINT).unwrap())
+    }
+
+    #[test]
+    fn it_accepts_a_valid_assertion_once() {
+        let key_pair = key_pair();
+        let client = client(&key_pair);
+        let assertions = assertions();
+        let assertion = sign(&key_pair, claims("1"));
+        assert_eq!(subject(&assertion.assertion).as_deref(), Some("service"));
+        assert!(assertions.verify(&client, &assertion).is_ok());
+        let err = assertions.verify(&client, &assertion).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+        assert!(assertions.verify(&client, &sign(&key_pair, claims("2"))).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_assertions_signed_by_another_key() {
+        let key_pair = key_pair();
+        let client = client(&key_pair);
+        let assertion = sign(&self::key_pair(), claims("1"));
+        assert!(assertions().verify(&client, &assertion).is_err());
+
+        // Claims swapped into an assertion signed by the right key
+        let signed = sign(&key_pair, claims("2")).assertion;
+        let forged = sign(&key_pair, claims("3")).assertion;
+        let mut parts: Vec<&str> = signed.split('.').collect();
+        parts[1] = forged.split('.').nth(1).unwrap();
+        let assertion = ClientAssertion {
+            assertion_type: Some(JWT_BEARER.to_string()),
+            assertion: parts.join("."),
+        };
+        assert!(assertions().verify(&client, &assertion).is_err());
+    }
+
+    #[test]
+    fn it_checks_the_claims() {
+        let key_pair = key_pair();
+        let client = client(&key_pair);
+        let assertions = assertions();
+        let mut invalid_claims = vec![];
+        for (name, value) in [
+            ("iss", json!("other")),
+            ("sub", json!("other")),
+            ("aud", json!("https://enseada.example.com/oauth/introspect")),
+            ("exp", json!((Utc::now() - Duration::seconds(1)).timestamp())),
+            ("exp", json!((Utc::now() + Duration::hours(1)).timestamp())),
+            ("jti", json!("")),
+        ] {
+            let mut claims = claims(name);
+            claims[name] = value;
+            invalid_claims.push(claims);
+        }
+        for claims in invalid_claims {
+            let assertion = sign(&key_pair, claims.clone());
+            assert!(assertions.verify(&client, &assertion).is_err(), "{}", claims);
+        }
+
+        let mut claims = claims("aud");
+        claims["aud"] = json!(["https://example.com", TOKEN_ENDPOINT]);
+        assert!(assertions.verify(&client, &sign(&key_pair, claims)).is_ok());
+    }
+
+    #[test]
+    fn it_requires_the_jwt_bearer_type() {
+        let key_pair = key_pair();
+        let mut assertion = sign(&key_pair, claims("1"));
+        assertion.assertion_type = Some("urn:example:saml".to_string());
+        assert!(assertions().verify(&client(&key_pair), &assertion).is_err());
+    }
+
+    #[test]
+    fn it_verifies_jwts_of_other_issuers() {
+        let key_pair = key_pair();
+        let jwt = sign(&key_pair, json!({ "iss": "https://sso.example.com", "sub": "jdoe" }));
+        let jwt = jwt.assertion;
+        assert_eq!(key_id(&jwt).as_deref(), Some("key-1"));
+
+        let claims: serde_json::Value = verify_jwt(&[jwk(&key_pair)], &jwt).unwrap();
+        assert_eq!(claims["sub"], "jdoe");
+        assert!(verify_jwt::<serde_json::Value>(&[jwk(&self::key_pair())], &jwt).is_err());
+        assert!(verify_jwt::<serde_json::Value>(&[], &jwt).is_err());
+    }
+
+    #[test]
+    fn it_rejects_unsupported_keys() {
+        let mut key = jwk(&key_pair());
+        assert!(key.validate().is_ok());
+        key.alg = Some("RS256".to_string());
+        assert!(key.validate().is_err());
+        key.alg = None;
+        key.crv = Some("P-384".to_string());
+        assert!(key.validate().is_err());
+
+        let rsa = Jwk {
+            kty: "RSA".to_string(),
+            kid: None,
+            alg: None,
+            n: Some(encode(&[0xff; 128])),
+            e: Some(encode(&[1, 0, 1])),
+            crv: None,
+            x: None,
+            y: None,
+        };
+        assert!(rsa.validate().is_err());
+        let rsa = Jwk {
+            n: Some(encode(&[0xff; 256])),
+            ..rsa
+        };
+        assert!(rsa.validate().is_ok());
+    }
+}
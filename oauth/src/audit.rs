@@ -0,0 +1,147 @@
+//! Audit trail of security relevant OAuth events, like token issuance and revocation.
+//!
+//! The routes report events to the [`AuditSink`] registered as application data
+//! as an `Arc<dyn AuditSink>`, if any. Sinks must not block or fail the request,
+//! so they are expected to persist events in the background.
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    CodeIssuance,
+    TokenIssuance,
+    Refresh,
+    Revocation,
+    Introspection,
+    /// A user approving or denying a device authorization
+    DeviceApproval,
+    /// A user changing their own password
+    PasswordChange,
+    /// An administrator setting the password of another user
+    PasswordReset,
+    /// A user setting a new password with a reset token emailed to them
+    PasswordRecovery,
+    /// A user enabling or disabling two-factor authentication
+    TwoFactorChange,
+    /// An administrator purging a user, along with their tokens and sessions
+    UserDeletion,
+    /// An administrator deleting a user without purging them, which revokes their tokens
+    /// and sessions and keeps them from logging in
+    UserDeactivation,
+    /// An administrator letting a deactivated user log in again
+    UserReactivation,
+    /// An administrator getting a token to act as another user
+    Impersonation,
+    /// A user being issued tokens on a device family they were never seen with
+    NewDevice,
+}
+
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AuditAction::Login => "login",
+            AuditAction::CodeIssuance => "code_issuance",
+            AuditAction::TokenIssuance => "token_issuance",
+            AuditAction::Refresh => "refresh",
+            AuditAction::Revocation => "revocation",
+            AuditAction::Introspection => "introspection",
+            AuditAction::DeviceApproval => "device_approval",
+            AuditAction::PasswordChange => "password_change",
+            AuditAction::PasswordReset => "password_reset",
+            AuditAction::PasswordRecovery => "password_recovery",
+            AuditAction::TwoFactorChange => "two_factor_change",
+            AuditAction::UserDeletion => "user_deletion",
+            AuditAction::UserDeactivation => "user_deactivation",
+            AuditAction::UserReactivation => "user_reactivation",
+            AuditAction::Impersonation => "impersonation",
+            AuditAction::NewDevice => "new_device",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEvent {
+    pub action: AuditAction,
+    pub outcome: Outcome,
+    pub client_id: Option<String>,
+    pub user_id: Option<String>,
+    /// User the action was performed on, when it is not the acting user
+    pub target: Option<String>,
+    /// Why the action failed, if it did
+    pub reason: Option<String>,
+    /// User acting as `user_id`, when the action was performed while impersonating them
+    pub impersonator: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn success(action: AuditAction) -> Self {
+        AuditEvent {
+            action,
+            outcome: Outcome::Success,
+            client_id: None,
+            user_id: None,
+            target: None,
+            reason: None,
+            impersonator: None,
+        }
+    }
+
+    pub fn failure(action: AuditAction, reason: String) -> Self {
+        AuditEvent {
+            outcome: Outcome::Failure,
+            reason: Some(reason),
+            ..Self::success(action)
+        }
+    }
+
+    pub fn set_client_id(mut self, client_id: Option<String>) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    pub fn set_user_id(mut self, user_id: Option<String>) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
+    pub fn set_target(mut self, target: Option<String>) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn set_impersonator(mut self, impersonator: Option<String>) -> Self {
+        self.impersonator = impersonator;
+        self
+    }
+}
+
+pub trait AuditSink: Send + Sync {
+    /// Records an event of the request, which gives access to the client address
+    fn record(&self, req: &HttpRequest, event: AuditEvent);
+}
+
+/// Reports the event to the [`AuditSink`] registered as application data, if any
+pub fn record(req: &HttpRequest, event: AuditEvent) {
+    if let Some(sink) = req.app_data::<Data<Arc<dyn AuditSink>>>() {
+        sink.record(req, event);
+    }
+}
+
+/// Whether an [`AuditSink`] is registered, to skip collecting details nobody records
+pub fn is_enabled(req: &HttpRequest) -> bool {
+    req.app_data::<Data<Arc<dyn AuditSink>>>().is_some()
+}
@@ -0,0 +1,113 @@
+//! Optional binding of tokens to the origin of the request they were issued for.
+//!
+//! Tokens issued to clients that [bind tokens](crate::client::Client::binds_tokens) record the
+//! address and user agent of the token request, and using them from another origin is rejected
+//! with `invalid_grant`, so that a stolen access token is useless elsewhere and the client has to
+//! refresh it. Clients behind the same NAT share an address and can't be told apart, while mobile
+//! clients change address often and will have to refresh every time, which is why it is opt-in.
+//!
+//! Addresses are resolved by the [`AddressResolver`] registered as application data
+//! as an `Arc<dyn AddressResolver>`, if any, so that reverse proxies can be accounted for.
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+
+use crate::user_agent::UserAgent;
+
+/// Where the requests using a token come from
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Origin {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<IpAddr>,
+    user_agent: String,
+}
+
+impl Origin {
+    pub fn new(ip: Option<IpAddr>, user_agent: String) -> Self {
+        Origin {
+            ip: ip.map(canonical),
+            user_agent,
+        }
+    }
+
+    /// Origin of the request, with the address resolved by the registered [`AddressResolver`]
+    pub fn of(req: &HttpRequest) -> Self {
+        let user_agent = UserAgent::from(req);
+        Self::new(client_ip(req), user_agent.raw().to_string())
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    /// Returns true if a token bound to this origin can be used from the other one
+    pub fn matches(&self, other: &Origin) -> bool {
+        self.ip.map(canonical) == other.ip.map(canonical) && self.user_agent == other.user_agent
+    }
+}
+
+/// IPv4 addresses mapped to IPv6, like `::ffff:10.0.0.1` from dual-stack sockets,
+/// are compared as the plain IPv4 address
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Resolves the address of the client of a request, like through trusted reverse proxies
+pub trait AddressResolver: Send + Sync {
+    fn resolve(&self, req: &HttpRequest) -> Option<IpAddr>;
+}
+
+/// Address of the client of the request, the peer address if no [`AddressResolver`] is registered
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    match req.app_data::<Data<Arc<dyn AddressResolver>>>() {
+        Some(resolver) => resolver.resolve(req),
+        None => req.peer_addr().map(|addr| addr.ip()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:76.0) Gecko/20100101 Firefox/76.0";
+
+    fn origin(ip: &str, user_agent: &str) -> Origin {
+        Origin::new(Some(ip.parse().unwrap()), user_agent.to_string())
+    }
+
+    #[test]
+    fn it_matches_the_same_address_and_user_agent() {
+        let bound = origin("10.0.0.1", FIREFOX);
+        assert!(bound.matches(&origin("10.0.0.1", FIREFOX)));
+        assert!(!bound.matches(&origin("10.0.0.2", FIREFOX)));
+        assert!(!bound.matches(&origin("10.0.0.1", "curl/7.68.0")));
+        assert!(!bound.matches(&Origin::new(None, FIREFOX.to_string())));
+    }
+
+    #[test]
+    fn it_compares_ipv4_mapped_addresses_as_ipv4() {
+        let bound = origin("::ffff:10.0.0.1", FIREFOX);
+        assert_eq!(bound.ip(), Some("10.0.0.1".parse().unwrap()));
+        assert!(bound.matches(&origin("10.0.0.1", FIREFOX)));
+        assert!(origin("10.0.0.1", FIREFOX).matches(&origin("::ffff:10.0.0.1", FIREFOX)));
+        assert!(!bound.matches(&origin("::1", FIREFOX)));
+
+        // Addresses stored before they were canonicalized still match
+        let stored: Origin = serde_json::from_value(serde_json::json!({
+            "ip": "::ffff:10.0.0.1",
+            "user_agent": FIREFOX,
+        }))
+        .unwrap();
+        assert!(stored.matches(&origin("10.0.0.1", FIREFOX)));
+    }
+}
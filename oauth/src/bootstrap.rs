@@ -0,0 +1,56 @@
+//! Pre-authorized tokens for setting up automation where no browser or device login is possible,
+//! like air-gapped installs. An administrator mints a bootstrap token bound to a user and a client,
+//! and its holder exchanges it once for a regular token set.
+use std::fmt;
+use std::ops::Add;
+
+use chrono::{DateTime, Duration, Utc};
+
+use enseada::secure::SecureSecret;
+
+use crate::secrets;
+use crate::session::Session;
+use crate::Expirable;
+
+#[derive(Debug, Clone)]
+pub struct BootstrapToken {
+    token: SecureSecret,
+    session: Session,
+    expiration: DateTime<Utc>,
+}
+
+impl BootstrapToken {
+    pub fn new(token: SecureSecret, session: Session, expires_in: Duration) -> BootstrapToken {
+        BootstrapToken {
+            token,
+            session,
+            expiration: Utc::now().add(expires_in),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Expirable for BootstrapToken {
+    fn expiration(&self) -> &DateTime<Utc> {
+        &self.expiration
+    }
+
+    fn expires_in(&self) -> i64 {
+        self.expiration
+            .signed_duration_since(Utc::now())
+            .num_seconds()
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expiration.lt(&Utc::now())
+    }
+}
+
+impl fmt::Display for BootstrapToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", secrets::encode(&self.token))
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::ops::Add;
 
 use chrono::{DateTime, Duration, Utc};
@@ -5,8 +6,9 @@ use serde::{Serialize, Serializer};
 
 use enseada::secure::SecureSecret;
 
-use crate::oauth::session::Session;
-use crate::oauth::Expirable;
+use crate::secrets;
+use crate::session::Session;
+use crate::Expirable;
 
 #[derive(Debug, Clone)]
 pub struct AuthorizationCode {
@@ -45,9 +47,9 @@ impl Expirable for AuthorizationCode {
     }
 }
 
-impl ToString for AuthorizationCode {
-    fn to_string(&self) -> String {
-        self.code.to_string()
+impl fmt::Display for AuthorizationCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", secrets::encode(&self.code))
     }
 }
 
@@ -56,7 +58,7 @@ impl Serialize for AuthorizationCode {
     where
         S: Serializer,
     {
-        let code = self.code.to_string();
+        let code = self.to_string();
         code.serialize(serializer)
     }
 }
@@ -0,0 +1,187 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::binding::Origin;
+use crate::user_agent::UserAgent;
+use crate::storage::DeviceStorage;
+use crate::Result;
+
+/// How the subject of a session authenticated when it was established
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    Password,
+    SessionCookie,
+    /// Through an upstream OpenID provider
+    Sso,
+    ClientSecretBasic,
+    ClientSecretPost,
+    None,
+}
+
+/// Coarse fingerprint of the device holding a session
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Device {
+    user_agent: UserAgent,
+    auth_method: AuthMethod,
+    /// Only kept for the request, tokens of clients that bind them record it in their [`Origin`]
+    #[serde(skip)]
+    ip: Option<IpAddr>,
+}
+
+impl Device {
+    pub fn new(user_agent: UserAgent, auth_method: AuthMethod) -> Self {
+        Device {
+            user_agent,
+            auth_method,
+            ip: None,
+        }
+    }
+
+    pub fn user_agent(&self) -> &UserAgent {
+        &self.user_agent
+    }
+
+    pub fn auth_method(&self) -> AuthMethod {
+        self.auth_method
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: Option<IpAddr>) -> &mut Self {
+        self.ip = ip;
+        self
+    }
+
+    /// Origin the tokens issued to this device are bound to, if their client binds them
+    pub fn origin(&self) -> Origin {
+        Origin::new(self.ip, self.user_agent.raw().to_string())
+    }
+
+    /// Identifies the kind of device, regardless of its exact version
+    pub fn family(&self) -> String {
+        format!(
+            "{} on {}",
+            self.user_agent.family(),
+            self.user_agent.platform()
+        )
+    }
+}
+
+/// Notified when a user is issued tokens on a device family they were never seen with
+#[async_trait]
+pub trait NewDeviceListener: Send + Sync {
+    async fn on_new_device(&self, user_id: &str, client_id: &str, device: &Device);
+}
+
+/// Keeps track of the device families each user has been seen with
+pub struct DeviceTracker {
+    storage: Arc<dyn DeviceStorage>,
+    listeners: Vec<Arc<dyn NewDeviceListener>>,
+}
+
+impl DeviceTracker {
+    pub fn new(storage: Arc<dyn DeviceStorage>) -> Self {
+        DeviceTracker {
+            storage,
+            listeners: Vec::new(),
+        }
+    }
+
+    pub fn add_listener(&mut self, listener: Arc<dyn NewDeviceListener>) -> &mut Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Records the device the client requested tokens of the user from,
+    /// returning true and notifying the listeners if its family was never seen before
+    pub async fn track(&self, user_id: &str, client_id: &str, device: &Device) -> Result<bool> {
+        let family = device.family();
+        let is_new = self.storage.add_device_family(user_id, &family).await?;
+        if is_new {
+            log::warn!(
+                "New device '{}' for user {} through client {} ({:?}): {}",
+                &family,
+                user_id,
+                client_id,
+                device.auth_method(),
+                device.user_agent().raw()
+            );
+            for listener in &self.listeners {
+                listener.on_new_device(user_id, client_id, device).await;
+            }
+        }
+        Ok(is_new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+
+    use crate::user_agent::UserAgent;
+    use crate::memory::MemoryStorage;
+
+    use super::{AuthMethod, Device, DeviceTracker, NewDeviceListener};
+
+    fn device(ua: &str) -> Device {
+        Device::new(UserAgent::parse(ua), AuthMethod::Password)
+    }
+
+    #[test]
+    fn it_detects_a_first_seen_device() {
+        let tracker = DeviceTracker::new(Arc::new(MemoryStorage::new()));
+        let firefox =
+            device("Mozilla/5.0 (X11; Linux x86_64; rv:76.0) Gecko/20100101 Firefox/76.0");
+        let newer_firefox =
+            device("Mozilla/5.0 (X11; Linux x86_64; rv:77.0) Gecko/20100101 Firefox/77.0");
+
+        assert!(block_on(tracker.track("user:test", "cli", &firefox)).unwrap());
+        assert!(!block_on(tracker.track("user:test", "cli", &firefox)).unwrap());
+        assert!(!block_on(tracker.track("user:test", "cli", &newer_firefox)).unwrap());
+    }
+
+    #[test]
+    fn it_tracks_families_per_user() {
+        let tracker = DeviceTracker::new(Arc::new(MemoryStorage::new()));
+        let firefox =
+            device("Mozilla/5.0 (X11; Linux x86_64; rv:76.0) Gecko/20100101 Firefox/76.0");
+        let curl = device("curl/7.68.0");
+
+        assert!(block_on(tracker.track("user:test", "cli", &firefox)).unwrap());
+        assert!(block_on(tracker.track("user:test", "cli", &curl)).unwrap());
+        assert!(block_on(tracker.track("user:other", "cli", &firefox)).unwrap());
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(String, String)>>);
+
+    #[async_trait]
+    impl NewDeviceListener for Recorder {
+        async fn on_new_device(&self, user_id: &str, client_id: &str, _device: &Device) {
+            let mut seen = self.0.lock().unwrap();
+            seen.push((user_id.to_string(), client_id.to_string()));
+        }
+    }
+
+    #[test]
+    fn it_notifies_listeners_of_new_devices_only() {
+        let recorder = Arc::new(Recorder::default());
+        let mut tracker = DeviceTracker::new(Arc::new(MemoryStorage::new()));
+        tracker.add_listener(recorder.clone());
+        let curl = device("curl/7.68.0");
+
+        block_on(tracker.track("user:test", "cli", &curl)).unwrap();
+        block_on(tracker.track("user:test", "web", &curl)).unwrap();
+        let seen = recorder.0.lock().unwrap();
+        assert_eq!(*seen, vec![("user:test".to_string(), "cli".to_string())]);
+    }
+}
@@ -0,0 +1,281 @@
+//! Device authorization grant (RFC 8628), for clients running where no browser is available,
+//! like CLIs on headless servers. The client asks for a device code and a short user code,
+//! then polls the token endpoint with the device code while the user enters the user code
+//! on the verification page, from a browser on another device, and approves the request.
+use std::ops::Add;
+
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use enseada::secure;
+
+use crate::error::{Error, ErrorKind};
+use crate::scope::Scope;
+use crate::session::Session;
+use crate::Expirable;
+
+/// Grant type of the token requests exchanging a device code
+pub const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Consonants only, so that user codes can't spell words or be mistaken for digits
+const USER_CODE_CHARSET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+/// How long a device code can be polled for, and its user code approved
+pub fn lifetime() -> Duration {
+    Duration::minutes(10)
+}
+
+/// Minimum time between two polls of a device code, increased every time a client polls faster
+pub fn interval() -> Duration {
+    Duration::seconds(5)
+}
+
+/// Generates a user code like `BCDF-GHJK`
+pub fn generate_user_code() -> String {
+    let mut code = String::with_capacity(USER_CODE_LENGTH);
+    while code.len() < USER_CODE_LENGTH {
+        let bytes = secure::generate_token(USER_CODE_LENGTH).unwrap();
+        // Bytes past the last multiple of the charset length are skipped, to avoid a modulo bias
+        let limit = 256 - 256 % USER_CODE_CHARSET.len();
+        for byte in bytes.as_bytes() {
+            if (*byte as usize) < limit && code.len() < USER_CODE_LENGTH {
+                code.push(USER_CODE_CHARSET[*byte as usize % USER_CODE_CHARSET.len()] as char);
+            }
+        }
+    }
+    format_user_code(&code)
+}
+
+/// Normalizes a user code typed by a user, ignoring case, spaces and dashes
+pub fn normalize_user_code(input: &str) -> String {
+    let code: String = input
+        .chars()
+        .filter(char::is_ascii_alphabetic)
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    format_user_code(&code)
+}
+
+fn format_user_code(code: &str) -> String {
+    if code.len() == USER_CODE_LENGTH {
+        let (first, second) = code.split_at(USER_CODE_LENGTH / 2);
+        format!("{}-{}", first, second)
+    } else {
+        code.to_string()
+    }
+}
+
+/// Decision of the user on a device authorization
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceAuthorizationStatus {
+    Pending,
+    /// Approved by the user, with the session the tokens will be issued for
+    Approved { session: Box<Session> },
+    Denied,
+}
+
+/// Authorization requested by a device, stored under the signature of its device code
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceAuthorization {
+    client_id: String,
+    scope: Scope,
+    user_code: String,
+    status: DeviceAuthorizationStatus,
+    /// Seconds the client must wait between polls
+    interval: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_polled_at: Option<DateTime<Utc>>,
+    #[serde(with = "ts_seconds")]
+    expiration: DateTime<Utc>,
+    /// Revision of the stored authorization, so that concurrent updates conflict
+    /// instead of overwriting each other
+    #[serde(skip)]
+    revision: Option<String>,
+}
+
+impl DeviceAuthorization {
+    pub fn new(
+        client_id: String,
+        scope: Scope,
+        user_code: String,
+        expires_in: Duration,
+    ) -> DeviceAuthorization {
+        DeviceAuthorization {
+            client_id,
+            scope,
+            user_code,
+            status: DeviceAuthorizationStatus::Pending,
+            interval: interval().num_seconds(),
+            last_polled_at: None,
+            expiration: Utc::now().add(expires_in),
+            revision: None,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    pub fn user_code(&self) -> &str {
+        &self.user_code
+    }
+
+    pub fn status(&self) -> &DeviceAuthorizationStatus {
+        &self.status
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, DeviceAuthorizationStatus::Pending)
+    }
+
+    pub fn interval(&self) -> i64 {
+        self.interval
+    }
+
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    pub fn set_revision(&mut self, revision: Option<String>) -> &mut Self {
+        self.revision = revision;
+        self
+    }
+
+    /// Records the decision of the user, which the client learns the next time it polls
+    pub fn decide(&mut self, session: Option<Session>) -> &mut Self {
+        self.status = match session {
+            Some(session) => DeviceAuthorizationStatus::Approved {
+                session: Box::new(session),
+            },
+            None => DeviceAuthorizationStatus::Denied,
+        };
+        self
+    }
+
+    /// Records a poll of the client, returning the approved session or the error telling
+    /// the client how to go on. Clients polling faster than the interval are asked to slow down,
+    /// and the interval grows by five seconds every time they are.
+    pub fn poll(&mut self, now: DateTime<Utc>) -> crate::Result<Session> {
+        if self.expiration.lt(&now) {
+            return Err(Error::new(
+                ErrorKind::ExpiredToken,
+                "device code expired".to_string(),
+            ));
+        }
+
+        let too_early = self
+            .last_polled_at
+            .is_some_and(|last| now.signed_duration_since(last).num_seconds() < self.interval);
+        self.last_polled_at = Some(now);
+        if too_early {
+            self.interval += interval().num_seconds();
+            return Err(Error::new(
+                ErrorKind::SlowDown,
+                format!("poll at most every {} seconds", self.interval),
+            ));
+        }
+
+        match &self.status {
+            DeviceAuthorizationStatus::Pending => Err(Error::new(
+                ErrorKind::AuthorizationPending,
+                "the user has not approved the request yet".to_string(),
+            )),
+            DeviceAuthorizationStatus::Approved { session } => Ok(session.as_ref().clone()),
+            DeviceAuthorizationStatus::Denied => Err(Error::new(
+                ErrorKind::AccessDenied,
+                "the user denied the request".to_string(),
+            )),
+        }
+    }
+}
+
+impl Expirable for DeviceAuthorization {
+    fn expiration(&self) -> &DateTime<Utc> {
+        &self.expiration
+    }
+
+    fn expires_in(&self) -> i64 {
+        self.expiration
+            .signed_duration_since(Utc::now())
+            .num_seconds()
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expiration.lt(&Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn authorization() -> DeviceAuthorization {
+        DeviceAuthorization::new(
+            "cli".to_string(),
+            Scope::from("profile"),
+            generate_user_code(),
+            lifetime(),
+        )
+    }
+
+    #[test]
+    fn it_generates_readable_user_codes() {
+        let code = generate_user_code();
+        assert_eq!(code.len(), 9);
+        assert_eq!(&code[4..5], "-");
+        assert!(code
+            .chars()
+            .filter(|c| *c != '-')
+            .all(|c| USER_CODE_CHARSET.contains(&(c as u8))));
+        assert_eq!(normalize_user_code(&code.to_lowercase().replace('-', " ")), code);
+    }
+
+    #[test]
+    fn it_asks_to_wait_until_approved() {
+        let mut auth = authorization();
+        let now = Utc::now();
+        let err = auth.poll(now).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AuthorizationPending);
+
+        let mut session = Session::for_client("cli".to_string());
+        session.set_user_id("user:jdoe".to_string());
+        auth.decide(Some(session));
+        let session = auth.poll(now + Duration::seconds(5)).unwrap();
+        assert_eq!(session.user_id().as_deref(), Some("user:jdoe"));
+    }
+
+    #[test]
+    fn it_slows_down_fast_pollers() {
+        let mut auth = authorization();
+        let now = Utc::now();
+        auth.poll(now).unwrap_err();
+        let err = auth.poll(now + Duration::seconds(1)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::SlowDown);
+        assert_eq!(auth.interval(), 10);
+
+        let err = auth.poll(now + Duration::seconds(7)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::SlowDown);
+        let err = auth.poll(now + Duration::seconds(30)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AuthorizationPending);
+    }
+
+    #[test]
+    fn it_reports_denied_and_expired_requests() {
+        let mut auth = authorization();
+        auth.decide(None);
+        let err = auth.poll(Utc::now()).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AccessDenied);
+
+        let err = auth
+            .poll(Utc::now() + lifetime() + Duration::seconds(1))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::ExpiredToken);
+    }
+}
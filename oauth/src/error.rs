@@ -0,0 +1,246 @@
+use std::fmt::{self, Debug, Display, Formatter};
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Serialize, Debug)]
+pub struct Error {
+    error: ErrorKind,
+    error_description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_uri: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, description: String) -> Error {
+        Error {
+            error: kind,
+            error_description: description,
+            error_uri: None,
+        }
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.error
+    }
+
+    pub fn set_error_uri(&mut self, url: Url) -> &mut Self {
+        self.error_uri = Some(url.to_string());
+        self
+    }
+
+    pub fn error_uri(&self) -> Option<&str> {
+        self.error_uri.as_deref()
+    }
+
+    pub fn description(&self) -> &str {
+        &self.error_description
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.error_description)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::new(ErrorKind::ServerError, message)
+    }
+}
+
+impl From<couchdb::error::Error> for Error {
+    fn from(err: couchdb::error::Error) -> Self {
+        Self::from(err.to_string())
+    }
+}
+
+/// Token endpoint errors, as described in [RFC 6749](https://tools.ietf.org/html/rfc6749#section-5.2).
+/// Errors of the authorization endpoint are redirected to the client instead, see `AuthorizationErrorResponse`.
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self.kind() {
+            ErrorKind::AccessDenied => StatusCode::FORBIDDEN,
+            ErrorKind::InvalidClient => StatusCode::UNAUTHORIZED,
+            ErrorKind::ServerError | ErrorKind::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::TemporarilyUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::AuthorizationPending
+            | ErrorKind::ExpiredToken
+            | ErrorKind::InvalidGrant
+            | ErrorKind::InvalidRedirectUri
+            | ErrorKind::InvalidRequest
+            | ErrorKind::InvalidScope
+            | ErrorKind::InvalidTarget
+            | ErrorKind::LoginRequired
+            | ErrorKind::SlowDown
+            | ErrorKind::UnauthorizedClient
+            | ErrorKind::UnsupportedGrantType
+            | ErrorKind::UnsupportedResponseType => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        res.header(header::CACHE_CONTROL, "no-store")
+            .header(header::PRAGMA, "no-cache");
+        if self.kind() == &ErrorKind::InvalidClient {
+            res.header(header::WWW_AUTHENTICATE, "Basic realm=\"enseada\"");
+        }
+        res.json(self)
+    }
+}
+
+/// Links errors to the documentation of their kind, hosted under a base URL
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorDocs {
+    base: Url,
+}
+
+impl ErrorDocs {
+    pub fn new(mut base: Url) -> Self {
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        ErrorDocs { base }
+    }
+
+    /// URL of the documentation of the error kind, like `<base>/invalid_scope`
+    pub fn uri(&self, kind: &ErrorKind) -> Url {
+        let mut url = self.base.clone();
+        url.set_path(&format!("{}{}", self.base.path(), kind.code()));
+        url
+    }
+
+    /// Sets the `error_uri` of the error, unless it already links somewhere else
+    pub fn document(&self, mut err: Error) -> Error {
+        if err.error_uri.is_none() {
+            err.set_error_uri(self.uri(err.kind()));
+        }
+        err
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    AccessDenied,
+    /// The user has not approved the device authorization yet, the client should keep polling
+    AuthorizationPending,
+    /// The device code expired before the user approved it
+    ExpiredToken,
+    InvalidClient,
+    InvalidGrant,
+    InvalidRedirectUri,
+    InvalidRequest,
+    InvalidScope,
+    /// A requested resource is invalid, unknown, or was not granted, see [`crate::resource`]
+    InvalidTarget,
+    /// The user must sign in, but the client asked for no interaction with `prompt=none`
+    LoginRequired,
+    ServerError,
+    /// The client polls for a device authorization faster than its interval
+    SlowDown,
+    TemporarilyUnavailable,
+    UnauthorizedClient,
+    Unknown,
+    UnsupportedGrantType,
+    UnsupportedResponseType,
+}
+
+impl ErrorKind {
+    /// Error code sent to clients, like `invalid_scope`
+    pub fn code(&self) -> String {
+        self.to_string().trim_matches('"').to_string()
+    }
+}
+
+impl Debug for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match serde_json::to_string(self) {
+            Ok(s) => write!(f, "{}", s),
+            Err(_) => Err(fmt::Error),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_maps_kinds_to_status_codes() {
+        let cases = vec![
+            (ErrorKind::AccessDenied, StatusCode::FORBIDDEN),
+            (ErrorKind::AuthorizationPending, StatusCode::BAD_REQUEST),
+            (ErrorKind::ExpiredToken, StatusCode::BAD_REQUEST),
+            (ErrorKind::InvalidClient, StatusCode::UNAUTHORIZED),
+            (ErrorKind::InvalidGrant, StatusCode::BAD_REQUEST),
+            (ErrorKind::InvalidRedirectUri, StatusCode::BAD_REQUEST),
+            (ErrorKind::InvalidRequest, StatusCode::BAD_REQUEST),
+            (ErrorKind::InvalidScope, StatusCode::BAD_REQUEST),
+            (ErrorKind::InvalidTarget, StatusCode::BAD_REQUEST),
+            (ErrorKind::LoginRequired, StatusCode::BAD_REQUEST),
+            (ErrorKind::ServerError, StatusCode::INTERNAL_SERVER_ERROR),
+            (ErrorKind::SlowDown, StatusCode::BAD_REQUEST),
+            (
+                ErrorKind::TemporarilyUnavailable,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (ErrorKind::UnauthorizedClient, StatusCode::BAD_REQUEST),
+            (ErrorKind::Unknown, StatusCode::INTERNAL_SERVER_ERROR),
+            (ErrorKind::UnsupportedGrantType, StatusCode::BAD_REQUEST),
+            (ErrorKind::UnsupportedResponseType, StatusCode::BAD_REQUEST),
+        ];
+        for (kind, status) in cases {
+            let err = Error::new(kind, "test".to_string());
+            assert_eq!(err.status_code(), status, "{}", err);
+            assert_eq!(err.error_response().status(), status, "{}", err);
+        }
+    }
+
+    #[test]
+    fn it_links_errors_to_their_docs() {
+        for base in &[
+            "https://example.com/docs/errors",
+            "https://example.com/docs/errors/",
+        ] {
+            let docs = ErrorDocs::new(Url::parse(base).unwrap());
+            let err = docs.document(Error::new(ErrorKind::InvalidScope, "test".to_string()));
+            assert_eq!(
+                err.error_uri(),
+                Some("https://example.com/docs/errors/invalid_scope")
+            );
+        }
+
+        let docs = ErrorDocs::new(Url::parse("https://example.com/docs/errors").unwrap());
+        let mut err = Error::new(ErrorKind::InvalidScope, "test".to_string());
+        err.set_error_uri(Url::parse("https://example.com/scopes").unwrap());
+        let err = docs.document(err);
+        assert_eq!(err.error_uri(), Some("https://example.com/scopes"));
+    }
+
+    #[test]
+    fn it_asks_for_client_credentials() {
+        let res =
+            Error::new(ErrorKind::InvalidClient, "unknown client".to_string()).error_response();
+        assert!(res.headers().contains_key(header::WWW_AUTHENTICATE));
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "no-store"
+        );
+
+        let res = Error::new(ErrorKind::InvalidGrant, "expired code".to_string()).error_response();
+        assert!(!res.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+}
@@ -0,0 +1,95 @@
+//! Hooks on the lifecycle of tokens, for shipping token events to external systems like a SIEM.
+//!
+//! Listeners registered with [`crate::handler::OAuthHandler::add_token_event_listener`] are
+//! notified of every issued, refreshed and revoked token. Events carry the session and metadata
+//! of the token, never its value. Listeners cannot fail the request they are notified from,
+//! and are awaited before it is answered, so they are expected to deliver events in the background.
+use async_trait::async_trait;
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::session::Session;
+use crate::token::{Token, TokenTypeHint};
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenAction {
+    /// Issued by any grant but the refresh token one
+    Issued,
+    /// Issued in exchange for a refresh token. The tokens it replaces are revoked
+    /// without an event of their own.
+    Refreshed,
+    Revoked,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TokenEvent {
+    pub action: TokenAction,
+    pub client_id: String,
+    pub token_type: TokenTypeHint,
+    #[serde(with = "ts_seconds")]
+    pub expires_at: DateTime<Utc>,
+    pub session: Session,
+}
+
+impl TokenEvent {
+    pub fn new<T: Token>(action: TokenAction, token: &T) -> Self {
+        let session = token.session().clone();
+        TokenEvent {
+            action,
+            client_id: session.client_id().clone(),
+            token_type: token.type_hint(),
+            expires_at: *token.expiration(),
+            session,
+        }
+    }
+}
+
+#[async_trait]
+pub trait TokenEventListener: Send + Sync {
+    async fn on_issued(&self, event: &TokenEvent);
+    async fn on_refreshed(&self, event: &TokenEvent);
+    async fn on_revoked(&self, event: &TokenEvent);
+}
+
+/// Notifies the listener of the event, according to its action
+pub async fn notify(listener: &dyn TokenEventListener, event: &TokenEvent) {
+    match event.action {
+        TokenAction::Issued => listener.on_issued(event).await,
+        TokenAction::Refreshed => listener.on_refreshed(event).await,
+        TokenAction::Revoked => listener.on_revoked(event).await,
+    }
+}
+
+/// Writes token events to the log
+pub struct LogListener;
+
+impl LogListener {
+    fn log(&self, event: &TokenEvent) {
+        log::info!(
+            "Token event: {:?} {:?} of client '{}' for user {:?}, scope '{}', expiring at {}",
+            event.action,
+            event.token_type,
+            &event.client_id,
+            event.session.user_id(),
+            event.session.scope(),
+            event.expires_at
+        );
+    }
+}
+
+#[async_trait]
+impl TokenEventListener for LogListener {
+    async fn on_issued(&self, event: &TokenEvent) {
+        self.log(event);
+    }
+
+    async fn on_refreshed(&self, event: &TokenEvent) {
+        self.log(event);
+    }
+
+    async fn on_revoked(&self, event: &TokenEvent) {
+        self.log(event);
+    }
+}
@@ -0,0 +1,1248 @@
+use async_trait::async_trait;
+use chrono::Duration;
+
+use crate::client::Client;
+use crate::device::Device;
+use crate::device_code::DeviceAuthorization;
+use crate::error::{Error, ErrorKind};
+use crate::handler::{
+    BasicAuth, OAuthHandler, RequestHandler, TokenIntrospectionHandler, INTROSPECTION_SCOPE,
+};
+use crate::request::{
+    AuthorizationRequest, BootstrapRequest, DeviceAuthorizationRequest, IntrospectionRequest,
+    PushRequest, PushedRequestReference, RevocationRequest, TokenRequest,
+};
+use crate::response::{
+    AuthorizationResponse, DeviceAuthorizationResponse, Diagnosis, IntrospectionResponse,
+    PushResponse, RevocationResponse, TokenAccess, TokenResponse,
+};
+use crate::session::Session;
+use crate::storage::{AuthorizationCodeStorage, ClientStorage, TokenStorage};
+use crate::token::{AccessToken, RefreshToken, Token};
+use crate::{Expirable, Result};
+
+/// Object-safe entrypoint to the OAuth flows, so that routes don't depend on the storage types
+/// the handler is composed with.
+#[async_trait]
+pub trait Oauth: Send + Sync {
+    /// Validates an authorization request, returning the requesting client
+    async fn validate(
+        &self,
+        req: &AuthorizationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<Client>;
+    /// Looks up a registered client, for showing errors of requests that failed validation
+    async fn client(&self, client_id: &str) -> Option<Client>;
+    /// Stores an authorization request pushed by a confidential client, if pushed requests are enabled
+    async fn push(
+        &self,
+        req: &PushRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<PushResponse>;
+    /// Loads the authorization request a request URI references, which can only be used once
+    async fn pushed_request(
+        &self,
+        reference: &PushedRequestReference,
+    ) -> Result<AuthorizationRequest>;
+    /// Dry-runs [`Oauth::validate`], reporting every violation and advisory instead of the first error
+    async fn diagnose(&self, req: &AuthorizationRequest) -> Diagnosis;
+    async fn authorize(
+        &self,
+        req: &AuthorizationRequest,
+        session: &mut Session,
+    ) -> Result<AuthorizationResponse>;
+    /// Issues a token set, recording the requesting device on the issued session if given
+    async fn token(
+        &self,
+        req: &TokenRequest,
+        client_auth: Option<&BasicAuth>,
+        device: Option<Device>,
+    ) -> Result<TokenResponse>;
+    /// Exchanges a single-use bootstrap token for a token set, if bootstrap tokens are enabled
+    async fn bootstrap(
+        &self,
+        req: &BootstrapRequest,
+        client_auth: Option<&BasicAuth>,
+        device: Option<Device>,
+    ) -> Result<TokenResponse>;
+    /// Issues a short-lived access token acting as the user of the session, on behalf of the
+    /// impersonator it names
+    async fn impersonate(&self, session: &Session, expires_in: Duration) -> Result<TokenResponse>;
+    /// Issues a device code and a user code, if the device authorization grant is enabled
+    async fn authorize_device(
+        &self,
+        req: &DeviceAuthorizationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<DeviceAuthorizationResponse>;
+    /// Loads the pending device authorization of a user code, for the user to review it
+    async fn device_authorization(&self, user_code: &str) -> Result<DeviceAuthorization>;
+    /// Approves the device authorization of a user code for the session, or denies it without one
+    async fn decide_device_authorization(
+        &self,
+        user_code: &str,
+        session: Option<Session>,
+    ) -> Result<()>;
+    /// Introspects a token of the authenticated client
+    async fn introspect(
+        &self,
+        req: &IntrospectionRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<TokenAccess<IntrospectionResponse>>;
+    /// Introspects a token of any client, on behalf of a client marked as a resource server,
+    /// authenticated with an access token granted the [`INTROSPECTION_SCOPE`]
+    async fn introspect_with_token(
+        &self,
+        req: &IntrospectionRequest,
+        access_token: &str,
+    ) -> Result<TokenAccess<IntrospectionResponse>>;
+    /// Revokes a token of the authenticated client
+    async fn revoke(
+        &self,
+        req: &RevocationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<TokenAccess<RevocationResponse>>;
+    async fn access_token(&self, token: &str) -> Result<AccessToken>;
+    async fn revoke_access_token(&self, token: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> Oauth for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn validate(
+        &self,
+        req: &AuthorizationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<Client> {
+        RequestHandler::validate(self, req, client_auth).await
+    }
+
+    async fn client(&self, client_id: &str) -> Option<Client> {
+        OAuthHandler::client(self, client_id).await
+    }
+
+    async fn push(
+        &self,
+        req: &PushRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<PushResponse> {
+        self.push_authorization_request(req, client_auth).await
+    }
+
+    async fn pushed_request(
+        &self,
+        reference: &PushedRequestReference,
+    ) -> Result<AuthorizationRequest> {
+        self.pushed_authorization_request(reference).await
+    }
+
+    async fn diagnose(&self, req: &AuthorizationRequest) -> Diagnosis {
+        OAuthHandler::diagnose(self, req).await
+    }
+
+    async fn authorize(
+        &self,
+        req: &AuthorizationRequest,
+        session: &mut Session,
+    ) -> Result<AuthorizationResponse> {
+        RequestHandler::handle(self, req, session).await
+    }
+
+    async fn token(
+        &self,
+        req: &TokenRequest,
+        client_auth: Option<&BasicAuth>,
+        device: Option<Device>,
+    ) -> Result<TokenResponse> {
+        let client = RequestHandler::validate(self, req, client_auth).await?;
+        let session = &mut Session::for_client(client.client_id().to_string());
+        if let Some(device) = device {
+            session.set_requester(device);
+        }
+        RequestHandler::handle(self, req, session).await
+    }
+
+    async fn bootstrap(
+        &self,
+        req: &BootstrapRequest,
+        client_auth: Option<&BasicAuth>,
+        device: Option<Device>,
+    ) -> Result<TokenResponse> {
+        self.redeem_bootstrap_token(req, client_auth, device).await
+    }
+
+    async fn impersonate(&self, session: &Session, expires_in: Duration) -> Result<TokenResponse> {
+        self.issue_impersonation_token(session, expires_in).await
+    }
+
+    async fn authorize_device(
+        &self,
+        req: &DeviceAuthorizationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<DeviceAuthorizationResponse> {
+        OAuthHandler::authorize_device(self, req, client_auth).await
+    }
+
+    async fn device_authorization(&self, user_code: &str) -> Result<DeviceAuthorization> {
+        self.pending_device_authorization(user_code).await
+    }
+
+    async fn decide_device_authorization(
+        &self,
+        user_code: &str,
+        session: Option<Session>,
+    ) -> Result<()> {
+        OAuthHandler::decide_device_authorization(self, user_code, session).await
+    }
+
+    async fn introspect(
+        &self,
+        req: &IntrospectionRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<TokenAccess<IntrospectionResponse>> {
+        let client = RequestHandler::validate(self, req, client_auth).await?;
+        let session = &mut Session::for_client(client.client_id().to_string());
+        RequestHandler::handle(self, req, session).await
+    }
+
+    async fn introspect_with_token(
+        &self,
+        req: &IntrospectionRequest,
+        access_token: &str,
+    ) -> Result<TokenAccess<IntrospectionResponse>> {
+        let denied = || {
+            Error::new(
+                ErrorKind::AccessDenied,
+                format!(
+                    "introspection requires a resource server token granted the '{}' scope",
+                    INTROSPECTION_SCOPE
+                ),
+            )
+        };
+        let access_token = TokenIntrospectionHandler::<AccessToken>::get_token(self, access_token)
+            .await
+            .map_err(|_| denied())?;
+        if access_token.is_expired() || !self.is_resource_server(access_token.session()).await {
+            return Err(denied());
+        }
+
+        let session = &mut access_token.session().clone();
+        RequestHandler::handle(self, req, session).await
+    }
+
+    async fn revoke(
+        &self,
+        req: &RevocationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<TokenAccess<RevocationResponse>> {
+        let client = RequestHandler::validate(self, req, client_auth).await?;
+        let session = &mut Session::for_client(client.client_id().to_string());
+        RequestHandler::handle(self, req, session).await
+    }
+
+    async fn access_token(&self, token: &str) -> Result<AccessToken> {
+        TokenIntrospectionHandler::<AccessToken>::get_token(self, token).await
+    }
+
+    async fn revoke_access_token(&self, token: &str) -> Result<()> {
+        TokenIntrospectionHandler::<AccessToken>::revoke_token(self, token).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::iter::FromIterator;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use chrono::{Duration, Utc};
+    use enseada::trust::{KeySet, TrustBundle};
+    use futures::executor::block_on;
+    use url::Url;
+
+    use crate::client::Client;
+    use crate::device::{AuthMethod, Device};
+    use crate::error::{Error, ErrorKind};
+    use crate::handler::{BasicAuth, OAuthHandler};
+    use crate::issuance::{IssuanceLimits, IssuanceMonitor};
+    use crate::memory::MemoryStorage;
+    use crate::par::REQUEST_URI_PREFIX;
+    use crate::registry::ScopeRegistry;
+    use crate::request::{
+        AuthorizationCodeRequest, AuthorizationRequest, BootstrapRequest,
+        DeviceAuthorizationRequest, DeviceCodeRequest, GrantType, IntrospectionRequest,
+        PushRequest, PushedRequestReference, RefreshTokenRequest, ResponseType,
+        RevocationRequest, TokenRequest,
+    };
+    use crate::response::TokenResponse;
+    use crate::scope::Scope;
+    use crate::session::Session;
+    use crate::signing::{AccessTokenClaims, TokenSigner};
+    use crate::storage::ClientStorage;
+    use crate::token::{Token, TokenTypeHint};
+    use crate::user_agent::UserAgent;
+
+    use super::Oauth;
+
+    const REDIRECT_URI: &str = "http://localhost:9623/callback";
+
+    fn oauth() -> Arc<dyn Oauth> {
+        let storage = Arc::new(MemoryStorage::new());
+        for (client_id, scope) in &[
+            ("test", "profile"),
+            ("other", "profile"),
+            ("resource-server", "tokens:introspect"),
+            ("self-declared", "tokens:introspect"),
+        ] {
+            let mut client = Client::public(
+                client_id.to_string(),
+                Scope::from(*scope),
+                HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+            );
+            client.set_resource_server(*client_id == "resource-server");
+            block_on(storage.save_client(client)).unwrap();
+        }
+        Arc::new(OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        ))
+    }
+
+    fn issue_token(oauth: &dyn Oauth) -> String {
+        issue_token_set(oauth).access_token
+    }
+
+    fn issue_token_set(oauth: &dyn Oauth) -> TokenResponse {
+        let req = code_token_request(authorize(oauth, None), None);
+        let res = block_on(oauth.token(&req, None, None)).unwrap();
+        assert_eq!(res.scope, Scope::from("profile"));
+        res
+    }
+
+    fn authorize(oauth: &dyn Oauth, nonce: Option<&str>) -> String {
+        let auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile"),
+            state: None,
+            nonce: nonce.map(str::to_string),
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let client = block_on(oauth.validate(&auth, None)).unwrap();
+        let session = &mut Session::for_client(client.client_id().to_string());
+        let res = block_on(oauth.authorize(&auth, session)).unwrap();
+        serde_json::to_value(&res).unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Issues an access token to the client through the authorization code flow
+    fn issue_token_to(oauth: &dyn Oauth, client_id: &str, scope: &str) -> String {
+        let auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: client_id.to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from(scope),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let session = &mut Session::for_client(client_id.to_string());
+        let res = block_on(oauth.authorize(&auth, session)).unwrap();
+        let code = serde_json::to_value(&res).unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let req = TokenRequest::AuthorizationCode(AuthorizationCodeRequest {
+            code,
+            redirect_uri: REDIRECT_URI.to_string(),
+            code_verifier: None,
+            client_id: Some(client_id.to_string()),
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            nonce: None,
+            resource: Vec::new(),
+        });
+        block_on(oauth.token(&req, None, None))
+            .unwrap()
+            .access_token
+    }
+
+    fn code_token_request(code: String, nonce: Option<&str>) -> TokenRequest {
+        TokenRequest::AuthorizationCode(AuthorizationCodeRequest {
+            code,
+            redirect_uri: REDIRECT_URI.to_string(),
+            code_verifier: None,
+            client_id: Some("test".to_string()),
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            nonce: nonce.map(str::to_string),
+            resource: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn it_issues_a_token_through_the_facade() {
+        let oauth = oauth();
+        let token = issue_token(oauth.as_ref());
+
+        let access_token = block_on(oauth.access_token(&token)).unwrap();
+        assert_eq!(access_token.scope(), &Scope::from("profile"));
+
+        let auth = BasicAuth::new("test".to_string(), None);
+        let req = IntrospectionRequest {
+            token,
+            token_type_hint: None,
+        };
+        let res = block_on(oauth.introspect(&req, Some(&auth))).unwrap();
+        assert!(!res.is_denied());
+        assert!(res.into_response().active);
+    }
+
+    #[test]
+    fn it_revokes_a_token_through_the_facade() {
+        let oauth = oauth();
+        let token = issue_token(oauth.as_ref());
+
+        let auth = BasicAuth::new("test".to_string(), None);
+        let req = RevocationRequest {
+            token: token.clone(),
+            token_type_hint: None,
+        };
+        let res = block_on(oauth.revoke(&req, Some(&auth))).unwrap();
+        assert!(!res.is_denied());
+        assert!(block_on(oauth.access_token(&token)).is_err());
+    }
+
+    #[test]
+    fn it_answers_probes_for_tokens_of_other_clients_like_unknown_tokens() {
+        let oauth = oauth();
+        let token = issue_token(oauth.as_ref());
+        let other = BasicAuth::new("other".to_string(), None);
+
+        let hint = |hinted: bool| {
+            if hinted {
+                Some(TokenTypeHint::AccessToken)
+            } else {
+                None
+            }
+        };
+        for hinted in &[false, true] {
+            let req = IntrospectionRequest {
+                token: token.clone(),
+                token_type_hint: hint(*hinted),
+            };
+            let res = block_on(oauth.introspect(&req, Some(&other))).unwrap();
+            assert!(res.is_denied());
+            let res = res.into_response();
+            assert!(!res.active);
+            assert!(res.introspection_data.is_none());
+
+            let req = RevocationRequest {
+                token: token.clone(),
+                token_type_hint: hint(*hinted),
+            };
+            assert!(block_on(oauth.revoke(&req, Some(&other)))
+                .unwrap()
+                .is_denied());
+        }
+        assert!(block_on(oauth.access_token(&token)).is_ok());
+
+        // Unknown tokens are not reported as probes
+        let req = IntrospectionRequest {
+            token: "unknown".to_string(),
+            token_type_hint: None,
+        };
+        let res = block_on(oauth.introspect(&req, Some(&other))).unwrap();
+        assert!(!res.is_denied());
+        assert!(!res.into_response().active);
+    }
+
+    #[test]
+    fn it_lets_resource_servers_introspect_tokens_of_any_client() {
+        let oauth = oauth();
+        let token = issue_token(oauth.as_ref());
+        let req = IntrospectionRequest {
+            token,
+            token_type_hint: None,
+        };
+
+        let rs_token = issue_token_to(oauth.as_ref(), "resource-server", "tokens:introspect");
+        let res = block_on(oauth.introspect_with_token(&req, &rs_token)).unwrap();
+        assert!(!res.is_denied());
+        let res = res.into_response();
+        assert!(res.active);
+        assert_eq!(res.introspection_data.unwrap().client_id, "test");
+
+        // Tokens without the introspection scope can't be used, even for the tokens of their client
+        let other_token = issue_token_to(oauth.as_ref(), "other", "profile");
+        let err = block_on(oauth.introspect_with_token(&req, &other_token)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AccessDenied);
+        let err = block_on(oauth.introspect_with_token(&req, "unknown")).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AccessDenied);
+
+        // The scope alone is not enough, the client must be marked as a resource server
+        let scoped_token = issue_token_to(oauth.as_ref(), "self-declared", "tokens:introspect");
+        let err = block_on(oauth.introspect_with_token(&req, &scoped_token)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AccessDenied);
+    }
+
+    struct KeySetSigner(KeySet);
+
+    #[async_trait]
+    impl TokenSigner for KeySetSigner {
+        fn issuer(&self) -> String {
+            "https://enseada.example.com".to_string()
+        }
+
+        async fn sign(&self, claims: &AccessTokenClaims) -> crate::Result<String> {
+            self.0
+                .sign_jwt(claims)
+                .map_err(|err| Error::new(ErrorKind::ServerError, err))
+        }
+    }
+
+    #[test]
+    fn it_issues_signed_access_tokens_that_can_be_introspected() {
+        let storage = Arc::new(MemoryStorage::new());
+        let client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        block_on(storage.save_client(client)).unwrap();
+        let mut handler = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        let keys = KeySet::generate().unwrap();
+        let now = Utc::now().timestamp() as u64;
+        let bundle = TrustBundle::new(
+            "https://enseada.example.com".to_string(),
+            &keys,
+            Default::default(),
+            now,
+            60,
+        );
+        handler.set_token_signer(Arc::new(KeySetSigner(keys)));
+        let oauth: Arc<dyn Oauth> = Arc::new(handler);
+        let issued = issue_token_set(oauth.as_ref());
+
+        let claims: AccessTokenClaims = bundle.verify_token(&issued.access_token, now).unwrap();
+        assert_eq!(claims.sub, "test");
+        assert_eq!(claims.client_id, "test");
+        assert_eq!(claims.scope, "profile");
+        let access_token = block_on(oauth.access_token(&issued.access_token)).unwrap();
+        assert_eq!(access_token.scope(), &Scope::from("profile"));
+        // The random value alone is not a valid token
+        assert!(block_on(oauth.access_token(&claims.jti)).is_err());
+    }
+
+    #[test]
+    fn it_rejects_an_unknown_client() {
+        let oauth = oauth();
+        let auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "unknown".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        assert!(block_on(oauth.validate(&auth, None)).is_err());
+    }
+
+    #[test]
+    fn it_requires_state_when_the_client_asks_for_it() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        client.set_require_state(true);
+        block_on(storage.save_client(client)).unwrap();
+        let oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+
+        let mut auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let err = block_on(Oauth::validate(&oauth, &auth, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+
+        auth.state = Some("xyz".to_string());
+        assert!(block_on(Oauth::validate(&oauth, &auth, None)).is_ok());
+    }
+
+    #[test]
+    fn it_keeps_the_refresh_token_when_issuance_is_refused() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        client.set_token_issuance_threshold(Some(1));
+        block_on(storage.save_client(client)).unwrap();
+        let mut oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        let monitor = Arc::new(IssuanceMonitor::new(IssuanceLimits {
+            refuse: true,
+            ..IssuanceLimits::default()
+        }));
+        oauth.set_issuance_monitor(monitor.clone());
+
+        let res = issue_token_set(&oauth);
+        let req = TokenRequest::RefreshToken(RefreshTokenRequest {
+            refresh_token: res.refresh_token.unwrap(),
+            scope: None,
+            client_id: Some("test".to_string()),
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            resource: Vec::new(),
+        });
+        for _ in 0..2 {
+            let err = block_on(Oauth::token(&oauth, &req, None, None)).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::TemporarilyUnavailable);
+        }
+        assert_eq!(monitor.anomalies(), 1);
+        assert!(block_on(oauth.access_token(&res.access_token)).is_ok());
+    }
+
+    #[test]
+    fn it_binds_tokens_to_their_origin_only_for_clients_that_ask_for_it() {
+        let device = |ip: &str| {
+            let mut device = Device::new(UserAgent::parse("curl/7.68.0"), AuthMethod::None);
+            device.set_ip(Some(ip.parse().unwrap()));
+            device
+        };
+        let refresh = |oauth: &dyn Oauth, refresh_token: String, ip: &str| {
+            let req = TokenRequest::RefreshToken(RefreshTokenRequest {
+                refresh_token,
+                scope: None,
+                client_id: Some("test".to_string()),
+                client_secret: None,
+                client_assertion_type: None,
+                client_assertion: None,
+                resource: Vec::new(),
+            });
+            block_on(oauth.token(&req, None, Some(device(ip)))).unwrap()
+        };
+        let origin = |oauth: &dyn Oauth, token: &str| {
+            let access_token = block_on(oauth.access_token(token)).unwrap();
+            access_token.session().origin().cloned()
+        };
+
+        let oauth = oauth();
+        let res = issue_token_set(oauth.as_ref());
+        let res = refresh(oauth.as_ref(), res.refresh_token.unwrap(), "10.0.0.1");
+        assert_eq!(origin(oauth.as_ref(), &res.access_token), None);
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        client.set_bind_tokens(true);
+        block_on(storage.save_client(client)).unwrap();
+        let oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        let res = issue_token_set(&oauth);
+        let res = refresh(&oauth, res.refresh_token.unwrap(), "10.0.0.1");
+        let bound = origin(&oauth, &res.access_token).unwrap();
+        assert_eq!(bound.ip(), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(bound.user_agent(), "curl/7.68.0");
+
+        // Refreshing from elsewhere moves the new tokens to the new origin
+        let res = refresh(&oauth, res.refresh_token.unwrap(), "::ffff:10.0.0.2");
+        let bound = origin(&oauth, &res.access_token).unwrap();
+        assert_eq!(bound.ip(), Some("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn it_binds_the_nonce_to_the_authorization_code() {
+        let oauth = oauth();
+
+        let code = authorize(oauth.as_ref(), Some("n-0S6_WzA2Mj"));
+        let req = code_token_request(code.clone(), Some("other"));
+        let err = block_on(oauth.token(&req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+
+        let req = code_token_request(code, Some("n-0S6_WzA2Mj"));
+        let res = block_on(oauth.token(&req, None, None)).unwrap();
+        let token = block_on(oauth.access_token(&res.access_token)).unwrap();
+        assert_eq!(token.session().nonce(), Some("n-0S6_WzA2Mj"));
+    }
+
+    #[test]
+    fn it_rejects_a_nonce_that_was_never_sent() {
+        let oauth = oauth();
+        let req = code_token_request(authorize(oauth.as_ref(), None), Some("n-0S6_WzA2Mj"));
+        let err = block_on(oauth.token(&req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_diagnoses_requests_like_the_authorization_endpoint_validates_them() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        client.set_require_state(true);
+        block_on(storage.save_client(client)).unwrap();
+        let oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+
+        let request = |client_id: &str, redirect_uri: &str, scope: &str, state: Option<&str>| {
+            AuthorizationRequest {
+                response_type: ResponseType::Code,
+                client_id: client_id.to_string(),
+                redirect_uri: redirect_uri.to_string(),
+                scope: Scope::from(scope),
+                state: state.map(str::to_string),
+                nonce: None,
+                response_mode: None,
+                prompt: None,
+                max_age: None,
+                resource: Vec::new(),
+            }
+        };
+        let other_uri = "http://localhost:9623/callback/";
+        let matrix = vec![
+            (request("test", REDIRECT_URI, "profile", Some("xyz")), 0),
+            (request("unknown", REDIRECT_URI, "profile", Some("xyz")), 1),
+            (request("test", other_uri, "profile", Some("xyz")), 1),
+            (request("test", REDIRECT_URI, "admin", Some("xyz")), 1),
+            (request("test", REDIRECT_URI, "profile", None), 1),
+            (request("test", other_uri, "profile admin", None), 3),
+        ];
+
+        for (req, count) in matrix {
+            let diagnosis = block_on(Oauth::diagnose(&oauth, &req));
+            assert_eq!(diagnosis.violations().len(), count, "{:?}", req);
+            match block_on(Oauth::validate(&oauth, &req, None)) {
+                Ok(_) => assert!(diagnosis.is_valid(), "{:?}", req),
+                Err(err) => assert_eq!(diagnosis.violations()[0].kind(), err.kind(), "{:?}", req),
+            }
+        }
+    }
+
+    #[test]
+    fn it_advises_on_accepted_requests() {
+        let oauth = oauth();
+        let req = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::default(),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let diagnosis = block_on(oauth.diagnose(&req));
+        assert!(diagnosis.is_valid());
+        assert_eq!(diagnosis.advisories().len(), 2);
+    }
+
+    #[test]
+    fn it_rejects_an_unregistered_redirect_uri() {
+        let oauth = oauth();
+        let auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: format!("{}/", REDIRECT_URI),
+            scope: Scope::from("admin"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let err = block_on(oauth.validate(&auth, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRedirectUri);
+    }
+
+    #[test]
+    fn it_rejects_unregistered_scopes() {
+        let storage = Arc::new(MemoryStorage::new());
+        let client = Client::public(
+            "test".to_string(),
+            Scope::from("*"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        block_on(storage.save_client(client)).unwrap();
+        let mut oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        let mut auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile pkgs:write admin"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        assert!(block_on(Oauth::validate(&oauth, &auth, None)).is_ok());
+
+        let mut registry = ScopeRegistry::new();
+        registry.register("profile", "Read your profile");
+        oauth.set_scope_registry(Arc::new(registry));
+        let err = block_on(Oauth::validate(&oauth, &auth, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+        assert_eq!(err.description(), "unknown scope: admin pkgs:write");
+        let diagnosis = block_on(oauth.diagnose(&auth));
+        assert!(!diagnosis.is_valid());
+
+        auth.scope = Scope::from("profile");
+        assert!(block_on(Oauth::validate(&oauth, &auth, None)).is_ok());
+    }
+
+    #[test]
+    fn it_authorizes_silent_requests_with_a_session() {
+        let oauth = oauth();
+        let mut auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: Some("none".to_string()),
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let client = block_on(oauth.validate(&auth, None)).unwrap();
+        let session = &mut Session::for_client(client.client_id().to_string());
+        session.set_user_id("jdoe".to_string());
+        let res = block_on(oauth.authorize(&auth, session)).unwrap();
+        assert!(serde_json::to_value(&res).unwrap()["code"].is_string());
+
+        auth.prompt = Some("none login".to_string());
+        let err = block_on(oauth.validate(&auth, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+    }
+
+    type MemoryHandler = OAuthHandler<MemoryStorage, MemoryStorage, MemoryStorage, MemoryStorage>;
+
+    fn bootstrap_oauth() -> MemoryHandler {
+        let storage = Arc::new(MemoryStorage::new());
+        let client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        block_on(storage.save_client(client)).unwrap();
+        let mut oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        oauth.set_bootstrap_storage(storage);
+        oauth
+    }
+
+    fn bootstrap_session() -> Session {
+        let mut session = Session::for_client("test".to_string());
+        session
+            .set_user_id("ci".to_string())
+            .set_scope(Scope::from("profile"));
+        session
+    }
+
+    fn issue_bootstrap_token(oauth: &MemoryHandler, expires_in: Duration) -> String {
+        let token = block_on(oauth.issue_bootstrap_token(bootstrap_session(), expires_in));
+        token.unwrap().to_string()
+    }
+
+    fn bootstrap_request(token: String) -> BootstrapRequest {
+        BootstrapRequest {
+            bootstrap_token: token,
+            client_id: None,
+            client_secret: None,
+        }
+    }
+
+    #[test]
+    fn it_exchanges_a_bootstrap_token_once() {
+        let oauth = bootstrap_oauth();
+        let token = issue_bootstrap_token(&oauth, Duration::minutes(5));
+
+        let req = bootstrap_request(token);
+        let res = block_on(Oauth::bootstrap(&oauth, &req, None, None)).unwrap();
+        assert_eq!(res.scope, Scope::from("profile"));
+        assert!(res.refresh_token.is_some());
+        let access_token = block_on(oauth.access_token(&res.access_token)).unwrap();
+        assert_eq!(access_token.session().user_id(), &Some("ci".to_string()));
+
+        let err = block_on(Oauth::bootstrap(&oauth, &req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_rejects_bootstrap_tokens_for_other_clients() {
+        let oauth = bootstrap_oauth();
+        let token = issue_bootstrap_token(&oauth, Duration::minutes(5));
+
+        let mut req = bootstrap_request(token);
+        req.client_id = Some("other".to_string());
+        let err = block_on(Oauth::bootstrap(&oauth, &req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+
+        req.client_id = Some("test".to_string());
+        assert!(block_on(Oauth::bootstrap(&oauth, &req, None, None)).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_expired_bootstrap_tokens() {
+        let oauth = bootstrap_oauth();
+        let token = issue_bootstrap_token(&oauth, Duration::seconds(-1));
+
+        let req = bootstrap_request(token);
+        let err = block_on(Oauth::bootstrap(&oauth, &req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_refuses_bootstrap_tokens_unless_enabled() {
+        let storage = Arc::new(MemoryStorage::new());
+        let oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        let err = block_on(oauth.issue_bootstrap_token(bootstrap_session(), Duration::minutes(5)))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnsupportedGrantType);
+
+        let req = bootstrap_request("anything".to_string());
+        let err = block_on(Oauth::bootstrap(&oauth, &req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnsupportedGrantType);
+    }
+
+    fn par_oauth() -> MemoryHandler {
+        let storage = Arc::new(MemoryStorage::new());
+        let redirect_uris = HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]);
+        for client_id in &["test", "other"] {
+            let client = Client::confidential(
+                client_id.to_string(),
+                "secret".to_string(),
+                Scope::from("profile"),
+                redirect_uris.clone(),
+            )
+            .unwrap();
+            block_on(storage.save_client(client)).unwrap();
+        }
+        let client = Client::public("public".to_string(), Scope::from("profile"), redirect_uris);
+        block_on(storage.save_client(client)).unwrap();
+        let mut oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        oauth.set_pushed_request_storage(storage);
+        oauth
+    }
+
+    fn push_request(client_id: &str) -> PushRequest {
+        PushRequest {
+            request: AuthorizationRequest {
+                response_type: ResponseType::Code,
+                client_id: client_id.to_string(),
+                redirect_uri: REDIRECT_URI.to_string(),
+                scope: Scope::from("profile"),
+                state: Some("xyz".to_string()),
+                nonce: None,
+                response_mode: None,
+                prompt: None,
+                max_age: None,
+                resource: Vec::new(),
+            },
+            client_secret: Some("secret".to_string()),
+        }
+    }
+
+    fn reference(client_id: &str, request_uri: &str) -> PushedRequestReference {
+        PushedRequestReference {
+            client_id: client_id.to_string(),
+            request_uri: request_uri.to_string(),
+        }
+    }
+
+    #[test]
+    fn it_loads_a_pushed_request_once() {
+        let oauth = par_oauth();
+        let res = block_on(Oauth::push(&oauth, &push_request("test"), None)).unwrap();
+        assert!(res.request_uri.starts_with(REQUEST_URI_PREFIX));
+        assert!(res.expires_in > 0 && res.expires_in <= 90);
+
+        let reference = reference("test", &res.request_uri);
+        let auth = block_on(Oauth::pushed_request(&oauth, &reference)).unwrap();
+        assert_eq!(auth.client_id, "test");
+        assert_eq!(auth.state.as_deref(), Some("xyz"));
+
+        let err = block_on(Oauth::pushed_request(&oauth, &reference)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn it_ties_pushed_requests_to_the_pushing_client() {
+        let oauth = par_oauth();
+        let res = block_on(Oauth::push(&oauth, &push_request("test"), None)).unwrap();
+        let err = block_on(Oauth::pushed_request(
+            &oauth,
+            &reference("other", &res.request_uri),
+        ))
+        .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+
+        // Pushing on behalf of another client fails
+        let auth = BasicAuth::new("other".to_string(), Some("secret".to_string()));
+        let mut req = push_request("test");
+        req.client_secret = None;
+        let err = block_on(Oauth::push(&oauth, &req, Some(&auth))).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+    }
+
+    #[test]
+    fn it_only_accepts_pushed_requests_of_authenticated_confidential_clients() {
+        let oauth = par_oauth();
+        let mut req = push_request("test");
+        req.client_secret = Some("wrong".to_string());
+        let err = block_on(Oauth::push(&oauth, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+
+        let err = block_on(Oauth::push(&oauth, &push_request("public"), None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnauthorizedClient);
+
+        let mut req = push_request("test");
+        req.request.scope = Scope::from("profile admin");
+        let err = block_on(Oauth::push(&oauth, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+    }
+
+    #[test]
+    fn it_rejects_unknown_request_uris() {
+        let oauth = par_oauth();
+        for request_uri in &[
+            "https://example.com/request",
+            "urn:ietf:params:oauth:request_uri:nope",
+        ] {
+            let err = block_on(Oauth::pushed_request(
+                &oauth,
+                &reference("test", request_uri),
+            ))
+            .unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+        }
+    }
+
+    fn device_oauth() -> MemoryHandler {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut client = Client::native("cli".to_string(), Scope::from("profile"), HashSet::new());
+        client
+            .set_allowed_grant_types(HashSet::from_iter(vec![
+                GrantType::DeviceCode,
+                GrantType::RefreshToken,
+            ]))
+            .unwrap();
+        block_on(storage.save_client(client)).unwrap();
+        let client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]),
+        );
+        block_on(storage.save_client(client)).unwrap();
+        let mut oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        oauth.set_device_authorization_storage(
+            storage,
+            Url::parse("https://enseada.example.com/oauth/device").unwrap(),
+        );
+        oauth
+    }
+
+    fn device_request(client_id: &str) -> DeviceAuthorizationRequest {
+        DeviceAuthorizationRequest {
+            client_id: Some(client_id.to_string()),
+            scope: Scope::default(),
+            client_secret: None,
+        }
+    }
+
+    fn device_token_request(device_code: &str) -> TokenRequest {
+        TokenRequest::DeviceCode(DeviceCodeRequest {
+            device_code: device_code.to_string(),
+            client_id: Some("cli".to_string()),
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            resource: Vec::new(),
+        })
+    }
+
+    fn device_session(user_id: &str) -> Session {
+        let mut session = Session::for_client("cli".to_string());
+        session.set_user_id(user_id.to_string());
+        session
+    }
+
+    #[test]
+    fn it_issues_tokens_to_approved_devices() {
+        let oauth = device_oauth();
+        let res = block_on(Oauth::authorize_device(&oauth, &device_request("cli"), None)).unwrap();
+        assert_eq!(res.interval, 5);
+        assert_eq!(
+            res.verification_uri_complete.query(),
+            Some(format!("user_code={}", res.user_code).as_str())
+        );
+
+        let req = device_token_request(&res.device_code);
+        let err = block_on(oauth.token(&req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AuthorizationPending);
+        let err = block_on(oauth.token(&req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::SlowDown);
+
+        // Typed by the user, without the dash
+        let user_code = res.user_code.replace('-', "").to_lowercase();
+        let authorization = block_on(oauth.device_authorization(&user_code)).unwrap();
+        assert_eq!(authorization.scope(), &Scope::from("profile"));
+        block_on(oauth.decide_device_authorization(&user_code, Some(device_session("jdoe"))))
+            .unwrap();
+        let err = block_on(oauth.device_authorization(&user_code)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_redeems_a_device_code_once() {
+        let oauth = device_oauth();
+        let res = block_on(Oauth::authorize_device(&oauth, &device_request("cli"), None)).unwrap();
+        block_on(oauth.decide_device_authorization(&res.user_code, Some(device_session("jdoe"))))
+            .unwrap();
+
+        let req = device_token_request(&res.device_code);
+        let token = block_on(oauth.token(&req, None, None)).unwrap();
+        assert_eq!(token.scope, Scope::from("profile"));
+        let access_token = block_on(oauth.access_token(&token.access_token)).unwrap();
+        assert_eq!(access_token.session().user_id(), &Some("jdoe".to_string()));
+
+        let err = block_on(oauth.token(&req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_reports_denied_device_authorizations() {
+        let oauth = device_oauth();
+        let res = block_on(Oauth::authorize_device(&oauth, &device_request("cli"), None)).unwrap();
+        let session = Session::for_client("test".to_string());
+        let err = block_on(oauth.decide_device_authorization(&res.user_code, Some(session)))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+        block_on(oauth.decide_device_authorization(&res.user_code, None)).unwrap();
+
+        let req = device_token_request(&res.device_code);
+        let err = block_on(oauth.token(&req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AccessDenied);
+        let err = block_on(oauth.token(&req, None, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_only_issues_device_codes_to_allowed_clients() {
+        let oauth = device_oauth();
+        let err =
+            block_on(Oauth::authorize_device(&oauth, &device_request("test"), None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnauthorizedClient);
+
+        let mut req = device_request("cli");
+        req.scope = Scope::from("profile admin");
+        let err = block_on(Oauth::authorize_device(&oauth, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+
+        let err = block_on(Oauth::authorize_device(&par_oauth(), &device_request("test"), None))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnsupportedGrantType);
+    }
+}
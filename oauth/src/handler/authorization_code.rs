@@ -0,0 +1,208 @@
+//! Authorization code grant, exchanging the code issued by the authorization endpoint for tokens
+use async_trait::async_trait;
+
+use enseada::secure;
+
+use crate::client::Client;
+use crate::device::Device;
+use crate::error::{Error, ErrorKind};
+use crate::request::AuthorizationCodeRequest;
+use crate::response::TokenResponse;
+use crate::storage::{AuthorizationCodeStorage, ClientStorage, TokenStorage};
+use crate::token::{AccessToken, RefreshToken};
+use crate::{Expirable, Result};
+
+use super::OAuthHandler;
+
+#[async_trait]
+pub trait AuthorizationCodeGrant {
+    /// Exchanges an authorization code issued to the client for a token set, invalidating the code
+    async fn exchange_code(
+        &self,
+        client: &Client,
+        req: &AuthorizationCodeRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse>;
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> AuthorizationCodeGrant for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn exchange_code(
+        &self,
+        client: &Client,
+        req: &AuthorizationCodeRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse> {
+        log::debug!("Handling AuthorizationCode token request");
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidGrant,
+                "invalid authorization code".to_string(),
+            )
+        };
+        let code_sig = secure::generate_signature(req.code.as_str(), &self.secret_key).to_string();
+        log::debug!("Received auth code with sig {}", &code_sig);
+        let code = self
+            .authorization_code_storage
+            .get_code(&code_sig)
+            .await
+            .ok_or_else(invalid)?;
+
+        if code.is_expired() {
+            log::warn!("Authorization code is expired");
+            return Err(invalid());
+        }
+
+        let mut session = code.session().clone();
+        if session.client_id() != client.client_id() {
+            return Err(Error::new(
+                ErrorKind::InvalidClient,
+                format!("invalid client '{}'", client.client_id()),
+            ));
+        }
+
+        if let Some(nonce) = &req.nonce {
+            if session.nonce() != Some(nonce.as_str()) {
+                log::warn!("Nonce does not match the authorization request");
+                return Err(Error::new(
+                    ErrorKind::InvalidGrant,
+                    "invalid nonce".to_string(),
+                ));
+            }
+        }
+
+        if !client.is_redirect_uri_allowed(&req.redirect_uri) {
+            return Err(Error::new(
+                ErrorKind::InvalidRedirectUri,
+                String::from("invalid redirect URI"),
+            ));
+        }
+        self.validate_scope(client, session.scope())?;
+        let resources = self.narrow_resources(&session, &req.resource)?;
+        session.set_resources(resources);
+
+        if let Some(device) = device {
+            session.set_requester(device);
+        }
+        let res = self.generate_token_set(&session).await?;
+
+        self.authorization_code_storage.revoke_code(&code_sig).await?;
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use futures::executor::block_on;
+
+    use crate::code::AuthorizationCode;
+    use crate::memory::MemoryStorage;
+    use crate::scope::Scope;
+    use crate::secrets::Secrets;
+    use crate::session::Session;
+    use crate::token::Token;
+
+    use super::super::test::{client, handler, REDIRECT_URI, SECRET_KEY};
+    use super::super::TokenIntrospectionHandler;
+    use super::*;
+
+    /// Stores an authorization code issued to the test client, returning its value
+    fn issue_code(storage: &MemoryStorage, nonce: Option<&str>) -> String {
+        let mut session = Session::for_client("test".to_string());
+        session
+            .set_scope(Scope::from("profile"))
+            .set_nonce(nonce.map(str::to_string));
+        store_code(storage, session)
+    }
+
+    fn store_code(storage: &MemoryStorage, session: Session) -> String {
+        let secret = Secrets::default().generate().unwrap();
+        let code = AuthorizationCode::new(secret, session, Duration::minutes(5));
+        let value = code.to_string();
+        let sig = secure::generate_signature(&value, SECRET_KEY).to_string();
+        block_on(storage.store_code(&sig, code)).unwrap();
+        value
+    }
+
+    fn request(code: String, nonce: Option<&str>) -> AuthorizationCodeRequest {
+        AuthorizationCodeRequest {
+            code,
+            redirect_uri: REDIRECT_URI.to_string(),
+            code_verifier: None,
+            client_id: Some("test".to_string()),
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            nonce: nonce.map(str::to_string),
+            resource: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn it_exchanges_a_code_only_once() {
+        let (storage, handler) = handler();
+        let client = client(&storage, "test");
+        let req = request(issue_code(&storage, None), None);
+        let res = block_on(handler.exchange_code(&client, &req, None)).unwrap();
+        assert_eq!(res.scope, Scope::from("profile"));
+        assert!(res.refresh_token.is_some());
+
+        let err = block_on(handler.exchange_code(&client, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_only_exchanges_codes_issued_to_the_client() {
+        let (storage, handler) = handler();
+        let req = request(issue_code(&storage, None), None);
+        let err = block_on(handler.exchange_code(&client(&storage, "service"), &req, None))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+
+        // The code is still valid for the client it was issued to
+        assert!(block_on(handler.exchange_code(&client(&storage, "test"), &req, None)).is_ok());
+    }
+
+    #[test]
+    fn it_checks_the_nonce_of_the_authorization_request() {
+        let (storage, handler) = handler();
+        let client = client(&storage, "test");
+        let code = issue_code(&storage, Some("n-0S6_WzA2Mj"));
+        let req = request(code.clone(), Some("other"));
+        let err = block_on(handler.exchange_code(&client, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+
+        let req = request(code, Some("n-0S6_WzA2Mj"));
+        assert!(block_on(handler.exchange_code(&client, &req, None)).is_ok());
+    }
+
+    #[test]
+    fn it_narrows_the_authorized_resources() {
+        let (storage, handler) = handler();
+        let client = client(&storage, "test");
+        let mut session = Session::for_client("test".to_string());
+        session.set_scope(Scope::from("profile")).set_resources(vec![
+            "https://api.example.com/".to_string(),
+            "https://files.example.com/".to_string(),
+        ]);
+        let code = store_code(&storage, session);
+
+        let mut req = request(code, None);
+        req.resource = vec!["https://other.example.com/".to_string()];
+        let err = block_on(handler.exchange_code(&client, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidTarget);
+
+        req.resource = vec!["https://files.example.com/".to_string()];
+        let res = block_on(handler.exchange_code(&client, &req, None)).unwrap();
+        let token: AccessToken = block_on(handler.get_token(&res.access_token)).unwrap();
+        assert_eq!(token.session().resources(), ["https://files.example.com/"]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Client credentials grant, issuing tokens to confidential clients acting on their own behalf
+use async_trait::async_trait;
+
+use crate::client::{Client, ClientKind};
+use crate::device::Device;
+use crate::error::{Error, ErrorKind};
+use crate::events::TokenAction;
+use crate::request::ClientCredentialsRequest;
+use crate::response::TokenResponse;
+use crate::session::Session;
+use crate::storage::{AuthorizationCodeStorage, ClientStorage, TokenStorage};
+use crate::token::{AccessToken, RefreshToken};
+use crate::Result;
+
+use super::{access_token_ttl, OAuthHandler};
+
+#[async_trait]
+pub trait ClientCredentialsGrant {
+    /// Issues an access token to the client itself, for the requested scope
+    /// or all its allowed scopes. No refresh token is issued, as the client can always ask again.
+    async fn issue_client_token(
+        &self,
+        client: &Client,
+        req: &ClientCredentialsRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse>;
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> ClientCredentialsGrant for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn issue_client_token(
+        &self,
+        client: &Client,
+        req: &ClientCredentialsRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse> {
+        if !matches!(client.kind(), ClientKind::Confidential { .. }) {
+            return Err(Error::new(
+                ErrorKind::UnauthorizedClient,
+                "only confidential clients can use the client_credentials grant".to_string(),
+            ));
+        }
+
+        let scope = req.scope.clone().unwrap_or_default();
+        let mut session = Session::for_client(client.client_id().to_string());
+        session.set_scope(self.validate_scope(client, &scope)?);
+        let resources = self.narrow_resources(&session, &req.resource)?;
+        session.set_resources(resources);
+        if let Some(device) = device {
+            session.set_requester(device);
+        }
+        log::info!("Issuing a client token to client '{}'", client.client_id());
+        self.generate_tokens(&session, false, access_token_ttl(), TokenAction::Issued).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use url::Url;
+
+    use crate::resource::ResourceRegistry;
+    use crate::scope::Scope;
+    use crate::token::Token;
+
+    use super::super::test::{client, handler};
+    use super::super::TokenIntrospectionHandler;
+    use super::*;
+
+    fn request(scope: Option<&str>) -> ClientCredentialsRequest {
+        ClientCredentialsRequest {
+            scope: scope.map(Scope::from),
+            client_id: None,
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            resource: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn it_issues_an_access_token_to_confidential_clients() {
+        let (storage, handler) = handler();
+        let client = client(&storage, "service");
+        let res = block_on(handler.issue_client_token(&client, &request(None), None)).unwrap();
+        assert_eq!(res.scope, Scope::from("profile clients:read"));
+        assert_eq!(res.refresh_token, None);
+
+        let res = block_on(handler.issue_client_token(&client, &request(Some("profile")), None))
+            .unwrap();
+        assert_eq!(res.scope, Scope::from("profile"));
+
+        let req = request(Some("admin"));
+        let err = block_on(handler.issue_client_token(&client, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+    }
+
+    #[test]
+    fn it_refuses_public_clients() {
+        let (storage, handler) = handler();
+        let client = client(&storage, "test");
+        let err = block_on(handler.issue_client_token(&client, &request(None), None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnauthorizedClient);
+    }
+
+    #[test]
+    fn it_restricts_tokens_to_known_resources() {
+        let (storage, mut handler) = handler();
+        let mut registry = ResourceRegistry::new();
+        registry.register(Url::parse("https://api.example.com").unwrap());
+        handler.set_resource_registry(Arc::new(registry));
+        let client = client(&storage, "service");
+
+        let mut req = request(None);
+        req.resource = vec!["https://api.example.com".to_string()];
+        let res = block_on(handler.issue_client_token(&client, &req, None)).unwrap();
+        let token: AccessToken = block_on(handler.get_token(&res.access_token)).unwrap();
+        assert_eq!(token.session().resources(), ["https://api.example.com/"]);
+
+        req.resource = vec!["https://evil.example.com".to_string()];
+        let err = block_on(handler.issue_client_token(&client, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidTarget);
+    }
+}
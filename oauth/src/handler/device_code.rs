@@ -0,0 +1,243 @@
+//! Device authorization grant, see [`crate::device_code`]
+use async_trait::async_trait;
+use chrono::Utc;
+
+use enseada::secure;
+
+use crate::client::Client;
+use crate::device::Device;
+use crate::device_code::{self, DeviceAuthorization};
+use crate::error::{Error, ErrorKind};
+use crate::request::{DeviceAuthorizationRequest, DeviceCodeRequest, GrantType};
+use crate::secrets;
+use crate::response::{DeviceAuthorizationResponse, TokenResponse};
+use crate::session::Session;
+use crate::storage::{AuthorizationCodeStorage, ClientStorage, TokenStorage};
+use crate::token::{AccessToken, RefreshToken};
+use crate::{Expirable, Result};
+
+use super::{capped_scope, BasicAuth, OAuthHandler};
+
+/// Attempts to generate a user code that is not in use already
+const USER_CODE_ATTEMPTS: usize = 5;
+
+#[async_trait]
+pub trait DeviceCodeGrant {
+    /// Answers a poll of the device code, issuing a token set once the user approved it.
+    /// The device code is invalidated when tokens are issued, or the request was denied or expired.
+    async fn poll_device_code(
+        &self,
+        client: &Client,
+        req: &DeviceCodeRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse>;
+}
+
+impl<CS, ATS, RTS, ACS> OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    /// Issues a device code for the client to poll the token endpoint with,
+    /// and a user code for the user to approve the client at the verification URI.
+    pub async fn authorize_device(
+        &self,
+        req: &DeviceAuthorizationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<DeviceAuthorizationResponse> {
+        let storage = self.device_authorization_storage()?;
+        let auth_client_id = client_auth.map(|BasicAuth(client_id, _client_secret)| client_id);
+        let auth_client_secret =
+            client_auth.and_then(|BasicAuth(_client_id, client_secret)| client_secret.as_ref());
+        let client_id = req.client_id.as_ref().or(auth_client_id).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string())
+        })?;
+        if let Some(auth_client_id) = auth_client_id {
+            if auth_client_id != client_id {
+                return Err(Error::new(
+                    ErrorKind::InvalidClient,
+                    format!("invalid client '{}'", auth_client_id),
+                ));
+            }
+        }
+
+        let client = self.validate_client(client_id, None, &req.scope).await?;
+        if !client.is_grant_type_allowed(&GrantType::DeviceCode) {
+            return Err(Error::new(
+                ErrorKind::UnauthorizedClient,
+                format!("client is not allowed to use the {} grant", GrantType::DeviceCode),
+            ));
+        }
+        self.authenticate_client(&client, req.client_secret.as_ref().or(auth_client_secret))
+            .await?;
+
+        let mut user_code = device_code::generate_user_code();
+        for _ in 1..USER_CODE_ATTEMPTS {
+            if storage.find_device_authorization(&user_code).await.is_none() {
+                break;
+            }
+            user_code = device_code::generate_user_code();
+        }
+        let value = secrets::encode(&self.secrets.generate()?);
+        let sig = secure::generate_signature(&value, &self.secret_key);
+        let authorization = DeviceAuthorization::new(
+            client_id.clone(),
+            req.scope.restrict_to(client.allowed_scopes())?,
+            user_code,
+            device_code::lifetime(),
+        );
+        let authorization = storage
+            .store_device_authorization(sig.to_string().as_str(), authorization)
+            .await?;
+        log::info!("Issued device code to client '{}'", client_id);
+
+        let verification_uri = self.verification_uri.clone().unwrap();
+        let mut verification_uri_complete = verification_uri.clone();
+        verification_uri_complete
+            .query_pairs_mut()
+            .append_pair("user_code", authorization.user_code());
+        Ok(DeviceAuthorizationResponse {
+            device_code: value,
+            user_code: authorization.user_code().to_string(),
+            verification_uri,
+            verification_uri_complete,
+            expires_in: authorization.expires_in(),
+            interval: authorization.interval(),
+        })
+    }
+
+    /// Loads the device authorization of a user code, as typed by the user,
+    /// along with the signature of its device code. Only pending authorizations are returned.
+    async fn find_pending_device_authorization(
+        &self,
+        user_code: &str,
+    ) -> Result<(String, DeviceAuthorization)> {
+        let storage = self.device_authorization_storage()?;
+        let user_code = device_code::normalize_user_code(user_code);
+        match storage.find_device_authorization(&user_code).await {
+            Some((sig, authorization))
+                if authorization.is_pending() && !authorization.is_expired() =>
+            {
+                Ok((sig, authorization))
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidGrant,
+                "invalid or expired user code".to_string(),
+            )),
+        }
+    }
+
+    /// Loads the pending device authorization of a user code, for the user to review it
+    pub async fn pending_device_authorization(
+        &self,
+        user_code: &str,
+    ) -> Result<DeviceAuthorization> {
+        let (_sig, authorization) = self.find_pending_device_authorization(user_code).await?;
+        Ok(authorization)
+    }
+
+    /// Approves the device authorization of a user code for the session of the user,
+    /// or denies it if no session is given. The session gets the scope the client asked for,
+    /// capped by the scope it was given beforehand if any.
+    pub async fn decide_device_authorization(
+        &self,
+        user_code: &str,
+        session: Option<Session>,
+    ) -> Result<()> {
+        let storage = self.device_authorization_storage()?;
+        let (sig, mut authorization) = self.find_pending_device_authorization(user_code).await?;
+        let session = match session {
+            Some(mut session) => {
+                if session.client_id() != authorization.client_id() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidClient,
+                        format!("invalid client '{}'", session.client_id()),
+                    ));
+                }
+                let scope = capped_scope(authorization.scope().clone(), &session);
+                session.set_scope(scope);
+                Some(session)
+            }
+            None => None,
+        };
+        let approved = session.is_some();
+        authorization.decide(session);
+        storage.store_device_authorization(&sig, authorization).await?;
+        log::info!(
+            "User code {} was {}",
+            device_code::normalize_user_code(user_code),
+            if approved { "approved" } else { "denied" }
+        );
+        Ok(())
+    }
+
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> DeviceCodeGrant for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn poll_device_code(
+        &self,
+        client: &Client,
+        req: &DeviceCodeRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse> {
+        let storage = self.device_authorization_storage()?;
+        let sig = secure::generate_signature(&req.device_code, &self.secret_key).to_string();
+        let invalid = || Error::new(ErrorKind::InvalidGrant, "invalid device code".to_string());
+        let mut authorization = storage
+            .get_device_authorization(&sig)
+            .await
+            .ok_or_else(invalid)?;
+        if authorization.client_id() != client.client_id() {
+            return Err(Error::new(
+                ErrorKind::InvalidClient,
+                format!("invalid client '{}'", client.client_id()),
+            ));
+        }
+        self.validate_scope(client, authorization.scope())?;
+
+        match authorization.poll(Utc::now()) {
+            Ok(mut session) => {
+                let resources = self.narrow_resources(&session, &req.resource)?;
+                session.set_resources(resources);
+                // Only the caller removing the authorization gets the tokens
+                if !storage.take_device_authorization(&sig).await? {
+                    return Err(invalid());
+                }
+                if let Some(device) = device {
+                    session.set_requester(device);
+                }
+                self.generate_token_set(&session).await
+            }
+            Err(err) => {
+                match err.kind() {
+                    ErrorKind::AuthorizationPending | ErrorKind::SlowDown => {
+                        // Polls updating the authorization concurrently are too fast anyway
+                        if storage
+                            .store_device_authorization(&sig, authorization)
+                            .await
+                            .is_err()
+                        {
+                            return Err(Error::new(
+                                ErrorKind::SlowDown,
+                                "concurrent polls of the device code".to_string(),
+                            ));
+                        }
+                    }
+                    _ => {
+                        storage.take_device_authorization(&sig).await?;
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+}
@@ -0,0 +1,1492 @@
+//! OAuth handler, dispatching token requests to the handler of their grant type
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+
+use async_trait::async_trait;
+use enseada::secure;
+use url::Url;
+
+use crate::assertion::{self, ClientAssertion, ClientAssertions};
+use crate::bootstrap::BootstrapToken;
+use crate::client::{Client, ClientKind};
+use crate::code;
+use crate::device::{Device, DeviceTracker};
+use crate::error::{Error, ErrorKind};
+use crate::events::{self, TokenAction, TokenEvent, TokenEventListener};
+use crate::issuance::IssuanceMonitor;
+use crate::par::{self, PushedRequest};
+use crate::registry::ScopeRegistry;
+use crate::resource::{self, ResourceRegistry};
+use crate::request::{
+    AuthorizationRequest, BootstrapRequest, GrantType, IntrospectionRequest, PushRequest,
+    PushedRequestReference, RevocationRequest, TokenRequest,
+};
+use crate::response::{
+    AuthorizationResponse, Diagnosis, IntrospectionResponse, PushResponse, RevocationResponse,
+    TokenAccess, TokenResponse, TokenType,
+};
+use crate::scope::Scope;
+use crate::secrets::{self, Secrets};
+use crate::session::Session;
+use crate::signing::{AccessTokenClaims, TokenSigner};
+use crate::storage::{
+    AuthorizationCodeStorage, BootstrapTokenStorage, ClientStorage, DeviceAuthorizationStorage,
+    PushedRequestStorage, TokenStorage,
+};
+use crate::token::{AccessToken, RefreshToken, Token, TokenTypeHint};
+use crate::{Expirable, Result};
+
+pub use self::authorization_code::AuthorizationCodeGrant;
+pub use self::client_credentials::ClientCredentialsGrant;
+pub use self::device_code::DeviceCodeGrant;
+pub use self::refresh_token::RefreshTokenGrant;
+
+mod authorization_code;
+mod client_credentials;
+mod device_code;
+mod refresh_token;
+
+/// Grant types the token endpoint issues tokens for. Others are parsed but refused.
+pub const SUPPORTED_GRANT_TYPES: &[GrantType] = &[
+    GrantType::AuthorizationCode,
+    GrantType::RefreshToken,
+    GrantType::ClientCredentials,
+    GrantType::DeviceCode,
+];
+
+/// Scope of the resource servers allowed to introspect the tokens of any client,
+/// only honored for clients an admin marked as [resource servers](Client::is_resource_server)
+pub const INTROSPECTION_SCOPE: &str = "tokens:introspect";
+
+/// Longest lifetime of an impersonation token in minutes, whatever is asked for
+pub const MAX_IMPERSONATION_MINUTES: i64 = 15;
+
+/// Represent HTTP basic authentication as (client_id, client_secret)
+#[derive(Debug)]
+pub struct BasicAuth(String, Option<String>);
+
+impl BasicAuth {
+    pub fn new(username: String, password: Option<String>) -> Self {
+        BasicAuth(username, password)
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.0
+    }
+}
+
+#[async_trait]
+pub trait RequestHandler<T, R> {
+    async fn validate(&self, req: &T, client_auth: Option<&BasicAuth>) -> Result<Client>;
+    async fn handle(&self, req: &T, session: &mut Session) -> Result<R>;
+}
+
+#[async_trait]
+pub trait TokenIntrospectionHandler<T: Token> {
+    async fn get_token(&self, token: &str) -> Result<T>;
+    async fn revoke_token(&self, token: &str) -> Result<()>;
+}
+
+pub struct OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    client_storage: Arc<CS>,
+    access_token_storage: Arc<ATS>,
+    refresh_token_storage: Arc<RTS>,
+    authorization_code_storage: Arc<ACS>,
+    secret_key: String,
+    issuance_monitor: Option<Arc<IssuanceMonitor>>,
+    bootstrap_storage: Option<Arc<dyn BootstrapTokenStorage>>,
+    pushed_request_storage: Option<Arc<dyn PushedRequestStorage>>,
+    device_authorization_storage: Option<Arc<dyn DeviceAuthorizationStorage>>,
+    verification_uri: Option<Url>,
+    scope_registry: Option<Arc<ScopeRegistry>>,
+    token_signer: Option<Arc<dyn TokenSigner>>,
+    resource_registry: Option<Arc<ResourceRegistry>>,
+    token_event_listeners: Vec<Arc<dyn TokenEventListener>>,
+    secrets: Secrets,
+    client_assertions: Option<Arc<ClientAssertions>>,
+    device_tracker: Option<Arc<DeviceTracker>>,
+}
+
+impl<CS, ATS, RTS, ACS> OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    /// Creates a new handler backed by the given storages.
+    /// The secret key is used to sign issued tokens and codes before storing them.
+    pub fn new(
+        client_storage: Arc<CS>,
+        access_token_storage: Arc<ATS>,
+        refresh_token_storage: Arc<RTS>,
+        authorization_code_storage: Arc<ACS>,
+        secret_key: String,
+    ) -> OAuthHandler<CS, ATS, RTS, ACS>
+    where
+        CS: ClientStorage,
+        ATS: TokenStorage<AccessToken>,
+        RTS: TokenStorage<RefreshToken>,
+        ACS: AuthorizationCodeStorage,
+    {
+        OAuthHandler {
+            client_storage,
+            access_token_storage,
+            refresh_token_storage,
+            authorization_code_storage,
+            secret_key,
+            issuance_monitor: None,
+            bootstrap_storage: None,
+            pushed_request_storage: None,
+            device_authorization_storage: None,
+            verification_uri: None,
+            scope_registry: None,
+            token_signer: None,
+            resource_registry: None,
+            token_event_listeners: Vec::new(),
+            secrets: Secrets::default(),
+            client_assertions: None,
+            device_tracker: None,
+        }
+    }
+
+    /// Tracks issued token sets per client, refusing them if the monitor is configured to.
+    pub fn set_issuance_monitor(&mut self, monitor: Arc<IssuanceMonitor>) -> &mut Self {
+        self.issuance_monitor = Some(monitor);
+        self
+    }
+
+    /// Enables bootstrap tokens, which are refused unless a storage is set.
+    pub fn set_bootstrap_storage(&mut self, storage: Arc<dyn BootstrapTokenStorage>) -> &mut Self {
+        self.bootstrap_storage = Some(storage);
+        self
+    }
+
+    /// Enables pushed authorization requests, which are refused unless a storage is set.
+    pub fn set_pushed_request_storage(
+        &mut self,
+        storage: Arc<dyn PushedRequestStorage>,
+    ) -> &mut Self {
+        self.pushed_request_storage = Some(storage);
+        self
+    }
+
+    /// Enables the device authorization grant, which is refused unless a storage is set.
+    /// Users enter their user codes at the verification URI to approve devices.
+    pub fn set_device_authorization_storage(
+        &mut self,
+        storage: Arc<dyn DeviceAuthorizationStorage>,
+        verification_uri: Url,
+    ) -> &mut Self {
+        self.device_authorization_storage = Some(storage);
+        self.verification_uri = Some(verification_uri);
+        self
+    }
+
+    /// Rejects requests for scopes that are not registered. Any scope is accepted without a registry.
+    pub fn set_scope_registry(&mut self, registry: Arc<ScopeRegistry>) -> &mut Self {
+        self.scope_registry = Some(registry);
+        self
+    }
+
+    /// Issues access tokens as JWTs signed by the signer, instead of opaque values
+    pub fn set_token_signer(&mut self, signer: Arc<dyn TokenSigner>) -> &mut Self {
+        self.token_signer = Some(signer);
+        self
+    }
+
+    /// Rejects requests for resources that are not registered.
+    /// Any absolute URI is accepted as a resource without a registry.
+    pub fn set_resource_registry(&mut self, registry: Arc<ResourceRegistry>) -> &mut Self {
+        self.resource_registry = Some(registry);
+        self
+    }
+
+    /// Generates codes and tokens with the given secrets, instead of 32 bytes from the OS
+    pub fn set_secrets(&mut self, secrets: Secrets) -> &mut Self {
+        self.secrets = secrets;
+        self
+    }
+
+    /// Lets clients with registered keys authenticate to the token endpoint with assertions,
+    /// which are refused unless a verifier is set.
+    pub fn set_client_assertions(&mut self, assertions: Arc<ClientAssertions>) -> &mut Self {
+        self.client_assertions = Some(assertions);
+        self
+    }
+
+    /// Tracks the devices users are issued tokens on, so that new ones can be reported
+    pub fn set_device_tracker(&mut self, tracker: Arc<DeviceTracker>) -> &mut Self {
+        self.device_tracker = Some(tracker);
+        self
+    }
+
+    /// Notifies the listener of every issued, refreshed and revoked token
+    pub fn add_token_event_listener(&mut self, listener: Arc<dyn TokenEventListener>) -> &mut Self {
+        self.token_event_listeners.push(listener);
+        self
+    }
+
+    async fn notify<T: Token>(&self, action: TokenAction, token: &T) {
+        if self.token_event_listeners.is_empty() {
+            return;
+        }
+        let event = TokenEvent::new(action, token);
+        for listener in &self.token_event_listeners {
+            events::notify(listener.as_ref(), &event).await;
+        }
+    }
+
+    fn bootstrap_storage(&self) -> Result<&Arc<dyn BootstrapTokenStorage>> {
+        self.bootstrap_storage.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnsupportedGrantType,
+                "bootstrap tokens are disabled".to_string(),
+            )
+        })
+    }
+
+    fn pushed_request_storage(&self) -> Result<&Arc<dyn PushedRequestStorage>> {
+        self.pushed_request_storage.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidRequest,
+                "pushed authorization requests are disabled".to_string(),
+            )
+        })
+    }
+
+    fn device_authorization_storage(&self) -> Result<&Arc<dyn DeviceAuthorizationStorage>> {
+        self.device_authorization_storage.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnsupportedGrantType,
+                "device authorization is disabled".to_string(),
+            )
+        })
+    }
+
+    /// Mints a single-use token that can be exchanged for a token set of the session.
+    /// The returned token is the only copy of its value, as only its signature is stored.
+    pub async fn issue_bootstrap_token(
+        &self,
+        session: Session,
+        expires_in: Duration,
+    ) -> Result<BootstrapToken> {
+        let storage = self.bootstrap_storage()?;
+        let value = self.secrets.generate()?;
+        let sig = secure::generate_signature(&secrets::encode(&value), &self.secret_key);
+        let token = BootstrapToken::new(value, session, expires_in);
+        storage
+            .store_bootstrap_token(sig.to_string().as_str(), token)
+            .await
+    }
+
+    /// Exchanges a bootstrap token for a token set, invalidating it.
+    pub async fn redeem_bootstrap_token(
+        &self,
+        req: &BootstrapRequest,
+        client_auth: Option<&BasicAuth>,
+        device: Option<Device>,
+    ) -> Result<TokenResponse> {
+        let storage = self.bootstrap_storage()?;
+        let sig = secure::generate_signature(&req.bootstrap_token, &self.secret_key).to_string();
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidGrant,
+                "invalid bootstrap token".to_string(),
+            )
+        };
+        let token = storage
+            .get_bootstrap_token(&sig)
+            .await
+            .ok_or_else(invalid)?;
+        if token.is_expired() {
+            storage.take_bootstrap_token(&sig).await?;
+            return Err(invalid());
+        }
+
+        let auth_client_id = client_auth.map(|BasicAuth(client_id, _client_secret)| client_id);
+        let auth_client_secret =
+            client_auth.and_then(|BasicAuth(_client_id, client_secret)| client_secret.as_ref());
+        let mut session = token.session().clone();
+        if let Some(client_id) = req.client_id.as_ref().or(auth_client_id) {
+            if client_id != session.client_id() {
+                return Err(Error::new(
+                    ErrorKind::InvalidClient,
+                    format!("invalid client '{}'", client_id),
+                ));
+            }
+        }
+        let client = self
+            .validate_client(session.client_id(), None, session.scope())
+            .await?;
+        self.authenticate_client(&client, req.client_secret.as_ref().or(auth_client_secret))
+            .await?;
+
+        // Only the caller removing the token gets to exchange it
+        if !storage.take_bootstrap_token(&sig).await? {
+            return Err(invalid());
+        }
+        log::info!(
+            "Redeemed bootstrap token of user {:?} for client '{}'",
+            session.user_id(),
+            session.client_id()
+        );
+
+        if let Some(device) = device {
+            session.set_requester(device);
+        }
+        self.generate_token_set(&session).await
+    }
+
+    /// Validates and stores an authorization request pushed by a confidential client,
+    /// returning the request URI to send to the authorization endpoint instead of its parameters.
+    pub async fn push_authorization_request(
+        &self,
+        req: &PushRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<PushResponse> {
+        let storage = self.pushed_request_storage()?;
+        let auth = &req.request;
+        let auth_client_secret = match client_auth {
+            Some(BasicAuth(client_id, _)) if client_id != &auth.client_id => {
+                return Err(Error::new(
+                    ErrorKind::InvalidClient,
+                    format!("invalid client '{}'", client_id),
+                ))
+            }
+            Some(BasicAuth(_, client_secret)) => client_secret.as_ref(),
+            None => None,
+        };
+        let client = RequestHandler::validate(self, auth, client_auth).await?;
+        if !matches!(client.kind(), ClientKind::Confidential { .. }) {
+            return Err(Error::new(
+                ErrorKind::UnauthorizedClient,
+                "only confidential clients can push authorization requests".to_string(),
+            ));
+        }
+        self.authenticate_client(&client, req.client_secret.as_ref().or(auth_client_secret))
+            .await?;
+
+        let value = secrets::encode(&self.secrets.generate()?);
+        let sig = secure::generate_signature(&value, &self.secret_key);
+        let pushed = PushedRequest::new(auth.clone(), par::lifetime());
+        let pushed = storage
+            .store_pushed_request(sig.to_string().as_str(), pushed)
+            .await?;
+        log::info!(
+            "Stored pushed authorization request of client '{}'",
+            pushed.client_id()
+        );
+        Ok(PushResponse {
+            request_uri: format!("{}{}", par::REQUEST_URI_PREFIX, value),
+            expires_in: pushed.expires_in(),
+        })
+    }
+
+    /// Loads the authorization request a request URI references, invalidating it.
+    /// Request URIs can only be used by the client that pushed them.
+    pub async fn pushed_authorization_request(
+        &self,
+        reference: &PushedRequestReference,
+    ) -> Result<AuthorizationRequest> {
+        let storage = self.pushed_request_storage()?;
+        let invalid = || Error::new(ErrorKind::InvalidRequest, "invalid request_uri".to_string());
+        let value = reference
+            .request_uri
+            .strip_prefix(par::REQUEST_URI_PREFIX)
+            .ok_or_else(invalid)?;
+        let sig = secure::generate_signature(value, &self.secret_key).to_string();
+        let pushed = storage.get_pushed_request(&sig).await.ok_or_else(invalid)?;
+        // Removed before anything else is checked, so that a request URI is never used twice
+        if !storage.take_pushed_request(&sig).await? || pushed.is_expired() {
+            return Err(invalid());
+        }
+        if pushed.client_id() != reference.client_id {
+            log::warn!(
+                "Client '{}' used a request URI pushed by client '{}'",
+                &reference.client_id,
+                pushed.client_id()
+            );
+            return Err(invalid());
+        }
+        Ok(pushed.request().clone())
+    }
+
+    /// Registered client with the given ID, if any
+    pub async fn client(&self, client_id: &str) -> Option<Client> {
+        self.client_storage.get_client(client_id).await
+    }
+
+    /// Whether the caller of the session can introspect the tokens of any client: it must be
+    /// granted the [`INTROSPECTION_SCOPE`] and be a client marked as a resource server
+    pub(crate) async fn is_resource_server(&self, session: &Session) -> bool {
+        if !session.scope().grants(INTROSPECTION_SCOPE) {
+            return false;
+        }
+        self.client_storage
+            .get_client(session.client_id())
+            .await
+            .is_some_and(|client| client.is_resource_server())
+    }
+
+    /// Dry-runs the validation of an authorization request, collecting every violation
+    /// instead of stopping at the first one. Nothing is stored.
+    pub async fn diagnose(&self, req: &AuthorizationRequest) -> Diagnosis {
+        let client = match self.client_storage.get_client(&req.client_id).await {
+            Some(client) => client,
+            None => {
+                let err = Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string());
+                return Diagnosis::new(vec![err], Vec::new());
+            }
+        };
+
+        let mut advisories = Vec::new();
+        if req.scope.is_empty() {
+            advisories.push(format!(
+                "no scope requested, the user would grant the full allowed scope '{}'",
+                client.allowed_scopes()
+            ));
+        }
+        if req.state.is_none() {
+            advisories.push(
+                "no state given, the client cannot detect forged authorization responses"
+                    .to_string(),
+            );
+        }
+        if !client.is_grant_type_allowed(&GrantType::AuthorizationCode) {
+            advisories.push(format!(
+                "the client is not allowed to use the {} grant, so the issued code cannot be exchanged for tokens",
+                GrantType::AuthorizationCode
+            ));
+        }
+
+        let violations = authorization_violations(
+            &client,
+            req,
+            self.scope_registry.as_deref(),
+            self.resource_registry.as_deref(),
+        );
+        Diagnosis::new(violations, advisories)
+    }
+
+    /// Rejects token requests using a grant type the client is not allowed to use.
+    /// Unknown clients are left to the grant-specific validation.
+    async fn validate_grant_type(&self, client_id: &str, grant_type: &GrantType) -> Result<()> {
+        log::debug!("Validating grant type {} for client '{}'", grant_type, client_id);
+        match self.client_storage.get_client(client_id).await {
+            Some(client) if !client.is_grant_type_allowed(grant_type) => Err(Error::new(
+                ErrorKind::UnauthorizedClient,
+                format!("client is not allowed to use the {} grant", grant_type),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn validate_client(
+        &self,
+        client_id: &str,
+        redirect_uri: Option<&String>,
+        scope: &Scope,
+    ) -> Result<Client> {
+        log::debug!("Validating client '{}'", client_id);
+        let client = self
+            .client_storage
+            .get_client(client_id)
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string()))?;
+
+        if let Some(redirect_uri) = redirect_uri {
+            log::debug!("Validating redirect_uri");
+            if !client.is_redirect_uri_allowed(redirect_uri) {
+                return Err(Error::new(
+                    ErrorKind::InvalidRedirectUri,
+                    String::from("invalid redirect URI"),
+                ));
+            }
+        }
+
+        self.validate_scope(&client, scope)?;
+
+        log::debug!("Client validation successful");
+        Ok(client)
+    }
+
+    /// Revokes the access token issued along with the refresh token, if it is still valid.
+    /// Failures are ignored, since the access token may have been revoked before the refresh token.
+    async fn revoke_related_access_token(&self, refresh_token: &RefreshToken) {
+        let sig = refresh_token.related_access_token_signature();
+        if let Some(access_token) = self.access_token_storage.get_token(sig).await {
+            if self.access_token_storage.revoke_token(sig).await.is_ok() {
+                self.notify(TokenAction::Revoked, &access_token).await;
+            }
+        }
+    }
+
+    /// Validates a requested scope against the registry and the scopes allowed to the client,
+    /// returning the scope to grant
+    fn validate_scope(&self, client: &Client, scope: &Scope) -> Result<Scope> {
+        log::debug!("Validating request scopes");
+        if let Some(registry) = &self.scope_registry {
+            registry.validate(scope)?;
+        }
+        scope.restrict_to(client.allowed_scopes())
+    }
+
+    /// Resources to issue the tokens of a session for, out of the ones it was granted,
+    /// see [`resource::narrow`]
+    fn narrow_resources(&self, session: &Session, requested: &[String]) -> Result<Vec<String>> {
+        log::debug!("Validating requested resources");
+        resource::narrow(
+            self.resource_registry.as_deref(),
+            session.resources(),
+            requested,
+        )
+    }
+
+    async fn authenticate_client(
+        &self,
+        client: &Client,
+        client_secret: Option<&String>,
+    ) -> Result<()> {
+        log::debug!("Checking client authentication");
+        match client.kind() {
+            ClientKind::Public | ClientKind::Native => {
+                log::debug!(
+                    "Client is of kind '{}', no authentication needed",
+                    client.kind()
+                );
+                Ok(())
+            }
+            ClientKind::Confidential { secret } => {
+                log::debug!("Client is of kind 'confidential', validating secret");
+                match client_secret {
+                    Some(client_secret) => {
+                        if secure::verify_password(secret, client_secret)? {
+                            log::debug!("Client authentication successful");
+                            Ok(())
+                        } else {
+                            log::debug!("Client authentication failed");
+                            Err(Error::new(
+                                ErrorKind::InvalidClient,
+                                "invalid client credentials".to_string(),
+                            ))
+                        }
+                    }
+                    None => Err(Error::new(
+                        ErrorKind::InvalidClient,
+                        "invalid client credentials".to_string(),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Authenticates the client of a token request, with an assertion if it registered keys
+    /// and with its secret otherwise
+    async fn authenticate_token_client(
+        &self,
+        client: &Client,
+        assertion: Option<&ClientAssertion>,
+        client_secret: Option<&String>,
+    ) -> Result<()> {
+        match assertion {
+            Some(_) if client_secret.is_some() => Err(Error::new(
+                ErrorKind::InvalidRequest,
+                "only one client authentication method can be used".to_string(),
+            )),
+            Some(assertion) => {
+                let assertions = self.client_assertions.as_ref().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidClient,
+                        "client assertions are disabled".to_string(),
+                    )
+                })?;
+                assertions.verify(client, assertion)
+            }
+            None if !client.jwks().is_empty() => Err(Error::new(
+                ErrorKind::InvalidClient,
+                "the client must authenticate with a client assertion".to_string(),
+            )),
+            None => self.authenticate_client(client, client_secret).await,
+        }
+    }
+
+    /// Issues an access token acting as the user of a session naming their impersonator,
+    /// without a refresh token so that the impersonation ends with it. It is valid for
+    /// [`MAX_IMPERSONATION_MINUTES`] at most.
+    pub async fn issue_impersonation_token(
+        &self,
+        session: &Session,
+        expires_in: Duration,
+    ) -> Result<TokenResponse> {
+        if session.impersonator().is_none() || session.user_id().is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidRequest,
+                "impersonation sessions must name the user and their impersonator".to_string(),
+            ));
+        }
+        let expires_in = expires_in.min(Duration::minutes(MAX_IMPERSONATION_MINUTES));
+        self.generate_tokens(session, false, expires_in, TokenAction::Issued)
+            .await
+    }
+
+    async fn generate_token_set(&self, session: &Session) -> Result<TokenResponse> {
+        self.generate_tokens(session, true, access_token_ttl(), TokenAction::Issued)
+            .await
+    }
+
+    /// Issues an access token for the session, along with a refresh token if asked for,
+    /// notifying the listeners with the given action
+    async fn generate_tokens(
+        &self,
+        session: &Session,
+        with_refresh_token: bool,
+        access_token_ttl: Duration,
+        action: TokenAction,
+    ) -> Result<TokenResponse> {
+        let client = self.client_storage.get_client(session.client_id()).await;
+        if let (Some(monitor), Some(client)) = (&self.issuance_monitor, &client) {
+            monitor.track(client)?;
+        }
+
+        // Tokens are bound to the device requesting them, so a refresh moves them to its new origin
+        let mut session = session.clone();
+        let origin = match (&client, session.requester()) {
+            (Some(client), Some(device)) if client.binds_tokens() => Some(device.origin()),
+            _ => None,
+        };
+        let issued_at = Utc::now();
+        session.set_origin(origin).set_issued_at(Some(issued_at));
+        let session_for = |expires_in: Duration| {
+            let mut session = session.clone();
+            session.set_expires_at(Some(issued_at + expires_in));
+            session
+        };
+
+        let access_token_value = self.secrets.generate()?;
+        let access_token_session = session_for(access_token_ttl);
+        // Signed tokens are stored by the signature of the JWT, as that is what clients present
+        let access_token_string = match &self.token_signer {
+            Some(signer) => {
+                let jti = secrets::encode(&access_token_value);
+                let claims = AccessTokenClaims::new(signer.issuer(), &access_token_session, jti);
+                signer.sign(&claims).await?
+            }
+            None => secrets::encode(&access_token_value),
+        };
+        let access_token_sig =
+            secure::generate_signature(&access_token_string, &self.secret_key).to_string();
+        let access_token =
+            AccessToken::new(access_token_value, access_token_session, access_token_ttl);
+        let access_token = self
+            .access_token_storage
+            .store_token(access_token_sig.as_str(), access_token)
+            .await?;
+        self.notify(action, &access_token).await;
+        self.track_device(&session).await;
+
+        let refresh_token = if with_refresh_token {
+            let refresh_token_ttl = Duration::days(1);
+            let refresh_token_value = self.secrets.generate()?;
+            let refresh_token_sig = secure::generate_signature(
+                &secrets::encode(&refresh_token_value),
+                &self.secret_key,
+            );
+            let refresh_token = RefreshToken::new(
+                refresh_token_value,
+                session_for(refresh_token_ttl),
+                refresh_token_ttl,
+                access_token_sig,
+            );
+            let refresh_token = self
+                .refresh_token_storage
+                .store_token(refresh_token_sig.to_string().as_str(), refresh_token)
+                .await?;
+            self.notify(action, &refresh_token).await;
+            Some(refresh_token.to_string())
+        } else {
+            None
+        };
+
+        Ok(TokenResponse {
+            access_token: access_token_string,
+            token_type: TokenType::Bearer,
+            expires_in: access_token.expires_in(),
+            refresh_token,
+            scope: session.scope().clone(),
+            extra: HashMap::new(),
+        })
+    }
+
+    /// Records the device requesting tokens of a user, failures only being logged
+    /// as they must not prevent the issuance
+    async fn track_device(&self, session: &Session) {
+        let tracker = match &self.device_tracker {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        if let (Some(user_id), Some(device)) = (session.user_id(), session.requester()) {
+            if let Err(err) = tracker.track(user_id, session.client_id(), device).await {
+                log::error!("Failed to track the device of user {}: {}", user_id, err);
+            }
+        }
+    }
+}
+
+/// Lifetime of the access tokens issued by the grants
+fn access_token_ttl() -> Duration {
+    Duration::minutes(5)
+}
+
+/// Every reason an authorization request from the client would be rejected, in the order they are checked
+fn authorization_violations(
+    client: &Client,
+    req: &AuthorizationRequest,
+    registry: Option<&ScopeRegistry>,
+    resources: Option<&ResourceRegistry>,
+) -> Vec<Error> {
+    let mut violations = Vec::new();
+
+    log::debug!("Validating redirect_uri");
+    if !client.is_redirect_uri_allowed(&req.redirect_uri) {
+        violations.push(Error::new(
+            ErrorKind::InvalidRedirectUri,
+            String::from("invalid redirect URI"),
+        ));
+    }
+
+    log::debug!("Validating request scopes");
+    let known = registry.map_or(Ok(()), |registry| registry.validate(&req.scope));
+    if let Err(err) = known.and_then(|_| req.scope.restrict_to(client.allowed_scopes())) {
+        violations.push(err);
+    }
+
+    log::debug!("Validating requested resources");
+    if let Err(err) = resource::validate(resources, &req.resource) {
+        violations.push(err);
+    }
+
+    if let Err(err) = req.response_mode() {
+        violations.push(err);
+    }
+
+    if let Err(err) = req.prompt() {
+        violations.push(err);
+    }
+
+    if client.requires_state() && req.state.is_none() {
+        violations.push(Error::new(
+            ErrorKind::InvalidRequest,
+            "state is required".to_string(),
+        ));
+    }
+
+    violations
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> RequestHandler<AuthorizationRequest, AuthorizationResponse>
+    for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn validate(
+        &self,
+        req: &AuthorizationRequest,
+        _client_auth: Option<&BasicAuth>,
+    ) -> Result<Client> {
+        log::debug!("Validating client '{}'", &req.client_id);
+        let client = self
+            .client_storage
+            .get_client(&req.client_id)
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string()))?;
+        let violations = authorization_violations(
+            &client,
+            req,
+            self.scope_registry.as_deref(),
+            self.resource_registry.as_deref(),
+        );
+        match violations.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(client),
+        }
+    }
+
+    async fn handle(
+        &self,
+        req: &AuthorizationRequest,
+        session: &mut Session,
+    ) -> Result<AuthorizationResponse> {
+        log::info!("Handling new authorization request");
+        let client = self
+            .client_storage
+            .get_client(session.client_id())
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string()))?;
+        let resources = resource::validate(self.resource_registry.as_deref(), &req.resource)?;
+        let scope = capped_scope(req.scope.restrict_to(client.allowed_scopes())?, session);
+        session
+            .set_scope(scope)
+            .set_nonce(req.nonce.clone())
+            .set_resources(resources);
+
+        let secret = self.secrets.generate()?;
+        let code = code::AuthorizationCode::new(secret, session.clone(), Duration::minutes(5));
+        let code_sig = secure::generate_signature(code.to_string().as_str(), &self.secret_key);
+        log::debug!("Storing token with signature {}", code_sig);
+        let code = self
+            .authorization_code_storage
+            .store_code(code_sig.to_string().as_str(), code)
+            .await?;
+        log::debug!("Successfully stored token with signature {}", code_sig);
+
+        let res = AuthorizationResponse::new(code, req.state.clone());
+        Ok(res)
+    }
+}
+
+/// Scope granted to a session, capped by the scope the session was given beforehand if any,
+/// like for users who are only allowed to change their password
+pub(crate) fn capped_scope(granted: Scope, session: &Session) -> Scope {
+    if session.scope().is_empty() {
+        granted
+    } else {
+        granted.intersection(session.scope())
+    }
+}
+
+/// Token requests are dispatched to the handler of their grant type, see [`AuthorizationCodeGrant`],
+/// [`RefreshTokenGrant`], [`ClientCredentialsGrant`] and [`DeviceCodeGrant`]
+#[async_trait]
+impl<CS, ATS, RTS, ACS> RequestHandler<TokenRequest, TokenResponse>
+    for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    /// Authenticates the client, which must be allowed to use the grant type of the request
+    async fn validate(
+        &self,
+        req: &TokenRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<Client> {
+        let auth_client_id = client_auth.map(|BasicAuth(client_id, _client_secret)| client_id);
+        let auth_client_secret =
+            client_auth.and_then(|BasicAuth(_client_id, client_secret)| client_secret.as_ref());
+        let assertion = req.client_assertion();
+        // Clients authenticating with an assertion are identified by it
+        let assertion_client_id = assertion
+            .as_ref()
+            .and_then(|assertion| assertion::subject(&assertion.assertion));
+        let client_id = req
+            .client_id()
+            .or(auth_client_id)
+            .or(assertion_client_id.as_ref());
+
+        if let (Some(grant_type), Some(client_id)) = (req.grant_type(), client_id) {
+            self.validate_grant_type(client_id, &grant_type).await?;
+        }
+
+        // Other grant types are parsed so that their parameters are validated, but not issued by this server yet
+        let supported = req
+            .grant_type()
+            .is_some_and(|grant_type| SUPPORTED_GRANT_TYPES.contains(&grant_type));
+        if !supported {
+            return Err(Error::new(
+                ErrorKind::UnsupportedGrantType,
+                "unsupported grant type".to_string(),
+            ));
+        }
+
+        let client_id = client_id.ok_or_else(|| {
+            Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string())
+        })?;
+        log::debug!("Validating client '{}'", client_id);
+        let client = self
+            .client_storage
+            .get_client(client_id)
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string()))?;
+        let client_secret = req.client_secret().or(auth_client_secret);
+        self.authenticate_token_client(&client, assertion.as_ref(), client_secret)
+            .await?;
+        Ok(client)
+    }
+
+    async fn handle(&self, req: &TokenRequest, session: &mut Session) -> Result<TokenResponse> {
+        let client = self
+            .client_storage
+            .get_client(session.client_id())
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string()))?;
+        let device = session.requester().cloned();
+        match req {
+            TokenRequest::AuthorizationCode(req) => self.exchange_code(&client, req, device).await,
+            TokenRequest::RefreshToken(req) => self.refresh(&client, req, device).await,
+            TokenRequest::ClientCredentials(req) => {
+                self.issue_client_token(&client, req, device).await
+            }
+            TokenRequest::DeviceCode(req) => self.poll_device_code(&client, req, device).await,
+            TokenRequest::Password(_) | TokenRequest::Unknown => Err(Error::new(
+                ErrorKind::UnsupportedGrantType,
+                "unsupported grant type".to_string(),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> RequestHandler<IntrospectionRequest, TokenAccess<IntrospectionResponse>>
+    for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn validate(
+        &self,
+        _req: &IntrospectionRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<Client> {
+        let BasicAuth(client_id, client_secret) = client_auth.ok_or_else(|| {
+            Error::new(
+                ErrorKind::AccessDenied,
+                "introspection requires client credentials".to_string(),
+            )
+        })?;
+        let client = self
+            .client_storage
+            .get_client(client_id)
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string()))?;
+        self.authenticate_client(&client, client_secret.as_ref())
+            .await?;
+        Ok(client)
+    }
+
+    /// Introspects the token on behalf of the caller of the session, which must be the client
+    /// the token was issued to or a resource server, see [`OAuthHandler::is_resource_server`]
+    async fn handle(
+        &self,
+        req: &IntrospectionRequest,
+        session: &mut Session,
+    ) -> Result<TokenAccess<IntrospectionResponse>> {
+        let sig = secure::generate_signature(&req.token, &self.secret_key).to_string();
+        let sig = sig.as_str();
+        let hinted = match &req.token_type_hint {
+            Some(TokenTypeHint::AccessToken) => self
+                .access_token_storage
+                .get_token(sig)
+                .await
+                .map(|token| introspect(&token)),
+            Some(TokenTypeHint::RefreshToken) => self
+                .refresh_token_storage
+                .get_token(sig)
+                .await
+                .map(|token| introspect(&token)),
+            Some(TokenTypeHint::Unknown) | None => None,
+        };
+        let found = match hinted {
+            Some(found) => Some(found),
+            None => match self.access_token_storage.get_token(sig).await {
+                Some(token) => Some(introspect(&token)),
+                None => self
+                    .refresh_token_storage
+                    .get_token(sig)
+                    .await
+                    .map(|token| introspect(&token)),
+            },
+        };
+
+        if let Some((client_id, res)) = found {
+            if session.client_id() != &client_id && !self.is_resource_server(session).await {
+                log::warn!(
+                    "Client '{}' introspected a token of client '{}'",
+                    session.client_id(),
+                    client_id
+                );
+                return Ok(TokenAccess::Denied(IntrospectionResponse::inactive()));
+            }
+            return Ok(TokenAccess::Granted(res));
+        }
+
+        // Both are reported as inactive, as required by RFC 7662
+        if self.access_token_storage.is_revoked(sig).await
+            || self.refresh_token_storage.is_revoked(sig).await
+        {
+            log::debug!("Introspected token was revoked");
+        } else {
+            log::debug!("Introspected token is unknown or expired");
+        }
+        Ok(TokenAccess::Granted(IntrospectionResponse::inactive()))
+    }
+}
+
+/// Client ID the token was issued to, along with its introspection
+fn introspect<T: Token>(token: &T) -> (String, IntrospectionResponse) {
+    (
+        token.session().client_id().clone(),
+        IntrospectionResponse::from_token(token),
+    )
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> RequestHandler<RevocationRequest, TokenAccess<RevocationResponse>>
+    for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn validate(
+        &self,
+        _req: &RevocationRequest,
+        client_auth: Option<&BasicAuth>,
+    ) -> Result<Client> {
+        let BasicAuth(client_id, client_secret) = client_auth.ok_or_else(|| {
+            Error::new(
+                ErrorKind::AccessDenied,
+                "revocation requires client credentials".to_string(),
+            )
+        })?;
+        let client = self
+            .client_storage
+            .get_client(client_id)
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::InvalidClient, "invalid client_id".to_string()))?;
+        self.authenticate_client(&client, client_secret.as_ref())
+            .await?;
+        Ok(client)
+    }
+
+    /// Revokes the token if it was issued to the client of the session.
+    /// Tokens of other clients are left untouched, but answered like unknown ones.
+    async fn handle(
+        &self,
+        req: &RevocationRequest,
+        session: &mut Session,
+    ) -> Result<TokenAccess<RevocationResponse>> {
+        let requester_client_id = session.client_id();
+        let ok = RevocationResponse::ok();
+        let sig = secure::generate_signature(&req.token, &self.secret_key).to_string();
+        let sig = sig.as_str();
+        let denied = |owner: &str| {
+            log::warn!(
+                "Client '{}' tried to revoke a token of client '{}'",
+                requester_client_id,
+                owner
+            );
+            Ok(TokenAccess::Denied(RevocationResponse::ok()))
+        };
+        if let Some(hint) = &req.token_type_hint {
+            if let Some(()) = match hint {
+                TokenTypeHint::AccessToken => {
+                    let access_token = self.access_token_storage.get_token(sig).await;
+                    if let Some(access_token) = access_token {
+                        let session = access_token.session();
+                        if session.client_id() != requester_client_id {
+                            return denied(session.client_id());
+                        }
+                        self.access_token_storage.revoke_token(sig).await?;
+                        self.notify(TokenAction::Revoked, &access_token).await;
+                    }
+                    Some(())
+                }
+                TokenTypeHint::RefreshToken => {
+                    let refresh_token = match self.refresh_token_storage.get_token(sig).await {
+                        Some(token) => token,
+                        None => return Ok(TokenAccess::Granted(ok)),
+                    };
+
+                    let session = refresh_token.session();
+                    if session.client_id() != requester_client_id {
+                        return denied(session.client_id());
+                    }
+                    self.refresh_token_storage.revoke_token(sig).await?;
+                    self.notify(TokenAction::Revoked, &refresh_token).await;
+                    // The access token may have been revoked before the refresh token
+                    self.revoke_related_access_token(&refresh_token).await;
+                    Some(())
+                }
+                TokenTypeHint::Unknown => None,
+            } {
+                return Ok(TokenAccess::Granted(ok));
+            };
+        };
+
+        let access_token = self.access_token_storage.get_token(sig).await;
+        if let Some(access_token) = access_token {
+            let session = access_token.session();
+            if session.client_id() != requester_client_id {
+                return denied(session.client_id());
+            }
+            if self.access_token_storage.revoke_token(sig).await.is_ok() {
+                self.notify(TokenAction::Revoked, &access_token).await;
+            }
+            return Ok(TokenAccess::Granted(ok));
+        }
+
+        if let Some(refresh_token) = self.refresh_token_storage.get_token(sig).await {
+            let session = refresh_token.session();
+            if session.client_id() != requester_client_id {
+                return denied(session.client_id());
+            }
+            self.refresh_token_storage.revoke_token(sig).await?;
+            self.notify(TokenAction::Revoked, &refresh_token).await;
+            self.revoke_related_access_token(&refresh_token).await;
+            return Ok(TokenAccess::Granted(ok));
+        };
+
+        Ok(TokenAccess::Granted(ok))
+    }
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> TokenIntrospectionHandler<AccessToken> for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn get_token(&self, token: &str) -> Result<AccessToken> {
+        let sig = secure::generate_signature(token, &self.secret_key);
+        let token = self
+            .access_token_storage
+            .get_token(sig.to_string().as_str())
+            .await
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidRequest,
+                    "access token not found".to_string(),
+                )
+            })?;
+
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, token: &str) -> Result<()> {
+        let sig = secure::generate_signature(token, &self.secret_key).to_string();
+        let access_token = self.access_token_storage.get_token(&sig).await;
+        self.access_token_storage.revoke_token(&sig).await?;
+        if let Some(access_token) = access_token {
+            self.notify(TokenAction::Revoked, &access_token).await;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> TokenIntrospectionHandler<RefreshToken> for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn get_token(&self, token: &str) -> Result<RefreshToken> {
+        let sig = secure::generate_signature(token, &self.secret_key);
+        let token = self
+            .refresh_token_storage
+            .get_token(sig.to_string().as_str())
+            .await
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidRequest,
+                    "refresh token not found".to_string(),
+                )
+            })?;
+
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, token: &str) -> Result<()> {
+        let sig = secure::generate_signature(token, &self.secret_key).to_string();
+        let refresh_token = self.refresh_token_storage.get_token(&sig).await;
+        self.refresh_token_storage.revoke_token(&sig).await?;
+        if let Some(refresh_token) = refresh_token {
+            self.notify(TokenAction::Revoked, &refresh_token).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::iter::FromIterator;
+    use std::sync::{Arc, Mutex};
+
+    use futures::executor::block_on;
+    use url::Url;
+
+    use crate::memory::MemoryStorage;
+    use crate::request::{ClientCredentialsRequest, RefreshTokenRequest};
+
+    use super::*;
+
+    pub const SECRET_KEY: &str = "0123456789abcdef0123456789abcdef";
+    pub const REDIRECT_URI: &str = "http://localhost:9623/callback";
+
+    pub type MemoryHandler =
+        OAuthHandler<MemoryStorage, MemoryStorage, MemoryStorage, MemoryStorage>;
+
+    /// Handler backed by an in-memory storage, with a public client "test"
+    /// and a confidential client "service" whose secret is "secret"
+    pub fn handler() -> (Arc<MemoryStorage>, MemoryHandler) {
+        let storage = Arc::new(MemoryStorage::new());
+        let redirect_uris = HashSet::from_iter(vec![Url::parse(REDIRECT_URI).unwrap()]);
+        let client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            redirect_uris.clone(),
+        );
+        block_on(storage.save_client(client)).unwrap();
+        let client = Client::confidential(
+            "service".to_string(),
+            "secret".to_string(),
+            Scope::from("profile clients:read"),
+            redirect_uris,
+        )
+        .unwrap();
+        block_on(storage.save_client(client)).unwrap();
+
+        let handler = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            SECRET_KEY.to_string(),
+        );
+        (storage, handler)
+    }
+
+    pub fn client(storage: &MemoryStorage, client_id: &str) -> Client {
+        block_on(storage.get_client(client_id)).unwrap()
+    }
+
+    #[derive(Default)]
+    struct RecordingListener(Mutex<Vec<(TokenAction, TokenTypeHint)>>);
+
+    #[async_trait]
+    impl TokenEventListener for RecordingListener {
+        async fn on_issued(&self, event: &TokenEvent) {
+            assert_eq!(event.action, TokenAction::Issued);
+            self.0.lock().unwrap().push((event.action, event.token_type));
+        }
+
+        async fn on_refreshed(&self, event: &TokenEvent) {
+            assert_eq!(event.action, TokenAction::Refreshed);
+            self.0.lock().unwrap().push((event.action, event.token_type));
+        }
+
+        async fn on_revoked(&self, event: &TokenEvent) {
+            assert_eq!(event.action, TokenAction::Revoked);
+            self.0.lock().unwrap().push((event.action, event.token_type));
+        }
+    }
+
+    #[test]
+    fn it_caps_the_scope_of_sessions_given_one() {
+        let granted = Scope::from("profile clients:read");
+        let session = Session::for_client("test".to_string());
+        assert_eq!(capped_scope(granted.clone(), &session), granted);
+
+        let mut session = Session::for_client("test".to_string());
+        session.set_scope(Scope::from("profile users:read"));
+        assert_eq!(capped_scope(granted, &session), Scope::from("profile"));
+    }
+
+    #[test]
+    fn it_tracks_the_requester_while_keeping_the_login_device() {
+        use crate::device::AuthMethod;
+        use crate::storage::DeviceStorage;
+        use crate::user_agent::UserAgent;
+
+        let (storage, mut handler) = handler();
+        handler.set_device_tracker(Arc::new(DeviceTracker::new(storage.clone())));
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:76.0) Gecko/20100101 Firefox/76.0";
+        let browser = Device::new(UserAgent::parse(firefox), AuthMethod::Password);
+        let cli = Device::new(UserAgent::parse("curl/7.68.0"), AuthMethod::None);
+        let mut session = Session::for_client("test".to_string());
+        session
+            .set_user_id("user:jdoe".to_string())
+            .set_scope(Scope::from("profile"))
+            .set_device(browser.clone())
+            .set_requester(cli.clone());
+        let issued = block_on(handler.generate_token_set(&session)).unwrap();
+
+        let access_token = TokenIntrospectionHandler::<AccessToken>::get_token(
+            &handler,
+            &issued.access_token,
+        );
+        let access_token = block_on(access_token).unwrap();
+        assert_eq!(access_token.session().device(), Some(&browser));
+        assert_eq!(access_token.session().requester(), Some(&cli));
+        // Only the family of the requester was recorded at issuance
+        let is_new = block_on(storage.add_device_family("user:jdoe", &cli.family())).unwrap();
+        assert!(!is_new);
+        let is_new = block_on(storage.add_device_family("user:jdoe", &browser.family())).unwrap();
+        assert!(is_new);
+    }
+
+    #[test]
+    fn it_stamps_sessions_with_the_lifetime_of_their_token() {
+        let (_, handler) = handler();
+        let mut session = Session::for_client("test".to_string());
+        session.set_scope(Scope::from("profile"));
+        let issued = block_on(handler.generate_token_set(&session)).unwrap();
+
+        let access_token = TokenIntrospectionHandler::<AccessToken>::get_token(
+            &handler,
+            &issued.access_token,
+        );
+        let access_token = block_on(access_token).unwrap();
+        let session = access_token.session();
+        let issued_at = *session.issued_at().unwrap();
+        assert_eq!(session.expires_at(), Some(&(issued_at + Duration::minutes(5))));
+
+        let refresh_token = issued.refresh_token.unwrap();
+        let refresh_token =
+            TokenIntrospectionHandler::<RefreshToken>::get_token(&handler, &refresh_token);
+        let refresh_token = block_on(refresh_token).unwrap();
+        let session = refresh_token.session();
+        assert_eq!(session.issued_at(), Some(&issued_at));
+        assert_eq!(session.expires_at(), Some(&(issued_at + Duration::days(1))));
+    }
+
+    #[test]
+    fn it_caps_the_lifetime_of_impersonation_tokens() {
+        let (_, handler) = handler();
+        let mut session = Session::for_client("test".to_string());
+        session
+            .set_scope(Scope::from("profile"))
+            .set_user_id("user:jdoe".to_string());
+        let issued = block_on(handler.issue_impersonation_token(&session, Duration::minutes(5)));
+        assert!(issued.is_err());
+
+        session.set_impersonator(Some("user:admin".to_string()));
+        let issued =
+            block_on(handler.issue_impersonation_token(&session, Duration::hours(1))).unwrap();
+        assert_eq!(issued.refresh_token, None);
+
+        let access_token = TokenIntrospectionHandler::<AccessToken>::get_token(
+            &handler,
+            &issued.access_token,
+        );
+        let access_token = block_on(access_token).unwrap();
+        let session = access_token.session();
+        let issued_at = *session.issued_at().unwrap();
+        assert_eq!(
+            session.expires_at(),
+            Some(&(issued_at + Duration::minutes(MAX_IMPERSONATION_MINUTES)))
+        );
+        let introspection = IntrospectionResponse::active(&access_token);
+        let data = introspection.introspection_data.unwrap();
+        assert_eq!(data.username.as_deref(), Some("user:jdoe"));
+        assert_eq!(data.impersonator.as_deref(), Some("user:admin"));
+    }
+
+    #[test]
+    fn it_notifies_listeners_of_the_token_lifecycle() {
+        let (storage, mut handler) = handler();
+        let listener = Arc::new(RecordingListener::default());
+        handler.add_token_event_listener(listener.clone());
+        let client = client(&storage, "test");
+        let mut session = Session::for_client("test".to_string());
+        session.set_scope(Scope::from("profile"));
+
+        let issued = block_on(handler.generate_token_set(&session)).unwrap();
+        let req = RefreshTokenRequest {
+            refresh_token: issued.refresh_token.unwrap(),
+            scope: None,
+            client_id: None,
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            resource: Vec::new(),
+        };
+        let refreshed = block_on(handler.refresh(&client, &req, None)).unwrap();
+        let req = RevocationRequest {
+            token: refreshed.refresh_token.unwrap(),
+            token_type_hint: None,
+        };
+        block_on(RequestHandler::handle(&handler, &req, &mut session)).unwrap();
+
+        assert_eq!(
+            *listener.0.lock().unwrap(),
+            vec![
+                (TokenAction::Issued, TokenTypeHint::AccessToken),
+                (TokenAction::Issued, TokenTypeHint::RefreshToken),
+                (TokenAction::Refreshed, TokenTypeHint::AccessToken),
+                (TokenAction::Refreshed, TokenTypeHint::RefreshToken),
+                (TokenAction::Revoked, TokenTypeHint::RefreshToken),
+                (TokenAction::Revoked, TokenTypeHint::AccessToken),
+            ]
+        );
+    }
+
+    fn client_credentials_request(
+        client_secret: Option<&str>,
+        client_assertion: Option<&str>,
+    ) -> TokenRequest {
+        TokenRequest::ClientCredentials(ClientCredentialsRequest {
+            scope: None,
+            client_id: Some("service".to_string()),
+            client_secret: client_secret.map(str::to_string),
+            client_assertion_type: client_assertion.map(|_| assertion::JWT_BEARER.to_string()),
+            client_assertion: client_assertion.map(str::to_string),
+            resource: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn it_requires_an_assertion_from_clients_with_keys() {
+        let (storage, mut handler) = handler();
+        let mut client = client(&storage, "service");
+        let jwk = serde_json::from_value(serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": "MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4",
+            "y": "4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM",
+        }))
+        .unwrap();
+        client
+            .set_allowed_grant_types(HashSet::from_iter(vec![GrantType::ClientCredentials]))
+            .unwrap();
+        client.set_jwks(vec![jwk]).unwrap();
+        block_on(storage.save_client(client)).unwrap();
+        let validate = |handler: &MemoryHandler, req: TokenRequest| {
+            block_on(RequestHandler::validate(handler, &req, None)).unwrap_err()
+        };
+
+        let err = validate(&handler, client_credentials_request(Some("secret"), None));
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+        let err = validate(&handler, client_credentials_request(Some("secret"), Some("a.b.c")));
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+        let err = validate(&handler, client_credentials_request(None, Some("a.b.c")));
+        assert_eq!(err.description(), "client assertions are disabled");
+
+        let token_endpoint = Url::parse("https://enseada.example.com/oauth/token").unwrap();
+        handler.set_client_assertions(Arc::new(ClientAssertions::new(token_endpoint)));
+        let err = validate(&handler, client_credentials_request(None, Some("a.b.c")));
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+        assert_ne!(err.description(), "client assertions are disabled");
+    }
+}
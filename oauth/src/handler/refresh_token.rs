@@ -0,0 +1,161 @@
+//! Refresh token grant, rotating a token set
+use async_trait::async_trait;
+
+use enseada::secure;
+
+use crate::client::Client;
+use crate::device::Device;
+use crate::error::{Error, ErrorKind};
+use crate::events::TokenAction;
+use crate::request::RefreshTokenRequest;
+use crate::response::TokenResponse;
+use crate::storage::{AuthorizationCodeStorage, ClientStorage, TokenStorage};
+use crate::token::{AccessToken, RefreshToken, Token};
+use crate::{Expirable, Result};
+
+use super::{access_token_ttl, OAuthHandler};
+
+#[async_trait]
+pub trait RefreshTokenGrant {
+    /// Exchanges a refresh token issued to the client for a new token set,
+    /// revoking the refresh token and the access token issued along with it
+    async fn refresh(
+        &self,
+        client: &Client,
+        req: &RefreshTokenRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse>;
+}
+
+#[async_trait]
+impl<CS, ATS, RTS, ACS> RefreshTokenGrant for OAuthHandler<CS, ATS, RTS, ACS>
+where
+    CS: ClientStorage,
+    ATS: TokenStorage<AccessToken>,
+    RTS: TokenStorage<RefreshToken>,
+    ACS: AuthorizationCodeStorage,
+{
+    async fn refresh(
+        &self,
+        client: &Client,
+        req: &RefreshTokenRequest,
+        device: Option<Device>,
+    ) -> Result<TokenResponse> {
+        let invalid = || Error::new(ErrorKind::InvalidGrant, "invalid refresh token".to_string());
+        let refresh_token_sig =
+            secure::generate_signature(&req.refresh_token, &self.secret_key).to_string();
+        let refresh_token = self
+            .refresh_token_storage
+            .get_token(&refresh_token_sig)
+            .await
+            .ok_or_else(invalid)?;
+
+        if refresh_token.is_expired() {
+            return Err(invalid());
+        }
+
+        let mut session = refresh_token.session().clone();
+        if session.client_id() != client.client_id() {
+            return Err(Error::new(
+                ErrorKind::InvalidClient,
+                "invalid client_id".to_string(),
+            ));
+        }
+
+        if let Some(other) = &req.scope {
+            if !session.scope().is_superset(other) {
+                return Err(Error::new(
+                    ErrorKind::InvalidScope,
+                    "invalid scope".to_string(),
+                ));
+            }
+            session.set_scope(other.clone());
+        }
+        self.validate_scope(client, session.scope())?;
+        let resources = self.narrow_resources(&session, &req.resource)?;
+        session.set_resources(resources);
+
+        if let Some(device) = device {
+            session.set_requester(device);
+        }
+
+        // Issuance may be refused, so the old tokens stay valid until a new set is generated
+        let res = self.generate_tokens(&session, true, access_token_ttl(), TokenAction::Refreshed).await?;
+        // We revoke it because we generated a new one
+        self.refresh_token_storage.revoke_token(&refresh_token_sig).await?;
+        // We don't care if the revocation fails, since the access token may have been revoked before the refresh token.
+        self.access_token_storage
+            .revoke_token(refresh_token.related_access_token_signature())
+            .await
+            .ok();
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use crate::scope::Scope;
+    use crate::session::Session;
+
+    use super::super::test::{client, handler};
+    use super::*;
+
+    fn request(refresh_token: String, scope: Option<&str>) -> RefreshTokenRequest {
+        RefreshTokenRequest {
+            refresh_token,
+            scope: scope.map(Scope::from),
+            client_id: None,
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            resource: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn it_rotates_the_token_set() {
+        let (storage, handler) = handler();
+        let client = client(&storage, "test");
+        let mut session = Session::for_client("test".to_string());
+        session.set_scope(Scope::from("profile"));
+        let issued = block_on(handler.generate_token_set(&session)).unwrap();
+
+        let req = request(issued.refresh_token.unwrap(), None);
+        let res = block_on(handler.refresh(&client, &req, None)).unwrap();
+        assert_ne!(res.access_token, issued.access_token);
+        let err = block_on(handler.refresh(&client, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidGrant);
+    }
+
+    #[test]
+    fn it_narrows_but_never_widens_the_scope() {
+        let (storage, handler) = handler();
+        let client = client(&storage, "service");
+        let mut session = Session::for_client("service".to_string());
+        session.set_scope(Scope::from("profile clients:read"));
+        let issued = block_on(handler.generate_token_set(&session)).unwrap();
+        let refresh_token = issued.refresh_token.unwrap();
+
+        let req = request(refresh_token.clone(), Some("profile admin"));
+        let err = block_on(handler.refresh(&client, &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+
+        let req = request(refresh_token, Some("profile"));
+        let res = block_on(handler.refresh(&client, &req, None)).unwrap();
+        assert_eq!(res.scope, Scope::from("profile"));
+    }
+
+    #[test]
+    fn it_only_refreshes_tokens_issued_to_the_client() {
+        let (storage, handler) = handler();
+        let mut session = Session::for_client("test".to_string());
+        session.set_scope(Scope::from("profile"));
+        let issued = block_on(handler.generate_token_set(&session)).unwrap();
+
+        let req = request(issued.refresh_token.unwrap(), None);
+        let err = block_on(handler.refresh(&client(&storage, "service"), &req, None)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidClient);
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::client::Client;
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+
+/// Global settings for detecting bursts of token issuance.
+/// Clients can override the threshold with their own.
+#[derive(Clone, Debug)]
+pub struct IssuanceLimits {
+    /// Maximum number of token sets issued to a client within a window
+    pub threshold: u32,
+    pub window: Duration,
+    /// Refuse issuance with `temporarily_unavailable` while a client is over its threshold
+    pub refuse: bool,
+}
+
+impl Default for IssuanceLimits {
+    fn default() -> Self {
+        IssuanceLimits {
+            threshold: 600,
+            window: Duration::from_secs(60),
+            refuse: false,
+        }
+    }
+}
+
+/// Emitted once when a client exceeds its issuance threshold,
+/// and not again until its rate has dropped below it.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct IssuanceAnomaly {
+    pub client_id: String,
+    pub rate: u32,
+    pub threshold: u32,
+    pub window_seconds: u64,
+}
+
+pub trait AnomalyListener: Send + Sync {
+    fn on_anomaly(&self, anomaly: &IssuanceAnomaly);
+}
+
+/// Tracks a rolling per-client token issuance rate.
+/// The rate is approximated with two fixed windows, weighting the previous one
+/// by how much of it still overlaps the sliding window, so tracking is O(1) per client.
+pub struct IssuanceMonitor {
+    limits: IssuanceLimits,
+    windows: Mutex<HashMap<String, Window>>,
+    anomalies: AtomicU64,
+    listeners: Vec<Arc<dyn AnomalyListener>>,
+}
+
+struct Window {
+    start: Instant,
+    current: u32,
+    previous: u32,
+    alerting: bool,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Window {
+            start: now,
+            current: 0,
+            previous: 0,
+            alerting: false,
+        }
+    }
+
+    fn roll(&mut self, now: Instant, size: Duration) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= size * 2 {
+            self.start = now;
+            self.previous = 0;
+            self.current = 0;
+        } else if elapsed >= size {
+            self.start += size;
+            self.previous = self.current;
+            self.current = 0;
+        }
+    }
+
+    fn rate(&self, now: Instant, size: Duration) -> u32 {
+        let elapsed = now.saturating_duration_since(self.start).as_secs_f64();
+        let overlap = 1.0 - (elapsed / size.as_secs_f64()).min(1.0);
+        self.current + (f64::from(self.previous) * overlap) as u32
+    }
+}
+
+impl IssuanceMonitor {
+    pub fn new(limits: IssuanceLimits) -> Self {
+        IssuanceMonitor {
+            limits,
+            windows: Mutex::new(HashMap::new()),
+            anomalies: AtomicU64::new(0),
+            listeners: Vec::new(),
+        }
+    }
+
+    pub fn add_listener(&mut self, listener: Arc<dyn AnomalyListener>) -> &mut Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Value of the `oauth_token_issuance_anomaly` counter
+    pub fn anomalies(&self) -> u64 {
+        self.anomalies.load(Ordering::Relaxed)
+    }
+
+    /// Records a token issuance for the client.
+    /// Fails with `temporarily_unavailable` if the client is over its threshold
+    /// and refusal is enabled, in which case the issuance is not recorded.
+    pub fn track(&self, client: &Client) -> Result<()> {
+        self.track_at(client, Instant::now())
+    }
+
+    fn track_at(&self, client: &Client, now: Instant) -> Result<()> {
+        let threshold = client
+            .token_issuance_threshold()
+            .unwrap_or(self.limits.threshold);
+        let size = self.limits.window;
+
+        let (anomaly, refused) = {
+            let mut windows = self.windows.lock().unwrap();
+            let window = windows
+                .entry(client.client_id().to_string())
+                .or_insert_with(|| Window::new(now));
+            window.roll(now, size);
+
+            let rate = window.rate(now, size);
+            if rate < threshold {
+                window.alerting = false;
+            }
+
+            let anomaly = if rate >= threshold && !window.alerting {
+                window.alerting = true;
+                Some(IssuanceAnomaly {
+                    client_id: client.client_id().to_string(),
+                    rate: rate + 1,
+                    threshold,
+                    window_seconds: size.as_secs(),
+                })
+            } else {
+                None
+            };
+
+            let refused = rate >= threshold && self.limits.refuse;
+            if !refused {
+                window.current += 1;
+            }
+            (anomaly, refused)
+        };
+
+        if let Some(anomaly) = anomaly {
+            self.anomalies.fetch_add(1, Ordering::Relaxed);
+            for listener in &self.listeners {
+                listener.on_anomaly(&anomaly);
+            }
+        }
+
+        if refused {
+            return Err(Error::new(
+                ErrorKind::TemporarilyUnavailable,
+                "token issuance rate exceeded, retry later".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use crate::client::Client;
+    use crate::error::ErrorKind;
+    use crate::scope::Scope;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<IssuanceAnomaly>>);
+
+    impl AnomalyListener for Recorder {
+        fn on_anomaly(&self, anomaly: &IssuanceAnomaly) {
+            self.0.lock().unwrap().push(anomaly.clone());
+        }
+    }
+
+    fn client(threshold: Option<u32>) -> Client {
+        let mut client = Client::public("ci".to_string(), Scope::from("*"), HashSet::new());
+        client.set_token_issuance_threshold(threshold);
+        client
+    }
+
+    fn monitor(refuse: bool) -> (IssuanceMonitor, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        let mut monitor = IssuanceMonitor::new(IssuanceLimits {
+            threshold: 100,
+            window: Duration::from_secs(60),
+            refuse,
+        });
+        monitor.add_listener(recorder.clone());
+        (monitor, recorder)
+    }
+
+    #[test]
+    fn it_refuses_a_burst_and_recovers_after_the_window() {
+        let (monitor, recorder) = monitor(true);
+        let client = client(Some(10));
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            monitor.track_at(&client, start).unwrap();
+        }
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        let err = monitor.track_at(&client, start).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::TemporarilyUnavailable);
+        assert_eq!(recorder.0.lock().unwrap().len(), 1);
+        assert_eq!(monitor.anomalies(), 1);
+
+        let later = start + Duration::from_secs(30);
+        assert!(monitor.track_at(&client, later).is_err());
+        assert_eq!(monitor.anomalies(), 1);
+
+        let recovered = start + Duration::from_secs(121);
+        monitor.track_at(&client, recovered).unwrap();
+    }
+
+    #[test]
+    fn it_emits_a_single_event_per_burst() {
+        let (monitor, recorder) = monitor(false);
+        let client = client(Some(10));
+        let start = Instant::now();
+
+        for _ in 0..50 {
+            monitor.track_at(&client, start).unwrap();
+        }
+        assert_eq!(monitor.anomalies(), 1);
+        let events = recorder.0.lock().unwrap().clone();
+        assert_eq!(
+            events,
+            vec![IssuanceAnomaly {
+                client_id: "ci".to_string(),
+                rate: 11,
+                threshold: 10,
+                window_seconds: 60,
+            }]
+        );
+
+        let recovered = start + Duration::from_secs(121);
+        for _ in 0..11 {
+            monitor.track_at(&client, recovered).unwrap();
+        }
+        assert_eq!(monitor.anomalies(), 2);
+    }
+
+    #[test]
+    fn it_falls_back_to_the_global_threshold() {
+        let (monitor, _) = monitor(true);
+        let client = client(None);
+        let start = Instant::now();
+
+        for _ in 0..100 {
+            monitor.track_at(&client, start).unwrap();
+        }
+        assert!(monitor.track_at(&client, start).is_err());
+    }
+
+    #[test]
+    fn it_weights_the_previous_window() {
+        let (monitor, _) = monitor(true);
+        let client = client(Some(10));
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            monitor.track_at(&client, start).unwrap();
+        }
+        // Halfway into the next window, half of the previous burst still counts
+        let halfway = start + Duration::from_secs(90);
+        for _ in 0..5 {
+            monitor.track_at(&client, halfway).unwrap();
+        }
+        assert!(monitor.track_at(&client, halfway).is_err());
+    }
+}
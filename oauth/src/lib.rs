@@ -0,0 +1,58 @@
+//! OAuth 2.0 authorization server used by Enseada.
+//!
+//! The [`handler::OAuthHandler`] implements the authorization code, refresh token,
+//! client credentials, introspection and revocation flows on top of the traits in [`storage`],
+//! each grant behind its own trait like [`handler::AuthorizationCodeGrant`], as well as
+//! the exchange of [`bootstrap`] tokens, [pushed authorization requests](par)
+//! and the [device authorization grant](device_code),
+//! and is exposed to routes through the object-safe [`facade::Oauth`] trait.
+//! Requested scopes are checked against a [`registry::ScopeRegistry`], when one is set.
+//! Access tokens are opaque unless a [`signing::TokenSigner`] is set to issue them as JWTs.
+//! [`memory::MemoryStorage`] implements every storage trait in memory,
+//! while [`routes`] provides the token endpoints for actix-web applications,
+//! reporting security relevant events to an [`audit::AuditSink`].
+//! Clients can opt into [`binding`] their tokens to the origin they were issued to.
+//! Token [`events`] can be shipped to external systems through listeners.
+//! Codes and tokens are made of random [`secrets`].
+//! Confidential clients can authenticate with a signed [`assertion`] instead of a secret.
+//! Tokens can be restricted to the [`resource`] servers they are meant for.
+use chrono::{DateTime, Utc};
+
+use crate::error::Error;
+
+pub mod assertion;
+pub mod audit;
+pub mod binding;
+pub mod bootstrap;
+pub mod client;
+pub mod code;
+pub mod device;
+pub mod device_code;
+pub mod error;
+pub mod events;
+pub mod facade;
+pub mod handler;
+pub mod issuance;
+pub mod memory;
+pub mod par;
+pub mod registry;
+pub mod request;
+pub mod resource;
+pub mod response;
+pub mod routes;
+pub mod scope;
+pub mod secrets;
+pub mod session;
+pub mod signing;
+pub mod storage;
+pub mod token;
+pub mod user_agent;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Something that is only valid until a point in time, like tokens and authorization codes
+pub trait Expirable {
+    fn expiration(&self) -> &DateTime<Utc>;
+    fn expires_in(&self) -> i64;
+    fn is_expired(&self) -> bool;
+}
@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use enseada::pagination::{Cursor, Page};
+
+use crate::bootstrap::BootstrapToken;
+use crate::client::{Client, ClientFilter, ClientStats};
+use crate::code::AuthorizationCode;
+use crate::device_code::DeviceAuthorization;
+use crate::error::{Error, ErrorKind};
+use crate::par::PushedRequest;
+use crate::storage::{
+    AuthorizationCodeStorage, BootstrapTokenStorage, ClientStorage, DeviceAuthorizationStorage,
+    DeviceStorage, PushedRequestStorage, TokenStorage,
+};
+use crate::token::{AccessToken, RefreshToken, Token};
+use crate::{Expirable, Result};
+
+/// Storage keeping everything in memory, implementing all the storage traits.
+/// Useful for tests and for embedding the provider without a database,
+/// as nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    clients: RwLock<BTreeMap<String, Client>>,
+    access_tokens: RwLock<HashMap<String, AccessToken>>,
+    refresh_tokens: RwLock<HashMap<String, RefreshToken>>,
+    codes: RwLock<HashMap<String, AuthorizationCode>>,
+    bootstrap_tokens: RwLock<HashMap<String, BootstrapToken>>,
+    pushed_requests: RwLock<HashMap<String, PushedRequest>>,
+    device_authorizations: RwLock<HashMap<String, DeviceAuthorization>>,
+    devices: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn matches(client: &Client, filter: &ClientFilter) -> bool {
+    filter
+        .kind
+        .as_ref()
+        .is_none_or(|kind| *kind == client.kind().to_string())
+        && filter
+            .trusted
+            .is_none_or(|trusted| trusted == client.is_trusted())
+        && filter
+            .labels
+            .iter()
+            .all(|(key, value)| client.labels().get(key) == Some(value))
+        && filter
+            .owner
+            .as_deref()
+            .is_none_or(|owner| client.owner() == Some(owner))
+}
+
+#[async_trait]
+impl ClientStorage for MemoryStorage {
+    async fn list_clients(
+        &self,
+        filter: &ClientFilter,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<Client>> {
+        let start = cursor.map(Cursor::to_string).unwrap_or_default();
+        let clients = self.clients.read().unwrap();
+        let total = clients
+            .values()
+            .filter(|client| matches(client, filter))
+            .count();
+        let mut items: Vec<Client> = clients
+            .range(start..)
+            .map(|(_, client)| client)
+            .filter(|client| matches(client, filter))
+            .take(limit + 1)
+            .cloned()
+            .collect();
+        let next_cursor = if items.len() > limit {
+            items
+                .pop()
+                .map(|client| Cursor::from(client.client_id().to_string()))
+        } else {
+            None
+        };
+        let mut page = Page::from_slice(items, limit, next_cursor);
+        page.set_total(total);
+        Ok(page)
+    }
+
+    async fn get_client(&self, id: &str) -> Option<Client> {
+        let clients = self.clients.read().unwrap();
+        clients.get(id).cloned()
+    }
+
+    async fn save_client(&self, client: Client) -> Result<Client> {
+        let mut clients = self.clients.write().unwrap();
+        clients.insert(client.client_id().to_string(), client.clone());
+        Ok(client)
+    }
+
+    async fn delete_client(&self, client: &Client) -> Result<()> {
+        let mut clients = self.clients.write().unwrap();
+        clients.remove(client.client_id()).map(|_| ()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidClient,
+                format!("client '{}' not found", client.client_id()),
+            )
+        })
+    }
+
+    async fn client_stats(&self, client_id: &str) -> Result<ClientStats> {
+        let tokens = self.access_tokens.read().unwrap();
+        let mut stats = ClientStats::default();
+        for token in tokens.values() {
+            if token.session().client_id() == client_id && !token.is_expired() {
+                stats.active_tokens += 1;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[async_trait]
+impl TokenStorage<AccessToken> for MemoryStorage {
+    async fn get_token(&self, sig: &str) -> Option<AccessToken> {
+        let tokens = self.access_tokens.read().unwrap();
+        tokens.get(sig).cloned()
+    }
+
+    async fn store_token(&self, sig: &str, token: AccessToken) -> Result<AccessToken> {
+        let mut tokens = self.access_tokens.write().unwrap();
+        tokens.insert(sig.to_string(), token.clone());
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, sig: &str) -> Result<()> {
+        let mut tokens = self.access_tokens.write().unwrap();
+        tokens.remove(sig).map(|_| ()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidRequest,
+                "invalid access token".to_string(),
+            )
+        })
+    }
+
+    /// Revoked tokens are removed, so they are indistinguishable from unknown ones
+    async fn is_revoked(&self, _sig: &str) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl TokenStorage<RefreshToken> for MemoryStorage {
+    async fn get_token(&self, sig: &str) -> Option<RefreshToken> {
+        let tokens = self.refresh_tokens.read().unwrap();
+        tokens.get(sig).cloned()
+    }
+
+    async fn store_token(&self, sig: &str, token: RefreshToken) -> Result<RefreshToken> {
+        let mut tokens = self.refresh_tokens.write().unwrap();
+        tokens.insert(sig.to_string(), token.clone());
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, sig: &str) -> Result<()> {
+        let mut tokens = self.refresh_tokens.write().unwrap();
+        tokens.remove(sig).map(|_| ()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidRequest,
+                "invalid refresh token".to_string(),
+            )
+        })
+    }
+
+    /// Revoked tokens are removed, so they are indistinguishable from unknown ones
+    async fn is_revoked(&self, _sig: &str) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl AuthorizationCodeStorage for MemoryStorage {
+    async fn get_code(&self, sig: &str) -> Option<AuthorizationCode> {
+        let codes = self.codes.read().unwrap();
+        codes.get(sig).cloned()
+    }
+
+    async fn store_code(&self, sig: &str, code: AuthorizationCode) -> Result<AuthorizationCode> {
+        let mut codes = self.codes.write().unwrap();
+        codes.insert(sig.to_string(), code.clone());
+        Ok(code)
+    }
+
+    async fn revoke_code(&self, sig: &str) -> Result<()> {
+        let mut codes = self.codes.write().unwrap();
+        codes.remove(sig).map(|_| ()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidRequest,
+                "invalid authorization code".to_string(),
+            )
+        })
+    }
+}
+
+#[async_trait]
+impl BootstrapTokenStorage for MemoryStorage {
+    async fn get_bootstrap_token(&self, sig: &str) -> Option<BootstrapToken> {
+        let tokens = self.bootstrap_tokens.read().unwrap();
+        tokens.get(sig).cloned()
+    }
+
+    async fn store_bootstrap_token(
+        &self,
+        sig: &str,
+        token: BootstrapToken,
+    ) -> Result<BootstrapToken> {
+        let mut tokens = self.bootstrap_tokens.write().unwrap();
+        tokens.insert(sig.to_string(), token.clone());
+        Ok(token)
+    }
+
+    async fn take_bootstrap_token(&self, sig: &str) -> Result<bool> {
+        let mut tokens = self.bootstrap_tokens.write().unwrap();
+        Ok(tokens.remove(sig).is_some())
+    }
+}
+
+#[async_trait]
+impl PushedRequestStorage for MemoryStorage {
+    async fn get_pushed_request(&self, sig: &str) -> Option<PushedRequest> {
+        let requests = self.pushed_requests.read().unwrap();
+        requests.get(sig).cloned()
+    }
+
+    async fn store_pushed_request(&self, sig: &str, req: PushedRequest) -> Result<PushedRequest> {
+        let mut requests = self.pushed_requests.write().unwrap();
+        requests.insert(sig.to_string(), req.clone());
+        Ok(req)
+    }
+
+    async fn take_pushed_request(&self, sig: &str) -> Result<bool> {
+        let mut requests = self.pushed_requests.write().unwrap();
+        Ok(requests.remove(sig).is_some())
+    }
+}
+
+#[async_trait]
+impl DeviceAuthorizationStorage for MemoryStorage {
+    async fn get_device_authorization(&self, sig: &str) -> Option<DeviceAuthorization> {
+        let authorizations = self.device_authorizations.read().unwrap();
+        authorizations.get(sig).cloned()
+    }
+
+    async fn find_device_authorization(
+        &self,
+        user_code: &str,
+    ) -> Option<(String, DeviceAuthorization)> {
+        let authorizations = self.device_authorizations.read().unwrap();
+        authorizations
+            .iter()
+            .find(|(_, authorization)| authorization.user_code() == user_code)
+            .map(|(sig, authorization)| (sig.clone(), authorization.clone()))
+    }
+
+    /// Revisions are counters, bumped on every update
+    async fn store_device_authorization(
+        &self,
+        sig: &str,
+        mut authorization: DeviceAuthorization,
+    ) -> Result<DeviceAuthorization> {
+        let mut authorizations = self.device_authorizations.write().unwrap();
+        let current = authorizations
+            .get(sig)
+            .and_then(|stored| stored.revision().map(ToString::to_string));
+        if current.as_deref() != authorization.revision() {
+            return Err(Error::new(
+                ErrorKind::ServerError,
+                "device authorization was updated concurrently".to_string(),
+            ));
+        }
+        let next = current.map_or(1, |rev| rev.parse::<u64>().unwrap_or_default() + 1);
+        authorization.set_revision(Some(next.to_string()));
+        authorizations.insert(sig.to_string(), authorization.clone());
+        Ok(authorization)
+    }
+
+    async fn take_device_authorization(&self, sig: &str) -> Result<bool> {
+        let mut authorizations = self.device_authorizations.write().unwrap();
+        Ok(authorizations.remove(sig).is_some())
+    }
+}
+
+#[async_trait]
+impl DeviceStorage for MemoryStorage {
+    async fn add_device_family(&self, user_id: &str, family: &str) -> Result<bool> {
+        let mut devices = self.devices.write().unwrap();
+        Ok(devices
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(family.to_string()))
+    }
+}
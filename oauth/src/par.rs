@@ -0,0 +1,59 @@
+//! Pushed authorization requests (RFC 9126). Confidential clients submit the parameters of an
+//! authorization request to the server beforehand and send the user to the authorization endpoint
+//! with a short-lived `request_uri` referencing them instead, keeping the parameters out of
+//! browser history and referrer headers. A request URI can only be used once, by the client
+//! that pushed it.
+use std::ops::Add;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::request::AuthorizationRequest;
+use crate::Expirable;
+
+/// Prefix of the request URIs referencing pushed requests
+pub const REQUEST_URI_PREFIX: &str = "urn:ietf:params:oauth:request_uri:";
+
+/// How long a pushed request can be used for
+pub fn lifetime() -> Duration {
+    Duration::seconds(90)
+}
+
+#[derive(Debug, Clone)]
+pub struct PushedRequest {
+    request: AuthorizationRequest,
+    expiration: DateTime<Utc>,
+}
+
+impl PushedRequest {
+    pub fn new(request: AuthorizationRequest, expires_in: Duration) -> PushedRequest {
+        PushedRequest {
+            request,
+            expiration: Utc::now().add(expires_in),
+        }
+    }
+
+    pub fn request(&self) -> &AuthorizationRequest {
+        &self.request
+    }
+
+    /// Client that pushed the request, the only one allowed to use it
+    pub fn client_id(&self) -> &str {
+        &self.request.client_id
+    }
+}
+
+impl Expirable for PushedRequest {
+    fn expiration(&self) -> &DateTime<Utc> {
+        &self.expiration
+    }
+
+    fn expires_in(&self) -> i64 {
+        self.expiration
+            .signed_duration_since(Utc::now())
+            .num_seconds()
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expiration.lt(&Utc::now())
+    }
+}
@@ -0,0 +1,107 @@
+//! Registry of the scopes known to the server, with the human readable descriptions
+//! shown to users when they are asked to grant them.
+use std::collections::BTreeMap;
+
+use crate::error::{Error, ErrorKind};
+use crate::scope::Scope;
+use crate::Result;
+
+#[derive(Clone, Debug, Default)]
+pub struct ScopeRegistry {
+    scopes: BTreeMap<String, String>,
+}
+
+impl ScopeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a scope, replacing the description of an already registered one
+    pub fn register(&mut self, name: &str, description: &str) -> &mut Self {
+        self.scopes
+            .insert(name.to_string(), description.to_string());
+        self
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.scopes.contains_key(name)
+    }
+
+    pub fn description(&self, name: &str) -> Option<&str> {
+        self.scopes.get(name).map(String::as_str)
+    }
+
+    /// Registered scopes and their descriptions, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.scopes
+            .iter()
+            .map(|(name, description)| (name.as_str(), description.as_str()))
+    }
+
+    /// Returns true if the entry is registered, or grants registered scopes like `users:*`
+    pub fn is_known(&self, name: &str) -> bool {
+        let entry = Scope::from(name);
+        self.is_registered(name) || self.scopes.keys().any(|scope| entry.grants(scope))
+    }
+
+    /// Rejects a scope with entries that are not known with an InvalidScope error
+    /// listing the offending entries. The full scope is always known.
+    pub fn validate(&self, scope: &Scope) -> Result<()> {
+        let mut unknown: Vec<&str> = scope.iter().filter(|name| !self.is_known(name)).collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+
+        unknown.sort();
+        Err(Error::new(
+            ErrorKind::InvalidScope,
+            format!("unknown scope: {}", unknown.join(" ")),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::ErrorKind;
+    use crate::scope::Scope;
+
+    use super::ScopeRegistry;
+
+    fn registry() -> ScopeRegistry {
+        let mut registry = ScopeRegistry::new();
+        registry
+            .register("profile", "Read your profile")
+            .register("users:read", "Read registered users");
+        registry
+    }
+
+    #[test]
+    fn it_accepts_registered_scopes() {
+        let registry = registry();
+        assert!(registry
+            .validate(&Scope::from("profile users:read"))
+            .is_ok());
+        assert!(registry.validate(&Scope::from("")).is_ok());
+        assert!(registry.validate(&Scope::from("*")).is_ok());
+        assert!(registry.validate(&Scope::from("users users:*")).is_ok());
+        assert!(registry.validate(&Scope::from("clients:*")).is_err());
+        assert_eq!(registry.description("profile"), Some("Read your profile"));
+    }
+
+    #[test]
+    fn it_lists_the_unknown_scope_entries() {
+        let err = registry()
+            .validate(&Scope::from("profile pkgs:write admin"))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+        assert_eq!(err.description(), "unknown scope: admin pkgs:write");
+    }
+
+    #[test]
+    fn it_lists_registered_scopes_by_name() {
+        let mut registry = registry();
+        registry.register("clients:read", "Read registered clients");
+        let names: Vec<&str> = registry.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["clients:read", "profile", "users:read"]);
+    }
+}
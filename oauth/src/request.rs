@@ -0,0 +1,727 @@
+use std::fmt::{self, Debug, Formatter};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::de::Error as _;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::assertion::ClientAssertion;
+use crate::device_code;
+use crate::error::{Error, ErrorKind};
+use crate::resource;
+use crate::scope::Scope;
+use crate::token::TokenTypeHint;
+
+/// Largest `max_age` a chrono `Duration` can hold, in seconds
+const MAX_AGE_SECONDS: u64 = i64::MAX as u64 / 1000;
+
+/// Serializes like it is deserialized, so that pushed requests can be stored and read back
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuthorizationRequest {
+    pub response_type: ResponseType,
+    pub client_id: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: Scope,
+    #[serde(
+        default,
+        deserialize_with = "non_empty",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub state: Option<String>,
+    /// OpenID Connect nonce, bound to the issued authorization code
+    #[serde(
+        default,
+        deserialize_with = "non_empty",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub nonce: Option<String>,
+    /// Kept raw so that unsupported modes can be reported as `invalid_request` to the client
+    #[serde(
+        default,
+        deserialize_with = "non_empty",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub response_mode: Option<String>,
+    /// Space-delimited OpenID Connect prompt values, kept raw like `response_mode`
+    #[serde(
+        default,
+        deserialize_with = "non_empty",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub prompt: Option<String>,
+    /// Maximum age in seconds of the user authentication, older sessions must log in again
+    #[serde(
+        default,
+        deserialize_with = "non_empty_seconds",
+        serialize_with = "seconds",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_age: Option<u64>,
+    /// Resource servers the tokens are meant for, see [`crate::resource`]
+    #[serde(
+        default,
+        deserialize_with = "resource::deserialize",
+        serialize_with = "resource::serialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub resource: Vec<String>,
+}
+
+impl AuthorizationRequest {
+    /// How the authorization response is returned to the client, `query` if not given
+    pub fn response_mode(&self) -> crate::Result<ResponseMode> {
+        match self.response_mode.as_deref() {
+            None | Some("query") => Ok(ResponseMode::Query),
+            Some("form_post") => Ok(ResponseMode::FormPost),
+            Some(mode) => Err(Error::new(
+                ErrorKind::InvalidRequest,
+                format!("unsupported response_mode '{}'", mode),
+            )),
+        }
+    }
+
+    /// Prompt values requested by the client, none if not given
+    pub fn prompt(&self) -> crate::Result<Vec<Prompt>> {
+        let prompts = match &self.prompt {
+            Some(prompt) => prompt
+                .split_whitespace()
+                .map(Prompt::parse)
+                .collect::<crate::Result<Vec<Prompt>>>()?,
+            None => Vec::new(),
+        };
+        if prompts.contains(&Prompt::None) && prompts.len() > 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidRequest,
+                "prompt 'none' cannot be combined with other values".to_string(),
+            ));
+        }
+        Ok(prompts)
+    }
+
+    /// Whether a user authenticated at the given time can be authorized without logging in again.
+    /// An unknown authentication time is only accepted without `max_age`, and `max_age=0` accepts none.
+    /// Neither do `prompt=login` and `prompt=select_account`, which always ask the user to log in.
+    pub fn accepts_auth_time(&self, auth_time: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        if self.forces_login() {
+            return false;
+        }
+        match (self.max_age, auth_time) {
+            (None, _) => true,
+            (Some(0), _) | (Some(_), None) => false,
+            (Some(max_age), Some(auth_time)) => {
+                // Longer durations overflow, and no session is that old anyway
+                let max_age = max_age.min(MAX_AGE_SECONDS) as i64;
+                now.signed_duration_since(auth_time) <= Duration::seconds(max_age)
+            }
+        }
+    }
+
+    /// Whether the client asked not to interact with the user at all, with `prompt=none`
+    pub fn is_silent(&self) -> bool {
+        self.prompt()
+            .map(|prompts| prompts.contains(&Prompt::None))
+            .unwrap_or(false)
+    }
+
+    /// Whether the client asked for the user to log in even if they are signed in
+    fn forces_login(&self) -> bool {
+        self.prompt()
+            .map(|prompts| {
+                prompts.contains(&Prompt::Login) || prompts.contains(&Prompt::SelectAccount)
+            })
+            .unwrap_or(false)
+    }
+}
+
+// The login form always submits optional fields like state and nonce, which are empty when the client didn't send them
+fn non_empty<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.filter(|value| !value.is_empty()))
+}
+
+// Numbers are parsed from strings, as flattened fields like in the login form are always deserialized as such
+fn non_empty_seconds<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match non_empty(deserializer)? {
+        Some(value) => value.parse().map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+// Serialized as a string, to be read back by `non_empty_seconds`
+fn seconds<S>(seconds: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match seconds {
+        Some(seconds) => serializer.serialize_str(&seconds.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseType {
+    Code,
+}
+
+impl Debug for ResponseType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match serde_json::to_string(self) {
+            Ok(s) => s.fmt(f),
+            Err(_) => Err(fmt::Error),
+        }
+    }
+}
+
+impl From<String> for ResponseType {
+    fn from(_typ: String) -> Self {
+        ResponseType::Code
+    }
+}
+
+impl fmt::Display for ResponseType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseType::Code => write!(f, "code"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseMode {
+    /// Redirect with the response in the query string
+    Query,
+    /// Post the response to the redirect URI from an auto-submitting form
+    FormPost,
+}
+
+impl fmt::Display for ResponseMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseMode::Query => write!(f, "query"),
+            ResponseMode::FormPost => write!(f, "form_post"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prompt {
+    /// Never show any UI, failing with `login_required` if the user is not signed in
+    None,
+    /// Ask the user to log in again, even if they are signed in
+    Login,
+    /// Let the user log in with another account, which the login form does
+    SelectAccount,
+}
+
+impl Prompt {
+    /// Users are never asked for consent, so `consent` is unsupported like unknown values
+    fn parse(value: &str) -> crate::Result<Prompt> {
+        match value {
+            "none" => Ok(Prompt::None),
+            "login" => Ok(Prompt::Login),
+            "select_account" => Ok(Prompt::SelectAccount),
+            value => Err(Error::new(
+                ErrorKind::InvalidRequest,
+                format!("unsupported prompt '{}'", value),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantType {
+    AuthorizationCode,
+    RefreshToken,
+    ClientCredentials,
+    Password,
+    /// Device authorization grant, see [`crate::device_code`]
+    #[serde(rename = "urn:ietf:params:oauth:grant-type:device_code")]
+    DeviceCode,
+}
+
+impl fmt::Display for GrantType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            GrantType::AuthorizationCode => "authorization_code",
+            GrantType::RefreshToken => "refresh_token",
+            GrantType::ClientCredentials => "client_credentials",
+            GrantType::Password => "password",
+            GrantType::DeviceCode => device_code::GRANT_TYPE,
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Token request, with the parameters of its grant type
+#[derive(Debug, Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+pub enum TokenRequest {
+    AuthorizationCode(AuthorizationCodeRequest),
+    RefreshToken(RefreshTokenRequest),
+    ClientCredentials(ClientCredentialsRequest),
+    Password(PasswordRequest),
+    #[serde(rename = "urn:ietf:params:oauth:grant-type:device_code")]
+    DeviceCode(DeviceCodeRequest),
+    /// Any grant type we don't know, reported as `unsupported_grant_type` rather than a parse error
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizationCodeRequest {
+    pub code: String,
+    pub redirect_uri: String,
+    /// PKCE is not supported yet, so it is accepted and ignored
+    pub code_verifier: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+    /// Must match the nonce of the authorization request, if given
+    pub nonce: Option<String>,
+    /// Narrows the resources of the issued tokens, which can't be broader than the authorized ones
+    #[serde(default, deserialize_with = "resource::deserialize")]
+    pub resource: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+    /// Narrows the scope of the refreshed tokens, which can't be broader than the original
+    pub scope: Option<Scope>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+    /// Narrows the resources of the refreshed tokens, like the scope
+    #[serde(default, deserialize_with = "resource::deserialize")]
+    pub resource: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientCredentialsRequest {
+    pub scope: Option<Scope>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+    #[serde(default, deserialize_with = "resource::deserialize")]
+    pub resource: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordRequest {
+    pub username: String,
+    pub password: String,
+    /// Code of an authenticator app, or a recovery code, for users with two-factor authentication
+    pub otp: Option<String>,
+    pub scope: Option<Scope>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+    #[serde(default, deserialize_with = "resource::deserialize")]
+    pub resource: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceCodeRequest {
+    pub device_code: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+    #[serde(default, deserialize_with = "resource::deserialize")]
+    pub resource: Vec<String>,
+}
+
+impl TokenRequest {
+    /// Parses a token request from its form parameters, naming the missing or invalid parameter
+    /// in the `invalid_request` error
+    pub fn from_form(params: &[(String, String)]) -> crate::Result<TokenRequest> {
+        from_form(params)
+    }
+
+    pub fn grant_type(&self) -> Option<GrantType> {
+        match self {
+            TokenRequest::AuthorizationCode(_) => Some(GrantType::AuthorizationCode),
+            TokenRequest::RefreshToken(_) => Some(GrantType::RefreshToken),
+            TokenRequest::ClientCredentials(_) => Some(GrantType::ClientCredentials),
+            TokenRequest::Password(_) => Some(GrantType::Password),
+            TokenRequest::DeviceCode(_) => Some(GrantType::DeviceCode),
+            TokenRequest::Unknown => None,
+        }
+    }
+
+    pub fn client_id(&self) -> Option<&String> {
+        match self {
+            TokenRequest::AuthorizationCode(req) => req.client_id.as_ref(),
+            TokenRequest::RefreshToken(req) => req.client_id.as_ref(),
+            TokenRequest::ClientCredentials(req) => req.client_id.as_ref(),
+            TokenRequest::Password(req) => req.client_id.as_ref(),
+            TokenRequest::DeviceCode(req) => req.client_id.as_ref(),
+            TokenRequest::Unknown => None,
+        }
+    }
+
+    pub fn client_secret(&self) -> Option<&String> {
+        match self {
+            TokenRequest::AuthorizationCode(req) => req.client_secret.as_ref(),
+            TokenRequest::RefreshToken(req) => req.client_secret.as_ref(),
+            TokenRequest::ClientCredentials(req) => req.client_secret.as_ref(),
+            TokenRequest::Password(req) => req.client_secret.as_ref(),
+            TokenRequest::DeviceCode(req) => req.client_secret.as_ref(),
+            TokenRequest::Unknown => None,
+        }
+    }
+
+    /// Assertion authenticating the client instead of a secret, see [`crate::assertion`]
+    pub fn client_assertion(&self) -> Option<ClientAssertion> {
+        let (assertion_type, assertion) = match self {
+            TokenRequest::AuthorizationCode(req) => {
+                (&req.client_assertion_type, &req.client_assertion)
+            }
+            TokenRequest::RefreshToken(req) => (&req.client_assertion_type, &req.client_assertion),
+            TokenRequest::ClientCredentials(req) => {
+                (&req.client_assertion_type, &req.client_assertion)
+            }
+            TokenRequest::Password(req) => (&req.client_assertion_type, &req.client_assertion),
+            TokenRequest::DeviceCode(req) => (&req.client_assertion_type, &req.client_assertion),
+            TokenRequest::Unknown => return None,
+        };
+        Some(ClientAssertion {
+            assertion_type: assertion_type.clone(),
+            assertion: assertion.clone()?,
+        })
+    }
+
+    /// Resources requested with the `resource` parameter, see [`crate::resource`]
+    pub fn resource(&self) -> &[String] {
+        match self {
+            TokenRequest::AuthorizationCode(req) => &req.resource,
+            TokenRequest::RefreshToken(req) => &req.resource,
+            TokenRequest::ClientCredentials(req) => &req.resource,
+            TokenRequest::Password(req) => &req.resource,
+            TokenRequest::DeviceCode(req) => &req.resource,
+            TokenRequest::Unknown => &[],
+        }
+    }
+}
+
+// Repeated resource parameters are merged first, as they cannot be deserialized as such
+fn from_form<T: DeserializeOwned>(params: &[(String, String)]) -> crate::Result<T> {
+    let encoded = serde_urlencoded::to_string(resource::merge_params(params))
+        .map_err(|err| Error::new(ErrorKind::InvalidRequest, err.to_string()))?;
+    serde_urlencoded::from_str(&encoded).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidRequest,
+            err.to_string().replace("field", "parameter"),
+        )
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    pub token: String,
+    pub token_type_hint: Option<TokenTypeHint>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevocationRequest {
+    pub token: String,
+    pub token_type_hint: Option<TokenTypeHint>,
+}
+
+/// Exchange of a bootstrap token for a token set, see [`crate::bootstrap`]
+#[derive(Debug, Deserialize)]
+pub struct BootstrapRequest {
+    pub bootstrap_token: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// Authorization request pushed by a client ahead of sending the user to the authorization endpoint,
+/// see [`crate::par`]
+#[derive(Debug, Deserialize)]
+pub struct PushRequest {
+    #[serde(flatten)]
+    pub request: AuthorizationRequest,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+impl PushRequest {
+    /// Parses a pushed request from its form parameters, like [`TokenRequest::from_form`]
+    pub fn from_form(params: &[(String, String)]) -> crate::Result<PushRequest> {
+        from_form(params)
+    }
+}
+
+/// Request for a device code and a user code, see [`crate::device_code`]
+#[derive(Debug, Deserialize)]
+pub struct DeviceAuthorizationRequest {
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub scope: Scope,
+    pub client_secret: Option<String>,
+}
+
+/// Reference to a pushed authorization request, sent to the authorization endpoint instead of its parameters
+#[derive(Debug, Deserialize)]
+pub struct PushedRequestReference {
+    pub client_id: String,
+    pub request_uri: String,
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+
+    use crate::error::ErrorKind;
+
+    use super::{
+        AuthorizationRequest, GrantType, Prompt, PushRequest, ResponseMode, TokenRequest,
+    };
+
+    #[test]
+    fn it_decodes_an_encoded_state() {
+        let req: AuthorizationRequest = serde_urlencoded::from_str(
+            "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost&state=a+b%26c%3Dd%2F%C3%A9%3F%23%25%2B",
+        )
+        .unwrap();
+        assert_eq!(req.state.as_deref(), Some("a b&c=d/é?#%+"));
+    }
+
+    #[test]
+    fn it_treats_an_empty_state_as_missing() {
+        let req: AuthorizationRequest = serde_urlencoded::from_str(
+            "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost&state=",
+        )
+        .unwrap();
+        assert_eq!(req.state, None);
+
+        let req: AuthorizationRequest = serde_urlencoded::from_str(
+            "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost",
+        )
+        .unwrap();
+        assert_eq!(req.state, None);
+    }
+
+    #[test]
+    fn it_parses_the_response_mode() {
+        let parse = |query: &str| {
+            let req: AuthorizationRequest = serde_urlencoded::from_str(&format!(
+                "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost{}",
+                query
+            ))
+            .unwrap();
+            req.response_mode()
+        };
+
+        assert_eq!(parse("").unwrap(), ResponseMode::Query);
+        assert_eq!(parse("&response_mode=").unwrap(), ResponseMode::Query);
+        assert_eq!(parse("&response_mode=query").unwrap(), ResponseMode::Query);
+        assert_eq!(
+            parse("&response_mode=form_post").unwrap(),
+            ResponseMode::FormPost
+        );
+        let err = parse("&response_mode=fragment").unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn it_parses_the_prompt() {
+        let parse = |query: &str| {
+            let req: AuthorizationRequest = serde_urlencoded::from_str(&format!(
+                "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost{}",
+                query
+            ))
+            .unwrap();
+            req
+        };
+
+        assert!(parse("").prompt().unwrap().is_empty());
+        assert!(!parse("&prompt=").is_silent());
+        assert!(parse("&prompt=none").is_silent());
+        assert_eq!(
+            parse("&prompt=login+select_account").prompt().unwrap(),
+            vec![Prompt::Login, Prompt::SelectAccount]
+        );
+
+        let err = parse("&prompt=none+login").prompt().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+        assert!(!parse("&prompt=none+login").is_silent());
+        let err = parse("&prompt=never").prompt().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+        let err = parse("&prompt=login+consent").prompt().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn it_checks_the_auth_time_against_max_age() {
+        let parse = |query: &str| {
+            serde_urlencoded::from_str::<AuthorizationRequest>(&format!(
+                "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost{}",
+                query
+            ))
+        };
+        let now = Utc::now();
+        let recent = Some(now - Duration::seconds(30));
+
+        let req = parse("&max_age=").unwrap();
+        assert_eq!(req.max_age, None);
+        assert!(req.accepts_auth_time(None, now));
+
+        let req = parse("&max_age=60").unwrap();
+        assert!(req.accepts_auth_time(recent, now));
+        assert!(!req.accepts_auth_time(Some(now - Duration::seconds(61)), now));
+        assert!(!req.accepts_auth_time(None, now));
+
+        let req = parse("&max_age=0").unwrap();
+        assert!(!req.accepts_auth_time(Some(now), now));
+
+        assert!(parse("&max_age=-1").is_err());
+
+        let req = parse("&max_age=100000000000000000").unwrap();
+        assert!(req.accepts_auth_time(recent, now));
+        let req = parse(&format!("&max_age={}", u64::MAX)).unwrap();
+        assert!(req.accepts_auth_time(recent, now));
+    }
+
+    #[test]
+    fn it_forces_login_when_prompted() {
+        let parse = |query: &str| {
+            serde_urlencoded::from_str::<AuthorizationRequest>(&format!(
+                "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost{}",
+                query
+            ))
+            .unwrap()
+        };
+        let now = Utc::now();
+
+        assert!(parse("").accepts_auth_time(Some(now), now));
+        assert!(!parse("&prompt=login").accepts_auth_time(Some(now), now));
+        assert!(!parse("&prompt=select_account").accepts_auth_time(Some(now), now));
+        assert!(!parse("&prompt=login&max_age=60").accepts_auth_time(Some(now), now));
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn it_parses_token_requests_per_grant_type() {
+        let req = TokenRequest::from_form(&params(&[
+            ("grant_type", "password"),
+            ("username", "jdoe"),
+            ("password", "secret"),
+            ("otp", "123456"),
+            ("scope", "profile"),
+        ]))
+        .unwrap();
+        assert_eq!(req.grant_type(), Some(GrantType::Password));
+        match req {
+            TokenRequest::Password(req) => assert_eq!(req.otp.as_deref(), Some("123456")),
+            _ => panic!("expected a password request"),
+        }
+
+        let req = TokenRequest::from_form(&params(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", "ci"),
+        ]))
+        .unwrap();
+        assert_eq!(req.grant_type(), Some(GrantType::ClientCredentials));
+        assert_eq!(req.client_id().map(String::as_str), Some("ci"));
+
+        let req = TokenRequest::from_form(&params(&[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", "xyz"),
+            ("client_id", "cli"),
+        ]))
+        .unwrap();
+        assert_eq!(req.grant_type(), Some(GrantType::DeviceCode));
+        assert_eq!(req.client_id().map(String::as_str), Some("cli"));
+
+        let req = TokenRequest::from_form(&params(&[("grant_type", "implicit")])).unwrap();
+        assert_eq!(req.grant_type(), None);
+    }
+
+    #[test]
+    fn it_names_missing_token_request_parameters() {
+        let err = TokenRequest::from_form(&params(&[
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", "http://localhost"),
+        ]))
+        .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidRequest);
+        assert_eq!(err.description(), "missing parameter `code`");
+
+        let err = TokenRequest::from_form(&params(&[("grant_type", "password")])).unwrap_err();
+        assert_eq!(err.description(), "missing parameter `username`");
+
+        let err = TokenRequest::from_form(&params(&[("code", "xyz")])).unwrap_err();
+        assert_eq!(err.description(), "missing parameter `grant_type`");
+    }
+
+    #[test]
+    fn it_reads_back_a_serialized_authorization_request() {
+        let req: AuthorizationRequest = serde_urlencoded::from_str(
+            "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost&scope=profile+openid&state=xyz&max_age=300",
+        )
+        .unwrap();
+        let json = serde_json::to_value(&req).unwrap();
+        assert_eq!(json.get("nonce"), None);
+        let read: AuthorizationRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(read.client_id, "test");
+        assert_eq!(read.scope, req.scope);
+        assert_eq!(read.state.as_deref(), Some("xyz"));
+        assert_eq!(read.nonce, None);
+        assert_eq!(read.max_age, Some(300));
+    }
+
+    #[test]
+    fn it_reads_repeated_resource_parameters() {
+        let req = TokenRequest::from_form(&params(&[
+            ("grant_type", "client_credentials"),
+            ("resource", "https://api.example.com/"),
+            ("scope", "profile"),
+            ("resource", "https://files.example.com/"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            req.resource(),
+            ["https://api.example.com/", "https://files.example.com/"]
+        );
+
+        let req = PushRequest::from_form(&params(&[
+            ("response_type", "code"),
+            ("client_id", "test"),
+            ("redirect_uri", "http://localhost"),
+            ("resource", "https://api.example.com/"),
+            ("resource", "https://files.example.com/"),
+        ]))
+        .unwrap();
+        assert_eq!(req.request.resource.len(), 2);
+        let json = serde_json::to_value(&req.request).unwrap();
+        let read: AuthorizationRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(read.resource, req.request.resource);
+
+        let req: AuthorizationRequest = serde_urlencoded::from_str(
+            "response_type=code&client_id=test&redirect_uri=http%3A%2F%2Flocalhost&resource=",
+        )
+        .unwrap();
+        assert!(req.resource.is_empty());
+    }
+}
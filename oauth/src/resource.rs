@@ -0,0 +1,250 @@
+//! Resource indicators, as described in [RFC 8707](https://tools.ietf.org/html/rfc8707).
+//!
+//! Clients name the resource servers they want a token for with the repeatable `resource`
+//! parameter of authorization and token requests. Resources are absolute URIs without a fragment,
+//! and must be registered in the [`ResourceRegistry`] when one is set. Token requests can only
+//! narrow the resources granted by the authorization request, and the granted resources are
+//! the audience reported when introspecting the token.
+//!
+//! Form bodies and query strings cannot be deserialized with repeated parameters, so requests
+//! are read after merging their `resource` parameters with [`merge_params`] or [`merge_query`],
+//! into a single space-delimited value.
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Deserializer, Serializer};
+use url::{form_urlencoded, Url};
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+
+/// Name of the request parameter carrying resource indicators
+pub const PARAM: &str = "resource";
+
+/// Resource servers tokens can be requested for
+#[derive(Clone, Debug, Default)]
+pub struct ResourceRegistry {
+    resources: BTreeSet<Url>,
+}
+
+impl ResourceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, resource: Url) -> &mut Self {
+        self.resources.insert(resource);
+        self
+    }
+
+    pub fn is_registered(&self, resource: &Url) -> bool {
+        self.resources.contains(resource)
+    }
+
+    /// Registered resources, sorted
+    pub fn iter(&self) -> impl Iterator<Item = &Url> {
+        self.resources.iter()
+    }
+}
+
+/// Parses a resource indicator, which must be an absolute URI without a fragment
+pub fn parse(resource: &str) -> Result<Url> {
+    match Url::parse(resource) {
+        Ok(url) if url.fragment().is_none() => Ok(url),
+        _ => Err(Error::new(
+            ErrorKind::InvalidTarget,
+            format!("invalid resource '{}'", resource),
+        )),
+    }
+}
+
+/// Validates requested resources against the registry, or only their syntax without one,
+/// failing with an InvalidTarget error listing the unknown resources.
+/// Returns the resources normalized, like `https://api.example.com/` for `https://api.example.com`.
+pub fn validate(registry: Option<&ResourceRegistry>, resources: &[String]) -> Result<Vec<String>> {
+    let resources = resources
+        .iter()
+        .map(String::as_str)
+        .map(parse)
+        .collect::<Result<Vec<Url>>>()?;
+    if let Some(registry) = registry {
+        let unknown: Vec<&str> = resources
+            .iter()
+            .filter(|resource| !registry.is_registered(resource))
+            .map(Url::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidTarget,
+                format!("unknown resource: {}", unknown.join(" ")),
+            ));
+        }
+    }
+
+    let mut normalized: Vec<String> = Vec::with_capacity(resources.len());
+    for resource in resources {
+        let resource = String::from(resource);
+        if !normalized.contains(&resource) {
+            normalized.push(resource);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Resources to issue tokens for, out of the granted ones.
+/// All granted resources are kept if none are requested, otherwise each requested resource
+/// must have been granted, as token requests cannot widen the audience of a grant.
+/// Grants without resources are not restricted to any, so they can be narrowed to any known one.
+pub fn narrow(
+    registry: Option<&ResourceRegistry>,
+    granted: &[String],
+    requested: &[String],
+) -> Result<Vec<String>> {
+    if requested.is_empty() {
+        return Ok(granted.to_vec());
+    }
+
+    let requested = validate(registry, requested)?;
+    let widened = requested
+        .iter()
+        .find(|resource| !granted.is_empty() && !granted.contains(resource));
+    match widened {
+        Some(resource) => Err(Error::new(
+            ErrorKind::InvalidTarget,
+            format!("resource '{}' was not granted", resource),
+        )),
+        None => Ok(requested),
+    }
+}
+
+/// Joins repeated `resource` parameters into the first one, space-delimited
+pub fn merge_params(params: &[(String, String)]) -> Vec<(String, String)> {
+    let mut merged: Vec<(String, String)> = Vec::with_capacity(params.len());
+    let mut resource: Option<usize> = None;
+    for (name, value) in params {
+        match resource {
+            Some(i) if name == PARAM => {
+                let joined = &mut merged[i].1;
+                if !value.is_empty() {
+                    if !joined.is_empty() {
+                        joined.push(' ');
+                    }
+                    joined.push_str(value);
+                }
+            }
+            _ => {
+                if name == PARAM {
+                    resource = Some(merged.len());
+                }
+                merged.push((name.clone(), value.clone()));
+            }
+        }
+    }
+    merged
+}
+
+/// Query string with its repeated `resource` parameters joined, see [`merge_params`]
+pub fn merge_query(query: &str) -> String {
+    let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(merge_params(&params))
+        .finish()
+}
+
+/// Deserializes space-delimited resources, none if the parameter is missing or empty
+pub fn deserialize<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let resources = Option::<String>::deserialize(deserializer)?.unwrap_or_default();
+    Ok(resources.split_whitespace().map(str::to_string).collect())
+}
+
+/// Serializes resources space-delimited, to be read back by [`deserialize`]
+pub fn serialize<S>(resources: &[String], serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&resources.join(" "))
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use crate::error::ErrorKind;
+
+    use super::*;
+
+    const API: &str = "https://api.example.com/";
+
+    fn registry() -> ResourceRegistry {
+        let mut registry = ResourceRegistry::new();
+        registry.register(Url::parse(API).unwrap());
+        registry
+    }
+
+    fn resources(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn it_accepts_registered_resources() {
+        let registry = registry();
+        let validated = validate(Some(&registry), &resources(&["https://api.example.com"]));
+        assert_eq!(validated.unwrap(), resources(&[API]));
+        assert!(validate(Some(&registry), &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_rejects_unknown_and_invalid_resources() {
+        let registry = registry();
+        let err = validate(Some(&registry), &resources(&[API, "https://evil.example.com"]))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidTarget);
+        assert_eq!(err.description(), "unknown resource: https://evil.example.com/");
+
+        for invalid in &["api", "/api", "https://api.example.com/#docs"] {
+            let err = validate(None, &resources(&[*invalid])).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::InvalidTarget, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn it_narrows_but_never_widens_the_granted_resources() {
+        let granted = resources(&[API, "https://files.example.com/"]);
+        assert_eq!(narrow(None, &granted, &[]).unwrap(), granted);
+        assert_eq!(
+            narrow(None, &granted, &resources(&["https://api.example.com"])).unwrap(),
+            resources(&[API])
+        );
+
+        let other = resources(&["https://other.example.com/"]);
+        let err = narrow(None, &granted, &other).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidTarget);
+    }
+
+    #[test]
+    fn it_narrows_unrestricted_grants_to_known_resources() {
+        let registry = registry();
+        let narrowed = narrow(Some(&registry), &[], &resources(&[API])).unwrap();
+        assert_eq!(narrowed, resources(&[API]));
+
+        let other = resources(&["https://other.example.com/"]);
+        let err = narrow(Some(&registry), &[], &other).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidTarget);
+    }
+
+    #[test]
+    fn it_merges_repeated_resource_parameters() {
+        let query = merge_query(
+            "resource=https%3A%2F%2Fa.example&scope=x&resource=https%3A%2F%2Fb.example",
+        );
+        assert_eq!(
+            query,
+            "resource=https%3A%2F%2Fa.example+https%3A%2F%2Fb.example&scope=x"
+        );
+        assert_eq!(merge_query("scope=x"), "scope=x");
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::code::AuthorizationCode;
+use crate::error::Error;
+use crate::scope::Scope;
+use crate::token::{Token, TokenTypeHint};
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationResponse {
+    code: AuthorizationCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+}
+
+impl AuthorizationResponse {
+    pub fn new(code: AuthorizationCode, state: Option<String>) -> AuthorizationResponse {
+        AuthorizationResponse { code, state }
+    }
+}
+
+/// Error sent back to the client's redirect URI, echoing the state of the request
+#[derive(Debug, Serialize)]
+pub struct AuthorizationErrorResponse {
+    #[serde(flatten)]
+    error: Error,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+}
+
+impl AuthorizationErrorResponse {
+    pub fn new(error: Error, state: Option<String>) -> AuthorizationErrorResponse {
+        AuthorizationErrorResponse { error, state }
+    }
+}
+
+/// Appends a response to the client's redirect URI as query parameters,
+/// keeping any query the registered URI already carries.
+pub fn redirect_uri<T: Serialize>(redirect_uri: &Url, res: &T) -> Url {
+    let params = serde_urlencoded::to_string(res).unwrap_or_default();
+    let query = match redirect_uri.query() {
+        Some(query) if !query.is_empty() && !params.is_empty() => format!("{}&{}", query, params),
+        Some(query) if !query.is_empty() => query.to_string(),
+        _ => params,
+    };
+    let mut redirect_uri = redirect_uri.clone();
+    redirect_uri.set_query(Some(&query).filter(|query| !query.is_empty()).map(String::as_str));
+    redirect_uri
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: TokenType,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: Scope,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenType {
+    #[default]
+    Bearer,
+}
+
+impl Debug for TokenType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match serde_json::to_string(self) {
+            Ok(s) => write!(f, "{}", s),
+            Err(_) => Err(fmt::Error),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub introspection_data: Option<ActiveIntrospectionResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActiveIntrospectionResponse {
+    pub scope: Scope,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub token_type: TokenTypeHint,
+    /// Seconds since the epoch the token was issued at, unknown for tokens of earlier versions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(with = "ts_seconds")]
+    pub exp: DateTime<Utc>,
+    /// Resource servers the token is meant for, see [`crate::resource`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aud: Vec<String>,
+    /// ID of the user acting as `username`, when the token impersonates them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
+}
+
+impl IntrospectionResponse {
+    pub fn from_token<T: Token>(token: &T) -> Self {
+        if token.is_expired() {
+            IntrospectionResponse::inactive()
+        } else {
+            IntrospectionResponse::active(token)
+        }
+    }
+
+    pub fn inactive() -> Self {
+        IntrospectionResponse {
+            active: false,
+            introspection_data: None,
+        }
+    }
+
+    pub fn active<T: Token>(token: &T) -> Self {
+        let session = token.session();
+        IntrospectionResponse {
+            active: true,
+            introspection_data: Some(ActiveIntrospectionResponse {
+                scope: session.scope().clone(),
+                client_id: session.client_id().clone(),
+                username: session.user_id().clone(),
+                token_type: token.type_hint(),
+                iat: session.issued_at().map(DateTime::timestamp),
+                exp: *token.expiration(),
+                aud: session.resources().to_vec(),
+                impersonator: session.impersonator().map(str::to_string),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevocationResponse {
+    ok: bool,
+}
+
+impl RevocationResponse {
+    pub fn ok() -> Self {
+        RevocationResponse { ok: true }
+    }
+}
+
+/// Reference to a pushed authorization request, see [`crate::par`]
+#[derive(Debug, Serialize)]
+pub struct PushResponse {
+    pub request_uri: String,
+    pub expires_in: i64,
+}
+
+/// Codes of a device authorization, see [`crate::device_code`]
+#[derive(Debug, Serialize)]
+pub struct DeviceAuthorizationResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: Url,
+    /// Verification URI with the user code filled in, for showing as a link or QR code
+    pub verification_uri_complete: Url,
+    pub expires_in: i64,
+    pub interval: i64,
+}
+
+/// Response to an introspection or revocation request, telling whether the caller was allowed
+/// to act on the token. Callers that are not get the response of an unknown token,
+/// so that they cannot probe for the tokens of other clients.
+#[derive(Debug)]
+pub enum TokenAccess<R> {
+    Granted(R),
+    /// The token was issued to another client
+    Denied(R),
+}
+
+impl<R> TokenAccess<R> {
+    pub fn is_denied(&self) -> bool {
+        matches!(self, TokenAccess::Denied(_))
+    }
+
+    pub fn into_response(self) -> R {
+        match self {
+            TokenAccess::Granted(res) | TokenAccess::Denied(res) => res,
+        }
+    }
+}
+
+/// Outcome of dry-running the validation of an authorization request.
+/// Violations are the errors the authorization endpoint would fail with, in the order it checks them,
+/// while advisories point out requests that are accepted but probably not what the client meant.
+#[derive(Debug, Serialize)]
+pub struct Diagnosis {
+    valid: bool,
+    violations: Vec<Error>,
+    advisories: Vec<String>,
+}
+
+impl Diagnosis {
+    pub fn new(violations: Vec<Error>, advisories: Vec<String>) -> Self {
+        Diagnosis {
+            valid: violations.is_empty(),
+            violations,
+            advisories,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn violations(&self) -> &[Error] {
+        &self.violations
+    }
+
+    pub fn advisories(&self) -> &[String] {
+        &self.advisories
+    }
+
+    pub fn add_violation(&mut self, violation: Error) -> &mut Self {
+        self.violations.push(violation);
+        self.valid = false;
+        self
+    }
+
+    pub fn add_advisory(&mut self, advisory: String) -> &mut Self {
+        self.advisories.push(advisory);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use url::Url;
+
+    use enseada::secure::SecureSecret;
+
+    use crate::code::AuthorizationCode;
+    use crate::error::{Error, ErrorKind};
+    use crate::session::Session;
+    use crate::token::AccessToken;
+
+    use super::*;
+
+    const STATE: &str = "a b&c=d/é?#%+;\u{1F512}";
+
+    fn query_param(uri: &Url, name: &str) -> Option<String> {
+        uri.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+    }
+
+    #[test]
+    fn it_echoes_the_state_on_success() {
+        let code = AuthorizationCode::new(
+            SecureSecret::new(b"code".to_vec()),
+            Session::for_client("test".to_string()),
+            Duration::minutes(5),
+        );
+        let res = AuthorizationResponse::new(code, Some(STATE.to_string()));
+        let uri = redirect_uri(&Url::parse("https://example.com/callback").unwrap(), &res);
+        assert_eq!(query_param(&uri, "state").as_deref(), Some(STATE));
+        assert!(query_param(&uri, "code").is_some());
+    }
+
+    #[test]
+    fn it_echoes_the_state_on_error() {
+        let err = Error::new(ErrorKind::AccessDenied, "denied".to_string());
+        let res = AuthorizationErrorResponse::new(err, Some(STATE.to_string()));
+        let uri = redirect_uri(&Url::parse("https://example.com/callback").unwrap(), &res);
+        assert_eq!(query_param(&uri, "state").as_deref(), Some(STATE));
+        assert_eq!(query_param(&uri, "error").as_deref(), Some("access_denied"));
+        assert_eq!(query_param(&uri, "error_description").as_deref(), Some("denied"));
+    }
+
+    #[test]
+    fn it_omits_a_missing_state() {
+        let err = Error::new(ErrorKind::AccessDenied, "denied".to_string());
+        let res = AuthorizationErrorResponse::new(err, None);
+        let uri = redirect_uri(&Url::parse("https://example.com/callback").unwrap(), &res);
+        assert_eq!(query_param(&uri, "state"), None);
+    }
+
+    #[test]
+    fn it_keeps_the_registered_query() {
+        let err = Error::new(ErrorKind::AccessDenied, "denied".to_string());
+        let res = AuthorizationErrorResponse::new(err, Some(STATE.to_string()));
+        let uri = redirect_uri(&Url::parse("https://example.com/cb?app=1").unwrap(), &res);
+        assert_eq!(query_param(&uri, "app").as_deref(), Some("1"));
+        assert_eq!(query_param(&uri, "state").as_deref(), Some(STATE));
+    }
+
+    #[test]
+    fn it_introspects_the_resources_as_audience() {
+        let mut session = Session::for_client("test".to_string());
+        let token = AccessToken::new(
+            SecureSecret::new(b"token".to_vec()),
+            session.clone(),
+            Duration::minutes(5),
+        );
+        let json = serde_json::to_value(IntrospectionResponse::from_token(&token)).unwrap();
+        assert_eq!(json.get("aud"), None);
+
+        session.set_resources(vec!["https://api.example.com/".to_string()]);
+        let token = AccessToken::new(
+            SecureSecret::new(b"token".to_vec()),
+            session,
+            Duration::minutes(5),
+        );
+        let json = serde_json::to_value(IntrospectionResponse::from_token(&token)).unwrap();
+        assert_eq!(json["aud"], serde_json::json!(["https://api.example.com/"]));
+    }
+}
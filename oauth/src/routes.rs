@@ -0,0 +1,837 @@
+//! Actix routes for the token, introspection, revocation, pushed authorization request
+//! and device authorization endpoints, plus the opt-in bootstrap token exchange.
+//!
+//! The routes expect an `Arc<dyn Oauth>` to be registered as application data.
+//! If [`ErrorDocs`] are registered too, errors link to the documentation of their kind,
+//! and if an [`AuditSink`](crate::audit::AuditSink) is, token issuance, refresh, revocation
+//! and denied introspection requests are recorded to it.
+//!
+//! Browser clients can call the token endpoint from the origins they registered,
+//! which are answered with CORS headers, preflight requests included.
+//!
+//! Clients can only introspect and revoke their own tokens. Clients an admin marked as resource
+//! servers introspect the tokens of any client with an access token granted the
+//! `tokens:introspect` scope.
+
+use std::sync::Arc;
+
+use actix_web::error::{Error, InternalError, UrlencodedError};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::web::{self, Data, Form, FormConfig, Json, Query};
+use actix_web::{options, post, FromRequest, HttpRequest, HttpResponse, ResponseError, Scope};
+use actix_web_httpauth::headers::authorization::{Basic, Bearer, ParseError, Scheme};
+use serde::Deserialize;
+
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::binding;
+use crate::device::{AuthMethod, Device};
+use crate::error::{Error as OAuthError, ErrorDocs, ErrorKind};
+use crate::facade::Oauth;
+use crate::handler::BasicAuth;
+use crate::request::{
+    BootstrapRequest, DeviceAuthorizationRequest, GrantType, IntrospectionRequest, PushRequest,
+    RevocationRequest, TokenRequest,
+};
+use crate::response::{IntrospectionResponse, RevocationResponse, TokenResponse};
+use crate::token::Token;
+use crate::user_agent::UserAgent;
+
+/// Builds a scope mounted at `path` serving the token, introspection, revocation,
+/// pushed authorization request and device authorization endpoints.
+/// More services, like an authorization endpoint, can be added to the returned scope.
+pub fn scope(path: &str) -> Scope {
+    web::scope(path)
+        .app_data(Form::<TokenForm>::configure(handle_form_errors))
+        .app_data(Form::<DeviceAuthorizationRequest>::configure(handle_form_errors))
+        .service(token)
+        .service(token_preflight)
+        .service(introspect)
+        .service(revoke)
+        .service(par)
+        .service(device_authorization)
+}
+
+/// Raw parameters of a token or pushed authorization request, parsed with
+/// [`TokenRequest::from_form`] and [`PushRequest::from_form`]
+type TokenForm = Vec<(String, String)>;
+
+#[post("/token")]
+pub async fn token(
+    oauth: Data<Arc<dyn Oauth>>,
+    form: Form<TokenForm>,
+    http_req: HttpRequest,
+) -> HttpResponse {
+    let client_auth = basic_auth(&http_req);
+    let client_id = client_auth
+        .as_ref()
+        .map(|auth| auth.client_id().to_string())
+        .or_else(|| form_client_id(&form));
+    let mut res = match issue_token(&oauth, &form, client_auth.as_ref(), &http_req).await {
+        Ok(res) => HttpResponse::Ok().json(res),
+        Err(err) => err.error_response(),
+    };
+    if let Some(client_id) = client_id {
+        allow_origin(&oauth, &http_req, &client_id, &mut res).await;
+    }
+    res
+}
+
+async fn issue_token(
+    oauth: &Data<Arc<dyn Oauth>>,
+    form: &TokenForm,
+    client_auth: Option<&BasicAuth>,
+    http_req: &HttpRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let req = TokenRequest::from_form(form).map_err(|err| document(http_req, err))?;
+    let ip = binding::client_ip(http_req);
+    log::debug!("received token request from {:?}", ip);
+
+    let auth_method = if client_auth.is_some() {
+        AuthMethod::ClientSecretBasic
+    } else if req.client_secret().is_some() {
+        AuthMethod::ClientSecretPost
+    } else {
+        AuthMethod::None
+    };
+    let mut device = Device::new(UserAgent::from(http_req), auth_method);
+    device.set_ip(ip);
+    let action = match req.grant_type() {
+        Some(GrantType::RefreshToken) => AuditAction::Refresh,
+        _ => AuditAction::TokenIssuance,
+    };
+    let client_id = client_auth
+        .map(|auth| auth.client_id().to_string())
+        .or_else(|| req.client_id().cloned());
+    let res = oauth.token(&req, client_auth, Some(device)).await;
+    audit_token_issuance(oauth, http_req, action, client_id, &res).await;
+    res.map_err(|err| document(http_req, err))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreflightQuery {
+    client_id: Option<String>,
+}
+
+/// Answers the CORS preflight of browser token requests.
+/// Preflights carry no body, so browser clients pass their ID in the `client_id` query parameter.
+#[options("/token")]
+pub async fn token_preflight(
+    oauth: Data<Arc<dyn Oauth>>,
+    query: Query<PreflightQuery>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let mut res = HttpResponse::NoContent().finish();
+    if let Some(client_id) = &query.client_id {
+        if allow_origin(&oauth, &req, client_id, &mut res).await {
+            let headers = res.headers_mut();
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("POST"),
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static("authorization, content-type"),
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_MAX_AGE,
+                HeaderValue::from_static("600"),
+            );
+        }
+    }
+    res
+}
+
+/// Lets the browser expose the response to the origin of the request, if the client registered it.
+/// Requests from unknown origins get no CORS headers, so browsers hide the response from them.
+async fn allow_origin(
+    oauth: &Data<Arc<dyn Oauth>>,
+    req: &HttpRequest,
+    client_id: &str,
+    res: &mut HttpResponse,
+) -> bool {
+    let origin = match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin.clone(),
+        None => return false,
+    };
+    let allowed = match (origin.to_str(), oauth.client(client_id).await) {
+        (Ok(value), Some(client)) => client.is_origin_allowed(value),
+        _ => false,
+    };
+    let headers = res.headers_mut();
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    if allowed {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    allowed
+}
+
+/// Client ID sent in the body of a token request, for clients that don't authenticate with Basic
+fn form_client_id(form: &TokenForm) -> Option<String> {
+    form.iter()
+        .find(|(name, _)| name == "client_id")
+        .map(|(_, value)| value.clone())
+}
+
+#[post("/introspect")]
+pub async fn introspect(
+    oauth: Data<Arc<dyn Oauth>>,
+    form: Form<IntrospectionRequest>,
+    req: HttpRequest,
+) -> Result<Json<IntrospectionResponse>, OAuthError> {
+    let client_auth = basic_auth(&req);
+    let client_auth = client_auth.as_ref();
+    let client_id = client_auth.map(|auth| auth.client_id().to_string());
+    let form = form.into_inner();
+    log::debug!("received introspection request");
+
+    // Resource servers authenticate with an access token instead of client credentials
+    let res = match bearer_token(&req) {
+        Some(access_token) => oauth.introspect_with_token(&form, &access_token).await,
+        None => oauth.introspect(&form, client_auth).await,
+    };
+    let res = res.map_err(|err| {
+        let event = AuditEvent::failure(AuditAction::Introspection, err.to_string())
+            .set_client_id(client_id.clone());
+        audit::record(&req, event);
+        document(&req, err)
+    })?;
+    if res.is_denied() {
+        let event = AuditEvent::failure(AuditAction::Introspection, DENIED.to_string())
+            .set_client_id(client_id);
+        audit::record(&req, event);
+    }
+    Ok(Json(res.into_response()))
+}
+
+#[post("/revoke")]
+pub async fn revoke(
+    oauth: Data<Arc<dyn Oauth>>,
+    form: Form<RevocationRequest>,
+    req: HttpRequest,
+) -> Result<Json<RevocationResponse>, OAuthError> {
+    let client_auth = basic_auth(&req);
+    let client_auth = client_auth.as_ref();
+    let form = form.into_inner();
+    log::debug!("received revocation request");
+
+    // The owner can only be found before the token is revoked
+    let user_id = if audit::is_enabled(&req) {
+        token_owner(&oauth, &form.token).await
+    } else {
+        None
+    };
+    let res = oauth.revoke(&form, client_auth).await;
+    let event = match &res {
+        Ok(res) if res.is_denied() => {
+            AuditEvent::failure(AuditAction::Revocation, DENIED.to_string())
+        }
+        Ok(_) => AuditEvent::success(AuditAction::Revocation),
+        Err(err) => AuditEvent::failure(AuditAction::Revocation, err.to_string()),
+    };
+    let client_id = client_auth.map(|auth| auth.client_id().to_string());
+    audit::record(&req, event.set_client_id(client_id).set_user_id(user_id));
+    let res = res.map_err(|err| document(&req, err))?;
+    Ok(Json(res.into_response()))
+}
+
+/// Stores an authorization request pushed by a confidential client (RFC 9126),
+/// answering with the request URI referencing it
+#[post("/par")]
+pub async fn par(
+    oauth: Data<Arc<dyn Oauth>>,
+    form: Form<TokenForm>,
+    req: HttpRequest,
+) -> Result<HttpResponse, OAuthError> {
+    let client_auth = basic_auth(&req);
+    let client_auth = client_auth.as_ref();
+    log::debug!("received pushed authorization request");
+
+    let push = PushRequest::from_form(&form).map_err(|err| document(&req, err))?;
+    let res = oauth
+        .push(&push, client_auth)
+        .await
+        .map_err(|err| document(&req, err))?;
+    Ok(HttpResponse::Created()
+        .header(header::CACHE_CONTROL, "no-store")
+        .json(res))
+}
+
+/// Issues a device code and a user code (RFC 8628), for clients that cannot open a browser
+#[post("/device/code")]
+pub async fn device_authorization(
+    oauth: Data<Arc<dyn Oauth>>,
+    form: Form<DeviceAuthorizationRequest>,
+    req: HttpRequest,
+) -> Result<HttpResponse, OAuthError> {
+    let client_auth = basic_auth(&req);
+    let client_auth = client_auth.as_ref();
+    log::debug!("received device authorization request");
+
+    let res = oauth
+        .authorize_device(&form, client_auth)
+        .await
+        .map_err(|err| document(&req, err))?;
+    Ok(HttpResponse::Ok()
+        .header(header::CACHE_CONTROL, "no-store")
+        .json(res))
+}
+
+/// Audit reason of the requests on tokens of other clients, which are answered like unknown tokens
+const DENIED: &str = "token was issued to another client";
+
+/// Links the error to its documentation, if [`ErrorDocs`] are registered as application data
+pub fn document(req: &HttpRequest, err: OAuthError) -> OAuthError {
+    match req.app_data::<Data<ErrorDocs>>() {
+        Some(docs) => docs.document(err),
+        None => err,
+    }
+}
+
+/// Exchanges a bootstrap token for a token set.
+/// Not part of [`scope`], add it to the scope only if bootstrap tokens are enabled.
+#[post("/bootstrap")]
+pub async fn bootstrap(
+    oauth: Data<Arc<dyn Oauth>>,
+    form: Form<BootstrapRequest>,
+    req: HttpRequest,
+) -> Result<Json<TokenResponse>, OAuthError> {
+    let client_auth = basic_auth(&req);
+    let client_auth = client_auth.as_ref();
+    let form = form.into_inner();
+    let ip = binding::client_ip(&req);
+    log::debug!("received bootstrap request from {:?}", ip);
+
+    let auth_method = if client_auth.is_some() {
+        AuthMethod::ClientSecretBasic
+    } else if form.client_secret.is_some() {
+        AuthMethod::ClientSecretPost
+    } else {
+        AuthMethod::None
+    };
+    let mut device = Device::new(UserAgent::from(&req), auth_method);
+    device.set_ip(ip);
+    let client_id = client_auth
+        .map(|auth| auth.client_id().to_string())
+        .or_else(|| form.client_id.clone());
+    let res = oauth.bootstrap(&form, client_auth, Some(device)).await;
+    audit_token_issuance(&oauth, &req, AuditAction::TokenIssuance, client_id, &res).await;
+    Ok(Json(res.map_err(|err| document(&req, err))?))
+}
+
+/// Records the outcome of a token request, with the user the tokens were issued to
+async fn audit_token_issuance(
+    oauth: &Data<Arc<dyn Oauth>>,
+    req: &HttpRequest,
+    action: AuditAction,
+    client_id: Option<String>,
+    res: &Result<TokenResponse, OAuthError>,
+) {
+    if !audit::is_enabled(req) {
+        return;
+    }
+
+    let event = match res {
+        Ok(res) => {
+            AuditEvent::success(action).set_user_id(token_owner(oauth, &res.access_token).await)
+        }
+        Err(err) => AuditEvent::failure(action, err.to_string()),
+    };
+    audit::record(req, event.set_client_id(client_id));
+}
+
+/// User an access token was issued to, if the token is valid and was issued to a user
+async fn token_owner(oauth: &Data<Arc<dyn Oauth>>, access_token: &str) -> Option<String> {
+    let access_token = oauth.access_token(access_token).await.ok()?;
+    access_token.session().user_id().clone()
+}
+
+/// Extracts client credentials from the Authorization header, if present
+pub fn basic_auth(req: &HttpRequest) -> Option<BasicAuth> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .map(Basic::parse)
+        .and_then(Result::<Basic, ParseError>::ok)
+        .map(|basic| {
+            BasicAuth::new(
+                basic.user_id().to_string(),
+                basic.password().map(ToString::to_string),
+            )
+        })
+}
+
+/// Extracts the access token from the Authorization header, if present
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .map(Bearer::parse)
+        .and_then(Result::<Bearer, ParseError>::ok)
+        .map(|bearer| bearer.token().to_string())
+}
+
+/// Reports form deserialization failures as OAuth errors.
+/// Forms that are well encoded but are missing a parameter only fail later, when parsed by grant type,
+/// so that the error can name the parameter.
+pub fn handle_form_errors(cfg: FormConfig) -> FormConfig {
+    cfg.error_handler(handle_form_error)
+}
+
+fn handle_form_error(err: UrlencodedError, req: &HttpRequest) -> Error {
+    let detail = err.to_string();
+    log::error!("Error: {}", &detail);
+    log::debug!("{:?}", req);
+    let description = match &err {
+        UrlencodedError::Parse => {
+            "request data is invalid or is missing a required parameter".to_string()
+        }
+        _ => detail,
+    };
+    let oauth_err = OAuthError::new(ErrorKind::InvalidRequest, description);
+    let res = document(req, oauth_err).error_response();
+    InternalError::from_response(err, res).into()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::{header, Method, StatusCode};
+    use actix_web::{test, App, HttpRequest};
+    use url::Url;
+
+    use crate::audit::{AuditAction, AuditEvent, AuditSink, Outcome};
+    use crate::client::Client;
+    use crate::error::ErrorDocs;
+    use crate::facade::Oauth;
+    use crate::handler::OAuthHandler;
+    use crate::memory::MemoryStorage;
+    use crate::request::{
+        AuthorizationCodeRequest, AuthorizationRequest, ResponseType, TokenRequest,
+    };
+    use crate::scope::Scope;
+    use crate::session::Session;
+    use crate::storage::ClientStorage;
+
+    const REDIRECT_URI: &str = "http://127.0.0.1:9623/callback";
+    const ORIGIN: &str = "https://app.example.com";
+
+    async fn oauth() -> Arc<dyn Oauth> {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut redirect_uris = HashSet::new();
+        redirect_uris.insert(Url::parse(REDIRECT_URI).unwrap());
+        for client_id in &["test", "other"] {
+            let mut client = Client::public(
+                client_id.to_string(),
+                Scope::from("profile"),
+                redirect_uris.clone(),
+            );
+            if *client_id == "test" {
+                let origins = vec![Url::parse(ORIGIN).unwrap()].into_iter().collect();
+                client.set_allowed_origins(origins).unwrap();
+            }
+            storage.save_client(client).await.unwrap();
+        }
+        Arc::new(OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+            "0123456789abcdef0123456789abcdef".to_string(),
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn it_exchanges_a_code_on_a_bare_app() {
+        let oauth = oauth().await;
+        let auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let session = &mut Session::for_client("test".to_string());
+        let res = oauth.authorize(&auth, session).await.unwrap();
+        let code = serde_json::to_value(&res).unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let mut app =
+            test::init_service(App::new().data(oauth).service(super::scope("/oauth"))).await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &code),
+                ("redirect_uri", REDIRECT_URI),
+                ("client_id", "test"),
+            ])
+            .to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["token_type"], "bearer");
+        assert_eq!(body["scope"], "profile");
+    }
+
+    fn allowed_origin(res: &ServiceResponse) -> Option<&str> {
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_registered_origins_to_read_token_responses() {
+        let oauth = oauth().await;
+        let mut app =
+            test::init_service(App::new().data(oauth).service(super::scope("/oauth"))).await;
+        let token_request = |origin: &str, client_id: &str| {
+            test::TestRequest::post()
+                .uri("/oauth/token")
+                .header(header::ORIGIN, origin)
+                .set_form(&[
+                    ("grant_type", "authorization_code"),
+                    ("code", "invalid"),
+                    ("redirect_uri", REDIRECT_URI),
+                    ("client_id", client_id),
+                ])
+                .to_request()
+        };
+
+        let res = test::call_service(&mut app, token_request(ORIGIN, "test")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(allowed_origin(&res), Some(ORIGIN));
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Origin");
+
+        let req = token_request("https://evil.example.com", "test");
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(allowed_origin(&res), None);
+
+        let res = test::call_service(&mut app, token_request(ORIGIN, "other")).await;
+        assert_eq!(allowed_origin(&res), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_answers_preflights_of_registered_origins_only() {
+        let oauth = oauth().await;
+        let mut app =
+            test::init_service(App::new().data(oauth).service(super::scope("/oauth"))).await;
+        let preflight = |origin: &str, uri: &str| {
+            test::TestRequest::with_uri(uri)
+                .method(Method::OPTIONS)
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .to_request()
+        };
+
+        let req = preflight(ORIGIN, "/oauth/token?client_id=test");
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(allowed_origin(&res), Some(ORIGIN));
+        let allowed_methods = res.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS);
+        assert_eq!(allowed_methods.unwrap(), "POST");
+
+        let req = preflight("https://evil.example.com", "/oauth/token?client_id=test");
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(allowed_origin(&res), None);
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS), None);
+
+        let res = test::call_service(&mut app, preflight(ORIGIN, "/oauth/token")).await;
+        assert_eq!(allowed_origin(&res), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_invalid_forms_as_oauth_errors() {
+        let oauth = oauth().await;
+        let mut app =
+            test::init_service(App::new().data(oauth).service(super::scope("/oauth"))).await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&[("grant_type", "authorization_code")])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let body = test::read_body(res).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "invalid_request");
+        assert_eq!(body["error_description"], "missing parameter `code`");
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_unsupported_grant_types() {
+        let oauth = oauth().await;
+        let mut app =
+            test::init_service(App::new().data(oauth).service(super::scope("/oauth"))).await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer")])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let body = test::read_body(res).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "unsupported_grant_type");
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_unidentified_clients_with_unauthorized() {
+        let oauth = oauth().await;
+        let mut app =
+            test::init_service(App::new().data(oauth).service(super::scope("/oauth"))).await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", "unknown"),
+                ("redirect_uri", REDIRECT_URI),
+            ])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(res.headers().contains_key("www-authenticate"));
+
+        let body = test::read_body(res).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "invalid_client");
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_invalid_codes_with_bad_request() {
+        let oauth = oauth().await;
+        let mut app =
+            test::init_service(App::new().data(oauth).service(super::scope("/oauth"))).await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", "unknown"),
+                ("redirect_uri", REDIRECT_URI),
+                ("client_id", "test"),
+            ])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers().get("cache-control").unwrap(), "no-store");
+
+        let body = test::read_body(res).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "invalid_grant");
+    }
+
+    #[actix_rt::test]
+    async fn it_links_errors_to_their_docs() {
+        let oauth = oauth().await;
+        let docs = ErrorDocs::new(Url::parse("https://docs.example.com/errors").unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .data(oauth)
+                .data(docs)
+                .service(super::scope("/oauth")),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&[("grant_type", "authorization_code")])
+            .to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            body["error_uri"],
+            "https://docs.example.com/errors/invalid_request"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_serves_bootstrap_only_when_added() {
+        let mut app = test::init_service(
+            App::new()
+                .data(oauth().await)
+                .service(super::scope("/oauth")),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/bootstrap")
+            .set_form(&[("bootstrap_token", "xyz")])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(oauth().await)
+                .service(super::scope("/oauth").service(super::bootstrap)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/bootstrap")
+            .set_form(&[("bootstrap_token", "xyz")])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        // The handler of these tests has no storage for bootstrap tokens
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(res).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "unsupported_grant_type");
+    }
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<AuditEvent>>);
+
+    impl AuditSink for RecordingSink {
+        fn record(&self, _req: &HttpRequest, event: AuditEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn it_audits_token_requests_and_introspection_denials() {
+        let oauth = oauth().await;
+        let sink = Arc::new(RecordingSink::default());
+        let audit: Arc<dyn AuditSink> = sink.clone();
+        let mut app = test::init_service(
+            App::new()
+                .data(oauth.clone())
+                .data(audit)
+                .service(super::scope("/oauth")),
+        )
+        .await;
+
+        let auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let session = &mut Session::for_client("test".to_string());
+        session.set_user_id("user:jdoe".to_string());
+        let res = oauth.authorize(&auth, session).await.unwrap();
+        let code = serde_json::to_value(&res).unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        for code in &[code.as_str(), "unknown"] {
+            let req = test::TestRequest::post()
+                .uri("/oauth/token")
+                .set_form(&[
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", REDIRECT_URI),
+                    ("client_id", "test"),
+                ])
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+        let req = test::TestRequest::post()
+            .uri("/oauth/introspect")
+            .set_form(&[("token", "xyz")])
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        let events = sink.0.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].action, AuditAction::TokenIssuance);
+        assert_eq!(events[0].outcome, Outcome::Success);
+        assert_eq!(events[0].client_id.as_deref(), Some("test"));
+        assert_eq!(events[0].user_id.as_deref(), Some("user:jdoe"));
+        assert_eq!(events[1].outcome, Outcome::Failure);
+        assert!(events[1].reason.as_ref().unwrap().contains("invalid_grant"));
+        assert_eq!(events[2].action, AuditAction::Introspection);
+        assert_eq!(events[2].outcome, Outcome::Failure);
+    }
+
+    #[actix_rt::test]
+    async fn it_answers_cross_client_probes_like_unknown_tokens_and_audits_them() {
+        let oauth = oauth().await;
+        let auth = AuthorizationRequest {
+            response_type: ResponseType::Code,
+            client_id: "test".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: Scope::from("profile"),
+            state: None,
+            nonce: None,
+            response_mode: None,
+            prompt: None,
+            max_age: None,
+            resource: Vec::new(),
+        };
+        let session = &mut Session::for_client("test".to_string());
+        let code = oauth.authorize(&auth, session).await.unwrap();
+        let code = serde_json::to_value(&code).unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let req = TokenRequest::AuthorizationCode(AuthorizationCodeRequest {
+            code,
+            redirect_uri: REDIRECT_URI.to_string(),
+            code_verifier: None,
+            client_id: Some("test".to_string()),
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            nonce: None,
+            resource: Vec::new(),
+        });
+        let token = oauth.token(&req, None, None).await.unwrap().access_token;
+
+        let sink = Arc::new(RecordingSink::default());
+        let audit: Arc<dyn AuditSink> = sink.clone();
+        let mut app = test::init_service(
+            App::new()
+                .data(oauth.clone())
+                .data(audit)
+                .service(super::scope("/oauth")),
+        )
+        .await;
+        // Basic credentials of the public clients, without a secret
+        let other = "Basic b3RoZXI6";
+        let owner = "Basic dGVzdDo=";
+
+        let req = test::TestRequest::post()
+            .uri("/oauth/introspect")
+            .header("Authorization", other)
+            .set_form(&[("token", &token)])
+            .to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body, serde_json::json!({ "active": false }));
+
+        let req = test::TestRequest::post()
+            .uri("/oauth/revoke")
+            .header("Authorization", other)
+            .set_form(&[("token", &token)])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The token is still active for its client
+        let req = test::TestRequest::post()
+            .uri("/oauth/introspect")
+            .header("Authorization", owner)
+            .set_form(&[("token", &token)])
+            .to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["active"], true);
+        assert_eq!(body["client_id"], "test");
+
+        let events = sink.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].action, AuditAction::Introspection);
+        assert_eq!(events[1].action, AuditAction::Revocation);
+        for event in events.iter() {
+            assert_eq!(event.outcome, Outcome::Failure);
+            assert_eq!(event.client_id.as_deref(), Some("other"));
+            assert_eq!(event.reason.as_deref(), Some(super::DENIED));
+        }
+    }
+}
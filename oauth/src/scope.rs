@@ -0,0 +1,591 @@
+//! OAuth scopes, as space-separated lists of entries.
+//!
+//! Entries are hierarchical, with segments separated by `:`. When checking whether a scope
+//! satisfies a required one, an entry grants a required entry if, in order of precedence:
+//!
+//! 1. it is the full scope `*`, which grants everything;
+//! 2. it is the same entry;
+//! 3. it is an ancestor, like `users` granting `users:read` and `users:read:self`;
+//! 4. it is a wildcard, like `users:*` granting `users:read`, but not `users` itself.
+//!
+//! Nothing else grants an entry: `users:read` grants neither `users:manage` nor `users`.
+//!
+//! Set operations follow the same rules, so that `users` contains `users:read`:
+//!
+//! - [`Scope::union`] keeps the entries of both scopes as they are, without collapsing
+//!   entries granted by others, so `users users:read` stays as is;
+//! - [`Scope::intersection`] keeps the entries of each scope granted by the other, so
+//!   `users:*` and `users:read profile` intersect as `users:read`, and `*` and any scope
+//!   as the latter;
+//! - [`Scope::difference`] keeps the entries not granted by the other scope, so `users`
+//!   minus `users:read` is still `users`, while `users:read profile` minus `users` is `profile`;
+//! - [`Scope::is_subset_of`] tells whether every entry is granted by the other scope.
+//!
+//! Scopes are always serialized as a single space-delimited string, as mandated by OAuth.
+//! Arrays of entries are still accepted on input, as stored by older documents.
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::iter::FromIterator;
+use std::str::FromStr;
+use std::vec::Vec;
+
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+
+#[derive(Clone, Default, Debug)]
+pub struct Scope(HashSet<String>);
+
+impl Scope {
+    /// Returns the entries of this scope granted by the other one, or an InvalidScope error
+    /// if none of them is.
+    /// A full scope always matches everything
+    pub fn matches(&self, other: &Scope) -> Result<Scope> {
+        if self.is_full_scope() {
+            return Ok(other.clone());
+        }
+
+        let granted: Scope = self
+            .iter()
+            .filter(|entry| Scope::from(*entry).is_subset_of(other))
+            .collect();
+        if granted.is_empty() {
+            Err(Error::new(
+                ErrorKind::InvalidScope,
+                "invalid scope".to_string(),
+            ))
+        } else {
+            Ok(granted)
+        }
+    }
+
+    /// Checks if every entry of the scope is granted by the other scope
+    /// A full scope always matches everything
+    pub fn matches_exactly(&self, other: &Scope) -> Result<()> {
+        if self.is_full_scope() {
+            return Ok(());
+        }
+
+        if self.is_subset_of(other) {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidScope,
+                "invalid scope".to_string(),
+            ))
+        }
+    }
+
+    /// Returns true if the scope is a superset of another, i.e., self grants at least all the values in other.
+    pub fn is_superset(&self, other: &Scope) -> bool {
+        other.is_subset_of(self)
+    }
+
+    /// Returns true if every entry of the scope is granted by the other one
+    pub fn is_subset_of(&self, other: &Scope) -> bool {
+        self.iter().all(|entry| other.grants(entry))
+    }
+
+    /// Entries of both scopes
+    pub fn union(&self, other: &Scope) -> Scope {
+        let mut union = self.clone();
+        union.extend(other);
+        union
+    }
+
+    /// Entries of each scope granted by the other one
+    pub fn intersection(&self, other: &Scope) -> Scope {
+        self.iter()
+            .filter(|entry| other.grants(entry))
+            .chain(other.iter().filter(|entry| self.grants(entry)))
+            .collect()
+    }
+
+    /// Entries of the scope not granted by the other one
+    pub fn difference(&self, other: &Scope) -> Scope {
+        self.iter().filter(|entry| !other.grants(entry)).collect()
+    }
+
+    /// Returns true if any entry of the scope grants the required entry
+    pub fn grants(&self, required: &str) -> bool {
+        self.0.iter().any(|entry| grants(entry, required))
+    }
+
+    /// Returns true if the scope is '*', meaning it matches every possible scope
+    pub fn is_full_scope(&self) -> bool {
+        self.0.contains("*")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Entries of the scope, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// Restricts a requested scope to the allowed one.
+    /// An empty scope is granted the full allowed scope, while any entry
+    /// outside of the allowed scope results in an InvalidScope error listing the offending entries.
+    pub fn restrict_to(&self, allowed: &Scope) -> Result<Scope> {
+        if self.is_empty() {
+            return Ok(allowed.clone());
+        }
+
+        if allowed.is_full_scope() {
+            return Ok(self.clone());
+        }
+
+        let mut offending: Vec<&String> = self.0.difference(&allowed.0).collect();
+        if offending.is_empty() {
+            return Ok(self.clone());
+        }
+
+        offending.sort();
+        let offending: Vec<&str> = offending.into_iter().map(String::as_str).collect();
+        Err(Error::new(
+            ErrorKind::InvalidScope,
+            format!("scope not allowed: {}", offending.join(" ")),
+        ))
+    }
+}
+
+fn grants(entry: &str, required: &str) -> bool {
+    if entry == "*" || entry == required {
+        return true;
+    }
+
+    let parent = entry.strip_suffix(":*").unwrap_or(entry);
+    required.len() > parent.len()
+        && required.starts_with(parent)
+        && required[parent.len()..].starts_with(':')
+}
+
+fn parse(scope: &str) -> HashSet<String> {
+    scope
+        .split(' ')
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl From<HashSet<String>> for Scope {
+    fn from(set: HashSet<String>) -> Self {
+        Scope(set)
+    }
+}
+
+impl From<Vec<String>> for Scope {
+    fn from(vec: Vec<String>) -> Self {
+        let set = vec.iter().map(String::clone).collect();
+        Scope(set)
+    }
+}
+
+impl From<Vec<&str>> for Scope {
+    fn from(vec: Vec<&str>) -> Self {
+        Scope(vec.iter().map(|s| (*s).to_string()).collect())
+    }
+}
+
+impl From<String> for Scope {
+    fn from(scope: String) -> Self {
+        Scope(parse(&scope))
+    }
+}
+
+impl From<&str> for Scope {
+    fn from(scope: &str) -> Self {
+        Scope::from(scope.to_string())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Infallible;
+
+    fn from_str(scope: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Scope(parse(scope)))
+    }
+}
+
+impl FromIterator<String> for Scope {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        Scope(iter.into_iter().filter(|s| !s.is_empty()).collect())
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Scope {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        iter.into_iter().map(str::to_string).collect()
+    }
+}
+
+impl Extend<String> for Scope {
+    fn extend<I: IntoIterator<Item = String>>(&mut self, iter: I) {
+        self.0.extend(iter.into_iter().filter(|s| !s.is_empty()));
+    }
+}
+
+impl<'a> Extend<&'a str> for Scope {
+    fn extend<I: IntoIterator<Item = &'a str>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(str::to_string));
+    }
+}
+
+impl<'a> IntoIterator for &'a Scope {
+    type Item = &'a str;
+    type IntoIter =
+        std::iter::Map<std::collections::hash_set::Iter<'a, String>, fn(&String) -> &str>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut vec: Vec<&str> = self.0.iter().map(String::as_str).collect();
+        vec.sort();
+        write!(f, "{}", vec.join(" "))
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<<S as Serializer>::Ok, <S as Serializer>::Error>
+    where
+        S: Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, <D as Deserializer<'de>>::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ScopeVisitor)
+    }
+}
+
+/// Accepts a space-delimited string, or the legacy array of entries
+struct ScopeVisitor;
+
+impl<'de> Visitor<'de> for ScopeVisitor {
+    type Value = Scope;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a space-delimited scope string or an array of scope entries")
+    }
+
+    fn visit_str<E>(self, scope: &str) -> std::result::Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Scope(parse(scope)))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut entries = Vec::new();
+        while let Some(entry) = seq.next_element::<String>()? {
+            entries.extend(parse(&entry));
+        }
+        Ok(entries.into_iter().collect())
+    }
+}
+
+impl PartialEq for Scope {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::super::error::ErrorKind;
+    use super::Scope;
+
+    #[test]
+    fn it_matches_a_similar_scope() {
+        let a = Scope::from("test profile email");
+        let b = Scope::from("email test something");
+
+        let i = a.matches(&b).unwrap();
+        assert_eq!(i.to_string(), "email test");
+    }
+
+    #[test]
+    fn it_does_not_match_a_different_scope() {
+        let a = Scope::from("profile email");
+        let b = Scope::from("test something");
+
+        let i = a.matches(&b).unwrap_err();
+        assert_eq!(i.kind(), &ErrorKind::InvalidScope);
+        assert_eq!(i.to_string(), "\"invalid_scope\": invalid scope");
+    }
+
+    #[test]
+    fn a_full_scope_always_matches_everything() {
+        let a = Scope::from("*");
+        let b = Scope::from("everything should match");
+        assert!(a.is_full_scope());
+        assert!(!b.is_full_scope());
+
+        let i = a.matches(&b).unwrap();
+        assert_eq!(i.to_string(), "everything match should");
+        assert!(a.is_superset(&b));
+        assert!(b.is_subset_of(&a));
+    }
+
+    #[test]
+    fn it_checks_a_subset() {
+        let a = Scope::from("profile email");
+        let b = Scope::from("profile");
+
+        assert!(a.is_superset(&b))
+    }
+
+    #[test]
+    fn it_does_not_check_an_invalid_subset() {
+        let a = Scope::from("profile email");
+        let b = Scope::from("test");
+
+        assert!(!a.is_superset(&b))
+    }
+
+    #[test]
+    fn it_parses_an_empty_scope() {
+        assert!(Scope::from("").is_empty());
+        assert_eq!(Scope::from(" profile  email ").to_string(), "email profile");
+    }
+
+    #[test]
+    fn an_empty_scope_defaults_to_the_allowed_scope() {
+        let allowed = Scope::from("profile email");
+        let requested = Scope::from("");
+
+        let granted = requested.restrict_to(&allowed).unwrap();
+        assert_eq!(granted, allowed);
+    }
+
+    #[test]
+    fn it_restricts_to_an_allowed_subset() {
+        let allowed = Scope::from("profile email");
+        let requested = Scope::from("email");
+
+        let granted = requested.restrict_to(&allowed).unwrap();
+        assert_eq!(granted.to_string(), "email");
+
+        let granted = requested.restrict_to(&Scope::from("*")).unwrap();
+        assert_eq!(granted.to_string(), "email");
+    }
+
+    #[test]
+    fn it_lists_the_offending_scope_entries() {
+        let allowed = Scope::from("profile email");
+        let requested = Scope::from("profile users:manage clients:manage");
+
+        let err = requested.restrict_to(&allowed).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+        assert_eq!(
+            err.description(),
+            "scope not allowed: clients:manage users:manage"
+        );
+    }
+
+    #[test]
+    fn a_parent_scope_grants_its_children() {
+        let token = Scope::from("users");
+        assert_eq!(
+            Scope::from("users:read")
+                .matches(&token)
+                .unwrap()
+                .to_string(),
+            "users:read"
+        );
+        assert!(Scope::from("users:manage").matches(&token).is_ok());
+        assert!(Scope::from("users:read:self").matches(&token).is_ok());
+        assert!(Scope::from("users").matches(&token).is_ok());
+    }
+
+    #[test]
+    fn a_wildcard_scope_grants_children_but_not_the_parent() {
+        let token = Scope::from("users:*");
+        assert!(Scope::from("users:read").matches(&token).is_ok());
+        assert!(Scope::from("users:manage").matches(&token).is_ok());
+        assert!(Scope::from("users:*").matches(&token).is_ok());
+        assert!(Scope::from("users").matches(&token).is_err());
+    }
+
+    #[test]
+    fn a_full_token_scope_grants_everything() {
+        let token = Scope::from("*");
+        assert!(Scope::from("users:read").matches(&token).is_ok());
+        assert!(Scope::from(vec!["users:manage", "roles"])
+            .matches_exactly(&token)
+            .is_ok());
+    }
+
+    #[test]
+    fn siblings_and_children_do_not_grant_other_scopes() {
+        let token = Scope::from("users:read");
+        let err = Scope::from("users:manage").matches(&token).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidScope);
+        assert!(Scope::from("users").matches(&token).is_err());
+        assert!(Scope::from("users:*").matches(&token).is_err());
+        assert!(Scope::from("profile")
+            .matches(&Scope::from("users"))
+            .is_err());
+    }
+
+    #[test]
+    fn it_does_not_treat_prefixes_as_parents() {
+        assert!(Scope::from("users:read")
+            .matches(&Scope::from("user"))
+            .is_err());
+        assert!(Scope::from("usersettings")
+            .matches(&Scope::from("users"))
+            .is_err());
+        assert!(Scope::from("usersettings:read")
+            .matches(&Scope::from("users:*"))
+            .is_err());
+    }
+
+    #[test]
+    fn it_requires_every_entry_to_be_granted_exactly() {
+        let token = Scope::from("users:* roles");
+        assert!(Scope::from(vec!["users:manage", "roles"])
+            .matches_exactly(&token)
+            .is_ok());
+        assert!(Scope::from(vec!["users:read", "permissions"])
+            .matches_exactly(&token)
+            .is_err());
+        assert!(token.is_superset(&Scope::from("users:read roles")));
+        assert!(!token.is_superset(&Scope::from("users roles")));
+    }
+
+    #[test]
+    fn it_unites_entries_as_they_are() {
+        let union = Scope::from("users").union(&Scope::from("users:read profile"));
+        assert_eq!(union.to_string(), "profile users users:read");
+        assert_eq!(Scope::default().union(&Scope::from("*")).to_string(), "*");
+
+        let mut extended = Scope::from("profile");
+        extended.extend(vec!["email", ""]);
+        extended.extend(vec!["roles".to_string()]);
+        assert_eq!(extended.to_string(), "email profile roles");
+    }
+
+    #[test]
+    fn it_intersects_the_entries_granted_by_each_other() {
+        let scope = Scope::from("users:read profile");
+        assert_eq!(
+            Scope::from("users:*").intersection(&scope).to_string(),
+            "users:read"
+        );
+        assert_eq!(
+            scope.intersection(&Scope::from("users")).to_string(),
+            "users:read"
+        );
+        assert_eq!(Scope::from("*").intersection(&scope), scope);
+        assert_eq!(scope.intersection(&Scope::from("*")), scope);
+        assert_eq!(
+            Scope::from("users")
+                .intersection(&Scope::from("users:*"))
+                .to_string(),
+            "users:*"
+        );
+        assert!(Scope::from("users:*")
+            .intersection(&Scope::from("users"))
+            .is_subset_of(&Scope::from("users:*")));
+        assert!(Scope::from("profile")
+            .intersection(&Scope::from("email"))
+            .is_empty());
+    }
+
+    #[test]
+    fn it_removes_the_entries_granted_by_the_other() {
+        assert_eq!(
+            Scope::from("users:read profile")
+                .difference(&Scope::from("users"))
+                .to_string(),
+            "profile"
+        );
+        assert_eq!(
+            Scope::from("users")
+                .difference(&Scope::from("users:read"))
+                .to_string(),
+            "users"
+        );
+        assert_eq!(
+            Scope::from("users users:read")
+                .difference(&Scope::from("users:*"))
+                .to_string(),
+            "users"
+        );
+        assert!(Scope::from("profile")
+            .difference(&Scope::from("*"))
+            .is_empty());
+        assert_eq!(
+            Scope::from("*")
+                .difference(&Scope::from("profile"))
+                .to_string(),
+            "*"
+        );
+    }
+
+    #[test]
+    fn it_checks_subsets_with_wildcards_and_parents() {
+        assert!(Scope::from("users:read users:manage")
+            .is_subset_of(&Scope::from("users:*")));
+        assert!(Scope::from("users:read:self").is_subset_of(&Scope::from("users")));
+        assert!(!Scope::from("users").is_subset_of(&Scope::from("users:*")));
+        assert!(Scope::from("anything at all").is_subset_of(&Scope::from("*")));
+        assert!(!Scope::from("*").is_subset_of(&Scope::from("users")));
+        assert!(Scope::default().is_subset_of(&Scope::default()));
+    }
+
+    #[test]
+    fn it_serializes_as_a_space_delimited_string() {
+        let scope = Scope::from(vec!["profile", "email"]);
+        assert_eq!(
+            serde_json::to_value(&scope).unwrap(),
+            json!("email profile")
+        );
+        assert_eq!(serde_json::to_value(Scope::default()).unwrap(), json!(""));
+    }
+
+    #[test]
+    fn it_deserializes_strings_and_legacy_arrays() {
+        let scope: Scope = serde_json::from_value(json!("profile email")).unwrap();
+        assert_eq!(scope, Scope::from("email profile"));
+
+        let legacy: Scope = serde_json::from_value(json!(["profile", "email", ""])).unwrap();
+        assert_eq!(legacy, scope);
+
+        assert!(serde_json::from_value::<Scope>(json!(42)).is_err());
+    }
+
+    #[test]
+    fn it_parses_and_iterates_entries() {
+        let scope: Scope = "users:read  profile".parse().unwrap();
+        let mut entries: Vec<&str> = (&scope).into_iter().collect();
+        entries.sort();
+        assert_eq!(entries, vec!["profile", "users:read"]);
+
+        let collected: Scope = entries.into_iter().collect();
+        assert_eq!(collected, scope);
+    }
+}
@@ -0,0 +1,124 @@
+//! Generation of the secret values of authorization codes, tokens and the other credentials
+//! the handler hands out. Values are drawn from a [`SecretGenerator`], the operating system's
+//! random number generator by default, and rendered as unpadded base64url so that they can be
+//! used in URLs and form bodies as they are.
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+use enseada::secure::{self, SecureSecret};
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+
+/// Fewest random bytes a secret can be made of
+pub const MIN_LENGTH: usize = 32;
+
+/// Source of the random bytes of secrets, which tests can replace with deterministic values
+pub trait SecretGenerator: Send + Sync {
+    fn generate(&self, length: usize) -> Result<SecureSecret>;
+}
+
+/// Reads secrets from the random number generator of the operating system
+pub struct OsRandom;
+
+impl SecretGenerator for OsRandom {
+    fn generate(&self, length: usize) -> Result<SecureSecret> {
+        secure::generate_token(length).map_err(Error::from)
+    }
+}
+
+/// Generates secrets of a fixed length
+#[derive(Clone)]
+pub struct Secrets {
+    generator: Arc<dyn SecretGenerator>,
+    length: usize,
+}
+
+impl Secrets {
+    /// Fails if the length is below [`MIN_LENGTH`]
+    pub fn new(generator: Arc<dyn SecretGenerator>, length: usize) -> Result<Self> {
+        if length < MIN_LENGTH {
+            return Err(Error::new(
+                ErrorKind::ServerError,
+                format!("secrets must be at least {} bytes long", MIN_LENGTH),
+            ));
+        }
+        Ok(Secrets { generator, length })
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn generate(&self) -> Result<SecureSecret> {
+        self.generator.generate(self.length)
+    }
+}
+
+impl Default for Secrets {
+    fn default() -> Self {
+        Secrets {
+            generator: Arc::new(OsRandom),
+            length: MIN_LENGTH,
+        }
+    }
+}
+
+impl Debug for Secrets {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets")
+            .field("length", &self.length)
+            .finish()
+    }
+}
+
+/// Renders a secret as unpadded base64url
+pub fn encode(secret: &SecureSecret) -> String {
+    base64::encode_config(secret.as_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+
+    /// Generates secrets filled with an increasing byte
+    struct Sequence(AtomicU8);
+
+    impl SecretGenerator for Sequence {
+        fn generate(&self, length: usize) -> Result<SecureSecret> {
+            let byte = self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(SecureSecret::new(vec![byte; length]))
+        }
+    }
+
+    #[test]
+    fn it_enforces_the_minimum_length() {
+        assert!(Secrets::new(Arc::new(OsRandom), MIN_LENGTH - 1).is_err());
+        let secrets = Secrets::new(Arc::new(OsRandom), 48).unwrap();
+        assert_eq!(secrets.generate().unwrap().len(), 48);
+    }
+
+    #[test]
+    fn it_generates_unique_url_safe_secrets() {
+        let secrets = Secrets::default();
+        let mut seen = HashSet::new();
+        for _ in 0..10_000 {
+            let secret = encode(&secrets.generate().unwrap());
+            assert_eq!(secret.len(), 43);
+            assert!(secret
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+            assert!(seen.insert(secret));
+        }
+    }
+
+    #[test]
+    fn it_uses_the_injected_generator() {
+        let secrets = Secrets::new(Arc::new(Sequence(AtomicU8::new(0))), MIN_LENGTH).unwrap();
+        assert_eq!(encode(&secrets.generate().unwrap()), "A".repeat(43));
+        assert_eq!(encode(&secrets.generate().unwrap()), "AQEB".repeat(10) + "AQE");
+    }
+}
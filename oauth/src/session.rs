@@ -0,0 +1,187 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::binding::Origin;
+use crate::device::Device;
+use crate::scope::Scope;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Session {
+    client_id: String,
+    scope: Scope,
+    user_id: Option<String>,
+    /// Device the user signed in with, when the session was established
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device: Option<Device>,
+    /// Device that requested the tokens of the session, usually the client itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    requester: Option<Device>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    /// When the user authenticated, as opposed to when the session was issued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth_time: Option<DateTime<Utc>>,
+    /// Where the tokens of the session can be used from, see [`crate::binding`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<Origin>,
+    /// When the token of the session was issued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    issued_at: Option<DateTime<Utc>>,
+    /// When the token of the session expires. Sessions stored by earlier versions have none,
+    /// and never expire by themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// Resource servers the tokens of the session are meant for, see [`crate::resource`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    resources: Vec<String>,
+    /// ID of the user acting as the user of the session, when it impersonates them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    impersonator: Option<String>,
+}
+
+impl Session {
+    pub fn for_client(client_id: String) -> Session {
+        Session {
+            client_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn client_id(&self) -> &String {
+        &self.client_id
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    pub fn set_scope(&mut self, scope: Scope) -> &mut Self {
+        self.scope = scope;
+        self
+    }
+
+    pub fn user_id(&self) -> &Option<String> {
+        &self.user_id
+    }
+
+    pub fn set_user_id(&mut self, user_id: String) -> &mut Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    pub fn device(&self) -> Option<&Device> {
+        self.device.as_ref()
+    }
+
+    pub fn set_device(&mut self, device: Device) -> &mut Self {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn requester(&self) -> Option<&Device> {
+        self.requester.as_ref()
+    }
+
+    pub fn set_requester(&mut self, requester: Device) -> &mut Self {
+        self.requester = Some(requester);
+        self
+    }
+
+    pub fn nonce(&self) -> Option<&str> {
+        self.nonce.as_deref()
+    }
+
+    pub fn set_nonce(&mut self, nonce: Option<String>) -> &mut Self {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn auth_time(&self) -> Option<&DateTime<Utc>> {
+        self.auth_time.as_ref()
+    }
+
+    pub fn set_auth_time(&mut self, auth_time: Option<DateTime<Utc>>) -> &mut Self {
+        self.auth_time = auth_time;
+        self
+    }
+
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    pub fn set_origin(&mut self, origin: Option<Origin>) -> &mut Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn issued_at(&self) -> Option<&DateTime<Utc>> {
+        self.issued_at.as_ref()
+    }
+
+    pub fn set_issued_at(&mut self, issued_at: Option<DateTime<Utc>>) -> &mut Self {
+        self.issued_at = issued_at;
+        self
+    }
+
+    pub fn expires_at(&self) -> Option<&DateTime<Utc>> {
+        self.expires_at.as_ref()
+    }
+
+    pub fn set_expires_at(&mut self, expires_at: Option<DateTime<Utc>>) -> &mut Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn resources(&self) -> &[String] {
+        &self.resources
+    }
+
+    pub fn set_resources(&mut self, resources: Vec<String>) -> &mut Self {
+        self.resources = resources;
+        self
+    }
+
+    pub fn impersonator(&self) -> Option<&str> {
+        self.impersonator.as_deref()
+    }
+
+    pub fn set_impersonator(&mut self, impersonator: Option<String>) -> &mut Self {
+        self.impersonator = impersonator;
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_never_expires_sessions_stored_without_an_expiration() {
+        let session: Session = serde_json::from_value(json!({
+            "client_id": "test",
+            "scope": "profile",
+            "user_id": null,
+        }))
+        .unwrap();
+        assert_eq!(session.issued_at(), None);
+        assert_eq!(session.expires_at(), None);
+        assert!(!session.is_expired());
+    }
+
+    #[test]
+    fn it_expires_past_its_expiration() {
+        let mut session = Session::for_client("test".to_string());
+        session.set_expires_at(Some(Utc::now() + Duration::minutes(5)));
+        assert!(!session.is_expired());
+        session.set_expires_at(Some(Utc::now() - Duration::seconds(1)));
+        assert!(session.is_expired());
+    }
+}
@@ -0,0 +1,121 @@
+//! Access tokens as JWTs signed by the server (RFC 9068), so that resource servers can validate
+//! them offline, for instance with a trust bundle of the keys of the server.
+//!
+//! The handler issues opaque tokens unless a [`TokenSigner`] is set. Signed tokens are still
+//! stored like opaque ones, by the signature of their whole value, so that they can be
+//! introspected and revoked, while their `jti` is the random secret an opaque token would be made of.
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::session::Session;
+use crate::Result;
+
+/// Signs the claims of access tokens, typically with a rotating key set of the server
+#[async_trait]
+pub trait TokenSigner: Send + Sync {
+    /// Issuer named by the tokens
+    fn issuer(&self) -> String;
+    /// Compact JWS of the claims
+    async fn sign(&self, claims: &AccessTokenClaims) -> Result<String>;
+}
+
+/// User acting as the subject of a token, see [`Session::impersonator`]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Actor {
+    pub sub: String,
+}
+
+/// Claims of a JWT access token
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    /// The user of the session, or the client itself without one
+    pub sub: String,
+    /// Resources the token is meant for, or the issuer when it was not restricted
+    pub aud: Vec<String>,
+    pub client_id: String,
+    pub scope: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+}
+
+impl AccessTokenClaims {
+    /// Claims of a token of the session, which must have been issued
+    pub fn new(issuer: String, session: &Session, jti: String) -> Self {
+        let timestamp = |time: Option<&DateTime<Utc>>| time.map_or(0, DateTime::timestamp);
+        let aud = if session.resources().is_empty() {
+            vec![issuer.clone()]
+        } else {
+            session.resources().to_vec()
+        };
+        AccessTokenClaims {
+            iss: issuer,
+            sub: session
+                .user_id()
+                .clone()
+                .unwrap_or_else(|| session.client_id().clone()),
+            aud,
+            client_id: session.client_id().clone(),
+            scope: session.scope().to_string(),
+            iat: timestamp(session.issued_at()),
+            exp: timestamp(session.expires_at()),
+            jti,
+            act: session.impersonator().map(|sub| Actor {
+                sub: sub.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use crate::scope::Scope;
+
+    use super::*;
+
+    #[test]
+    fn it_describes_the_session() {
+        let issued_at = Utc::now();
+        let mut session = Session::for_client("cli".to_string());
+        session
+            .set_user_id("user:jdoe".to_string())
+            .set_scope(Scope::from("profile"))
+            .set_issued_at(Some(issued_at))
+            .set_expires_at(Some(issued_at + Duration::minutes(5)));
+        let claims = AccessTokenClaims::new(
+            "https://enseada.example.com".to_string(),
+            &session,
+            "secret".to_string(),
+        );
+        assert_eq!(claims.sub, "user:jdoe");
+        assert_eq!(claims.aud, vec!["https://enseada.example.com".to_string()]);
+        assert_eq!(claims.scope, "profile");
+        assert_eq!(claims.exp - claims.iat, 300);
+        assert_eq!(claims.act, None);
+
+        session
+            .set_resources(vec!["https://api.example.com".to_string()])
+            .set_impersonator(Some("user:root".to_string()));
+        let claims = AccessTokenClaims::new(
+            "https://enseada.example.com".to_string(),
+            &session,
+            "secret".to_string(),
+        );
+        assert_eq!(claims.aud, vec!["https://api.example.com".to_string()]);
+        assert_eq!(claims.act.unwrap().sub, "user:root");
+
+        let session = Session::for_client("cli".to_string());
+        let claims = AccessTokenClaims::new(
+            "https://enseada.example.com".to_string(),
+            &session,
+            "secret".to_string(),
+        );
+        assert_eq!(claims.sub, "cli");
+    }
+}
@@ -0,0 +1,85 @@
+use async_trait::async_trait;
+use enseada::pagination::{Cursor, Page};
+
+use crate::bootstrap::BootstrapToken;
+use crate::client::{Client, ClientFilter, ClientStats};
+use crate::code::AuthorizationCode;
+use crate::device_code::DeviceAuthorization;
+use crate::par::PushedRequest;
+use crate::token::Token;
+use crate::Result;
+
+#[async_trait]
+pub trait ClientStorage: Send + Sync {
+    async fn list_clients(
+        &self,
+        filter: &ClientFilter,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<Client>>;
+    async fn get_client(&self, id: &str) -> Option<Client>;
+    async fn save_client(&self, client: Client) -> Result<Client>;
+    async fn delete_client(&self, client: &Client) -> Result<()>;
+    async fn client_stats(&self, client_id: &str) -> Result<ClientStats>;
+}
+
+#[async_trait]
+pub trait TokenStorage<T: Token>: Send + Sync {
+    async fn get_token(&self, sig: &str) -> Option<T>;
+    async fn store_token(&self, sig: &str, token: T) -> Result<T>;
+    async fn revoke_token(&self, sig: &str) -> Result<()>;
+    /// Returns true if the token exists but was revoked, as opposed to never issued.
+    /// Revoked tokens are not returned by [`get_token`](TokenStorage::get_token).
+    async fn is_revoked(&self, sig: &str) -> bool;
+}
+
+#[async_trait]
+pub trait AuthorizationCodeStorage: Send + Sync {
+    async fn get_code(&self, sig: &str) -> Option<AuthorizationCode>;
+    async fn store_code(&self, sig: &str, code: AuthorizationCode) -> Result<AuthorizationCode>;
+    async fn revoke_code(&self, sig: &str) -> Result<()>;
+}
+
+#[async_trait]
+pub trait BootstrapTokenStorage: Send + Sync {
+    async fn get_bootstrap_token(&self, sig: &str) -> Option<BootstrapToken>;
+    async fn store_bootstrap_token(
+        &self,
+        sig: &str,
+        token: BootstrapToken,
+    ) -> Result<BootstrapToken>;
+    /// Removes the token, returning false if it was already removed, by a concurrent call or otherwise
+    async fn take_bootstrap_token(&self, sig: &str) -> Result<bool>;
+}
+
+#[async_trait]
+pub trait PushedRequestStorage: Send + Sync {
+    async fn get_pushed_request(&self, sig: &str) -> Option<PushedRequest>;
+    async fn store_pushed_request(&self, sig: &str, req: PushedRequest) -> Result<PushedRequest>;
+    /// Removes the request, returning false if it was already removed, by a concurrent call or otherwise
+    async fn take_pushed_request(&self, sig: &str) -> Result<bool>;
+}
+
+#[async_trait]
+pub trait DeviceAuthorizationStorage: Send + Sync {
+    async fn get_device_authorization(&self, sig: &str) -> Option<DeviceAuthorization>;
+    /// Finds the authorization with the given user code, along with the signature of its device code
+    async fn find_device_authorization(
+        &self,
+        user_code: &str,
+    ) -> Option<(String, DeviceAuthorization)>;
+    /// Stores the authorization, failing if it was updated since it was read
+    async fn store_device_authorization(
+        &self,
+        sig: &str,
+        authorization: DeviceAuthorization,
+    ) -> Result<DeviceAuthorization>;
+    /// Removes the authorization, returning false if it was already removed, by a concurrent call or otherwise
+    async fn take_device_authorization(&self, sig: &str) -> Result<bool>;
+}
+
+#[async_trait]
+pub trait DeviceStorage: Send + Sync {
+    /// Records a device family for the user, returning true if it was not known before
+    async fn add_device_family(&self, user_id: &str, family: &str) -> Result<bool>;
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::ops::Add;
 
 use chrono::{DateTime, Duration, Utc};
@@ -5,11 +6,12 @@ use serde::{Deserialize, Serialize};
 
 use enseada::secure::SecureSecret;
 
-use crate::oauth::scope::Scope;
-use crate::oauth::session::Session;
-use crate::oauth::Expirable;
+use crate::scope::Scope;
+use crate::secrets;
+use crate::session::Session;
+use crate::Expirable;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum TokenTypeHint {
     AccessToken,
@@ -24,6 +26,7 @@ pub trait Token: ToString + Expirable {
     fn type_hint(&self) -> TokenTypeHint;
 }
 
+#[derive(Clone)]
 pub struct AccessToken {
     token_rep: Option<SecureSecret>,
     session: Session,
@@ -40,7 +43,7 @@ impl AccessToken {
     }
 
     pub fn scope(&self) -> &Scope {
-        &self.session.scope()
+        self.session.scope()
     }
 }
 
@@ -58,9 +61,9 @@ impl Token for AccessToken {
     }
 }
 
-impl ToString for AccessToken {
-    fn to_string(&self) -> String {
-        self.token().to_string()
+impl fmt::Display for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", secrets::encode(&self.token()))
     }
 }
 
@@ -80,6 +83,7 @@ impl Expirable for AccessToken {
     }
 }
 
+#[derive(Clone)]
 pub struct RefreshToken {
     token_rep: Option<SecureSecret>,
     session: Session,
@@ -102,7 +106,7 @@ impl RefreshToken {
         }
     }
     pub fn scope(&self) -> &Scope {
-        &self.session.scope()
+        self.session.scope()
     }
 
     pub fn related_access_token_signature(&self) -> &str {
@@ -124,9 +128,9 @@ impl Token for RefreshToken {
     }
 }
 
-impl ToString for RefreshToken {
-    fn to_string(&self) -> String {
-        self.token().to_string()
+impl fmt::Display for RefreshToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", secrets::encode(&self.token()))
     }
 }
 
@@ -0,0 +1,136 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of characters of the raw User-Agent header that are kept
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+
+const UNKNOWN: &str = "Other";
+
+/// Coarse description of the software making a request, parsed from the `User-Agent` header.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct UserAgent {
+    raw: String,
+    family: String,
+    platform: String,
+}
+
+impl UserAgent {
+    /// Parses a raw User-Agent header. Any input is accepted:
+    /// unrecognized values are classified as "Other" and the raw value is sanitized and truncated.
+    pub fn parse(raw: &str) -> Self {
+        let raw: String = raw
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_USER_AGENT_LENGTH)
+            .collect();
+        let family = family(&raw).to_string();
+        let platform = platform(&raw).to_string();
+        UserAgent {
+            raw,
+            family,
+            platform,
+        }
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn family(&self) -> &str {
+        &self.family
+    }
+
+    pub fn platform(&self) -> &str {
+        &self.platform
+    }
+}
+
+impl From<&HttpRequest> for UserAgent {
+    fn from(req: &HttpRequest) -> Self {
+        let raw = req
+            .headers()
+            .get(header::USER_AGENT)
+            .map(|ua| String::from_utf8_lossy(ua.as_bytes()).to_string())
+            .unwrap_or_default();
+        UserAgent::parse(&raw)
+    }
+}
+
+// Order matters, as most browsers advertise the engines they are compatible with
+fn family(ua: &str) -> &'static str {
+    let families = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("FxiOS/", "Firefox"),
+        ("CriOS/", "Chrome"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+        ("Wget/", "Wget"),
+        ("okhttp/", "OkHttp"),
+        ("docker/", "Docker"),
+    ];
+    families
+        .iter()
+        .find(|(token, _)| ua.contains(token))
+        .map_or(UNKNOWN, |(_, family)| family)
+}
+
+fn platform(ua: &str) -> &'static str {
+    let platforms = [
+        ("Windows", "Windows"),
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iOS"),
+        ("Mac OS X", "macOS"),
+        ("Macintosh", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ];
+    platforms
+        .iter()
+        .find(|(token, _)| ua.contains(token))
+        .map_or(UNKNOWN, |(_, platform)| platform)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UserAgent, MAX_USER_AGENT_LENGTH};
+
+    #[test]
+    fn it_parses_a_browser_user_agent() {
+        let ua = UserAgent::parse(
+            "Mozilla/5.0 (X11; Linux x86_64; rv:76.0) Gecko/20100101 Firefox/76.0",
+        );
+        assert_eq!(ua.family(), "Firefox");
+        assert_eq!(ua.platform(), "Linux");
+
+        let ua = UserAgent::parse("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/83.0.4103.61 Safari/537.36 Edg/83.0.478.37");
+        assert_eq!(ua.family(), "Edge");
+        assert_eq!(ua.platform(), "Windows");
+
+        let ua = UserAgent::parse("Mozilla/5.0 (iPhone; CPU iPhone OS 13_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/13.1.1 Mobile/15E148 Safari/604.1");
+        assert_eq!(ua.family(), "Safari");
+        assert_eq!(ua.platform(), "iOS");
+    }
+
+    #[test]
+    fn it_survives_garbage() {
+        let ua = UserAgent::parse("\u{0}\u{7f}💥;;(((/");
+        assert_eq!(ua.family(), "Other");
+        assert_eq!(ua.platform(), "Other");
+        assert_eq!(ua.raw(), "💥;;(((/");
+
+        let ua = UserAgent::parse("");
+        assert_eq!(ua.family(), "Other");
+    }
+
+    #[test]
+    fn it_truncates_the_raw_user_agent() {
+        let raw = "é".repeat(MAX_USER_AGENT_LENGTH * 4);
+        let ua = UserAgent::parse(&raw);
+        assert_eq!(ua.raw().chars().count(), MAX_USER_AGENT_LENGTH);
+    }
+}
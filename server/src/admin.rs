@@ -0,0 +1,211 @@
+//! Administrative commands, run with `enseada-server admin <command>` instead of the server.
+//! They work on CouchDB directly, so they can be used before anything else is set up.
+use std::io;
+use std::sync::Arc;
+
+use enseada::error::Error;
+
+use crate::config::CONFIG;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::couchdb::{name as dbname, SINGLETON};
+use crate::jobs;
+use crate::oauth::handler::OAuthHandler;
+use crate::oauth::keys::{SigningKeys, RELOAD_INTERVAL};
+use crate::oauth::persistence::CouchStorage;
+use crate::oauth::scope::Scope;
+use crate::oauth::session::Session;
+use crate::oauth::storage::ClientStorage;
+use crate::user::UserService;
+
+const USAGE: &str = "usage: enseada-server admin bootstrap-token <username> <client_id> [scope...]
+       enseada-server admin run-job <name>
+       enseada-server admin export-trust-bundle
+       enseada-server admin rotate-signing-key";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Mints a single-use token the holder exchanges at `/oauth/bootstrap` for a token set
+    BootstrapToken {
+        username: String,
+        client_id: String,
+        scope: Scope,
+    },
+    /// Runs a maintenance job in the foreground, see [`crate::jobs`]
+    RunJob { name: String },
+    /// Prints the trust bundle of the current signing keys, see [`crate::oauth::keys`]
+    ExportTrustBundle,
+    /// Replaces the primary signing key, keeping the current one to verify the tokens it signed
+    RotateSigningKey,
+}
+
+impl Command {
+    pub fn parse(args: &[String]) -> Result<Command, String> {
+        match args {
+            [command, username, client_id, scope @ ..] if command == "bootstrap-token" => {
+                Ok(Command::BootstrapToken {
+                    username: username.clone(),
+                    client_id: client_id.clone(),
+                    scope: Scope::from(scope.join(" ").as_str()),
+                })
+            }
+            [command, name] if command == "run-job" => Ok(Command::RunJob { name: name.clone() }),
+            [command] if command == "export-trust-bundle" => Ok(Command::ExportTrustBundle),
+            [command] if command == "rotate-signing-key" => Ok(Command::RotateSigningKey),
+            _ => Err(USAGE.to_string()),
+        }
+    }
+}
+
+pub async fn run(args: &[String]) -> io::Result<()> {
+    let command =
+        Command::parse(args).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let result = match command {
+        Command::BootstrapToken {
+            username,
+            client_id,
+            scope,
+        } => bootstrap_token(&username, &client_id, scope).await,
+        Command::RunJob { name } => run_job(&name).await,
+        Command::ExportTrustBundle => export_trust_bundle().await,
+        Command::RotateSigningKey => rotate_signing_key().await,
+    };
+    result.map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+}
+
+async fn bootstrap_token(username: &str, client_id: &str, scope: Scope) -> Result<(), Error> {
+    let bootstrap = CONFIG.oauth().bootstrap();
+    if !bootstrap.enabled() {
+        return Err(Error::from(
+            "bootstrap tokens are disabled, set ENSEADA_OAUTH_BOOTSTRAP_ENABLED=true to enable them",
+        ));
+    }
+
+    let users = UserService::new(SINGLETON.database(dbname::USERS, true));
+    let user = users
+        .find(username)
+        .await?
+        .ok_or_else(|| Error::from(format!("user {} not found", username)))?;
+
+    let db = Arc::new(SINGLETON.database(dbname::OAUTH, true));
+    let storage = Arc::new(CouchStorage::new(db));
+    let client = storage
+        .get_client(client_id)
+        .await
+        .ok_or_else(|| Error::from(format!("client '{}' not found", client_id)))?;
+    let scope = scope
+        .restrict_to(client.allowed_scopes())
+        .map_err(|err| Error::from(err.description()))?;
+
+    let mut handler = OAuthHandler::new(
+        storage.clone(),
+        storage.clone(),
+        storage.clone(),
+        storage.clone(),
+        CONFIG.secret_key(),
+    );
+    handler.set_bootstrap_storage(storage);
+
+    let mut session = Session::for_client(client_id.to_string());
+    session
+        .set_user_id(user.id().to_string())
+        .set_scope(scope.clone());
+    let ttl =
+        chrono::Duration::from_std(bootstrap.ttl()).map_err(|err| Error::from(err.to_string()))?;
+    let token = handler
+        .issue_bootstrap_token(session, ttl)
+        .await
+        .map_err(|err| Error::from(err.description()))?;
+
+    log::info!(
+        "Issued bootstrap token for user {} and client '{}' with scope '{}', valid for {}s",
+        user.id(),
+        client_id,
+        scope,
+        ttl.num_seconds()
+    );
+    println!("{}", token);
+    Ok(())
+}
+
+async fn run_job(name: &str) -> Result<(), Error> {
+    let job = jobs::registry()
+        .job(name)
+        .ok_or_else(|| Error::not_found("job", name))?;
+    job.run().await?;
+    log::info!("Job {} completed", name);
+    Ok(())
+}
+
+async fn export_trust_bundle() -> Result<(), Error> {
+    let bundle = SigningKeys::from_config().export_bundle().await?;
+    println!("{}", bundle);
+    Ok(())
+}
+
+async fn rotate_signing_key() -> Result<(), Error> {
+    let kid = SigningKeys::from_config().rotate().await?;
+    log::info!(
+        "Signing key {} is now the primary key. Running instances switch to it within {}s, \
+        export a fresh trust bundle once they did",
+        kid,
+        RELOAD_INTERVAL.as_secs()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn it_parses_bootstrap_token() {
+        let command = Command::parse(&args(&[
+            "bootstrap-token",
+            "ci",
+            "cli",
+            "profile",
+            "users:read",
+        ]))
+        .unwrap();
+        assert_eq!(
+            command,
+            Command::BootstrapToken {
+                username: "ci".to_string(),
+                client_id: "cli".to_string(),
+                scope: Scope::from("profile users:read"),
+            }
+        );
+    }
+
+    #[test]
+    fn it_parses_run_job() {
+        let command = Command::parse(&args(&["run-job", "token-purge"])).unwrap();
+        assert_eq!(
+            command,
+            Command::RunJob {
+                name: "token-purge".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn it_parses_the_key_commands() {
+        let command = Command::parse(&args(&["export-trust-bundle"])).unwrap();
+        assert_eq!(command, Command::ExportTrustBundle);
+        let command = Command::parse(&args(&["rotate-signing-key"])).unwrap();
+        assert_eq!(command, Command::RotateSigningKey);
+    }
+
+    #[test]
+    fn it_rejects_unknown_commands() {
+        assert!(Command::parse(&args(&[])).is_err());
+        assert!(Command::parse(&args(&["bootstrap-token", "ci"])).is_err());
+        assert!(Command::parse(&args(&["reset", "ci", "cli"])).is_err());
+        assert!(Command::parse(&args(&["run-job"])).is_err());
+        assert!(Command::parse(&args(&["export-trust-bundle", "now"])).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+
+/// Maximum length of the summary sent in the announcement header
+const SUMMARY_LENGTH: usize = 256;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn css_class(&self) -> &'static str {
+        match self {
+            Severity::Info => "is-info",
+            Severity::Warning => "is-warning",
+            Severity::Critical => "is-danger",
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        };
+        write!(f, "{}", severity)
+    }
+}
+
+/// A message shown to every user during a time window, like a scheduled maintenance.
+///
+/// Announcements are identified by the start of their window, so that setting one again
+/// for the same window replaces it. Expired ones are kept for history.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Announcement {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    message: String,
+    severity: Severity,
+    starts_at: DateTime<Utc>,
+    ends_at: DateTime<Utc>,
+}
+
+impl Announcement {
+    pub fn new(
+        message: String,
+        severity: Severity,
+        starts_at: DateTime<Utc>,
+        ends_at: DateTime<Utc>,
+    ) -> Self {
+        Announcement {
+            id: Self::build_guid(&Self::window_id(&starts_at)),
+            rev: None,
+            message,
+            severity,
+            starts_at,
+            ends_at,
+        }
+    }
+
+    /// ID of the announcement starting at the given time, sorting announcements by their start
+    pub fn window_id(starts_at: &DateTime<Utc>) -> String {
+        starts_at.format("%Y%m%dT%H%M%SZ").to_string()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn starts_at(&self) -> &DateTime<Utc> {
+        &self.starts_at
+    }
+
+    pub fn ends_at(&self) -> &DateTime<Utc> {
+        &self.ends_at
+    }
+
+    pub fn is_active_at(&self, now: &DateTime<Utc>) -> bool {
+        self.starts_at <= *now && *now < self.ends_at
+    }
+
+    pub fn is_expired_at(&self, now: &DateTime<Utc>) -> bool {
+        self.ends_at <= *now
+    }
+
+    /// Human readable window, as shown in the banner of HTML pages
+    pub fn window(&self) -> String {
+        format!(
+            "{} to {}",
+            self.starts_at.format("%Y-%m-%d %H:%M UTC"),
+            self.ends_at.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+
+    /// Single line summary fit for an HTTP header, replacing any non-ASCII character
+    pub fn summary(&self) -> String {
+        let summary = format!(
+            "{}; starts={}; ends={}; {}",
+            self.severity,
+            self.starts_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.ends_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.message
+        );
+        summary
+            .chars()
+            .map(|c| match c {
+                c if c.is_ascii_whitespace() => ' ',
+                c if c.is_ascii() && !c.is_ascii_control() => c,
+                _ => '?',
+            })
+            .take(SUMMARY_LENGTH)
+            .collect()
+    }
+}
+
+impl Entity for Announcement {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::ANNOUNCEMENT, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
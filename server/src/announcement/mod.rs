@@ -0,0 +1,184 @@
+use actix_web::dev::{Payload, PayloadStream, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::web::Data;
+use actix_web::{Error, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use futures::Future;
+
+pub use entity::{Announcement, Severity};
+pub use routes::mount;
+pub use service::{AnnouncementCache, AnnouncementService};
+
+mod entity;
+mod routes;
+mod service;
+pub mod watcher;
+
+pub const ANNOUNCEMENT_HEADER: &str = "x-enseada-announcement";
+
+/// Announcement active while serving the request, shown as a banner on HTML pages
+pub struct Banner(Option<Announcement>);
+
+impl Banner {
+    pub fn current(req: &HttpRequest) -> Self {
+        let cache = req.app_data::<Data<AnnouncementCache>>();
+        Banner(cache.and_then(|cache| cache.current()))
+    }
+
+    pub fn into_inner(self) -> Option<Announcement> {
+        self.0
+    }
+}
+
+impl FromRequest for Banner {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        ready(Ok(Banner::current(req)))
+    }
+}
+
+/// Middleware attaching a summary of the active announcement to API responses
+pub fn announcement_header<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let announcement = if req.path().starts_with("/api/") {
+        req.app_data::<AnnouncementCache>()
+            .and_then(|cache| cache.current())
+    } else {
+        None
+    };
+    let fut = srv.call(req);
+    async move {
+        let mut res = fut.await?;
+        if let Some(announcement) = announcement {
+            if let Ok(value) = HeaderValue::from_str(&announcement.summary()) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(ANNOUNCEMENT_HEADER), value);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, web, App, HttpResponse};
+    use askama::Template;
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::templates::Index;
+
+    use super::*;
+
+    fn maintenance(severity: Severity, from: i64, to: i64) -> Announcement {
+        let now = Utc::now();
+        Announcement::new(
+            "Upgrading CouchDB".to_string(),
+            severity,
+            now + Duration::hours(from),
+            now + Duration::hours(to),
+        )
+    }
+
+    fn cache(announcements: Vec<Announcement>) -> AnnouncementCache {
+        let cache = AnnouncementCache::default();
+        cache.replace(announcements);
+        cache
+    }
+
+    fn render(cache: &AnnouncementCache) -> String {
+        Index {
+            name: None,
+            announcement: cache.current(),
+        }
+        .render()
+        .unwrap()
+    }
+
+    #[test]
+    fn it_renders_the_banner_inside_the_window() {
+        let html = render(&cache(vec![maintenance(Severity::Warning, -1, 1)]));
+        assert!(html.contains("notification is-warning"));
+        assert!(html.contains("Upgrading CouchDB"));
+    }
+
+    #[test]
+    fn it_hides_the_banner_outside_the_window() {
+        let html = render(&cache(vec![
+            maintenance(Severity::Warning, -2, -1),
+            maintenance(Severity::Critical, 1, 2),
+        ]));
+        assert!(!html.contains("notification"));
+        assert!(!html.contains("Upgrading CouchDB"));
+    }
+
+    #[test]
+    fn it_shows_the_most_severe_announcement() {
+        let cache = cache(vec![
+            maintenance(Severity::Critical, -1, 1),
+            maintenance(Severity::Info, 0, 1),
+        ]);
+        assert_eq!(cache.current().unwrap().severity(), Severity::Critical);
+    }
+
+    #[test]
+    fn it_summarizes_announcements_for_headers() {
+        let announcement = Announcement::new(
+            "Mise à jour\nde CouchDB".to_string(),
+            Severity::Info,
+            Utc.ymd(2020, 5, 1).and_hms(10, 0, 0),
+            Utc.ymd(2020, 5, 1).and_hms(12, 0, 0),
+        );
+        assert_eq!(
+            announcement.summary(),
+            "info; starts=2020-05-01T10:00:00Z; ends=2020-05-01T12:00:00Z; Mise ? jour de CouchDB"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_sets_the_header_on_api_responses() {
+        let mut app = test::init_service(
+            App::new()
+                .data(cache(vec![maintenance(Severity::Warning, -1, 1)]))
+                .wrap_fn(announcement_header)
+                .route("/api/v1beta1/users", web::get().to(HttpResponse::Ok))
+                .route("/ui", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1beta1/users")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        let header = res.headers().get(ANNOUNCEMENT_HEADER).unwrap();
+        assert!(header.to_str().unwrap().starts_with("warning; starts="));
+
+        let req = test::TestRequest::get().uri("/ui").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res.headers().get(ANNOUNCEMENT_HEADER).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn it_omits_the_header_outside_the_window() {
+        let mut app = test::init_service(
+            App::new()
+                .data(cache(vec![maintenance(Severity::Warning, -2, -1)]))
+                .wrap_fn(announcement_header)
+                .route("/api/v1beta1/users", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1beta1/users")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res.headers().get(ANNOUNCEMENT_HEADER).is_none());
+    }
+}
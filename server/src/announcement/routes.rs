@@ -0,0 +1,171 @@
+use actix_web::web::{Data, Json, Query, ServiceConfig};
+use actix_web::{get, put};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use enseada::guid::Guid;
+use enseada::pagination::{Cursor, Page};
+
+use crate::announcement::{Announcement, AnnouncementService, Severity};
+use crate::couchdb::repository::{Entity, Repository};
+use crate::http::error::ApiError;
+use crate::http::extractor::{scope::Scope, user::CurrentUser};
+use crate::http::{ApiResult, PaginationQuery};
+use crate::rbac::Enforcer;
+
+/// Maximum length of the message of an announcement
+const MESSAGE_LENGTH: usize = 500;
+
+pub fn mount(cfg: &mut ServiceConfig) {
+    let couch = &crate::couchdb::SINGLETON;
+    let db = couch.database(crate::couchdb::name::SYSTEM, true);
+    cfg.data(AnnouncementService::new(db));
+    cfg.service(list);
+    cfg.service(put);
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AnnouncementResponse {
+    pub id: String,
+    pub message: String,
+    pub severity: Severity,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub expired: bool,
+}
+
+impl From<&Announcement> for AnnouncementResponse {
+    fn from(announcement: &Announcement) -> Self {
+        AnnouncementResponse {
+            id: announcement.id().id().to_string(),
+            message: announcement.message().to_string(),
+            severity: announcement.severity(),
+            starts_at: *announcement.starts_at(),
+            ends_at: *announcement.ends_at(),
+            expired: announcement.is_expired_at(&Utc::now()),
+        }
+    }
+}
+
+#[get("/api/v1beta1/admin/announcements")]
+pub async fn list(
+    service: Data<AnnouncementService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    list: Query<PaginationQuery>,
+) -> ApiResult<Json<Page<AnnouncementResponse>>> {
+    Scope::from("system:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("announcements")?, "read")?;
+    let limit = list.limit();
+    let cursor = list.cursor();
+
+    let cursor = if let Some(cursor) = cursor {
+        Some(Cursor::from_b64(cursor)?)
+    } else {
+        None
+    };
+
+    let page = service
+        .list(limit, cursor.as_ref())
+        .await?
+        .map(|announcement| AnnouncementResponse::from(announcement));
+    Ok(Json(page))
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct AnnouncementRequest {
+    pub message: String,
+    pub severity: Severity,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+}
+
+impl AnnouncementRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut reasons = Vec::new();
+        if self.message.trim().is_empty() {
+            reasons.push("message must not be empty".to_string());
+        }
+        if self.message.chars().count() > MESSAGE_LENGTH {
+            reasons.push(format!(
+                "message must be at most {} characters long",
+                MESSAGE_LENGTH
+            ));
+        }
+        if self.ends_at <= self.starts_at {
+            reasons.push("ends_at must be later than starts_at".to_string());
+        }
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::ValidationError(reasons))
+        }
+    }
+}
+
+/// Sets the announcement for a window, replacing the one starting at the same time, if any
+#[put("/api/v1beta1/admin/announcements")]
+pub async fn put(
+    service: Data<AnnouncementService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    data: Json<AnnouncementRequest>,
+) -> ApiResult<Json<AnnouncementResponse>> {
+    Scope::from("system:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("announcements")?, "manage")?;
+    data.validate()?;
+
+    let data = data.into_inner();
+    let mut announcement =
+        Announcement::new(data.message, data.severity, data.starts_at, data.ends_at);
+    let existing = service
+        .find(&Announcement::window_id(&data.starts_at))
+        .await?;
+    if let Some(rev) = existing.as_ref().and_then(Entity::rev) {
+        announcement.set_rev(rev.to_string());
+    }
+
+    let announcement = service.save(announcement).await?;
+    log::info!(
+        "User {} set a {} announcement for {}",
+        current_user.id(),
+        announcement.severity(),
+        announcement.window()
+    );
+    Ok(Json(AnnouncementResponse::from(&announcement)))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn request(message: &str, hours: i64) -> AnnouncementRequest {
+        let starts_at = Utc::now();
+        AnnouncementRequest {
+            message: message.to_string(),
+            severity: Severity::Warning,
+            starts_at,
+            ends_at: starts_at + Duration::hours(hours),
+        }
+    }
+
+    #[test]
+    fn it_validates_announcements() {
+        assert!(request("Upgrading CouchDB", 2).validate().is_ok());
+
+        match request(" ", 0).validate() {
+            Err(ApiError::ValidationError(reasons)) => assert_eq!(reasons.len(), 2),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(request(&"a".repeat(MESSAGE_LENGTH + 1), 2)
+            .validate()
+            .is_err());
+    }
+}
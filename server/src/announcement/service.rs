@@ -0,0 +1,88 @@
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+
+use crate::announcement::Announcement;
+use crate::couchdb::partition;
+use crate::couchdb::repository::Repository;
+
+/// Upper bound of announcements not yet expired, there should only ever be a handful
+const PENDING_LIMIT: usize = 100;
+
+pub struct AnnouncementService {
+    db: Database,
+}
+
+#[async_trait]
+impl Repository<Announcement> for AnnouncementService {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl AnnouncementService {
+    pub fn new(db: Database) -> AnnouncementService {
+        AnnouncementService { db }
+    }
+
+    /// Announcements that are active or scheduled at the given time
+    pub async fn pending_at(&self, now: &DateTime<Utc>) -> Result<Vec<Announcement>, Error> {
+        let selector = serde_json::json!({
+            "ends_at": {
+                "$gt": now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            }
+        });
+        let res = self
+            .db
+            .find_partitioned::<Announcement>(
+                partition::ANNOUNCEMENT,
+                selector,
+                PENDING_LIMIT,
+                None,
+            )
+            .await?;
+        if let Some(warning) = &res.warning {
+            log::warn!("{}", warning);
+        }
+        Ok(res.docs)
+    }
+}
+
+/// Announcements that are active or scheduled, kept in memory so that serving the current one
+/// never needs a lookup. It is refreshed from the changes feed of the database.
+#[derive(Default)]
+pub struct AnnouncementCache {
+    pending: RwLock<Vec<Announcement>>,
+}
+
+impl AnnouncementCache {
+    pub fn replace(&self, pending: Vec<Announcement>) {
+        *self.pending.write().unwrap() = pending;
+    }
+
+    pub async fn reload(&self, service: &AnnouncementService) -> Result<(), Error> {
+        let pending = service.pending_at(&Utc::now()).await?;
+        log::debug!("Loaded {} pending announcements", pending.len());
+        self.replace(pending);
+        Ok(())
+    }
+
+    pub fn current(&self) -> Option<Announcement> {
+        self.current_at(&Utc::now())
+    }
+
+    /// The most severe announcement active at the given time, the latest one among equals
+    pub fn current_at(&self, now: &DateTime<Utc>) -> Option<Announcement> {
+        self.pending
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|announcement| announcement.is_active_at(now))
+            .max_by_key(|announcement| (announcement.severity(), *announcement.starts_at()))
+            .cloned()
+    }
+}
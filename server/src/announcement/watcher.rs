@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use enseada::error::Error;
+
+use crate::announcement::{AnnouncementCache, AnnouncementService};
+use crate::jobs::changes::ChangeListener;
+
+/// Reloads the cached announcements when any document of the system database changes
+pub struct Watcher {
+    service: Arc<AnnouncementService>,
+    cache: Arc<AnnouncementCache>,
+}
+
+impl Watcher {
+    pub fn new(service: Arc<AnnouncementService>, cache: Arc<AnnouncementCache>) -> Self {
+        Watcher { service, cache }
+    }
+}
+
+#[async_trait]
+impl ChangeListener for Watcher {
+    fn name(&self) -> &str {
+        "announcements"
+    }
+
+    async fn on_change(&self, _id: &str, _deleted: bool) -> Result<(), Error> {
+        log::trace!("Received change event from database. Reloading announcements");
+        self.cache
+            .reload(&self.service)
+            .await
+            .map_err(Error::from)
+    }
+}
@@ -0,0 +1,171 @@
+use std::net::IpAddr;
+
+use chrono::serde::ts_milliseconds;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+use crate::oauth::audit::{AuditAction, AuditEvent, Outcome};
+
+/// An audited OAuth event, as stored in the audit database.
+///
+/// Records are identified by their timestamp and a random suffix, so they sort chronologically,
+/// and are never updated.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditRecord {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    #[serde(with = "ts_milliseconds")]
+    timestamp: DateTime<Utc>,
+    action: AuditAction,
+    outcome: Outcome,
+    user_id: Option<String>,
+    client_id: Option<String>,
+    remote_ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    impersonator: Option<String>,
+    /// Set when free-form fields were cut short to fit in a document
+    #[serde(default, skip_serializing_if = "is_false")]
+    truncated: bool,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent, remote_ip: Option<IpAddr>, timestamp: DateTime<Utc>) -> Self {
+        let id = format!(
+            "{}-{}",
+            timestamp.format("%Y%m%dT%H%M%S%.3fZ"),
+            Uuid::new_v4().to_simple()
+        );
+        AuditRecord {
+            id: Self::build_guid(&id),
+            rev: None,
+            timestamp,
+            action: event.action,
+            outcome: event.outcome,
+            user_id: event.user_id,
+            client_id: event.client_id,
+            remote_ip,
+            target: event.target,
+            reason: event.reason,
+            impersonator: event.impersonator,
+            truncated: false,
+        }
+    }
+
+    pub fn timestamp(&self) -> &DateTime<Utc> {
+        &self.timestamp
+    }
+
+    pub fn action(&self) -> AuditAction {
+        self.action
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    pub fn remote_ip(&self) -> Option<IpAddr> {
+        self.remote_ip
+    }
+
+    /// User the action was performed on, when it is not the acting user
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    /// User acting as the user of the record, when they impersonated them
+    pub fn impersonator(&self) -> Option<&str> {
+        self.impersonator.as_deref()
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+pub(super) fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl Entity for AuditRecord {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::EVENT, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_sorts_records_chronologically() {
+        let event = AuditEvent::failure(AuditAction::Login, "authentication failed".to_string());
+        let earlier = AuditRecord::new(event.clone(), None, Utc.ymd(2020, 5, 1).and_hms(9, 59, 59));
+        let later = AuditRecord::new(event, None, Utc.ymd(2020, 5, 1).and_hms(10, 0, 0));
+        assert!(earlier.id().to_string() < later.id().to_string());
+        assert!(earlier
+            .id()
+            .to_string()
+            .starts_with("event:20200501T095959.000Z-"));
+    }
+
+    #[test]
+    fn it_stores_the_timestamp_in_milliseconds() {
+        let event = AuditEvent::success(AuditAction::TokenIssuance)
+            .set_client_id(Some("enseada".to_string()))
+            .set_user_id(Some("user:jdoe".to_string()));
+        let ip = "10.0.0.1".parse().unwrap();
+        let timestamp = Utc.ymd(2020, 5, 1).and_hms_milli(10, 0, 0, 250);
+        let record = AuditRecord::new(event, Some(ip), timestamp);
+
+        let doc = serde_json::to_value(&record).unwrap();
+        assert_eq!(doc["timestamp"], json!(1588327200250i64));
+        assert_eq!(doc["action"], "token_issuance");
+        assert_eq!(doc["outcome"], "success");
+        assert_eq!(doc["remote_ip"], "10.0.0.1");
+        assert!(doc.get("reason").is_none());
+        assert!(doc.get("impersonator").is_none());
+
+        let parsed: AuditRecord = serde_json::from_value(doc).unwrap();
+        assert_eq!(parsed.timestamp(), &timestamp);
+        assert_eq!(parsed.user_id(), Some("user:jdoe"));
+    }
+}
@@ -0,0 +1,11 @@
+//! Audit log of OAuth security events, like logins and token issuance.
+//!
+//! Events reported by the OAuth flows are written to the `audit` database in the background,
+//! and listed to holders of the `audit:read` scope.
+mod entity;
+mod routes;
+mod service;
+
+pub use entity::AuditRecord;
+pub use routes::*;
+pub use service::{AuditLog, AuditService};
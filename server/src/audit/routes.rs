@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use actix_web::get;
+use actix_web::web::{Data, Json, Query, ServiceConfig};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use enseada::guid::Guid;
+use enseada::pagination::{Cursor, Page};
+
+use crate::audit::{AuditLog, AuditRecord, AuditService};
+use crate::couchdb::repository::Entity;
+use crate::http::error::ApiError;
+use crate::http::extractor::{scope::Scope, user::CurrentUser};
+use crate::http::{ApiResult, PaginationQuery};
+use crate::oauth::audit::{AuditAction, AuditSink, Outcome};
+use crate::rbac::Enforcer;
+
+pub fn mount(cfg: &mut ServiceConfig) {
+    let couch = &crate::couchdb::SINGLETON;
+    let db = couch.database(crate::couchdb::name::AUDIT, true);
+    let mut service = AuditService::new(db.clone());
+    service.set_size_guard(crate::couchdb::SIZE_GUARD.clone());
+    let sink: Arc<dyn AuditSink> = Arc::new(AuditLog::new(Arc::new(service)));
+    cfg.data(sink);
+    cfg.data(AuditService::new(db));
+    cfg.service(list);
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AuditRecordResponse {
+    pub timestamp: String,
+    pub action: AuditAction,
+    pub outcome: Outcome,
+    pub user_id: Option<String>,
+    pub client_id: Option<String>,
+    pub remote_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
+    /// Whether the reason or target were cut short to fit in the log
+    #[serde(skip_serializing_if = "super::entity::is_false")]
+    pub truncated: bool,
+}
+
+impl From<&AuditRecord> for AuditRecordResponse {
+    fn from(record: &AuditRecord) -> Self {
+        AuditRecordResponse {
+            timestamp: record
+                .timestamp()
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            action: record.action(),
+            outcome: record.outcome(),
+            user_id: record.user_id().map(str::to_string),
+            client_id: record.client_id().map(str::to_string),
+            remote_ip: record.remote_ip().map(|ip| ip.to_string()),
+            target: record.target().map(str::to_string),
+            reason: record.reason().map(str::to_string),
+            impersonator: record.impersonator().map(str::to_string),
+            truncated: record.is_truncated(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+impl AuditQuery {
+    fn validate(&self) -> ApiResult<()> {
+        match (&self.from, &self.to) {
+            (Some(from), Some(to)) if from >= to => Err(ApiError::BadRequest(
+                "'from' must be earlier than 'to'".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[get("/api/v1beta1/audit")]
+pub async fn list(
+    service: Data<AuditService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    list: Query<PaginationQuery>,
+    query: Query<AuditQuery>,
+) -> ApiResult<Json<Page<AuditRecordResponse>>> {
+    Scope::from("audit:read").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("audit")?, "read")?;
+    query.validate()?;
+
+    let limit = list.limit();
+    let cursor = if let Some(cursor) = list.cursor() {
+        Some(Cursor::from_b64(cursor)?)
+    } else {
+        None
+    };
+
+    let page = service
+        .search(
+            query.from.as_ref(),
+            query.to.as_ref(),
+            limit,
+            cursor.as_ref(),
+        )
+        .await?;
+    Ok(Json(page.map(|record| AuditRecordResponse::from(record))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query(from: Option<&str>, to: Option<&str>) -> AuditQuery {
+        let parse = |date: &str| {
+            DateTime::parse_from_rfc3339(date)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        AuditQuery {
+            from: from.map(parse),
+            to: to.map(parse),
+        }
+    }
+
+    #[test]
+    fn it_rejects_empty_time_ranges() {
+        assert!(
+            query(Some("2020-05-01T00:00:00Z"), Some("2020-05-02T00:00:00Z"))
+                .validate()
+                .is_ok()
+        );
+        assert!(query(Some("2020-05-01T00:00:00Z"), None).validate().is_ok());
+        assert!(query(None, None).validate().is_ok());
+        assert!(query(
+            Some("2020-05-02T00:00:00Z"),
+            Some("2020-05-01T00:00:00+02:00")
+        )
+        .validate()
+        .is_err());
+    }
+}
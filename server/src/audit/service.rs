@@ -0,0 +1,198 @@
+use std::sync::Arc;
+
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+use couchdb::size::SizeGuard;
+use enseada::pagination::{Cursor, Page};
+
+use crate::audit::AuditRecord;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::http::client_addr::ClientAddr;
+use crate::http::extractor::session::Impersonator;
+use crate::oauth::audit::{AuditEvent, AuditSink};
+
+/// Free-form fields of records, cut short in this order when a record is too large
+const TRUNCATED_FIELDS: &[&str] = &["reason", "target"];
+
+pub struct AuditService {
+    db: Database,
+    size_guard: Arc<SizeGuard>,
+}
+
+#[async_trait]
+impl Repository<AuditRecord> for AuditService {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl AuditService {
+    pub fn new(db: Database) -> AuditService {
+        AuditService {
+            db,
+            size_guard: Arc::new(SizeGuard::default()),
+        }
+    }
+
+    /// Sets the guard keeping records under the maximum document size,
+    /// by truncating their free-form fields if they are too large
+    pub fn set_size_guard(&mut self, size_guard: Arc<SizeGuard>) -> &mut Self {
+        self.size_guard = size_guard;
+        self
+    }
+
+    /// Writes the record, truncated if it does not fit in a document.
+    /// Fails with a `413` error if it does not fit even truncated.
+    pub async fn record(&self, record: &AuditRecord) -> Result<(), Error> {
+        let doc = self.document(record)?;
+        self.db.put(&record.id().to_string(), &doc).await?;
+        Ok(())
+    }
+
+    fn document(&self, record: &AuditRecord) -> Result<serde_json::Value, Error> {
+        let mut doc =
+            serde_json::to_value(record).map_err(|err| Error::internal(err.to_string()))?;
+        let id = record.id().to_string();
+        self.size_guard.check(&id, &mut doc, TRUNCATED_FIELDS)?;
+        Ok(doc)
+    }
+
+    /// Records in the time range, oldest first. Either bound can be left open.
+    pub async fn search(
+        &self,
+        from: Option<&DateTime<Utc>>,
+        to: Option<&DateTime<Utc>>,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<AuditRecord>, Error> {
+        let res = self
+            .db
+            .find_partitioned::<AuditRecord>(
+                "event",
+                time_range_selector(from, to),
+                limit,
+                cursor.map(Cursor::to_string),
+            )
+            .await?;
+        if let Some(warning) = &res.warning {
+            log::warn!("{}", warning);
+        }
+        Ok(Page::from_find_response(res, limit))
+    }
+}
+
+/// Selects records by timestamp, including the lower bound and excluding the upper one
+fn time_range_selector(
+    from: Option<&DateTime<Utc>>,
+    to: Option<&DateTime<Utc>>,
+) -> serde_json::Value {
+    let mut range = serde_json::Map::new();
+    // Always constrained, so that the timestamp index is used and records come out in order
+    range.insert(
+        "$gte".to_string(),
+        serde_json::json!(from.map_or(0, DateTime::timestamp_millis)),
+    );
+    if let Some(to) = to {
+        range.insert("$lt".to_string(), serde_json::json!(to.timestamp_millis()));
+    }
+    serde_json::json!({ "timestamp": range })
+}
+
+/// Persists audit events in the background, so that a failing write never fails the audited flow
+pub struct AuditLog {
+    service: Arc<AuditService>,
+}
+
+impl AuditLog {
+    pub fn new(service: Arc<AuditService>) -> Self {
+        AuditLog { service }
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&self, req: &HttpRequest, mut event: AuditEvent) {
+        if event.impersonator.is_none() {
+            event.impersonator = Impersonator::of(req);
+        }
+        let remote_ip = ClientAddr::from(req).ip();
+        let record = AuditRecord::new(event, remote_ip, Utc::now());
+        let service = self.service.clone();
+        actix_rt::spawn(async move {
+            if let Err(err) = service.record(&record).await {
+                log::error!("Failed to write audit record: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use serde_json::json;
+    use url::Url;
+
+    use crate::oauth::audit::AuditAction;
+
+    use super::*;
+
+    fn service(guard: Arc<SizeGuard>) -> AuditService {
+        // Never queried, documents are only built
+        let couch = couchdb::Couch::new(
+            Url::parse("http://localhost:5984").unwrap(),
+            "admin".to_string(),
+            "admin".to_string(),
+        );
+        let mut service = AuditService::new(couch.database("audit", true));
+        service.set_size_guard(guard);
+        service
+    }
+
+    fn failure(reason: String) -> AuditRecord {
+        let event = AuditEvent::failure(AuditAction::Login, reason)
+            .set_user_id(Some("user:jdoe".to_string()));
+        AuditRecord::new(event, None, Utc.ymd(2020, 5, 1).and_hms(10, 0, 0))
+    }
+
+    #[test]
+    fn it_truncates_the_reason_of_oversized_records() {
+        let guard = Arc::new(SizeGuard::new(1000));
+        let service = service(guard.clone());
+        let doc = service.document(&failure("x".repeat(2000))).unwrap();
+        assert_eq!(doc["truncated"], true);
+        assert_eq!(guard.truncations(), 1);
+        assert!(doc["reason"].as_str().unwrap().starts_with('x'));
+        assert!(serde_json::to_vec(&doc).unwrap().len() <= 1000);
+        assert_eq!(doc["user_id"], "user:jdoe");
+        let parsed: AuditRecord = serde_json::from_value(doc).unwrap();
+        assert!(parsed.is_truncated());
+
+        let doc = service.document(&failure("too bad".to_string())).unwrap();
+        assert!(doc.get("truncated").is_none());
+        assert_eq!(doc["reason"], "too bad");
+    }
+
+    #[test]
+    fn it_rejects_records_too_large_when_truncated() {
+        let service = service(Arc::new(SizeGuard::new(50)));
+        let err = service.document(&failure("x".repeat(2000))).unwrap_err();
+        assert!(err.is_too_large());
+    }
+
+    #[test]
+    fn it_selects_records_in_the_time_range() {
+        let from = Utc.ymd(2020, 5, 1).and_hms(0, 0, 0);
+        let to = Utc.ymd(2020, 5, 2).and_hms(0, 0, 0);
+        assert_eq!(
+            time_range_selector(Some(&from), Some(&to)),
+            json!({ "timestamp": { "$gte": 1588291200000i64, "$lt": 1588377600000i64 } })
+        );
+        assert_eq!(
+            time_range_selector(None, None),
+            json!({ "timestamp": { "$gte": 0 } })
+        );
+    }
+}
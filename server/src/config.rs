@@ -1,7 +1,22 @@
+use std::str::FromStr;
+use std::time::Duration;
+
 use config::{Config, ConfigError, Environment};
+use ipnet::IpNet;
+use lettre::message::Mailbox;
 use serde::Deserialize;
 use url::Url;
 
+use couchdb::client::{AuthMethod, Pool, Timeouts};
+use enseada::secure::HashParams;
+
+use crate::http::client_addr::ForwardedHeader;
+use crate::http::urls::UrlBuilder;
+use crate::oauth::error::ErrorDocs;
+use crate::oauth::resource::{self, ResourceRegistry};
+use crate::user::auth;
+use crate::user::password::{self, PasswordPolicy};
+
 #[derive(Debug, Deserialize)]
 pub struct Configuration {
     port: i16,
@@ -11,6 +26,15 @@ pub struct Configuration {
     public: Public,
     secret: Secret,
     root: Root,
+    proxy: Proxy,
+    oauth: OAuth,
+    quota: Quota,
+    users: Users,
+    password: Password,
+    mail: Mail,
+    auth: Auth,
+    ldap: Ldap,
+    sso: Sso,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,6 +49,50 @@ pub struct CouchDB {
     url: Option<String>,
     username: Option<String>,
     password: Option<String>,
+    document: CouchDocument,
+    attachment: CouchAttachment,
+    timeout: CouchTimeout,
+    auth: CouchAuth,
+    pool: CouchPool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CouchDocument {
+    limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct CouchAttachment {
+    limit: usize,
+}
+
+/// Connections kept open to CouchDB, by default suited to a single node: 32 idle connections
+/// kept for 90 seconds, probed every 60 seconds, over HTTP/1.1
+#[derive(Debug, Deserialize)]
+struct CouchPool {
+    /// Idle connections kept open, at least one
+    size: usize,
+    /// Seconds an idle connection is kept open
+    idle: u64,
+    /// Seconds between TCP keep-alive probes, 0 to disable them
+    keepalive: u64,
+    /// Speaks HTTP/2 right away, for a proxy in front of CouchDB, which only speaks HTTP/1.1
+    http2: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CouchAuth {
+    /// Sends basic authentication on every request instead of opening a session, for debugging
+    basic: bool,
+}
+
+/// Timeouts of the requests to CouchDB, in seconds
+#[derive(Debug, Deserialize)]
+struct CouchTimeout {
+    connect: u64,
+    request: u64,
+    /// For the changes feed and migrations
+    long: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,6 +110,8 @@ struct WithOptionalPath {
 #[derive(Debug, Deserialize)]
 struct Public {
     host: Url,
+    /// Path prefix of the reverse proxy we are served under
+    prefix: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,8 +120,175 @@ struct Secret {
 }
 
 #[derive(Debug, Deserialize)]
-struct Root {
-    password: String,
+pub struct Root {
+    username: String,
+    password: Option<String>,
+    forcereset: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Proxy {
+    trusted: Option<String>,
+    header: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OAuth {
+    issuance: Issuance,
+    errors: Errors,
+    bootstrap: Bootstrap,
+    scopes: Scopes,
+    issuer: Issuer,
+    throttle: Throttle,
+    lockout: Lockout,
+    clients: Clients,
+    events: Events,
+    resources: Resources,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Resources {
+    allowed: Option<String>,
+    enforce: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Events {
+    log: bool,
+    webhook: Option<Url>,
+    retries: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Issuer {
+    acceptchange: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Clients {
+    cascade: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Throttle {
+    failures: u32,
+    window: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Lockout {
+    failures: u32,
+    duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Scopes {
+    custom: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Bootstrap {
+    enabled: bool,
+    ttl: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Errors {
+    url: Option<Url>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Issuance {
+    threshold: u32,
+    window: u64,
+    refuse: bool,
+    webhook: Option<Url>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Password {
+    memory: u32,
+    iterations: u32,
+    parallelism: u32,
+    budget: u64,
+    minlength: usize,
+    classes: Option<String>,
+    denylist: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Mail {
+    from: Option<String>,
+    smtp: Smtp,
+    verification: Verification,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Smtp {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Verification {
+    ttl: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Auth {
+    backends: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Ldap {
+    url: Option<String>,
+    binddn: Option<String>,
+    search: LdapSearch,
+    starttls: bool,
+    tls: LdapTls,
+    timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LdapSearch {
+    base: Option<String>,
+    filter: String,
+    attribute: String,
+    binddn: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LdapTls {
+    verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Sso {
+    discovery: Option<Url>,
+    client: SsoClient,
+    scopes: String,
+    register: bool,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SsoClient {
+    id: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Quota {
+    daily: Option<u64>,
+    warning: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Users {
+    retention: u64,
 }
 
 impl Configuration {
@@ -72,9 +309,78 @@ impl Configuration {
         let proto = if c.get_bool("tls.enabled")? { "https" } else { "http" };
         c.set_default("public.host", format!("{}://localhost:{}", proto, port))?;
 
+        c.set_default("public.prefix", None::<String>)?;
         c.set_default("log.level", "info")?;
         c.set_default("log.rootlevel", "warn")?;
         c.set_default("couchdb.url", "http://localhost:5984")?;
+        c.set_default("couchdb.document.limit", couchdb::size::DEFAULT_LIMIT as i64)?;
+        c.set_default("couchdb.attachment.limit", couchdb::attachment::DEFAULT_LIMIT as i64)?;
+        c.set_default("couchdb.timeout.connect", 5)?;
+        c.set_default("couchdb.timeout.request", 30)?;
+        c.set_default("couchdb.timeout.long", 300)?;
+        c.set_default("couchdb.auth.basic", false)?;
+        c.set_default("couchdb.pool.size", 32)?;
+        c.set_default("couchdb.pool.idle", 90)?;
+        c.set_default("couchdb.pool.keepalive", 60)?;
+        c.set_default("couchdb.pool.http2", false)?;
+        c.set_default("root.username", "root")?;
+        c.set_default("root.password", None::<String>)?;
+        c.set_default("root.forcereset", false)?;
+        c.set_default("proxy.trusted", None::<String>)?;
+        c.set_default("proxy.header", "x-forwarded")?;
+        c.set_default("oauth.issuance.threshold", 600)?;
+        c.set_default("oauth.issuance.window", 60)?;
+        c.set_default("oauth.issuance.refuse", false)?;
+        c.set_default("oauth.issuance.webhook", None::<String>)?;
+        c.set_default("oauth.errors.url", None::<String>)?;
+        c.set_default("oauth.bootstrap.enabled", false)?;
+        c.set_default("oauth.bootstrap.ttl", 900)?;
+        c.set_default("oauth.scopes.custom", None::<String>)?;
+        c.set_default("oauth.issuer.acceptchange", false)?;
+        c.set_default("oauth.throttle.failures", 5)?;
+        c.set_default("oauth.throttle.window", 300)?;
+        c.set_default("oauth.lockout.failures", 10)?;
+        c.set_default("oauth.lockout.duration", 900)?;
+        c.set_default("oauth.clients.cascade", false)?;
+        c.set_default("oauth.events.log", false)?;
+        c.set_default("oauth.events.webhook", None::<String>)?;
+        c.set_default("oauth.events.retries", 3)?;
+        c.set_default("oauth.resources.allowed", None::<String>)?;
+        c.set_default("oauth.resources.enforce", false)?;
+        c.set_default("quota.daily", None::<String>)?;
+        c.set_default("quota.warning", 80)?;
+        c.set_default("users.retention", 30)?;
+        let hash_params = HashParams::default();
+        c.set_default("password.memory", hash_params.memory as i64)?;
+        c.set_default("password.iterations", hash_params.iterations as i64)?;
+        c.set_default("password.parallelism", hash_params.parallelism as i64)?;
+        c.set_default("password.budget", 1000)?;
+        c.set_default("password.minlength", password::MIN_LENGTH as i64)?;
+        c.set_default("password.classes", None::<String>)?;
+        c.set_default("password.denylist", true)?;
+        c.set_default("mail.from", None::<String>)?;
+        c.set_default("mail.smtp.host", None::<String>)?;
+        c.set_default("mail.smtp.port", None::<String>)?;
+        c.set_default("mail.smtp.username", None::<String>)?;
+        c.set_default("mail.smtp.password", None::<String>)?;
+        c.set_default("mail.verification.ttl", 86400)?;
+        c.set_default("auth.backends", auth::COUCHDB)?;
+        c.set_default("ldap.url", None::<String>)?;
+        c.set_default("ldap.binddn", None::<String>)?;
+        c.set_default("ldap.search.base", None::<String>)?;
+        c.set_default("ldap.search.filter", "(uid={username})")?;
+        c.set_default("ldap.search.attribute", "uid")?;
+        c.set_default("ldap.search.binddn", None::<String>)?;
+        c.set_default("ldap.search.password", None::<String>)?;
+        c.set_default("ldap.starttls", false)?;
+        c.set_default("ldap.tls.verify", true)?;
+        c.set_default("ldap.timeout", 10)?;
+        c.set_default("sso.discovery", None::<String>)?;
+        c.set_default("sso.client.id", None::<String>)?;
+        c.set_default("sso.client.secret", None::<String>)?;
+        c.set_default("sso.scopes", "openid profile email")?;
+        c.set_default("sso.register", false)?;
+        c.set_default("sso.name", "SSO")?;
 
 
         // Validations
@@ -83,9 +389,141 @@ impl Configuration {
             return Err(ConfigError::Message("insecure secret key, must be at least 32 bytes".to_string()))
         }
 
-        let root_pwd = c.get_str("root.password")?;
-        if root_pwd.len() < 8 {
-            return Err(ConfigError::Message("insecure root password, must be at least 8 characters".to_string()))
+        if c.get_str("root.username")?.trim().is_empty() {
+            return Err(ConfigError::Message("root username must not be empty".to_string()))
+        }
+
+        if let Ok(root_pwd) = c.get_str("root.password") {
+            if root_pwd.len() < 8 {
+                return Err(ConfigError::Message("insecure root password, must be at least 8 characters".to_string()))
+            }
+        }
+
+        let public_host = Url::parse(&c.get_str("public.host")?)
+            .map_err(|err| ConfigError::Message(format!("invalid public host: {}", err)))?;
+        let prefix = c.get_str("public.prefix").ok();
+        UrlBuilder::new(&public_host, prefix.as_deref()).map_err(ConfigError::Message)?;
+
+        if c.get_int("couchdb.document.limit")? < 1 {
+            return Err(ConfigError::Message("couchdb document limit must be positive".to_string()))
+        }
+        if c.get_int("couchdb.attachment.limit")? < 1 {
+            return Err(ConfigError::Message("couchdb attachment limit must be positive".to_string()))
+        }
+        for timeout in &["connect", "request", "long"] {
+            if c.get_int(&format!("couchdb.timeout.{}", timeout))? < 1 {
+                return Err(ConfigError::Message(format!("couchdb {} timeout must be positive", timeout)))
+            }
+        }
+        if c.get_int("couchdb.pool.size")? < 1 || c.get_int("couchdb.pool.idle")? < 1 {
+            return Err(ConfigError::Message("couchdb pool size and idle timeout must be positive".to_string()))
+        }
+        if c.get_int("couchdb.pool.keepalive")? < 0 {
+            return Err(ConfigError::Message("couchdb pool keepalive cannot be negative".to_string()))
+        }
+
+        if let Ok(trusted) = c.get_str("proxy.trusted") {
+            parse_trusted_proxies(&trusted)?;
+        }
+        ForwardedHeader::from_str(&c.get_str("proxy.header")?).map_err(ConfigError::Message)?;
+
+        if c.get_int("oauth.issuance.threshold")? < 1 || c.get_int("oauth.issuance.window")? < 1 {
+            return Err(ConfigError::Message("oauth issuance threshold and window must be positive".to_string()))
+        }
+
+        if c.get_int("oauth.throttle.failures")? < 1 || c.get_int("oauth.throttle.window")? < 1 {
+            return Err(ConfigError::Message("oauth throttle failures and window must be positive".to_string()))
+        }
+
+        if c.get_int("oauth.lockout.failures")? < 1 || c.get_int("oauth.lockout.duration")? < 1 {
+            return Err(ConfigError::Message("oauth lockout failures and duration must be positive".to_string()))
+        }
+
+        if c.get_int("oauth.bootstrap.ttl")? < 1 {
+            return Err(ConfigError::Message("oauth bootstrap token ttl must be positive".to_string()))
+        }
+
+        if let Ok(custom) = c.get_str("oauth.scopes.custom") {
+            parse_custom_scopes(&custom)?;
+        }
+
+        if let Ok(allowed) = c.get_str("oauth.resources.allowed") {
+            parse_resources(&allowed)?;
+        }
+
+        let warning = c.get_int("quota.warning")?;
+        if !(1..=100).contains(&warning) {
+            return Err(ConfigError::Message("quota warning must be a percentage between 1 and 100".to_string()))
+        }
+
+        let iterations = c.get_int("password.iterations")?;
+        let parallelism = c.get_int("password.parallelism")?;
+        if iterations < 1 || parallelism < 1 || c.get_int("password.budget")? < 1 {
+            return Err(ConfigError::Message("password iterations, parallelism and budget must be positive".to_string()))
+        }
+        if c.get_int("password.memory")? < 8 * parallelism {
+            return Err(ConfigError::Message("password memory must be at least 8 KiB per lane of parallelism".to_string()))
+        }
+        let min_length = c.get_int("password.minlength")?;
+        if min_length < password::MIN_LENGTH as i64 || min_length > password::MAX_LENGTH as i64 {
+            return Err(ConfigError::Message(format!("password min length must be between {} and {}", password::MIN_LENGTH, password::MAX_LENGTH)))
+        }
+        if let Ok(classes) = c.get_str("password.classes") {
+            password::parse_classes(&classes).map_err(ConfigError::Message)?;
+        }
+
+        if c.get_str("mail.smtp.host").is_ok() {
+            let from = c.get_str("mail.from")
+                .map_err(|_| ConfigError::Message("mail from address is required to send email".to_string()))?;
+            from.parse::<Mailbox>()
+                .map_err(|err| ConfigError::Message(format!("invalid mail from address: {}", err)))?;
+        }
+        if c.get_str("mail.smtp.username").is_ok() != c.get_str("mail.smtp.password").is_ok() {
+            return Err(ConfigError::Message("mail smtp username and password must be set together".to_string()))
+        }
+        if c.get_int("mail.verification.ttl")? < 1 {
+            return Err(ConfigError::Message("email verification ttl must be positive".to_string()))
+        }
+
+        let backends = parse_backends(&c.get_str("auth.backends")?)?;
+        if backends.iter().any(|backend| backend == auth::LDAP) {
+            let url = c.get_str("ldap.url")
+                .map_err(|_| ConfigError::Message("ldap url is required by the ldap authentication backend".to_string()))?;
+            let url = Url::parse(&url)
+                .map_err(|err| ConfigError::Message(format!("invalid ldap url: {}", err)))?;
+            if url.scheme() != "ldap" && url.scheme() != "ldaps" {
+                return Err(ConfigError::Message("ldap url must use the ldap or ldaps scheme".to_string()))
+            }
+            match (c.get_str("ldap.binddn"), c.get_str("ldap.search.base")) {
+                (Ok(binddn), _) if !binddn.contains("{username}") => {
+                    return Err(ConfigError::Message("ldap bind dn must contain {username}".to_string()))
+                }
+                (Ok(_), _) => {}
+                (Err(_), Ok(_)) if !c.get_str("ldap.search.filter")?.contains("{username}") => {
+                    return Err(ConfigError::Message("ldap search filter must contain {username}".to_string()))
+                }
+                (Err(_), Ok(_)) => {}
+                (Err(_), Err(_)) => {
+                    return Err(ConfigError::Message("ldap bind dn or search base is required by the ldap authentication backend".to_string()))
+                }
+            }
+        }
+        if c.get_str("ldap.search.binddn").is_ok() != c.get_str("ldap.search.password").is_ok() {
+            return Err(ConfigError::Message("ldap search bind dn and password must be set together".to_string()))
+        }
+        if c.get_int("ldap.timeout")? < 1 {
+            return Err(ConfigError::Message("ldap timeout must be positive".to_string()))
+        }
+
+        if let Ok(discovery) = c.get_str("sso.discovery") {
+            Url::parse(&discovery)
+                .map_err(|err| ConfigError::Message(format!("invalid sso discovery url: {}", err)))?;
+            if c.get_str("sso.client.id").is_err() || c.get_str("sso.client.secret").is_err() {
+                return Err(ConfigError::Message("sso client id and secret are required to log in with sso".to_string()))
+            }
+            if !c.get_str("sso.scopes")?.split_whitespace().any(|scope| scope == "openid") {
+                return Err(ConfigError::Message("sso scopes must include openid".to_string()))
+            }
         }
 
         // Deserialize
@@ -100,6 +538,40 @@ impl Configuration {
         &self.public.host
     }
 
+    pub fn public_prefix(&self) -> Option<&str> {
+        self.public.prefix.as_deref()
+    }
+
+    /// Builder of the absolute URLs we hand out, rooted at the public host and prefix
+    pub fn urls(&self) -> UrlBuilder {
+        let mut urls = UrlBuilder::new(self.public_host(), self.public_prefix())
+            .expect("public host is validated on load");
+        urls.set_trusted_proxies(self.proxy().trusted())
+            .set_forwarded_header(self.proxy().header());
+        urls
+    }
+
+    /// Links OAuth errors to their documentation, served by us unless another URL is configured
+    pub fn error_docs(&self) -> ErrorDocs {
+        let url = self
+            .oauth
+            .errors()
+            .url()
+            .cloned()
+            .unwrap_or_else(|| self.urls().url("docs/errors"));
+        ErrorDocs::new(url)
+    }
+
+    /// Resource servers tokens can be requested for, us and the configured ones
+    pub fn resource_registry(&self) -> ResourceRegistry {
+        let mut registry = ResourceRegistry::new();
+        registry.register(self.public_host().clone());
+        for resource in self.oauth.resources().allowed() {
+            registry.register(resource);
+        }
+        registry
+    }
+
     pub fn log(&self) -> &Logging {
         &self.log
     }
@@ -116,8 +588,44 @@ impl Configuration {
         self.secret.key.clone()
     }
 
-    pub fn root_password(&self) -> String {
-        self.root.password.clone()
+    pub fn root(&self) -> &Root {
+        &self.root
+    }
+
+    pub fn proxy(&self) -> &Proxy {
+        &self.proxy
+    }
+
+    pub fn oauth(&self) -> &OAuth {
+        &self.oauth
+    }
+
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    pub fn users(&self) -> &Users {
+        &self.users
+    }
+
+    pub fn password(&self) -> &Password {
+        &self.password
+    }
+
+    pub fn mail(&self) -> &Mail {
+        &self.mail
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    pub fn ldap(&self) -> &Ldap {
+        &self.ldap
+    }
+
+    pub fn sso(&self) -> &Sso {
+        &self.sso
     }
 }
 
@@ -154,6 +662,43 @@ impl CouchDB {
             .expect("missing couchdb.password")
             .clone()
     }
+
+    /// Size in bytes past which free-form fields of documents are truncated before being written
+    pub fn document_limit(&self) -> usize {
+        self.document.limit
+    }
+
+    /// Size in bytes of the largest attachment written or read at once
+    pub fn attachment_limit(&self) -> usize {
+        self.attachment.limit
+    }
+
+    pub fn pool(&self) -> Pool {
+        Pool {
+            max_idle_per_host: self.pool.size,
+            idle_timeout: Duration::from_secs(self.pool.idle),
+            tcp_keepalive: Some(self.pool.keepalive)
+                .filter(|keepalive| *keepalive > 0)
+                .map(Duration::from_secs),
+            http2: self.pool.http2,
+        }
+    }
+
+    pub fn auth_method(&self) -> AuthMethod {
+        if self.auth.basic {
+            AuthMethod::Basic
+        } else {
+            AuthMethod::Session
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(self.timeout.connect),
+            request: Duration::from_secs(self.timeout.request),
+            long: Duration::from_secs(self.timeout.long),
+        }
+    }
 }
 
 impl TLS {
@@ -170,6 +715,456 @@ impl TLS {
     }
 }
 
+impl Root {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Password of the root user, generated when it is created if none is configured
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    /// Whether to apply the password to an existing root user on every boot
+    pub fn force_reset(&self) -> bool {
+        self.forcereset
+    }
+}
+
+impl Proxy {
+    /// Networks of the reverse proxies allowed to set forwarding headers.
+    /// Configured as a comma-separated list of CIDRs or bare IP addresses.
+    pub fn trusted(&self) -> Vec<IpNet> {
+        self.trusted
+            .as_deref()
+            .map(|trusted| parse_trusted_proxies(trusted).unwrap_or_default())
+            .unwrap_or_default()
+    }
+
+    /// Headers the trusted proxies forward the client address with,
+    /// either `forwarded` (RFC 7239) or `x-forwarded` (`X-Forwarded-For` and friends)
+    pub fn header(&self) -> ForwardedHeader {
+        ForwardedHeader::from_str(&self.header).unwrap_or(ForwardedHeader::XForwarded)
+    }
+}
+
+impl OAuth {
+    pub fn issuance(&self) -> &Issuance {
+        &self.issuance
+    }
+
+    pub fn errors(&self) -> &Errors {
+        &self.errors
+    }
+
+    pub fn bootstrap(&self) -> &Bootstrap {
+        &self.bootstrap
+    }
+
+    pub fn scopes(&self) -> &Scopes {
+        &self.scopes
+    }
+
+    pub fn issuer(&self) -> &Issuer {
+        &self.issuer
+    }
+
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    pub fn lockout(&self) -> &Lockout {
+        &self.lockout
+    }
+
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+}
+
+impl Resources {
+    /// Resource servers tokens can be requested for besides us, configured as a comma-separated list of URLs
+    pub fn allowed(&self) -> Vec<Url> {
+        self.allowed
+            .as_deref()
+            .map(|allowed| parse_resources(allowed).unwrap_or_default())
+            .unwrap_or_default()
+    }
+
+    /// Whether the API only accepts OAuth tokens issued for it with the `resource` parameter
+    pub fn enforce(&self) -> bool {
+        self.enforce
+    }
+}
+
+impl Events {
+    /// Whether token events are written to the log
+    pub fn log(&self) -> bool {
+        self.log
+    }
+
+    /// URL token events are POSTed to as JSON
+    pub fn webhook(&self) -> Option<&Url> {
+        self.webhook.as_ref()
+    }
+
+    /// Deliveries retried when the webhook fails, before the event is dropped
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+impl Clients {
+    /// Whether deleting a user deletes their clients, rather than flagging them as orphaned
+    pub fn cascade(&self) -> bool {
+        self.cascade
+    }
+}
+
+impl Issuer {
+    /// Whether to start even if the public host no longer matches the stored issuer
+    pub fn accept_change(&self) -> bool {
+        self.acceptchange
+    }
+}
+
+impl Throttle {
+    /// Failed logins allowed to an address or username within the window
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+}
+
+impl Lockout {
+    /// Consecutive failed logins after which a user is locked
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Time users stay locked for
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration)
+    }
+}
+
+impl Scopes {
+    /// Scopes declared for downstream services, with their descriptions.
+    /// Configured as a comma-separated list of `name=description` pairs.
+    pub fn custom(&self) -> Vec<(String, String)> {
+        self.custom
+            .as_deref()
+            .map(|custom| parse_custom_scopes(custom).unwrap_or_default())
+            .unwrap_or_default()
+    }
+}
+
+impl Bootstrap {
+    /// Whether bootstrap tokens can be minted and exchanged, disabled by default
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// How long a bootstrap token can be exchanged for after it is minted
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+}
+
+impl Errors {
+    /// Base URL of the documentation of OAuth errors, if not served by us
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+}
+
+impl Issuance {
+    /// Token sets a client can be issued within the window before an anomaly is reported
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+
+    /// Whether to refuse issuance while a client is over its threshold
+    pub fn refuse(&self) -> bool {
+        self.refuse
+    }
+
+    /// URL anomalies are POSTed to as JSON
+    pub fn webhook(&self) -> Option<&Url> {
+        self.webhook.as_ref()
+    }
+}
+
+impl Password {
+    /// Cost of hashing new passwords with Argon2id
+    pub fn hash_params(&self) -> HashParams {
+        HashParams {
+            memory: self.memory,
+            iterations: self.iterations,
+            parallelism: self.parallelism,
+        }
+    }
+
+    /// Time hashing a password may take before we warn that logins are slowed down
+    pub fn budget(&self) -> Duration {
+        Duration::from_millis(self.budget)
+    }
+
+    /// Rules new passwords must follow
+    pub fn policy(&self) -> PasswordPolicy {
+        let classes = self
+            .classes
+            .as_deref()
+            .map(|classes| password::parse_classes(classes).unwrap_or_default())
+            .unwrap_or_default();
+        PasswordPolicy::new(self.minlength, classes, self.denylist)
+    }
+}
+
+impl Mail {
+    /// Sender of the emails we send, validated on load when an SMTP relay is set
+    pub fn from(&self) -> Option<Mailbox> {
+        self.from.as_deref().and_then(|from| from.parse().ok())
+    }
+
+    pub fn smtp(&self) -> &Smtp {
+        &self.smtp
+    }
+
+    pub fn verification(&self) -> &Verification {
+        &self.verification
+    }
+}
+
+impl Smtp {
+    /// Relay emails are sent through, none are sent if not set
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Port of the relay, the submission port with implicit TLS by default
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn credentials(&self) -> Option<(String, String)> {
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl Verification {
+    /// How long a link verifying an email address can be followed for
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+}
+
+impl Auth {
+    /// Names of the backends passwords are verified with, in the order they are tried
+    pub fn backends(&self) -> Vec<String> {
+        parse_backends(&self.backends).unwrap_or_default()
+    }
+}
+
+impl Ldap {
+    /// Directory passwords are verified with, required by the ldap backend
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// Template of the DN users bind as, like `uid={username},ou=people,dc=example,dc=com`.
+    /// Users are searched for under the search base if not set.
+    pub fn bind_dn(&self) -> Option<&str> {
+        self.binddn.as_deref()
+    }
+
+    pub fn search(&self) -> &LdapSearch {
+        &self.search
+    }
+
+    /// Whether to upgrade plain connections to TLS
+    pub fn starttls(&self) -> bool {
+        self.starttls
+    }
+
+    /// Whether to verify the certificate of the directory, only to be disabled for testing
+    pub fn verify_tls(&self) -> bool {
+        self.tls.verify
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl LdapSearch {
+    pub fn base(&self) -> Option<&str> {
+        self.base.as_deref()
+    }
+
+    /// Template of the filter matching the entry of a user, like `(sAMAccountName={username})`
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    /// Attribute of the entries holding the username, which the shadow users are named after
+    pub fn attribute(&self) -> &str {
+        &self.attribute
+    }
+
+    /// Account searching for users, anonymous if not set
+    pub fn credentials(&self) -> Option<(String, String)> {
+        match (&self.binddn, &self.password) {
+            (Some(binddn), Some(password)) => Some((binddn.clone(), password.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl Sso {
+    /// OpenID provider users can log in with, as the URL of its discovery document
+    /// or its issuer. Logging in with SSO is disabled if not set.
+    pub fn discovery(&self) -> Option<&Url> {
+        self.discovery.as_ref()
+    }
+
+    /// Credentials of our client at the provider, validated on load when SSO is enabled
+    pub fn credentials(&self) -> Option<(String, String)> {
+        match (&self.client.id, &self.client.secret) {
+            (Some(id), Some(secret)) => Some((id.clone(), secret.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn scopes(&self) -> &str {
+        &self.scopes
+    }
+
+    /// Whether users logging in for the first time get an account, instead of being refused
+    pub fn register(&self) -> bool {
+        self.register
+    }
+
+    /// Name of the provider shown on the login button
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Quota {
+    /// Daily API requests allowed to each user, unlimited if not set
+    pub fn daily(&self) -> Option<u64> {
+        self.daily
+    }
+
+    /// Percentage of a quota past which users are warned
+    pub fn warning(&self) -> u64 {
+        self.warning
+    }
+}
+
+impl Users {
+    /// Time deactivated users are kept before being purged, never if zero
+    pub fn retention(&self) -> Option<chrono::Duration> {
+        Some(self.retention)
+            .filter(|days| *days > 0)
+            .map(|days| chrono::Duration::days(days as i64))
+    }
+}
+
+fn parse_backends(backends: &str) -> Result<Vec<String>, ConfigError> {
+    let mut parsed: Vec<String> = Vec::new();
+    for backend in backends.split(',').map(str::trim) {
+        if backend.is_empty() {
+            continue;
+        }
+        let backend = backend.to_lowercase();
+        if backend != auth::COUCHDB && backend != auth::LDAP {
+            return Err(ConfigError::Message(format!(
+                "unknown authentication backend '{}'",
+                backend
+            )));
+        }
+        if parsed.contains(&backend) {
+            return Err(ConfigError::Message(format!(
+                "authentication backend '{}' is listed twice",
+                backend
+            )));
+        }
+        parsed.push(backend);
+    }
+    if parsed.is_empty() {
+        return Err(ConfigError::Message(
+            "at least one authentication backend is required".to_string(),
+        ));
+    }
+    Ok(parsed)
+}
+
+fn parse_trusted_proxies(trusted: &str) -> Result<Vec<IpNet>, ConfigError> {
+    trusted
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(|cidr| {
+            IpNet::from_str(cidr)
+                .or_else(|_| std::net::IpAddr::from_str(cidr).map(IpNet::from))
+                .map_err(|_| ConfigError::Message(format!("invalid trusted proxy '{}'", cidr)))
+        })
+        .collect()
+}
+
+fn parse_custom_scopes(custom: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    custom
+        .split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .map(|scope| {
+            let (name, description) = match scope.find('=') {
+                Some(i) => (scope[..i].trim(), scope[i + 1..].trim()),
+                None => (scope, ""),
+            };
+            if name.is_empty() || name == "*" || name.contains(char::is_whitespace) {
+                return Err(ConfigError::Message(format!(
+                    "invalid custom scope '{}'",
+                    scope
+                )));
+            }
+            Ok((name.to_string(), description.to_string()))
+        })
+        .collect()
+}
+
+fn parse_resources(allowed: &str) -> Result<Vec<Url>, ConfigError> {
+    allowed
+        .split(',')
+        .map(str::trim)
+        .filter(|uri| !uri.is_empty())
+        .map(|uri| {
+            resource::parse(uri)
+                .map_err(|_| ConfigError::Message(format!("invalid resource '{}'", uri)))
+        })
+        .collect()
+}
+
 // Throw the Config struct into a CONFIG lazy_static to avoid multiple processing
 lazy_static! {
     pub static ref CONFIG: Configuration = Configuration::new().expect("failed to load configuration");
@@ -2,64 +2,252 @@ use std::collections::HashSet;
 use std::io::{Error, ErrorKind};
 use std::iter::FromIterator;
 
+use async_trait::async_trait;
 use include_dir::{Dir, File};
 
+use couchdb::data_migration::MigrationReport;
 use couchdb::db::Database;
+use couchdb::error::Error as CouchError;
+use couchdb::migration::{Migration, Migrations};
 use couchdb::migrator::Migrator;
 use couchdb::{Couch, Result};
+use enseada::guid::Guid;
+use enseada::secure;
 
-use crate::config::{Configuration, CONFIG};
+use crate::config::{Configuration, Root, CONFIG};
+use crate::couchdb::partition;
 use crate::couchdb::repository::Entity;
 use crate::oauth::client::Client;
 use crate::oauth::persistence::client::ClientEntity;
+use crate::oauth::persistence::migration;
+use crate::oauth::request::GrantType;
 use crate::oauth::scope::Scope;
-use crate::user::User;
+use crate::rbac::{RoleAssignment, Rule, ADMIN_ROLE};
+use crate::user::{self, User, UserFilter, UserService};
 
 static MIGRATION_DIR: Dir = include_dir!("./migrations");
 
+/// Indexes the queries of each feature rely on, as database, name and fields. They are ensured
+/// on every startup, creating an index only if no identical one exists.
+const INDEXES: &[(&str, &str, &[&str])] = &[
+    // Listing and revoking the tokens of a user
+    (crate::couchdb::name::OAUTH, "token_user_idx", &["session.user_id"]),
+    // Purging expired tokens
+    (crate::couchdb::name::OAUTH, "token_expiration_idx", &["expiration"]),
+    (crate::couchdb::name::USERS, "user_email_idx", &["email"]),
+    // Searching users by username prefix
+    (crate::couchdb::name::USERS, "user_username_lower_idx", &["username_lower"]),
+];
+
+/// Versioned migrations, applied once each, in order. Their IDs are recorded once they are
+/// applied, so they must never change.
+pub fn migrations() -> Migrations {
+    let mut migrations = Migrations::new();
+    migrations
+        .register(DataRewrite::HashClientSecrets)
+        .register(DataRewrite::RehashTokenReferences)
+        .register(DataRewrite::BackfillSessionLifetimes)
+        .register(DataRewrite::BackfillUsernameSearch)
+        .register(DataRewrite::ReportInvalidUsernames);
+    migrations
+}
+
 pub async fn migrate() -> std::io::Result<()> {
-    let couch = &crate::couchdb::SINGLETON;
+    let couch = crate::couchdb::SINGLETON.long_running();
+
+    check_connection(&couch).await?;
+    run(&couch, &CONFIG).await.map_err(|err| {
+        log::error!("Migrations failed: {}", err);
+        Error::new(ErrorKind::Other, err.to_string())
+    })
+}
+
+/// Lists the pending migrations without applying them
+pub async fn dry_run() -> std::io::Result<()> {
+    let couch = crate::couchdb::SINGLETON.long_running();
 
-    run(couch, &CONFIG)
+    check_connection(&couch).await?;
+    let pending = migrations()
+        .pending(&couch)
         .await
-        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))
+        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+    if pending.is_empty() {
+        println!("No pending migrations");
+    }
+    for id in pending {
+        println!("{}", id);
+    }
+    Ok(())
+}
+
+/// Fails early, with a message telling what to fix, if CouchDB is unreachable or refuses
+/// the configured credentials
+async fn check_connection(couch: &Couch) -> std::io::Result<()> {
+    let url = CONFIG.couchdb().url();
+    match couch.status().await {
+        Ok(_) => Ok(()),
+        Err(CouchError::Unauthorized(reason)) => {
+            let message = format!(
+                "CouchDB at {} refused the credentials of user '{}' ({}). Check \
+                ENSEADA_COUCHDB_USERNAME and ENSEADA_COUCHDB_PASSWORD, and that the user \
+                exists and is an admin of the server",
+                url,
+                CONFIG.couchdb().username(),
+                reason
+            );
+            log::error!("{}", &message);
+            Err(Error::new(ErrorKind::Other, message))
+        }
+        Err(err) => {
+            let message = format!("CouchDB at {} is not available: {}", url, err);
+            log::error!("{}", &message);
+            Err(Error::new(ErrorKind::Other, message))
+        }
+    }
 }
 
 async fn run(couch: &Couch, cfg: &'static Configuration) -> Result<()> {
     log::info!("Running CouchDB migrations");
-    let migs: Vec<String> = MIGRATION_DIR
-        .files()
+    let mut files = MIGRATION_DIR.files().to_vec();
+    files.sort_by_key(|file| file.path());
+    let migs: Vec<String> = files
         .iter()
-        .map(File::contents_utf8)
-        .filter(Option::is_some)
-        .map(Option::unwrap)
+        .filter_map(File::contents_utf8)
         .map(str::to_string)
         .collect();
 
     let migrator = Migrator::new(couch, migs)?;
     migrator.run().await?;
+    ensure_indexes(couch).await?;
+    ensure_design_docs(couch).await?;
 
     let oauth_db = couch.database(crate::couchdb::name::OAUTH, true);
     let users_db = couch.database(crate::couchdb::name::USERS, true);
 
-    let public_host = cfg.public_host();
-    create_oauth_client(
-        &oauth_db,
-        Client::public(
-            "enseada".to_string(),
-            Scope::from("*"),
-            HashSet::from_iter(vec![public_host.join("/ui/auth/callback").unwrap()]),
-        ),
-    )
-    .await?;
+    let mut client = Client::public(
+        "enseada".to_string(),
+        Scope::from("*"),
+        HashSet::from_iter(vec![cfg.urls().ui("auth/callback")]),
+    );
+    client.set_allowed_grant_types(HashSet::from_iter(vec![
+        GrantType::AuthorizationCode,
+        GrantType::RefreshToken,
+    ]))
+    .unwrap();
+    create_oauth_client(&oauth_db, client).await?;
+
+    let rbac_db = couch.database(crate::couchdb::name::RBAC, true);
+    create_root_user(&users_db, cfg.root()).await?;
+    grant_admin_role(&rbac_db, cfg.root().username()).await?;
 
-    let root_pwd = cfg.root_password();
-    create_root_user(&users_db, root_pwd).await?;
+    let applied = migrations().run(couch).await?;
+    log::debug!("Applied {} pending migrations", applied.len());
+    check_user_search(&UserService::new(users_db)).await;
 
     log::info!("Migrations completed");
     Ok(())
 }
 
+/// Rewrites of existing documents left by earlier versions
+enum DataRewrite {
+    HashClientSecrets,
+    RehashTokenReferences,
+    BackfillSessionLifetimes,
+    BackfillUsernameSearch,
+    ReportInvalidUsernames,
+}
+
+#[async_trait]
+impl Migration for DataRewrite {
+    fn id(&self) -> &str {
+        match self {
+            DataRewrite::HashClientSecrets => "hash_client_secrets",
+            DataRewrite::RehashTokenReferences => "rehash_token_references",
+            DataRewrite::BackfillSessionLifetimes => "backfill_session_lifetimes",
+            DataRewrite::BackfillUsernameSearch => "backfill_username_search",
+            DataRewrite::ReportInvalidUsernames => "report_invalid_usernames",
+        }
+    }
+
+    fn database(&self) -> &str {
+        match self {
+            DataRewrite::HashClientSecrets
+            | DataRewrite::RehashTokenReferences
+            | DataRewrite::BackfillSessionLifetimes => crate::couchdb::name::OAUTH,
+            DataRewrite::BackfillUsernameSearch | DataRewrite::ReportInvalidUsernames => {
+                crate::couchdb::name::USERS
+            }
+        }
+    }
+
+    async fn run(&self, couch: &Couch) -> Result<()> {
+        let db = couch.database(self.database(), true);
+        let size_guard = crate::couchdb::SIZE_GUARD.clone();
+        match self {
+            DataRewrite::HashClientSecrets => {
+                migration::hash_client_secrets(db, size_guard).await?;
+            }
+            DataRewrite::RehashTokenReferences => {
+                migration::rehash_token_references(db, CONFIG.secret_key(), size_guard).await?;
+            }
+            DataRewrite::BackfillSessionLifetimes => {
+                migration::backfill_session_lifetimes(db, size_guard).await?;
+            }
+            DataRewrite::BackfillUsernameSearch => {
+                user::migration::backfill_username_search(db, size_guard).await?;
+            }
+            DataRewrite::ReportInvalidUsernames => {
+                let report = user::migration::report_invalid_usernames(db, size_guard).await?;
+                if !report.failures.is_empty() {
+                    log::warn!(
+                        "{} users have a username breaking the username rules, they are listed in the {} document",
+                        report.failures.len(),
+                        MigrationReport::build_id(&report.name)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn ensure_indexes(couch: &Couch) -> Result<()> {
+    for (database, name, fields) in INDEXES {
+        let db = couch.database(database, true);
+        db.create_index(name, fields, None).await?;
+    }
+    Ok(())
+}
+
+/// Installs the design documents of views, rewriting them only when their content changed so
+/// that their views are not rebuilt on every startup
+async fn ensure_design_docs(couch: &Couch) -> Result<()> {
+    let ddoc = crate::stats::design_doc();
+    for database in crate::stats::DATABASES {
+        couch.database(database, true).put_design_doc(&ddoc).await?;
+    }
+    couch
+        .database(crate::couchdb::name::OAUTH, true)
+        .put_design_doc(&crate::stats::tokens_design_doc())
+        .await?;
+    Ok(())
+}
+
+/// Warns if searching users by username would read every user, like when its index is missing
+async fn check_user_search(users: &UserService) {
+    let filter = UserFilter {
+        prefix: Some("a".to_string()),
+        ..Default::default()
+    };
+    match users.explain(&filter).await {
+        Ok(index) if index.is_full_scan() => {
+            log::warn!("Searching users by username scans every user, its index is missing")
+        }
+        Ok(index) => log::debug!("Searching users by username with index {}", index.name),
+        Err(err) => log::warn!("Failed to explain the search of users: {}", err),
+    }
+}
+
 async fn create_oauth_client(db: &Database, client: Client) -> Result<()> {
     log::debug!("Creating oauth client");
     let guid = ClientEntity::build_guid(client.client_id());
@@ -72,13 +260,63 @@ async fn create_oauth_client(db: &Database, client: Client) -> Result<()> {
     db.put(&entity.id().to_string(), &entity).await.map(|_| ())
 }
 
-async fn create_root_user(db: &Database, password: String) -> Result<()> {
+/// Creates the root user, with the configured password or a random one logged once.
+/// The password of an existing root user is only applied again when a reset is forced.
+async fn create_root_user(db: &Database, root: &Root) -> Result<()> {
     log::debug!("Creating root user");
-    let user = User::new(String::from("root"), password).unwrap();
-    if db.exists(&user.id().to_string()).await? {
-        log::debug!("Root user already exists. Skipping");
-        return Ok(());
+    let username = root.username();
+    let guid = User::build_guid(username);
+    let user = match db.get::<User>(&guid.to_string()).await? {
+        Some(_) if !root.force_reset() => {
+            log::debug!("Root user already exists. Skipping");
+            return Ok(());
+        }
+        Some(mut user) => {
+            log::warn!("Resetting the password of root user {}", username);
+            user.set_password(&root_password(root)?)
+                .map_err(|err| CouchError::internal(err.to_string()))?;
+            user
+        }
+        None => User::new(username.to_string(), root_password(root)?)
+            .map_err(|err| CouchError::internal(err.to_string()))?,
+    };
+
+    db.put(&guid.to_string(), user).await.map(|_| ())
+}
+
+/// Configured password of the root user, or a random one logged for the operator
+fn root_password(root: &Root) -> Result<String> {
+    if let Some(password) = root.password() {
+        return Ok(password.to_string());
+    }
+
+    let password = secure::generate_token(16)
+        .map_err(CouchError::internal)?
+        .to_string();
+    log::warn!(
+        "Generated password for root user {}: {}. It is not shown again, change it after logging in",
+        root.username(),
+        password
+    );
+    Ok(password)
+}
+
+/// Defines the admin role, allowed to do anything, and assigns it to the root user
+async fn grant_admin_role(db: &Database, username: &str) -> Result<()> {
+    let role = Guid::partitioned(partition::ROLE, ADMIN_ROLE)?;
+    let rule_id = Rule::build_guid(&role.to_string(), "*", "*");
+    if !db.exists(&rule_id.to_string()).await? {
+        log::debug!("Creating role {}", ADMIN_ROLE);
+        let rule = Rule::new(role, Guid::simple("*")?, "*".to_string());
+        db.put(&rule_id.to_string(), rule).await?;
     }
 
-    db.put(&user.id().to_string(), user).await.map(|_| ())
+    let sub = Guid::partitioned(partition::USER, username)?;
+    let assignment_id = RoleAssignment::build_guid(&sub.to_string(), ADMIN_ROLE);
+    if !db.exists(&assignment_id.to_string()).await? {
+        log::debug!("Assigning role {} to root user {}", ADMIN_ROLE, username);
+        let assignment = RoleAssignment::new(sub, ADMIN_ROLE.to_string());
+        db.put(&assignment_id.to_string(), assignment).await?;
+    }
+    Ok(())
 }
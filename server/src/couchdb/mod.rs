@@ -1,7 +1,10 @@
+use std::sync::Arc;
+
 use actix_web::web;
 
+use couchdb::size::SizeGuard;
 use couchdb::Couch;
-pub use migrate::migrate;
+pub use migrate::{dry_run, migrate};
 
 use crate::config::CONFIG;
 
@@ -12,10 +15,42 @@ pub mod name {
     pub const OAUTH: &str = "oauth";
     pub const USERS: &str = "users";
     pub const RBAC: &str = "rbac";
+    pub const SYSTEM: &str = "system";
+    pub const AUDIT: &str = "audit";
+}
+
+/// Partitions of the documents, the part of their IDs before ':'
+pub mod partition {
+    pub const ACCESS_TOKEN: &str = "access_token";
+    pub const ANNOUNCEMENT: &str = "announcement";
+    pub const API_KEY: &str = "apikey";
+    pub const AUTH_CODE: &str = "code";
+    pub const BOOTSTRAP_TOKEN: &str = "bootstrap_token";
+    pub const CLIENT: &str = "client";
+    pub const DEVICE: &str = "device";
+    pub const DEVICE_AUTHORIZATION: &str = "device_authorization";
+    pub const EMAIL: &str = "email";
+    pub const EVENT: &str = "event";
+    pub const GROUP: &str = "group";
+    pub const IDENTITY: &str = "identity";
+    pub const PAT: &str = "pat";
+    pub const PASSWORD_RESET: &str = "reset";
+    pub const PUSHED_REQUEST: &str = "pushed_request";
+    pub const REFRESH_TOKEN: &str = "refresh_token";
+    pub const ROLE: &str = "role";
+    pub const RULE: &str = "rule";
+    pub const SETTING: &str = "setting";
+    pub const SSO: &str = "sso";
+    pub const USER: &str = "user";
+    pub const VERIFICATION: &str = "verification";
+    pub const WEBHOOK_DELIVERY: &str = "webhook_delivery";
 }
 
 lazy_static! {
     pub static ref SINGLETON: Couch = from_global_config();
+    /// Shared by every writer of free-form documents, so that truncations are counted once
+    pub static ref SIZE_GUARD: Arc<SizeGuard> =
+        Arc::new(SizeGuard::new(CONFIG.couchdb().document_limit()));
 }
 
 fn from_global_config() -> Couch {
@@ -23,7 +58,10 @@ fn from_global_config() -> Couch {
     let url = couch.url();
     let username = couch.username();
     let password = couch.password();
-    Couch::new(url, username, password)
+    let mut client = Couch::with_pool(url, username, password, couch.timeouts(), couch.pool());
+    client.set_attachment_limit(couch.attachment_limit());
+    client.set_auth_method(couch.auth_method());
+    client
 }
 
 pub fn add_couch_client(app: &mut web::ServiceConfig) {
@@ -4,6 +4,7 @@ use serde::Serialize;
 use async_trait::async_trait;
 use couchdb::db::Database;
 use couchdb::error::Error;
+use couchdb::responses::FindResponse;
 use enseada::guid::Guid;
 use enseada::pagination::{Cursor, Page};
 
@@ -29,17 +30,23 @@ pub trait Repository<T> {
         let id = T::build_guid("");
         let partition = id.partition();
         let db = self.db();
-        let res = match partition {
+        let start_key = cursor.map(Cursor::start_key);
+        let start_key = start_key.as_deref();
+        match partition {
             Some(partition) => {
-                db.list_partitioned::<T>(partition, limit + 1, cursor.map(Cursor::to_string))
-                    .await?
+                let (res, total) = futures::try_join!(
+                    db.list_partitioned::<T>(partition, limit + 1, 0, start_key, None),
+                    db.count_partition(partition),
+                )?;
+                let mut page = Page::from_rows_response(res, limit);
+                page.set_total(total);
+                Ok(page)
             }
             None => {
-                db.list::<T>(limit + 1, cursor.map(Cursor::to_string))
-                    .await?
+                let res = db.list::<T>(limit + 1, 0, start_key, None).await?;
+                Ok(Page::from_rows_response(res, limit))
             }
-        };
-        Ok(Page::from_rows_response(res, limit))
+        }
     }
 
     async fn find(&self, id: &str) -> Result<Option<T>, Error>
@@ -51,6 +58,20 @@ pub trait Repository<T> {
         self.db().get(guid.as_str()).await.map_err(Error::from)
     }
 
+    /// Like [`find`], but observes an entity saved moments ago with [`save_tracked`]
+    /// even if the replica serving the read has not seen the write yet.
+    ///
+    /// [`find`]: Repository::find
+    /// [`save_tracked`]: Repository::save_tracked
+    async fn find_consistent(&self, id: &str) -> Result<Option<T>, Error>
+    where
+        Self: Sized,
+        T: 'async_trait + Entity,
+    {
+        let guid = T::build_guid(id).to_string();
+        self.db().get_consistent(guid.as_str()).await
+    }
+
     async fn get(&self, id: &str) -> Result<T, Error>
     where
         Self: Sized,
@@ -73,16 +94,172 @@ pub trait Repository<T> {
         Ok(entity)
     }
 
+    /// Like [`save`], for entities that are read again right away with [`find_consistent`]
+    ///
+    /// [`save`]: Repository::save
+    /// [`find_consistent`]: Repository::find_consistent
+    async fn save_tracked(&self, entity: T) -> Result<T, Error>
+    where
+        Self: Sized,
+        T: 'async_trait + Entity,
+    {
+        let guid = entity.id().to_string();
+        let token = self.db().put_tracked(guid.as_str(), &entity).await?;
+        let mut entity = entity;
+        entity.set_rev(token.rev().to_string());
+        Ok(entity)
+    }
+
     async fn delete(&self, entity: &T) -> Result<(), Error>
     where
         Self: Sized,
         T: 'async_trait + Entity,
     {
         let id = entity.id().to_string();
-        let rev = match entity.rev() {
-            Some(rev) => rev,
-            None => panic!("entity {} is missing rev", id),
+        let rev = entity.rev().ok_or_else(|| {
+            Error::internal(format!("entity {} has no revision, it was never saved", id))
+        })?;
+        self.db().delete(&id, rev).await
+    }
+
+    /// Like [`save_tracked`], but an entity without a revision replaces the current revision
+    /// of its document if there is one, instead of conflicting with it
+    ///
+    /// [`save_tracked`]: Repository::save_tracked
+    async fn upsert(&self, entity: T) -> Result<T, Error>
+    where
+        Self: Sized,
+        T: 'async_trait + Entity,
+    {
+        let mut entity = entity;
+        if entity.rev().is_none() {
+            let guid = entity.id().to_string();
+            if let Some(current) = self.db().get::<T>(&guid).await? {
+                if let Some(rev) = current.rev() {
+                    entity.set_rev(rev.to_string());
+                }
+            }
+        }
+        self.save_tracked(entity).await
+    }
+
+    /// One page of the entities matching the Mango selector, queried within the partition of
+    /// their IDs if they have one
+    async fn find_in_partition(
+        &self,
+        selector: serde_json::Value,
+        limit: usize,
+        bookmark: Option<String>,
+    ) -> Result<FindResponse<T>, Error>
+    where
+        Self: Sized,
+        T: 'async_trait + Entity,
+    {
+        let id = T::build_guid("");
+        match id.partition() {
+            Some(partition) => {
+                self.db()
+                    .find_partitioned::<T>(partition, selector, limit, bookmark)
+                    .await
+            }
+            None => self.db().find::<T>(selector, limit, bookmark).await,
+        }
+    }
+
+    /// Entities matching the Mango selector, within their partition if they have one, along with
+    /// how many match in total
+    async fn find_by(
+        &self,
+        selector: serde_json::Value,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<T>, Error>
+    where
+        Self: Sized,
+        T: 'async_trait + Entity,
+    {
+        let id = T::build_guid("");
+        let db = self.db();
+        let bookmark = cursor.map(Cursor::to_string);
+        let (res, total) = match id.partition() {
+            Some(partition) => {
+                let (res, total) = futures::try_join!(
+                    db.find_partitioned::<T>(partition, selector.clone(), limit, bookmark),
+                    db.count_partitioned(partition, selector.clone()),
+                )?;
+                (res, Some(total))
+            }
+            None => (db.find::<T>(selector, limit, bookmark).await?, None),
+        };
+        if let Some(warning) = &res.warning {
+            log::warn!("{}", warning);
+        }
+        let mut page = Page::from_find_response(res, limit);
+        if let Some(total) = total {
+            page.set_total(total);
+        }
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use couchdb::Couch;
+    use serde::Deserialize;
+    use url::Url;
+
+    use super::*;
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    struct Note {
+        #[serde(rename = "_id")]
+        id: Guid,
+        #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+        rev: Option<String>,
+    }
+
+    impl Entity for Note {
+        fn build_guid(id: &str) -> Guid {
+            Guid::from(format!("note:{}", id))
+        }
+
+        fn id(&self) -> &Guid {
+            &self.id
+        }
+
+        fn rev(&self) -> Option<&str> {
+            self.rev.as_deref()
+        }
+
+        fn set_rev(&mut self, rev: String) -> &mut Self {
+            self.rev = Some(rev);
+            self
+        }
+    }
+
+    struct Notes(Database);
+
+    #[async_trait]
+    impl Repository<Note> for Notes {
+        fn db(&self) -> &Database {
+            &self.0
+        }
+    }
+
+    #[actix_rt::test]
+    async fn it_refuses_deleting_unsaved_entities() {
+        // Never queried, as the entity is refused first
+        let couch = Couch::new(
+            Url::parse("http://localhost:5984").unwrap(),
+            "admin".to_string(),
+            "admin".to_string(),
+        );
+        let notes = Notes(couch.database("notes", true));
+        let note = Note {
+            id: Note::build_guid("a"),
+            rev: None,
         };
-        self.db().delete(&id, &rev).await.map_err(Error::from)
+        let err = notes.delete(&note).await.unwrap_err();
+        assert_eq!(err.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
     }
 }
@@ -0,0 +1,185 @@
+use std::sync::Arc;
+
+use chrono::serde::ts_milliseconds;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+use couchdb::size::SizeGuard;
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+
+/// Free-form fields of deliveries, cut short in this order when a delivery is too large
+const TRUNCATED_FIELDS: &[&str] = &["payload", "error"];
+
+/// A webhook delivery that failed on its last attempt, with the payload that was not delivered.
+///
+/// Deliveries are identified by the time of their failure and a random suffix, like audit
+/// records, and are never updated.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FailedDelivery {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    webhook: String,
+    event_type: String,
+    /// The JSON body that was sent, kept as a string so that it can be truncated
+    payload: String,
+    error: String,
+    attempts: u32,
+    #[serde(with = "ts_milliseconds")]
+    failed_at: DateTime<Utc>,
+    /// Set when the payload or error were cut short to fit in a document
+    #[serde(default, skip_serializing_if = "is_false")]
+    truncated: bool,
+}
+
+impl FailedDelivery {
+    pub fn new(
+        webhook: String,
+        event_type: &str,
+        payload: &[u8],
+        error: String,
+        attempts: u32,
+        failed_at: DateTime<Utc>,
+    ) -> Self {
+        let id = format!(
+            "{}-{}",
+            failed_at.format("%Y%m%dT%H%M%S%.3fZ"),
+            Uuid::new_v4().to_simple()
+        );
+        FailedDelivery {
+            id: Self::build_guid(&id),
+            rev: None,
+            webhook,
+            event_type: event_type.to_string(),
+            payload: String::from_utf8_lossy(payload).into_owned(),
+            error,
+            attempts,
+            failed_at,
+            truncated: false,
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl Entity for FailedDelivery {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::WEBHOOK_DELIVERY, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+/// Keeps failed webhook deliveries in the system database, so that they can be replayed by hand
+pub struct DeliveryLog {
+    db: Database,
+    size_guard: Arc<SizeGuard>,
+}
+
+impl DeliveryLog {
+    pub fn new(db: Database) -> Self {
+        DeliveryLog {
+            db,
+            size_guard: Arc::new(SizeGuard::default()),
+        }
+    }
+
+    /// Sets the guard keeping deliveries under the maximum document size,
+    /// by truncating their payload and error if they are too large
+    pub fn set_size_guard(&mut self, size_guard: Arc<SizeGuard>) -> &mut Self {
+        self.size_guard = size_guard;
+        self
+    }
+
+    /// Writes the delivery, truncated if it does not fit in a document.
+    /// Fails with a `413` error if it does not fit even truncated.
+    pub async fn record(&self, delivery: &FailedDelivery) -> Result<(), Error> {
+        let doc = self.document(delivery)?;
+        self.db.put(&delivery.id().to_string(), &doc).await?;
+        Ok(())
+    }
+
+    fn document(&self, delivery: &FailedDelivery) -> Result<serde_json::Value, Error> {
+        let mut doc =
+            serde_json::to_value(delivery).map_err(|err| Error::internal(err.to_string()))?;
+        let id = delivery.id().to_string();
+        self.size_guard.check(&id, &mut doc, TRUNCATED_FIELDS)?;
+        Ok(doc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use super::*;
+
+    fn log(guard: Arc<SizeGuard>) -> DeliveryLog {
+        // Never queried, documents are only built
+        let couch = couchdb::Couch::new(
+            Url::parse("http://localhost:5984").unwrap(),
+            "admin".to_string(),
+            "admin".to_string(),
+        );
+        let mut log = DeliveryLog::new(couch.database("system", true));
+        log.set_size_guard(guard);
+        log
+    }
+
+    fn delivery(payload: &str) -> FailedDelivery {
+        FailedDelivery::new(
+            "https://hooks.example.com/tokens".to_string(),
+            "oauth.token_event",
+            payload.as_bytes(),
+            "HTTP status server error (502 Bad Gateway)".to_string(),
+            4,
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn it_truncates_the_payload_of_oversized_deliveries() {
+        let guard = Arc::new(SizeGuard::new(1000));
+        let log = log(guard.clone());
+        let payload = format!("{{\"session\":\"{}\"}}", "x".repeat(2000));
+        let doc = log.document(&delivery(&payload)).unwrap();
+        assert_eq!(doc["truncated"], true);
+        let payload = doc["payload"].as_str().unwrap();
+        assert!(payload.starts_with("{\"session\":\"x"));
+        assert!(serde_json::to_vec(&doc).unwrap().len() <= 1000);
+        assert_eq!(doc["error"], "HTTP status server error (502 Bad Gateway)");
+        assert_eq!(guard.truncations(), 1);
+
+        let doc = log.document(&delivery("{}")).unwrap();
+        assert!(doc.get("truncated").is_none());
+        assert_eq!(doc["payload"], "{}");
+        assert_eq!(guard.truncations(), 1);
+    }
+
+    #[test]
+    fn it_rejects_deliveries_too_large_when_truncated() {
+        let log = log(Arc::new(SizeGuard::new(50)));
+        let err = log.document(&delivery(&"x".repeat(2000))).unwrap_err();
+        assert!(err.is_too_large());
+    }
+}
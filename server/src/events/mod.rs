@@ -0,0 +1,261 @@
+//! Events emitted to external consumers, like webhooks, and the JSON schemas of their payloads.
+//!
+//! Every payload is wrapped in an [`Envelope`] carrying the type of the event and the version
+//! of its schema. Schemas are maintained by hand next to this module and published by [`mount`].
+//! A change to the payload of an event must come with a new schema version.
+use serde::Serialize;
+
+use crate::oauth::events::TokenEvent;
+use crate::oauth::issuance::IssuanceAnomaly;
+
+pub use delivery::{DeliveryLog, FailedDelivery};
+pub use routes::mount;
+
+mod delivery;
+mod routes;
+
+/// Payload of an event, described by a published schema
+pub trait Event: Serialize {
+    const TYPE: &'static str;
+    const SCHEMA_VERSION: u32;
+}
+
+impl Event for IssuanceAnomaly {
+    const TYPE: &'static str = "oauth.issuance_anomaly";
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+impl Event for TokenEvent {
+    const TYPE: &'static str = "oauth.token_event";
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+/// Payload of an event as emitted, with its type and schema version next to its fields
+#[derive(Debug, Serialize)]
+pub struct Envelope<'a, E: Event> {
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    schema_version: u32,
+    #[serde(flatten)]
+    data: &'a E,
+}
+
+impl<'a, E: Event> Envelope<'a, E> {
+    pub fn new(data: &'a E) -> Self {
+        Envelope {
+            event_type: E::TYPE,
+            schema_version: E::SCHEMA_VERSION,
+            data,
+        }
+    }
+}
+
+/// Published JSON schema of an event type
+#[derive(Debug)]
+pub struct EventSchema {
+    pub event_type: &'static str,
+    pub schema_version: u32,
+    pub schema: &'static str,
+}
+
+impl EventSchema {
+    const fn of<E: Event>(schema: &'static str) -> Self {
+        EventSchema {
+            event_type: E::TYPE,
+            schema_version: E::SCHEMA_VERSION,
+            schema,
+        }
+    }
+}
+
+pub const SCHEMAS: &[EventSchema] = &[
+    EventSchema::of::<IssuanceAnomaly>(include_str!("schemas/oauth.issuance_anomaly.json")),
+    EventSchema::of::<TokenEvent>(include_str!("schemas/oauth.token_event.json")),
+];
+
+pub fn schema(event_type: &str) -> Option<&'static EventSchema> {
+    SCHEMAS
+        .iter()
+        .find(|schema| schema.event_type == event_type)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use serde_json::{json, Value};
+
+    use crate::oauth::events::TokenAction;
+    use crate::oauth::scope::Scope;
+    use crate::oauth::session::Session;
+    use crate::oauth::token::TokenTypeHint;
+
+    use super::*;
+
+    /// Fields of each schema version, which must not change once published
+    const PUBLISHED: &[(&str, u32, &[&str])] = &[
+        (
+            "oauth.issuance_anomaly",
+            1,
+            &[
+                "client_id",
+                "rate",
+                "schema_version",
+                "threshold",
+                "type",
+                "window_seconds",
+            ],
+        ),
+        (
+            "oauth.token_event",
+            1,
+            &[
+                "action",
+                "client_id",
+                "expires_at",
+                "schema_version",
+                "session",
+                "token_type",
+                "type",
+            ],
+        ),
+    ];
+
+    /// Validates the subset of JSON Schema used by the event schemas
+    fn validate(schema: &Value, value: &Value, path: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                errors.push(format!("{}: expected {}, got {}", path, expected, value));
+            }
+        }
+        let matches_type = match schema.get("type").and_then(Value::as_str) {
+            Some("object") => value.is_object(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            Some("array") => value.is_array(),
+            _ => true,
+        };
+        if !matches_type {
+            errors.push(format!(
+                "{}: {} is not of type {}",
+                path, value, schema["type"]
+            ));
+            return errors;
+        }
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if value.as_f64().is_some_and(|value| value < minimum) {
+                errors.push(format!("{}: {} is less than {}", path, value, minimum));
+            }
+        }
+        if let Some(object) = value.as_object() {
+            let properties = schema["properties"]
+                .as_object()
+                .cloned()
+                .unwrap_or_default();
+            for required in schema["required"].as_array().into_iter().flatten() {
+                let required = required.as_str().unwrap();
+                if !object.contains_key(required) {
+                    errors.push(format!("{}: missing property {}", path, required));
+                }
+            }
+            for (key, value) in object {
+                match properties.get(key) {
+                    Some(property) => {
+                        errors.extend(validate(property, value, &format!("{}.{}", path, key)))
+                    }
+                    None if schema["additionalProperties"] == json!(false) => {
+                        errors.push(format!("{}: unexpected property {}", path, key))
+                    }
+                    None => {}
+                }
+            }
+        }
+        errors
+    }
+
+    fn parse(schema: &EventSchema) -> Value {
+        serde_json::from_str(schema.schema).unwrap()
+    }
+
+    #[test]
+    fn it_validates_captured_payloads_against_their_schema() {
+        let anomaly = IssuanceAnomaly {
+            client_id: "enseada".to_string(),
+            rate: 31,
+            threshold: 30,
+            window_seconds: 60,
+        };
+        let payload = serde_json::to_value(Envelope::new(&anomaly)).unwrap();
+        assert_eq!(payload["type"], "oauth.issuance_anomaly");
+        assert_eq!(payload["schema_version"], 1);
+
+        let schema = parse(schema(IssuanceAnomaly::TYPE).unwrap());
+        assert_eq!(validate(&schema, &payload, "$"), Vec::<String>::new());
+
+        let mut session = Session::for_client("enseada".to_string());
+        session
+            .set_user_id("jdoe".to_string())
+            .set_scope(Scope::from("profile"));
+        let event = TokenEvent {
+            action: TokenAction::Issued,
+            client_id: "enseada".to_string(),
+            token_type: TokenTypeHint::AccessToken,
+            expires_at: Utc::now(),
+            session,
+        };
+        let payload = serde_json::to_value(Envelope::new(&event)).unwrap();
+        assert_eq!(payload["action"], "issued");
+        assert_eq!(payload["session"]["user_id"], "jdoe");
+
+        let schema = parse(super::schema(TokenEvent::TYPE).unwrap());
+        assert_eq!(validate(&schema, &payload, "$"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn it_rejects_payloads_not_matching_the_schema() {
+        let schema = parse(schema(IssuanceAnomaly::TYPE).unwrap());
+        let payload = json!({
+            "type": "oauth.issuance_anomaly",
+            "schema_version": 2,
+            "client_id": "enseada",
+            "rate": "31",
+            "threshold": 30,
+            "window": 60
+        });
+        assert_eq!(validate(&schema, &payload, "$").len(), 4);
+    }
+
+    #[test]
+    fn published_schemas_are_not_changed_without_a_version_bump() {
+        for schema in SCHEMAS {
+            let json = parse(schema);
+            assert_eq!(
+                json["properties"]["schema_version"]["const"], schema.schema_version,
+                "the schema of {} does not declare its version",
+                schema.event_type
+            );
+
+            let mut fields: Vec<&str> = json["properties"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(String::as_str)
+                .collect();
+            fields.sort();
+            let published = PUBLISHED
+                .iter()
+                .find(|(event_type, version, _)| {
+                    *event_type == schema.event_type && *version == schema.schema_version
+                })
+                .map(|(_, _, fields)| fields.to_vec());
+            assert_eq!(
+                published,
+                Some(fields),
+                "the schema of {} changed, bump its version and record it as published",
+                schema.event_type
+            );
+        }
+    }
+}
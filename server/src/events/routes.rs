@@ -0,0 +1,73 @@
+use actix_web::web::{Json, Path, ServiceConfig};
+use actix_web::{get, HttpResponse};
+use serde::Serialize;
+
+use crate::events::{self, SCHEMAS};
+use crate::http::error::ApiError;
+use crate::http::ApiResult;
+
+pub fn mount(cfg: &mut ServiceConfig) {
+    cfg.service(list_schemas);
+    cfg.service(get_schema);
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SchemaResponse {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub schema_version: u32,
+    pub url: String,
+}
+
+#[get("/api/v1beta1/events/schemas")]
+pub async fn list_schemas() -> ApiResult<Json<Vec<SchemaResponse>>> {
+    let schemas = SCHEMAS
+        .iter()
+        .map(|schema| SchemaResponse {
+            event_type: schema.event_type.to_string(),
+            schema_version: schema.schema_version,
+            url: format!("/api/v1beta1/events/schemas/{}", schema.event_type),
+        })
+        .collect();
+    Ok(Json(schemas))
+}
+
+#[get("/api/v1beta1/events/schemas/{event_type}")]
+pub async fn get_schema(event_type: Path<String>) -> ApiResult<HttpResponse> {
+    let schema = events::schema(&event_type).ok_or_else(|| {
+        ApiError::NotFound(format!("event type {} not found", event_type.as_str()))
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/schema+json")
+        .body(schema.schema))
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn it_serves_the_schema_of_each_event_type() {
+        let mut app = test::init_service(App::new().configure(mount)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1beta1/events/schemas")
+            .to_request();
+        let schemas: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(schemas[0]["type"], "oauth.issuance_anomaly");
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1beta1/events/schemas/oauth.issuance_anomaly")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1beta1/events/schemas/users.deleted")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 404);
+    }
+}
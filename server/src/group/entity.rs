@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+
+/// Most characters a group name can have
+pub const NAME_MAX_LENGTH: usize = 64;
+/// The rules on group names, as reported to users breaking them
+pub const NAME_RULE: &str =
+    "name must be 1 to 64 characters long, made of lowercase letters, digits, '-', '_' and '.'";
+
+/// A named set of users sharing roles
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Group {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Roles every member inherits
+    #[serde(default)]
+    roles: Vec<String>,
+    /// Usernames of the members
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+impl Group {
+    pub fn new(name: String) -> Self {
+        Group {
+            id: Self::build_guid(&name),
+            rev: None,
+            name,
+            description: None,
+            roles: Vec::new(),
+            members: Vec::new(),
+        }
+    }
+
+    /// Whether the name follows the rules, so that it cannot break document IDs
+    pub fn is_valid_name(name: &str) -> bool {
+        (1..=NAME_MAX_LENGTH).contains(&name.len())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn set_description(&mut self, description: Option<String>) -> &mut Self {
+        self.description = description;
+        self
+    }
+
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+
+    /// Replaces the roles of the group, ignoring duplicates
+    pub fn set_roles(&mut self, roles: Vec<String>) -> &mut Self {
+        self.roles = roles;
+        self.roles.sort();
+        self.roles.dedup();
+        self
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    pub fn has_member(&self, username: &str) -> bool {
+        self.members.iter().any(|member| member == username)
+    }
+
+    /// Adds the user to the group, telling whether they were not a member already
+    pub fn add_member(&mut self, username: &str) -> bool {
+        if self.has_member(username) {
+            return false;
+        }
+        self.members.push(username.to_string());
+        self.members.sort();
+        true
+    }
+
+    /// Removes the user from the group, telling whether they were a member
+    pub fn remove_member(&mut self, username: &str) -> bool {
+        let count = self.members.len();
+        self.members.retain(|member| member != username);
+        self.members.len() != count
+    }
+}
+
+impl Entity for Group {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::GROUP, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_accepts_names_that_cannot_break_ids() {
+        assert!(Group::is_valid_name("developers"));
+        assert!(Group::is_valid_name("ops-team.eu_1"));
+        assert!(!Group::is_valid_name(""));
+        assert!(!Group::is_valid_name("Developers"));
+        assert!(!Group::is_valid_name("group:admins"));
+        assert!(!Group::is_valid_name(&"a".repeat(NAME_MAX_LENGTH + 1)));
+    }
+
+    #[test]
+    fn it_keeps_members_and_roles_unique() {
+        let mut group = Group::new("developers".to_string());
+        assert_eq!(group.id().to_string(), "group:developers");
+        assert!(group.add_member("jdoe"));
+        assert!(group.add_member("alice"));
+        assert!(!group.add_member("jdoe"));
+        assert_eq!(group.members(), ["alice", "jdoe"]);
+
+        assert!(group.remove_member("jdoe"));
+        assert!(!group.remove_member("jdoe"));
+        assert_eq!(group.members(), ["alice"]);
+
+        let roles = vec!["deployer", "admin", "deployer"];
+        group.set_roles(roles.into_iter().map(str::to_string).collect());
+        assert_eq!(group.roles(), ["admin", "deployer"]);
+    }
+}
@@ -0,0 +1,12 @@
+//! Groups of users, who inherit the roles of every group they are a member of.
+//!
+//! Groups are stored in the users database as `group:{name}` documents listing the roles of the
+//! group and the usernames of its members. The enforcer assigns the roles of each group to its
+//! members when loading its rules, along with the roles assigned to them directly.
+pub use entity::Group;
+pub use routes::mount;
+pub use service::GroupService;
+
+mod entity;
+mod routes;
+mod service;
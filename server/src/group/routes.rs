@@ -0,0 +1,398 @@
+use actix_web::web::{Data, Json, Path, Query, ServiceConfig};
+use actix_web::{delete, get, post, put, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use enseada::guid::Guid;
+use enseada::pagination::{Cursor, Page};
+
+use crate::couchdb::repository::{Entity, Repository};
+use crate::group::entity::NAME_RULE;
+use crate::group::{Group, GroupService};
+use crate::http::error::ApiError;
+use crate::http::extractor::{scope::Scope, user::CurrentUser};
+use crate::http::{ApiResult, PaginationQuery};
+use crate::rbac::Enforcer;
+use crate::user::UserService;
+
+/// Longest description groups can have
+const DESCRIPTION_LENGTH: usize = 500;
+
+pub fn mount(cfg: &mut ServiceConfig) {
+    let couch = &crate::couchdb::SINGLETON;
+    let db = couch.database(crate::couchdb::name::USERS, true);
+    cfg.data(GroupService::new(db));
+    cfg.service(list);
+    cfg.service(create);
+    cfg.service(get);
+    cfg.service(update);
+    cfg.service(delete);
+    cfg.service(add_member);
+    cfg.service(remove_member);
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct GroupResponse {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub roles: Vec<String>,
+    pub members: Vec<String>,
+}
+
+impl From<&Group> for GroupResponse {
+    fn from(group: &Group) -> Self {
+        GroupResponse {
+            name: group.name().to_string(),
+            description: group.description().map(str::to_string),
+            roles: group.roles().to_vec(),
+            members: group.members().to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupPathParam {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupMemberPathParams {
+    name: String,
+    username: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ForceQuery {
+    #[serde(default)]
+    force: bool,
+}
+
+#[get("/api/v1beta1/groups")]
+pub async fn list(
+    service: Data<GroupService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    list: Query<PaginationQuery>,
+) -> ApiResult<Json<Page<GroupResponse>>> {
+    Scope::from("groups:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("groups")?, "read")?;
+    let limit = list.limit();
+    let cursor = list.cursor();
+
+    let cursor = if let Some(cursor) = cursor {
+        Some(Cursor::from_b64(cursor)?)
+    } else {
+        None
+    };
+
+    let page = service
+        .list(limit, cursor.as_ref())
+        .await?
+        .map(|group| GroupResponse::from(group));
+    Ok(Json(page))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GroupRequest {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Usernames of the first members
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+impl GroupRequest {
+    fn validate(&self) -> ApiResult<()> {
+        let mut reasons = Vec::new();
+        if !Group::is_valid_name(&self.name) {
+            reasons.push(NAME_RULE.to_string());
+        }
+        reasons.extend(validate_fields(self.description.as_deref(), Some(&self.roles)));
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::ValidationError(reasons))
+        }
+    }
+}
+
+/// Reasons the description and roles of a group are invalid, if any
+fn validate_fields(description: Option<&str>, roles: Option<&[String]>) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if description.is_some_and(|description| description.chars().count() > DESCRIPTION_LENGTH) {
+        reasons.push(format!(
+            "description must be at most {} characters long",
+            DESCRIPTION_LENGTH
+        ));
+    }
+    if roles.is_some_and(|roles| roles.iter().any(|role| role.trim().is_empty())) {
+        reasons.push("roles must not be empty".to_string());
+    }
+    reasons
+}
+
+/// Empty descriptions clear them
+fn description(description: Option<&str>) -> Option<String> {
+    description
+        .map(str::trim)
+        .filter(|description| !description.is_empty())
+        .map(str::to_string)
+}
+
+#[post("/api/v1beta1/groups")]
+pub async fn create(
+    service: Data<GroupService>,
+    users: Data<UserService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    data: Json<GroupRequest>,
+) -> ApiResult<Json<GroupResponse>> {
+    Scope::from("groups:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("groups")?, "create")?;
+    if !data.roles.is_empty() {
+        Scope::from(vec!["groups:manage", "roles"]).matches_exactly(&scope)?;
+        enforcer.check(current_user.id(), &Guid::simple("groups")?, "manage_roles")?;
+    }
+    data.validate()?;
+
+    let data = data.into_inner();
+    let mut group = Group::new(data.name);
+    group
+        .set_description(description(data.description.as_deref()))
+        .set_roles(data.roles);
+    let mut unknown = Vec::new();
+    for username in &data.members {
+        match users.find(username).await? {
+            Some(user) => {
+                group.add_member(user.username());
+            }
+            None => unknown.push(format!("user {} not found", username)),
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(ApiError::ValidationError(unknown));
+    }
+
+    let name = group.name().to_string();
+    let group = service.save(group).await.map_err(|err| match ApiError::from(err) {
+        ApiError::Conflict(_) => ApiError::Conflict(format!("group {} already exists", name)),
+        err => err,
+    })?;
+    log::info!(
+        "User {} created group {}",
+        current_user.username(),
+        group.name()
+    );
+    Ok(Json(GroupResponse::from(&group)))
+}
+
+#[get("/api/v1beta1/groups/{name}")]
+pub async fn get(
+    service: Data<GroupService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<GroupPathParam>,
+) -> ApiResult<Json<GroupResponse>> {
+    Scope::from("groups:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Group::build_guid(&path.name), "read")?;
+
+    let group = find(&service, &path.name).await?;
+    Ok(Json(GroupResponse::from(&group)))
+}
+
+/// Changes to a group, leaving out the fields to keep
+#[derive(Debug, Default, Deserialize)]
+pub struct GroupUpdate {
+    pub description: Option<String>,
+    /// Replaces all the roles of the group
+    pub roles: Option<Vec<String>>,
+}
+
+#[put("/api/v1beta1/groups/{name}")]
+pub async fn update(
+    service: Data<GroupService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<GroupPathParam>,
+    data: Json<GroupUpdate>,
+) -> ApiResult<Json<GroupResponse>> {
+    Scope::from("groups:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    let sub = Group::build_guid(&path.name);
+    enforcer.check(current_user.id(), &sub, "update")?;
+    if data.roles.is_some() {
+        Scope::from(vec!["groups:manage", "roles"]).matches_exactly(&scope)?;
+        enforcer.check(current_user.id(), &sub, "manage_roles")?;
+    }
+    let reasons = validate_fields(data.description.as_deref(), data.roles.as_deref());
+    if !reasons.is_empty() {
+        return Err(ApiError::ValidationError(reasons));
+    }
+
+    let data = data.into_inner();
+    let mut group = find(&service, &path.name).await?;
+    if data.description.is_some() {
+        group.set_description(description(data.description.as_deref()));
+    }
+    if let Some(roles) = data.roles {
+        group.set_roles(roles);
+    }
+    let group = service.save(group).await?;
+    log::info!(
+        "User {} updated group {}",
+        current_user.username(),
+        group.name()
+    );
+    Ok(Json(GroupResponse::from(&group)))
+}
+
+/// Deletes a group, refusing to remove its members along with it unless forced
+#[delete("/api/v1beta1/groups/{name}")]
+pub async fn delete(
+    service: Data<GroupService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<GroupPathParam>,
+    force: Query<ForceQuery>,
+) -> ApiResult<HttpResponse> {
+    Scope::from("groups:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Group::build_guid(&path.name), "delete")?;
+
+    let group = find(&service, &path.name).await?;
+    if !group.members().is_empty() && !force.force {
+        return Err(ApiError::Conflict(format!(
+            "group {} still has {} members, delete it with force=true to remove them",
+            group.name(),
+            group.members().len()
+        )));
+    }
+    service.delete(&group).await?;
+    log::info!(
+        "User {} deleted group {} with {} members",
+        current_user.username(),
+        group.name(),
+        group.members().len()
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[put("/api/v1beta1/groups/{name}/members/{username}")]
+pub async fn add_member(
+    service: Data<GroupService>,
+    users: Data<UserService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<GroupMemberPathParams>,
+) -> ApiResult<Json<GroupResponse>> {
+    Scope::from("groups:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    let sub = Group::build_guid(&path.name);
+    enforcer.check(current_user.id(), &sub, "manage_members")?;
+
+    let mut group = find(&service, &path.name).await?;
+    let user = users
+        .find(&path.username)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} not found", path.username)))?;
+    if group.add_member(user.username()) {
+        group = service.save(group).await?;
+        log::info!(
+            "User {} added user {} to group {}",
+            current_user.username(),
+            user.username(),
+            group.name()
+        );
+    }
+    Ok(Json(GroupResponse::from(&group)))
+}
+
+#[delete("/api/v1beta1/groups/{name}/members/{username}")]
+pub async fn remove_member(
+    service: Data<GroupService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<GroupMemberPathParams>,
+) -> ApiResult<Json<GroupResponse>> {
+    Scope::from("groups:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    let sub = Group::build_guid(&path.name);
+    enforcer.check(current_user.id(), &sub, "manage_members")?;
+
+    let mut group = find(&service, &path.name).await?;
+    if !group.remove_member(&path.username) {
+        return Err(ApiError::NotFound(format!(
+            "User {} is not a member of group {}",
+            path.username,
+            group.name()
+        )));
+    }
+    let group = service.save(group).await?;
+    log::info!(
+        "User {} removed user {} from group {}",
+        current_user.username(),
+        path.username,
+        group.name()
+    );
+    Ok(Json(GroupResponse::from(&group)))
+}
+
+async fn find(service: &GroupService, name: &str) -> ApiResult<Group> {
+    service
+        .find(name)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("Group {} not found", name)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_validates_new_groups() {
+        let request = GroupRequest {
+            name: "developers".to_string(),
+            roles: vec!["deployer".to_string()],
+            ..Default::default()
+        };
+        assert!(request.validate().is_ok());
+
+        let request = GroupRequest {
+            name: "Developers".to_string(),
+            description: Some("d".repeat(DESCRIPTION_LENGTH + 1)),
+            roles: vec![" ".to_string()],
+            members: Vec::new(),
+        };
+        match request.validate() {
+            Err(ApiError::ValidationError(reasons)) => assert_eq!(
+                reasons,
+                vec![
+                    NAME_RULE.to_string(),
+                    "description must be at most 500 characters long".to_string(),
+                    "roles must not be empty".to_string(),
+                ]
+            ),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn it_clears_blank_descriptions() {
+        assert_eq!(description(Some("  ")), None);
+        assert_eq!(description(Some(" Ops ")), Some("Ops".to_string()));
+        assert_eq!(description(None), None);
+    }
+}
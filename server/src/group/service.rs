@@ -0,0 +1,69 @@
+use async_trait::async_trait;
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+
+use crate::couchdb::repository::Repository;
+use crate::group::Group;
+
+/// Groups fetched per request when listing the groups of a user
+const BATCH_SIZE: usize = 100;
+
+pub struct GroupService {
+    db: Database,
+}
+
+#[async_trait]
+impl Repository<Group> for GroupService {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl GroupService {
+    pub fn new(db: Database) -> GroupService {
+        GroupService { db }
+    }
+
+    /// Every group the user is a member of
+    pub async fn groups_of(&self, username: &str) -> Result<Vec<Group>, Error> {
+        let mut groups = Vec::new();
+        let mut bookmark = None;
+        loop {
+            let response = self
+                .db
+                .find_partitioned::<Group>(
+                    "group",
+                    serde_json::json!({
+                        "members": { "$elemMatch": { "$eq": username } }
+                    }),
+                    BATCH_SIZE,
+                    bookmark,
+                )
+                .await?;
+
+            if let Some(warning) = &response.warning {
+                log::warn!("{}", warning);
+            }
+
+            let done = response.docs.len() < BATCH_SIZE;
+            groups.extend(response.docs);
+            if done {
+                return Ok(groups);
+            }
+            bookmark = Some(response.bookmark);
+        }
+    }
+
+    /// Removes the user from every group they are a member of, so that a user registered later
+    /// with the same username does not inherit their roles. Returns the number of groups left.
+    pub async fn remove_member_everywhere(&self, username: &str) -> Result<usize, Error> {
+        let groups = self.groups_of(username).await?;
+        let count = groups.len();
+        for mut group in groups {
+            group.remove_member(username);
+            self.save(group).await?;
+        }
+        Ok(count)
+    }
+}
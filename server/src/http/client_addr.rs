@@ -0,0 +1,467 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use actix_web::dev::{Payload, PayloadStream};
+use actix_web::http::{header, HeaderMap};
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::{ok, Ready};
+use ipnet::IpNet;
+
+use crate::config::CONFIG;
+use crate::http::error::ApiError;
+use crate::oauth::binding::AddressResolver;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Headers the trusted proxies forward the client address with. Only one kind is read,
+/// so that clients cannot smuggle an address in the kind our proxies pass through untouched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ForwardedHeader {
+    /// The standard `Forwarded` header (RFC 7239)
+    Forwarded,
+    /// The `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers
+    XForwarded,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            "x-forwarded" => Ok(ForwardedHeader::XForwarded),
+            other => Err(format!(
+                "unknown forwarding header '{}', expected 'forwarded' or 'x-forwarded'",
+                other
+            )),
+        }
+    }
+}
+
+/// Address of the client that originated a request, as seen through any trusted reverse proxies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientAddr {
+    ip: Option<IpAddr>,
+    scheme: String,
+    host: Option<String>,
+}
+
+impl ClientAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+}
+
+/// Resolves the client address of a request.
+///
+/// Forwarding headers are only honored when the peer is one of the trusted proxies,
+/// otherwise they are ignored and the peer address is used as is.
+/// Only the configured kind of forwarding headers is read, the other one is ignored.
+pub fn resolve(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted: &[IpNet],
+    forwarded: ForwardedHeader,
+    default_scheme: &str,
+) -> ClientAddr {
+    let direct = ClientAddr {
+        ip: peer,
+        scheme: default_scheme.to_string(),
+        host: header_str(headers, header::HOST.as_str()),
+    };
+
+    match peer {
+        Some(peer) if is_trusted(trusted, &peer) => {}
+        _ => return direct,
+    }
+
+    let hops = match forwarded {
+        ForwardedHeader::Forwarded => header_str(headers, header::FORWARDED.as_str())
+            .map(|value| parse_forwarded(&value))
+            .unwrap_or_default(),
+        ForwardedHeader::XForwarded => parse_x_forwarded(headers),
+    };
+
+    if hops.is_empty() {
+        return direct;
+    }
+
+    // Walk the chain from the closest hop, skipping our own proxies.
+    // If every hop is trusted, the leftmost one is the client.
+    let index = hops
+        .iter()
+        .rposition(|hop| match hop.ip {
+            Some(ip) => !is_trusted(trusted, &ip),
+            None => true,
+        })
+        .unwrap_or(0);
+
+    // Scheme and host are set by the proxy closest to us
+    let last = hops.last().unwrap();
+    ClientAddr {
+        ip: hops[index].ip,
+        scheme: last.proto.clone().unwrap_or(direct.scheme),
+        host: last.host.clone().or(direct.host),
+    }
+}
+
+fn is_trusted(trusted: &[IpNet], ip: &IpAddr) -> bool {
+    trusted.iter().any(|net| net.contains(ip))
+}
+
+/// Joins repeated instances of a header into a single comma-separated value
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    let mut instances: Vec<_> = headers.get_all(name).collect();
+    // The header map of actix-http keeps the first instance after the second one
+    if instances.len() > 1 {
+        instances.swap(0, 1);
+    }
+    let values: Vec<&str> = instances
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Hop {
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// Parses an RFC 7239 `Forwarded` header into its hops, from the client to the last proxy.
+fn parse_forwarded(value: &str) -> Vec<Hop> {
+    value
+        .split(',')
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let mut kv = pair.trim().splitn(2, '=');
+                let key = kv.next().unwrap_or_default().trim().to_lowercase();
+                let value = kv.next().unwrap_or_default().trim().trim_matches('"');
+                match key.as_str() {
+                    "for" => hop.ip = parse_node(value),
+                    "proto" => hop.proto = Some(value.to_lowercase()),
+                    "host" => hop.host = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+/// Parses the `X-Forwarded-*` headers into hops. Only the chain of addresses is available,
+/// so the scheme and host appended by the closest proxy are attached to the last hop.
+fn parse_x_forwarded(headers: &HeaderMap) -> Vec<Hop> {
+    let mut hops: Vec<Hop> = header_str(headers, X_FORWARDED_FOR)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|node| Hop {
+                    ip: parse_node(node.trim()),
+                    ..Default::default()
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let proto = header_str(headers, X_FORWARDED_PROTO).map(|value| {
+        value
+            .rsplit(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+    });
+    let host = header_str(headers, X_FORWARDED_HOST).map(|value| {
+        value
+            .rsplit(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    });
+
+    if proto.is_some() || host.is_some() {
+        if hops.is_empty() {
+            hops.push(Hop::default());
+        }
+        let last = hops.last_mut().unwrap();
+        last.proto = proto;
+        last.host = host;
+    }
+
+    hops
+}
+
+/// Parses a node identifier, which can be a bare IP, an `ip:port` pair or a `[ipv6]:port` pair.
+/// Unknown and obfuscated identifiers yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip);
+    }
+
+    if let Some(rest) = node.strip_prefix('[') {
+        let end = rest.find(']')?;
+        return IpAddr::from_str(&rest[..end]).ok();
+    }
+
+    SocketAddr::from_str(node).ok().map(|addr| addr.ip())
+}
+
+impl From<&HttpRequest> for ClientAddr {
+    fn from(req: &HttpRequest) -> Self {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let scheme = if CONFIG.tls().enabled() {
+            "https"
+        } else {
+            "http"
+        };
+        let proxy = CONFIG.proxy();
+        let addr = resolve(
+            peer,
+            req.headers(),
+            &proxy.trusted(),
+            proxy.header(),
+            scheme,
+        );
+        log::debug!("Resolved client address {:?}", &addr);
+        addr
+    }
+}
+
+/// Resolves the addresses tokens are bound to through the trusted proxies, see [`crate::oauth::binding`]
+pub struct ClientAddrResolver;
+
+impl AddressResolver for ClientAddrResolver {
+    fn resolve(&self, req: &HttpRequest) -> Option<IpAddr> {
+        ClientAddr::from(req).ip()
+    }
+}
+
+impl FromRequest for ClientAddr {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        ok(ClientAddr::from(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+    use ipnet::IpNet;
+
+    use super::resolve;
+    use super::ForwardedHeader::{self, Forwarded, XForwarded};
+
+    fn ip(ip: &str) -> IpAddr {
+        IpAddr::from_str(ip).unwrap()
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        vec![
+            IpNet::from_str("10.0.0.0/8").unwrap(),
+            IpNet::from_str("fd00::/8").unwrap(),
+        ]
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn it_uses_the_peer_without_forwarding_headers() {
+        for forwarded in &[Forwarded, XForwarded] {
+            let addr = resolve(
+                Some(ip("10.0.0.1")),
+                &HeaderMap::new(),
+                &trusted(),
+                *forwarded,
+                "http",
+            );
+            assert_eq!(addr.ip(), Some(ip("10.0.0.1")));
+            assert_eq!(addr.scheme(), "http");
+        }
+    }
+
+    #[test]
+    fn it_resolves_chained_x_forwarded_for() {
+        let headers = headers(&[
+            ("x-forwarded-for", "203.0.113.7, 198.51.100.2, 10.0.0.5"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "enseada.example.com"),
+        ]);
+        let addr = resolve(
+            Some(ip("10.0.0.1")),
+            &headers,
+            &trusted(),
+            XForwarded,
+            "http",
+        );
+        assert_eq!(addr.ip(), Some(ip("198.51.100.2")));
+        assert_eq!(addr.scheme(), "https");
+        assert_eq!(addr.host(), Some("enseada.example.com"));
+    }
+
+    #[test]
+    fn it_takes_scheme_and_host_from_the_closest_proxy() {
+        let headers = headers(&[
+            ("x-forwarded-for", "203.0.113.7, 10.0.0.5"),
+            ("x-forwarded-proto", "http, https"),
+            ("x-forwarded-host", "evil.example.com"),
+            ("x-forwarded-host", "enseada.example.com"),
+        ]);
+        let addr = resolve(
+            Some(ip("10.0.0.1")),
+            &headers,
+            &trusted(),
+            XForwarded,
+            "http",
+        );
+        assert_eq!(addr.scheme(), "https");
+        assert_eq!(addr.host(), Some("enseada.example.com"));
+    }
+
+    #[test]
+    fn it_takes_the_leftmost_hop_when_all_are_trusted() {
+        let headers = headers(&[("x-forwarded-for", "10.1.1.1, 10.0.0.5")]);
+        let addr = resolve(
+            Some(ip("10.0.0.1")),
+            &headers,
+            &trusted(),
+            XForwarded,
+            "http",
+        );
+        assert_eq!(addr.ip(), Some(ip("10.1.1.1")));
+    }
+
+    #[test]
+    fn it_ignores_headers_from_untrusted_peers() {
+        let headers = headers(&[
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=203.0.113.7;proto=https"),
+        ]);
+        for forwarded in &[Forwarded, XForwarded] {
+            let addr = resolve(
+                Some(ip("198.51.100.9")),
+                &headers,
+                &trusted(),
+                *forwarded,
+                "http",
+            );
+            assert_eq!(addr.ip(), Some(ip("198.51.100.9")));
+            assert_eq!(addr.scheme(), "http");
+        }
+    }
+
+    #[test]
+    fn it_only_reads_the_configured_header() {
+        let headers = headers(&[
+            ("x-forwarded-for", "203.0.113.99"),
+            (
+                "forwarded",
+                "for=203.0.113.7:4711;proto=https;host=enseada.example.com",
+            ),
+        ]);
+        let addr = resolve(
+            Some(ip("10.0.0.1")),
+            &headers,
+            &trusted(),
+            Forwarded,
+            "http",
+        );
+        assert_eq!(addr.ip(), Some(ip("203.0.113.7")));
+        assert_eq!(addr.scheme(), "https");
+        assert_eq!(addr.host(), Some("enseada.example.com"));
+
+        let addr = resolve(
+            Some(ip("10.0.0.1")),
+            &headers,
+            &trusted(),
+            XForwarded,
+            "http",
+        );
+        assert_eq!(addr.ip(), Some(ip("203.0.113.99")));
+        assert_eq!(addr.scheme(), "http");
+
+        let headers = self::headers(&[("x-forwarded-for", "203.0.113.99")]);
+        let addr = resolve(
+            Some(ip("10.0.0.1")),
+            &headers,
+            &trusted(),
+            Forwarded,
+            "http",
+        );
+        assert_eq!(addr.ip(), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn it_parses_ipv6_addresses() {
+        let headers = headers(&[(
+            "forwarded",
+            "for=\"[2001:db8:cafe::17]:4711\", for=\"[fd00::1]\"",
+        )]);
+        let addr = resolve(Some(ip("fd00::2")), &headers, &trusted(), Forwarded, "http");
+        assert_eq!(addr.ip(), Some(ip("2001:db8:cafe::17")));
+
+        let headers = self::headers(&[("x-forwarded-for", "2001:db8::1, fd00::3")]);
+        let addr = resolve(
+            Some(ip("fd00::2")),
+            &headers,
+            &trusted(),
+            XForwarded,
+            "http",
+        );
+        assert_eq!(addr.ip(), Some(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn it_does_not_guess_unknown_clients() {
+        let headers = headers(&[("forwarded", "for=unknown, for=10.0.0.5")]);
+        let addr = resolve(
+            Some(ip("10.0.0.1")),
+            &headers,
+            &trusted(),
+            Forwarded,
+            "http",
+        );
+        assert_eq!(addr.ip(), None);
+    }
+
+    #[test]
+    fn it_parses_the_header_kind() {
+        assert_eq!(ForwardedHeader::from_str("Forwarded"), Ok(Forwarded));
+        assert_eq!(ForwardedHeader::from_str("x-forwarded"), Ok(XForwarded));
+        assert!(ForwardedHeader::from_str("x-real-ip").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::couchdb::repository::Entity;
+use crate::http::extractor::user::CurrentUser;
+
+/// Maximum number of ids of affected documents reported by a plan
+pub const SAMPLE_SIZE: usize = 10;
+
+/// `?dry_run=true` convention of destructive operations: they compute a [`Plan`] of what would
+/// change and, on a dry run, return it instead of applying it.
+#[derive(Debug, Default, Deserialize)]
+pub struct DryRunQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl DryRunQuery {
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+}
+
+/// What a destructive operation would change, without performing any write
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Plan {
+    pub action: String,
+    pub count: usize,
+    pub sample_ids: Vec<String>,
+    /// Changes making up the operation, in the order they are applied
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<Plan>,
+}
+
+impl Plan {
+    pub fn new<I: IntoIterator<Item = String>>(action: &str, ids: I) -> Self {
+        let mut count = 0;
+        let mut sample_ids = Vec::new();
+        for id in ids {
+            if sample_ids.len() < SAMPLE_SIZE {
+                sample_ids.push(id);
+            }
+            count += 1;
+        }
+        Plan {
+            action: action.to_string(),
+            count,
+            sample_ids,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Plan of an operation made of several steps, counting every change of them
+    pub fn of_steps(action: &str, steps: Vec<Plan>) -> Self {
+        let ids = steps
+            .iter()
+            .flat_map(|step| step.sample_ids.iter().cloned());
+        let mut plan = Plan::new(action, ids);
+        plan.count = steps.iter().map(|step| step.count).sum();
+        plan.steps = steps;
+        plan
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DryRunResponse {
+    pub dry_run: bool,
+    #[serde(flatten)]
+    pub plan: Plan,
+}
+
+/// Reports the plan of a dry run to the caller and records that it was performed
+pub fn respond(plan: Plan, current_user: &CurrentUser) -> HttpResponse {
+    log::info!(
+        "User {} performed a dry run of '{}', affecting {} documents",
+        current_user.id(),
+        &plan.action,
+        plan.count
+    );
+    HttpResponse::Ok().json(DryRunResponse {
+        dry_run: true,
+        plan,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::web::Query;
+
+    use super::*;
+
+    #[test]
+    fn it_defaults_to_a_real_run() {
+        let query = Query::<DryRunQuery>::from_query("").unwrap();
+        assert!(!query.is_dry_run());
+        let query = Query::<DryRunQuery>::from_query("dry_run=false").unwrap();
+        assert!(!query.is_dry_run());
+        let query = Query::<DryRunQuery>::from_query("dry_run=true").unwrap();
+        assert!(query.is_dry_run());
+    }
+
+    #[test]
+    fn it_samples_the_affected_ids() {
+        let plan = Plan::new("tokens:revoke", (0..25).map(|i| i.to_string()));
+        assert_eq!(plan.count, 25);
+        assert_eq!(plan.sample_ids.len(), SAMPLE_SIZE);
+        assert_eq!(plan.sample_ids[0], "0");
+    }
+
+    #[test]
+    fn it_counts_the_changes_of_every_step() {
+        let plan = Plan::of_steps(
+            "users:deactivate",
+            vec![
+                Plan::new("users:deactivate", vec!["user:jdoe".to_string()]),
+                Plan::new("tokens:revoke", (0..25).map(|i| i.to_string())),
+            ],
+        );
+        assert_eq!(plan.count, 26);
+        assert_eq!(plan.sample_ids.len(), SAMPLE_SIZE);
+        assert_eq!(plan.sample_ids[0], "user:jdoe");
+        assert_eq!(plan.steps[1].count, 25);
+    }
+
+    #[test]
+    fn it_marks_the_response_as_a_dry_run() {
+        let res = DryRunResponse {
+            dry_run: true,
+            plan: Plan::new("clients:delete", vec!["client:test".to_string()]),
+        };
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            serde_json::json!({
+                "dry_run": true,
+                "action": "clients:delete",
+                "count": 1,
+                "sample_ids": ["client:test"],
+            })
+        );
+    }
+}
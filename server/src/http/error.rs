@@ -1,7 +1,7 @@
 use actix::MailboxError;
 use actix_web::dev::ServiceResponse;
 use actix_web::error::BlockingError;
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
 use actix_web::middleware::errhandlers::ErrorHandlerResponse;
 use actix_web::{Error as HttpError, HttpResponse, ResponseError};
 use derive_more::Display;
@@ -9,9 +9,10 @@ use serde::{Deserialize, Serialize};
 use url::ParseError;
 
 use couchdb::error::Error as CouchError;
+use couchdb::guid::GuidError;
 use enseada::error::Error;
 
-use crate::oauth::error::{Error as OAuthError, ErrorKind};
+use crate::oauth::error::Error as OAuthError;
 use crate::rbac::EvaluationError;
 
 #[derive(Debug, Display, PartialEq, Eq)]
@@ -21,18 +22,29 @@ pub enum ApiError {
     BlockingError(String),
     Conflict(String),
     Forbidden(String),
+    /// Forbidden, with a code telling clients why, as the `code` of the error response
+    #[display(fmt = "{}", _1)]
+    ForbiddenWithCode(&'static str, String),
     InternalServerError(String),
     NotFound(String),
     #[display(fmt = "")]
     ValidationError(Vec<String>),
     Unauthorized(String),
     ServiceUnavailable(String),
+    /// The database did not answer in time
+    GatewayTimeout(String),
+    /// Rejected until the given number of seconds have passed
+    #[display(fmt = "{}", _0)]
+    TooManyRequests(String, u64),
 }
 
 /// User-friendly error messages
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ErrorResponse {
     error: String,
+    /// Machine-readable code of the error, for the errors clients are expected to handle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
     reasons: Vec<String>,
 }
 
@@ -45,6 +57,7 @@ impl ApiError {
             StatusCode::NOT_FOUND => ApiError::NotFound(reason),
             StatusCode::UNAUTHORIZED => ApiError::Unauthorized(reason),
             StatusCode::SERVICE_UNAVAILABLE => ApiError::ServiceUnavailable(reason),
+            StatusCode::GATEWAY_TIMEOUT => ApiError::GatewayTimeout(reason),
             _ => ApiError::InternalServerError(reason),
         }
     }
@@ -64,10 +77,13 @@ impl ResponseError for ApiError {
             ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
             ApiError::Conflict(_) => StatusCode::CONFLICT,
             ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::ForbiddenWithCode(_, _) => StatusCode::FORBIDDEN,
             ApiError::NotFound(_) => StatusCode::NOT_FOUND,
-            ApiError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            ApiError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
             ApiError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::TooManyRequests(_, _) => StatusCode::TOO_MANY_REQUESTS,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -79,6 +95,10 @@ impl ResponseError for ApiError {
                 .json::<ErrorResponse>(ErrorResponse::new(self.status_code(), vec![error.clone()])),
             ApiError::Forbidden(error) => HttpResponse::Forbidden()
                 .json::<ErrorResponse>(ErrorResponse::new(self.status_code(), vec![error.clone()])),
+            ApiError::ForbiddenWithCode(code, error) => HttpResponse::Forbidden()
+                .json::<ErrorResponse>(
+                    ErrorResponse::new(self.status_code(), vec![error.clone()]).with_code(code),
+                ),
             ApiError::NotFound(error) => HttpResponse::NotFound()
                 .json::<ErrorResponse>(ErrorResponse::new(self.status_code(), vec![error.clone()])),
             ApiError::ValidationError(errors) => HttpResponse::UnprocessableEntity()
@@ -87,6 +107,11 @@ impl ResponseError for ApiError {
                 .json::<ErrorResponse>(ErrorResponse::new(self.status_code(), vec![error.clone()])),
             ApiError::ServiceUnavailable(error) => HttpResponse::ServiceUnavailable()
                 .json::<ErrorResponse>(ErrorResponse::new(self.status_code(), vec![error.clone()])),
+            ApiError::GatewayTimeout(error) => HttpResponse::GatewayTimeout()
+                .json::<ErrorResponse>(ErrorResponse::new(self.status_code(), vec![error.clone()])),
+            ApiError::TooManyRequests(error, retry_after) => HttpResponse::TooManyRequests()
+                .header(header::RETRY_AFTER, retry_after.to_string())
+                .json::<ErrorResponse>(ErrorResponse::new(self.status_code(), vec![error.clone()])),
             _ => HttpResponse::InternalServerError().finish(),
         }
     }
@@ -99,9 +124,15 @@ impl ErrorResponse {
                 .canonical_reason()
                 .unwrap_or_else(|| "Internal Server Error")
                 .to_string(),
+            code: None,
             reasons,
         }
     }
+
+    pub fn with_code(mut self, code: &str) -> Self {
+        self.code = Some(code.to_string());
+        self
+    }
 }
 
 /// Convert Thread BlockingErrors to ApiErrors
@@ -116,26 +147,52 @@ impl From<BlockingError<ApiError>> for ApiError {
 
 impl From<Error> for ApiError {
     fn from(err: Error) -> Self {
+        if let Error::Database { source } = err {
+            return ApiError::from(source);
+        }
         let message = err.to_string();
         match err.status() {
+            StatusCode::BAD_REQUEST => ApiError::BadRequest(message),
             StatusCode::CONFLICT => ApiError::Conflict(message),
+            StatusCode::FORBIDDEN => ApiError::Forbidden(message),
             StatusCode::NOT_FOUND => ApiError::NotFound(message),
+            StatusCode::GATEWAY_TIMEOUT => ApiError::GatewayTimeout(message),
             _ => ApiError::InternalServerError(message),
         }
     }
 }
 
+/// Maps the errors of CouchDB to the responses of the API. Errors caused by how the server talks
+/// to CouchDB, like refused credentials, are not the fault of the client and answer 500.
 impl From<CouchError> for ApiError {
     fn from(err: CouchError) -> Self {
-        let message = err.to_string();
-        match err.status() {
-            StatusCode::CONFLICT => ApiError::Conflict(message),
-            StatusCode::NOT_FOUND => ApiError::NotFound(message),
-            _ => ApiError::InternalServerError(message),
+        match err {
+            CouchError::NotFound(message) => ApiError::NotFound(message),
+            CouchError::Conflict(message) | CouchError::PreconditionFailed(message) => {
+                ApiError::Conflict(message)
+            }
+            // Refused by a validation function
+            CouchError::Forbidden(message) => ApiError::Forbidden(message),
+            CouchError::TooManyRequests(message) => ApiError::ServiceUnavailable(message),
+            CouchError::Timeout(message) => ApiError::GatewayTimeout(message),
+            CouchError::Server(status, message) if status == StatusCode::SERVICE_UNAVAILABLE => {
+                ApiError::ServiceUnavailable(message)
+            }
+            CouchError::Unauthorized(message) => {
+                log::error!("CouchDB refused the credentials of the server: {}", message);
+                ApiError::InternalServerError(message)
+            }
+            err => ApiError::InternalServerError(err.to_string()),
         }
     }
 }
 
+impl From<GuidError> for ApiError {
+    fn from(err: GuidError) -> Self {
+        ApiError::BadRequest(err.to_string())
+    }
+}
+
 impl From<HttpError> for ApiError {
     fn from(err: HttpError) -> Self {
         Self::from(&err)
@@ -157,14 +214,7 @@ impl From<url::ParseError> for ApiError {
 
 impl From<OAuthError> for ApiError {
     fn from(err: OAuthError) -> Self {
-        let message = err.description().to_string();
-        match err.kind() {
-            ErrorKind::AccessDenied => ApiError::Forbidden(message),
-            ErrorKind::InvalidClient => ApiError::Unauthorized(message),
-            ErrorKind::ServerError | ErrorKind::Unknown => ApiError::InternalServerError(message),
-            ErrorKind::TemporarilyUnavailable => ApiError::ServiceUnavailable(message),
-            _ => ApiError::BadRequest(message),
-        }
+        ApiError::new(err.status_code(), err.description().to_string())
     }
 }
 
@@ -194,3 +244,38 @@ pub fn handle_bad_request<B>(
     log::error!("{}", &err);
     Ok(ErrorHandlerResponse::Response(res.error_response(err)))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_maps_couchdb_errors_in_one_place() {
+        let not_found = CouchError::not_found("missing".to_string());
+        assert_eq!(
+            ApiError::from(Error::from(not_found)),
+            ApiError::NotFound("missing".to_string())
+        );
+        let exists = CouchError::from_response(StatusCode::PRECONDITION_FAILED, "");
+        assert_eq!(ApiError::from(exists).status_code(), StatusCode::CONFLICT);
+        let refused = CouchError::from_response(StatusCode::UNAUTHORIZED, "");
+        assert_eq!(
+            ApiError::from(refused).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        let timeout = CouchError::timeout("operation timed out".to_string());
+        assert_eq!(
+            ApiError::from(timeout).status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_ids() {
+        let invalid = couchdb::guid::Guid::partitioned("user", "_admin").unwrap_err();
+        assert_eq!(
+            ApiError::from(Error::from(invalid)).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::ops::Deref;
 use std::pin::Pin;
 
 use actix_web::dev::{Payload, PayloadStream};
@@ -7,9 +8,30 @@ use futures::Future;
 use crate::http::error::ApiError;
 use crate::http::extractor::session::TokenSession;
 use crate::oauth::scope::Scope as OAuthScope;
-use crate::oauth::session::Session;
 
-pub type Scope = OAuthScope;
+/// Scope granted to the access token authenticating the current request
+#[derive(Clone, Debug)]
+pub struct Scope(OAuthScope);
+
+impl Deref for Scope {
+    type Target = OAuthScope;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<&str> for Scope {
+    fn from(scope: &str) -> Self {
+        Scope(OAuthScope::from(scope))
+    }
+}
+
+impl From<Vec<&str>> for Scope {
+    fn from(scope: Vec<&str>) -> Self {
+        Scope(OAuthScope::from(scope))
+    }
+}
 
 impl FromRequest for Scope {
     type Error = ApiError;
@@ -20,9 +42,9 @@ impl FromRequest for Scope {
         log::debug!("Extracting token scope from request");
         let session_fut = TokenSession::from_request(req, payload);
         Box::pin(async move {
-            let session: Session = session_fut.await?;
+            let session = session_fut.await?;
             log::debug!("Extracted session: {:?}", &session);
-            Ok(session.scope().clone())
+            Ok(Scope(session.scope().clone()))
         })
     }
 }
@@ -1,4 +1,6 @@
+use std::ops::Deref;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use actix_web::dev::{Payload, PayloadStream};
 use actix_web::http::header;
@@ -6,14 +8,70 @@ use actix_web::web::Data;
 use actix_web::{FromRequest, HttpRequest};
 use actix_web_httpauth::headers::authorization::{Basic, Bearer, ParseError, Scheme};
 use futures::Future;
+use url::Url;
 
+use enseada::guid::Guid;
+use enseada::secure;
+
+use crate::config::CONFIG;
+use crate::couchdb::repository::Repository;
 use crate::http::error::ApiError;
-use crate::oauth::handler::TokenIntrospectionHandler;
+use crate::oauth::binding::Origin;
+use crate::oauth::facade::Oauth;
+use crate::oauth::last_used::LastUsedTracker;
 use crate::oauth::session::Session;
 use crate::oauth::token::{AccessToken, Token};
-use crate::oauth::{ConcreteOAuthHandler, Expirable};
+use crate::oauth::Expirable;
+use crate::user::api_key::{ApiKeyService, KEY_ID_PREFIX};
+use crate::user::pat::{PatService, PAT_PREFIX};
+use crate::user::UserService;
+
+/// Session of the credentials authenticating the current request, either an OAuth access token,
+/// a personal access token, or an API key sent as basic credentials
+#[derive(Clone, Debug)]
+pub struct TokenSession(Session, Option<String>);
+
+impl TokenSession {
+    /// Signature the OAuth access token is stored under,
+    /// none for personal access tokens and API keys
+    pub fn token_signature(&self) -> Option<&str> {
+        self.1.as_deref()
+    }
+}
 
-pub type TokenSession = Session;
+impl Deref for TokenSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// User impersonating the user of the current request, kept in its extensions once its token
+/// is verified so that the audit events it records name them
+#[derive(Clone, Debug)]
+pub struct Impersonator(String);
+
+impl Impersonator {
+    /// ID of the impersonator of the request, if it is authenticated with an impersonation token
+    pub fn of(req: &HttpRequest) -> Option<String> {
+        req.extensions()
+            .get::<Impersonator>()
+            .map(|Impersonator(user_id)| user_id.clone())
+    }
+}
+
+/// Resource the API is known as, registered as application data to only accept
+/// OAuth tokens issued for it with the `resource` parameter. Personal access tokens and API keys
+/// are always accepted.
+#[derive(Clone, Debug)]
+pub struct RequiredAudience(String);
+
+impl RequiredAudience {
+    pub fn new(resource: &Url) -> Self {
+        RequiredAudience(resource.to_string())
+    }
+}
 
 impl FromRequest for TokenSession {
     type Error = ApiError;
@@ -22,53 +80,151 @@ impl FromRequest for TokenSession {
 
     fn from_request(req: &HttpRequest, payload: &mut Payload<PayloadStream>) -> Self::Future {
         log::debug!("Extracting token session from request");
-        let handler_fut = Data::<ConcreteOAuthHandler>::from_request(req, payload);
+        let oauth_fut = Data::<Arc<dyn Oauth>>::from_request(req, payload);
         let header = req.headers().get(header::AUTHORIZATION);
+        let basic = header
+            .map(Basic::parse)
+            .and_then(Result::<Basic, ParseError>::ok);
+        let api_key = basic
+            .as_ref()
+            .filter(|basic| basic.user_id().starts_with(KEY_ID_PREFIX))
+            .map(|basic| {
+                let secret = basic.password().map(|secret| secret.to_string());
+                (basic.user_id().to_string(), secret.unwrap_or_default())
+            });
         let token = header
             .map(Bearer::parse)
             .and_then(Result::<Bearer, ParseError>::ok)
             .map(|bearer| bearer.token().clone())
             .or_else(|| {
-                header
-                    .map(Basic::parse)
-                    .and_then(Result::<Basic, ParseError>::ok)
-                    .and_then(|basic| {
-                        let username = basic.user_id();
-                        if username.ne("x-oauth-token") {
-                            None
-                        } else {
-                            basic.password().cloned()
-                        }
-                    })
+                basic.and_then(|basic| {
+                    let username = basic.user_id();
+                    if username.ne("x-oauth-token") {
+                        None
+                    } else {
+                        basic.password().cloned()
+                    }
+                })
             });
+        let pats = req.app_data::<Data<PatService>>().cloned();
+        let keys = req.app_data::<Data<ApiKeyService>>().cloned();
+        let audience = req.app_data::<Data<RequiredAudience>>().cloned();
+        let users = req.app_data::<Data<UserService>>().cloned();
+        let last_used = req.app_data::<Data<LastUsedTracker>>().cloned();
+        let req = req.clone();
         Box::pin(async move {
-            match token {
-                Some(token) => {
+            let session = match (api_key, token) {
+                (Some((key_id, secret)), _) => {
+                    log::debug!("API key found");
+                    let keys = keys.ok_or_else(ApiError::unauthorized)?;
+                    match keys.verify(&key_id, &secret).await? {
+                        Some(key) if key.is_expired() => Err(expired_token()),
+                        Some(key) => {
+                            keys.touch(&key);
+                            Ok(TokenSession(key.session(), None))
+                        }
+                        None => Err(ApiError::unauthorized()),
+                    }
+                }
+                (None, Some(token)) if token.starts_with(PAT_PREFIX) => {
+                    log::debug!("Personal access token found");
+                    let pats = pats.ok_or_else(ApiError::unauthorized)?;
+                    match pats.find_by_token(&token).await? {
+                        Some(pat) if pat.is_expired() => Err(expired_token()),
+                        Some(pat) => Ok(TokenSession(pat.session(), None)),
+                        None => Err(ApiError::unauthorized()),
+                    }
+                }
+                (None, Some(token)) => {
                     log::debug!("Token found");
-                    let oauth_handler = handler_fut.await?;
-                    let access_token: AccessToken = oauth_handler
-                        .get_token(&token)
+                    let oauth = oauth_fut.await?;
+                    let access_token: AccessToken = oauth
+                        .access_token(&token)
                         .await
                         .map_err(|_| ApiError::unauthorized())?;
-                    if access_token.is_expired() {
+                    if access_token.is_expired() || access_token.session().is_expired() {
                         log::debug!("Token is expired");
-                        TokenIntrospectionHandler::<AccessToken>::revoke_token(
-                            oauth_handler.get_ref(),
-                            &token,
-                        )
-                        .await
-                        .map_err(|_| ApiError::unauthorized())?;
-                        Err(ApiError::unauthorized())
+                        oauth
+                            .revoke_access_token(&token)
+                            .await
+                            .map_err(|_| ApiError::unauthorized())?;
+                        Err(expired_token())
                     } else {
-                        log::debug!("Token is valid");
-                        Ok(access_token.session().clone())
+                        let session = access_token.session();
+                        let request_origin = Origin::of(&req);
+                        match session.origin() {
+                            Some(origin) if !origin.matches(&request_origin) => {
+                                log::warn!(
+                                    "Token of client {} used from another origin than {:?}",
+                                    session.client_id(),
+                                    origin
+                                );
+                                Err(ApiError::Unauthorized(
+                                    "invalid_grant: token was issued to another origin, refresh it"
+                                        .to_string(),
+                                ))
+                            }
+                            _ if !is_meant_for(session, audience.as_deref().map(Arc::as_ref)) => {
+                                log::warn!(
+                                    "Token of client {} is not meant for this server",
+                                    session.client_id()
+                                );
+                                Err(ApiError::Unauthorized(
+                                    "invalid_token: token was issued for another resource"
+                                        .to_string(),
+                                ))
+                            }
+                            _ => {
+                                log::debug!("Token is valid");
+                                let sig = secure::generate_signature(&token, &CONFIG.secret_key());
+                                let sig = sig.to_string();
+                                if let Some(last_used) = &last_used {
+                                    last_used.touch(&sig, request_origin.ip());
+                                }
+                                Ok(TokenSession(session.clone(), Some(sig)))
+                            }
+                        }
                     }
                 }
-                None => {
+                (None, None) => {
                     log::debug!("Token not found");
                     Err(ApiError::unauthorized())
                 }
+            }?;
+
+            if let (Some(users), Some(user_id)) = (users, session.user_id().as_deref()) {
+                reject_disabled(&users, user_id).await?;
             }
+            if let Some(impersonator) = session.impersonator() {
+                req.extensions_mut()
+                    .insert(Impersonator(impersonator.to_string()));
+            }
+            Ok(session)
         })
     }
 }
+
+/// Rejects the tokens of users disabled after they were issued
+async fn reject_disabled(users: &UserService, user_id: &str) -> Result<(), ApiError> {
+    let guid = Guid::from(user_id.to_string());
+    match users.find(guid.id()).await? {
+        Some(user) if !user.is_enabled() => {
+            log::debug!("User {} is disabled", user_id);
+            Err(ApiError::Unauthorized(
+                "invalid_token: user is disabled".to_string(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_meant_for(session: &Session, audience: Option<&RequiredAudience>) -> bool {
+    match audience {
+        Some(RequiredAudience(audience)) => session.resources().contains(audience),
+        None => true,
+    }
+}
+
+fn expired_token() -> ApiError {
+    ApiError::Unauthorized("invalid_token: token is expired".to_string())
+}
@@ -10,6 +10,7 @@ use enseada::guid::Guid;
 use crate::couchdb::repository::{Entity, Repository};
 use crate::http::error::ApiError;
 use crate::http::extractor::session::TokenSession;
+use crate::user::usage::UsageTracker;
 use crate::user::{User, UserService};
 
 pub type CurrentUser = User;
@@ -23,6 +24,7 @@ impl FromRequest for CurrentUser {
         log::debug!("Extracting current user from request");
         let service_fut = Data::<UserService>::from_request(req, payload);
         let session_fut = TokenSession::from_request(req, payload);
+        let req = req.clone();
         Box::pin(async move {
             let service = service_fut.await?;
             let session: TokenSession = session_fut.await?;
@@ -34,8 +36,17 @@ impl FromRequest for CurrentUser {
             let guid = Guid::from(username.clone());
             let user = service.find(guid.id()).await?;
             match user {
+                Some(user) if !user.is_enabled() => {
+                    log::debug!("User {} is disabled", user.id());
+                    Err(ApiError::Unauthorized("unauthorized".to_string()))
+                }
                 Some(user) => {
                     log::debug!("Found user {}", user.id());
+                    if let Some(tracker) = req.app_data::<Data<UsageTracker>>() {
+                        if let Some(warning) = tracker.record(user.username()) {
+                            req.extensions_mut().insert(warning);
+                        }
+                    }
                     Ok(user)
                 }
                 None => Err(ApiError::Unauthorized("unauthorized".to_string())),
@@ -2,9 +2,12 @@ use serde::Deserialize;
 
 use crate::http::error::ApiError;
 
+pub mod client_addr;
+pub mod dry_run;
 pub mod error;
 pub mod extractor;
 pub mod middleware;
+pub mod urls;
 
 pub type ApiResult<T> = Result<T, ApiError>;
 
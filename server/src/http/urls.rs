@@ -0,0 +1,217 @@
+use actix_web::dev::{Payload, PayloadStream};
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use ipnet::IpNet;
+use url::{Position, Url};
+
+use crate::http::client_addr::{self, ForwardedHeader};
+use crate::http::error::ApiError;
+
+/// Builds the absolute URLs handed out to clients, like redirects and links in pages.
+///
+/// URLs are rooted at the public host and the path prefix of the reverse proxy in front of us,
+/// if any. Extracted from a request, they use https whenever the client reached us over https.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UrlBuilder {
+    base: Url,
+    trusted: Vec<IpNet>,
+    forwarded: ForwardedHeader,
+}
+
+impl UrlBuilder {
+    /// Fails if the public host is not an absolute http(s) URL
+    pub fn new(public_host: &Url, prefix: Option<&str>) -> Result<Self, String> {
+        let is_http = public_host.scheme() == "http" || public_host.scheme() == "https";
+        if !is_http || public_host.host().is_none() {
+            return Err(format!(
+                "public host '{}' must be an absolute http(s) URL",
+                public_host
+            ));
+        }
+
+        let segments: Vec<&str> = public_host
+            .path()
+            .split('/')
+            .chain(prefix.unwrap_or_default().split('/'))
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let mut base = public_host.clone();
+        base.set_query(None);
+        base.set_fragment(None);
+        base.set_path(&segments.join("/"));
+        Ok(UrlBuilder {
+            base,
+            trusted: Vec::new(),
+            forwarded: ForwardedHeader::XForwarded,
+        })
+    }
+
+    /// Proxies trusted to tell the scheme the client used, see [`client_addr::resolve`]
+    pub fn set_trusted_proxies(&mut self, trusted: Vec<IpNet>) -> &mut Self {
+        self.trusted = trusted;
+        self
+    }
+
+    /// Headers the trusted proxies tell the scheme with, `X-Forwarded-*` by default
+    pub fn set_forwarded_header(&mut self, forwarded: ForwardedHeader) -> &mut Self {
+        self.forwarded = forwarded;
+        self
+    }
+
+    /// Switches to https if the request was made over it, but never downgrades to http
+    pub fn with_scheme(&self, scheme: &str) -> Self {
+        let mut base = self.base.clone();
+        if scheme == "https" {
+            // Only fails for non-special schemes, which are rejected on construction
+            base.set_scheme("https").ok();
+        }
+        UrlBuilder {
+            base,
+            trusted: self.trusted.clone(),
+            forwarded: self.forwarded,
+        }
+    }
+
+    /// URL of any path served by us, relative to the root
+    pub fn url(&self, path: &str) -> Url {
+        let mut url = self.base.clone();
+        let path = path.trim_start_matches('/');
+        if !path.is_empty() {
+            let base = self.base.path().trim_end_matches('/');
+            url.set_path(&format!("{}/{}", base, path));
+        }
+        url
+    }
+
+    pub fn api(&self, path: &str) -> Url {
+        self.url(&join("api", path))
+    }
+
+    pub fn ui(&self, path: &str) -> Url {
+        self.url(&join("ui", path))
+    }
+
+    pub fn oauth(&self, path: &str) -> Url {
+        self.url(&join("oauth", path))
+    }
+}
+
+fn join(root: &str, path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        root.to_string()
+    } else {
+        format!("{}/{}", root, path)
+    }
+}
+
+impl FromRequest for UrlBuilder {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        let urls = match req.app_data::<Data<UrlBuilder>>() {
+            Some(urls) => {
+                let peer = req.peer_addr().map(|addr| addr.ip());
+                let addr = client_addr::resolve(
+                    peer,
+                    req.headers(),
+                    &urls.trusted,
+                    urls.forwarded,
+                    urls.base.scheme(),
+                );
+                let public_host = &urls.base[Position::BeforeHost..Position::AfterPort];
+                if let Some(host) = addr.host().filter(|host| *host != public_host) {
+                    log::debug!(
+                        "Request for host {} does not match the public host {}",
+                        host,
+                        public_host
+                    );
+                }
+                Ok(urls.with_scheme(addr.scheme()))
+            }
+            None => Err(ApiError::InternalServerError(
+                "URL builder is not configured".to_string(),
+            )),
+        };
+        ready(urls)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use super::UrlBuilder;
+
+    fn builder(host: &str, prefix: Option<&str>) -> UrlBuilder {
+        UrlBuilder::new(&Url::parse(host).unwrap(), prefix).unwrap()
+    }
+
+    #[test]
+    fn it_builds_urls_without_a_prefix() {
+        let urls = builder("http://localhost:9623", None);
+        assert_eq!(urls.url("/").as_str(), "http://localhost:9623/");
+        assert_eq!(urls.url("/health").as_str(), "http://localhost:9623/health");
+        assert_eq!(urls.ui("").as_str(), "http://localhost:9623/ui");
+        assert_eq!(
+            urls.oauth("/authorize").as_str(),
+            "http://localhost:9623/oauth/authorize"
+        );
+    }
+
+    #[test]
+    fn it_prepends_the_prefix() {
+        let expected = "https://example.com/enseada/api/v1beta1/users";
+        for prefix in &["enseada", "/enseada", "/enseada/", "enseada/"] {
+            let urls = builder("https://example.com", Some(prefix));
+            assert_eq!(urls.api("v1beta1/users").as_str(), expected);
+            assert_eq!(urls.url("").as_str(), "https://example.com/enseada");
+        }
+
+        let urls = builder("https://example.com/tools/", Some("/enseada"));
+        assert_eq!(
+            urls.ui("/auth/callback").as_str(),
+            "https://example.com/tools/enseada/ui/auth/callback"
+        );
+    }
+
+    #[test]
+    fn it_keeps_trailing_slashes_of_paths() {
+        let urls = builder("https://example.com/", Some("/enseada/"));
+        assert_eq!(urls.ui("/").as_str(), "https://example.com/enseada/ui");
+        assert_eq!(
+            urls.ui("auth/callback/").as_str(),
+            "https://example.com/enseada/ui/auth/callback/"
+        );
+    }
+
+    #[test]
+    fn it_forces_https() {
+        let urls = builder("http://example.com:8080", None);
+        assert_eq!(
+            urls.with_scheme("https").ui("").as_str(),
+            "https://example.com:8080/ui"
+        );
+        assert_eq!(
+            urls.with_scheme("http").ui("").as_str(),
+            "http://example.com:8080/ui"
+        );
+
+        let urls = builder("https://example.com", None);
+        assert_eq!(
+            urls.with_scheme("http").ui("").as_str(),
+            "https://example.com/ui"
+        );
+    }
+
+    #[test]
+    fn it_rejects_relative_public_hosts() {
+        for host in &["localhost:9623", "file:///enseada", "mailto:admin@example.com"] {
+            let host = Url::parse(host).unwrap();
+            assert!(UrlBuilder::new(&host, None).is_err(), "{}", host);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Identity of the server as an OAuth issuer, derived from its public host.
+//!
+//! Redirect URIs of the bundled client and anything handed out to clients embed the public host,
+//! so changing it after deployment breaks them. The canonical issuer is stored at first startup,
+//! and the server refuses to start when the public host no longer matches it, unless the change
+//! is explicitly accepted, in which case the dependent data is updated.
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use couchdb::db::Database;
+use enseada::error::Error;
+use enseada::guid::Guid;
+
+use crate::config::CONFIG;
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+use crate::couchdb::{name as dbname, SINGLETON};
+use crate::oauth::client::Client;
+use crate::oauth::persistence::CouchStorage;
+use crate::oauth::storage::ClientStorage;
+
+/// Command line flag accepting a change of the public host, see [`check`]
+pub const ACCEPT_CHANGE_FLAG: &str = "--accept-issuer-change";
+
+/// ID of the client of the bundled UI, whose redirect URI is rooted at the public host
+const BUNDLED_CLIENT_ID: &str = "enseada";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IssuerEntity {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    issuer: String,
+    updated_at: DateTime<Utc>,
+}
+
+impl IssuerEntity {
+    pub fn new(issuer: String) -> Self {
+        IssuerEntity {
+            id: Self::build_guid("issuer"),
+            rev: None,
+            issuer,
+            updated_at: Utc::now(),
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+}
+
+impl Entity for IssuerEntity {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::SETTING, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+/// What to do with the configured issuer, given the stored one
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// First startup, the issuer has to be stored
+    Store,
+    Unchanged,
+    /// The public host changed and the change was accepted
+    Change {
+        previous: String,
+    },
+}
+
+/// Canonical form of an issuer URL: without a trailing slash, query, fragment or default port
+pub fn normalize(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    // Default ports of the scheme are left out on parsing already
+    let port = url.port().map(|port| format!(":{}", port));
+    format!(
+        "{}://{}{}{}",
+        url.scheme(),
+        host,
+        port.unwrap_or_default(),
+        url.path().trim_end_matches('/')
+    )
+}
+
+/// Compares the configured issuer with the stored one, failing if it changed without being accepted
+pub fn decide(
+    stored: Option<&str>,
+    configured: &str,
+    accept_change: bool,
+) -> Result<Decision, Error> {
+    match stored {
+        None => Ok(Decision::Store),
+        Some(stored) if stored == configured => Ok(Decision::Unchanged),
+        Some(stored) if accept_change => Ok(Decision::Change {
+            previous: stored.to_string(),
+        }),
+        Some(stored) => Err(Error::from(format!(
+            "the public host changed from {} to {}, which invalidates the redirect URIs and \
+            tokens bound to the previous one. Restore the previous public host, or start with {} \
+            or ENSEADA_OAUTH_ISSUER_ACCEPTCHANGE=true to accept the change",
+            stored, configured, ACCEPT_CHANGE_FLAG
+        ))),
+    }
+}
+
+/// Moves the redirect URIs rooted at the previous issuer under the new one, leaving others alone.
+/// Returns true if any URI was changed.
+pub fn rebase_redirect_uris(client: &mut Client, previous: &str, issuer: &str) -> bool {
+    let mut changed = false;
+    let uris: HashSet<Url> = client
+        .allowed_redirect_uris()
+        .iter()
+        .map(|uri| {
+            let rebased = uri
+                .as_str()
+                .strip_prefix(previous)
+                .filter(|path| path.is_empty() || path.starts_with('/'))
+                .and_then(|path| Url::parse(&format!("{}{}", issuer, path)).ok());
+            match rebased {
+                Some(rebased) => {
+                    changed = true;
+                    rebased
+                }
+                None => uri.clone(),
+            }
+        })
+        .collect();
+    client.set_allowed_redirect_uris(uris);
+    changed
+}
+
+/// Verifies the configured public host against the stored issuer, see the module documentation.
+/// The change is accepted with the [`ACCEPT_CHANGE_FLAG`] argument or the matching config key.
+pub async fn check(args: &[String]) -> std::io::Result<()> {
+    let accept_change =
+        CONFIG.oauth().issuer().accept_change() || args.iter().any(|arg| arg == ACCEPT_CHANGE_FLAG);
+    let configured = normalize(&CONFIG.urls().url(""));
+    let db = SINGLETON.database(dbname::SYSTEM, true);
+    verify(&db, &configured, accept_change)
+        .await
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))
+}
+
+async fn verify(db: &Database, configured: &str, accept_change: bool) -> Result<(), Error> {
+    let guid = IssuerEntity::build_guid("issuer").to_string();
+    let stored = db.get::<IssuerEntity>(&guid).await?;
+    let decision = decide(
+        stored.as_ref().map(IssuerEntity::issuer),
+        configured,
+        accept_change,
+    )?;
+
+    let mut entity = IssuerEntity::new(configured.to_string());
+    match decision {
+        Decision::Unchanged => return Ok(()),
+        Decision::Store => log::info!("Recording {} as the OAuth issuer", configured),
+        Decision::Change { previous } => {
+            apply_change(&previous, configured).await?;
+            log::warn!(
+                "The OAuth issuer changed from {} to {}, tokens bound to the previous one are no longer valid",
+                previous,
+                configured
+            );
+        }
+    }
+
+    if let Some(rev) = stored.as_ref().and_then(Entity::rev) {
+        entity.set_rev(rev.to_string());
+    }
+    db.put(&guid, &entity).await?;
+    Ok(())
+}
+
+async fn apply_change(previous: &str, issuer: &str) -> Result<(), Error> {
+    let db = Arc::new(SINGLETON.database(dbname::OAUTH, true));
+    let storage = CouchStorage::new(db);
+    if let Some(mut client) = storage.get_client(BUNDLED_CLIENT_ID).await {
+        if rebase_redirect_uris(&mut client, previous, issuer) {
+            storage
+                .save_client(client)
+                .await
+                .map_err(|err| Error::from(err.description()))?;
+            log::info!(
+                "Moved the redirect URIs of client '{}' to {}",
+                BUNDLED_CLIENT_ID,
+                issuer
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::FromIterator;
+
+    use crate::oauth::scope::Scope;
+
+    use super::*;
+
+    fn issuer(url: &str) -> String {
+        normalize(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn it_normalizes_trailing_slashes_and_default_ports() {
+        assert_eq!(issuer("https://Enseada.io:443/"), "https://enseada.io");
+        assert_eq!(issuer("http://localhost:80"), "http://localhost");
+        assert_eq!(issuer("http://localhost:9623/"), "http://localhost:9623");
+        assert_eq!(
+            issuer("https://example.com/enseada/?q=1"),
+            "https://example.com/enseada"
+        );
+    }
+
+    #[test]
+    fn it_stores_the_issuer_on_first_startup() {
+        let decision = decide(None, "https://enseada.io", false).unwrap();
+        assert_eq!(decision, Decision::Store);
+    }
+
+    #[test]
+    fn it_accepts_an_unchanged_issuer() {
+        let configured = issuer("https://enseada.io:443/");
+        let decision = decide(Some("https://enseada.io"), &configured, false).unwrap();
+        assert_eq!(decision, Decision::Unchanged);
+    }
+
+    #[test]
+    fn it_refuses_a_changed_issuer_without_the_flag() {
+        let err = decide(Some("https://old.enseada.io"), "https://enseada.io", false).unwrap_err();
+        assert!(err.to_string().contains(ACCEPT_CHANGE_FLAG));
+    }
+
+    #[test]
+    fn it_updates_the_bundled_client_when_the_change_is_accepted() {
+        let decision = decide(Some("http://localhost:9623"), "https://enseada.io", true).unwrap();
+        assert_eq!(
+            decision,
+            Decision::Change {
+                previous: "http://localhost:9623".to_string()
+            }
+        );
+
+        let mut client = Client::public(
+            BUNDLED_CLIENT_ID.to_string(),
+            Scope::from("*"),
+            HashSet::from_iter(vec![
+                Url::parse("http://localhost:9623/ui/auth/callback").unwrap(),
+                Url::parse("http://localhost:19623/callback").unwrap(),
+                Url::parse("https://other.example.com/callback").unwrap(),
+            ]),
+        );
+        assert!(rebase_redirect_uris(
+            &mut client,
+            "http://localhost:9623",
+            "https://enseada.io"
+        ));
+        let mut uris: Vec<&str> = client
+            .allowed_redirect_uris()
+            .iter()
+            .map(Url::as_str)
+            .collect();
+        uris.sort();
+        assert_eq!(
+            uris,
+            vec![
+                "http://localhost:19623/callback",
+                "https://enseada.io/ui/auth/callback",
+                "https://other.example.com/callback",
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Reactive jobs, run on the changes of a database rather than on an interval.
+//!
+//! The [`ChangeSupervisor`] owns one consumer of the changes feed per database and dispatches
+//! every document change to the [`ChangeListener`]s registered for it. Changes of a database are
+//! dispatched in order, one at a time, to each listener in turn; only changes made after the
+//! supervisor started are seen. Delivery is at-least-once: a dropped feed is resumed from the
+//! last change received, which may be delivered again, so listeners must be idempotent.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_rt::Arbiter;
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use couchdb::changes::ChangeEvent;
+use enseada::error::Error;
+
+use crate::couchdb::SINGLETON;
+
+#[async_trait]
+pub trait ChangeListener: Send + Sync {
+    /// Name of the listener, used in logs
+    fn name(&self) -> &str;
+
+    /// Reacts to the change of the document with the given ID
+    async fn on_change(&self, id: &str, deleted: bool) -> Result<(), Error>;
+}
+
+/// Consumes the changes feeds of the databases listened to, on an arbiter of its own
+pub struct ChangeSupervisor {
+    listeners: HashMap<String, Vec<Arc<dyn ChangeListener>>>,
+    arbiter: Arbiter,
+}
+
+impl ChangeSupervisor {
+    pub fn new() -> Self {
+        ChangeSupervisor {
+            listeners: HashMap::new(),
+            arbiter: Arbiter::new(),
+        }
+    }
+
+    /// Registers the listener for the changes of the database with the given name
+    pub fn listen(&mut self, db_name: &str, listener: Arc<dyn ChangeListener>) -> &mut Self {
+        self.listeners
+            .entry(db_name.to_string())
+            .or_default()
+            .push(listener);
+        self
+    }
+
+    pub fn start(&self) -> Result<(), Error> {
+        for (db_name, listeners) in &self.listeners {
+            let db = SINGLETON.database(db_name, true);
+            let listeners = listeners.clone();
+            let fut = Box::pin(async move {
+                let mut changes = Box::pin(db.changes(None));
+                while let Some(event) = changes.next().await {
+                    dispatch(&listeners, &event).await;
+                }
+            });
+            self.arbiter.send(fut);
+        }
+
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        self.arbiter.stop();
+    }
+}
+
+impl Default for ChangeSupervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hands the change to every listener in turn. A failing listener is logged and does not keep
+/// the others from the change.
+async fn dispatch(listeners: &[Arc<dyn ChangeListener>], event: &ChangeEvent) {
+    let (id, deleted) = match event {
+        ChangeEvent::Next { id, deleted, .. } => (id, deleted.unwrap_or(false)),
+        ChangeEvent::End { .. } => return,
+    };
+    for listener in listeners {
+        log::trace!("Dispatching change of {} to {}", id, listener.name());
+        if let Err(err) = listener.on_change(id, deleted).await {
+            log::error!(
+                "Listener {} failed on the change of {}: {}",
+                listener.name(),
+                id,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    struct Recorder {
+        fail: bool,
+        seen: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ChangeListener for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        async fn on_change(&self, id: &str, _deleted: bool) -> Result<(), Error> {
+            self.seen.lock().unwrap().push(id.to_string());
+            if self.fail {
+                Err(Error::from("tripped over the change"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn event(json: &str) -> ChangeEvent {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn it_dispatches_changes_in_order_to_every_listener() {
+        let failing = Arc::new(Recorder {
+            fail: true,
+            seen: Mutex::new(Vec::new()),
+        });
+        let recorder = Arc::new(Recorder {
+            fail: false,
+            seen: Mutex::new(Vec::new()),
+        });
+        let listeners: Vec<Arc<dyn ChangeListener>> = vec![failing.clone(), recorder.clone()];
+
+        dispatch(
+            &listeners,
+            &event(r#"{"seq":"1-a","id":"first","changes":[{"rev":"1-x"}]}"#),
+        )
+        .await;
+        dispatch(&listeners, &event(r#"{"last_seq":"1-a","pending":0}"#)).await;
+        dispatch(
+            &listeners,
+            &event(r#"{"seq":"2-b","id":"second","changes":[{"rev":"1-y"}],"deleted":true}"#),
+        )
+        .await;
+
+        assert_eq!(*failing.seen.lock().unwrap(), vec!["first", "second"]);
+        assert_eq!(*recorder.seen.lock().unwrap(), vec!["first", "second"]);
+    }
+}
@@ -0,0 +1,397 @@
+//! Periodic maintenance jobs, like purging expired tokens.
+//!
+//! The [`Scheduler`] runs every registered [`Job`] on its interval, delayed by a random jitter so
+//! that instances started together do not hit the database at the same time. Jobs can also be run
+//! on demand, through the jobs API or with `enseada-server admin run-job <name>`.
+//! A job never overlaps its own executions: runs due while it is still running are skipped,
+//! and the interval is backed off exponentially while it keeps failing.
+//! Jobs reacting to the changes of a database are run by a [`changes::ChangeSupervisor`] instead.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_rt::Arbiter;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use enseada::error::Error;
+use enseada::secure;
+
+pub use routes::mount;
+
+use crate::config::CONFIG;
+use crate::couchdb::{name as dbname, SINGLETON};
+use crate::group::GroupService;
+use crate::oauth::jobs::TokenPurge;
+use crate::oauth::persistence::CouchStorage;
+use crate::user::api_key::ApiKeyService;
+use crate::user::pat::PatService;
+use crate::user::purge::UserPurge;
+use crate::user::UserService;
+
+pub mod changes;
+pub mod routes;
+
+/// Largest factor the interval of a failing job is multiplied by
+const MAX_BACKOFF: u32 = 32;
+/// Largest share of the interval added as jitter, in percent
+const JITTER_PERCENT: u32 = 10;
+
+#[async_trait]
+pub trait Job: Send + Sync {
+    /// Unique name, used to trigger the job
+    fn name(&self) -> &str;
+
+    /// Time between two runs of the job
+    fn interval(&self) -> Duration;
+
+    async fn run(&self) -> Result<(), Error>;
+}
+
+/// Outcome of the last run of a job
+#[derive(Clone, Debug)]
+pub struct JobStatus {
+    name: String,
+    interval: Duration,
+    running: bool,
+    last_run: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    failures: u32,
+    next_run: Option<DateTime<Utc>>,
+}
+
+impl JobStatus {
+    fn new(job: &dyn Job) -> Self {
+        JobStatus {
+            name: job.name().to_string(),
+            interval: job.interval(),
+            running: false,
+            last_run: None,
+            last_error: None,
+            failures: 0,
+            next_run: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// When the last run started
+    pub fn last_run(&self) -> Option<&DateTime<Utc>> {
+        self.last_run.as_ref()
+    }
+
+    /// Error the last run failed with, if it did
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Runs failed in a row
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn next_run(&self) -> Option<&DateTime<Utc>> {
+        self.next_run.as_ref()
+    }
+}
+
+struct Entry {
+    job: Arc<dyn Job>,
+    running: AtomicBool,
+    status: Mutex<JobStatus>,
+}
+
+impl Entry {
+    fn new(job: Arc<dyn Job>) -> Self {
+        let status = Mutex::new(JobStatus::new(job.as_ref()));
+        Entry {
+            job,
+            running: AtomicBool::new(false),
+            status,
+        }
+    }
+
+    /// Marks the job as running, unless it already is
+    fn claim(&self) -> bool {
+        self.running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Runs a claimed job, recording its outcome
+    async fn execute(&self) {
+        let name = self.job.name();
+        let started = Utc::now();
+        log::debug!("Running job {}", name);
+        let res = self.job.run().await;
+        {
+            let mut status = self.status.lock().unwrap();
+            status.last_run = Some(started);
+            match res {
+                Ok(()) => {
+                    status.failures = 0;
+                    status.last_error = None;
+                    log::info!(
+                        "Job {} completed in {}ms",
+                        name,
+                        (Utc::now() - started).num_milliseconds()
+                    );
+                }
+                Err(err) => {
+                    status.failures += 1;
+                    status.last_error = Some(err.to_string());
+                    log::error!(
+                        "Job {} failed {} time(s) in a row: {}",
+                        name,
+                        status.failures,
+                        err
+                    );
+                }
+            }
+        }
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    fn status(&self) -> JobStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        status.running = self.running.load(Ordering::SeqCst);
+        status
+    }
+
+    /// Schedules the next run, returning how long to wait for it
+    fn schedule(&self) -> Duration {
+        let mut status = self.status.lock().unwrap();
+        let delay = jitter(backoff(status.interval, status.failures));
+        status.next_run = chrono::Duration::from_std(delay)
+            .ok()
+            .map(|delay| Utc::now() + delay);
+        delay
+    }
+}
+
+/// Registered jobs and the status of their runs
+#[derive(Default)]
+pub struct Jobs {
+    entries: Vec<Arc<Entry>>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, job: Arc<dyn Job>) -> &mut Self {
+        self.entries.push(Arc::new(Entry::new(job)));
+        self
+    }
+
+    pub fn job(&self, name: &str) -> Option<Arc<dyn Job>> {
+        self.entry(name).map(|entry| entry.job.clone())
+    }
+
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.entries.iter().map(|entry| entry.status()).collect()
+    }
+
+    pub fn status(&self, name: &str) -> Option<JobStatus> {
+        self.entry(name).map(|entry| entry.status())
+    }
+
+    /// Runs the job in the background, returning false if it is already running.
+    /// Fails if there is no job with the given name.
+    pub fn trigger(&self, name: &str) -> Result<bool, Error> {
+        let entry = self
+            .entry(name)
+            .cloned()
+            .ok_or_else(|| Error::not_found("job", name))?;
+        if !entry.claim() {
+            return Ok(false);
+        }
+        actix_rt::spawn(async move { entry.execute().await });
+        Ok(true)
+    }
+
+    fn entry(&self, name: &str) -> Option<&Arc<Entry>> {
+        self.entries.iter().find(|entry| entry.job.name() == name)
+    }
+}
+
+/// Runs the registered jobs on their intervals, on an arbiter of its own
+pub struct Scheduler {
+    jobs: Arc<Jobs>,
+    arbiter: Arbiter,
+}
+
+impl Scheduler {
+    pub fn new(jobs: Arc<Jobs>) -> Self {
+        Scheduler {
+            jobs,
+            arbiter: Arbiter::new(),
+        }
+    }
+
+    pub fn start(&self) -> Result<(), Error> {
+        for entry in &self.jobs.entries {
+            let entry = entry.clone();
+            let fut = Box::pin(async move {
+                loop {
+                    let delay = entry.schedule();
+                    actix_rt::time::delay_for(delay).await;
+                    if entry.claim() {
+                        entry.execute().await;
+                    } else {
+                        log::warn!(
+                            "Skipping run of job {}, the previous one is still running",
+                            entry.job.name()
+                        );
+                    }
+                }
+            });
+            self.arbiter.send(fut);
+        }
+
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        self.arbiter.stop();
+    }
+}
+
+/// Jobs run by the server
+pub fn registry() -> Jobs {
+    let db = Arc::new(SINGLETON.database(dbname::OAUTH, true));
+    let storage = Arc::new(CouchStorage::new(db));
+    let mut jobs = Jobs::new();
+    jobs.add(Arc::new(TokenPurge::new(storage.clone())));
+    if let Some(retention) = CONFIG.users().retention() {
+        let users_db = SINGLETON.database(dbname::USERS, true);
+        let oauth_db = SINGLETON.database(dbname::OAUTH, true);
+        jobs.add(Arc::new(UserPurge::new(
+            UserService::new(users_db.clone()),
+            storage,
+            PatService::new(oauth_db.clone(), CONFIG.secret_key()),
+            ApiKeyService::new(oauth_db, CONFIG.secret_key()),
+            GroupService::new(users_db),
+            retention,
+        )));
+    }
+    jobs
+}
+
+/// Interval of a job that failed the given times in a row, doubled for each failure
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32
+        .checked_pow(failures)
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF);
+    interval * factor
+}
+
+/// Delays the interval by up to a tenth of it, at random
+fn jitter(interval: Duration) -> Duration {
+    let random = match secure::generate_token(4) {
+        Ok(token) => {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(token.as_bytes());
+            u32::from_le_bytes(bytes)
+        }
+        Err(err) => {
+            log::warn!("Failed to generate job jitter: {}", err);
+            0
+        }
+    };
+    let max = interval.as_millis() as u64 * JITTER_PERCENT as u64 / 100;
+    let jitter = if max == 0 { 0 } else { random as u64 % max };
+    interval + Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Sleep {
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl Job for Sleep {
+        fn name(&self) -> &str {
+            "sleep"
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(60)
+        }
+
+        async fn run(&self) -> Result<(), Error> {
+            actix_rt::time::delay_for(Duration::from_millis(50)).await;
+            if self.fail {
+                Err(Error::from("woke up on the wrong side"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn jobs(fail: bool) -> Jobs {
+        let mut jobs = Jobs::new();
+        jobs.add(Arc::new(Sleep { fail }));
+        jobs
+    }
+
+    #[test]
+    fn it_backs_off_failing_jobs() {
+        let interval = Duration::from_secs(60);
+        assert_eq!(backoff(interval, 0), interval);
+        assert_eq!(backoff(interval, 2), interval * 4);
+        assert_eq!(backoff(interval, 5), interval * MAX_BACKOFF);
+        assert_eq!(backoff(interval, 100), interval * MAX_BACKOFF);
+    }
+
+    #[test]
+    fn it_jitters_intervals_by_a_tenth_at_most() {
+        let interval = Duration::from_secs(60);
+        for _ in 0..100 {
+            let delay = jitter(interval);
+            assert!(delay >= interval && delay < interval + Duration::from_secs(6));
+        }
+        assert_eq!(jitter(Duration::from_millis(5)), Duration::from_millis(5));
+    }
+
+    #[actix_rt::test]
+    async fn it_does_not_overlap_runs() {
+        let jobs = jobs(false);
+        assert!(jobs.trigger("sleep").unwrap());
+        assert!(!jobs.trigger("sleep").unwrap());
+        assert!(jobs.status("sleep").unwrap().is_running());
+        assert!(jobs.trigger("nap").is_err());
+
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        let status = jobs.status("sleep").unwrap();
+        assert!(!status.is_running());
+        assert!(status.last_run().is_some());
+        assert_eq!(status.failures(), 0);
+        assert!(jobs.trigger("sleep").unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn it_records_failures() {
+        let jobs = jobs(true);
+        assert!(jobs.trigger("sleep").unwrap());
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        let status = jobs.status("sleep").unwrap();
+        assert_eq!(status.failures(), 1);
+        assert_eq!(status.last_error(), Some("woke up on the wrong side"));
+    }
+}
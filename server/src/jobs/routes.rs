@@ -0,0 +1,87 @@
+use actix_web::web::{Data, Json, Path, ServiceConfig};
+use actix_web::{get, post, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use enseada::guid::Guid;
+
+use crate::couchdb::repository::Entity;
+use crate::http::error::ApiError;
+use crate::http::extractor::{scope::Scope, user::CurrentUser};
+use crate::http::ApiResult;
+use crate::jobs::{JobStatus, Jobs};
+use crate::rbac::Enforcer;
+
+pub fn mount(cfg: &mut ServiceConfig) {
+    cfg.service(list);
+    cfg.service(run);
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct JobResponse {
+    pub name: String,
+    /// Seconds between two runs
+    pub interval: u64,
+    pub running: bool,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub failures: u32,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+impl From<&JobStatus> for JobResponse {
+    fn from(status: &JobStatus) -> Self {
+        JobResponse {
+            name: status.name().to_string(),
+            interval: status.interval().as_secs(),
+            running: status.is_running(),
+            last_run: status.last_run().cloned(),
+            last_error: status.last_error().map(str::to_string),
+            failures: status.failures(),
+            next_run: status.next_run().cloned(),
+        }
+    }
+}
+
+#[get("/api/v1beta1/jobs")]
+pub async fn list(
+    jobs: Data<Jobs>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+) -> ApiResult<Json<Vec<JobResponse>>> {
+    Scope::from("system:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("jobs")?, "read")?;
+    let statuses = jobs.statuses();
+    Ok(Json(statuses.iter().map(JobResponse::from).collect()))
+}
+
+/// Starts a run of the job in the background, unless it is already running
+#[post("/api/v1beta1/jobs/{name}/run")]
+pub async fn run(
+    jobs: Data<Jobs>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    name: Path<String>,
+) -> ApiResult<HttpResponse> {
+    Scope::from("system:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("jobs")?, "manage")?;
+
+    let name = name.into_inner();
+    let started = jobs
+        .trigger(&name)
+        .map_err(|_| ApiError::NotFound(format!("job {} not found", name)))?;
+    if !started {
+        return Err(ApiError::Conflict(format!("job {} is already running", name)));
+    }
+
+    log::info!("User {} started job {}", current_user.id(), name);
+    let status = jobs
+        .status(&name)
+        .ok_or_else(|| ApiError::NotFound(format!("job {} not found", name)))?;
+    Ok(HttpResponse::Accepted().json(JobResponse::from(&status)))
+}
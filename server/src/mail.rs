@@ -0,0 +1,92 @@
+//! Outgoing email, sent through a pluggable [`Mailer`].
+//!
+//! Only SMTP is supported for now, configured with `ENSEADA_MAIL_*`. Without it no mailer
+//! is configured, and features that email users hand the content out for manual delivery.
+use std::sync::Arc;
+
+use actix_web::web;
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+use enseada::error::Error;
+
+use crate::config::Mail;
+
+/// A plain text email to a single recipient
+#[derive(Clone, Debug, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers emails
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), Error>;
+}
+
+/// Sends emails through an SMTP relay, over TLS
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        credentials: Option<(String, String)>,
+        from: Mailbox,
+    ) -> Result<Self, Error> {
+        let mut builder = SmtpTransport::relay(host)
+            .map_err(|err| Error::from(format!("invalid SMTP relay {}: {}", host, err)))?;
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), Error> {
+        let to: Mailbox = email
+            .to
+            .parse()
+            .map_err(|err| Error::from(format!("invalid recipient {}: {}", email.to, err)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|err| Error::from(err.to_string()))?;
+        // The transport blocks, and connects anew for every email
+        let transport = self.transport.clone();
+        web::block(move || transport.send(&message))
+            .await
+            .map(|_| ())
+            .map_err(|err| Error::from(format!("failed to send email: {}", err)))
+    }
+}
+
+/// Mailer of the configuration, if an SMTP relay is set
+pub fn from_config(mail: &Mail) -> Option<Arc<dyn Mailer>> {
+    let host = mail.smtp().host()?;
+    let from = mail.from()?;
+    match SmtpMailer::new(host, mail.smtp().port(), mail.smtp().credentials(), from) {
+        Ok(mailer) => Some(Arc::new(mailer)),
+        Err(err) => {
+            log::error!("Failed to configure the SMTP mailer: {}", err);
+            None
+        }
+    }
+}
@@ -3,16 +3,25 @@ extern crate include_dir;
 #[macro_use]
 extern crate lazy_static;
 
+mod admin;
+mod announcement;
+mod audit;
 mod config;
 mod couchdb;
+mod events;
+mod group;
 mod http;
+mod issuer;
+mod jobs;
 mod logger;
+mod mail;
 mod oauth;
 mod observability;
 mod rbac;
 mod responses;
 mod routes;
 mod server;
+mod stats;
 mod templates;
 mod ui;
 mod user;
@@ -20,9 +29,21 @@ mod user;
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     logger::init();
+    user::password::configure();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--dry-run") {
+        return couchdb::dry_run().await;
+    }
 
     couchdb::migrate().await?;
 
+    if args.first().map(String::as_str) == Some("admin") {
+        return admin::run(&args[1..]).await;
+    }
+
+    issuer::check(&args).await?;
+
     log::info!("Starting Enseada...");
 
     server::run().await?;
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use reqwest::Client as HttpClient;
+use url::Url;
+
+use crate::events::{DeliveryLog, Envelope, Event, FailedDelivery};
+use crate::oauth::issuance::{AnomalyListener, IssuanceAnomaly};
+
+/// Reports token issuance anomalies to the log and, if configured, to a webhook
+pub struct AnomalyReporter {
+    http: HttpClient,
+    webhook: Option<Url>,
+    deliveries: Option<Arc<DeliveryLog>>,
+}
+
+impl AnomalyReporter {
+    pub fn new(webhook: Option<Url>) -> Self {
+        AnomalyReporter {
+            http: HttpClient::new(),
+            webhook,
+            deliveries: None,
+        }
+    }
+
+    /// Keeps the anomalies that could not be delivered to the webhook in the given log
+    pub fn set_delivery_log(&mut self, deliveries: Arc<DeliveryLog>) -> &mut Self {
+        self.deliveries = Some(deliveries);
+        self
+    }
+}
+
+impl AnomalyListener for AnomalyReporter {
+    fn on_anomaly(&self, anomaly: &IssuanceAnomaly) {
+        log::warn!(
+            "Token issuance anomaly for client '{}': {} token sets in {}s, threshold is {}",
+            &anomaly.client_id,
+            anomaly.rate,
+            anomaly.window_seconds,
+            anomaly.threshold
+        );
+
+        if let Some(webhook) = &self.webhook {
+            let body = match serde_json::to_vec(&Envelope::new(anomaly)) {
+                Ok(body) => body,
+                Err(err) => {
+                    log::error!("Failed to serialize issuance anomaly: {}", err);
+                    return;
+                }
+            };
+            let req = self
+                .http
+                .post(webhook.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            let webhook = webhook.clone();
+            let deliveries = self.deliveries.clone();
+            actix_rt::spawn(async move {
+                if let Err(err) = req.send().await.and_then(|res| res.error_for_status()) {
+                    log::error!("Failed to notify {} of issuance anomaly: {}", webhook, err);
+                    if let Some(deliveries) = deliveries {
+                        let delivery = FailedDelivery::new(
+                            webhook.to_string(),
+                            IssuanceAnomaly::TYPE,
+                            &body,
+                            err.to_string(),
+                            1,
+                            Utc::now(),
+                        );
+                        if let Err(err) = deliveries.record(&delivery).await {
+                            log::error!("Failed to record webhook delivery: {}", err);
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use enseada::error::Error;
+
+use crate::jobs::Job;
+use crate::oauth::persistence::CouchStorage;
+
+/// Deletes the access and refresh tokens that expired over a day ago
+pub struct TokenPurge {
+    storage: Arc<CouchStorage>,
+}
+
+impl TokenPurge {
+    pub fn new(storage: Arc<CouchStorage>) -> Self {
+        TokenPurge { storage }
+    }
+}
+
+#[async_trait]
+impl Job for TokenPurge {
+    fn name(&self) -> &str {
+        "token-purge"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    async fn run(&self) -> Result<(), Error> {
+        let before = Utc::now() - chrono::Duration::days(1);
+        let purged = self
+            .storage
+            .purge_expired_tokens(before)
+            .await
+            .map_err(|err| Error::from(err.description()))?;
+        log::info!("Purged {} tokens expired before {}", purged, before);
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+//! Keys the server signs access tokens and trust bundles with, see [`enseada::trust`].
+//!
+//! The key set is stored in CouchDB and created on first use, so every instance signs with the
+//! same primary key. Private keys are encrypted with the secret key of the server before being
+//! stored, so a copy of the database alone doesn't allow to sign tokens. Instances reload it every [`RELOAD_INTERVAL`], so a rotation done by another
+//! instance or by `enseada-server admin rotate-signing-key` reaches them well before the tokens of
+//! the previous key expire. Trust bundles are built from the current set whenever they are
+//! exported, so they follow rotations without any further step.
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use couchdb::db::Database;
+use enseada::error::Error;
+use enseada::guid::Guid;
+use enseada::trust::{KeySet, SigningKey, TrustBundle};
+
+use crate::config::CONFIG;
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+use crate::couchdb::{name as dbname, SINGLETON};
+use crate::issuer;
+use crate::oauth::error::{Error as OAuthError, ErrorKind};
+use crate::oauth::registry::ScopeRegistry;
+use crate::oauth::scopes;
+use crate::oauth::signing::{AccessTokenClaims, TokenSigner};
+
+/// How long an instance signs with the key set it loaded before reloading it
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long an exported trust bundle is valid for
+pub fn bundle_validity() -> chrono::Duration {
+    chrono::Duration::days(7)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct StoredKey {
+    kid: String,
+    /// PKCS#8 document of the key, encrypted with the secret key of the server
+    sealed_pkcs8: String,
+}
+
+impl StoredKey {
+    fn from_key(key: &SigningKey, secret: &str) -> Result<Self, Error> {
+        Ok(StoredKey {
+            kid: key.kid().to_string(),
+            sealed_pkcs8: key.sealed_pkcs8(secret).map_err(Error::from)?,
+        })
+    }
+
+    fn to_key(&self, secret: &str) -> Result<SigningKey, Error> {
+        SigningKey::from_sealed_pkcs8(self.kid.clone(), &self.sealed_pkcs8, secret)
+            .map_err(Error::from)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SigningKeysEntity {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    primary: StoredKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<StoredKey>,
+    rotated_at: DateTime<Utc>,
+}
+
+impl SigningKeysEntity {
+    fn from_keys(keys: &KeySet, secret: &str) -> Result<Self, Error> {
+        let previous = match keys.previous() {
+            Some(previous) => Some(StoredKey::from_key(previous, secret)?),
+            None => None,
+        };
+        Ok(SigningKeysEntity {
+            id: Self::build_guid("signing_keys"),
+            rev: None,
+            primary: StoredKey::from_key(keys.primary(), secret)?,
+            previous,
+            rotated_at: Utc::now(),
+        })
+    }
+
+    fn to_keys(&self, secret: &str) -> Result<KeySet, Error> {
+        let previous = match &self.previous {
+            Some(previous) => Some(previous.to_key(secret)?),
+            None => None,
+        };
+        Ok(KeySet::new(self.primary.to_key(secret)?, previous))
+    }
+}
+
+impl Entity for SigningKeysEntity {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::SETTING, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+pub struct SigningKeys {
+    db: Database,
+    secret_key: String,
+    issuer: String,
+    scopes: BTreeMap<String, String>,
+    loaded: RwLock<Option<(Arc<KeySet>, Instant)>>,
+}
+
+impl SigningKeys {
+    /// Keys stored in the database encrypted with the secret key, signing for the issuer.
+    /// Bundles include the registered scopes.
+    pub fn new(db: Database, secret_key: String, issuer: String, scopes: &ScopeRegistry) -> Self {
+        SigningKeys {
+            db,
+            secret_key,
+            issuer,
+            scopes: scopes
+                .iter()
+                .map(|(name, description)| (name.to_string(), description.to_string()))
+                .collect(),
+            loaded: RwLock::new(None),
+        }
+    }
+
+    /// Keys of the issuer of the public host, stored in the system database
+    pub fn from_config() -> Self {
+        SigningKeys::new(
+            SINGLETON.database(dbname::SYSTEM, true),
+            CONFIG.secret_key(),
+            issuer::normalize(CONFIG.public_host()),
+            &scopes::registry(),
+        )
+    }
+
+    /// The stored key set, reloaded if it was loaded more than [`RELOAD_INTERVAL`] ago
+    pub async fn current(&self) -> Result<Arc<KeySet>, Error> {
+        if let Some((keys, loaded_at)) = self.loaded.read().unwrap().as_ref() {
+            if loaded_at.elapsed() < RELOAD_INTERVAL {
+                return Ok(keys.clone());
+            }
+        }
+        let (_, keys) = self.load().await?;
+        Ok(self.remember(keys))
+    }
+
+    /// Makes a new primary key, keeping the current one to verify the tokens it signed.
+    /// Returns the ID of the new key.
+    pub async fn rotate(&self) -> Result<String, Error> {
+        let (entity, mut keys) = self.load().await?;
+        keys.rotate().map_err(Error::from)?;
+        let mut rotated = SigningKeysEntity::from_keys(&keys, &self.secret_key)?;
+        if let Some(rev) = entity.rev() {
+            rotated.set_rev(rev.to_string());
+        }
+        let guid = rotated.id().to_string();
+        match self.db.put(&guid, &rotated).await {
+            Ok(_) => {}
+            Err(err) if err.status() == StatusCode::CONFLICT => {
+                return Err(Error::conflict(
+                    "the signing keys were rotated concurrently, try again".to_string(),
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        }
+        let kid = keys.primary().kid().to_string();
+        log::info!("Rotated the signing keys, the primary key is now {}", kid);
+        self.remember(keys);
+        Ok(kid)
+    }
+
+    /// Trust bundle of the current key set, signed with its primary key
+    pub async fn export_bundle(&self) -> Result<String, Error> {
+        let keys = self.current().await?;
+        let bundle = TrustBundle::new(
+            self.issuer.clone(),
+            &keys,
+            self.scopes.clone(),
+            Utc::now().timestamp() as u64,
+            bundle_validity().num_seconds() as u64,
+        );
+        bundle.sign(&keys).map_err(Error::from)
+    }
+
+    /// Reads the stored key set, creating it if there is none yet
+    async fn load(&self) -> Result<(SigningKeysEntity, KeySet), Error> {
+        let guid = SigningKeysEntity::build_guid("signing_keys").to_string();
+        if let Some(entity) = self.db.get::<SigningKeysEntity>(&guid).await? {
+            let keys = entity.to_keys(&self.secret_key)?;
+            return Ok((entity, keys));
+        }
+
+        let keys = KeySet::generate().map_err(Error::from)?;
+        let mut entity = SigningKeysEntity::from_keys(&keys, &self.secret_key)?;
+        match self.db.put(&guid, &entity).await {
+            Ok(res) => {
+                log::info!("Generated signing key {}", keys.primary().kid());
+                entity.set_rev(res.rev);
+                Ok((entity, keys))
+            }
+            // Another instance created the keys first
+            Err(err) if err.status() == StatusCode::CONFLICT => {
+                let entity = self
+                    .db
+                    .get::<SigningKeysEntity>(&guid)
+                    .await?
+                    .ok_or_else(|| Error::not_found("signing keys", &guid))?;
+                let keys = entity.to_keys(&self.secret_key)?;
+                Ok((entity, keys))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn remember(&self, keys: KeySet) -> Arc<KeySet> {
+        let keys = Arc::new(keys);
+        *self.loaded.write().unwrap() = Some((keys.clone(), Instant::now()));
+        keys
+    }
+}
+
+#[async_trait]
+impl TokenSigner for SigningKeys {
+    fn issuer(&self) -> String {
+        self.issuer.clone()
+    }
+
+    async fn sign(&self, claims: &AccessTokenClaims) -> crate::oauth::Result<String> {
+        let keys = self.current().await.map_err(|err| {
+            OAuthError::new(
+                ErrorKind::ServerError,
+                format!("signing keys unavailable: {}", err),
+            )
+        })?;
+        keys.sign_jwt(claims)
+            .map_err(|err| OAuthError::new(ErrorKind::ServerError, err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use enseada::secure;
+
+    use super::*;
+
+    #[test]
+    fn it_stores_and_restores_key_sets() {
+        let mut keys = KeySet::generate().unwrap();
+        keys.rotate().unwrap();
+        let entity = SigningKeysEntity::from_keys(&keys, "secret").unwrap();
+        let json = serde_json::to_string(&entity).unwrap();
+        let entity = serde_json::from_str::<SigningKeysEntity>(&json).unwrap();
+        let restored = entity.to_keys("secret").unwrap();
+        assert_eq!(restored.jwks(), keys.jwks());
+        assert_eq!(restored.primary().kid(), keys.primary().kid());
+        // Only the secret key opens the stored private keys
+        let pkcs8 = secure::open(&entity.primary.sealed_pkcs8, "secret").unwrap();
+        assert_eq!(pkcs8, keys.primary().pkcs8());
+        assert!(entity.to_keys("other").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Lazy tracking of when access tokens were last used, shown to users listing their sessions.
+//!
+//! Writing the token document on every request would double the load of the API, so uses are
+//! written at most once per interval for each token: instances remember when they last wrote
+//! the use of a token, and skip writing it when another instance did in the meantime.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use crate::oauth::persistence::CouchStorage;
+
+/// How often the use of a token is written at most
+pub const INTERVAL: Duration = Duration::from_secs(60);
+/// Tokens whose last use is remembered at most, the least recently written are forgotten first
+const CAPACITY: usize = 10_000;
+
+pub struct LastUsedTracker {
+    storage: CouchStorage,
+    interval: Duration,
+    written: RwLock<HashMap<String, Instant>>,
+}
+
+impl LastUsedTracker {
+    pub fn new(storage: CouchStorage, interval: Duration) -> Self {
+        LastUsedTracker {
+            storage,
+            interval,
+            written: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Records the use of the access token with the given signature in the background, if due
+    pub fn touch(&self, sig: &str, ip: Option<IpAddr>) {
+        if !self.is_due(sig) {
+            return;
+        }
+        let storage = self.storage.clone();
+        let sig = sig.to_string();
+        let interval = chrono::Duration::from_std(self.interval)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+        actix_rt::spawn(async move {
+            if let Err(err) = storage
+                .record_token_use(&sig, Utc::now(), ip, interval)
+                .await
+            {
+                log::warn!("Failed to record the use of a token: {}", err);
+            }
+        });
+    }
+
+    /// Whether the use of the token is due to be written, counting it as written if so
+    fn is_due(&self, sig: &str) -> bool {
+        let is_recent = |written_at: &Instant| written_at.elapsed() < self.interval;
+        if self.written.read().unwrap().get(sig).is_some_and(is_recent) {
+            return false;
+        }
+
+        let mut written = self.written.write().unwrap();
+        // Checked again, as another request may have written it since
+        if written.get(sig).is_some_and(is_recent) {
+            return false;
+        }
+        if written.len() >= CAPACITY && !written.contains_key(sig) {
+            written.retain(|_, written_at| is_recent(written_at));
+            if written.len() >= CAPACITY {
+                let oldest = written
+                    .iter()
+                    .min_by_key(|(_, written_at)| **written_at)
+                    .map(|(sig, _)| sig.clone());
+                if let Some(oldest) = oldest {
+                    written.remove(&oldest);
+                }
+            }
+        }
+        written.insert(sig.to_string(), Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use couchdb::Couch;
+    use url::Url;
+
+    use super::*;
+
+    fn tracker(interval: Duration) -> LastUsedTracker {
+        // Never queried by the tests
+        let couch = Couch::new(
+            Url::parse("http://localhost:5984").unwrap(),
+            "admin".to_string(),
+            "admin".to_string(),
+        );
+        let storage = CouchStorage::new(Arc::new(couch.database("oauth", true)));
+        LastUsedTracker::new(storage, interval)
+    }
+
+    #[test]
+    fn it_writes_each_token_once_per_interval() {
+        let tracker = tracker(Duration::from_secs(60));
+        assert!(tracker.is_due("a"));
+        assert!(!tracker.is_due("a"));
+        assert!(tracker.is_due("b"));
+    }
+
+    #[test]
+    fn it_writes_again_after_the_interval() {
+        let tracker = tracker(Duration::from_millis(0));
+        assert!(tracker.is_due("a"));
+        assert!(tracker.is_due("a"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Authorization server metadata (RFC 8414), letting clients discover our endpoints and capabilities
+//! from `/.well-known/oauth-authorization-server` instead of configuring them one by one.
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::http::urls::UrlBuilder;
+use crate::issuer;
+use crate::oauth::handler::SUPPORTED_GRANT_TYPES;
+use crate::oauth::registry::ScopeRegistry;
+use crate::oauth::request::{GrantType, ResponseMode, ResponseType};
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Metadata {
+    pub issuer: String,
+    pub authorization_endpoint: Url,
+    pub token_endpoint: Url,
+    pub introspection_endpoint: Url,
+    pub revocation_endpoint: Url,
+    /// Endpoint of pushed authorization requests (RFC 9126)
+    pub pushed_authorization_request_endpoint: Url,
+    /// Endpoint issuing device codes (RFC 8628)
+    pub device_authorization_endpoint: Url,
+    /// Exchange of bootstrap tokens, only if they are enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap_endpoint: Option<Url>,
+    pub scopes_supported: Vec<String>,
+    pub response_types_supported: Vec<String>,
+    pub response_modes_supported: Vec<String>,
+    pub grant_types_supported: Vec<GrantType>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    /// Algorithms of the assertions of clients using `private_key_jwt`
+    pub token_endpoint_auth_signing_alg_values_supported: Vec<String>,
+    /// Resource servers can also introspect tokens with a bearer token, see [`INTROSPECTION_SCOPE`]
+    ///
+    /// [`INTROSPECTION_SCOPE`]: crate::oauth::handler::INTROSPECTION_SCOPE
+    pub introspection_endpoint_auth_methods_supported: Vec<String>,
+    pub revocation_endpoint_auth_methods_supported: Vec<String>,
+    /// Left out while PKCE is not supported, as RFC 8414 requires
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub code_challenge_methods_supported: Vec<String>,
+}
+
+impl Metadata {
+    /// Describes the server at the public host, with endpoint URLs built by `urls`
+    pub fn new(
+        public_host: &Url,
+        urls: &UrlBuilder,
+        scopes: &ScopeRegistry,
+        bootstrap: bool,
+    ) -> Self {
+        let strings = |values: &[&str]| values.iter().map(ToString::to_string).collect();
+        Metadata {
+            issuer: issuer::normalize(public_host),
+            authorization_endpoint: urls.oauth("authorize"),
+            token_endpoint: urls.oauth("token"),
+            introspection_endpoint: urls.oauth("introspect"),
+            revocation_endpoint: urls.oauth("revoke"),
+            pushed_authorization_request_endpoint: urls.oauth("par"),
+            device_authorization_endpoint: urls.oauth("device/code"),
+            bootstrap_endpoint: if bootstrap {
+                Some(urls.oauth("bootstrap"))
+            } else {
+                None
+            },
+            scopes_supported: scopes.iter().map(|(name, _)| name.to_string()).collect(),
+            response_types_supported: vec![ResponseType::Code.to_string()],
+            response_modes_supported: vec![
+                ResponseMode::Query.to_string(),
+                ResponseMode::FormPost.to_string(),
+            ],
+            grant_types_supported: SUPPORTED_GRANT_TYPES.to_vec(),
+            token_endpoint_auth_methods_supported: strings(&[
+                "client_secret_basic",
+                "client_secret_post",
+                "private_key_jwt",
+                "none",
+            ]),
+            token_endpoint_auth_signing_alg_values_supported: strings(&["RS256", "ES256"]),
+            introspection_endpoint_auth_methods_supported: strings(&[
+                "client_secret_basic",
+                "bearer",
+            ]),
+            revocation_endpoint_auth_methods_supported: strings(&["client_secret_basic"]),
+            code_challenge_methods_supported: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metadata(public_host: &str, prefix: Option<&str>, bootstrap: bool) -> Metadata {
+        let public_host = Url::parse(public_host).unwrap();
+        let urls = UrlBuilder::new(&public_host, prefix).unwrap();
+        let mut scopes = ScopeRegistry::new();
+        scopes
+            .register("profile", "Access your profile information")
+            .register("clients:read", "Read-only access to OAuth clients");
+        Metadata::new(&public_host, &urls, &scopes, bootstrap)
+    }
+
+    #[test]
+    fn it_round_trips_through_serde() {
+        for bootstrap in &[false, true] {
+            let metadata = metadata("https://enseada.example.com", None, *bootstrap);
+            let json = serde_json::to_string(&metadata).unwrap();
+            assert_eq!(serde_json::from_str::<Metadata>(&json).unwrap(), metadata);
+        }
+    }
+
+    #[test]
+    fn it_uses_the_stored_form_of_the_public_host_as_issuer() {
+        for public_host in &[
+            "https://enseada.example.com",
+            "https://enseada.example.com/",
+        ] {
+            let metadata = metadata(public_host, None, false);
+            assert_eq!(metadata.issuer, "https://enseada.example.com");
+            assert_eq!(
+                metadata.issuer,
+                issuer::normalize(&Url::parse(public_host).unwrap())
+            );
+        }
+
+        let metadata = metadata("http://localhost:9623/", Some("/enseada/"), false);
+        assert_eq!(metadata.issuer, "http://localhost:9623");
+        assert_eq!(
+            metadata.token_endpoint.as_str(),
+            "http://localhost:9623/enseada/oauth/token"
+        );
+    }
+
+    #[test]
+    fn it_describes_the_enabled_features() {
+        let json =
+            serde_json::to_value(metadata("https://enseada.example.com", None, false)).unwrap();
+        assert_eq!(
+            json["scopes_supported"],
+            serde_json::json!(["clients:read", "profile"])
+        );
+        assert_eq!(
+            json["grant_types_supported"],
+            serde_json::json!([
+                "authorization_code",
+                "refresh_token",
+                "client_credentials",
+                "urn:ietf:params:oauth:grant-type:device_code"
+            ])
+        );
+        assert_eq!(
+            json["pushed_authorization_request_endpoint"],
+            "https://enseada.example.com/oauth/par"
+        );
+        assert_eq!(
+            json["device_authorization_endpoint"],
+            "https://enseada.example.com/oauth/device/code"
+        );
+        assert_eq!(
+            json["token_endpoint_auth_methods_supported"][2],
+            "private_key_jwt"
+        );
+        assert!(json.get("bootstrap_endpoint").is_none());
+        assert!(json.get("code_challenge_methods_supported").is_none());
+
+        let json =
+            serde_json::to_value(metadata("https://enseada.example.com", None, true)).unwrap();
+        assert_eq!(
+            json["bootstrap_endpoint"],
+            "https://enseada.example.com/oauth/bootstrap"
+        );
+    }
+}
@@ -1,31 +1,19 @@
-use chrono::{DateTime, Utc};
-
+pub use enseada_oauth::{
+    assertion, audit, binding, bootstrap, client, code, device, device_code, error, events, facade, handler, issuance, par, registry, request, resource, response,
+    scope, session, signing, storage, token, user_agent, Expirable, Result,
+};
 pub use routes::mount;
 
-use crate::oauth::error::Error;
-use crate::oauth::handler::OAuthHandler;
-use crate::oauth::persistence::CouchStorage;
-
-pub mod client;
-pub mod code;
-pub mod error;
-pub mod handler;
+pub mod anomaly;
+pub mod jobs;
+pub mod keys;
+pub mod last_used;
+pub mod metadata;
+pub mod new_device;
 pub mod persistence;
-pub mod request;
-pub mod response;
 mod routes;
-pub mod scope;
-pub mod session;
-pub mod storage;
-pub mod token;
-
-pub type ConcreteOAuthHandler =
-    OAuthHandler<CouchStorage, CouchStorage, CouchStorage, CouchStorage>;
-
-pub type Result<T> = std::result::Result<T, Error>;
-
-pub trait Expirable {
-    fn expiration(&self) -> &DateTime<Utc>;
-    fn expires_in(&self) -> i64;
-    fn is_expired(&self) -> bool;
-}
+pub mod scopes;
+pub mod sso;
+pub mod stats;
+pub mod throttle;
+pub mod token_events;
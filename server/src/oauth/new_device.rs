@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use enseada::guid::Guid;
+
+use crate::audit::{AuditRecord, AuditService};
+use crate::couchdb::repository::Repository;
+use crate::mail::{Email, Mailer};
+use crate::oauth::audit::{AuditAction, AuditEvent};
+use crate::oauth::device::{Device, NewDeviceListener};
+use crate::user::UserService;
+
+/// Reports tokens issued on a new device of a user to the audit log and,
+/// if a mailer is configured and their address is verified, to the user by email
+pub struct NewDeviceNotifier {
+    audit: AuditService,
+    users: UserService,
+    mailer: Option<Arc<dyn Mailer>>,
+}
+
+impl NewDeviceNotifier {
+    pub fn new(audit: AuditService, users: UserService, mailer: Option<Arc<dyn Mailer>>) -> Self {
+        NewDeviceNotifier {
+            audit,
+            users,
+            mailer,
+        }
+    }
+
+    async fn email(&self, mailer: &dyn Mailer, user_id: &str, device: &Device) {
+        let guid = Guid::from(user_id.to_string());
+        let user = match self.users.find(guid.id()).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return,
+            Err(err) => {
+                log::error!(
+                    "Failed to look up user {} to notify of a new device: {}",
+                    user_id,
+                    err
+                );
+                return;
+            }
+        };
+        let address = match user.email() {
+            Some(address) if user.is_email_verified() => address,
+            _ => return,
+        };
+        let email = new_device_email(user.username(), address, device);
+        if let Err(err) = mailer.send(email).await {
+            log::error!("Failed to notify user {} of a new device: {}", user_id, err);
+        }
+    }
+}
+
+#[async_trait]
+impl NewDeviceListener for NewDeviceNotifier {
+    async fn on_new_device(&self, user_id: &str, client_id: &str, device: &Device) {
+        let event = AuditEvent::success(AuditAction::NewDevice)
+            .set_client_id(Some(client_id.to_string()))
+            .set_user_id(Some(user_id.to_string()));
+        let record = AuditRecord::new(event, device.ip(), Utc::now());
+        if let Err(err) = self.audit.record(&record).await {
+            log::error!("Failed to write audit record: {}", err);
+        }
+
+        if let Some(mailer) = &self.mailer {
+            self.email(mailer.as_ref(), user_id, device).await;
+        }
+    }
+}
+
+fn new_device_email(username: &str, address: &str, device: &Device) -> Email {
+    let ip = device
+        .ip()
+        .map_or_else(|| "an unknown address".to_string(), |ip| ip.to_string());
+    Email {
+        to: address.to_string(),
+        subject: "New device on your account".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+            Your Enseada account was just used from a device it was never used from before:\n\n\
+            {}, from {}\n\n\
+            If it was you, you can ignore this email. Otherwise, change your password \
+            and revoke the sessions you do not recognize.\n",
+            username,
+            device.family(),
+            ip,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::oauth::device::AuthMethod;
+    use crate::oauth::user_agent::UserAgent;
+
+    use super::*;
+
+    #[test]
+    fn it_describes_the_device_in_the_email() {
+        let mut device = Device::new(UserAgent::parse("curl/7.68.0"), AuthMethod::None);
+        device.set_ip(Some("10.0.0.1".parse().unwrap()));
+        let email = new_device_email("jdoe", "jdoe@example.com", &device);
+        assert_eq!(email.to, "jdoe@example.com");
+        assert!(email.body.contains(&device.family()));
+        assert!(email.body.contains("10.0.0.1"));
+    }
+}
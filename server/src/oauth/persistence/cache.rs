@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use enseada::error::Error;
+
+use crate::jobs::changes::ChangeListener;
+use crate::oauth::client::Client;
+
+lazy_static! {
+    /// Cache shared by the storages of every worker, kept fresh by a [`ClientInvalidator`]
+    pub static ref CLIENT_CACHE: Arc<ClientCache> = Arc::new(ClientCache::default());
+}
+
+/// Caches clients for a short time, since every authorization and token request looks them up.
+/// Entries are invalidated when a client is saved or deleted through the same storage,
+/// other workers and instances see the change once the changes feed reports it to a
+/// [`ClientInvalidator`], or the entry expires.
+pub struct ClientCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: RwLock<HashMap<String, (Instant, Client)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ClientCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        ClientCache {
+            ttl,
+            capacity,
+            entries: RwLock::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<Client> {
+        let entries = self.entries.read().unwrap();
+        let client = entries
+            .get(client_id)
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, client)| client.clone());
+        let counter = if client.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        client
+    }
+
+    /// Caches the client, evicting expired entries first and the oldest one if still full
+    pub fn insert(&self, client: Client) {
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(client.client_id()) {
+            let ttl = self.ttl;
+            entries.retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (cached_at, _))| *cached_at)
+                    .map(|(client_id, _)| client_id.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(client.client_id().to_string(), (Instant::now(), client));
+    }
+
+    pub fn invalidate(&self, client_id: &str) {
+        let mut entries = self.entries.write().unwrap();
+        entries.remove(client_id);
+    }
+
+    /// Value of the `oauth_client_cache_hits` counter
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Value of the `oauth_client_cache_misses` counter
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ClientCache {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60), 1000)
+    }
+}
+
+/// Invalidates cached clients when their documents change
+pub struct ClientInvalidator {
+    cache: Arc<ClientCache>,
+}
+
+impl ClientInvalidator {
+    pub fn new(cache: Arc<ClientCache>) -> Self {
+        ClientInvalidator { cache }
+    }
+}
+
+#[async_trait]
+impl ChangeListener for ClientInvalidator {
+    fn name(&self) -> &str {
+        "oauth_client_cache"
+    }
+
+    async fn on_change(&self, id: &str, _deleted: bool) -> Result<(), Error> {
+        if let Some(client_id) = id.strip_prefix("client:") {
+            log::trace!("Client {} changed, invalidating its cache entry", client_id);
+            self.cache.invalidate(client_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::jobs::changes::ChangeListener;
+    use crate::oauth::client::Client;
+    use crate::oauth::scope::Scope;
+
+    use super::{ClientCache, ClientInvalidator};
+
+    fn client(client_id: &str) -> Client {
+        Client::public(
+            client_id.to_string(),
+            Scope::from("profile"),
+            HashSet::new(),
+        )
+    }
+
+    #[test]
+    fn it_counts_hits_and_misses() {
+        let cache = ClientCache::default();
+        assert!(cache.get("enseada").is_none());
+
+        cache.insert(client("enseada"));
+        assert_eq!(cache.get("enseada").unwrap().client_id(), "enseada");
+        assert_eq!(cache.get("enseada").unwrap().client_id(), "enseada");
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+    }
+
+    #[test]
+    fn it_expires_clients_after_the_ttl() {
+        let cache = ClientCache::new(Duration::from_millis(10), 10);
+        cache.insert(client("enseada"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get("enseada").is_none());
+    }
+
+    #[test]
+    fn it_forgets_invalidated_clients() {
+        let cache = ClientCache::default();
+        cache.insert(client("enseada"));
+        cache.invalidate("enseada");
+        assert!(cache.get("enseada").is_none());
+    }
+
+    #[test]
+    fn it_evicts_the_oldest_client_when_full() {
+        let cache = ClientCache::new(Duration::from_secs(60), 2);
+        cache.insert(client("first"));
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert(client("second"));
+        cache.insert(client("third"));
+
+        assert!(cache.get("first").is_none());
+        assert!(cache.get("second").is_some());
+        assert!(cache.get("third").is_some());
+    }
+
+    #[actix_rt::test]
+    async fn it_invalidates_clients_on_changes() {
+        let cache = Arc::new(ClientCache::default());
+        cache.insert(client("enseada"));
+        cache.insert(client("other"));
+        let invalidator = ClientInvalidator::new(cache.clone());
+
+        invalidator.on_change("token:enseada", false).await.unwrap();
+        assert!(cache.get("enseada").is_some());
+        invalidator.on_change("client:enseada", true).await.unwrap();
+        assert!(cache.get("enseada").is_none());
+        assert!(cache.get("other").is_some());
+    }
+}
@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 use enseada::guid::Guid;
 use enseada::secure::SecureSecret;
 
+use crate::couchdb::partition;
 use crate::couchdb::repository::Entity;
 use crate::oauth::code::AuthorizationCode;
 use crate::oauth::session::Session;
@@ -22,7 +23,7 @@ pub struct AuthorizationCodeEntity {
 
 impl Entity for AuthorizationCodeEntity {
     fn build_guid(id: &str) -> Guid {
-        Guid::from(format!("code:{}", id))
+        Guid::from(format!("{}:{}", partition::AUTH_CODE, id))
     }
 
     fn id(&self) -> &Guid {
@@ -0,0 +1,62 @@
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+use enseada::secure::SecureSecret;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+use crate::oauth::bootstrap::BootstrapToken;
+use crate::oauth::session::Session;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BootstrapTokenEntity {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    session: Session,
+    #[serde(with = "ts_seconds")]
+    expiration: DateTime<Utc>,
+}
+
+impl Entity for BootstrapTokenEntity {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::BOOTSTRAP_TOKEN, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+impl BootstrapTokenEntity {
+    pub fn new(sig: String, session: Session, expiration: DateTime<Utc>) -> BootstrapTokenEntity {
+        let id = Self::build_guid(&sig);
+        BootstrapTokenEntity {
+            id,
+            rev: None::<String>,
+            session,
+            expiration,
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn to_empty_token(&self) -> BootstrapToken {
+        let expires_in = self.expiration.signed_duration_since(Utc::now());
+        BootstrapToken::new(SecureSecret::empty(), self.session().clone(), expires_in)
+    }
+}
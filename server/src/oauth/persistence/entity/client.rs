@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 
 use serde::{Deserialize, Serialize};
@@ -6,16 +6,20 @@ use url::Url;
 
 use enseada::guid::Guid;
 
+use crate::couchdb::partition;
 use crate::couchdb::repository::Entity;
-use crate::oauth::client::Client;
+use crate::oauth::assertion::Jwk;
 use crate::oauth::client::ClientKind as ExtClientKind;
+use crate::oauth::client::{default_grant_types, Client};
 use crate::oauth::error::Error;
+use crate::oauth::request::GrantType;
 use crate::oauth::scope::Scope;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ClientKind {
     Public,
+    Native,
     Confidential,
 }
 
@@ -23,6 +27,7 @@ impl From<ExtClientKind> for ClientKind {
     fn from(kind: ExtClientKind) -> Self {
         match kind {
             ExtClientKind::Public => ClientKind::Public,
+            ExtClientKind::Native => ClientKind::Native,
             ExtClientKind::Confidential { .. } => ClientKind::Confidential,
         }
     }
@@ -39,11 +44,33 @@ pub struct ClientEntity {
     client_secret_hash: Option<String>,
     allowed_scopes: Scope,
     allowed_redirect_uris: HashSet<Url>,
+    #[serde(default = "default_grant_types")]
+    allowed_grant_types: HashSet<GrantType>,
+    #[serde(default)]
+    allowed_origins: HashSet<Url>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    jwks: Vec<Jwk>,
+    #[serde(default)]
+    require_state: bool,
+    #[serde(default)]
+    bind_tokens: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_issuance_threshold: Option<u32>,
+    #[serde(default)]
+    trusted: bool,
+    #[serde(default)]
+    resource_server: bool,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<Guid>,
+    #[serde(default)]
+    orphaned: bool,
 }
 
 impl Entity for ClientEntity {
     fn build_guid(id: &str) -> Guid {
-        Guid::from(format!("client:{}", id))
+        Guid::from(format!("{}:{}", partition::CLIENT, id))
     }
 
     fn id(&self) -> &Guid {
@@ -68,12 +95,23 @@ impl From<Client> for ClientEntity {
             id,
             rev: None,
             client_secret_hash: match &kind {
-                ExtClientKind::Public => None,
+                ExtClientKind::Public | ExtClientKind::Native => None,
                 ExtClientKind::Confidential { secret } => Some(secret.clone()),
             },
             kind: ClientKind::from(kind),
             allowed_scopes: client.allowed_scopes().clone(),
             allowed_redirect_uris: client.allowed_redirect_uris().clone(),
+            allowed_grant_types: client.allowed_grant_types().clone(),
+            allowed_origins: client.allowed_origins().clone(),
+            jwks: client.jwks().to_vec(),
+            require_state: client.requires_state(),
+            bind_tokens: client.binds_tokens(),
+            token_issuance_threshold: client.token_issuance_threshold(),
+            trusted: client.is_trusted(),
+            resource_server: client.is_resource_server(),
+            labels: client.labels().clone(),
+            owner: client.owner().map(|owner| Guid::from(owner.to_string())),
+            orphaned: client.is_orphaned(),
         }
     }
 }
@@ -86,13 +124,26 @@ impl TryInto<Client> for ClientEntity {
         let allowed_redirect_uris = self.allowed_redirect_uris.clone();
         let client_id = guid.id().to_string();
         let scopes = self.allowed_scopes.clone();
-        let client = match &self.kind {
+        let mut client = match &self.kind {
             ClientKind::Public => Client::public(client_id, scopes, allowed_redirect_uris),
+            ClientKind::Native => Client::native(client_id, scopes, allowed_redirect_uris),
             ClientKind::Confidential => {
                 let secret = self.client_secret_hash.unwrap();
                 Client::confidential_with_hash(client_id, secret, scopes, allowed_redirect_uris)
             }
         };
+        client.set_allowed_grant_types(self.allowed_grant_types)?;
+        client.set_allowed_origins(self.allowed_origins)?;
+        client.set_jwks(self.jwks)?;
+        client
+            .set_require_state(self.require_state)
+            .set_bind_tokens(self.bind_tokens)
+            .set_token_issuance_threshold(self.token_issuance_threshold)
+            .set_trusted(self.trusted)
+            .set_resource_server(self.resource_server)
+            .set_labels(self.labels)
+            .set_owner(self.owner.as_ref().map(Guid::to_string))
+            .set_orphaned(self.orphaned);
         Ok(client)
     }
 }
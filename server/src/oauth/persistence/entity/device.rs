@@ -0,0 +1,52 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+
+/// Device families a user has been seen with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KnownDevicesEntity {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    families: HashSet<String>,
+}
+
+impl Entity for KnownDevicesEntity {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::DEVICE, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+impl KnownDevicesEntity {
+    pub fn new(user_id: &str) -> Self {
+        KnownDevicesEntity {
+            id: Self::build_guid(user_id),
+            rev: None,
+            families: HashSet::new(),
+        }
+    }
+
+    /// Adds a family, returning true if it wasn't already known
+    pub fn add_family(&mut self, family: &str) -> bool {
+        self.families.insert(family.to_string())
+    }
+}
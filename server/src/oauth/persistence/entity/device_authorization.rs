@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+use crate::oauth::device_code::DeviceAuthorization;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceAuthorizationEntity {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    #[serde(flatten)]
+    authorization: DeviceAuthorization,
+}
+
+impl Entity for DeviceAuthorizationEntity {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::DEVICE_AUTHORIZATION, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+impl DeviceAuthorizationEntity {
+    /// Stored under the signature of the device code, with the revision the authorization was read at
+    pub fn new(sig: String, authorization: DeviceAuthorization) -> DeviceAuthorizationEntity {
+        let id = Self::build_guid(&sig);
+        DeviceAuthorizationEntity {
+            id,
+            rev: authorization.revision().map(ToString::to_string),
+            authorization,
+        }
+    }
+
+    /// Signature of the device code
+    pub fn signature(&self) -> &str {
+        self.id.id()
+    }
+
+    pub fn to_device_authorization(&self) -> DeviceAuthorization {
+        let mut authorization = self.authorization.clone();
+        authorization.set_revision(self.rev.clone());
+        authorization
+    }
+}
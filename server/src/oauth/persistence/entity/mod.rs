@@ -1,3 +1,7 @@
 pub mod client;
 pub mod auth_code;
+pub mod bootstrap;
+pub mod device;
+pub mod device_authorization;
+pub mod pushed_request;
 pub mod token;
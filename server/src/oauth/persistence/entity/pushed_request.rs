@@ -0,0 +1,61 @@
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+use crate::oauth::par::PushedRequest;
+use crate::oauth::request::AuthorizationRequest;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PushedRequestEntity {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    request: AuthorizationRequest,
+    #[serde(with = "ts_seconds")]
+    expiration: DateTime<Utc>,
+}
+
+impl Entity for PushedRequestEntity {
+    fn build_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::PUSHED_REQUEST, id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+impl PushedRequestEntity {
+    pub fn new(
+        sig: String,
+        request: AuthorizationRequest,
+        expiration: DateTime<Utc>,
+    ) -> PushedRequestEntity {
+        let id = Self::build_guid(&sig);
+        PushedRequestEntity {
+            id,
+            rev: None::<String>,
+            request,
+            expiration,
+        }
+    }
+
+    pub fn to_pushed_request(&self) -> PushedRequest {
+        let expires_in = self.expiration.signed_duration_since(Utc::now());
+        PushedRequest::new(self.request.clone(), expires_in)
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use chrono::serde::ts_seconds;
 use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
@@ -5,11 +7,20 @@ use serde::{Deserialize, Serialize};
 use enseada::guid::Guid;
 use enseada::secure::SecureSecret;
 
+use crate::couchdb::partition;
 use crate::couchdb::repository::Entity;
 use crate::oauth::session::Session;
 use crate::oauth::token::{AccessToken, RefreshToken, Token};
 use crate::oauth::Expirable;
 
+/// Token documents, which are flagged as revoked rather than deleted
+/// so that revoked tokens can be told apart from unknown ones
+pub trait TokenEntity: Entity {
+    fn is_revoked(&self) -> bool;
+
+    fn revoke(&mut self) -> &mut Self;
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AccessTokenEntity {
     #[serde(rename = "_id")]
@@ -19,11 +30,21 @@ pub struct AccessTokenEntity {
     session: Session,
     #[serde(with = "ts_seconds")]
     expiration: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    issued_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    revoked: bool,
+    /// When the token was last used to call the API, only recorded every so often
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used: Option<DateTime<Utc>>,
+    /// Address the token was last used from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used_ip: Option<IpAddr>,
 }
 
 impl Entity for AccessTokenEntity {
     fn build_guid(id: &str) -> Guid {
-        Guid::from(format!("access_token:{}", id))
+        Guid::from(format!("{}:{}", partition::ACCESS_TOKEN, id))
     }
 
     fn id(&self) -> &Guid {
@@ -40,6 +61,17 @@ impl Entity for AccessTokenEntity {
     }
 }
 
+impl TokenEntity for AccessTokenEntity {
+    fn is_revoked(&self) -> bool {
+        self.revoked
+    }
+
+    fn revoke(&mut self) -> &mut Self {
+        self.revoked = true;
+        self
+    }
+}
+
 impl AccessTokenEntity {
     pub fn new(sig: String, session: Session, expiration: DateTime<Utc>) -> AccessTokenEntity {
         let id = Self::build_guid(&sig);
@@ -48,6 +80,10 @@ impl AccessTokenEntity {
             rev: None::<String>,
             session,
             expiration,
+            issued_at: Some(Utc::now()),
+            revoked: false,
+            last_used: None,
+            last_used_ip: None,
         }
     }
 
@@ -67,6 +103,24 @@ impl AccessTokenEntity {
         self.expiration.signed_duration_since(Utc::now())
     }
 
+    pub fn issued_at(&self) -> Option<&DateTime<Utc>> {
+        self.issued_at.as_ref()
+    }
+
+    pub fn last_used(&self) -> Option<&DateTime<Utc>> {
+        self.last_used.as_ref()
+    }
+
+    pub fn last_used_ip(&self) -> Option<IpAddr> {
+        self.last_used_ip
+    }
+
+    pub fn set_last_used(&mut self, at: DateTime<Utc>, ip: Option<IpAddr>) -> &mut Self {
+        self.last_used = Some(at);
+        self.last_used_ip = ip;
+        self
+    }
+
     pub fn to_token(&self, token: SecureSecret) -> AccessToken {
         AccessToken::new(token, self.session.clone(), self.expires_in())
     }
@@ -86,11 +140,13 @@ pub struct RefreshTokenEntity {
     #[serde(with = "ts_seconds")]
     expiration: DateTime<Utc>,
     related_access_token_signature: String,
+    #[serde(default)]
+    revoked: bool,
 }
 
 impl Entity for RefreshTokenEntity {
     fn build_guid(id: &str) -> Guid {
-        Guid::from(format!("access_token:{}", id))
+        Guid::from(format!("{}:{}", partition::REFRESH_TOKEN, id))
     }
 
     fn id(&self) -> &Guid {
@@ -107,7 +163,23 @@ impl Entity for RefreshTokenEntity {
     }
 }
 
+impl TokenEntity for RefreshTokenEntity {
+    fn is_revoked(&self) -> bool {
+        self.revoked
+    }
+
+    fn revoke(&mut self) -> &mut Self {
+        self.revoked = true;
+        self
+    }
+}
+
 impl RefreshTokenEntity {
+    /// ID of refresh tokens stored by earlier versions, which shared the access token partition
+    pub fn build_legacy_guid(id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::ACCESS_TOKEN, id))
+    }
+
     pub fn new(
         sig: String,
         session: Session,
@@ -121,6 +193,7 @@ impl RefreshTokenEntity {
             session,
             expiration,
             related_access_token_signature,
+            revoked: false,
         }
     }
 
@@ -145,6 +218,10 @@ impl RefreshTokenEntity {
         self.expiration.signed_duration_since(Utc::now())
     }
 
+    pub fn related_access_token_signature(&self) -> &str {
+        &self.related_access_token_signature
+    }
+
     pub fn to_token(&self, token: SecureSecret) -> RefreshToken {
         RefreshToken::new(
             token,
@@ -158,3 +235,44 @@ impl RefreshTokenEntity {
         self.to_token(SecureSecret::empty())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_stores_refresh_tokens_in_their_own_partition() {
+        let token = RefreshTokenEntity::new(
+            "sig".to_string(),
+            Session::for_client("enseada".to_string()),
+            Utc::now(),
+            "access_sig".to_string(),
+        );
+        assert_eq!(token.id().to_string(), "refresh_token:sig");
+        assert_eq!(
+            RefreshTokenEntity::build_legacy_guid("sig").to_string(),
+            "access_token:sig"
+        );
+    }
+
+    #[test]
+    fn it_reads_tokens_stored_before_revocation_was_tracked() {
+        let doc = json!({
+            "_id": "access_token:sig",
+            "_rev": "1-a",
+            "session": Session::for_client("enseada".to_string()),
+            "expiration": 1_600_000_000,
+            "related_access_token_signature": "access_sig",
+        });
+        let mut token: RefreshTokenEntity = serde_json::from_value(doc).unwrap();
+        assert!(!token.is_revoked());
+        assert_eq!(token.rev(), Some("1-a"));
+
+        token.revoke();
+        let doc = serde_json::to_value(&token).unwrap();
+        assert_eq!(doc["revoked"], true);
+        assert_eq!(doc["_rev"], "1-a");
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::Arc;
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde_json::{json, Value};
+
+use couchdb::data_migration::{DataMigration, DataMigrationError, MigrationReport};
+use couchdb::db::Database;
+use couchdb::size::SizeGuard;
+use enseada::secure;
+
+/// Hashes client secrets stored in plaintext by earlier versions
+pub async fn hash_client_secrets(
+    db: Database,
+    size_guard: Arc<SizeGuard>,
+) -> Result<MigrationReport, DataMigrationError> {
+    DataMigration::new(
+        "hash_client_secrets",
+        db,
+        json!({ "client_secret": { "$exists": true } }),
+        hash_client_secret,
+    )
+    .set_size_guard(size_guard)
+    .run()
+    .await
+}
+
+/// Replaces access tokens stored in plaintext on refresh tokens with their signature
+pub async fn rehash_token_references(
+    db: Database,
+    secret_key: String,
+    size_guard: Arc<SizeGuard>,
+) -> Result<MigrationReport, DataMigrationError> {
+    DataMigration::new(
+        "rehash_token_references",
+        db,
+        json!({ "related_access_token": { "$exists": true } }),
+        move |doc: &mut Value| rehash_token_reference(doc, &secret_key),
+    )
+    .set_size_guard(size_guard)
+    .run()
+    .await
+}
+
+/// Copies when tokens stored by earlier versions were issued and expire onto their sessions
+pub async fn backfill_session_lifetimes(
+    db: Database,
+    size_guard: Arc<SizeGuard>,
+) -> Result<MigrationReport, DataMigrationError> {
+    DataMigration::new(
+        "backfill_session_lifetimes",
+        db,
+        json!({
+            "_id": { "$regex": "^(access|refresh)_token:" },
+            "session.expires_at": { "$exists": false },
+        }),
+        backfill_session_lifetime,
+    )
+    .set_size_guard(size_guard)
+    .run()
+    .await
+}
+
+fn hash_client_secret(doc: &mut Value) -> Result<bool, String> {
+    let doc = doc.as_object_mut().ok_or("not an object")?;
+    let secret = match doc.get("client_secret") {
+        Some(Value::String(secret)) => secret,
+        Some(_) => return Err("client_secret is not a string".to_string()),
+        None => return Ok(false),
+    };
+    let hash = secure::hash_password(secret)?;
+    doc.remove("client_secret");
+    doc.insert("client_secret_hash".to_string(), Value::String(hash));
+    Ok(true)
+}
+
+fn rehash_token_reference(doc: &mut Value, secret_key: &str) -> Result<bool, String> {
+    let doc = doc.as_object_mut().ok_or("not an object")?;
+    let token = match doc.get("related_access_token") {
+        Some(Value::String(token)) => token,
+        Some(_) => return Err("related_access_token is not a string".to_string()),
+        None => return Ok(false),
+    };
+    let signature = secure::generate_signature(token, secret_key).to_string();
+    doc.remove("related_access_token");
+    doc.insert(
+        "related_access_token_signature".to_string(),
+        Value::String(signature),
+    );
+    Ok(true)
+}
+
+fn backfill_session_lifetime(doc: &mut Value) -> Result<bool, String> {
+    let doc = doc.as_object_mut().ok_or("not an object")?;
+    let expiration = match doc.get("expiration") {
+        Some(Value::Number(expiration)) => expiration
+            .as_i64()
+            .ok_or("expiration is not a timestamp")?,
+        Some(_) => return Err("expiration is not a timestamp".to_string()),
+        None => return Ok(false),
+    };
+    let issued_at = doc.get("issued_at").cloned();
+    let session = match doc.get_mut("session") {
+        Some(Value::Object(session)) => session,
+        Some(_) => return Err("session is not an object".to_string()),
+        None => return Ok(false),
+    };
+    if session.contains_key("expires_at") {
+        return Ok(false);
+    }
+    let expires_at = Utc
+        .timestamp(expiration, 0)
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    session.insert("expires_at".to_string(), Value::String(expires_at));
+    if let Some(issued_at @ Value::String(_)) = issued_at {
+        session.entry("issued_at").or_insert(issued_at);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_hashes_a_plaintext_client_secret() {
+        let mut doc = json!({ "_id": "client:test", "client_secret": "supersecret" });
+        assert!(hash_client_secret(&mut doc).unwrap());
+        assert!(doc.get("client_secret").is_none());
+        let hash = doc["client_secret_hash"].as_str().unwrap();
+        assert!(secure::verify_password(hash, "supersecret").unwrap());
+
+        assert!(!hash_client_secret(&mut doc).unwrap());
+        let mut doc = json!({ "_id": "client:test", "client_secret": 42 });
+        assert!(hash_client_secret(&mut doc).is_err());
+    }
+
+    #[test]
+    fn it_replaces_a_plaintext_token_with_its_signature() {
+        let key = "0123456789abcdef0123456789abcdef";
+        let mut doc = json!({ "_id": "refresh_token:abc", "related_access_token": "token" });
+        assert!(rehash_token_reference(&mut doc, key).unwrap());
+        assert!(doc.get("related_access_token").is_none());
+        assert_eq!(
+            doc["related_access_token_signature"],
+            secure::generate_signature("token", key).to_string()
+        );
+        assert!(!rehash_token_reference(&mut doc, key).unwrap());
+    }
+
+    #[test]
+    fn it_backfills_the_lifetime_of_sessions() {
+        let mut doc = json!({
+            "_id": "access_token:abc",
+            "session": { "client_id": "test", "scope": "profile", "user_id": null },
+            "expiration": 1_591_012_800,
+            "issued_at": "2020-06-01T11:55:00Z",
+        });
+        assert!(backfill_session_lifetime(&mut doc).unwrap());
+        assert_eq!(doc["session"]["expires_at"], "2020-06-01T12:00:00Z");
+        assert_eq!(doc["session"]["issued_at"], "2020-06-01T11:55:00Z");
+        assert!(!backfill_session_lifetime(&mut doc).unwrap());
+
+        let session: crate::oauth::session::Session =
+            serde_json::from_value(doc["session"].clone()).unwrap();
+        assert_eq!(
+            session.expires_at(),
+            Some(&Utc.ymd(2020, 6, 1).and_hms(12, 0, 0))
+        );
+
+        let mut doc = json!({ "_id": "access_token:abc", "session": "test", "expiration": 0 });
+        assert!(backfill_session_lifetime(&mut doc).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 pub use entity::*;
 pub use storage::CouchStorage;
 
+pub mod cache;
 mod entity;
+pub mod migration;
 mod storage;
@@ -1,104 +1,415 @@
 use std::convert::TryInto;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
 use couchdb;
 use couchdb::db::Database;
+use enseada::guid::Guid;
 use enseada::pagination::{Cursor, Page};
+use http::StatusCode;
 
-use crate::couchdb::repository::Entity;
-use crate::oauth::client::Client;
+use crate::couchdb::partition;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::oauth::bootstrap::BootstrapToken;
+use crate::oauth::client::{Client, ClientFilter, ClientStats};
 use crate::oauth::code::AuthorizationCode;
+use crate::oauth::device_code::DeviceAuthorization;
 use crate::oauth::error::{Error, ErrorKind};
+use crate::oauth::par::PushedRequest;
+use crate::oauth::session::Session;
+use crate::oauth::persistence::cache::ClientCache;
 use crate::oauth::persistence::client::ClientEntity;
 use crate::oauth::persistence::entity::auth_code::AuthorizationCodeEntity;
-use crate::oauth::persistence::entity::token::{AccessTokenEntity, RefreshTokenEntity};
-use crate::oauth::storage::{AuthorizationCodeStorage, ClientStorage, TokenStorage};
+use crate::oauth::persistence::entity::bootstrap::BootstrapTokenEntity;
+use crate::oauth::persistence::entity::device::KnownDevicesEntity;
+use crate::oauth::persistence::entity::device_authorization::DeviceAuthorizationEntity;
+use crate::oauth::persistence::entity::pushed_request::PushedRequestEntity;
+use crate::oauth::persistence::entity::token::{
+    AccessTokenEntity, RefreshTokenEntity, TokenEntity,
+};
+use crate::oauth::storage::{
+    AuthorizationCodeStorage, BootstrapTokenStorage, ClientStorage, DeviceAuthorizationStorage,
+    DeviceStorage, PushedRequestStorage, TokenStorage,
+};
 use crate::oauth::token::{AccessToken, RefreshToken, Token};
 use crate::oauth::{Expirable, Result};
 
+/// Documents fetched per request when scanning a partition
+const BATCH_SIZE: usize = 200;
+
+/// Storage of every OAuth entity. Clones share the client cache.
+#[derive(Clone)]
 pub struct CouchStorage {
     db: Arc<Database>,
+    client_cache: Arc<ClientCache>,
+}
+
+#[async_trait]
+impl Repository<ClientEntity> for CouchStorage {
+    fn db(&self) -> &Database {
+        &self.db
+    }
 }
 
 impl CouchStorage {
     pub fn new(db: Arc<Database>) -> CouchStorage {
-        CouchStorage { db }
+        CouchStorage {
+            db,
+            client_cache: Arc::new(ClientCache::default()),
+        }
+    }
+
+    /// Storage looking clients up through the given cache, shared with other storages
+    pub fn with_client_cache(db: Arc<Database>, client_cache: Arc<ClientCache>) -> CouchStorage {
+        CouchStorage { db, client_cache }
+    }
+
+    /// Deletes the access and refresh tokens that expired before the given time,
+    /// returning how many were deleted
+    pub async fn purge_expired_tokens(&self, before: DateTime<Utc>) -> Result<usize> {
+        let access = self
+            .purge_expired::<AccessTokenEntity>(partition::ACCESS_TOKEN, before)
+            .await?;
+        let refresh = self
+            .purge_expired::<RefreshTokenEntity>(partition::REFRESH_TOKEN, before)
+            .await?;
+        Ok(access + refresh)
+    }
+
+    // Deleted documents no longer match, so the first batch is queried until it runs out
+    async fn purge_expired<E: TokenEntity>(
+        &self,
+        partition: &str,
+        before: DateTime<Utc>,
+    ) -> Result<usize> {
+        let selector = serde_json::json!({ "expiration": { "$lt": before.timestamp() } });
+        let mut purged = 0;
+        loop {
+            let res = self
+                .db
+                .find_partitioned::<E>(partition, selector.clone(), BATCH_SIZE, None)
+                .await?;
+            let mut deleted = 0;
+            for token in &res.docs {
+                let id = token.id().to_string();
+                match self.db.delete(&id, token.rev().unwrap_or_default()).await {
+                    Ok(()) => deleted += 1,
+                    // Concurrently updated or deleted, left to the next run
+                    Err(err) => log::warn!("Failed to purge token {}: {}", id, err),
+                }
+            }
+            purged += deleted;
+            if res.docs.len() < BATCH_SIZE || deleted == 0 {
+                return Ok(purged);
+            }
+        }
+    }
+
+    /// Revokes the access and refresh tokens of the user, except the access token with the given
+    /// signature and its refresh token, returning how many were revoked
+    pub async fn revoke_user_tokens(&self, user_id: &str, keep: Option<&str>) -> Result<usize> {
+        let (access, refresh, legacy) = user_token_selectors(user_id, keep);
+        let revoked = self
+            .revoke_matching::<AccessTokenEntity>(partition::ACCESS_TOKEN, access)
+            .await?
+            + self
+                .revoke_matching::<RefreshTokenEntity>(partition::REFRESH_TOKEN, refresh)
+                .await?
+            + self
+                .revoke_matching::<RefreshTokenEntity>(partition::ACCESS_TOKEN, legacy)
+                .await?;
+        Ok(revoked)
+    }
+
+    /// Signatures of the tokens [`revoke_user_tokens`] would revoke, without revoking them
+    ///
+    /// [`revoke_user_tokens`]: CouchStorage::revoke_user_tokens
+    pub async fn user_token_signatures(&self, user_id: &str) -> Result<Vec<String>> {
+        let (access, refresh, legacy) = user_token_selectors(user_id, None);
+        let access = self
+            .find_all::<AccessTokenEntity>(partition::ACCESS_TOKEN, access)
+            .await?;
+        let refresh = self
+            .find_all::<RefreshTokenEntity>(partition::REFRESH_TOKEN, refresh)
+            .await?;
+        let legacy = self
+            .find_all::<RefreshTokenEntity>(partition::ACCESS_TOKEN, legacy)
+            .await?;
+        let access = access.iter().map(|token| token.id().id().to_string());
+        let refresh = refresh.iter().chain(&legacy).map(|token| token.id().id().to_string());
+        Ok(access.chain(refresh).collect())
+    }
+
+    // Revoked documents no longer match, so the first batch is queried until it runs out
+    async fn revoke_matching<E: TokenEntity>(
+        &self,
+        partition: &str,
+        selector: serde_json::Value,
+    ) -> Result<usize> {
+        let mut revoked = 0;
+        loop {
+            let res = self
+                .db
+                .find_partitioned::<E>(partition, selector.clone(), BATCH_SIZE, None)
+                .await?;
+            for token in &res.docs {
+                if self.revoke_token_entity::<E>(token.id()).await? {
+                    revoked += 1;
+                }
+            }
+            if res.docs.len() < BATCH_SIZE {
+                return Ok(revoked);
+            }
+        }
+    }
+
+    /// Live access tokens of the user, neither revoked nor expired
+    pub async fn user_access_tokens(&self, user_id: &str) -> Result<Vec<AccessTokenEntity>> {
+        let selector = serde_json::json!({
+            "session.user_id": user_id,
+            "revoked": { "$ne": true },
+            "related_access_token_signature": { "$exists": false },
+            "expiration": { "$gt": Utc::now().timestamp() },
+        });
+        self.find_all(partition::ACCESS_TOKEN, selector).await
+    }
+
+    /// Live refresh tokens of the user, neither revoked nor expired
+    pub async fn user_refresh_tokens(&self, user_id: &str) -> Result<Vec<RefreshTokenEntity>> {
+        let selector = serde_json::json!({
+            "session.user_id": user_id,
+            "revoked": { "$ne": true },
+            "expiration": { "$gt": Utc::now().timestamp() },
+        });
+        let mut tokens = self.find_all(partition::REFRESH_TOKEN, selector.clone()).await?;
+        // Refresh tokens stored by earlier versions share the access_token partition
+        let mut legacy = selector;
+        legacy["related_access_token_signature"] = serde_json::json!({ "$exists": true });
+        tokens.extend(self.find_all(partition::ACCESS_TOKEN, legacy).await?);
+        Ok(tokens)
+    }
+
+    /// Revokes a token of the user along with the tokens issued with it, so that an access token
+    /// cannot be refreshed and a refresh token leaves no access token behind.
+    /// Returns false if the user has no such live token.
+    pub async fn revoke_user_token(&self, user_id: &str, sig: &str) -> Result<bool> {
+        let owned = |session: &Session| session.user_id().as_deref() == Some(user_id);
+        let access = self
+            .get_token_entity::<AccessTokenEntity>(&AccessTokenEntity::build_guid(sig))
+            .await
+            .filter(|token| !token.is_revoked() && owned(token.session()));
+        if let Some(token) = access {
+            self.revoke_token_entity::<AccessTokenEntity>(token.id()).await?;
+            let refresh = serde_json::json!({
+                "related_access_token_signature": sig,
+                "revoked": { "$ne": true },
+            });
+            self.revoke_matching::<RefreshTokenEntity>(partition::REFRESH_TOKEN, refresh).await?;
+            return Ok(true);
+        }
+
+        let refresh = self
+            .get_refresh_token_entity(sig)
+            .await
+            .filter(|token| !token.is_revoked() && owned(token.session()));
+        if let Some(token) = refresh {
+            self.revoke_token_entity::<RefreshTokenEntity>(token.id()).await?;
+            let related = AccessTokenEntity::build_guid(token.related_access_token_signature());
+            self.revoke_token_entity::<AccessTokenEntity>(&related).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Records the use of the access token, unless a use was recorded less than `interval` ago,
+    /// possibly by another instance. Returns whether it was written.
+    pub async fn record_token_use(
+        &self,
+        sig: &str,
+        at: DateTime<Utc>,
+        ip: Option<IpAddr>,
+        interval: Duration,
+    ) -> Result<bool> {
+        let guid = AccessTokenEntity::build_guid(sig);
+        let mut token = match self.get_token_entity::<AccessTokenEntity>(&guid).await {
+            Some(token) if !token.is_revoked() => token,
+            _ => return Ok(false),
+        };
+        if token.last_used().is_some_and(|last_used| at - *last_used < interval) {
+            return Ok(false);
+        }
+
+        token.set_last_used(at, ip);
+        match self.db.put(&guid.to_string(), &token).await {
+            Ok(_) => Ok(true),
+            // Revoked or used concurrently, either way there is nothing left to record
+            Err(err) if err.status() == StatusCode::CONFLICT => Ok(false),
+            Err(err) => Err(map_couch_err(err)),
+        }
+    }
+
+    async fn find_all<E: TokenEntity>(
+        &self,
+        partition: &str,
+        selector: serde_json::Value,
+    ) -> Result<Vec<E>> {
+        let mut tokens = Vec::new();
+        let mut bookmark = None;
+        loop {
+            let res = self
+                .db
+                .find_partitioned::<E>(partition, selector.clone(), BATCH_SIZE, bookmark)
+                .await?;
+            let done = res.docs.len() < BATCH_SIZE;
+            tokens.extend(res.docs);
+            if done {
+                return Ok(tokens);
+            }
+            bookmark = Some(res.bookmark);
+        }
+    }
+
+    /// Every client owned by the user with the given ID
+    pub async fn clients_owned_by(&self, owner: &str) -> Result<Vec<Client>> {
+        let filter = ClientFilter {
+            owner: Some(owner.to_string()),
+            ..Default::default()
+        };
+        let mut clients = Vec::new();
+        let mut bookmark = None;
+        loop {
+            let res = self
+                .db
+                .find_partitioned::<ClientEntity>(
+                    partition::CLIENT,
+                    client_selector(&filter),
+                    BATCH_SIZE,
+                    bookmark,
+                )
+                .await?;
+            let done = res.docs.len() < BATCH_SIZE;
+            for entity in res.docs {
+                clients.push(entity.try_into()?);
+            }
+
+            if done {
+                break;
+            }
+            bookmark = Some(res.bookmark);
+        }
+        Ok(clients)
     }
 }
 
 #[async_trait]
 impl ClientStorage for CouchStorage {
-    async fn list_clients(&self, limit: usize, cursor: Option<&Cursor>) -> Result<Page<Client>> {
-        let res = self
-            .db
-            .list_partitioned::<ClientEntity>("client", limit, cursor.map(Cursor::to_string))
-            .await?;
-        Ok(Page::from_rows_response(res, limit)
-            .map(|entity| ClientEntity::try_into(entity.clone()).unwrap()))
+    async fn list_clients(
+        &self,
+        filter: &ClientFilter,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<Client>> {
+        let page: Page<ClientEntity> = if filter.is_empty() {
+            self.list(limit, cursor).await?
+        } else {
+            self.find_by(client_selector(filter), limit, cursor).await?
+        };
+        Ok(page.map(|entity| ClientEntity::try_into(entity.clone()).unwrap()))
     }
 
     async fn get_client(&self, id: &str) -> Option<Client> {
-        let guid = ClientEntity::build_guid(id);
-        let client = match self.db.get::<ClientEntity>(guid.to_string().as_str()).await {
-            Ok(client) => match client {
-                Some(client) => client,
-                None => return None,
-            },
+        if let Some(client) = self.client_cache.get(id) {
+            return Some(client);
+        }
+        log::debug!(
+            "Client cache miss for '{}', {} hits and {} misses so far",
+            id,
+            self.client_cache.hits(),
+            self.client_cache.misses()
+        );
+
+        // A client is often used to authorize right after being created
+        let client = match self.find_consistent(id).await {
+            Ok(client) => client?,
             Err(err) => {
                 log::error!("Error fetching client from database: {}", err);
                 return None;
             }
         };
 
-        client.try_into().ok()
+        let client: Client = client.try_into().ok()?;
+        self.client_cache.insert(client.clone());
+        Some(client)
     }
 
     async fn save_client(&self, client: Client) -> Result<Client> {
-        let id = ClientEntity::build_guid(client.client_id());
-        let mut entity = ClientEntity::from(client.clone());
-        if let Some(rev) = self
-            .db
-            .get::<ClientEntity>(&id.to_string())
-            .await?
-            .as_ref()
-            .and_then(ClientEntity::rev)
-        {
-            entity.set_rev(rev.to_string());
-        }
-        let res = self.db.put(&entity.id().to_string(), &entity).await?;
-        entity.set_rev(res.rev);
+        let entity = self.upsert(ClientEntity::from(client.clone())).await?;
+        self.client_cache.invalidate(client.client_id());
         entity.try_into()
     }
 
     async fn delete_client(&self, client: &Client) -> Result<()> {
-        let id = ClientEntity::build_guid(client.client_id());
-        let entity = self.db.get::<ClientEntity>(&id.to_string()).await?;
+        let entity: Option<ClientEntity> = self.find(client.client_id()).await?;
         let entity = entity.ok_or_else(|| {
             Error::new(
                 ErrorKind::InvalidClient,
-                format!("client '{}' not found", id.id()),
+                format!("client '{}' not found", client.client_id()),
             )
         })?;
-        self.db
-            .delete(&entity.id().to_string(), entity.rev().unwrap())
-            .await?;
+        self.delete(&entity).await?;
+        self.client_cache.invalidate(client.client_id());
         Ok(())
     }
+
+    async fn client_stats(&self, client_id: &str) -> Result<ClientStats> {
+        let mut stats = ClientStats::default();
+        let mut bookmark = None;
+        let now = Utc::now();
+        loop {
+            let res = self
+                .db
+                .find_partitioned::<AccessTokenEntity>(
+                    partition::ACCESS_TOKEN,
+                    client_tokens_selector(client_id),
+                    BATCH_SIZE,
+                    bookmark,
+                )
+                .await?;
+            if let Some(warning) = &res.warning {
+                log::warn!("{}", warning);
+            }
+
+            for token in &res.docs {
+                if token.expiration().gt(&now) && !token.is_revoked() {
+                    stats.active_tokens += 1;
+                }
+                if let Some(issued_at) = token.issued_at() {
+                    let last = stats
+                        .last_token_issued_at
+                        .map_or(*issued_at, |last| last.max(*issued_at));
+                    stats.last_token_issued_at = Some(last);
+                }
+            }
+
+            if res.docs.len() < BATCH_SIZE {
+                break;
+            }
+            bookmark = Some(res.bookmark);
+        }
+        Ok(stats)
+    }
 }
 
 #[async_trait]
 impl TokenStorage<AccessToken> for CouchStorage {
     async fn get_token(&self, sig: &str) -> Option<AccessToken> {
         let guid = AccessTokenEntity::build_guid(sig);
-        let token = match self.db.get::<AccessTokenEntity>(&guid.to_string()).await {
-            Ok(token) => token,
-            Err(err) => {
-                log::error!("Error fetching access token from database: {}", err);
-                return None;
-            }
-        };
-        token.map(|t| t.to_empty_token())
+        self.get_token_entity::<AccessTokenEntity>(&guid)
+            .await
+            .filter(|token| !token.is_revoked())
+            .map(|token| token.to_empty_token())
     }
 
     async fn store_token(&self, sig: &str, token: AccessToken) -> Result<AccessToken> {
@@ -112,37 +423,31 @@ impl TokenStorage<AccessToken> for CouchStorage {
 
     async fn revoke_token(&self, sig: &str) -> Result<()> {
         let guid = AccessTokenEntity::build_guid(sig);
-        let token: Option<AccessTokenEntity> = self
-            .db
-            .get(&guid.to_string())
-            .await
-            .map_err(map_couch_err)?;
-        match token {
-            Some(token) => self
-                .db
-                .delete(token.id().to_string().as_str(), token.rev().unwrap())
-                .await
-                .map_err(map_couch_err),
-            None => Err(Error::new(
+        if self.revoke_token_entity::<AccessTokenEntity>(&guid).await? {
+            Ok(())
+        } else {
+            Err(Error::new(
                 ErrorKind::InvalidRequest,
                 "invalid access token".to_string(),
-            )),
+            ))
         }
     }
+
+    async fn is_revoked(&self, sig: &str) -> bool {
+        let guid = AccessTokenEntity::build_guid(sig);
+        self.get_token_entity::<AccessTokenEntity>(&guid)
+            .await
+            .is_some_and(|token| token.is_revoked())
+    }
 }
 
 #[async_trait]
 impl TokenStorage<RefreshToken> for CouchStorage {
     async fn get_token(&self, sig: &str) -> Option<RefreshToken> {
-        let guid = RefreshTokenEntity::build_guid(sig);
-        let token = match self.db.get::<RefreshTokenEntity>(&guid.to_string()).await {
-            Ok(token) => token,
-            Err(err) => {
-                log::error!("Error fetching access token from database: {}", err);
-                return None;
-            }
-        };
-        token.map(|t| t.to_empty_token())
+        self.get_refresh_token_entity(sig)
+            .await
+            .filter(|token| !token.is_revoked())
+            .map(|token| token.to_empty_token())
     }
 
     async fn store_token(&self, sig: &str, token: RefreshToken) -> Result<RefreshToken> {
@@ -156,23 +461,70 @@ impl TokenStorage<RefreshToken> for CouchStorage {
 
     async fn revoke_token(&self, sig: &str) -> Result<()> {
         let guid = RefreshTokenEntity::build_guid(sig);
-        let token: Option<RefreshTokenEntity> = self
-            .db
-            .get(&guid.to_string())
-            .await
-            .map_err(map_couch_err)?;
-        match token {
-            Some(token) => self
-                .db
-                .delete(token.id().to_string().as_str(), token.rev().unwrap())
-                .await
-                .map_err(map_couch_err),
-            None => Err(Error::new(
+        let legacy_guid = RefreshTokenEntity::build_legacy_guid(sig);
+        if self
+            .revoke_token_entity::<RefreshTokenEntity>(&guid)
+            .await?
+            || self
+                .revoke_token_entity::<RefreshTokenEntity>(&legacy_guid)
+                .await?
+        {
+            Ok(())
+        } else {
+            Err(Error::new(
                 ErrorKind::InvalidRequest,
                 "invalid refresh token".to_string(),
-            )),
+            ))
+        }
+    }
+
+    async fn is_revoked(&self, sig: &str) -> bool {
+        self.get_refresh_token_entity(sig)
+            .await
+            .is_some_and(|token| token.is_revoked())
+    }
+}
+
+impl CouchStorage {
+    async fn get_token_entity<E: TokenEntity>(&self, guid: &Guid) -> Option<E> {
+        match self.db.get::<E>(&guid.to_string()).await {
+            Ok(token) => token,
+            Err(err) => {
+                log::error!("Error fetching token {} from database: {}", guid, err);
+                None
+            }
+        }
+    }
+
+    /// Refresh tokens stored by earlier versions are looked up in the access token partition
+    async fn get_refresh_token_entity(&self, sig: &str) -> Option<RefreshTokenEntity> {
+        let guid = RefreshTokenEntity::build_guid(sig);
+        match self.get_token_entity(&guid).await {
+            Some(token) => Some(token),
+            None => {
+                let legacy_guid = RefreshTokenEntity::build_legacy_guid(sig);
+                self.get_token_entity(&legacy_guid).await
+            }
         }
     }
+
+    /// Flags the token as revoked, returning false if it does not exist.
+    /// A concurrent update makes the write conflict, in which case the token is read again,
+    /// so racing revocations all succeed and revoking a revoked token is a no-op.
+    async fn revoke_token_entity<E: TokenEntity>(&self, guid: &Guid) -> Result<bool> {
+        let token = self
+            .db
+            .update_if(&guid.to_string(), |token: &mut E| {
+                if token.is_revoked() {
+                    return false;
+                }
+                token.revoke();
+                true
+            })
+            .await
+            .map_err(map_couch_err)?;
+        Ok(token.is_some())
+    }
 }
 
 #[async_trait]
@@ -227,6 +579,358 @@ impl AuthorizationCodeStorage for CouchStorage {
     }
 }
 
+#[async_trait]
+impl BootstrapTokenStorage for CouchStorage {
+    async fn get_bootstrap_token(&self, sig: &str) -> Option<BootstrapToken> {
+        let guid = BootstrapTokenEntity::build_guid(sig);
+        let token = match self.db.get::<BootstrapTokenEntity>(&guid.to_string()).await {
+            Ok(token) => token,
+            Err(err) => {
+                log::error!("Error fetching bootstrap token from database: {}", err);
+                return None;
+            }
+        };
+        token.map(|token| token.to_empty_token())
+    }
+
+    async fn store_bootstrap_token(
+        &self,
+        sig: &str,
+        token: BootstrapToken,
+    ) -> Result<BootstrapToken> {
+        let entity = BootstrapTokenEntity::new(
+            String::from(sig),
+            token.session().clone(),
+            *token.expiration(),
+        );
+        self.db
+            .put(&entity.id().to_string(), &entity)
+            .await
+            .map_err(map_couch_err)?;
+        Ok(token)
+    }
+
+    async fn take_bootstrap_token(&self, sig: &str) -> Result<bool> {
+        let guid = BootstrapTokenEntity::build_guid(sig);
+        let token: Option<BootstrapTokenEntity> = self
+            .db
+            .get(&guid.to_string())
+            .await
+            .map_err(map_couch_err)?;
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(false),
+        };
+        // Deleting a revision that is already gone conflicts, so only one concurrent call succeeds
+        match self
+            .db
+            .delete(token.id().to_string().as_str(), token.rev().unwrap())
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(err) if err.status() == StatusCode::CONFLICT => Ok(false),
+            Err(err) => Err(map_couch_err(err)),
+        }
+    }
+}
+
+#[async_trait]
+impl PushedRequestStorage for CouchStorage {
+    async fn get_pushed_request(&self, sig: &str) -> Option<PushedRequest> {
+        let guid = PushedRequestEntity::build_guid(sig);
+        let req = match self.db.get::<PushedRequestEntity>(&guid.to_string()).await {
+            Ok(req) => req,
+            Err(err) => {
+                log::error!("Error fetching pushed request from database: {}", err);
+                return None;
+            }
+        };
+        req.map(|req| req.to_pushed_request())
+    }
+
+    async fn store_pushed_request(&self, sig: &str, req: PushedRequest) -> Result<PushedRequest> {
+        let entity =
+            PushedRequestEntity::new(String::from(sig), req.request().clone(), *req.expiration());
+        self.db
+            .put(&entity.id().to_string(), &entity)
+            .await
+            .map_err(map_couch_err)?;
+        Ok(req)
+    }
+
+    async fn take_pushed_request(&self, sig: &str) -> Result<bool> {
+        let guid = PushedRequestEntity::build_guid(sig);
+        let req: Option<PushedRequestEntity> = self
+            .db
+            .get(&guid.to_string())
+            .await
+            .map_err(map_couch_err)?;
+        let req = match req {
+            Some(req) => req,
+            None => return Ok(false),
+        };
+        // Deleting a revision that is already gone conflicts, so only one concurrent call succeeds
+        match self
+            .db
+            .delete(req.id().to_string().as_str(), req.rev().unwrap())
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(err) if err.status() == StatusCode::CONFLICT => Ok(false),
+            Err(err) => Err(map_couch_err(err)),
+        }
+    }
+}
+
+#[async_trait]
+impl DeviceAuthorizationStorage for CouchStorage {
+    async fn get_device_authorization(&self, sig: &str) -> Option<DeviceAuthorization> {
+        let guid = DeviceAuthorizationEntity::build_guid(sig);
+        let authorization = match self
+            .db
+            .get::<DeviceAuthorizationEntity>(&guid.to_string())
+            .await
+        {
+            Ok(authorization) => authorization,
+            Err(err) => {
+                log::error!("Error fetching device authorization from database: {}", err);
+                return None;
+            }
+        };
+        authorization.map(|authorization| authorization.to_device_authorization())
+    }
+
+    async fn find_device_authorization(
+        &self,
+        user_code: &str,
+    ) -> Option<(String, DeviceAuthorization)> {
+        let res = self
+            .db
+            .find_partitioned::<DeviceAuthorizationEntity>(
+                "device_authorization",
+                serde_json::json!({ "user_code": user_code }),
+                1,
+                None,
+            )
+            .await;
+        match res {
+            Ok(res) => res.docs.first().map(|authorization| {
+                (
+                    authorization.signature().to_string(),
+                    authorization.to_device_authorization(),
+                )
+            }),
+            Err(err) => {
+                log::error!("Error finding device authorization in database: {}", err);
+                None
+            }
+        }
+    }
+
+    async fn store_device_authorization(
+        &self,
+        sig: &str,
+        authorization: DeviceAuthorization,
+    ) -> Result<DeviceAuthorization> {
+        let entity = DeviceAuthorizationEntity::new(String::from(sig), authorization);
+        // Writing a revision that is not the latest conflicts, so concurrent updates are not lost
+        let res = self
+            .db
+            .put(&entity.id().to_string(), &entity)
+            .await
+            .map_err(map_couch_err)?;
+        let mut authorization = entity.to_device_authorization();
+        authorization.set_revision(Some(res.rev));
+        Ok(authorization)
+    }
+
+    async fn take_device_authorization(&self, sig: &str) -> Result<bool> {
+        let guid = DeviceAuthorizationEntity::build_guid(sig);
+        let authorization: Option<DeviceAuthorizationEntity> = self
+            .db
+            .get(&guid.to_string())
+            .await
+            .map_err(map_couch_err)?;
+        let authorization = match authorization {
+            Some(authorization) => authorization,
+            None => return Ok(false),
+        };
+        // Deleting a revision that is already gone conflicts, so only one concurrent call succeeds
+        match self
+            .db
+            .delete(
+                authorization.id().to_string().as_str(),
+                authorization.rev().unwrap(),
+            )
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(err) if err.status() == StatusCode::CONFLICT => Ok(false),
+            Err(err) => Err(map_couch_err(err)),
+        }
+    }
+}
+
+#[async_trait]
+impl DeviceStorage for CouchStorage {
+    /// Tokens of a user can be issued concurrently, so the family is added to the latest
+    /// revision of the document, retrying on conflicts
+    async fn add_device_family(&self, user_id: &str, family: &str) -> Result<bool> {
+        let guid = KnownDevicesEntity::build_guid(user_id).to_string();
+        if let Some(added) = self.add_known_device_family(&guid, family).await? {
+            return Ok(added);
+        }
+
+        let mut entity = KnownDevicesEntity::new(user_id);
+        entity.add_family(family);
+        match self.db.put(&guid, &entity).await {
+            Ok(_) => Ok(true),
+            // Created concurrently, the family may be the one it was created with
+            Err(err) if err.status() == StatusCode::CONFLICT => Ok(self
+                .add_known_device_family(&guid, family)
+                .await?
+                .unwrap_or(false)),
+            Err(err) => Err(map_couch_err(err)),
+        }
+    }
+}
+
+impl CouchStorage {
+    /// Adds the family to the known devices document, returning whether it was missing,
+    /// or none if there is no such document yet
+    async fn add_known_device_family(&self, guid: &str, family: &str) -> Result<Option<bool>> {
+        let mut added = false;
+        let entity = self
+            .db
+            .update_if(guid, |entity: &mut KnownDevicesEntity| {
+                added = entity.add_family(family);
+                added
+            })
+            .await
+            .map_err(map_couch_err)?;
+        Ok(entity.map(|_| added))
+    }
+}
+
 fn map_couch_err(err: couchdb::error::Error) -> Error {
     Error::new(ErrorKind::ServerError, err.to_string())
 }
+
+fn client_selector(filter: &ClientFilter) -> serde_json::Value {
+    let mut selector = serde_json::Map::new();
+    if let Some(kind) = &filter.kind {
+        selector.insert("kind".to_string(), serde_json::json!(kind));
+    }
+    if let Some(trusted) = filter.trusted {
+        selector.insert("trusted".to_string(), serde_json::json!(trusted));
+    }
+    for (key, value) in &filter.labels {
+        selector.insert(format!("labels.{}", key), serde_json::json!(value));
+    }
+    if let Some(owner) = &filter.owner {
+        selector.insert("owner".to_string(), serde_json::json!(owner));
+    }
+    serde_json::Value::Object(selector)
+}
+
+// Refresh tokens stored by earlier versions share the access_token partition,
+// so they are excluded explicitly
+fn client_tokens_selector(client_id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "session.client_id": client_id,
+        "related_access_token_signature": { "$exists": false },
+    })
+}
+
+/// Selectors of the live access tokens of the user, of their refresh tokens, and of their
+/// refresh tokens stored by earlier versions, which share the access_token partition.
+/// The access token with the given signature and its refresh tokens are left out.
+fn user_token_selectors(
+    user_id: &str,
+    keep: Option<&str>,
+) -> (serde_json::Value, serde_json::Value, serde_json::Value) {
+    let mut access = serde_json::json!({
+        "session.user_id": user_id,
+        "revoked": { "$ne": true },
+        "related_access_token_signature": { "$exists": false },
+    });
+    let mut refresh = serde_json::json!({
+        "session.user_id": user_id,
+        "revoked": { "$ne": true },
+    });
+    if let Some(sig) = keep {
+        let kept = AccessTokenEntity::build_guid(sig).to_string();
+        access["_id"] = serde_json::json!({ "$ne": kept });
+        refresh["related_access_token_signature"] = serde_json::json!({ "$ne": sig });
+    }
+    let mut legacy = refresh.clone();
+    legacy["related_access_token_signature"]["$exists"] = serde_json::json!(true);
+    (access, refresh, legacy)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::oauth::client::ClientFilter;
+
+    use super::client_selector;
+
+    #[test]
+    fn it_builds_an_empty_selector() {
+        let filter = ClientFilter::default();
+        assert!(filter.is_empty());
+        assert_eq!(client_selector(&filter), json!({}));
+    }
+
+    #[test]
+    fn it_filters_by_kind_and_trusted() {
+        let filter = ClientFilter {
+            kind: Some("public".to_string()),
+            trusted: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            client_selector(&filter),
+            json!({ "kind": "public", "trusted": false })
+        );
+    }
+
+    #[test]
+    fn it_filters_by_labels() {
+        let mut labels = HashMap::new();
+        labels.insert("env".to_string(), "prod".to_string());
+        labels.insert("team".to_string(), "core".to_string());
+        let filter = ClientFilter {
+            labels,
+            ..Default::default()
+        };
+        assert_eq!(
+            client_selector(&filter),
+            json!({ "labels.env": "prod", "labels.team": "core" })
+        );
+    }
+
+    #[test]
+    fn it_combines_all_filters() {
+        let mut labels = HashMap::new();
+        labels.insert("env".to_string(), "prod".to_string());
+        let filter = ClientFilter {
+            kind: Some("confidential".to_string()),
+            trusted: Some(true),
+            labels,
+            owner: Some("user:jdoe".to_string()),
+        };
+        assert_eq!(
+            client_selector(&filter),
+            json!({
+                "kind": "confidential",
+                "trusted": true,
+                "labels.env": "prod",
+                "owner": "user:jdoe",
+            })
+        );
+    }
+}
@@ -1,30 +1,63 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
 
 use actix_web::web::{Data, Json, Path, Query};
-use actix_web::{delete, get, post, put};
+use actix_web::{delete, get, post, put, HttpResponse};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use enseada::guid::Guid;
 use enseada::pagination::{Cursor, Page};
+use enseada::secure;
 
+use crate::couchdb::partition;
 use crate::couchdb::repository::Entity;
+use crate::http::dry_run::{self, DryRunQuery, Plan};
 use crate::http::error::ApiError;
 use crate::http::extractor::scope::Scope;
 use crate::http::extractor::user::CurrentUser;
 use crate::http::{ApiResult, PaginationQuery};
-use crate::oauth::client::Client;
+use crate::oauth::assertion::Jwk;
+use crate::oauth::client::{Client, ClientFilter, ClientStats};
+use crate::oauth::error::{Error as OAuthError, ErrorKind};
+use crate::oauth::facade::Oauth;
+use crate::oauth::handler::INTROSPECTION_SCOPE;
 use crate::oauth::persistence::client::ClientEntity;
 use crate::oauth::persistence::CouchStorage;
+use crate::oauth::request::{AuthorizationRequest, GrantType, ResponseType};
+use crate::oauth::response::Diagnosis;
+use crate::oauth::scope::Scope as OAuthScope;
+use crate::oauth::stats::ClientStatsCache;
 use crate::oauth::storage::ClientStorage;
 use crate::rbac::Enforcer;
+use crate::user::User;
 
 #[derive(Debug, Serialize, PartialEq)]
 pub struct ClientResponse {
     pub client_id: String,
     pub kind: String,
-    pub allowed_scopes: Scope,
+    pub allowed_scopes: OAuthScope,
     pub allowed_redirect_uris: HashSet<url::Url>,
+    pub allowed_grant_types: HashSet<GrantType>,
+    pub allowed_origins: HashSet<url::Url>,
+    /// Public keys of clients authenticating with `private_key_jwt`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub jwks: Vec<Jwk>,
+    pub require_state: bool,
+    pub bind_tokens: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_issuance_threshold: Option<u32>,
+    pub trusted: bool,
+    /// Whether the client can introspect the tokens of any client
+    pub resource_server: bool,
+    pub labels: HashMap<String, String>,
+    /// Username of the owner
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub orphaned: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<ClientStats>,
 }
 
 impl From<Client> for ClientResponse {
@@ -40,21 +73,191 @@ impl From<&Client> for ClientResponse {
             kind: client.kind().to_string(),
             allowed_scopes: client.allowed_scopes().clone(),
             allowed_redirect_uris: client.allowed_redirect_uris().clone(),
+            allowed_grant_types: client.allowed_grant_types().clone(),
+            allowed_origins: client.allowed_origins().clone(),
+            jwks: client.jwks().to_vec(),
+            require_state: client.requires_state(),
+            bind_tokens: client.binds_tokens(),
+            token_issuance_threshold: client.token_issuance_threshold(),
+            trusted: client.is_trusted(),
+            resource_server: client.is_resource_server(),
+            labels: client.labels().clone(),
+            owner: client
+                .owner()
+                .map(|owner| Guid::from(owner.to_string()).id().to_string()),
+            orphaned: client.is_orphaned(),
+            stats: None,
         }
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ClientListQuery {
+    kind: Option<ClientKind>,
+    trusted: Option<bool>,
+    labels: Option<String>,
+    /// Username of the owner
+    owner: Option<String>,
+    include: Option<String>,
+}
+
+impl ClientListQuery {
+    /// Label selectors are expressed as comma-separated `key=value` pairs
+    fn filter(&self) -> ApiResult<ClientFilter> {
+        let mut labels = HashMap::new();
+        if let Some(selectors) = &self.labels {
+            for selector in selectors.split(',').filter(|s| !s.is_empty()) {
+                let mut parts = selector.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        labels.insert(key.to_string(), value.to_string());
+                    }
+                    _ => {
+                        return Err(ApiError::BadRequest(format!(
+                            "invalid label selector '{}', expected key=value",
+                            selector
+                        )))
+                    }
+                }
+            }
+        }
+
+        Ok(ClientFilter {
+            kind: self.kind.as_ref().map(ClientKind::to_string),
+            trusted: self.trusted,
+            labels,
+            owner: self
+                .owner
+                .as_ref()
+                .map(|owner| User::build_guid(owner).to_string()),
+        })
+    }
+
+    fn include_stats(&self) -> bool {
+        self.include
+            .as_ref()
+            .map(|include| include.split(',').any(|i| i == "stats"))
+            .unwrap_or(false)
+    }
+}
+
+/// Scope letting users manage the clients they own, without any other permission
+const OWN_CLIENTS_SCOPE: &str = "clients:manage:own";
+
+/// Scopes only admins can allow to clients, since tokens granted them reach every client
+const ADMIN_ONLY_SCOPES: &[&str] = &[INTROSPECTION_SCOPE];
+
+/// Clients the current user can act on
+#[derive(Debug, PartialEq)]
+enum ClientAccess {
+    /// Every client, with the admin scope and a permission on the object
+    All,
+    /// Only the clients owned by the user with the given ID
+    Own(String),
+}
+
+impl ClientAccess {
+    /// Falls back to the clients owned by the user if the admin scope or permission is missing
+    fn resolve(
+        scope: &OAuthScope,
+        admin_scope: &str,
+        enforcer: &Enforcer,
+        user: &User,
+        object: &Guid,
+        action: &str,
+    ) -> ApiResult<Self> {
+        let admin = Scope::from(admin_scope)
+            .matches(scope)
+            .map_err(ApiError::from)
+            .and_then(|_| Ok(enforcer.check(user.id(), object, action)?));
+        match admin {
+            Ok(()) => Ok(ClientAccess::All),
+            Err(_) if scope.grants(OWN_CLIENTS_SCOPE) => {
+                Ok(ClientAccess::Own(user.id().to_string()))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn allows(&self, client: &Client) -> bool {
+        match self {
+            ClientAccess::All => true,
+            ClientAccess::Own(owner) => client.owner() == Some(owner),
+        }
+    }
+
+    /// Checks the scopes allowed to a client. Owners can only allow the scopes granted to their
+    /// own token, and never the admin-only ones, so that their clients cannot gain more rights
+    fn check_allowed_scopes(&self, granted: &OAuthScope, allowed: &OAuthScope) -> ApiResult<()> {
+        if *self == ClientAccess::All {
+            return Ok(());
+        }
+
+        let mut reasons = Vec::new();
+        let not_granted = allowed.difference(granted);
+        if !not_granted.is_empty() {
+            reasons.push(format!("not granted to your token: {}", not_granted));
+        }
+        let admin_only: OAuthScope = allowed
+            .iter()
+            .filter(|entry| {
+                let entry = OAuthScope::from(*entry);
+                ADMIN_ONLY_SCOPES.iter().any(|admin| entry.grants(admin))
+            })
+            .collect();
+        if !admin_only.is_empty() {
+            reasons.push(format!("reserved to admins: {}", admin_only));
+        }
+
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(format!(
+                "allowed_scopes cannot include scopes {}",
+                reasons.join(", ")
+            )))
+        }
+    }
+
+    /// Only admins can mark clients as resource servers, or unmark them
+    fn check_resource_server(&self) -> ApiResult<()> {
+        match self {
+            ClientAccess::All => Ok(()),
+            ClientAccess::Own(_) => Err(ApiError::Forbidden(
+                "only admins can change whether a client is a resource server".to_string(),
+            )),
+        }
+    }
+
+    /// Fetches a client the user can act on. Clients of other users are reported as not found.
+    async fn get_client(&self, storage: &CouchStorage, client_id: &str) -> ApiResult<Client> {
+        storage
+            .get_client(client_id)
+            .await
+            .filter(|client| self.allows(client))
+            .ok_or_else(|| ApiError::not_found(&format!("client '{}' not found", client_id)))
+    }
+}
+
 #[get("/api/v1beta1/clients")]
 pub async fn list_clients(
     storage: Data<CouchStorage>,
+    stats_cache: Data<ClientStatsCache>,
     enforcer: Data<RwLock<Enforcer>>,
     scope: Scope,
     current_user: CurrentUser,
     list: Query<PaginationQuery>,
+    query: Query<ClientListQuery>,
 ) -> ApiResult<Json<Page<ClientResponse>>> {
-    Scope::from("clients:read").matches(&scope)?;
     let enforcer = enforcer.read().await;
-    enforcer.check(current_user.id(), &Guid::simple("clients"), "read")?;
+    let access = ClientAccess::resolve(
+        &scope,
+        "clients:read",
+        &enforcer,
+        &current_user,
+        &Guid::simple("clients")?,
+        "read",
+    )?;
 
     let limit = list.limit();
     let cursor = list.cursor();
@@ -65,27 +268,81 @@ pub async fn list_clients(
         None
     };
 
+    let mut filter = query.filter()?;
+    if let ClientAccess::Own(owner) = access {
+        if filter.owner.as_ref().is_some_and(|filter| *filter != owner) {
+            return Err(ApiError::Forbidden(
+                "only your own clients can be listed".to_string(),
+            ));
+        }
+        filter.owner = Some(owner);
+    }
     let page = storage
-        .list_clients(limit, cursor.as_ref())
-        .await?
-        .map(|client| ClientResponse::from(client));
-    Ok(Json(page))
+        .list_clients(&filter, limit, cursor.as_ref())
+        .await?;
+
+    if !query.include_stats() {
+        return Ok(Json(page.map(|client| ClientResponse::from(client))));
+    }
+
+    let mut stats = HashMap::new();
+    for client in page.items() {
+        let client_id = client.client_id();
+        let client_stats = stats_cache
+            .get_or_compute(client_id, || storage.client_stats(client_id))
+            .await?;
+        stats.insert(client_id.to_string(), client_stats);
+    }
+
+    Ok(Json(page.map(|client| {
+        let mut res = ClientResponse::from(client);
+        res.stats = stats.remove(client.client_id());
+        res
+    })))
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ClientKind {
     Public,
+    Native,
     Confidential,
 }
 
+impl Display for ClientKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ClientKind::Public => "public",
+            ClientKind::Native => "native",
+            ClientKind::Confidential => "confidential",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateClientPayload {
     pub client_id: String,
     pub kind: ClientKind,
     pub client_secret: Option<String>,
-    pub allowed_scopes: Scope,
+    pub allowed_scopes: OAuthScope,
     pub allowed_redirect_uris: HashSet<url::Url>,
+    pub allowed_grant_types: Option<HashSet<GrantType>>,
+    #[serde(default)]
+    pub allowed_origins: HashSet<url::Url>,
+    #[serde(default)]
+    pub jwks: Vec<Jwk>,
+    #[serde(default)]
+    pub require_state: bool,
+    #[serde(default)]
+    pub bind_tokens: bool,
+    pub token_issuance_threshold: Option<u32>,
+    #[serde(default)]
+    pub trusted: bool,
+    #[serde(default)]
+    pub resource_server: bool,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
 }
 
 #[post("/api/v1beta1/clients")]
@@ -96,11 +353,24 @@ pub async fn create_client(
     current_user: CurrentUser,
     body: Json<CreateClientPayload>,
 ) -> ApiResult<Json<ClientResponse>> {
-    Scope::from("clients:manage").matches(&scope)?;
     let enforcer = enforcer.read().await;
-    enforcer.check(current_user.id(), &Guid::simple("clients"), "create")?;
+    let access = ClientAccess::resolve(
+        &scope,
+        "clients:manage",
+        &enforcer,
+        &current_user,
+        &Guid::simple("clients")?,
+        "create",
+    )?;
+    access.check_allowed_scopes(&scope, &body.allowed_scopes)?;
+    if body.resource_server {
+        access.check_resource_server()?;
+    }
 
     let client_id = body.client_id.clone();
+    // Client IDs are part of the IDs of their documents
+    Guid::partitioned(partition::CLIENT, &client_id)
+        .map_err(|err| ApiError::ValidationError(vec![err.to_string()]))?;
     let client_secret = body.client_secret.clone();
     let allowed_scopes = body.allowed_scopes.clone();
     let allowed_redirect_uris = body.allowed_redirect_uris.clone();
@@ -108,14 +378,19 @@ pub async fn create_client(
 
     log::debug!("creating new {:?} client '{}'", kind, &client_id);
 
-    let client = match kind {
+    let mut client = match kind {
         ClientKind::Public => Client::public(client_id, allowed_scopes, allowed_redirect_uris),
+        ClientKind::Native => Client::native(client_id, allowed_scopes, allowed_redirect_uris),
         ClientKind::Confidential => {
             let client_secret = match client_secret {
                 Some(client_secret) => client_secret,
+                // Clients with keys authenticate with assertions, so their secret is never used
+                None if !body.jwks.is_empty() => secure::generate_token(32)
+                    .map_err(ApiError::InternalServerError)?
+                    .to_string(),
                 None => {
                     return Err(ApiError::ValidationError(vec![
-                        "client_secret is required for confidential clients".to_string(),
+                        "client_secret or jwks is required for confidential clients".to_string(),
                     ]))
                 }
             };
@@ -128,6 +403,30 @@ pub async fn create_client(
             )?
         }
     };
+    if let Some(allowed_grant_types) = &body.allowed_grant_types {
+        client
+            .set_allowed_grant_types(allowed_grant_types.clone())
+            .map_err(|err| ApiError::ValidationError(vec![err.description().to_string()]))?;
+    }
+    client
+        .set_allowed_origins(body.allowed_origins.clone())
+        .map_err(|err| ApiError::ValidationError(vec![err.description().to_string()]))?;
+    client
+        .set_jwks(body.jwks.clone())
+        .map_err(|err| ApiError::ValidationError(vec![err.description().to_string()]))?;
+    if body.token_issuance_threshold == Some(0) {
+        return Err(ApiError::ValidationError(vec![
+            "token_issuance_threshold must be greater than 0".to_string(),
+        ]));
+    }
+    client
+        .set_require_state(body.require_state)
+        .set_bind_tokens(body.bind_tokens)
+        .set_token_issuance_threshold(body.token_issuance_threshold)
+        .set_trusted(body.trusted)
+        .set_resource_server(body.resource_server)
+        .set_labels(body.labels.clone())
+        .set_owner(Some(current_user.id().to_string()));
 
     log::debug!("saving client");
     let client = storage.save_client(client).await?;
@@ -148,28 +447,105 @@ pub async fn get_client(
     current_user: CurrentUser,
     path: Path<ClientPathParam>,
 ) -> ApiResult<Json<ClientResponse>> {
-    Scope::from("clients:read").matches(&scope)?;
     let enforcer = enforcer.read().await;
     let client_id = &path.client_id;
-    enforcer.check(
-        current_user.id(),
-        &ClientEntity::build_guid(client_id),
+    let access = ClientAccess::resolve(
+        &scope,
+        "clients:read",
+        &enforcer,
+        &current_user,
+        &Guid::partitioned(partition::CLIENT, client_id)?,
         "read",
     )?;
 
-    let client = storage.get_client(client_id).await;
+    let client = access.get_client(&storage, client_id).await?;
+    Ok(Json(ClientResponse::from(client)))
+}
 
-    client
-        .ok_or_else(|| ApiError::not_found(&format!("client '{}' not found", client_id)))
-        .map(ClientResponse::from)
-        .map(Json)
+#[derive(Debug, Deserialize)]
+pub struct ValidateClientPayload {
+    pub response_type: String,
+    pub response_mode: Option<String>,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: OAuthScope,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub prompt: Option<String>,
+    pub max_age: Option<u64>,
+    #[serde(default)]
+    pub resource: Vec<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[post("/api/v1beta1/clients/{client_id}/validate")]
+pub async fn validate_client(
+    storage: Data<CouchStorage>,
+    oauth: Data<Arc<dyn Oauth>>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<ClientPathParam>,
+    body: Json<ValidateClientPayload>,
+) -> ApiResult<Json<Diagnosis>> {
+    let enforcer = enforcer.read().await;
+    let client_id = &path.client_id;
+    let access = ClientAccess::resolve(
+        &scope,
+        "clients:manage",
+        &enforcer,
+        &current_user,
+        &Guid::partitioned(partition::CLIENT, client_id)?,
+        "update",
+    )?;
+    access.get_client(&storage, client_id).await?;
+
+    let body = body.into_inner();
+    let response_type = serde_json::from_value::<ResponseType>(body.response_type.clone().into());
+    let req = AuthorizationRequest {
+        response_type: ResponseType::Code,
+        client_id: client_id.clone(),
+        redirect_uri: body.redirect_uri,
+        scope: body.scope,
+        state: body.state.filter(|state| !state.is_empty()),
+        nonce: body.nonce.filter(|nonce| !nonce.is_empty()),
+        response_mode: body.response_mode.filter(|mode| !mode.is_empty()),
+        prompt: body.prompt.filter(|prompt| !prompt.is_empty()),
+        max_age: body.max_age,
+        resource: body.resource,
+    };
+
+    let mut diagnosis = oauth.diagnose(&req).await;
+    if response_type.is_err() {
+        diagnosis.add_violation(OAuthError::new(
+            ErrorKind::UnsupportedResponseType,
+            format!("unsupported response type '{}'", body.response_type),
+        ));
+    }
+    if body.code_challenge.is_some() || body.code_challenge_method.is_some() {
+        diagnosis.add_advisory(
+            "PKCE is not supported yet, code_challenge and code_challenge_method are ignored"
+                .to_string(),
+        );
+    }
+    Ok(Json(diagnosis))
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UpdateClientPayload {
     pub client_secret: Option<String>,
-    pub allowed_scopes: Option<Scope>,
+    pub allowed_scopes: Option<OAuthScope>,
     pub allowed_redirect_uris: Option<HashSet<url::Url>>,
+    pub allowed_grant_types: Option<HashSet<GrantType>>,
+    pub allowed_origins: Option<HashSet<url::Url>>,
+    pub jwks: Option<Vec<Jwk>>,
+    pub require_state: Option<bool>,
+    pub bind_tokens: Option<bool>,
+    pub token_issuance_threshold: Option<u32>,
+    pub trusted: Option<bool>,
+    pub resource_server: Option<bool>,
+    pub labels: Option<HashMap<String, String>>,
 }
 
 #[put("/api/v1beta1/clients/{client_id}")]
@@ -181,24 +557,28 @@ pub async fn update_client(
     path: Path<ClientPathParam>,
     body: Json<UpdateClientPayload>,
 ) -> ApiResult<Json<ClientResponse>> {
-    Scope::from("clients:manage").matches(&scope)?;
     let enforcer = enforcer.read().await;
-    enforcer.check(current_user.id(), &Guid::simple("clients"), "update")?;
+    let access = ClientAccess::resolve(
+        &scope,
+        "clients:manage",
+        &enforcer,
+        &current_user,
+        &Guid::simple("clients")?,
+        "update",
+    )?;
 
     let client_id = &path.client_id;
 
     log::debug!("updating client '{}'", client_id);
 
-    let mut client = storage
-        .get_client(client_id)
-        .await
-        .ok_or_else(|| ApiError::not_found(&format!("client '{}' not found", client_id)))?;
+    let mut client = access.get_client(&storage, client_id).await?;
 
     if let Some(client_secret) = &body.client_secret {
         client.set_client_secret(client_secret.clone())?;
     }
 
     if let Some(allowed_scopes) = &body.allowed_scopes {
+        access.check_allowed_scopes(&scope, allowed_scopes)?;
         client.set_allowed_scopes(allowed_scopes.clone());
     }
 
@@ -206,6 +586,56 @@ pub async fn update_client(
         client.set_allowed_redirect_uris(allowed_redirect_uris.clone());
     }
 
+    if let Some(allowed_grant_types) = &body.allowed_grant_types {
+        client
+            .set_allowed_grant_types(allowed_grant_types.clone())
+            .map_err(|err| ApiError::ValidationError(vec![err.description().to_string()]))?;
+    }
+
+    if let Some(allowed_origins) = &body.allowed_origins {
+        client
+            .set_allowed_origins(allowed_origins.clone())
+            .map_err(|err| ApiError::ValidationError(vec![err.description().to_string()]))?;
+    }
+
+    if let Some(jwks) = &body.jwks {
+        client
+            .set_jwks(jwks.clone())
+            .map_err(|err| ApiError::ValidationError(vec![err.description().to_string()]))?;
+    }
+
+    if let Some(require_state) = body.require_state {
+        client.set_require_state(require_state);
+    }
+
+    if let Some(bind_tokens) = body.bind_tokens {
+        client.set_bind_tokens(bind_tokens);
+    }
+
+    if let Some(threshold) = body.token_issuance_threshold {
+        if threshold == 0 {
+            return Err(ApiError::ValidationError(vec![
+                "token_issuance_threshold must be greater than 0".to_string(),
+            ]));
+        }
+        client.set_token_issuance_threshold(Some(threshold));
+    }
+
+    if let Some(trusted) = body.trusted {
+        client.set_trusted(trusted);
+    }
+
+    if let Some(resource_server) = body.resource_server {
+        if resource_server != client.is_resource_server() {
+            access.check_resource_server()?;
+        }
+        client.set_resource_server(resource_server);
+    }
+
+    if let Some(labels) = &body.labels {
+        client.set_labels(labels.clone());
+    }
+
     log::debug!("saving client");
     let client = storage.save_client(client).await?;
     log::debug!("client saved");
@@ -215,28 +645,139 @@ pub async fn update_client(
 #[delete("/api/v1beta1/clients/{client_id}")]
 pub async fn delete_client(
     storage: Data<CouchStorage>,
+    stats_cache: Data<ClientStatsCache>,
     enforcer: Data<RwLock<Enforcer>>,
     scope: Scope,
     current_user: CurrentUser,
     path: Path<ClientPathParam>,
-) -> ApiResult<Json<ClientResponse>> {
-    Scope::from("clients:manage").matches(&scope)?;
+    query: Query<DryRunQuery>,
+) -> ApiResult<HttpResponse> {
     let enforcer = enforcer.read().await;
     let client_id = &path.client_id;
-    enforcer.check(
-        current_user.id(),
-        &ClientEntity::build_guid(client_id),
+    let access = ClientAccess::resolve(
+        &scope,
+        "clients:manage",
+        &enforcer,
+        &current_user,
+        &Guid::partitioned(partition::CLIENT, client_id)?,
         "delete",
     )?;
 
-    let client = storage
-        .get_client(client_id)
-        .await
-        .ok_or_else(|| ApiError::not_found(&format!("client '{}' not found", client_id)))?;
+    let client = access.get_client(&storage, client_id).await?;
+
+    if query.is_dry_run() {
+        let guid = ClientEntity::build_guid(client_id).to_string();
+        let plan = Plan::new("clients:delete", vec![guid]);
+        return Ok(dry_run::respond(plan, &current_user));
+    }
 
     log::debug!("deleting client");
     storage.delete_client(&client).await?;
+    stats_cache.invalidate(client_id);
     log::debug!("client deleted");
 
-    Ok(Json(ClientResponse::from(client)))
+    Ok(HttpResponse::Ok().json(ClientResponse::from(client)))
+}
+
+#[cfg(test)]
+mod test {
+    use couchdb::Couch;
+    use url::Url;
+
+    use super::*;
+
+    fn enforcer() -> Enforcer {
+        // Never queried, the rules are not loaded
+        let couch = Couch::new(
+            Url::parse("http://localhost:5984").unwrap(),
+            "admin".to_string(),
+            "admin".to_string(),
+        );
+        Enforcer::new(Arc::new(couch.database("rbac", true)))
+    }
+
+    fn resolve(username: &str, scope: &str, admin_scope: &str) -> ApiResult<ClientAccess> {
+        let user = User::new(username.to_string(), "password".to_string()).unwrap();
+        ClientAccess::resolve(
+            &OAuthScope::from(scope),
+            admin_scope,
+            &enforcer(),
+            &user,
+            &Guid::simple("clients")?,
+            "update",
+        )
+    }
+
+    fn client(owner: Option<&str>) -> Client {
+        let mut client = Client::public("test".to_string(), OAuthScope::from("*"), HashSet::new());
+        client.set_owner(owner.map(str::to_string));
+        client
+    }
+
+    #[test]
+    fn it_grants_every_client_to_admins() {
+        let access = resolve("root", "clients:manage", "clients:manage").unwrap();
+        assert_eq!(access, ClientAccess::All);
+        assert!(access.allows(&client(None)));
+    }
+
+    #[test]
+    fn it_restricts_owners_to_their_own_clients() {
+        let access = resolve("jdoe", "clients:manage:own", "clients:manage").unwrap();
+        assert_eq!(access, ClientAccess::Own("user:jdoe".to_string()));
+        assert!(access.allows(&client(Some("user:jdoe"))));
+        assert!(!access.allows(&client(Some("user:other"))));
+        assert!(!access.allows(&client(None)));
+
+        // The admin scope grants the owner one, even without the admin permission
+        let access = resolve("jdoe", "clients:manage", "clients:manage").unwrap();
+        assert_eq!(access, ClientAccess::Own("user:jdoe".to_string()));
+    }
+
+    #[test]
+    fn it_restricts_the_scopes_owners_can_allow() {
+        let check = |access: &ClientAccess, granted: &str, allowed: &str| {
+            access.check_allowed_scopes(&OAuthScope::from(granted), &OAuthScope::from(allowed))
+        };
+        let own = ClientAccess::Own("user:jdoe".to_string());
+        let granted = "clients:manage:own profile packages";
+        assert!(check(&own, granted, "profile packages:read").is_ok());
+
+        let err = check(&own, granted, "profile users:manage").unwrap_err();
+        assert_eq!(
+            err,
+            ApiError::Forbidden(
+                "allowed_scopes cannot include scopes not granted to your token: users:manage"
+                    .to_string()
+            )
+        );
+        assert!(check(&own, granted, "*").is_err());
+
+        // Even when their token is granted them
+        for allowed in &["tokens:introspect", "tokens", "*"] {
+            let err = check(&own, "*", allowed).unwrap_err();
+            assert_eq!(
+                err,
+                ApiError::Forbidden(format!(
+                    "allowed_scopes cannot include scopes reserved to admins: {}",
+                    allowed
+                ))
+            );
+        }
+
+        assert!(check(&ClientAccess::All, "clients:manage", "tokens:introspect *").is_ok());
+    }
+
+    #[test]
+    fn it_only_lets_admins_mark_resource_servers() {
+        assert!(ClientAccess::All.check_resource_server().is_ok());
+        let own = ClientAccess::Own("user:jdoe".to_string());
+        assert!(own.check_resource_server().is_err());
+    }
+
+    #[test]
+    fn it_rejects_tokens_without_either_scope() {
+        let err = resolve("root", "profile clients:read", "clients:manage").unwrap_err();
+        assert_eq!(err, ApiError::BadRequest("invalid scope".to_string()));
+    }
 }
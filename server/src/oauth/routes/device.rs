@@ -0,0 +1,376 @@
+//! Verification page of the device authorization grant, where users enter the user code
+//! shown by a device and approve or deny it.
+use std::sync::Arc;
+
+use actix_session::Session as HttpSession;
+use actix_web::web::{Data, Form, Query};
+use actix_web::{get, post};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::Utc;
+use http::StatusCode;
+use serde::Deserialize;
+
+use crate::announcement::Banner;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::http::client_addr::ClientAddr;
+use crate::http::error::ApiError;
+use crate::http::urls::UrlBuilder;
+use crate::oauth::audit::{self, AuditAction, AuditEvent};
+use crate::oauth::device::{AuthMethod, Device};
+use crate::oauth::facade::Oauth;
+use crate::oauth::session::Session;
+use crate::oauth::throttle::LoginThrottle;
+use crate::oauth::user_agent::UserAgent;
+use crate::templates::oauth::{DeviceConsent, DeviceDone, DeviceForm};
+use crate::user::{User, UserService};
+
+use super::oauth::{restrict_to_password_change, retry_after_secs, session_auth_time, AUTH_TIME};
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceQuery {
+    #[serde(default)]
+    user_code: String,
+}
+
+/// Asks for the user code, prefilled when following the complete verification URI
+#[get("/device")]
+pub async fn device_form(
+    query: Query<DeviceQuery>,
+    users: Data<UserService>,
+    http_session: HttpSession,
+    urls: UrlBuilder,
+    banner: Banner,
+) -> Result<HttpResponse, ApiError> {
+    let signed_in = session_user(&users, &http_session).await?.is_some();
+    Ok(device_page(&urls, &query.user_code, signed_in, None, banner))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceFormBody {
+    user_code: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+    /// Code of an authenticator app, for users with two-factor authentication
+    #[serde(default)]
+    otp: String,
+}
+
+/// Signs the user in, unless they already are, and asks them to approve the device
+#[post("/device")]
+pub async fn device(
+    oauth: Data<Arc<dyn Oauth>>,
+    users: Data<UserService>,
+    throttle: Data<LoginThrottle>,
+    form: Form<DeviceFormBody>,
+    http_session: HttpSession,
+    urls: UrlBuilder,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    let signed_in = session_user(&users, &http_session).await?;
+    let authorization = match oauth.device_authorization(&form.user_code).await {
+        Ok(authorization) => authorization,
+        Err(err) => {
+            log::warn!("{}", err);
+            let page = device_page(
+                &urls,
+                &form.user_code,
+                signed_in.is_some(),
+                Some("The code is invalid or expired.".to_string()),
+                Banner::current(&req),
+            );
+            return Ok(page);
+        }
+    };
+
+    if signed_in.is_none() {
+        let client_addr = ClientAddr::from(&req);
+        if let Some(retry_after) = throttle.check(client_addr.ip(), &form.username).await {
+            log::warn!(
+                "Rejecting login of '{}' from {:?} after too many failures",
+                &form.username,
+                client_addr.ip()
+            );
+            let reason = "too many failed login attempts".to_string();
+            let event = AuditEvent::failure(AuditAction::Login, reason.clone())
+                .set_client_id(Some(authorization.client_id().to_string()))
+                .set_user_id(Some(form.username).filter(|username| !username.is_empty()));
+            audit::record(&req, event);
+            return Err(ApiError::TooManyRequests(reason, retry_after_secs(retry_after)));
+        }
+
+        let user = match users
+            .authenticate_user(&form.username, &form.password)
+            .await
+        {
+            Ok(user) => user,
+            Err(err) if err.status() == StatusCode::FORBIDDEN => {
+                throttle.reset(client_addr.ip(), &form.username).await;
+                let event = AuditEvent::failure(AuditAction::Login, err.to_string())
+                    .set_client_id(Some(authorization.client_id().to_string()))
+                    .set_user_id(Some(form.username));
+                audit::record(&req, event);
+                let page = device_page(
+                    &urls,
+                    &form.user_code,
+                    false,
+                    Some("This account is disabled.".to_string()),
+                    Banner::current(&req),
+                );
+                return Ok(page);
+            }
+            Err(err) if err.status() == StatusCode::LOCKED => {
+                let event = AuditEvent::failure(AuditAction::Login, err.to_string())
+                    .set_client_id(Some(authorization.client_id().to_string()))
+                    .set_user_id(Some(form.username));
+                audit::record(&req, event);
+                let page = device_page(
+                    &urls,
+                    &form.user_code,
+                    false,
+                    Some("This account is temporarily locked, try again later.".to_string()),
+                    Banner::current(&req),
+                );
+                return Ok(page);
+            }
+            Err(_) => {
+                log::warn!("Authentication failed from {:?}", client_addr.ip());
+                throttle
+                    .record_failure(client_addr.ip(), &form.username)
+                    .await;
+                let event =
+                    AuditEvent::failure(AuditAction::Login, "authentication failed".to_string())
+                        .set_client_id(Some(authorization.client_id().to_string()))
+                        .set_user_id(Some(form.username).filter(|username| !username.is_empty()));
+                audit::record(&req, event);
+                let page = device_page(
+                    &urls,
+                    &form.user_code,
+                    false,
+                    Some("Authentication failed.".to_string()),
+                    Banner::current(&req),
+                );
+                return Ok(page);
+            }
+        };
+
+        if user.requires_second_factor()
+            && users
+                .verify_second_factor(user.username(), &form.otp)
+                .await?
+                .is_none()
+        {
+            log::warn!("Wrong second factor from {:?}", client_addr.ip());
+            throttle
+                .record_failure(client_addr.ip(), &form.username)
+                .await;
+            let event = AuditEvent::failure(AuditAction::Login, "code is incorrect".to_string())
+                .set_client_id(Some(authorization.client_id().to_string()))
+                .set_user_id(Some(user.id().to_string()));
+            audit::record(&req, event);
+            let page = device_page(
+                &urls,
+                &form.user_code,
+                false,
+                Some("The authentication code is missing or incorrect.".to_string()),
+                Banner::current(&req),
+            );
+            return Ok(page);
+        }
+
+        throttle.reset(client_addr.ip(), &form.username).await;
+        let event = AuditEvent::success(AuditAction::Login)
+            .set_client_id(Some(authorization.client_id().to_string()))
+            .set_user_id(Some(user.id().to_string()));
+        audit::record(&req, event);
+        http_session.set("user_id", user.id().id())?;
+        http_session.set(AUTH_TIME, Utc::now().timestamp())?;
+    }
+
+    let page = DeviceConsent {
+        action: urls.oauth("device/confirm").to_string(),
+        user_code: authorization.user_code().to_string(),
+        client_id: authorization.client_id().to_string(),
+        scope: authorization.scope().to_string(),
+        announcement: Banner::current(&req).into_inner(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(page.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Approve,
+    Deny,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceConsentBody {
+    user_code: String,
+    decision: Decision,
+}
+
+/// Approves or denies the device, for the user signed in by [`device`]
+#[post("/device/confirm")]
+pub async fn confirm_device(
+    oauth: Data<Arc<dyn Oauth>>,
+    users: Data<UserService>,
+    form: Form<DeviceConsentBody>,
+    http_session: HttpSession,
+    urls: UrlBuilder,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    let user = match session_user(&users, &http_session).await? {
+        Some(user) => user,
+        None => {
+            let page = device_page(&urls, &form.user_code, false, None, Banner::current(&req));
+            return Ok(page);
+        }
+    };
+    let authorization = match oauth.device_authorization(&form.user_code).await {
+        Ok(authorization) => authorization,
+        Err(err) => {
+            log::warn!("{}", err);
+            let page = device_page(
+                &urls,
+                &form.user_code,
+                true,
+                Some("The code is invalid or expired.".to_string()),
+                Banner::current(&req),
+            );
+            return Ok(page);
+        }
+    };
+
+    let user_id = user.id().to_string();
+    let session = match form.decision {
+        Decision::Approve => {
+            let approving_device =
+                Device::new(UserAgent::from(&req), AuthMethod::SessionCookie);
+            let mut session = Session::for_client(authorization.client_id().to_string());
+            session
+                .set_user_id(user_id.clone())
+                .set_device(approving_device)
+                .set_auth_time(session_auth_time(&http_session)?);
+            restrict_to_password_change(&mut session, &user);
+            Some(session)
+        }
+        Decision::Deny => None,
+    };
+    let approved = session.is_some();
+    let res = oauth
+        .decide_device_authorization(&form.user_code, session)
+        .await;
+    let event = match (&res, approved) {
+        (Ok(()), true) => AuditEvent::success(AuditAction::DeviceApproval),
+        (Ok(()), false) => {
+            AuditEvent::failure(AuditAction::DeviceApproval, "denied by the user".to_string())
+        }
+        (Err(err), _) => AuditEvent::failure(AuditAction::DeviceApproval, err.to_string()),
+    };
+    audit::record(
+        &req,
+        event
+            .set_client_id(Some(authorization.client_id().to_string()))
+            .set_user_id(Some(user_id)),
+    );
+    res.map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+    let page = DeviceDone {
+        approved,
+        announcement: Banner::current(&req).into_inner(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(page.to_string()))
+}
+
+/// User of the browser session, if any and the session predates no password change
+async fn session_user(
+    users: &UserService,
+    http_session: &HttpSession,
+) -> Result<Option<User>, ApiError> {
+    let username = match http_session.get::<String>("user_id")? {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    let auth_time = session_auth_time(http_session)?;
+    let user = users
+        .find(&username)
+        .await?
+        .filter(|user| user.accepts_session(auth_time.as_ref()));
+    Ok(user)
+}
+
+fn device_page(
+    urls: &UrlBuilder,
+    user_code: &str,
+    signed_in: bool,
+    error: Option<String>,
+    banner: Banner,
+) -> HttpResponse {
+    let page = DeviceForm {
+        action: urls.oauth("device").to_string(),
+        user_code: user_code.to_string(),
+        signed_in,
+        error: error.clone(),
+        announcement: banner.into_inner(),
+    };
+    let mut res = if error.is_some() {
+        HttpResponse::BadRequest()
+    } else {
+        HttpResponse::Ok()
+    };
+    res.content_type("text/html; charset=utf-8")
+        .body(page.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use actix_session::CookieSession;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use couchdb::Couch;
+    use url::Url;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn it_prefills_the_user_code_and_asks_for_credentials() {
+        // Never queried without a session cookie
+        let couch = Couch::new(
+            Url::parse("http://localhost:5984").unwrap(),
+            "admin".to_string(),
+            "admin".to_string(),
+        );
+        let users = UserService::new(couch.database("users", true));
+        let public_host = Url::parse("https://enseada.example.com").unwrap();
+        let urls = UrlBuilder::new(&public_host, None).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(CookieSession::private(&[0; 32]).secure(false))
+                .data(users)
+                .data(urls)
+                .service(device_form),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/device?user_code=BCDF-GHJK")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"value="BCDF-GHJK""#));
+        assert!(body.contains(r#"name="password""#));
+        assert!(body.contains(
+            r#"action="https:&#x2f;&#x2f;enseada.example.com&#x2f;oauth&#x2f;device""#
+        ));
+    }
+}
@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use actix_web::web::Data;
+use actix_web::{get, HttpResponse};
+use tokio::sync::RwLock;
+
+use enseada::guid::Guid;
+
+use crate::couchdb::repository::Entity;
+use crate::http::extractor::{scope::Scope, user::CurrentUser};
+use crate::http::ApiResult;
+use crate::oauth::keys::SigningKeys;
+use crate::rbac::Enforcer;
+
+/// Trust bundle of the current signing keys, for resource servers validating tokens offline
+#[get("/api/v1beta1/admin/trust-bundle")]
+pub async fn trust_bundle(
+    keys: Data<Arc<SigningKeys>>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+) -> ApiResult<HttpResponse> {
+    Scope::from("system:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("trust_bundle")?, "read")?;
+
+    let bundle = keys.export_bundle().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(bundle))
+}
@@ -1,43 +1,165 @@
 use std::sync::Arc;
 
-use actix_web::web;
 use actix_web::web::ServiceConfig;
-use actix_web::FromRequest;
 
+use crate::audit::AuditService;
+use crate::config::CONFIG;
+use crate::events::DeliveryLog;
+use crate::http::client_addr::ClientAddrResolver;
+use crate::http::extractor::session::RequiredAudience;
+use crate::oauth::anomaly::AnomalyReporter;
+use crate::oauth::assertion::ClientAssertions;
+use crate::oauth::binding::AddressResolver;
+use crate::oauth::device::DeviceTracker;
+use crate::oauth::events::LogListener;
+use crate::oauth::facade::Oauth;
 use crate::oauth::handler::OAuthHandler;
+use crate::oauth::issuance::{IssuanceLimits, IssuanceMonitor};
+use crate::oauth::keys::SigningKeys;
+use crate::oauth::last_used::{self, LastUsedTracker};
+use crate::oauth::metadata::Metadata;
+use crate::oauth::new_device::NewDeviceNotifier;
+use crate::oauth::persistence::cache::CLIENT_CACHE;
 use crate::oauth::persistence::CouchStorage;
-use crate::oauth::request::{AuthorizationRequest, TokenRequest};
+use crate::oauth::scopes;
+use crate::oauth::sso::{OidcProvider, PendingLogins};
+use crate::oauth::stats::ClientStatsCache;
+use crate::oauth::token_events::TokenEventWebhook;
+use crate::user::UserService;
 
 mod api;
+mod device;
+mod keys;
 mod oauth;
+mod sso;
+
+lazy_static! {
+    /// Shared by the handlers of every worker, so that issuance is counted once per instance
+    static ref ISSUANCE_MONITOR: Arc<IssuanceMonitor> = Arc::new(issuance_monitor());
+    /// Shared by the handlers of every worker, so that a replayed assertion is rejected
+    /// whichever worker receives it
+    static ref CLIENT_ASSERTIONS: Arc<ClientAssertions> =
+        Arc::new(ClientAssertions::new(CONFIG.urls().oauth("token")));
+    /// Shared by the handlers of every worker, so that the key set is loaded once per instance
+    static ref SIGNING_KEYS: Arc<SigningKeys> = Arc::new(SigningKeys::from_config());
+}
 
 pub fn mount(cfg: &mut ServiceConfig) {
     let couch = &crate::couchdb::SINGLETON;
     let db = Arc::new(couch.database(crate::couchdb::name::OAUTH, true));
-    let storage = Arc::new(CouchStorage::new(db.clone()));
-    let handler = OAuthHandler::new(storage.clone(), storage.clone(), storage.clone(), storage);
+    let storage = Arc::new(CouchStorage::with_client_cache(
+        db.clone(),
+        CLIENT_CACHE.clone(),
+    ));
+    let mut handler = OAuthHandler::new(
+        storage.clone(),
+        storage.clone(),
+        storage.clone(),
+        storage.clone(),
+        CONFIG.secret_key(),
+    );
+    handler.set_issuance_monitor(ISSUANCE_MONITOR.clone());
+    let registry = scopes::registry();
+    handler.set_scope_registry(Arc::new(registry.clone()));
+    handler.set_token_signer(SIGNING_KEYS.clone());
+    handler.set_resource_registry(Arc::new(CONFIG.resource_registry()));
+    handler.set_pushed_request_storage(storage.clone());
+    handler.set_device_authorization_storage(storage.clone(), CONFIG.urls().oauth("device"));
+    handler.set_client_assertions(CLIENT_ASSERTIONS.clone());
+    let mut audit = AuditService::new(couch.database(crate::couchdb::name::AUDIT, true));
+    audit.set_size_guard(crate::couchdb::SIZE_GUARD.clone());
+    let mut devices = DeviceTracker::new(storage.clone());
+    devices.add_listener(Arc::new(NewDeviceNotifier::new(
+        audit,
+        UserService::new(couch.database(crate::couchdb::name::USERS, true)),
+        crate::mail::from_config(CONFIG.mail()),
+    )));
+    handler.set_device_tracker(Arc::new(devices));
+    let events = CONFIG.oauth().events();
+    if events.log() {
+        handler.add_token_event_listener(Arc::new(LogListener));
+    }
+    if let Some(webhook) = events.webhook() {
+        let mut webhook = TokenEventWebhook::new(webhook.clone(), events.retries());
+        webhook.set_delivery_log(delivery_log());
+        handler.add_token_event_listener(Arc::new(webhook));
+    }
+    let bootstrap = CONFIG.oauth().bootstrap().enabled();
+    if bootstrap {
+        handler.set_bootstrap_storage(storage.clone());
+    }
+    let handler: Arc<dyn Oauth> = Arc::new(handler);
 
-    cfg.data(CouchStorage::new(db.clone()));
+    // Shares the client cache with the handler, so that client changes invalidate it
+    cfg.data(CouchStorage::clone(&storage));
+    cfg.data(LastUsedTracker::new(
+        CouchStorage::clone(&storage),
+        last_used::INTERVAL,
+    ));
     cfg.data(handler);
+    cfg.data(SIGNING_KEYS.clone());
+    cfg.data(ClientStatsCache::default());
+    cfg.data(CONFIG.error_docs());
+    cfg.data::<Arc<dyn AddressResolver>>(Arc::new(ClientAddrResolver));
+    if CONFIG.oauth().resources().enforce() {
+        cfg.data(RequiredAudience::new(CONFIG.public_host()));
+    }
+    cfg.data(Metadata::new(
+        CONFIG.public_host(),
+        &CONFIG.urls(),
+        &registry,
+        bootstrap,
+    ));
 
-    cfg.service(
-        web::scope("/oauth")
-            .app_data(web::Query::<AuthorizationRequest>::configure(
-                oauth::handle_query_errors,
-            ))
-            .app_data(web::Form::<TokenRequest>::configure(
-                oauth::handle_form_errors,
-            ))
-            .service(oauth::login_form)
-            .service(oauth::login)
-            .service(oauth::token)
-            .service(oauth::introspect)
-            .service(oauth::revoke),
-    );
+    let mut scope = enseada_oauth::routes::scope("/oauth")
+        .service(oauth::login_form)
+        .service(oauth::login)
+        .service(oauth::change_password)
+        .service(device::device_form)
+        .service(device::device)
+        .service(device::confirm_device);
+    if let Some(provider) = OidcProvider::from_config(CONFIG.sso()) {
+        cfg.data(provider);
+        cfg.data(PendingLogins::new(
+            couch.database(crate::couchdb::name::OAUTH, true),
+            CONFIG.secret_key(),
+        ));
+        scope = scope.service(sso::start).service(sso::callback);
+    }
+    if bootstrap {
+        cfg.service(scope.service(enseada_oauth::routes::bootstrap));
+    } else {
+        cfg.service(scope);
+    }
+
+    cfg.service(oauth::error_doc);
+    cfg.service(oauth::metadata);
 
     cfg.service(api::list_clients);
     cfg.service(api::create_client);
     cfg.service(api::get_client);
     cfg.service(api::update_client);
+    cfg.service(api::validate_client);
     cfg.service(api::delete_client);
+    cfg.service(keys::trust_bundle);
+}
+
+fn issuance_monitor() -> IssuanceMonitor {
+    let issuance = CONFIG.oauth().issuance();
+    let mut monitor = IssuanceMonitor::new(IssuanceLimits {
+        threshold: issuance.threshold(),
+        window: issuance.window(),
+        refuse: issuance.refuse(),
+    });
+    let mut reporter = AnomalyReporter::new(issuance.webhook().cloned());
+    reporter.set_delivery_log(delivery_log());
+    monitor.add_listener(Arc::new(reporter));
+    monitor
+}
+
+fn delivery_log() -> Arc<DeliveryLog> {
+    let db = crate::couchdb::SINGLETON.database(crate::couchdb::name::SYSTEM, true);
+    let mut deliveries = DeliveryLog::new(db);
+    deliveries.set_size_guard(crate::couchdb::SIZE_GUARD.clone());
+    Arc::new(deliveries)
 }
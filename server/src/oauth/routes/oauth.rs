@@ -1,44 +1,82 @@
-use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_session::Session as HttpSession;
-use actix_web::error::{Error, InternalError, QueryPayloadError, UrlencodedError};
-use actix_web::http::header;
-use actix_web::web::{Data, Form, Json, Query};
-use actix_web::web::{FormConfig, QueryConfig};
-use actix_web::{get, post, FromRequest};
+use actix_web::error::{Error, QueryPayloadError};
+use actix_web::web::{Data, Form, Path, Query};
+use actix_web::{get, post};
 use actix_web::{HttpRequest, HttpResponse};
-use actix_web_httpauth::headers::authorization::{Basic, ParseError, Scheme};
+use chrono::{DateTime, TimeZone, Utc};
+use http::StatusCode;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use url::Url;
 
+use enseada_oauth::routes::{basic_auth, document};
+
+use crate::announcement::Banner;
 use crate::couchdb::repository::{Entity, Repository};
+use crate::http::client_addr::ClientAddr;
 use crate::http::error::ApiError;
+use crate::http::urls::UrlBuilder;
+use crate::oauth::audit::{self, AuditAction, AuditEvent};
+use crate::oauth::client::Client;
+use crate::oauth::device::{AuthMethod, Device};
 use crate::oauth::error::{Error as OAuthError, ErrorKind};
-use crate::oauth::handler::{BasicAuth, RequestHandler};
-use crate::oauth::request::{
-    AuthorizationRequest, IntrospectionRequest, RevocationRequest, TokenRequest,
-};
-use crate::oauth::response::{IntrospectionResponse, RevocationResponse, TokenResponse};
+use crate::oauth::facade::Oauth;
+use crate::oauth::metadata::Metadata;
+use crate::oauth::request::{AuthorizationRequest, PushedRequestReference, ResponseMode};
+use crate::oauth::resource;
+use crate::oauth::response::{self, AuthorizationErrorResponse};
 use crate::oauth::session::Session;
-use crate::oauth::ConcreteOAuthHandler;
+use crate::oauth::sso::OidcProvider;
+use crate::oauth::throttle::LoginThrottle;
+use crate::oauth::user_agent::UserAgent;
 use crate::responses;
-use crate::templates::oauth::LoginForm;
-use crate::user::UserService;
+use crate::oauth::scope::Scope;
+use crate::templates::oauth::{
+    ErrorDoc, ErrorPage, FormField, FormPost, LoginForm, PasswordChangeForm, SsoLink,
+};
+use crate::user::{password, User, UserService};
+
+pub(super) const AUTH_TIME: &str = "auth_time";
+/// User who entered their password and is asked for the code of their authenticator app
+const SECOND_FACTOR_USER: &str = "second_factor_user";
+/// When the user entered their password, as a UNIX timestamp
+const SECOND_FACTOR_SINCE: &str = "second_factor_since";
+/// How long the user has to enter the code after their password
+const SECOND_FACTOR_TIMEOUT_SECS: i64 = 300;
+/// User who logged in with a temporary password and is asked to choose their own
+const PASSWORD_CHANGE_USER: &str = "password_change_user";
+/// When the user logged in with their temporary password, as a UNIX timestamp
+const PASSWORD_CHANGE_SINCE: &str = "password_change_since";
+/// How long the user has to choose their password after logging in
+const PASSWORD_CHANGE_TIMEOUT_SECS: i64 = 600;
+/// Only scope granted to users who must change their password, enough to change it
+const PASSWORD_CHANGE_SCOPE: &str = "profile";
 
 #[get("/authorize")]
 pub async fn login_form(
-    handler: Data<ConcreteOAuthHandler>,
+    oauth: Data<Arc<dyn Oauth>>,
     users: Data<UserService>,
-    query: Query<AuthorizationRequest>,
+    throttle: Data<LoginThrottle>,
     http_session: HttpSession,
+    urls: UrlBuilder,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
-    let client_auth = get_basic_auth(&req);
+    let client_auth = basic_auth(&req);
     let client_auth = client_auth.as_ref();
-    let auth = query.into_inner();
-    if let Err(err) = handler.validate(&auth, client_auth).await {
-        log::error!("{}", err);
-    }
+    let auth = match authorization_request(oauth.get_ref().as_ref(), &req).await {
+        Ok(auth) => auth,
+        Err(res) => return Ok(res),
+    };
+    let client = match oauth.validate(&auth, client_auth).await {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("{}", err);
+            return Ok(rejection(oauth.get_ref().as_ref(), &req, &auth, err).await);
+        }
+    };
 
     log::debug!(
         "Reading user session from cookie {:?}",
@@ -46,234 +84,1225 @@ pub async fn login_form(
     );
 
     if let Some(username) = http_session.get::<String>("user_id")? {
-        if let Some(_user) = users.find(&username).await? {
-            return do_login(
-                handler,
-                users,
-                Form(LoginFormBody {
-                    auth_request: auth,
-                    username: String::from(""),
-                    password: String::from(""),
-                }),
-                http_session,
-                req,
-            )
-            .await;
+        let auth_time = session_auth_time(&http_session)?;
+        if !auth.accepts_auth_time(auth_time, Utc::now()) {
+            log::debug!(
+                "Session of user {} is older than max_age or prompt=login, asking to log in again",
+                username
+            );
+        } else {
+            match users.find(&username).await? {
+                Some(user) if user.accepts_session(auth_time.as_ref()) => {
+                    return do_login(
+                        oauth,
+                        users,
+                        throttle,
+                        Form(LoginFormBody {
+                            auth_request: auth,
+                            username: String::from(""),
+                            password: String::from(""),
+                            otp: None,
+                        }),
+                        http_session,
+                        urls,
+                        req,
+                    )
+                    .await;
+                }
+                Some(_) => {
+                    log::info!(
+                        "Session of user {} predates their password change, asking to log in again",
+                        username
+                    );
+                    http_session.remove("user_id");
+                }
+                None => {
+                    log::warn!(
+                        "User {} from session cookie cannot be found in database",
+                        username
+                    );
+                    http_session.remove("user_id");
+                }
+            }
         }
+    }
 
-        log::warn!(
-            "User {} from session cookie cannot be found in database",
-            username
+    if auth.is_silent() {
+        log::debug!("Silent authorization request without a user session");
+        let err = OAuthError::new(
+            ErrorKind::LoginRequired,
+            "the user is not signed in".to_string(),
         );
-        http_session.remove("user_id");
+        return Ok(error_response(&req, &auth, Some(&client), err));
     }
 
+    Ok(login_page(&req, &urls, &auth, None))
+}
+
+/// Login page for the authorization request, telling why the last attempt failed if needed
+pub(super) fn login_page(
+    req: &HttpRequest,
+    urls: &UrlBuilder,
+    auth: &AuthorizationRequest,
+    error: Option<String>,
+) -> HttpResponse {
+    login_step(req, urls, auth, false, error)
+}
+
+/// Second step of the login of users with two-factor authentication, asking for a code
+fn otp_page(
+    req: &HttpRequest,
+    urls: &UrlBuilder,
+    auth: &AuthorizationRequest,
+    error: Option<String>,
+) -> HttpResponse {
+    login_step(req, urls, auth, true, error)
+}
+
+/// Asks the user who logged in with a temporary password to choose their own
+fn password_change_page(
+    req: &HttpRequest,
+    urls: &UrlBuilder,
+    auth: &AuthorizationRequest,
+    error: Option<String>,
+) -> HttpResponse {
+    let form = PasswordChangeForm {
+        action: urls.oauth("password").to_string(),
+        response_type: auth.response_type.to_string(),
+        response_mode: auth.response_mode.clone().unwrap_or_default(),
+        client_id: auth.client_id.clone(),
+        redirect_uri: auth.redirect_uri.clone(),
+        scope: auth.scope.to_string(),
+        state: auth.state.clone().unwrap_or_default(),
+        nonce: auth.nonce.clone().unwrap_or_default(),
+        prompt: auth.prompt.clone().unwrap_or_default(),
+        max_age: auth.max_age.map(|max_age| max_age.to_string()).unwrap_or_default(),
+        resource: auth.resource.join(" "),
+        error,
+        announcement: Banner::current(req).into_inner(),
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(form.to_string())
+}
+
+fn login_step(
+    req: &HttpRequest,
+    urls: &UrlBuilder,
+    auth: &AuthorizationRequest,
+    otp: bool,
+    error: Option<String>,
+) -> HttpResponse {
     let form = LoginForm {
+        action: urls.oauth("authorize").to_string(),
         response_type: auth.response_type.to_string(),
+        response_mode: auth.response_mode.clone().unwrap_or_default(),
         client_id: auth.client_id.clone(),
         redirect_uri: auth.redirect_uri.clone(),
         scope: auth.scope.to_string(),
         state: auth.state.as_ref().unwrap_or(&"".to_string()).clone(),
+        nonce: auth.nonce.clone().unwrap_or_default(),
+        prompt: auth.prompt.clone().unwrap_or_default(),
+        max_age: auth.max_age.map(|max_age| max_age.to_string()).unwrap_or_default(),
+        resource: auth.resource.join(" "),
+        otp,
+        error,
+        sso: req.app_data::<Data<OidcProvider>>().map(|provider| SsoLink {
+            action: urls.oauth("sso").to_string(),
+            name: provider.name().to_string(),
+        }),
+        announcement: Banner::current(req).into_inner(),
     };
 
-    Ok(HttpResponse::Ok()
+    HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(form.to_string()))
+        .body(form.to_string())
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoginFormBody {
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    /// Code of the second step, for the user who entered their password in the first one
+    pub otp: Option<String>,
     #[serde(flatten)]
     pub auth_request: AuthorizationRequest,
 }
 
 #[post("/authorize")]
 pub async fn login(
-    handler: Data<ConcreteOAuthHandler>,
+    oauth: Data<Arc<dyn Oauth>>,
     users: Data<UserService>,
+    throttle: Data<LoginThrottle>,
     form: Form<LoginFormBody>,
     http_session: HttpSession,
+    urls: UrlBuilder,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
-    do_login(handler, users, form, http_session, req).await
+    do_login(oauth, users, throttle, form, http_session, urls, req).await
 }
 
 async fn do_login(
-    handler: Data<ConcreteOAuthHandler>,
+    oauth: Data<Arc<dyn Oauth>>,
     users: Data<UserService>,
+    throttle: Data<LoginThrottle>,
     form: Form<LoginFormBody>,
     http_session: HttpSession,
+    urls: UrlBuilder,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
-    let client_auth = get_basic_auth(&req);
+    let client_addr = ClientAddr::from(&req);
+    let client_auth = basic_auth(&req);
     let client_auth = client_auth.as_ref();
-    let form = form.into_inner();
+    let mut form = form.into_inner();
     let auth = form.auth_request;
-    let redirect_uri = auth.redirect_uri.clone();
-    let mut url = Url::parse(&redirect_uri)?;
 
-    let validate = handler.validate(&auth, client_auth).await;
+    let validate = oauth.validate(&auth, client_auth).await;
     let client = match validate {
         Ok(client) => client,
-        Err(err) => return Ok(redirect_to_client(&mut url, err)),
+        Err(err) => return Ok(rejection(oauth.get_ref().as_ref(), &req, &auth, err).await),
+    };
+    let url = match Url::parse(&auth.redirect_uri) {
+        Ok(url) => url,
+        Err(err) => {
+            let err = OAuthError::new(ErrorKind::InvalidRedirectUri, err.to_string());
+            return Ok(error_page(&req, &document(&req, err), Some(&client)));
+        }
     };
 
-    let user = match http_session.get::<String>("user_id")? {
-        Some(username) => users.find(&username).await?,
-        None => users
-            .authenticate_user(&form.username, &form.password)
-            .await
-            .ok(),
+    // A session too old for max_age, refused by prompt=login or older than the password
+    // is ignored, and the user must log in with a password again
+    let session_auth_time = session_auth_time(&http_session)?;
+    let session_user = match http_session
+        .get::<String>("user_id")?
+        .filter(|_| auth.accepts_auth_time(session_auth_time, Utc::now()))
+    {
+        Some(username) => users
+            .find(&username)
+            .await?
+            .filter(|user| user.accepts_session(session_auth_time.as_ref())),
+        None => None,
+    };
+    let (user, auth_method, auth_time) = match session_user {
+        Some(user) => (Some(user), AuthMethod::SessionCookie, session_auth_time),
+        None => {
+            if form.otp.is_some() {
+                // The code is for the user who entered their password in the first step
+                form.username = match pending_second_factor(&http_session)? {
+                    Some(username) => username,
+                    None => {
+                        clear_second_factor(&http_session);
+                        let message = "Your login timed out, please log in again.";
+                        return Ok(login_page(&req, &urls, &auth, Some(message.to_string())));
+                    }
+                };
+            }
+            if let Some(retry_after) = throttle.check(client_addr.ip(), &form.username).await {
+                log::warn!(
+                    "Rejecting login of '{}' from {:?} after too many failures",
+                    &form.username,
+                    client_addr.ip()
+                );
+                let reason = "too many failed login attempts".to_string();
+                let event = AuditEvent::failure(AuditAction::Login, reason.clone())
+                    .set_client_id(Some(client.client_id().to_string()))
+                    .set_user_id(Some(form.username).filter(|username| !username.is_empty()));
+                audit::record(&req, event);
+                return Err(ApiError::TooManyRequests(
+                    reason,
+                    retry_after_secs(retry_after),
+                ));
+            }
+            let user = if let Some(code) = &form.otp {
+                match users.verify_second_factor(&form.username, code).await? {
+                    Some(user) => {
+                        clear_second_factor(&http_session);
+                        Some(user)
+                    }
+                    None => {
+                        log::warn!("Wrong second factor from {:?}", client_addr.ip());
+                        throttle
+                            .record_failure(client_addr.ip(), &form.username)
+                            .await;
+                        let reason = "code is incorrect".to_string();
+                        let event = AuditEvent::failure(AuditAction::Login, reason)
+                            .set_client_id(Some(client.client_id().to_string()))
+                            .set_user_id(Some(form.username));
+                        audit::record(&req, event);
+                        let message = "The code is incorrect.".to_string();
+                        return Ok(otp_page(&req, &urls, &auth, Some(message)));
+                    }
+                }
+            } else {
+                match users
+                    .authenticate_user(&form.username, &form.password)
+                    .await
+                {
+                    Ok(user) if user.requires_second_factor() => {
+                        log::debug!("Asking user {} for their second factor", user.username());
+                        http_session.set(SECOND_FACTOR_USER, user.username())?;
+                        http_session.set(SECOND_FACTOR_SINCE, Utc::now().timestamp())?;
+                        return Ok(otp_page(&req, &urls, &auth, None));
+                    }
+                    Ok(user) => Some(user),
+                    // The password was right, so the client is told the user was denied access
+                    Err(err) if err.status() == StatusCode::FORBIDDEN => {
+                        throttle.reset(client_addr.ip(), &form.username).await;
+                        let reason = err.to_string();
+                        let event = AuditEvent::failure(AuditAction::Login, reason.clone())
+                            .set_client_id(Some(client.client_id().to_string()))
+                            .set_user_id(Some(form.username));
+                        audit::record(&req, event);
+                        let err = OAuthError::new(ErrorKind::AccessDenied, reason);
+                        return Ok(error_response(&req, &auth, Some(&client), err));
+                    }
+                    // Whether the password was right is not told, only that the user must wait
+                    Err(err) if err.status() == StatusCode::LOCKED => {
+                        let event = AuditEvent::failure(AuditAction::Login, err.to_string())
+                            .set_client_id(Some(client.client_id().to_string()))
+                            .set_user_id(Some(form.username));
+                        audit::record(&req, event);
+                        let message = "Your account is temporarily locked after too many failed logins. Try again later, or ask an administrator to unlock it.";
+                        return Ok(login_page(&req, &urls, &auth, Some(message.to_string())));
+                    }
+                    Err(_) => None,
+                }
+            };
+            (user, AuthMethod::Password, Some(Utc::now()))
+        }
     };
 
     let user = match user {
         Some(user) => user,
         None => {
-            log::debug!("Authentication failed");
+            log::warn!("Authentication failed from {:?}", client_addr.ip());
+            if auth_method == AuthMethod::Password {
+                throttle
+                    .record_failure(client_addr.ip(), &form.username)
+                    .await;
+            }
+            let event =
+                AuditEvent::failure(AuditAction::Login, "authentication failed".to_string())
+                    .set_client_id(Some(client.client_id().to_string()))
+                    .set_user_id(Some(form.username).filter(|username| !username.is_empty()));
+            audit::record(&req, event);
             return Err(ApiError::Unauthorized(String::from(
                 "authentication failed",
             )));
         }
     };
 
-    log::debug!("Authentication successful");
+    log::debug!("Authentication successful from {:?}", client_addr.ip());
 
+    let user_id = user.id();
+    if auth_method == AuthMethod::Password {
+        throttle.reset(client_addr.ip(), &form.username).await;
+        let event = AuditEvent::success(AuditAction::Login)
+            .set_client_id(Some(client.client_id().to_string()))
+            .set_user_id(Some(user_id.to_string()));
+        audit::record(&req, event);
+    } else if let Err(err) = users.record_login(user.username(), Utc::now()).await {
+        // Password logins are recorded when authenticating
+        log::warn!("Failed to record the login of user {}: {}", user.username(), err);
+    }
+    if user.must_change_password() {
+        log::debug!("Asking user {} to change their temporary password", user.username());
+        http_session.set(PASSWORD_CHANGE_USER, user.username())?;
+        http_session.set(PASSWORD_CHANGE_SINCE, Utc::now().timestamp())?;
+        return Ok(password_change_page(&req, &urls, &auth, None));
+    }
+    authorize_user(
+        oauth.get_ref().as_ref(),
+        &http_session,
+        &req,
+        &auth,
+        &client,
+        &url,
+        &user,
+        auth_method,
+        auth_time,
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordChangeFormBody {
+    #[serde(default)]
+    pub new_password: String,
+    #[serde(default)]
+    pub confirmation: String,
+    #[serde(flatten)]
+    pub auth_request: AuthorizationRequest,
+}
+
+/// Sets the password of the user who logged in with a temporary one,
+/// and completes the authorization request they logged in for
+#[post("/password")]
+pub async fn change_password(
+    oauth: Data<Arc<dyn Oauth>>,
+    users: Data<UserService>,
+    form: Form<PasswordChangeFormBody>,
+    http_session: HttpSession,
+    urls: UrlBuilder,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let client_auth = basic_auth(&req);
+    let form = form.into_inner();
+    let auth = form.auth_request;
+    let client = match oauth.validate(&auth, client_auth.as_ref()).await {
+        Ok(client) => client,
+        Err(err) => return Ok(rejection(oauth.get_ref().as_ref(), &req, &auth, err).await),
+    };
+    let url = match Url::parse(&auth.redirect_uri) {
+        Ok(url) => url,
+        Err(err) => {
+            let err = OAuthError::new(ErrorKind::InvalidRedirectUri, err.to_string());
+            return Ok(error_page(&req, &document(&req, err), Some(&client)));
+        }
+    };
+
+    let user = match pending_password_change(&http_session)? {
+        Some(username) => users.find(&username).await?,
+        None => None,
+    };
+    let mut user = match user.filter(User::must_change_password) {
+        Some(user) => user,
+        None => {
+            clear_password_change(&http_session);
+            let message = "Your login timed out, please log in again.";
+            return Ok(login_page(&req, &urls, &auth, Some(message.to_string())));
+        }
+    };
+
+    let error = if form.new_password != form.confirmation {
+        Some("The passwords do not match.".to_string())
+    } else if user.is_password(&form.new_password)? {
+        Some("Your new password must differ from the temporary one.".to_string())
+    } else {
+        let violations = password::violations(user.username(), &form.new_password);
+        if violations.is_empty() {
+            None
+        } else {
+            Some(format!("Your new password is too weak: {}.", violations.join(", ")))
+        }
+    };
+    if let Some(error) = error {
+        return Ok(password_change_page(&req, &urls, &auth, Some(error)));
+    }
+
+    user.set_password(&form.new_password)?;
+    // The browser session is checked against the new password right away
+    let user = users.save_tracked(user).await?;
+    clear_password_change(&http_session);
+    let event = AuditEvent::success(AuditAction::PasswordChange)
+        .set_client_id(Some(client.client_id().to_string()))
+        .set_user_id(Some(user.id().to_string()));
+    audit::record(&req, event);
+
+    authorize_user(
+        oauth.get_ref().as_ref(),
+        &http_session,
+        &req,
+        &auth,
+        &client,
+        &url,
+        &user,
+        AuthMethod::Password,
+        Some(Utc::now()),
+    )
+    .await
+}
+
+/// Signs the authenticated user in the browser session,
+/// and responds to the client with the outcome of its authorization request
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn authorize_user(
+    oauth: &dyn Oauth,
+    http_session: &HttpSession,
+    req: &HttpRequest,
+    auth: &AuthorizationRequest,
+    client: &Client,
+    url: &Url,
+    user: &User,
+    auth_method: AuthMethod,
+    auth_time: Option<DateTime<Utc>>,
+) -> Result<HttpResponse, ApiError> {
     let user_id = user.id();
     http_session.set("user_id", user_id.id())?;
+    if let Some(auth_time) = auth_time {
+        http_session.set(AUTH_TIME, auth_time.timestamp())?;
+    }
+    let device = Device::new(UserAgent::from(req), auth_method);
+
     let session = &mut Session::for_client(client.client_id().to_string());
-    session.set_user_id(user_id.to_string());
+    session
+        .set_user_id(user_id.to_string())
+        .set_device(device)
+        .set_auth_time(auth_time);
+    restrict_to_password_change(session, user);
 
-    let handle = handler.handle(&auth, session).await;
+    let handle = oauth.authorize(auth, session).await;
+    let event = match &handle {
+        Ok(_) => AuditEvent::success(AuditAction::CodeIssuance),
+        Err(err) => AuditEvent::failure(AuditAction::CodeIssuance, err.to_string()),
+    };
+    audit::record(
+        req,
+        event
+            .set_client_id(Some(client.client_id().to_string()))
+            .set_user_id(Some(user_id.to_string())),
+    );
     match handle {
-        Ok(res) => Ok(redirect_to_client(&mut url, res)),
-        Err(err) => match err.kind() {
-            ErrorKind::InvalidRedirectUri => Err(ApiError::BadRequest(err.to_string())),
-            _ => Ok(redirect_to_client(&mut url, err)),
-        },
+        Ok(res) => Ok(respond_to_client(auth, url, res)),
+        Err(err) => Ok(error_response(req, auth, Some(client), err)),
     }
 }
 
-#[post("/token")]
-pub async fn token(
-    handler: Data<ConcreteOAuthHandler>,
-    form: Form<TokenRequest>,
-    req: HttpRequest,
-) -> Result<Json<TokenResponse>, OAuthError> {
-    let client_auth = get_basic_auth(&req);
-    let client_auth = client_auth.as_ref();
-    let req = form.into_inner();
-    log::debug!("received token request");
+/// Reads the authorization request from the query, or loads it if the client pushed it beforehand.
+/// The redirect_uri cannot be verified without a valid request, so errors are shown to the user.
+async fn authorization_request(
+    oauth: &dyn Oauth,
+    req: &HttpRequest,
+) -> Result<AuthorizationRequest, HttpResponse> {
+    if let Ok(reference) = Query::<PushedRequestReference>::from_query(req.query_string()) {
+        return oauth.pushed_request(&reference).await.map_err(|err| {
+            log::error!("{}", err);
+            error_page(req, &document(req, err), None)
+        });
+    }
 
-    let client = handler.validate(&req, client_auth).await?;
-    let session = &mut Session::for_client(client.client_id().to_string());
-    let res = handler.handle(&req, session).await?;
-    Ok(Json(res))
+    Query::<AuthorizationRequest>::from_query(&resource::merge_query(req.query_string()))
+        .map(Query::into_inner)
+        .map_err(|QueryPayloadError::Deserialize(err)| {
+            log::error!("Error: {}", err);
+            let err = OAuthError::new(ErrorKind::InvalidRequest, err.to_string());
+            error_page(req, &document(req, err), None)
+        })
 }
 
-#[post("/introspect")]
-pub async fn introspect(
-    handler: Data<ConcreteOAuthHandler>,
-    form: Form<IntrospectionRequest>,
-    req: HttpRequest,
-) -> Result<Json<IntrospectionResponse>, OAuthError> {
-    let client_auth = get_basic_auth(&req);
-    let client_auth = client_auth.as_ref();
-    let req = form.into_inner();
-    log::debug!("received introspection request");
+/// Seconds to wait before trying again, rounded up so that clients do not retry too early
+pub(super) fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
 
-    let client = handler.validate(&req, client_auth).await?;
-    let session = &mut Session::for_client(client.client_id().to_string());
-    let res = handler.handle(&req, session).await?;
-    Ok(Json(res))
+/// When the user of the browser session last authenticated, if known.
+/// Sessions established before it was recorded have no authentication time.
+pub(super) fn session_auth_time(http_session: &HttpSession) -> Result<Option<DateTime<Utc>>, Error> {
+    let timestamp = http_session.get::<i64>(AUTH_TIME)?;
+    Ok(timestamp.map(|timestamp| Utc.timestamp(timestamp, 0)))
 }
 
-#[post("/revoke")]
-pub async fn revoke(
-    handler: Data<ConcreteOAuthHandler>,
-    form: Form<RevocationRequest>,
-    req: HttpRequest,
-) -> Result<Json<RevocationResponse>, OAuthError> {
-    let client_auth = get_basic_auth(&req);
-    let client_auth = client_auth.as_ref();
-    let req = form.into_inner();
-    log::debug!("received revocation request");
+/// User who entered their password and is yet to enter the code of their authenticator app,
+/// unless they took too long
+/// Caps the scope of the session of a user who must change their password to what changing it takes
+pub(super) fn restrict_to_password_change(session: &mut Session, user: &User) {
+    if user.must_change_password() {
+        session.set_scope(Scope::from(PASSWORD_CHANGE_SCOPE));
+    }
+}
 
-    let client = handler.validate(&req, client_auth).await?;
-    let session = &mut Session::for_client(client.client_id().to_string());
-    let res = handler.handle(&req, session).await?;
-    Ok(Json(res))
+fn pending_second_factor(http_session: &HttpSession) -> Result<Option<String>, Error> {
+    pending_user(
+        http_session,
+        SECOND_FACTOR_USER,
+        SECOND_FACTOR_SINCE,
+        SECOND_FACTOR_TIMEOUT_SECS,
+    )
 }
 
-pub fn redirect_to_client<T: Serialize>(redirect_uri: &mut Url, data: T) -> HttpResponse {
-    let option = serde_urlencoded::to_string(data).ok();
-    let query = option.as_deref();
-    redirect_uri.set_query(query);
+fn clear_second_factor(http_session: &HttpSession) {
+    http_session.remove(SECOND_FACTOR_USER);
+    http_session.remove(SECOND_FACTOR_SINCE);
+}
+
+fn pending_password_change(http_session: &HttpSession) -> Result<Option<String>, Error> {
+    pending_user(
+        http_session,
+        PASSWORD_CHANGE_USER,
+        PASSWORD_CHANGE_SINCE,
+        PASSWORD_CHANGE_TIMEOUT_SECS,
+    )
+}
+
+fn clear_password_change(http_session: &HttpSession) {
+    http_session.remove(PASSWORD_CHANGE_USER);
+    http_session.remove(PASSWORD_CHANGE_SINCE);
+}
+
+/// User in the middle of a login step started at most the given seconds ago, if any
+fn pending_user(
+    http_session: &HttpSession,
+    user_key: &str,
+    since_key: &str,
+    timeout_secs: i64,
+) -> Result<Option<String>, Error> {
+    let since = match http_session.get::<i64>(since_key)? {
+        Some(since) => since,
+        None => return Ok(None),
+    };
+    if Utc::now().timestamp() - since > timeout_secs {
+        return Ok(None);
+    }
+    http_session.get::<String>(user_key)
+}
+
+/// Returns an authorization response to the client, honoring the requested response mode
+fn respond_to_client<T: Serialize>(
+    auth: &AuthorizationRequest,
+    redirect_uri: &Url,
+    data: T,
+) -> HttpResponse {
+    match auth.response_mode() {
+        Ok(ResponseMode::FormPost) => post_to_client(redirect_uri, &data),
+        // Unsupported modes are rejected during validation and reported in the default mode
+        Ok(ResponseMode::Query) | Err(_) => redirect_to_client(redirect_uri, data),
+    }
+}
+
+fn redirect_to_client<T: Serialize>(redirect_uri: &Url, data: T) -> HttpResponse {
+    let redirect_uri = response::redirect_uri(redirect_uri, &data);
     log::debug!("redirecting to {}", &redirect_uri);
     responses::redirect_to(redirect_uri.to_string())
 }
 
-fn get_basic_auth(req: &HttpRequest) -> Option<BasicAuth> {
-    req.headers()
-        .get(header::AUTHORIZATION)
-        .map(Basic::parse)
-        .and_then(Result::<Basic, ParseError>::ok)
-        .map(|basic| {
-            BasicAuth::new(
-                basic.user_id().to_string(),
-                basic.password().map(ToString::to_string),
-            )
-        })
+fn post_to_client<T: Serialize>(redirect_uri: &Url, data: &T) -> HttpResponse {
+    let fields = match serde_json::to_value(data) {
+        Ok(Value::Object(fields)) => fields
+            .into_iter()
+            .filter_map(|(name, value)| match value {
+                Value::Null => None,
+                Value::String(value) => Some(FormField { name, value }),
+                value => Some(FormField {
+                    name,
+                    value: value.to_string(),
+                }),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    log::debug!("posting to {}", redirect_uri);
+    let page = FormPost {
+        action: redirect_uri.to_string(),
+        fields,
+    };
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .body(page.to_string())
 }
 
-pub fn handle_query_errors(cfg: QueryConfig) -> QueryConfig {
-    cfg.error_handler(handle_query_error)
+/// Sends an authorization error back to the client, but only to a redirect URI it registered.
+/// Errors of unknown clients or of unverified redirect URIs are shown to the user instead.
+pub(super) fn error_response(
+    req: &HttpRequest,
+    auth: &AuthorizationRequest,
+    client: Option<&Client>,
+    err: OAuthError,
+) -> HttpResponse {
+    let err = document(req, err);
+    let redirect_uri = match err.kind() {
+        ErrorKind::InvalidClient | ErrorKind::InvalidRedirectUri => None,
+        _ => client
+            .filter(|client| client.is_redirect_uri_allowed(&auth.redirect_uri))
+            .and_then(|_| Url::parse(&auth.redirect_uri).ok()),
+    };
+    match redirect_uri {
+        Some(url) => respond_to_client(
+            auth,
+            &url,
+            AuthorizationErrorResponse::new(err, auth.state.clone()),
+        ),
+        None => error_page(req, &err, client),
+    }
 }
 
-pub fn handle_form_errors(cfg: FormConfig) -> FormConfig {
-    cfg.error_handler(handle_form_error)
+/// Reports an authorization request that failed validation, looking up its client if it exists
+pub(super) async fn rejection(
+    oauth: &dyn Oauth,
+    req: &HttpRequest,
+    auth: &AuthorizationRequest,
+    err: OAuthError,
+) -> HttpResponse {
+    let client = oauth.client(&auth.client_id).await;
+    error_response(req, auth, client.as_ref(), err)
 }
 
-fn handle_query_error(err: QueryPayloadError, req: &HttpRequest) -> Error {
-    let detail = err.to_string();
-    log::error!("Error: {}", &detail);
-    let res = match &err {
-        QueryPayloadError::Deserialize(err) => {
-            if detail.contains("redirect_uri") {
-                HttpResponse::BadRequest().body("invalid redirect_uri parameter")
-            } else {
-                let err = OAuthError::new(ErrorKind::InvalidRequest, err.to_string());
-                let result =
-                    serde_urlencoded::from_str::<Vec<(String, String)>>(req.query_string());
-                match result
-                    .ok()
-                    .unwrap_or_else(Vec::new)
-                    .into_iter()
-                    .find(|(k, _)| k == "redirect_uri")
-                    .and_then(|(_, uri)| Url::from_str(uri.as_str()).ok())
-                {
-                    Some(mut redirect_uri) => redirect_to_client(&mut redirect_uri, err),
-                    None => HttpResponse::BadRequest().body("invalid redirect_uri parameter"),
-                }
-            }
-        }
+/// Shows an error to the user, with a link back to the client if it is known
+pub(super) fn error_page(req: &HttpRequest, err: &OAuthError, client: Option<&Client>) -> HttpResponse {
+    let page = ErrorPage {
+        error: err.kind().code(),
+        description: err.description().to_string(),
+        explanation: explain(err.kind()),
+        error_uri: err.error_uri().map(str::to_string),
+        client_uri: client
+            .and_then(Client::home_uri)
+            .map(|uri| uri.to_string()),
+        announcement: Banner::current(req).into_inner(),
     };
-    InternalError::from_response(err, res).into()
+    HttpResponse::BadRequest()
+        .content_type("text/html; charset=utf-8")
+        .body(page.to_string())
 }
 
-fn handle_form_error(err: UrlencodedError, req: &HttpRequest) -> Error {
-    let detail = err.to_string();
-    log::error!("Error: {}", &detail);
-    log::debug!("{:?}", req);
-    let res = match &err {
-        UrlencodedError::Parse => HttpResponse::BadRequest().json(OAuthError::new(
-            ErrorKind::InvalidRequest,
-            "request data is invalid or is missing a required parameter".to_string(),
-        )),
-        _ => HttpResponse::BadRequest()
-            .content_type("text/plain")
-            .body(detail),
+#[derive(Debug, Deserialize)]
+pub struct ErrorDocPath {
+    error: ErrorKind,
+}
+
+/// Documentation of an OAuth error, linked from the `error_uri` of errors
+#[get("/docs/errors/{error}")]
+pub async fn error_doc(path: Path<ErrorDocPath>, banner: Banner) -> HttpResponse {
+    let page = ErrorDoc {
+        error: path.error.code(),
+        explanation: explain(&path.error),
+        announcement: banner.into_inner(),
     };
-    InternalError::from_response(err, res).into()
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(page.to_string())
+}
+
+/// Authorization server metadata (RFC 8414)
+#[get("/.well-known/oauth-authorization-server")]
+pub async fn metadata(metadata: Data<Metadata>) -> HttpResponse {
+    HttpResponse::Ok().json(metadata.get_ref())
+}
+
+fn explain(kind: &ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::AccessDenied => "The user or the server denied the request.",
+        ErrorKind::AuthorizationPending => {
+            "The user has not approved the device yet. \
+            Keep polling the token endpoint with the device code, at the advertised interval."
+        }
+        ErrorKind::ExpiredToken => {
+            "The device code expired before the user approved it. \
+            Start over by requesting a new device code."
+        }
+        ErrorKind::InvalidClient => {
+            "The client is unknown, or it failed to authenticate. \
+            Check the client ID and, for confidential clients, the client secret, \
+            sent either in the request body or with HTTP Basic authentication."
+        }
+        ErrorKind::InvalidGrant => {
+            "The authorization code or refresh token is invalid, expired or revoked, \
+            or it was issued to another client or for another redirect URI. \
+            Authorization codes can only be used once."
+        }
+        ErrorKind::InvalidRedirectUri => {
+            "The redirect URI is not registered for the client. \
+            It must match one of the URIs of the client exactly."
+        }
+        ErrorKind::InvalidRequest => {
+            "The request is missing a required parameter, \
+            includes an invalid parameter value or is otherwise malformed."
+        }
+        ErrorKind::InvalidScope => {
+            "The requested scope is invalid, unknown, \
+            or exceeds the scopes the client is allowed to request."
+        }
+        ErrorKind::InvalidTarget => {
+            "A requested resource is not an absolute URI, is not a resource server known \
+            to this server, or was not granted by the authorization request. \
+            Token requests can only narrow the resources of the authorization request."
+        }
+        ErrorKind::LoginRequired => {
+            "The client asked for no user interaction with prompt=none, \
+            but the user must sign in. Send the user through the authorization flow again \
+            without prompt=none."
+        }
+        ErrorKind::ServerError => {
+            "The server encountered an unexpected condition. Try again later, \
+            and contact the administrators if the problem persists."
+        }
+        ErrorKind::SlowDown => {
+            "The client polls the token endpoint faster than the device code allows. \
+            Wait five more seconds between polls from now on."
+        }
+        ErrorKind::TemporarilyUnavailable => {
+            "The server is temporarily unable to handle the request, \
+            for example because the client was issued too many tokens. Try again later."
+        }
+        ErrorKind::UnauthorizedClient => {
+            "The client is not allowed to use this grant type or response type."
+        }
+        ErrorKind::Unknown => "The server encountered an unknown error.",
+        ErrorKind::UnsupportedGrantType => {
+            "The grant type is not supported by the server, or not allowed for the client."
+        }
+        ErrorKind::UnsupportedResponseType => {
+            "The response type is not supported by the server, or not allowed for the client."
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use actix_session::CookieSession;
+    use actix_web::body::{Body, ResponseBody};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+    use couchdb::Couch;
+    use serde_json::json;
+
+    use enseada_oauth::memory::MemoryStorage;
+
+    use crate::oauth::client::Client;
+    use crate::oauth::error::ErrorDocs;
+    use crate::oauth::handler::OAuthHandler;
+    use crate::oauth::scope::Scope;
+    use crate::oauth::storage::ClientStorage;
+    use crate::oauth::throttle::ThrottleLimits;
+
+    use super::*;
+
+    fn request(response_mode: Option<&str>) -> AuthorizationRequest {
+        serde_json::from_value(json!({
+            "response_type": "code",
+            "client_id": "test",
+            "redirect_uri": "https://example.com/callback",
+            "state": "a&b",
+            "response_mode": response_mode,
+        }))
+        .unwrap()
+    }
+
+    fn body(res: &mut HttpResponse) -> String {
+        match res.take_body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            _ => panic!("unexpected body"),
+        }
+    }
+
+    #[test]
+    fn it_posts_the_response_in_form_post_mode() {
+        let auth = request(Some("form_post"));
+        let url = Url::parse(&auth.redirect_uri).unwrap();
+        let mut res = respond_to_client(&auth, &url, json!({ "code": "xyz", "state": "a&b" }));
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = body(&mut res);
+        assert!(body.contains(r#"action="https:&#x2f;&#x2f;example.com&#x2f;callback""#));
+        assert!(body.contains(r#"name="code" value="xyz""#));
+        assert!(body.contains(r#"name="state" value="a&amp;b""#));
+    }
+
+    fn client(redirect_uri: &str) -> Client {
+        let mut redirect_uris = HashSet::new();
+        redirect_uris.insert(Url::parse(redirect_uri).unwrap());
+        Client::public("test".to_string(), Scope::from("profile"), redirect_uris)
+    }
+
+    #[test]
+    fn it_posts_errors_in_form_post_mode() {
+        let auth = request(Some("form_post"));
+        let err = OAuthError::new(ErrorKind::InvalidScope, "scope not allowed".to_string());
+        let req = test::TestRequest::default().to_http_request();
+        let client = client("https://example.com/callback");
+        let mut res = error_response(&req, &auth, Some(&client), err);
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = body(&mut res);
+        assert!(body.contains(r#"name="error" value="invalid_scope""#));
+        assert!(body.contains(r#"name="state" value="a&amp;b""#));
+    }
+
+    #[test]
+    fn it_never_redirects_errors_to_an_unregistered_uri() {
+        let auth = request(None);
+        let req = test::TestRequest::default().to_http_request();
+        let err = || OAuthError::new(ErrorKind::InvalidScope, "scope not allowed".to_string());
+
+        let mut res = error_response(&req, &auth, None, err());
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(!body(&mut res).contains("Back to the application"));
+
+        let client = client("https://example.com/other");
+        let mut res = error_response(&req, &auth, Some(&client), err());
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = body(&mut res);
+        assert!(body.contains("invalid_scope"));
+        assert!(body.contains(r#"href="https:&#x2f;&#x2f;example.com&#x2f;""#));
+    }
+
+    #[test]
+    fn it_redirects_in_query_mode() {
+        let auth = request(None);
+        let url = Url::parse(&auth.redirect_uri).unwrap();
+        let res = respond_to_client(&auth, &url, json!({ "code": "xyz" }));
+        assert_eq!(res.status(), StatusCode::FOUND);
+    }
+
+    async fn login_app_data() -> (Arc<dyn Oauth>, UserService, UrlBuilder) {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut redirect_uris = HashSet::new();
+        redirect_uris.insert(Url::parse("https://example.com/callback").unwrap());
+        let client = Client::public(
+            "test".to_string(),
+            Scope::from("profile"),
+            redirect_uris.clone(),
+        );
+        storage.save_client(client).await.unwrap();
+        let client = Client::confidential(
+            "confidential".to_string(),
+            "secret".to_string(),
+            Scope::from("profile"),
+            redirect_uris,
+        )
+        .unwrap();
+        storage.save_client(client).await.unwrap();
+        let mut oauth = OAuthHandler::new(
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            storage.clone(),
+            "0123456789abcdef0123456789abcdef".to_string(),
+        );
+        oauth.set_pushed_request_storage(storage.clone());
+        let oauth = Arc::new(oauth);
+        // Never queried without a fresh session cookie
+        let couch = Couch::new(
+            Url::parse("http://localhost:5984").unwrap(),
+            "admin".to_string(),
+            "admin".to_string(),
+        );
+        let users = UserService::new(couch.database("users", true));
+        let public_host = Url::parse("https://enseada.example.com").unwrap();
+        let urls = UrlBuilder::new(&public_host, Some("/enseada")).unwrap();
+        (oauth, users, urls)
+    }
+
+    /// Signs in as jdoe, who authenticated the given number of seconds ago
+    async fn start_session(http_session: HttpSession, age: String) -> HttpResponse {
+        let auth_time = Utc::now() - chrono::Duration::seconds(age.parse().unwrap());
+        http_session.set("user_id", "jdoe").unwrap();
+        http_session.set(AUTH_TIME, auth_time.timestamp()).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    macro_rules! login_app {
+        () => {{
+            let (oauth, users, urls) = login_app_data().await;
+            test::init_service(
+                App::new()
+                    .wrap(CookieSession::private(&[0; 32]).secure(false))
+                    .data(oauth)
+                    .data(users)
+                    .data(LoginThrottle::in_memory(ThrottleLimits::default()))
+                    .data(ErrorDocs::new(urls.url("docs/errors")))
+                    .data(urls)
+                    .route("/session", web::post().to(start_session))
+                    .service(login_form)
+                    .service(login)
+                    .service(change_password)
+                    .service(enseada_oauth::routes::par)
+                    .service(error_doc),
+            )
+            .await
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn it_requires_login_for_silent_requests_without_a_session() {
+        let mut app = login_app!();
+        let req = test::TestRequest::get()
+            .uri("/authorize?response_type=code&client_id=test&redirect_uri=https%3A%2F%2Fexample.com%2Fcallback&state=xyz&prompt=none")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+
+        let location = res.headers().get(http::header::LOCATION).unwrap();
+        let location = Url::parse(location.to_str().unwrap()).unwrap();
+        assert_eq!(location.path(), "/callback");
+        let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(query["error"], "login_required");
+        assert_eq!(
+            query["error_uri"],
+            "https://enseada.example.com/enseada/docs/errors/login_required"
+        );
+        assert_eq!(query["state"], "xyz");
+    }
+
+    #[actix_rt::test]
+    async fn it_serves_error_docs() {
+        let mut app = login_app!();
+        let req = test::TestRequest::get()
+            .uri("/docs/errors/login_required")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("prompt=none"));
+
+        let req = test::TestRequest::get()
+            .uri("/docs/errors/not_an_error")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn it_shows_the_login_form_without_a_session() {
+        let mut app = login_app!();
+        let req = test::TestRequest::get()
+            .uri("/authorize?response_type=code&client_id=test&redirect_uri=https%3A%2F%2Fexample.com%2Fcallback&prompt=login")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(
+            r#"action="https:&#x2f;&#x2f;enseada.example.com&#x2f;enseada&#x2f;oauth&#x2f;authorize""#
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn it_shows_the_login_form_to_signed_in_users_with_prompt_login() {
+        let mut app = login_app!();
+        let req = test::TestRequest::post()
+            .uri("/session")
+            .set_payload("0")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+
+        let uri = "/authorize?response_type=code&client_id=test&redirect_uri=https%3A%2F%2Fexample.com%2Fcallback";
+        for prompt in &["login", "select_account"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("{}&prompt={}", uri, prompt))
+                .cookie(cookie.clone())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let body = test::read_body(res).await;
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains(r#"name="password""#));
+            assert!(body.contains(&format!(r#"name="prompt" value="{}""#, prompt)));
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{}&prompt=consent", uri))
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        let location = res.headers().get(http::header::LOCATION).unwrap();
+        assert!(location.to_str().unwrap().contains("error=invalid_request"));
+    }
+
+    #[test]
+    fn it_asks_for_the_code_in_the_second_step() {
+        let auth = request(None);
+        let req = test::TestRequest::default().to_http_request();
+        let public_host = Url::parse("https://enseada.example.com").unwrap();
+        let urls = UrlBuilder::new(&public_host, None).unwrap();
+
+        let mut res = otp_page(&req, &urls, &auth, Some("The code is incorrect.".to_string()));
+        let otp_body = body(&mut res);
+        assert!(otp_body.contains(r#"name="otp""#));
+        assert!(otp_body.contains("The code is incorrect."));
+        assert!(!otp_body.contains(r#"name="password""#));
+
+        let mut res = login_page(&req, &urls, &auth, None);
+        let login_body = body(&mut res);
+        assert!(login_body.contains(r#"name="password""#));
+        assert!(!login_body.contains(r#"name="otp""#));
+    }
+
+    #[actix_rt::test]
+    async fn it_restarts_the_login_without_a_pending_second_factor() {
+        let mut app = login_app!();
+        let req = test::TestRequest::post()
+            .uri("/authorize")
+            .set_form(&[
+                ("response_type", "code"),
+                ("client_id", "test"),
+                ("redirect_uri", "https://example.com/callback"),
+                ("otp", "123456"),
+            ])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("Your login timed out"));
+        assert!(body.contains(r#"name="password""#));
+    }
+
+    #[test]
+    fn it_asks_for_a_new_password_keeping_the_request() {
+        let auth = request(Some("form_post"));
+        let req = test::TestRequest::default().to_http_request();
+        let public_host = Url::parse("https://enseada.example.com").unwrap();
+        let urls = UrlBuilder::new(&public_host, None).unwrap();
+
+        let error = "The passwords do not match.".to_string();
+        let mut res = password_change_page(&req, &urls, &auth, Some(error));
+        let body = body(&mut res);
+        assert!(body.contains(
+            r#"action="https:&#x2f;&#x2f;enseada.example.com&#x2f;oauth&#x2f;password""#
+        ));
+        assert!(body.contains(r#"name="new_password""#));
+        assert!(body.contains(r#"name="response_mode" value="form_post""#));
+        assert!(body.contains("The passwords do not match."));
+    }
+
+    #[actix_rt::test]
+    async fn it_restarts_the_login_without_a_pending_password_change() {
+        let mut app = login_app!();
+        let req = test::TestRequest::post()
+            .uri("/password")
+            .set_form(&[
+                ("response_type", "code"),
+                ("client_id", "test"),
+                ("redirect_uri", "https://example.com/callback"),
+                ("new_password", "battery staple"),
+                ("confirmation", "battery staple"),
+            ])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("Your login timed out"));
+        assert!(body.contains(r#"name="password""#));
+    }
+
+    #[actix_rt::test]
+    async fn it_asks_to_log_in_again_past_max_age() {
+        let mut app = login_app!();
+        let req = test::TestRequest::post()
+            .uri("/session")
+            .set_payload("120")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+
+        let uri = "/authorize?response_type=code&client_id=test&redirect_uri=https%3A%2F%2Fexample.com%2Fcallback";
+        for max_age in &["0", "60"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("{}&max_age={}", uri, max_age))
+                .cookie(cookie.clone())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let body = test::read_body(res).await;
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains(&format!(r#"name="max_age" value="{}""#, max_age)));
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{}&max_age=0&prompt=none", uri))
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        let location = res.headers().get(http::header::LOCATION).unwrap();
+        assert!(location.to_str().unwrap().contains("error=login_required"));
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_repeated_resources_in_the_login_form() {
+        let mut app = login_app!();
+        let req = test::TestRequest::get()
+            .uri("/authorize?response_type=code&client_id=test&redirect_uri=https%3A%2F%2Fexample.com%2Fcallback&resource=https%3A%2F%2Fapi.example.com&resource=https%3A%2F%2Ffiles.example.com")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(
+            r#"name="resource" value="https:&#x2f;&#x2f;api.example.com https:&#x2f;&#x2f;files.example.com""#
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn it_loads_pushed_requests_once() {
+        let mut app = login_app!();
+        let req = test::TestRequest::post()
+            .uri("/par")
+            .header(
+                http::header::AUTHORIZATION,
+                "Basic Y29uZmlkZW50aWFsOnNlY3JldA==",
+            )
+            .set_form(&[
+                ("response_type", "code"),
+                ("client_id", "confidential"),
+                ("redirect_uri", "https://example.com/callback"),
+                ("scope", "profile"),
+                ("state", "pushed"),
+            ])
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        let request_uri = body["request_uri"].as_str().unwrap().to_string();
+        let uri = format!(
+            "/authorize?client_id=confidential&request_uri={}",
+            url::form_urlencoded::byte_serialize(request_uri.as_bytes()).collect::<String>()
+        );
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"name="state" value="pushed""#));
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn it_shows_invalid_requests_to_the_user() {
+        let mut app = login_app!();
+        let req = test::TestRequest::get()
+            .uri("/authorize?client_id=test&redirect_uri=https%3A%2F%2Fexample.com%2Fcallback")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("response_type"));
+    }
+
+    #[actix_rt::test]
+    async fn it_explains_unknown_clients_instead_of_asking_to_log_in() {
+        let mut app = login_app!();
+        let req = test::TestRequest::get()
+            .uri("/authorize?response_type=code&client_id=unknown&redirect_uri=https%3A%2F%2Fexample.com%2Fcallback")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("invalid_client"));
+        assert!(body.contains("The client is unknown"));
+        assert!(!body.contains(r#"name="password""#));
+    }
+
+    #[actix_rt::test]
+    async fn it_shows_unparsable_redirect_uris_to_the_user() {
+        let mut app = login_app!();
+        let req = test::TestRequest::get()
+            .uri("/authorize?response_type=code&client_id=test&redirect_uri=not%20a%20uri&state=xyz")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.headers().get(http::header::LOCATION).is_none());
+        let body = test::read_body(res).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("invalid_redirect_uri"));
+        assert!(body.contains(r#"href="https:&#x2f;&#x2f;example.com&#x2f;""#));
+    }
 }
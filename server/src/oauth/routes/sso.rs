@@ -0,0 +1,200 @@
+//! Login with the upstream OpenID provider, started from the login page of an authorization
+//! request and completed when the provider sends the user back.
+use std::sync::Arc;
+
+use actix_session::Session as HttpSession;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::web::{Data, Form, Query};
+use actix_web::{get, post};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use url::Url;
+
+use enseada_oauth::routes::{basic_auth, document};
+
+use crate::config::CONFIG;
+use crate::couchdb::repository::Entity;
+use crate::http::error::ApiError;
+use crate::http::urls::UrlBuilder;
+use crate::oauth::audit::{self, AuditAction, AuditEvent};
+use crate::oauth::device::AuthMethod;
+use crate::oauth::error::{Error as OAuthError, ErrorKind};
+use crate::oauth::facade::Oauth;
+use crate::oauth::sso::{OidcProvider, PendingLogins};
+use crate::responses;
+use crate::user::UserService;
+
+use super::oauth::{authorize_user, error_page, login_page, rejection, LoginFormBody};
+
+/// Cookie keeping the state of the login in the browser that started it
+const STATE_COOKIE: &str = "enseada_sso";
+
+/// Sends the user to the provider, keeping their authorization request until they are back
+#[post("/sso")]
+pub async fn start(
+    oauth: Data<Arc<dyn Oauth>>,
+    provider: Data<OidcProvider>,
+    logins: Data<PendingLogins>,
+    form: Form<LoginFormBody>,
+    urls: UrlBuilder,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let client_auth = basic_auth(&req);
+    let auth = form.into_inner().auth_request;
+    if let Err(err) = oauth.validate(&auth, client_auth.as_ref()).await {
+        return Ok(rejection(oauth.get_ref().as_ref(), &req, &auth, err).await);
+    }
+
+    let metadata = match provider.metadata().await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            log::error!("{}", err);
+            let error = format!("{} cannot be reached, please try again later.", provider.name());
+            return Ok(login_page(&req, &urls, &auth, Some(error)));
+        }
+    };
+    let (state, nonce) = logins.create(auth).await?;
+    let redirect_uri = urls.oauth("sso/callback");
+    let url = provider.authorization_url(&metadata, &redirect_uri, &state, &nonce);
+
+    // Sent along when the provider redirects back, unlike the strict session cookie
+    let cookie = Cookie::build(STATE_COOKIE, state)
+        .path(redirect_uri.path().to_string())
+        .secure(CONFIG.tls().enabled())
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish();
+    let mut res = responses::redirect_to(url.to_string());
+    res.add_cookie(&cookie)
+        .map_err(|err| ApiError::InternalServerError(err.to_string()))?;
+    Ok(res)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    #[serde(default)]
+    state: String,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Completes the login of the user the provider sent back, and the authorization request
+/// they started it from
+#[get("/sso/callback")]
+#[allow(clippy::too_many_arguments)]
+pub async fn callback(
+    oauth: Data<Arc<dyn Oauth>>,
+    provider: Data<OidcProvider>,
+    logins: Data<PendingLogins>,
+    users: Data<UserService>,
+    query: Query<CallbackQuery>,
+    http_session: HttpSession,
+    urls: UrlBuilder,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    let cookie_state = req.cookie(STATE_COOKIE).map(|cookie| cookie.value().to_string());
+    let login = match cookie_state.filter(|state| !state.is_empty() && *state == query.state) {
+        Some(state) => logins.consume(&state).await?,
+        None => None,
+    };
+    let login = match login {
+        Some(login) => login,
+        None => {
+            let err = OAuthError::new(
+                ErrorKind::InvalidRequest,
+                "the SSO login is unknown, expired or was started in another browser".to_string(),
+            );
+            return Ok(error_page(&req, &document(&req, err), None));
+        }
+    };
+    let auth = login.auth_request().clone();
+
+    // The client may have been changed or deleted while the user was away
+    let client_auth = basic_auth(&req);
+    let client = match oauth.validate(&auth, client_auth.as_ref()).await {
+        Ok(client) => client,
+        Err(err) => return Ok(rejection(oauth.get_ref().as_ref(), &req, &auth, err).await),
+    };
+    let url = match Url::parse(&auth.redirect_uri) {
+        Ok(url) => url,
+        Err(err) => {
+            let err = OAuthError::new(ErrorKind::InvalidRedirectUri, err.to_string());
+            return Ok(error_page(&req, &document(&req, err), Some(&client)));
+        }
+    };
+
+    let code = match (query.code, query.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            let reason = query
+                .error_description
+                .or(error)
+                .unwrap_or_else(|| "no authorization code".to_string());
+            log::warn!("SSO login refused by the provider: {}", reason);
+            let error = format!("{} did not log you in: {}", provider.name(), reason);
+            return Ok(login_page(&req, &urls, &auth, Some(error)));
+        }
+    };
+
+    let redirect_uri = urls.oauth("sso/callback");
+    let identity = match provider.metadata().await {
+        Ok(metadata) => match provider.exchange(&metadata, &code, &redirect_uri).await {
+            Ok(id_token) => provider.verify(&metadata, &id_token, login.nonce()).await,
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+    let identity = match identity {
+        Ok(identity) => identity,
+        Err(err) => {
+            log::error!("{}", err);
+            let event = AuditEvent::failure(AuditAction::Login, err.to_string())
+                .set_client_id(Some(client.client_id().to_string()));
+            audit::record(&req, event);
+            let error = format!("Logging in with {} failed, please try again.", provider.name());
+            return Ok(login_page(&req, &urls, &auth, Some(error)));
+        }
+    };
+
+    let user = match users.login_external(&identity, provider.register()).await {
+        Ok(user) => user,
+        Err(err) if err.status().is_client_error() => {
+            log::warn!("SSO login of subject {} refused: {}", identity.subject, err);
+            let event = AuditEvent::failure(AuditAction::Login, err.to_string())
+                .set_client_id(Some(client.client_id().to_string()));
+            audit::record(&req, event);
+            let error = format!("Cannot log you in with {}: {}.", provider.name(), err);
+            return Ok(login_page(&req, &urls, &auth, Some(error)));
+        }
+        Err(err) => return Err(ApiError::from(err)),
+    };
+    let event = AuditEvent::success(AuditAction::Login)
+        .set_client_id(Some(client.client_id().to_string()))
+        .set_user_id(Some(user.id().to_string()));
+    audit::record(&req, event);
+
+    let mut res = authorize_user(
+        oauth.get_ref().as_ref(),
+        &http_session,
+        &req,
+        &auth,
+        &client,
+        &url,
+        &user,
+        AuthMethod::Sso,
+        Some(Utc::now()),
+    )
+    .await?;
+    // Expired right away, so that the browser drops it
+    let cookie = Cookie::build(STATE_COOKIE, "")
+        .path(redirect_uri.path().to_string())
+        .max_age(0)
+        .expires(time::at_utc(time::Timespec::new(0, 0)))
+        .finish();
+    res.add_cookie(&cookie)
+        .map_err(|err| ApiError::InternalServerError(err.to_string()))?;
+    Ok(res)
+}
@@ -0,0 +1,68 @@
+use crate::config::CONFIG;
+use crate::oauth::registry::ScopeRegistry;
+
+/// Scopes guarding the endpoints of the server, with the descriptions shown on consent
+const BUILTIN: &[(&str, &str)] = &[
+    ("profile", "Access your profile information"),
+    ("users:read", "Read-only access to registered users"),
+    ("users:manage", "Read-write access to registered users"),
+    (
+        "users:impersonate",
+        "Acting as other users for a few minutes, to reproduce what they see",
+    ),
+    ("roles", "Read-write access to user roles"),
+    ("groups:manage", "Read-write access to user groups and their members"),
+    ("permissions", "Read-write access to user permissions"),
+    ("clients:read", "Read-only access to OAuth clients"),
+    ("clients:manage", "Read-write access to OAuth clients"),
+    (
+        "clients:manage:own",
+        "Read-write access to the OAuth clients you registered",
+    ),
+    (
+        "audit:read",
+        "Read-only access to the audit log of security events",
+    ),
+    ("stats:read", "Read-only access to usage statistics"),
+    (
+        "tokens:introspect",
+        "Introspection of the tokens of any client, for resource servers",
+    ),
+    (
+        "system:manage",
+        "Server administration, like maintenance announcements",
+    ),
+];
+
+/// Registry of the built-in scopes and the custom ones configured for downstream services
+pub fn registry() -> ScopeRegistry {
+    let mut registry = builtin();
+    for (name, description) in CONFIG.oauth().scopes().custom() {
+        registry.register(&name, &description);
+    }
+    registry
+}
+
+fn builtin() -> ScopeRegistry {
+    let mut registry = ScopeRegistry::new();
+    for (name, description) in BUILTIN {
+        registry.register(name, description);
+    }
+    registry
+}
+
+#[cfg(test)]
+mod test {
+    use crate::oauth::scope::Scope;
+
+    use super::builtin;
+
+    #[test]
+    fn it_registers_the_scopes_of_every_endpoint() {
+        let scope = Scope::from(
+            "profile users:manage users:impersonate roles groups:manage permissions clients:manage:own audit:read stats:read tokens:introspect system:manage",
+        );
+        assert!(builtin().validate(&scope).is_ok());
+        assert!(builtin().validate(&Scope::from("packages:write")).is_err());
+    }
+}
@@ -0,0 +1,517 @@
+//! Logging in with an upstream OpenID provider, like the identity provider of a company.
+//!
+//! The login page sends users to the provider with the authorization code flow. Meanwhile the
+//! authorization request they came with is stored in the oauth database under a signature of the
+//! `state`, which the browser also keeps in a cookie, so that a login can only be completed once
+//! and by the browser that started it. The ID token of the provider must carry the `nonce` of the
+//! login, and its signature is verified with the keys the provider publishes.
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use reqwest::Client as HttpClient;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+use couchdb::db::Database;
+use enseada::error::Error;
+use enseada::guid::Guid;
+use enseada::secure;
+
+use crate::config::Sso;
+use crate::couchdb::partition;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::oauth::assertion::{self, Jwk};
+use crate::oauth::request::AuthorizationRequest;
+use crate::user::identity::ExternalIdentity;
+
+/// How long users have to log in at the provider
+pub const LOGIN_TTL_MINUTES: i64 = 10;
+/// How long the discovery document of the provider is cached
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+/// Least time between fetches of the keys of the provider, when ID tokens name unknown ones
+const KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// Seconds the clock of the provider may be ahead of ours
+const CLOCK_SKEW_SECS: i64 = 60;
+const DISCOVERY_PATH: &str = ".well-known/openid-configuration";
+
+/// Endpoints of the provider, from its discovery document
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: Url,
+    pub token_endpoint: Url,
+    pub jwks_uri: Url,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeySet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    #[serde(default)]
+    azp: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    /// A boolean, though some providers send it as a string
+    #[serde(default)]
+    email_verified: Value,
+    #[serde(default)]
+    preferred_username: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == audience,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+
+    fn is_many(&self) -> bool {
+        matches!(self, Audience::Many(auds) if auds.len() > 1)
+    }
+}
+
+/// Our client at the upstream OpenID provider
+pub struct OidcProvider {
+    discovery: Url,
+    client_id: String,
+    client_secret: String,
+    scopes: String,
+    register: bool,
+    name: String,
+    http: HttpClient,
+    metadata: RwLock<Option<(ProviderMetadata, Instant)>>,
+    keys: RwLock<(Vec<Jwk>, Option<Instant>)>,
+}
+
+impl OidcProvider {
+    /// Provider of the configuration, if logging in with SSO is enabled
+    pub fn from_config(sso: &Sso) -> Option<Self> {
+        let discovery = sso.discovery()?;
+        let (client_id, client_secret) = sso.credentials()?;
+        Some(OidcProvider {
+            discovery: discovery_url(discovery),
+            client_id,
+            client_secret,
+            scopes: sso.scopes().to_string(),
+            register: sso.register(),
+            name: sso.name().to_string(),
+            http: HttpClient::new(),
+            metadata: RwLock::new(None),
+            keys: RwLock::new((Vec::new(), None)),
+        })
+    }
+
+    /// Name of the provider shown to users
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether users logging in for the first time get an account
+    pub fn register(&self) -> bool {
+        self.register
+    }
+
+    /// Endpoints of the provider, fetched from its discovery document at most once an hour
+    pub async fn metadata(&self) -> Result<ProviderMetadata, Error> {
+        if let Some((metadata, fetched_at)) = &*self.metadata.read().unwrap() {
+            if fetched_at.elapsed() < METADATA_TTL {
+                return Ok(metadata.clone());
+            }
+        }
+        let metadata: ProviderMetadata = self.fetch(&self.discovery).await?;
+        *self.metadata.write().unwrap() = Some((metadata.clone(), Instant::now()));
+        Ok(metadata)
+    }
+
+    /// Where to send the user to log in at the provider
+    pub fn authorization_url(
+        &self,
+        metadata: &ProviderMetadata,
+        redirect_uri: &Url,
+        state: &str,
+        nonce: &str,
+    ) -> Url {
+        let mut url = metadata.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", redirect_uri.as_str())
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce);
+        url
+    }
+
+    /// Redeems the authorization code the provider sent the user back with for an ID token
+    pub async fn exchange(
+        &self,
+        metadata: &ProviderMetadata,
+        code: &str,
+        redirect_uri: &Url,
+    ) -> Result<String, Error> {
+        let res = self
+            .http
+            .post(metadata.token_endpoint.clone())
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri.as_str()),
+            ])
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| Error::from(format!("SSO token request failed: {}", err)))?;
+        let tokens: TokenResponse = res
+            .json()
+            .await
+            .map_err(|err| Error::from(format!("invalid SSO token response: {}", err)))?;
+        tokens
+            .id_token
+            .ok_or_else(|| Error::from("the SSO token response has no ID token"))
+    }
+
+    /// Identity of the user the ID token was issued for, once its signature and claims are verified
+    pub async fn verify(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<ExternalIdentity, Error> {
+        let keys = self
+            .keys(metadata, assertion::key_id(id_token).as_deref())
+            .await?;
+        let claims: IdClaims = assertion::verify_jwt(&keys, id_token)
+            .map_err(|reason| Error::from(format!("invalid ID token: {}", reason)))?;
+        check_claims(claims, metadata, &self.client_id, nonce, &Utc::now())
+            .map_err(|reason| Error::from(format!("invalid ID token: {}", reason)))
+    }
+
+    /// Keys of the provider, fetched again when the one named is unknown, as they are rotated
+    async fn keys(
+        &self,
+        metadata: &ProviderMetadata,
+        kid: Option<&str>,
+    ) -> Result<Vec<Jwk>, Error> {
+        {
+            let (keys, fetched_at) = &*self.keys.read().unwrap();
+            let known = match kid {
+                Some(kid) => keys.iter().any(|key| key.kid.as_deref() == Some(kid)),
+                None => !keys.is_empty(),
+            };
+            let recent = fetched_at.is_some_and(|at| at.elapsed() < KEYS_REFRESH_INTERVAL);
+            if known || recent {
+                return Ok(keys.clone());
+            }
+        }
+        let set: KeySet = self.fetch(&metadata.jwks_uri).await?;
+        *self.keys.write().unwrap() = (set.keys.clone(), Some(Instant::now()));
+        Ok(set.keys)
+    }
+
+    async fn fetch<T: serde::de::DeserializeOwned>(&self, url: &Url) -> Result<T, Error> {
+        let res = self
+            .http
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| Error::from(format!("failed to fetch {}: {}", url, err)))?;
+        res.json()
+            .await
+            .map_err(|err| Error::from(format!("invalid response from {}: {}", url, err)))
+    }
+}
+
+/// URL of the discovery document, under the issuer if that is what is configured
+fn discovery_url(url: &Url) -> Url {
+    if url.path().ends_with(DISCOVERY_PATH) {
+        return url.clone();
+    }
+    let mut url = url.clone();
+    let path = format!("{}/{}", url.path().trim_end_matches('/'), DISCOVERY_PATH);
+    url.set_path(&path);
+    url
+}
+
+/// Identity of the claims of an ID token, if it was issued by the provider for us and this login
+fn check_claims(
+    claims: IdClaims,
+    metadata: &ProviderMetadata,
+    client_id: &str,
+    nonce: &str,
+    now: &DateTime<Utc>,
+) -> Result<ExternalIdentity, String> {
+    if claims.iss != metadata.issuer {
+        return Err("iss is not the issuer of the provider".to_string());
+    }
+    if !claims.aud.contains(client_id) {
+        return Err("aud does not include our client".to_string());
+    }
+    if (claims.aud.is_many() || claims.azp.is_some()) && claims.azp.as_deref() != Some(client_id) {
+        return Err("azp is not our client".to_string());
+    }
+    if claims.exp + CLOCK_SKEW_SECS <= now.timestamp() {
+        return Err("the ID token is expired".to_string());
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err("nonce does not match the login".to_string());
+    }
+    if claims.sub.is_empty() {
+        return Err("sub is required".to_string());
+    }
+    let email_verified = claims.email_verified == Value::Bool(true)
+        || claims.email_verified == Value::String("true".to_string());
+    Ok(ExternalIdentity {
+        issuer: claims.iss,
+        subject: claims.sub,
+        preferred_username: claims.preferred_username,
+        email: claims.email,
+        email_verified,
+        name: claims.name,
+    })
+}
+
+/// Authorization request of a user logging in at the provider
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingLogin {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    nonce: String,
+    auth_request: AuthorizationRequest,
+    expires_at: DateTime<Utc>,
+}
+
+impl PendingLogin {
+    pub fn nonce(&self) -> &str {
+        &self.nonce
+    }
+
+    pub fn auth_request(&self) -> &AuthorizationRequest {
+        &self.auth_request
+    }
+
+    pub fn is_expired(&self, now: &DateTime<Utc>) -> bool {
+        self.expires_at <= *now
+    }
+}
+
+impl Entity for PendingLogin {
+    fn build_guid(sig: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::SSO, sig))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+/// Logins in progress at the provider
+pub struct PendingLogins {
+    db: Database,
+    secret_key: String,
+}
+
+#[async_trait]
+impl Repository<PendingLogin> for PendingLogins {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl PendingLogins {
+    pub fn new(db: Database, secret_key: String) -> Self {
+        PendingLogins { db, secret_key }
+    }
+
+    /// Keeps the authorization request until the user is back from the provider,
+    /// returning the state and nonce of the login
+    pub async fn create(
+        &self,
+        auth_request: AuthorizationRequest,
+    ) -> Result<(String, String), Error> {
+        let state = secure::generate_token(32)?.to_string();
+        let nonce = secure::generate_token(32)?.to_string();
+        let login = PendingLogin {
+            id: PendingLogin::build_guid(&self.signature(&state)),
+            rev: None,
+            nonce: nonce.clone(),
+            auth_request,
+            expires_at: Utc::now() + chrono::Duration::minutes(LOGIN_TTL_MINUTES),
+        };
+        self.save(login).await?;
+        Ok((state, nonce))
+    }
+
+    /// Takes the login of the state, which cannot be completed again.
+    /// Returns none if the state is unknown, already used or expired.
+    pub async fn consume(&self, state: &str) -> Result<Option<PendingLogin>, Error> {
+        let login = match self.find(&self.signature(state)).await? {
+            Some(login) => login,
+            None => return Ok(None),
+        };
+        match self.delete(&login).await {
+            Ok(()) => {}
+            // Completed concurrently by another request
+            Err(err) if err.status() == StatusCode::CONFLICT => return Ok(None),
+            Err(err) if err.status() == StatusCode::NOT_FOUND => return Ok(None),
+            Err(err) => return Err(Error::from(err)),
+        }
+        Ok(Some(login).filter(|login| !login.is_expired(&Utc::now())))
+    }
+
+    fn signature(&self, state: &str) -> String {
+        secure::generate_signature(state, &self.secret_key).to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use serde_json::json;
+
+    use super::*;
+
+    fn metadata() -> ProviderMetadata {
+        serde_json::from_value(json!({
+            "issuer": "https://sso.example.com",
+            "authorization_endpoint": "https://sso.example.com/authorize",
+            "token_endpoint": "https://sso.example.com/token",
+            "jwks_uri": "https://sso.example.com/keys",
+        }))
+        .unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": "https://sso.example.com",
+            "sub": "248289761001",
+            "aud": "enseada",
+            "exp": Utc::now().timestamp() + 300,
+            "nonce": "n-0S6_WzA2Mj",
+            "email": "jane.doe@example.com",
+            "email_verified": true,
+        })
+    }
+
+    fn check(claims: Value) -> Result<ExternalIdentity, String> {
+        let claims = serde_json::from_value(claims).unwrap();
+        check_claims(claims, &metadata(), "enseada", "n-0S6_WzA2Mj", &Utc::now())
+    }
+
+    #[test]
+    fn it_accepts_id_tokens_of_the_login() {
+        let identity = check(claims()).unwrap();
+        assert_eq!(identity.issuer, "https://sso.example.com");
+        assert_eq!(identity.subject, "248289761001");
+        assert_eq!(identity.verified_email(), Some("jane.doe@example.com"));
+
+        let mut claims = claims();
+        claims["email_verified"] = json!("false");
+        assert_eq!(check(claims).unwrap().verified_email(), None);
+    }
+
+    #[test]
+    fn it_rejects_id_tokens_of_other_logins() {
+        for (name, value) in [
+            ("iss", json!("https://other.example.com")),
+            ("aud", json!("other")),
+            ("aud", json!(["enseada", "other"])),
+            ("azp", json!("other")),
+            ("exp", json!((Utc::now() - Duration::minutes(2)).timestamp())),
+            ("nonce", json!("replayed")),
+            ("nonce", Value::Null),
+            ("sub", json!("")),
+        ] {
+            let mut claims = claims();
+            claims[name] = value;
+            assert!(check(claims.clone()).is_err(), "{}", claims);
+        }
+
+        let mut claims = claims();
+        claims["aud"] = json!(["enseada", "other"]);
+        claims["azp"] = json!("enseada");
+        assert!(check(claims).is_ok());
+    }
+
+    #[test]
+    fn it_sends_the_state_and_nonce_to_the_provider() {
+        let sso: Sso = serde_json::from_value(json!({
+            "discovery": "https://sso.example.com",
+            "client": { "id": "enseada", "secret": "secret" },
+            "scopes": "openid email",
+            "register": false,
+            "name": "Example",
+        }))
+        .unwrap();
+        let provider = OidcProvider::from_config(&sso).unwrap();
+        assert_eq!(
+            provider.discovery.as_str(),
+            "https://sso.example.com/.well-known/openid-configuration"
+        );
+
+        let redirect_uri = Url::parse("https://enseada.example.com/oauth/sso/callback").unwrap();
+        let url = provider.authorization_url(&metadata(), &redirect_uri, "xyz", "abc");
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert!(url.as_str().starts_with("https://sso.example.com/authorize?"));
+        for (name, value) in &[
+            ("client_id", "enseada"),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("scope", "openid email"),
+            ("state", "xyz"),
+            ("nonce", "abc"),
+        ] {
+            assert!(query.contains(&(name.to_string(), value.to_string())), "{}", name);
+        }
+    }
+
+    #[test]
+    fn it_finds_the_discovery_document_of_issuers() {
+        let issuer = Url::parse("https://sso.example.com/realms/corp/").unwrap();
+        assert_eq!(
+            discovery_url(&issuer).as_str(),
+            "https://sso.example.com/realms/corp/.well-known/openid-configuration"
+        );
+        let document = discovery_url(&issuer);
+        assert_eq!(discovery_url(&document), document);
+    }
+}
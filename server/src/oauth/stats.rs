@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::oauth::client::ClientStats;
+use crate::oauth::Result;
+
+/// Caches client usage statistics for a fixed amount of time,
+/// so that listing clients with stats doesn't query the tokens on every call.
+pub struct ClientStatsCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<String, (Instant, ClientStats)>>,
+}
+
+impl ClientStatsCache {
+    pub fn new(ttl: Duration) -> Self {
+        ClientStatsCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<ClientStats> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(client_id)
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    pub fn insert(&self, client_id: &str, stats: ClientStats) {
+        let mut entries = self.entries.write().unwrap();
+        entries.insert(client_id.to_string(), (Instant::now(), stats));
+    }
+
+    pub fn invalidate(&self, client_id: &str) {
+        let mut entries = self.entries.write().unwrap();
+        entries.remove(client_id);
+    }
+
+    /// Returns the cached stats for the client, or computes and caches them if missing or expired
+    pub async fn get_or_compute<F, Fut>(&self, client_id: &str, compute: F) -> Result<ClientStats>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<ClientStats>>,
+    {
+        if let Some(stats) = self.get(client_id) {
+            return Ok(stats);
+        }
+
+        let stats = compute().await?;
+        self.insert(client_id, stats.clone());
+        Ok(stats)
+    }
+}
+
+impl Default for ClientStatsCache {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+
+    use crate::oauth::client::ClientStats;
+
+    use super::ClientStatsCache;
+
+    fn stats(active_tokens: usize) -> ClientStats {
+        ClientStats {
+            active_tokens,
+            last_token_issued_at: None,
+        }
+    }
+
+    #[test]
+    fn it_serves_cached_stats_within_ttl() {
+        let cache = ClientStatsCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let compute = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(stats(2))
+        };
+
+        let first = block_on(cache.get_or_compute("test", compute)).unwrap();
+        let second = block_on(cache.get_or_compute("test", compute)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn it_reflects_revocations_after_expiry() {
+        let cache = ClientStatsCache::new(Duration::from_millis(10));
+        let active = AtomicUsize::new(2);
+        let compute = || async { Ok(stats(active.load(Ordering::SeqCst))) };
+
+        let before = block_on(cache.get_or_compute("test", compute)).unwrap();
+        assert_eq!(before.active_tokens, 2);
+
+        // a token gets revoked, but the cached value is still served
+        active.store(1, Ordering::SeqCst);
+        let cached = block_on(cache.get_or_compute("test", compute)).unwrap();
+        assert_eq!(cached.active_tokens, 2);
+
+        std::thread::sleep(Duration::from_millis(20));
+        let after = block_on(cache.get_or_compute("test", compute)).unwrap();
+        assert_eq!(after.active_tokens, 1);
+    }
+
+    #[test]
+    fn it_invalidates_an_entry() {
+        let cache = ClientStatsCache::new(Duration::from_secs(60));
+        cache.insert("test", stats(3));
+        assert_eq!(cache.get("test"), Some(stats(3)));
+        cache.invalidate("test");
+        assert_eq!(cache.get("test"), None);
+    }
+}
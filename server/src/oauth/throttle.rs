@@ -0,0 +1,240 @@
+//! Throttling of password authentication, against brute-force attacks.
+//!
+//! Failed attempts are counted per remote IP and per username in a sliding window. Once either
+//! reaches the limit, attempts are rejected until the oldest failure leaves the window.
+//! A successful authentication resets both counters.
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+/// Failed attempts allowed within the window, before further ones are rejected
+#[derive(Clone, Debug)]
+pub struct ThrottleLimits {
+    pub failures: u32,
+    pub window: Duration,
+}
+
+impl Default for ThrottleLimits {
+    fn default() -> Self {
+        ThrottleLimits {
+            failures: 5,
+            window: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Keeps the times of failed attempts, by key
+#[async_trait]
+pub trait FailureStore: Send + Sync {
+    async fn record(&self, key: &str, at: DateTime<Utc>);
+
+    /// Failures of the key since the given time, oldest first
+    async fn failures_since(&self, key: &str, since: DateTime<Utc>) -> Vec<DateTime<Utc>>;
+
+    async fn reset(&self, key: &str);
+}
+
+/// Keeps failures in memory, forgetting them once they are older than the window
+pub struct MemoryFailureStore {
+    window: chrono::Duration,
+    failures: Mutex<HashMap<String, VecDeque<DateTime<Utc>>>>,
+}
+
+impl MemoryFailureStore {
+    pub fn new(window: Duration) -> Self {
+        MemoryFailureStore {
+            window: chrono::Duration::from_std(window)
+                .unwrap_or_else(|_| chrono::Duration::max_value()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl FailureStore for MemoryFailureStore {
+    async fn record(&self, key: &str, at: DateTime<Utc>) {
+        let expired = at - self.window;
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, times| times.back().is_some_and(|last| *last > expired));
+        let times = failures.entry(key.to_string()).or_default();
+        while times.front().is_some_and(|first| *first <= expired) {
+            times.pop_front();
+        }
+        times.push_back(at);
+    }
+
+    async fn failures_since(&self, key: &str, since: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let failures = self.failures.lock().unwrap();
+        failures
+            .get(key)
+            .map(|times| times.iter().filter(|at| **at > since).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    async fn reset(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+pub struct LoginThrottle {
+    limits: ThrottleLimits,
+    store: Box<dyn FailureStore>,
+}
+
+impl LoginThrottle {
+    pub fn new(limits: ThrottleLimits, store: Box<dyn FailureStore>) -> Self {
+        LoginThrottle { limits, store }
+    }
+
+    /// Throttles attempts in memory, so counters are not shared between instances
+    pub fn in_memory(limits: ThrottleLimits) -> Self {
+        let store = MemoryFailureStore::new(limits.window);
+        Self::new(limits, Box::new(store))
+    }
+
+    /// Returns how long to wait before trying again, if attempts are rejected
+    pub async fn check(&self, ip: Option<IpAddr>, username: &str) -> Option<Duration> {
+        self.check_at(ip, username, Utc::now()).await
+    }
+
+    pub async fn record_failure(&self, ip: Option<IpAddr>, username: &str) {
+        self.record_failure_at(ip, username, Utc::now()).await
+    }
+
+    pub async fn reset(&self, ip: Option<IpAddr>, username: &str) {
+        for key in keys(ip, username) {
+            self.store.reset(&key).await;
+        }
+    }
+
+    async fn check_at(
+        &self,
+        ip: Option<IpAddr>,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> Option<Duration> {
+        let window = self.window();
+        let mut retry_after = None;
+        for key in keys(ip, username) {
+            let failures = self.store.failures_since(&key, now - window).await;
+            if failures.len() < self.limits.failures as usize {
+                continue;
+            }
+
+            // Attempts are accepted again once enough failures left the window
+            let unlocked_at = failures[failures.len() - self.limits.failures as usize] + window;
+            let wait = (unlocked_at - now).to_std().unwrap_or_default();
+            retry_after = retry_after.max(Some(wait));
+        }
+        retry_after
+    }
+
+    async fn record_failure_at(&self, ip: Option<IpAddr>, username: &str, now: DateTime<Utc>) {
+        for key in keys(ip, username) {
+            self.store.record(&key, now).await;
+        }
+    }
+
+    fn window(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.limits.window)
+            .unwrap_or_else(|_| chrono::Duration::max_value())
+    }
+}
+
+/// Counter keys of an attempt. Usernames are case-insensitive for counting,
+/// so that changing the case does not bypass the limit.
+fn keys(ip: Option<IpAddr>, username: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    if let Some(ip) = ip {
+        keys.push(format!("ip:{}", ip));
+    }
+    if !username.is_empty() {
+        keys.push(format!("user:{}", username.to_lowercase()));
+    }
+    keys
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::in_memory(ThrottleLimits {
+            failures: 3,
+            window: Duration::from_secs(60),
+        })
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_588_327_200 + secs, 0)
+    }
+
+    #[test]
+    fn it_locks_out_after_a_burst_of_failures_until_they_expire() {
+        let throttle = throttle();
+        for secs in 0..3 {
+            assert_eq!(
+                block_on(throttle.check_at(ip("10.0.0.1"), "jdoe", at(secs))),
+                None
+            );
+            block_on(throttle.record_failure_at(ip("10.0.0.1"), "jdoe", at(secs)));
+        }
+
+        let retry_after = block_on(throttle.check_at(ip("10.0.0.1"), "jdoe", at(10)));
+        assert_eq!(retry_after, Some(Duration::from_secs(50)));
+        // Both the address and the username are locked out
+        assert!(block_on(throttle.check_at(ip("10.0.0.2"), "JDoe", at(10))).is_some());
+        assert!(block_on(throttle.check_at(ip("10.0.0.1"), "other", at(10))).is_some());
+        assert_eq!(
+            block_on(throttle.check_at(ip("10.0.0.2"), "other", at(10))),
+            None
+        );
+
+        // The oldest failure leaves the window, one more attempt is allowed
+        assert_eq!(
+            block_on(throttle.check_at(ip("10.0.0.1"), "jdoe", at(60))),
+            None
+        );
+        block_on(throttle.record_failure_at(ip("10.0.0.1"), "jdoe", at(60)));
+        let retry_after = block_on(throttle.check_at(ip("10.0.0.1"), "jdoe", at(60)));
+        assert_eq!(retry_after, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn it_resets_the_counters_on_success() {
+        let throttle = throttle();
+        for secs in 0..3 {
+            block_on(throttle.record_failure_at(ip("10.0.0.1"), "jdoe", at(secs)));
+        }
+        assert!(block_on(throttle.check_at(ip("10.0.0.1"), "jdoe", at(5))).is_some());
+
+        block_on(throttle.reset(ip("10.0.0.1"), "jdoe"));
+        assert_eq!(
+            block_on(throttle.check_at(ip("10.0.0.1"), "jdoe", at(5))),
+            None
+        );
+    }
+
+    #[test]
+    fn it_forgets_expired_failures() {
+        let store = MemoryFailureStore::new(Duration::from_secs(60));
+        block_on(store.record("user:jdoe", at(0)));
+        block_on(store.record("user:other", at(30)));
+        block_on(store.record("user:other", at(90)));
+
+        let failures = store.failures.lock().unwrap();
+        assert!(failures.get("user:jdoe").is_none());
+        assert_eq!(failures["user:other"], vec![at(90)]);
+    }
+}
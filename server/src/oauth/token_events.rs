@@ -0,0 +1,113 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::Client as HttpClient;
+use url::Url;
+
+use crate::events::{DeliveryLog, Envelope, Event, FailedDelivery};
+use crate::oauth::events::{TokenEvent, TokenEventListener};
+
+/// Delay before the first retry, doubled on each of the following ones
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// POSTs token events as JSON to a webhook, retrying failed deliveries in the background
+pub struct TokenEventWebhook {
+    http: HttpClient,
+    webhook: Url,
+    retries: u32,
+    deliveries: Option<Arc<DeliveryLog>>,
+}
+
+impl TokenEventWebhook {
+    pub fn new(webhook: Url, retries: u32) -> Self {
+        TokenEventWebhook {
+            http: HttpClient::new(),
+            webhook,
+            retries,
+            deliveries: None,
+        }
+    }
+
+    /// Keeps the events that could not be delivered after every retry in the given log
+    pub fn set_delivery_log(&mut self, deliveries: Arc<DeliveryLog>) -> &mut Self {
+        self.deliveries = Some(deliveries);
+        self
+    }
+
+    fn send(&self, event: &TokenEvent) {
+        let body = match serde_json::to_vec(&Envelope::new(event)) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("Failed to serialize token event: {}", err);
+                return;
+            }
+        };
+        let http = self.http.clone();
+        let webhook = self.webhook.clone();
+        let retries = self.retries;
+        let deliveries = self.deliveries.clone();
+        actix_rt::spawn(async move {
+            let mut delay = RETRY_DELAY;
+            for attempt in 0..=retries {
+                let res = http
+                    .post(webhook.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone())
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status());
+                match res {
+                    Ok(_) => return,
+                    Err(err) if attempt < retries => {
+                        log::warn!(
+                            "Failed to deliver token event to {}, retrying in {:?}: {}",
+                            webhook,
+                            delay,
+                            err
+                        );
+                        actix_rt::time::delay_for(delay).await;
+                        delay *= 2;
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "Failed to deliver token event to {} after {} attempts: {}",
+                            webhook,
+                            attempt + 1,
+                            err
+                        );
+                        if let Some(deliveries) = &deliveries {
+                            let delivery = FailedDelivery::new(
+                                webhook.to_string(),
+                                TokenEvent::TYPE,
+                                &body,
+                                err.to_string(),
+                                attempt + 1,
+                                Utc::now(),
+                            );
+                            if let Err(err) = deliveries.record(&delivery).await {
+                                log::error!("Failed to record webhook delivery: {}", err);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl TokenEventListener for TokenEventWebhook {
+    async fn on_issued(&self, event: &TokenEvent) {
+        self.send(event);
+    }
+
+    async fn on_refreshed(&self, event: &TokenEvent) {
+        self.send(event);
+    }
+
+    async fn on_revoked(&self, event: &TokenEvent) {
+        self.send(event);
+    }
+}
@@ -1,33 +1,153 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
 use actix_web::get;
-use actix_web::web::ServiceConfig;
-use actix_web::web::{Data, Json};
-use serde::Serialize;
+use actix_web::web::{Data, Query, ServiceConfig};
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
 
 use couchdb;
-use couchdb::status::Status;
-
-use crate::http::error::ApiError;
-use crate::http::error::ApiError::ServiceUnavailable;
-use crate::responses;
 
 pub fn mount(cfg: &mut ServiceConfig) {
     cfg.service(get);
 }
 
+/// Time CouchDB has to answer a health check before it is reported down
+const COUCHDB_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Deserialize)]
+pub struct HealthQuery {
+    /// Whether to describe the components, or only answer with the status code
+    #[serde(default = "verbose")]
+    verbose: bool,
+}
+
+fn verbose() -> bool {
+    true
+}
+
 #[derive(Debug, Serialize, PartialEq)]
 pub struct HealthResponse {
-    pub status: String,
+    pub status: Health,
+    pub components: BTreeMap<String, ComponentHealth>,
 }
 
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ComponentHealth {
+    pub status: Health,
+    /// Time the component took to answer, or to fail
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Up if every component is, otherwise answering with 503 so that orchestrators stop routing
+/// traffic to the server
 #[get("/health")]
-pub async fn get(couch: Data<couchdb::Couch>) -> Result<Json<HealthResponse>, ApiError> {
-    match couch.status().await {
-        Ok(Status { status }) => responses::ok(HealthResponse { status }),
+pub async fn get(couch: Data<couchdb::Couch>, query: Query<HealthQuery>) -> HttpResponse {
+    let mut components = BTreeMap::new();
+    components.insert("couchdb".to_string(), check_couchdb(&couch, COUCHDB_TIMEOUT).await);
+    let status = if components.values().all(|component| component.status == Health::Up) {
+        Health::Up
+    } else {
+        Health::Down
+    };
+
+    let mut res = match status {
+        Health::Up => HttpResponse::Ok(),
+        Health::Down => HttpResponse::ServiceUnavailable(),
+    };
+    if query.verbose {
+        res.json(HealthResponse { status, components })
+    } else {
+        res.finish()
+    }
+}
+
+async fn check_couchdb(couch: &couchdb::Couch, timeout: Duration) -> ComponentHealth {
+    let started = Instant::now();
+    let res = futures::try_join!(couch.status_within(timeout), couch.version());
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match res {
+        Ok((_, version)) => ComponentHealth {
+            status: Health::Up,
+            latency_ms,
+            version: Some(version),
+            error: None,
+        },
         Err(err) => {
-            log::error!("{}", err);
-            Err(ServiceUnavailable(
-                "database connection refused".to_string(),
-            ))
+            log::error!("CouchDB is down: {}", err);
+            ComponentHealth {
+                status: Health::Down,
+                latency_ms,
+                version: None,
+                error: Some(err.to_string()),
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+    use url::Url;
+
+    use super::*;
+
+    /// A client of a CouchDB that is not there, on a port nothing listens on
+    fn dead_couch() -> couchdb::Couch {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        couchdb::Couch::new(
+            Url::parse(&url).unwrap(),
+            "enseada".to_string(),
+            "enseada".to_string(),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_couchdb_down() {
+        let mut app = test::init_service(App::new().data(dead_couch()).configure(mount)).await;
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 503);
+        let body = test::read_body(res).await;
+        let health: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(health["status"], "down");
+        assert_eq!(health["components"]["couchdb"]["status"], "down");
+        assert!(health["components"]["couchdb"]["error"].is_string());
+
+        let req = test::TestRequest::get().uri("/health?verbose=false").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 503);
+        assert!(test::read_body(res).await.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_bounds_the_time_couchdb_has_to_answer() {
+        // Accepts connections but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let couch = couchdb::Couch::new(
+            Url::parse(&url).unwrap(),
+            "enseada".to_string(),
+            "enseada".to_string(),
+        );
+
+        let health = check_couchdb(&couch, Duration::from_millis(100)).await;
+        assert_eq!(health.status, Health::Down);
+        assert!(health.latency_ms < 1000);
+        drop(listener);
+    }
+}
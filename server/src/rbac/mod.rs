@@ -13,14 +13,25 @@ use enseada::guid::Guid;
 use enseada::pagination::{Cursor, Page};
 pub use routes::*;
 
+use crate::couchdb::partition;
+use crate::couchdb::repository::Entity;
+use crate::group::Group;
 use crate::rbac::model::{EvaluationResult, Model, Permission, Principal, Role};
+use crate::user::User;
 
 mod model;
 mod routes;
 pub mod watcher;
 
+/// Role assignments fetched per request when listing all the roles of a principal
+const ROLES_BATCH_SIZE: usize = 100;
+/// Role allowed to perform any action on any object, seeded for the root user
+pub const ADMIN_ROLE: &str = "admin";
+
 pub struct Enforcer {
     db: Arc<Database>,
+    /// Database of the groups whose members inherit their roles, if any
+    groups_db: Option<Arc<Database>>,
     model: Model,
 }
 
@@ -28,10 +39,16 @@ impl Enforcer {
     pub fn new(db: Arc<Database>) -> Self {
         Enforcer {
             db,
+            groups_db: None,
             model: Model::empty(),
         }
     }
 
+    pub fn set_groups_db(&mut self, groups_db: Arc<Database>) -> &mut Self {
+        self.groups_db = Some(groups_db);
+        self
+    }
+
     pub async fn load_rules(&mut self) -> Result<(), Error> {
         log::info!("Loading RBAC rules from CouchDB");
         let model = &mut self.model;
@@ -39,13 +56,13 @@ impl Enforcer {
         let mut roles = HashMap::new();
 
         log::debug!("Loading rules");
-        let rules = self.db.list_all_partitioned::<Rule>("rule").await?;
+        let rules = self.db.list_all_partitioned::<Rule>(partition::RULE).await?;
         for row in rules.rows {
             let rule = &row.doc;
             log::debug!("Processing rule {:?}", rule);
             let permission = Permission::new(&rule.obj.to_string(), &rule.act);
             let sub = rule.sub.id().to_string();
-            if rule.sub.partition() == Some("role") {
+            if rule.sub.partition() == Some(partition::ROLE) {
                 log::debug!("Rule has a role subject. Adding permission to it");
                 if !roles.contains_key(&sub) {
                     roles.insert(sub.clone(), Role::new(sub.clone()));
@@ -66,7 +83,7 @@ impl Enforcer {
         log::debug!("Loading roles for principals");
         let role_assignments = self
             .db
-            .list_all_partitioned::<RoleAssignment>("role")
+            .list_all_partitioned::<RoleAssignment>(partition::ROLE)
             .await?;
         for row in role_assignments.rows {
             let assignment = &row.doc;
@@ -82,12 +99,25 @@ impl Enforcer {
                 "Found assignment subject {:?}. Adding role to it",
                 principal
             );
-            let role = roles.get(&assignment.role);
-            let role = match role {
-                Some(role) => role.clone(),
-                None => Role::new(assignment.role.clone()),
-            };
-            principal.add_role(role);
+            principal.add_role(resolve_role(&roles, &assignment.role));
+        }
+
+        if let Some(groups_db) = &self.groups_db {
+            log::debug!("Loading roles of groups for their members");
+            let groups = groups_db.list_all_partitioned::<Group>(partition::GROUP).await?;
+            for row in groups.rows {
+                let group = &row.doc;
+                log::debug!("Processing group {}", group.name());
+                for member in group.members() {
+                    let sub = User::build_guid(member).to_string();
+                    let principal = principals
+                        .entry(sub.clone())
+                        .or_insert_with(|| Principal::new(sub));
+                    for role in group.roles() {
+                        principal.add_role(resolve_role(&roles, role));
+                    }
+                }
+            }
         }
 
         model.set_principals(principals);
@@ -116,6 +146,16 @@ impl Enforcer {
         }
     }
 
+    /// Roles of the principal as of the last loading of the rules,
+    /// both assigned directly and inherited from their groups
+    pub fn roles_of(&self, sub: &Guid) -> Vec<String> {
+        self.model
+            .roles_of(&sub.to_string())
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    }
+
     pub async fn add_permission(&self, sub: Guid, obj: Guid, act: &str) -> Result<(), Error> {
         let sub_name = sub.to_string();
         let rule = Rule::new(sub, obj, act.to_string());
@@ -163,7 +203,7 @@ impl Enforcer {
         let response = self
             .db
             .find_partitioned::<Rule>(
-                "rule",
+                partition::RULE,
                 serde_json::json!({
                     "sub": sub.to_string(),
                 }),
@@ -208,6 +248,66 @@ impl Enforcer {
         Ok(())
     }
 
+    /// Assigns exactly the given roles to the principal, removing the others
+    pub async fn set_principal_roles(&self, sub: &Guid, roles: &[String]) -> Result<(), Error> {
+        let mut assigned = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self
+                .list_principal_roles(sub, ROLES_BATCH_SIZE, cursor.as_ref())
+                .await?;
+            assigned.extend_from_slice(page.items());
+            // Cursors of pages are encoded like the ones handed out to clients
+            match page.next_cursor() {
+                Some(next) => cursor = Some(Cursor::from_b64(next.to_string())?),
+                None => break,
+            }
+        }
+
+        for role in roles.iter().filter(|role| !assigned.contains(role)) {
+            self.add_role_to_principal(sub.clone(), role).await?;
+        }
+        for role in assigned.iter().filter(|role| !roles.contains(role)) {
+            self.remove_role_from_principal(sub, role).await?;
+        }
+        Ok(())
+    }
+
+    /// Every principal the role is assigned to
+    pub async fn list_role_members(&self, role: &str) -> Result<Vec<Guid>, Error> {
+        let mut members = Vec::new();
+        let mut bookmark = None;
+        loop {
+            let response = self
+                .db
+                .find_partitioned::<RoleAssignment>(
+                    partition::ROLE,
+                    serde_json::json!({
+                        "role": role
+                    }),
+                    ROLES_BATCH_SIZE,
+                    bookmark,
+                )
+                .await?;
+
+            if let Some(warning) = &response.warning {
+                log::warn!("{}", warning);
+            }
+
+            let done = response.docs.len() < ROLES_BATCH_SIZE;
+            members.extend(
+                response
+                    .docs
+                    .into_iter()
+                    .map(|assignment| assignment.subject),
+            );
+            if done {
+                return Ok(members);
+            }
+            bookmark = Some(response.bookmark);
+        }
+    }
+
     pub async fn list_principal_roles(
         &self,
         sub: &Guid,
@@ -217,7 +317,7 @@ impl Enforcer {
         let response = self
             .db
             .find_partitioned::<RoleAssignment>(
-                "role",
+                partition::ROLE,
                 serde_json::json!({
                     "subject": sub.to_string()
                 }),
@@ -236,6 +336,14 @@ impl Enforcer {
     }
 }
 
+/// The role with the given name, with its permissions if any were granted to it
+fn resolve_role(roles: &HashMap<String, Role>, name: &str) -> Role {
+    match roles.get(name) {
+        Some(role) => role.clone(),
+        None => Role::new(name.to_string()),
+    }
+}
+
 #[derive(Debug)]
 pub enum EvaluationError {
     Denied,
@@ -263,11 +371,11 @@ pub struct Rule {
 }
 
 impl Rule {
-    fn build_guid(sub: &str, obj: &str, act: &str) -> Guid {
-        Guid::from(format!("rule:{}-{}-{}", sub, obj, act))
+    pub fn build_guid(sub: &str, obj: &str, act: &str) -> Guid {
+        Guid::from(format!("{}:{}-{}-{}", partition::RULE, sub, obj, act))
     }
 
-    fn new(sub: Guid, obj: Guid, act: String) -> Self {
+    pub fn new(sub: Guid, obj: Guid, act: String) -> Self {
         let id = Self::build_guid(&sub.to_string(), &obj.to_string(), &act.to_string());
         Rule {
             id,
@@ -303,7 +411,7 @@ pub struct RoleAssignment {
 
 impl RoleAssignment {
     pub fn build_guid(sub: &str, role: &str) -> Guid {
-        Guid::from(format!("role:{}-{}", role, sub))
+        Guid::from(format!("{}:{}-{}", partition::ROLE, role, sub))
     }
 
     pub fn new(subject: Guid, role: String) -> Self {
@@ -35,6 +35,16 @@ impl Model {
         Model { principals: map }
     }
 
+    /// Names of the roles of the principal, sorted, whether assigned directly or through groups
+    pub fn roles_of(&self, principal: &str) -> Vec<&str> {
+        let mut roles: Vec<&str> = match self.principals.get(principal) {
+            Some(principal) => principal.roles.keys().map(String::as_str).collect(),
+            None => Vec::new(),
+        };
+        roles.sort_unstable();
+        roles
+    }
+
     pub fn check(&self, principal: &str, object: &str, action: &str) -> EvaluationResult {
         log::debug!("{:?}", &self.principals);
         if principal == "user:root" {
@@ -266,6 +276,17 @@ mod test {
         assert_eq!(result, EvaluationResult::Granted);
     }
 
+    #[test]
+    fn it_lists_the_roles_of_principals() {
+        let mut principal = Principal::new("test".to_string());
+        principal.add_role(Role::new("viewer".to_string()));
+        principal.add_role(Role::new("deployer".to_string()));
+
+        let model = Model::new(vec![principal]);
+        assert_eq!(model.roles_of("test"), vec!["deployer", "viewer"]);
+        assert!(model.roles_of("another_test").is_empty());
+    }
+
     #[test]
     fn it_doesnt_grant_missing_permissions() {
         let mut principal = Principal::new("test".to_string());
@@ -6,11 +6,12 @@ use tokio::sync::RwLock;
 use enseada::guid::Guid;
 use enseada::pagination::{Cursor, Page};
 
+use crate::couchdb::partition;
 use crate::couchdb::repository::{Entity, Repository};
 use crate::http::error::ApiError;
 use crate::http::extractor::user::CurrentUser;
 use crate::http::{ApiResult, PaginationQuery};
-use crate::oauth::scope::Scope;
+use crate::http::extractor::scope::Scope;
 use crate::rbac::{Enforcer, Rule};
 use crate::user::UserService;
 use crate::user::UsernamePathParam;
@@ -43,7 +44,7 @@ pub async fn get_user_roles(
     Scope::from(vec!["users:read", "roles"]).matches_exactly(&scope)?;
     let username = &path.username;
     let enforcer = enforcer.read().await;
-    let sub = Guid::partitioned("user", username);
+    let sub = Guid::partitioned(partition::USER, username)?;
     enforcer.check(current_user.id(), &sub, "read_roles")?;
 
     if service.find(username).await?.is_none() {
@@ -82,7 +83,7 @@ pub async fn add_user_role(
     Scope::from(vec!["users:manage", "roles"]).matches_exactly(&scope)?;
     let enforcer = enforcer.read().await;
     let username = &path.username;
-    let sub = Guid::partitioned("user", username);
+    let sub = Guid::partitioned(partition::USER, username)?;
     enforcer.check(current_user.id(), &sub, "manage_roles")?;
 
     if service.find(username).await?.is_none() {
@@ -105,7 +106,7 @@ pub async fn remove_user_role(
     Scope::from(vec!["users:manage", "roles"]).matches_exactly(&scope)?;
     let enforcer = enforcer.read().await;
     let username = &path.username;
-    let sub = &Guid::partitioned("user", username);
+    let sub = &Guid::partitioned(partition::USER, username)?;
     enforcer.check(current_user.id(), sub, "manage_roles")?;
 
     if service.find(username).await?.is_none() {
@@ -146,7 +147,7 @@ pub async fn get_user_permissions(
     Scope::from(vec!["users:read", "permissions"]).matches_exactly(&scope)?;
     let username = &path.username;
     let enforcer = enforcer.read().await;
-    let sub = &Guid::partitioned("user", username);
+    let sub = &Guid::partitioned(partition::USER, username)?;
     enforcer.check(current_user.id(), sub, "read_permissions")?;
 
     let limit = list.limit();
@@ -176,7 +177,7 @@ pub async fn add_user_permission(
     Scope::from(vec!["users:read", "permissions"]).matches_exactly(&scope)?;
     let username = &path.username;
     let enforcer = enforcer.read().await;
-    let sub = Guid::partitioned("user", username);
+    let sub = Guid::partitioned(partition::USER, username)?;
     enforcer.check(current_user.id(), &sub, "manage_permissions")?;
 
     let mut permission = permission;
@@ -199,7 +200,7 @@ pub async fn remove_user_permission(
     Scope::from(vec!["users:read", "permissions"]).matches_exactly(&scope)?;
     let username = &path.username;
     let enforcer = enforcer.read().await;
-    let sub = &Guid::partitioned("user", username);
+    let sub = &Guid::partitioned(partition::USER, username)?;
     enforcer.check(current_user.id(), sub, "manage_permissions")?;
 
     let mut permission = permission;
@@ -227,7 +228,7 @@ pub async fn get_role_permissions(
     Scope::from(vec!["roles", "permissions"]).matches_exactly(&scope)?;
     let role = &path.role;
     let enforcer = enforcer.read().await;
-    let sub = &Guid::partitioned("role", role);
+    let sub = &Guid::partitioned(partition::ROLE, role)?;
     enforcer.check(current_user.id(), sub, "read_permissions")?;
 
     let limit = list.limit();
@@ -257,7 +258,7 @@ pub async fn add_role_permission(
     Scope::from(vec!["roles", "permissions"]).matches_exactly(&scope)?;
     let role = &path.role;
     let enforcer = enforcer.read().await;
-    let sub = Guid::partitioned("role", role);
+    let sub = Guid::partitioned(partition::ROLE, role)?;
     enforcer.check(current_user.id(), &sub, "manage_permissions")?;
 
     let mut permission = permission;
@@ -280,7 +281,7 @@ pub async fn remove_role_permission(
     Scope::from(vec!["roles", "permissions"]).matches_exactly(&scope)?;
     let role = &path.role;
     let enforcer = enforcer.read().await;
-    let sub = &Guid::partitioned("role", role);
+    let sub = &Guid::partitioned(partition::ROLE, role)?;
     enforcer.check(current_user.id(), sub, "manage_permissions")?;
 
     let mut permission = permission;
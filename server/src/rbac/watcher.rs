@@ -1,68 +1,58 @@
 use std::sync::Arc;
 
-use actix_rt::Arbiter;
-use futures::StreamExt;
+use async_trait::async_trait;
 use tokio::sync::RwLock;
 
-use couchdb::changes::ChangeEvent;
-use couchdb::db::Database;
 use enseada::error::Error;
 
+use crate::jobs::changes::ChangeListener;
 use crate::rbac::Enforcer;
 
+/// Reloads the rules of the enforcer when the documents they are built from change
 pub struct Watcher {
-    db: Arc<Database>,
-    arbiter: Arbiter,
+    /// Partition of the documents whose changes reload the rules, all of them if none
+    partition: Option<&'static str>,
     enforcer: Arc<RwLock<Enforcer>>,
 }
 
 impl Watcher {
-    pub fn new(db: Arc<Database>, enforcer: Arc<RwLock<Enforcer>>) -> Self {
+    pub fn new(enforcer: Arc<RwLock<Enforcer>>) -> Self {
         Watcher {
-            db,
-            arbiter: Arbiter::new(),
+            partition: None,
             enforcer,
         }
     }
 
-    pub fn start(&self) -> Result<(), Error> {
-        let arbiter = &self.arbiter;
-        let db = self.db.clone();
-        let enf = self.enforcer.clone();
-        let fut = Box::pin(async move {
-            loop {
-                log::trace!("Getting fresh change stream");
-                match db.changes().await {
-                    Ok(mut stream) => {
-                        while let Some(el) = stream.next().await {
-                            match el {
-                                ChangeEvent::Next { .. } => {
-                                    log::trace!(
-                                        "Received change event from database. Reloading module"
-                                    );
-                                    let mut enf = enf.write().await;
-                                    if let Err(err) = enf.load_rules().await {
-                                        log::error!("Failed to reload RBAC rules: {:?}", &err);
-                                    }
-                                }
-                                ChangeEvent::End { .. } => {
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        log::error!("{:?}", err);
-                    }
-                }
-            }
-        });
-        arbiter.send(fut);
+    /// Reloads the rules only on changes to the documents of the partition,
+    /// for databases shared with documents the rules do not depend on
+    pub fn for_partition(enforcer: Arc<RwLock<Enforcer>>, partition: &'static str) -> Self {
+        Watcher {
+            partition: Some(partition),
+            ..Self::new(enforcer)
+        }
+    }
+}
 
-        Ok(())
+#[async_trait]
+impl ChangeListener for Watcher {
+    fn name(&self) -> &str {
+        "rbac_rules"
     }
 
-    pub fn stop(&self) {
-        self.arbiter.stop();
+    async fn on_change(&self, id: &str, _deleted: bool) -> Result<(), Error> {
+        if !is_watched(self.partition, id) {
+            return Ok(());
+        }
+        log::trace!("Received change event from database. Reloading module");
+        let mut enf = self.enforcer.write().await;
+        enf.load_rules().await
     }
 }
+
+/// Whether the change of the document with the given ID reloads the rules
+fn is_watched(partition: Option<&str>, id: &str) -> bool {
+    partition.is_none_or(|partition| {
+        id.strip_prefix(partition)
+            .is_some_and(|rest| rest.starts_with(':'))
+    })
+}
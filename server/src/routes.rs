@@ -1,6 +1,8 @@
 use actix_files as fs;
 use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
 
+use crate::announcement::Banner;
+use crate::http::urls::UrlBuilder;
 use crate::templates::ReDoc;
 
 pub fn mount(cfg: &mut web::ServiceConfig) {
@@ -12,7 +14,7 @@ pub fn mount(cfg: &mut web::ServiceConfig) {
 }
 
 #[get("/")]
-pub async fn home(req: HttpRequest) -> HttpResponse {
+pub async fn home(req: HttpRequest, urls: UrlBuilder) -> HttpResponse {
     let accept = req
         .headers()
         .get(http::header::ACCEPT)
@@ -20,11 +22,11 @@ pub async fn home(req: HttpRequest) -> HttpResponse {
         .map(str::to_lowercase)
         .filter(|accept| (*accept).contains("html"));
     let redirect = match accept {
-        Some(_) => "/ui",
-        None => "/health",
+        Some(_) => urls.ui(""),
+        None => urls.url("health"),
     };
     HttpResponse::SeeOther()
-        .header(http::header::LOCATION, redirect)
+        .header(http::header::LOCATION, redirect.to_string())
         .finish()
 }
 
@@ -36,8 +38,9 @@ pub async fn open_api() -> HttpResponse {
 }
 
 #[get("/api/docs")]
-pub async fn redoc() -> impl Responder {
+pub async fn redoc(urls: UrlBuilder, banner: Banner) -> impl Responder {
     ReDoc {
-        spec_url: "/api/docs/openapi.yml".to_string(),
+        spec_url: urls.api("docs/openapi.yml").to_string(),
+        announcement: banner.into_inner(),
     }
 }
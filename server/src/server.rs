@@ -16,25 +16,87 @@ use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
 use tokio::sync::RwLock;
 use url::Url;
 
+use crate::announcement::{AnnouncementCache, AnnouncementService};
 use crate::config::CONFIG;
 use crate::couchdb::{add_couch_client, name as dbname, SINGLETON};
 use crate::http::error;
+use crate::jobs::changes::ChangeSupervisor;
+use crate::jobs::Scheduler;
+use crate::oauth::persistence::cache::{ClientInvalidator, CLIENT_CACHE};
+use crate::oauth::throttle::{LoginThrottle, ThrottleLimits};
 use crate::rbac::watcher::Watcher;
 use crate::rbac::Enforcer;
-use crate::{oauth, observability, rbac, routes, ui, user};
+use crate::user::usage::UsageTracker;
+use crate::{
+    announcement, audit, events, group, jobs, oauth, observability, rbac, routes, stats, ui, user,
+};
 
 pub async fn run() -> io::Result<()> {
     let address = format!("0.0.0.0:{}", CONFIG.port());
     let public_host: &Url = CONFIG.public_host();
     let secret_key = CONFIG.secret_key();
     let tls = CONFIG.tls();
+    let urls = Data::new(CONFIG.urls());
+
+    if let Err(err) = SINGLETON.ping().await {
+        log::warn!("Failed to open a connection to CouchDB: {}", err);
+    }
 
     let rbac_db = Arc::new(SINGLETON.database(dbname::RBAC, true));
-    let mut enforcer = Enforcer::new(rbac_db.clone());
+    let users_db = Arc::new(SINGLETON.database(dbname::USERS, true));
+    let mut enforcer = Enforcer::new(rbac_db);
+    enforcer.set_groups_db(users_db);
     enforcer.load_rules().await.expect("enforcer.load_rules()");
     let enforcer = Data::new(RwLock::new(enforcer));
-    let watcher = Watcher::new(rbac_db.clone(), enforcer.clone().into_inner());
-    watcher.start().expect("watcher.start()");
+    let mut supervisor = ChangeSupervisor::new();
+    supervisor
+        .listen(
+            dbname::RBAC,
+            Arc::new(Watcher::new(enforcer.clone().into_inner())),
+        )
+        .listen(
+            dbname::USERS,
+            Arc::new(Watcher::for_partition(enforcer.clone().into_inner(), "group")),
+        );
+
+    let announcements = Arc::new(AnnouncementService::new(
+        SINGLETON.database(dbname::SYSTEM, true),
+    ));
+    let cache = Data::new(AnnouncementCache::default());
+    cache
+        .reload(&announcements)
+        .await
+        .expect("cache.reload(announcements)");
+    supervisor
+        .listen(
+            dbname::SYSTEM,
+            Arc::new(announcement::watcher::Watcher::new(
+                announcements,
+                cache.clone().into_inner(),
+            )),
+        )
+        .listen(
+            dbname::OAUTH,
+            Arc::new(ClientInvalidator::new(CLIENT_CACHE.clone())),
+        );
+    supervisor.start().expect("supervisor.start()");
+
+    // Shared by the login and device pages of every worker, so that attempts are counted once
+    let throttle = CONFIG.oauth().throttle();
+    let throttle = Data::new(LoginThrottle::in_memory(ThrottleLimits {
+        failures: throttle.failures(),
+        window: throttle.window(),
+    }));
+
+    // Shared by every worker, so that requests are counted against a single daily quota
+    let usage = Data::new(UsageTracker::new(
+        CONFIG.quota().daily(),
+        CONFIG.quota().warning(),
+    ));
+
+    let jobs = Data::new(jobs::registry());
+    let scheduler = Scheduler::new(jobs.clone().into_inner());
+    scheduler.start().expect("scheduler.start()");
 
     let server = HttpServer::new(move || {
         App::new()
@@ -50,10 +112,23 @@ pub async fn run() -> io::Result<()> {
             )
             .wrap(ErrorHandlers::new().handler(StatusCode::BAD_REQUEST, error::handle_bad_request))
             .wrap(default_headers())
+            .wrap_fn(user::usage::warning_header)
+            .wrap_fn(announcement::announcement_header)
             .app_data(enforcer.clone())
+            .app_data(cache.clone())
+            .app_data(urls.clone())
+            .app_data(jobs.clone())
+            .app_data(throttle.clone())
+            .app_data(usage.clone())
             .configure(add_couch_client)
             .configure(user::mount)
+            .configure(group::mount)
             .configure(rbac::mount)
+            .configure(announcement::mount)
+            .configure(events::mount)
+            .configure(audit::mount)
+            .configure(jobs::mount)
+            .configure(stats::mount)
             .configure(oauth::mount)
             .configure(ui::mount)
             .configure(observability::mount)
@@ -80,7 +155,8 @@ pub async fn run() -> io::Result<()> {
 
     log::info!("Server started listening on {}", &address);
     server.run().await?;
-    watcher.stop();
+    supervisor.stop();
+    scheduler.stop();
 
     Ok(())
 }
@@ -0,0 +1,202 @@
+//! Counts of documents, for dashboards.
+//!
+//! The type of a document is the partition of its ID, so `user:jdoe` is a `user`. Documents are
+//! counted by a map/reduce view spanning every partition, installed in each database by the
+//! migrations, which is cheaper than counting each partition with Mango. Users and clients are
+//! counted from the metadata of their partition, and active tokens by a view ordered by expiry,
+//! so that no count reads documents.
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use serde::Serialize;
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+use couchdb::view::{DesignDoc, ViewOptions, ViewResponse};
+use couchdb::Couch;
+
+use crate::couchdb::partition;
+
+pub use routes::mount;
+
+mod routes;
+
+/// Databases whose documents are counted
+pub const DATABASES: &[&str] = &[
+    crate::couchdb::name::OAUTH,
+    crate::couchdb::name::USERS,
+    crate::couchdb::name::RBAC,
+    crate::couchdb::name::SYSTEM,
+    crate::couchdb::name::AUDIT,
+];
+
+const DESIGN_DOC: &str = "stats";
+const BY_TYPE_VIEW: &str = "by_type";
+const BY_TYPE_MAP: &str = r#"function (doc) {
+  var sep = doc._id.indexOf(":");
+  if (sep > 0) {
+    emit(doc._id.substring(0, sep), null);
+  }
+}"#;
+
+const TOKENS_DESIGN_DOC: &str = "tokens";
+const BY_EXPIRATION_VIEW: &str = "by_expiration";
+const BY_EXPIRATION_MAP: &str = r#"function (doc) {
+  if (!doc.revoked && doc.expiration) {
+    emit(doc.expiration, null);
+  }
+}"#;
+
+/// Design document of the view counting documents by type
+pub fn design_doc() -> DesignDoc {
+    DesignDoc::new(DESIGN_DOC)
+        .partitioned(false)
+        .view(BY_TYPE_VIEW, BY_TYPE_MAP, Some("_count"))
+}
+
+/// Number of documents of each type in the database
+pub async fn count_by_type(db: &Database) -> Result<BTreeMap<String, u64>, Error> {
+    let options = ViewOptions::new().group(true);
+    let res: ViewResponse<String, u64> = db.query_view(DESIGN_DOC, BY_TYPE_VIEW, &options).await?;
+    Ok(res.rows.into_iter().map(|row| (row.key, row.value)).collect())
+}
+
+
+/// Design document of the view of unrevoked tokens by expiration, queried within the partition
+/// of a kind of token
+pub fn tokens_design_doc() -> DesignDoc {
+    DesignDoc::new(TOKENS_DESIGN_DOC).view(BY_EXPIRATION_VIEW, BY_EXPIRATION_MAP, Some("_count"))
+}
+
+/// Number of access tokens neither revoked nor expired
+pub async fn count_active_tokens(db: &Database) -> Result<usize, Error> {
+    let options = ViewOptions::new()
+        .start_key(&Utc::now().timestamp())
+        .map_err(|err| Error::internal(err.to_string()))?;
+    let res: ViewResponse<Option<i64>, usize> = db
+        .query_partition_view(
+            partition::ACCESS_TOKEN,
+            TOKENS_DESIGN_DOC,
+            BY_EXPIRATION_VIEW,
+            &options,
+        )
+        .await?;
+    // Reduced to a single row, or none if no token matches
+    Ok(res.rows.first().map_or(0, |row| row.value))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stats {
+    pub users: usize,
+    pub clients: usize,
+    pub active_tokens: usize,
+}
+
+impl Stats {
+    pub async fn compute(couch: &Couch) -> Result<Stats, Error> {
+        let users = couch.database(crate::couchdb::name::USERS, true);
+        let oauth = couch.database(crate::couchdb::name::OAUTH, true);
+        let (users, clients, active_tokens) = futures::try_join!(
+            users.count_partition(partition::USER),
+            oauth.count_partition(partition::CLIENT),
+            count_active_tokens(&oauth),
+        )?;
+        Ok(Stats {
+            users,
+            clients,
+            active_tokens,
+        })
+    }
+}
+
+/// Caches the stats for a few seconds, so that dashboards polling them don't query CouchDB on
+/// every call
+pub struct StatsCache {
+    ttl: Duration,
+    entry: RwLock<Option<(Instant, Stats)>>,
+}
+
+impl StatsCache {
+    pub fn new(ttl: Duration) -> Self {
+        StatsCache {
+            ttl,
+            entry: RwLock::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<Stats> {
+        let entry = self.entry.read().unwrap();
+        entry
+            .as_ref()
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    pub fn insert(&self, stats: Stats) {
+        let mut entry = self.entry.write().unwrap();
+        *entry = Some((Instant::now(), stats));
+    }
+
+    /// Returns the cached stats, or computes and caches them if missing or expired
+    pub async fn get_or_compute<F, Fut>(&self, compute: F) -> Result<Stats, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<Stats, Error>>,
+    {
+        if let Some(stats) = self.get() {
+            return Ok(stats);
+        }
+
+        let stats = compute().await?;
+        self.insert(stats.clone());
+        Ok(stats)
+    }
+}
+
+impl Default for StatsCache {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn stats(users: usize) -> Stats {
+        Stats {
+            users,
+            clients: 1,
+            active_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn it_serves_cached_stats_within_ttl() {
+        let cache = StatsCache::new(Duration::from_millis(50));
+        let calls = AtomicUsize::new(0);
+        let compute = || async { Ok(stats(calls.fetch_add(1, Ordering::SeqCst) + 1)) };
+
+        assert_eq!(block_on(cache.get_or_compute(compute)).unwrap(), stats(1));
+        assert_eq!(block_on(cache.get_or_compute(compute)).unwrap(), stats(1));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(block_on(cache.get_or_compute(compute)).unwrap(), stats(2));
+    }
+
+    #[test]
+    fn it_does_not_cache_failures() {
+        let cache = StatsCache::default();
+        let failed = block_on(cache.get_or_compute(|| async {
+            Err(Error::internal("unavailable".to_string()))
+        }));
+        assert!(failed.is_err());
+        assert_eq!(cache.get(), None);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+
+use actix_web::get;
+use actix_web::web::{Data, Json, ServiceConfig};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use couchdb::Couch;
+use enseada::guid::Guid;
+
+use crate::couchdb::repository::Entity;
+use crate::http::extractor::{scope::Scope, user::CurrentUser};
+use crate::http::ApiResult;
+use crate::rbac::Enforcer;
+use crate::stats::{count_by_type, Stats, StatsCache, DATABASES};
+
+pub fn mount(cfg: &mut ServiceConfig) {
+    cfg.data(StatsCache::default());
+    cfg.service(get);
+    cfg.service(summary);
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct StatsResponse {
+    /// Number of documents of each type, by database
+    pub databases: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+#[get("/api/v1beta1/admin/stats")]
+pub async fn get(
+    couch: Data<Couch>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+) -> ApiResult<Json<StatsResponse>> {
+    Scope::from("system:manage").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("stats")?, "read")?;
+
+    let counts = futures::future::try_join_all(DATABASES.iter().map(|name| {
+        let db = couch.database(name, true);
+        async move { count_by_type(&db).await.map(|counts| (name.to_string(), counts)) }
+    }))
+    .await?;
+    Ok(Json(StatsResponse {
+        databases: counts.into_iter().collect(),
+    }))
+}
+
+/// Counts of users, clients and active tokens, cached for a few seconds
+#[get("/api/v1beta1/stats")]
+pub async fn summary(
+    couch: Data<Couch>,
+    cache: Data<StatsCache>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+) -> ApiResult<Json<Stats>> {
+    Scope::from("stats:read").matches(&scope)?;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &Guid::simple("stats")?, "read")?;
+
+    let stats = cache.get_or_compute(|| Stats::compute(&couch)).await?;
+    Ok(Json(stats))
+}
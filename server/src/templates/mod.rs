@@ -1,15 +1,26 @@
 use askama::Template;
 
+use crate::announcement::Announcement;
+
 pub mod oauth;
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct Index<'a> {
     pub name: Option<&'a str>,
+    pub announcement: Option<Announcement>,
+}
+
+#[derive(Template)]
+#[template(path = "verify_email.html")]
+pub struct VerifyEmail {
+    pub verified: bool,
+    pub announcement: Option<Announcement>,
 }
 
 #[derive(Template)]
 #[template(path = "redoc.html")]
 pub struct ReDoc {
     pub spec_url: String,
+    pub announcement: Option<Announcement>,
 }
\ No newline at end of file
@@ -1,11 +1,119 @@
 use askama::Template;
 
+use crate::announcement::Announcement;
+
 #[derive(Template)]
 #[template(path = "oauth/login.html")]
 pub struct LoginForm {
+    pub action: String,
+    pub response_type: String,
+    pub response_mode: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: String,
+    pub nonce: String,
+    pub prompt: String,
+    pub max_age: String,
+    /// Requested resources, space-delimited
+    pub resource: String,
+    /// Whether the password was verified and the code of an authenticator app is asked for
+    pub otp: bool,
+    /// Why the last login attempt failed
+    pub error: Option<String>,
+    /// Button to log in with the upstream OpenID provider, when one is configured
+    pub sso: Option<SsoLink>,
+    pub announcement: Option<Announcement>,
+}
+
+pub struct SsoLink {
+    pub action: String,
+    /// Name of the provider shown to users
+    pub name: String,
+}
+
+/// Asks the user who logged in with a temporary password to choose their own,
+/// before continuing the authorization request
+#[derive(Template)]
+#[template(path = "oauth/password_change.html")]
+pub struct PasswordChangeForm {
+    pub action: String,
     pub response_type: String,
+    pub response_mode: String,
     pub client_id: String,
     pub redirect_uri: String,
     pub scope: String,
     pub state: String,
-}
\ No newline at end of file
+    pub nonce: String,
+    pub prompt: String,
+    pub max_age: String,
+    /// Requested resources, space-delimited
+    pub resource: String,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+    pub announcement: Option<Announcement>,
+}
+
+/// Shows an authorization error to the user, when it cannot be sent back to the client
+#[derive(Template)]
+#[template(path = "oauth/error.html")]
+pub struct ErrorPage {
+    pub error: String,
+    pub description: String,
+    pub explanation: &'static str,
+    pub error_uri: Option<String>,
+    /// Link back to the client, when it is known
+    pub client_uri: Option<String>,
+    pub announcement: Option<Announcement>,
+}
+
+/// Explains an OAuth error to the developers of clients, linked from its `error_uri`
+#[derive(Template)]
+#[template(path = "oauth/error_doc.html")]
+pub struct ErrorDoc {
+    pub error: String,
+    pub explanation: &'static str,
+    pub announcement: Option<Announcement>,
+}
+
+/// Posts an authorization response to the client, for the `form_post` response mode
+#[derive(Template)]
+#[template(path = "oauth/form_post.html")]
+pub struct FormPost {
+    pub action: String,
+    pub fields: Vec<FormField>,
+}
+
+pub struct FormField {
+    pub name: String,
+    pub value: String,
+}
+
+/// Asks for the user code of a device, and for credentials unless the user is signed in
+#[derive(Template)]
+#[template(path = "oauth/device.html")]
+pub struct DeviceForm {
+    pub action: String,
+    pub user_code: String,
+    pub signed_in: bool,
+    pub error: Option<String>,
+    pub announcement: Option<Announcement>,
+}
+
+/// Asks the user to approve the device authorization of a user code
+#[derive(Template)]
+#[template(path = "oauth/device_consent.html")]
+pub struct DeviceConsent {
+    pub action: String,
+    pub user_code: String,
+    pub client_id: String,
+    pub scope: String,
+    pub announcement: Option<Announcement>,
+}
+
+#[derive(Template)]
+#[template(path = "oauth/device_done.html")]
+pub struct DeviceDone {
+    pub approved: bool,
+    pub announcement: Option<Announcement>,
+}
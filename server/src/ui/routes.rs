@@ -1,13 +1,74 @@
-use actix_web::web::ServiceConfig;
-use actix_web::{get, Responder};
+use actix_web::web::{Data, Query, ServiceConfig};
+use actix_web::{get, HttpResponse, Responder};
+use serde::Deserialize;
 
-use crate::templates::Index;
+use crate::announcement::Banner;
+use crate::http::ApiResult;
+use crate::templates::{Index, VerifyEmail};
+use crate::user::email;
+use crate::user::verification::VerificationService;
+use crate::user::UserService;
 
 pub fn mount(cfg: &mut ServiceConfig) {
     cfg.service(index);
+    cfg.service(verify_email);
 }
 
 #[get("/ui")]
-pub async fn index() -> impl Responder {
-    Index { name: None }
+pub async fn index(banner: Banner) -> impl Responder {
+    Index {
+        name: None,
+        announcement: banner.into_inner(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailQuery {
+    token: Option<String>,
+}
+
+/// Target of the links verifying email addresses, marking the address as verified
+#[get("/ui/verify-email")]
+pub async fn verify_email(
+    verifications: Data<VerificationService>,
+    users: Data<UserService>,
+    query: Query<VerifyEmailQuery>,
+    banner: Banner,
+) -> ApiResult<HttpResponse> {
+    let verification = match &query.token {
+        Some(token) => verifications.consume(token).await?,
+        None => None,
+    };
+    let verified = match verification {
+        Some(verification) => {
+            let username = verification.username();
+            let address = verification.email();
+            let user = users
+                .patch(username, |user| user.verify_email(address))
+                .await?;
+            // The address may have changed since the link was sent
+            let verified = user.is_some_and(|user| {
+                user.is_email_verified()
+                    && user.email().map(email::normalize) == Some(email::normalize(address))
+            });
+            if verified {
+                log::info!("User {} verified their email address", username);
+            }
+            verified
+        }
+        None => false,
+    };
+
+    let page = VerifyEmail {
+        verified,
+        announcement: banner.into_inner(),
+    };
+    let mut res = if verified {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::BadRequest()
+    };
+    Ok(res
+        .content_type("text/html; charset=utf-8")
+        .body(page.to_string()))
 }
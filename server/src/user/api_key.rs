@@ -0,0 +1,304 @@
+//! API keys, static credentials for package clients that only support basic authentication.
+//!
+//! Clients send the key ID as username and its secret as password. Unlike personal access
+//! tokens, keys are looked up by their ID, which is not secret, and only a signature of the
+//! secret is stored, in the oauth database.
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+use enseada::guid::Guid;
+use enseada::pagination::{Cursor, Page};
+use enseada::secure;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::oauth::scope::Scope;
+use crate::oauth::session::Session;
+
+/// Prefix of every key ID, telling basic credentials of API keys apart from other ones
+pub const KEY_ID_PREFIX: &str = "enseada_key_";
+
+/// Client ID of the sessions of API keys
+pub const API_KEY_CLIENT_ID: &str = "api_key";
+
+/// How often the use of a key is written at most
+pub const USE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keys deleted at once when deleting all the keys of a user
+const BATCH_SIZE: usize = 200;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    guid: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    key_id: String,
+    user_id: String,
+    label: String,
+    /// Signature of the secret, which is not stored
+    secret_signature: String,
+    scope: Scope,
+    created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    pub fn created_at(&self) -> &DateTime<Utc> {
+        &self.created_at
+    }
+
+    pub fn expires_at(&self) -> Option<&DateTime<Utc>> {
+        self.expires_at.as_ref()
+    }
+
+    /// When the key last authenticated a request, written at most once per [`USE_INTERVAL`]
+    pub fn last_used_at(&self) -> Option<&DateTime<Utc>> {
+        self.last_used_at.as_ref()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// Session the key authenticates requests with, like an OAuth access token
+    pub fn session(&self) -> Session {
+        let mut session = Session::for_client(API_KEY_CLIENT_ID.to_string());
+        session
+            .set_user_id(self.user_id.clone())
+            .set_scope(self.scope.clone())
+            .set_issued_at(Some(self.created_at))
+            .set_expires_at(self.expires_at);
+        session
+    }
+
+    /// Whether the use of the key at the given time is due to be written
+    fn is_use_due(&self, now: DateTime<Utc>) -> bool {
+        let interval = chrono::Duration::from_std(USE_INTERVAL)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+        match self.last_used_at {
+            Some(last_used_at) => now - last_used_at >= interval,
+            None => true,
+        }
+    }
+}
+
+impl Entity for ApiKey {
+    fn build_guid(key_id: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::API_KEY, key_id))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.guid
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiKeyService {
+    db: Database,
+    secret_key: String,
+}
+
+#[async_trait]
+impl Repository<ApiKey> for ApiKeyService {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl ApiKeyService {
+    pub fn new(db: Database, secret_key: String) -> Self {
+        ApiKeyService { db, secret_key }
+    }
+
+    /// Creates a key for the user, returning it along with its secret, which is not stored
+    pub async fn create(
+        &self,
+        user_id: &Guid,
+        label: String,
+        scope: Scope,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(ApiKey, String), Error> {
+        let key_id = format!("{}{}", KEY_ID_PREFIX, Uuid::new_v4().to_simple());
+        let secret = secure::generate_token(32)
+            .map_err(Error::internal)?
+            .to_string();
+        let key = ApiKey {
+            guid: ApiKey::build_guid(&key_id),
+            rev: None,
+            secret_signature: secure::generate_signature(&secret, &self.secret_key).to_string(),
+            key_id,
+            user_id: user_id.to_string(),
+            label,
+            scope,
+            created_at: Utc::now(),
+            expires_at,
+            last_used_at: None,
+        };
+        let key = self.save(key).await?;
+        Ok((key, secret))
+    }
+
+    /// Looks up a key by its ID, if the secret is the one it was created with
+    pub async fn verify(&self, key_id: &str, secret: &str) -> Result<Option<ApiKey>, Error> {
+        let key = self.find(key_id).await?;
+        Ok(key.filter(|key| {
+            secure::verify_signature(secret, &self.secret_key, &key.secret_signature)
+        }))
+    }
+
+    /// Records the use of the key in the background, if due. Another request recording it
+    /// meanwhile makes the write conflict, which is fine.
+    pub fn touch(&self, key: &ApiKey) {
+        let now = Utc::now();
+        if !key.is_use_due(now) {
+            return;
+        }
+        let service = self.clone();
+        let mut key = key.clone();
+        key.last_used_at = Some(now);
+        actix_rt::spawn(async move {
+            if let Err(err) = service.save(key).await {
+                log::debug!("Did not record the use of an API key: {}", err);
+            }
+        });
+    }
+
+    pub async fn list(
+        &self,
+        user_id: &Guid,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<ApiKey>, Error> {
+        let selector = serde_json::json!({ "user_id": user_id.to_string() });
+        let res = self
+            .find_in_partition(selector, limit, cursor.map(Cursor::to_string))
+            .await?;
+        if let Some(warning) = &res.warning {
+            log::warn!("{}", warning);
+        }
+        Ok(Page::from_find_response(res, limit))
+    }
+
+    /// Deletes a key of the user by its ID, returning false if there is none
+    pub async fn delete_for_user(&self, user_id: &Guid, key_id: &str) -> Result<bool, Error> {
+        match self.find(key_id).await? {
+            Some(key) if key.user_id == user_id.to_string() => {
+                self.delete(&key).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Every key of the user, which [`delete_all_for_user`] would delete
+    ///
+    /// [`delete_all_for_user`]: ApiKeyService::delete_all_for_user
+    pub async fn list_all_for_user(&self, user_id: &Guid) -> Result<Vec<ApiKey>, Error> {
+        let selector = serde_json::json!({ "user_id": user_id.to_string() });
+        let mut all = Vec::new();
+        let mut bookmark = None;
+        loop {
+            let res = self
+                .find_in_partition(selector.clone(), BATCH_SIZE, bookmark)
+                .await?;
+            let done = res.docs.len() < BATCH_SIZE;
+            all.extend(res.docs);
+            if done {
+                return Ok(all);
+            }
+            bookmark = Some(res.bookmark);
+        }
+    }
+
+    /// Deletes all the keys of the user, returning how many there were
+    pub async fn delete_all_for_user(&self, user_id: &Guid) -> Result<usize, Error> {
+        let selector = serde_json::json!({ "user_id": user_id.to_string() });
+        let mut deleted = 0;
+        // Deleted documents no longer match, so the first batch is queried until it runs out
+        loop {
+            let res = self
+                .find_in_partition(selector.clone(), BATCH_SIZE, None)
+                .await?;
+            for key in &res.docs {
+                self.delete(key).await?;
+                deleted += 1;
+            }
+            if res.docs.len() < BATCH_SIZE {
+                return Ok(deleted);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn key(last_used_at: Option<DateTime<Utc>>) -> ApiKey {
+        let key_id = format!("{}abc", KEY_ID_PREFIX);
+        ApiKey {
+            guid: ApiKey::build_guid(&key_id),
+            rev: None,
+            key_id,
+            user_id: "user:jdoe".to_string(),
+            label: "maven".to_string(),
+            secret_signature: secure::generate_signature("secret", "key").to_string(),
+            scope: Scope::from("maven:read"),
+            created_at: Utc::now(),
+            expires_at: None,
+            last_used_at,
+        }
+    }
+
+    #[test]
+    fn it_authenticates_as_the_user_with_the_key_scope() {
+        let key = key(None);
+        let session = key.session();
+        assert_eq!(session.client_id(), API_KEY_CLIENT_ID);
+        assert_eq!(session.user_id().as_deref(), Some("user:jdoe"));
+        assert_eq!(session.scope(), &Scope::from("maven:read"));
+        assert_eq!(key.id().partition(), Some("apikey"));
+    }
+
+    #[test]
+    fn it_records_uses_once_per_interval() {
+        let now = Utc::now();
+        assert!(key(None).is_use_due(now));
+        assert!(!key(Some(now - Duration::seconds(10))).is_use_due(now));
+        assert!(key(Some(now - Duration::minutes(2))).is_use_due(now));
+    }
+}
@@ -0,0 +1,403 @@
+//! Backends verifying the passwords of users, tried in the order they are configured.
+//!
+//! Passwords are verified against the hashes stored in CouchDB by default. They can also be
+//! verified by binding to an LDAP directory like Active Directory, in which case a shadow user
+//! is created on the first login, so that roles and tokens work as for local users.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use couchdb::db::Database;
+use enseada::error::Error;
+use enseada::secure;
+
+use crate::config::Ldap;
+use crate::couchdb::repository::Entity;
+use crate::user::User;
+
+/// Name of the backend verifying the password hashes stored in CouchDB
+pub const COUCHDB: &str = "couchdb";
+/// Name of the backend binding to an LDAP directory
+pub const LDAP: &str = "ldap";
+/// Name of the backend of users logging in with an upstream OpenID provider, not a password
+pub const OIDC: &str = "oidc";
+/// Result code of an LDAP bind with the wrong password or an unknown DN
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// A user whose password was verified by a backend
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserRef {
+    pub username: String,
+    /// Backend the user is managed by, none for local users
+    pub backend: Option<String>,
+}
+
+impl UserRef {
+    pub fn local(username: String) -> Self {
+        UserRef {
+            username,
+            backend: None,
+        }
+    }
+
+    pub fn external(username: String, backend: &str) -> Self {
+        UserRef {
+            username,
+            backend: Some(backend.to_string()),
+        }
+    }
+}
+
+/// Verifies the passwords of users
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The user the credentials belong to, none if this backend does not accept them
+    async fn authenticate(&self, username: &str, password: &str)
+        -> Result<Option<UserRef>, Error>;
+}
+
+/// Verifies passwords against the hashes of local users, ignoring users of other backends
+pub struct CouchAuthenticator {
+    db: Database,
+}
+
+impl CouchAuthenticator {
+    pub fn new(db: Database) -> Self {
+        CouchAuthenticator { db }
+    }
+}
+
+#[async_trait]
+impl Authenticator for CouchAuthenticator {
+    fn name(&self) -> &str {
+        COUCHDB
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<UserRef>, Error> {
+        let guid = User::build_guid(username).to_string();
+        let user = match self.db.get_consistent::<User>(&guid).await? {
+            Some(user) if user.backend().is_none() => user,
+            _ => return Ok(None),
+        };
+        if !secure::verify_password(user.password_hash(), password)? {
+            return Ok(None);
+        }
+        Ok(Some(UserRef::local(user.username().to_string())))
+    }
+}
+
+/// Verifies passwords by binding to an LDAP directory as the user.
+/// The DN of the user is either built from a template, or searched for with a service account.
+pub struct LdapAuthenticator {
+    url: String,
+    bind_dn: Option<String>,
+    search_base: Option<String>,
+    search_filter: String,
+    /// Attribute of the entries found by searching holding their username
+    username_attribute: String,
+    search_credentials: Option<(String, String)>,
+    starttls: bool,
+    verify_tls: bool,
+    timeout: Duration,
+}
+
+impl LdapAuthenticator {
+    pub fn new(ldap: &Ldap) -> Result<Self, Error> {
+        let url = ldap
+            .url()
+            .ok_or_else(|| Error::from("LDAP server URL is not configured"))?;
+        Ok(LdapAuthenticator {
+            url: url.to_string(),
+            bind_dn: ldap.bind_dn().map(str::to_string),
+            search_base: ldap.search().base().map(str::to_string),
+            search_filter: ldap.search().filter().to_string(),
+            username_attribute: ldap.search().attribute().to_string(),
+            search_credentials: ldap.search().credentials(),
+            starttls: ldap.starttls(),
+            verify_tls: ldap.verify_tls(),
+            timeout: ldap.timeout(),
+        })
+    }
+
+    async fn connect(&self) -> Result<ldap3::Ldap, Error> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.timeout)
+            .set_starttls(self.starttls)
+            .set_no_tls_verify(!self.verify_tls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .map_err(ldap_error)?;
+        actix_rt::spawn(async move {
+            if let Err(err) = conn.drive().await {
+                log::warn!("LDAP connection failed: {}", err);
+            }
+        });
+        Ok(ldap)
+    }
+
+    /// DN and username of the entry of the user, found with the template or by searching
+    async fn find_user(
+        &self,
+        ldap: &mut ldap3::Ldap,
+        username: &str,
+    ) -> Result<Option<(String, String)>, Error> {
+        if let Some(template) = &self.bind_dn {
+            return Ok(Some((bind_dn(template, username), username.to_string())));
+        }
+        let base = match &self.search_base {
+            Some(base) => base,
+            None => return Err(Error::from("LDAP bind DN or search base is not configured")),
+        };
+        if let Some((dn, password)) = &self.search_credentials {
+            ldap.simple_bind(dn, password)
+                .await
+                .and_then(|res| res.success())
+                .map_err(ldap_error)?;
+        }
+        let filter = search_filter(&self.search_filter, username);
+        let (entries, _) = ldap
+            .search(base, Scope::Subtree, &filter, vec![self.username_attribute.as_str()])
+            .await
+            .and_then(|res| res.success())
+            .map_err(ldap_error)?;
+        // Ambiguous filters must not let the first match log in
+        if entries.len() > 1 {
+            log::warn!(
+                "LDAP search for user {} matched {} entries",
+                username,
+                entries.len()
+            );
+            return Ok(None);
+        }
+        let entry = match entries.into_iter().next() {
+            Some(entry) => SearchEntry::construct(entry),
+            None => return Ok(None),
+        };
+        let name = entry
+            .attrs
+            .get(&self.username_attribute)
+            .and_then(|values| values.first())
+            .cloned()
+            .unwrap_or_else(|| username.to_string());
+        Ok(Some((entry.dn, name)))
+    }
+}
+
+#[async_trait]
+impl Authenticator for LdapAuthenticator {
+    fn name(&self) -> &str {
+        LDAP
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<UserRef>, Error> {
+        // Directories treat binds without a password as anonymous, which always succeed
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+        let mut ldap = self.connect().await?;
+        let found = self.find_user(&mut ldap, username).await;
+        let verified = match found {
+            Ok(Some((dn, name))) => match ldap.simple_bind(&dn, password).await {
+                Ok(res) if res.rc == 0 => Ok(Some(UserRef::external(name, LDAP))),
+                Ok(res) if res.rc == INVALID_CREDENTIALS => Ok(None),
+                Ok(res) => Err(Error::from(format!(
+                    "LDAP bind failed with code {}: {}",
+                    res.rc, res.text
+                ))),
+                Err(err) => Err(ldap_error(err)),
+            },
+            Ok(None) => Ok(None),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = ldap.unbind().await {
+            log::debug!("Failed to unbind from LDAP: {}", err);
+        }
+        verified
+    }
+}
+
+/// Backends tried in order until one accepts the credentials of a user
+#[derive(Clone)]
+pub struct AuthenticatorChain {
+    backends: Vec<Arc<dyn Authenticator>>,
+}
+
+impl AuthenticatorChain {
+    pub fn new(backends: Vec<Arc<dyn Authenticator>>) -> Self {
+        AuthenticatorChain { backends }
+    }
+
+    /// Backends of the configuration, in the configured order
+    pub fn from_config(names: &[String], db: &Database, ldap: &Ldap) -> Result<Self, Error> {
+        let backends = names
+            .iter()
+            .map(|name| -> Result<Arc<dyn Authenticator>, Error> {
+                match name.as_str() {
+                    COUCHDB => Ok(Arc::new(CouchAuthenticator::new(db.clone()))),
+                    LDAP => Ok(Arc::new(LdapAuthenticator::new(ldap)?)),
+                    _ => Err(Error::from(format!("unknown authentication backend {}", name))),
+                }
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(AuthenticatorChain::new(backends))
+    }
+
+    /// The user of the first backend accepting the credentials.
+    /// Backends failing are skipped, their error is returned only if no other backend accepts.
+    pub async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<UserRef>, Error> {
+        let mut failure = None;
+        for backend in &self.backends {
+            match backend.authenticate(username, password).await {
+                Ok(Some(user)) => return Ok(Some(user)),
+                Ok(None) => {}
+                Err(err) => {
+                    log::error!(
+                        "Authentication backend {} failed for user {}: {}",
+                        backend.name(),
+                        username,
+                        err
+                    );
+                    failure = Some(err);
+                }
+            }
+        }
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+}
+
+fn ldap_error(err: ldap3::LdapError) -> Error {
+    Error::from(format!("LDAP request failed: {}", err))
+}
+
+/// DN of the user from a template like `uid={username},ou=people,dc=example,dc=com`
+fn bind_dn(template: &str, username: &str) -> String {
+    template.replace("{username}", &dn_escape(username))
+}
+
+/// Search filter for the user from a template like `(sAMAccountName={username})`
+fn search_filter(template: &str, username: &str) -> String {
+    template.replace("{username}", &ldap_escape(username))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Accepts a single user, recording the backends called in a shared log
+    struct Mock {
+        name: &'static str,
+        accepts: Option<&'static str>,
+        fails: bool,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl Authenticator for Mock {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn authenticate(
+            &self,
+            username: &str,
+            _password: &str,
+        ) -> Result<Option<UserRef>, Error> {
+            self.calls.lock().unwrap().push(self.name);
+            if self.fails {
+                return Err(Error::from("unreachable"));
+            }
+            Ok(self
+                .accepts
+                .filter(|accepted| *accepted == username)
+                .map(|accepted| UserRef::external(accepted.to_string(), self.name)))
+        }
+    }
+
+    fn mock_chain(
+        mocks: Vec<(&'static str, Option<&'static str>, bool)>,
+    ) -> (AuthenticatorChain, Arc<Mutex<Vec<&'static str>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let backends = mocks
+            .into_iter()
+            .map(|(name, accepts, fails)| -> Arc<dyn Authenticator> {
+                Arc::new(Mock {
+                    name,
+                    accepts,
+                    fails,
+                    calls: calls.clone(),
+                })
+            })
+            .collect();
+        (AuthenticatorChain::new(backends), calls)
+    }
+
+    #[actix_rt::test]
+    async fn it_stops_at_the_first_backend_accepting() {
+        let (chain, calls) = mock_chain(vec![
+            ("ldap", Some("jdoe"), false),
+            ("couchdb", Some("jdoe"), false),
+        ]);
+        let user = chain.authenticate("jdoe", "secret").await.unwrap().unwrap();
+        assert_eq!(user.backend.as_deref(), Some("ldap"));
+        assert_eq!(*calls.lock().unwrap(), vec!["ldap"]);
+    }
+
+    #[actix_rt::test]
+    async fn it_falls_back_in_the_configured_order() {
+        let (chain, calls) = mock_chain(vec![
+            ("ldap", Some("alice"), false),
+            ("couchdb", Some("jdoe"), false),
+        ]);
+        let user = chain.authenticate("jdoe", "secret").await.unwrap().unwrap();
+        assert_eq!(user.backend.as_deref(), Some("couchdb"));
+        assert_eq!(*calls.lock().unwrap(), vec!["ldap", "couchdb"]);
+
+        let (chain, calls) = mock_chain(vec![("couchdb", None, false), ("ldap", None, false)]);
+        assert_eq!(chain.authenticate("jdoe", "secret").await.unwrap(), None);
+        assert_eq!(*calls.lock().unwrap(), vec!["couchdb", "ldap"]);
+    }
+
+    #[actix_rt::test]
+    async fn it_falls_back_when_a_backend_fails() {
+        let (chain, _) = mock_chain(vec![("ldap", None, true), ("couchdb", Some("jdoe"), false)]);
+        let user = chain.authenticate("jdoe", "secret").await.unwrap().unwrap();
+        assert_eq!(user.backend.as_deref(), Some("couchdb"));
+
+        // Rejecting the credentials would hide that a backend is down
+        let (chain, _) = mock_chain(vec![("ldap", None, true), ("couchdb", None, false)]);
+        assert!(chain.authenticate("jdoe", "secret").await.is_err());
+    }
+
+    #[test]
+    fn it_escapes_usernames_in_templates() {
+        assert_eq!(
+            bind_dn("uid={username},ou=people,dc=example,dc=com", "doe, john"),
+            "uid=doe\\2c john,ou=people,dc=example,dc=com"
+        );
+        assert_eq!(
+            search_filter("(&(objectClass=user)(sAMAccountName={username}))", "j*)(uid=*"),
+            "(&(objectClass=user)(sAMAccountName=j\\2a\\29\\28uid=\\2a))"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Email addresses of users, unique across them.
+//!
+//! Every address in use is claimed by a lookup document in the users database, `email:{address}`,
+//! naming the user it belongs to. CouchDB refuses to create a document that already exists,
+//! so when two users claim the same address concurrently only one of them gets it.
+//! Addresses are compared after [normalizing](normalize) them.
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+
+use crate::couchdb::partition;
+
+/// Longest email address users can have, as allowed by SMTP
+pub const MAX_LENGTH: usize = 254;
+
+/// Characters of local parts besides letters and digits, the unquoted ones of RFC 5322
+/// without those that cannot appear unescaped in the URL of a lookup document
+const LOCAL_SYMBOLS: &str = ".!$&'*+=^_`{|}~-";
+
+/// Form of an address compared to the claimed ones: trimmed and lowercase.
+/// The case of local parts is ignored, as providers treat them case-insensitively in practice,
+/// while dots and `+` subaddresses are kept, as only some providers ignore them.
+pub fn normalize(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Whether the address looks deliverable: an unquoted local part and a domain made of labels
+pub fn is_valid(email: &str) -> bool {
+    let email = email.trim();
+    if email.len() > MAX_LENGTH {
+        return false;
+    }
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let local_valid = !local.is_empty()
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_alphanumeric() || LOCAL_SYMBOLS.contains(c));
+    let domain_valid = domain.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+    });
+    local_valid && domain_valid
+}
+
+/// Lookup document claiming an address for a user
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EmailClaim {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    username: String,
+}
+
+impl EmailClaim {
+    pub fn build_guid(email: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::EMAIL, normalize(email)))
+    }
+
+    pub fn new(email: &str, username: String) -> Self {
+        EmailClaim {
+            id: Self::build_guid(email),
+            rev: None,
+            username,
+        }
+    }
+
+    pub fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    pub fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    /// Username of the user the address belongs to
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Hands the address over to another user, keeping the revision to update
+    pub fn set_username(&mut self, username: String) -> &mut Self {
+        self.username = username;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_normalizes_case_and_whitespace_only() {
+        assert_eq!(normalize(" JDoe@Example.COM "), "jdoe@example.com");
+        assert_ne!(normalize("j.doe@example.com"), normalize("jdoe@example.com"));
+        assert_ne!(normalize("jdoe+ci@example.com"), normalize("jdoe@example.com"));
+        assert_eq!(
+            EmailClaim::build_guid("JDoe@Example.com").to_string(),
+            "email:jdoe@example.com"
+        );
+    }
+
+    #[test]
+    fn it_accepts_plausible_addresses() {
+        for email in &[
+            "jdoe@example.com",
+            "j.doe+ci@mail.example.co.uk",
+            "o'brien@example.ie",
+            "jdoe@localhost",
+            "zoë@exämple.com",
+        ] {
+            assert!(is_valid(email), "{}", email);
+        }
+    }
+
+    #[test]
+    fn it_rejects_malformed_addresses() {
+        let long = format!("{}@example.com", "j".repeat(MAX_LENGTH));
+        for email in &[
+            "jdoe",
+            "@example.com",
+            "jdoe@",
+            "jdoe at example.com",
+            "j..doe@example.com",
+            ".jdoe@example.com",
+            "jdoe@example..com",
+            "jdoe@-example.com",
+            "jdoe/admin@example.com",
+            "jdoe?@example.com",
+            "\"jdoe\"@example.com",
+            long.as_str(),
+        ] {
+            assert!(!is_valid(email), "{}", email);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt;
 use std::fmt::Debug;
 
+use chrono::{DateTime, Duration, Utc};
 use serde::export::Formatter;
 use serde::{Deserialize, Serialize};
 
@@ -8,7 +9,10 @@ use enseada::error::Error;
 use enseada::guid::Guid;
 use enseada::secure;
 
+use crate::couchdb::partition;
 use crate::couchdb::repository::Entity;
+use crate::user::email;
+use crate::user::mfa::Totp;
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct User {
@@ -16,32 +20,352 @@ pub struct User {
     id: Guid,
     #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
     rev: Option<String>,
+    /// Lowercase username, for case-insensitive searches.
+    /// Missing on users registered by earlier versions until backfilled.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    username_lower: String,
     password_hash: String,
+    /// Unknown for users whose password never changed since they registered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_changed_at: Option<DateTime<Utc>>,
+    /// Whether the user must set their own password before doing anything else,
+    /// like when an administrator registered them with a temporary one
+    #[serde(default, skip_serializing_if = "is_false")]
+    must_change_password: bool,
+    /// When the user signed out their other sessions, which browser sessions predating it honor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sessions_revoked_at: Option<DateTime<Utc>>,
+    /// Disabled users cannot authenticate
+    #[serde(default = "enabled")]
+    enabled: bool,
+    /// When the user was deleted without being purged, which disables them until they are
+    /// reactivated or purged for good once the retention period is over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deactivated_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// Whether the user proved they own their current email address
+    #[serde(default, skip_serializing_if = "is_false")]
+    email_verified: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    full_name: Option<String>,
+    /// Consecutive failed logins since the last successful one or lockout
+    #[serde(default, skip_serializing_if = "is_zero")]
+    failed_logins: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_failed_login: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locked_until: Option<DateTime<Utc>>,
+    /// Unknown for users registered by earlier versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_login: Option<DateTime<Utc>>,
+    /// Two-factor authentication, required to log in with a password once confirmed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp: Option<Totp>,
+    /// Authentication backend managing the password of the user, none if stored here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backend: Option<String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 impl User {
     pub fn new(username: String, password: String) -> Result<User, Error> {
+        let id = Guid::partitioned(partition::USER, &username)?;
         let password_hash = secure::hash_password(password.as_str())?;
-        let id = Self::build_guid(&username);
         Ok(User {
             id,
             rev: None,
+            username_lower: username.to_lowercase(),
             password_hash,
+            password_changed_at: None,
+            must_change_password: false,
+            sessions_revoked_at: None,
+            enabled: true,
+            deactivated_at: None,
+            email: None,
+            email_verified: false,
+            full_name: None,
+            failed_logins: 0,
+            last_failed_login: None,
+            locked_until: None,
+            created_at: Some(Utc::now()),
+            last_login: None,
+            totp: None,
+            backend: None,
         })
     }
 
+    /// Shadow of a user whose password is managed by another backend, like an LDAP directory.
+    /// It has no password hash, so that it can only log in through that backend.
+    pub fn external(username: String, backend: String) -> User {
+        User {
+            id: Self::build_guid(&username),
+            rev: None,
+            username_lower: username.to_lowercase(),
+            password_hash: String::new(),
+            password_changed_at: None,
+            must_change_password: false,
+            sessions_revoked_at: None,
+            enabled: true,
+            deactivated_at: None,
+            email: None,
+            email_verified: false,
+            full_name: None,
+            failed_logins: 0,
+            last_failed_login: None,
+            locked_until: None,
+            created_at: Some(Utc::now()),
+            last_login: None,
+            totp: None,
+            backend: Some(backend),
+        }
+    }
+
     pub fn username(&self) -> &str {
         self.id.id()
     }
-    
+
     pub(super) fn password_hash(&self) -> &str {
         &self.password_hash
     }
+
+    /// Replaces the hash of the unchanged password, like when rehashing it with stronger parameters
+    pub(super) fn set_password_hash(&mut self, password_hash: String) -> &mut Self {
+        self.password_hash = password_hash;
+        self
+    }
+
+    /// Sets a new password, which the user no longer has to change
+    pub fn set_password(&mut self, password: &str) -> Result<&mut Self, Error> {
+        self.password_hash = secure::hash_password(password)?;
+        self.password_changed_at = Some(Utc::now());
+        self.must_change_password = false;
+        Ok(self)
+    }
+
+    /// Whether the password is the one of the user, never for users of other backends
+    pub fn is_password(&self, password: &str) -> Result<bool, Error> {
+        if self.password_hash.is_empty() {
+            return Ok(false);
+        }
+        Ok(secure::verify_password(&self.password_hash, password)?)
+    }
+
+    /// Whether the user must set their own password before doing anything else
+    pub fn must_change_password(&self) -> bool {
+        self.must_change_password
+    }
+
+    pub fn set_must_change_password(&mut self, must_change_password: bool) -> &mut Self {
+        self.must_change_password = must_change_password;
+        self
+    }
+
+    /// Authentication backend managing the password of the user, none if stored here
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_deref()
+    }
+
+    /// Signs out the browser sessions of the user that authenticated before the given time
+    pub fn revoke_sessions(&mut self, at: DateTime<Utc>) -> &mut Self {
+        self.sessions_revoked_at = Some(at);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) -> &mut Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn deactivated_at(&self) -> Option<&DateTime<Utc>> {
+        self.deactivated_at.as_ref()
+    }
+
+    pub fn is_deactivated(&self) -> bool {
+        self.deactivated_at.is_some()
+    }
+
+    /// Disables the user and signs out their sessions, keeping the first time it was deactivated
+    pub fn deactivate(&mut self, at: DateTime<Utc>) -> &mut Self {
+        self.deactivated_at.get_or_insert(at);
+        self.set_enabled(false).revoke_sessions(at)
+    }
+
+    /// Lets a deactivated user log in again
+    pub fn reactivate(&mut self) -> &mut Self {
+        self.deactivated_at = None;
+        self.set_enabled(true)
+    }
+
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    /// Changes the email address, which is no longer verified unless it stays the same
+    pub fn set_email(&mut self, email: Option<String>) -> &mut Self {
+        if self.email.as_deref().map(email::normalize) != email.as_deref().map(email::normalize) {
+            self.email_verified = false;
+        }
+        self.email = email;
+        self
+    }
+
+    pub fn is_email_verified(&self) -> bool {
+        self.email_verified
+    }
+
+    /// Marks the email address as verified, if it is still the one the verification was for.
+    /// Returns whether the user changed.
+    pub fn verify_email(&mut self, address: &str) -> bool {
+        let current = match self.email.as_deref() {
+            Some(current) => email::normalize(current),
+            None => return false,
+        };
+        if self.email_verified || current != email::normalize(address) {
+            return false;
+        }
+        self.email_verified = true;
+        true
+    }
+
+    pub fn full_name(&self) -> Option<&str> {
+        self.full_name.as_deref()
+    }
+
+    pub fn set_full_name(&mut self, full_name: Option<String>) -> &mut Self {
+        self.full_name = full_name;
+        self
+    }
+
+    pub fn created_at(&self) -> Option<&DateTime<Utc>> {
+        self.created_at.as_ref()
+    }
+
+    /// When the user last authenticated, with a password or a browser session
+    pub fn last_login(&self) -> Option<&DateTime<Utc>> {
+        self.last_login.as_ref()
+    }
+
+    pub fn set_last_login(&mut self, last_login: DateTime<Utc>) -> &mut Self {
+        self.last_login = Some(last_login);
+        self
+    }
+
+    pub fn totp(&self) -> Option<&Totp> {
+        self.totp.as_ref()
+    }
+
+    pub fn set_totp(&mut self, totp: Option<Totp>) -> &mut Self {
+        self.totp = totp;
+        self
+    }
+
+    /// Whether logging in with a password also takes a code, once two-factor authentication
+    /// is confirmed
+    pub fn requires_second_factor(&self) -> bool {
+        self.totp.as_ref().is_some_and(Totp::is_confirmed)
+    }
+
+    /// Confirms a pending two-factor authentication with a first code, returning whether it did
+    pub fn confirm_totp(&mut self, code: &str, now: &DateTime<Utc>) -> bool {
+        match &mut self.totp {
+            Some(totp) if !totp.is_confirmed() => totp.confirm(code, now),
+            _ => false,
+        }
+    }
+
+    /// Checks the second factor of a login, using up the code. Returns whether it was right.
+    pub fn verify_second_factor(&mut self, code: &str, now: &DateTime<Utc>) -> bool {
+        match &mut self.totp {
+            Some(totp) if totp.is_confirmed() => totp.verify(code, now),
+            _ => false,
+        }
+    }
+
+    pub fn failed_logins(&self) -> u32 {
+        self.failed_logins
+    }
+
+    /// End of the lockout, possibly in the past
+    pub fn locked_until(&self) -> Option<&DateTime<Utc>> {
+        self.locked_until.as_ref()
+    }
+
+    pub fn is_locked(&self, now: &DateTime<Utc>) -> bool {
+        self.locked_until.is_some_and(|until| until > *now)
+    }
+
+    /// Counts a failed login, locking the user for the given duration once they reach
+    /// the allowed failures. Returns whether the user got locked.
+    pub fn record_failed_login(
+        &mut self,
+        max_failures: u32,
+        duration: Duration,
+        now: DateTime<Utc>,
+    ) -> bool {
+        self.failed_logins += 1;
+        self.last_failed_login = Some(now);
+        if self.failed_logins < max_failures {
+            return false;
+        }
+
+        self.failed_logins = 0;
+        self.locked_until = Some(now + duration);
+        true
+    }
+
+    /// Forgets failed logins and lifts any lockout, returning whether there was anything to clear
+    pub fn clear_failed_logins(&mut self) -> bool {
+        let cleared = self.failed_logins > 0 || self.locked_until.is_some();
+        self.failed_logins = 0;
+        self.last_failed_login = None;
+        self.locked_until = None;
+        cleared
+    }
+
+    /// Whether a browser session of the user authenticated at the given time is still valid.
+    /// Sessions of disabled users are not, nor those that authenticated before the password
+    /// last changed or the sessions were revoked, compared to the second as sessions record
+    /// their authentication time.
+    pub fn accepts_session(&self, auth_time: Option<&DateTime<Utc>>) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let not_before = self
+            .password_changed_at
+            .iter()
+            .chain(self.sessions_revoked_at.iter())
+            .max();
+        match (not_before, auth_time) {
+            (None, _) => true,
+            (Some(not_before), Some(auth_time)) => {
+                auth_time.timestamp() >= not_before.timestamp()
+            }
+            (Some(_), None) => false,
+        }
+    }
 }
 
 impl Entity for User {
     fn build_guid(username: &str) -> Guid {
-        Guid::partitioned("user", username)
+        Guid::from(format!("{}:{}", partition::USER, username))
     }
 
     fn id(&self) -> &Guid {
@@ -63,3 +387,154 @@ impl Debug for User {
         write!(f, "User {{ id: {:?}, rev: {:?} }}", &self.id, &self.rev)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_rejects_sessions_older_than_the_password() {
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        let before = Utc::now() - Duration::minutes(5);
+        assert!(user.accepts_session(None));
+        assert!(user.accepts_session(Some(&before)));
+
+        user.set_password("battery staple").unwrap();
+        assert!(!user.accepts_session(None));
+        assert!(!user.accepts_session(Some(&before)));
+        assert!(user.accepts_session(Some(&Utc::now())));
+
+        user.set_enabled(false);
+        assert!(!user.accepts_session(Some(&Utc::now())));
+    }
+
+    #[test]
+    fn it_rejects_sessions_older_than_their_revocation() {
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        let before = Utc::now() - Duration::minutes(5);
+        user.revoke_sessions(Utc::now() - Duration::minutes(1));
+        assert!(!user.accepts_session(None));
+        assert!(!user.accepts_session(Some(&before)));
+        assert!(user.accepts_session(Some(&Utc::now())));
+
+        // The latest of the password change and the revocation counts
+        user.set_password("battery staple").unwrap();
+        user.revoke_sessions(before);
+        assert!(!user.accepts_session(Some(&(Utc::now() - Duration::minutes(1)))));
+        assert!(user.accepts_session(Some(&Utc::now())));
+    }
+
+    #[test]
+    fn it_keeps_deactivated_users_disabled_until_reactivated() {
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        let first = Utc::now() - Duration::days(1);
+        user.deactivate(first);
+        assert!(user.is_deactivated());
+        assert!(!user.is_enabled());
+        assert!(!user.accepts_session(Some(&Utc::now())));
+
+        // Deleting again does not delay the purge
+        user.deactivate(Utc::now());
+        assert_eq!(user.deactivated_at(), Some(&first));
+
+        user.reactivate();
+        assert!(!user.is_deactivated());
+        assert!(user.is_enabled());
+        assert!(!user.accepts_session(Some(&(first + Duration::minutes(1)))));
+        assert!(user.accepts_session(Some(&Utc::now())));
+    }
+
+    #[test]
+    fn it_enables_users_stored_by_earlier_versions() {
+        let json = serde_json::json!({ "_id": "user:jdoe", "password_hash": "hash" });
+        let user: User = serde_json::from_value(json).unwrap();
+        assert!(user.is_enabled());
+        assert_eq!(user.email(), None);
+        assert_eq!(user.backend(), None);
+    }
+
+    #[test]
+    fn it_shadows_users_of_other_backends() {
+        let user = User::external("jdoe".to_string(), "ldap".to_string());
+        assert_eq!(user.backend(), Some("ldap"));
+        assert!(user.password_hash().is_empty());
+        let json = serde_json::to_value(&user).unwrap();
+        assert_eq!(json["backend"], "ldap");
+        let user: User = serde_json::from_value(json).unwrap();
+        assert_eq!(user.backend(), Some("ldap"));
+    }
+
+    #[test]
+    fn it_stops_requiring_a_password_change_once_changed() {
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        assert!(!user.must_change_password());
+        let json = serde_json::to_value(&user).unwrap();
+        assert!(json.get("must_change_password").is_none());
+
+        user.set_must_change_password(true);
+        let json = serde_json::to_value(&user).unwrap();
+        assert_eq!(json["must_change_password"], true);
+
+        user.set_password("battery staple").unwrap();
+        assert!(!user.must_change_password());
+        assert!(user.is_password("battery staple").unwrap());
+        assert!(!user.is_password("correct horse").unwrap());
+    }
+
+    #[test]
+    fn it_locks_users_after_too_many_failed_logins() {
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        let now = Utc::now();
+        assert!(!user.record_failed_login(3, Duration::minutes(15), now));
+        assert!(!user.record_failed_login(3, Duration::minutes(15), now));
+        assert!(!user.is_locked(&now));
+        assert_eq!(user.failed_logins(), 2);
+
+        assert!(user.record_failed_login(3, Duration::minutes(15), now));
+        assert!(user.is_locked(&now));
+        assert!(!user.is_locked(&(now + Duration::minutes(15))));
+        assert_eq!(user.failed_logins(), 0);
+
+        assert!(user.clear_failed_logins());
+        assert!(!user.is_locked(&now));
+        assert!(!user.clear_failed_logins());
+    }
+
+    #[test]
+    fn it_verifies_only_the_current_email_address() {
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        assert!(!user.verify_email("jdoe@example.com"));
+
+        user.set_email(Some("jdoe@example.com".to_string()));
+        assert!(!user.verify_email("john@example.com"));
+        assert!(user.verify_email("JDoe@example.com"));
+        assert!(user.is_email_verified());
+        assert!(!user.verify_email("jdoe@example.com"));
+
+        user.set_email(Some("JDOE@example.com".to_string()));
+        assert!(user.is_email_verified());
+        user.set_email(Some("john@example.com".to_string()));
+        assert!(!user.is_email_verified());
+    }
+
+    #[test]
+    fn it_requires_a_second_factor_once_confirmed() {
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        let (totp, recovery_codes) = Totp::generate().unwrap();
+        let secret = enseada::totp::base32_decode(totp.secret()).unwrap();
+        user.set_totp(Some(totp));
+        let now = Utc::now();
+        assert!(!user.requires_second_factor());
+        assert!(!user.verify_second_factor(&recovery_codes[0], &now));
+
+        let step = enseada::totp::step_at(now.timestamp() as u64);
+        assert!(!user.confirm_totp("000000x", &now));
+        assert!(user.confirm_totp(&enseada::totp::code_at(&secret, step), &now));
+        assert!(user.requires_second_factor());
+        assert!(!user.confirm_totp(&enseada::totp::code_at(&secret, step + 1), &now));
+
+        assert!(user.verify_second_factor(&recovery_codes[0], &now));
+        assert!(!user.verify_second_factor(&recovery_codes[0], &now));
+        assert_eq!(user.totp().unwrap().recovery_codes_left(), recovery_codes.len() - 1);
+    }
+}
@@ -0,0 +1,142 @@
+//! Identities of users at an upstream OpenID provider, which they log in with instead of a password
+//!
+//! Like email addresses, each linked identity is claimed by a lookup document in the users
+//! database naming the user it belongs to, `identity:{digest}`, where the digest is the SHA-256
+//! of the issuer and subject of the identity, as subjects can contain any character.
+use serde::{Deserialize, Serialize};
+
+use enseada::guid::Guid;
+use enseada::secure;
+
+use crate::couchdb::partition;
+use crate::user::username;
+
+/// Who a user is at an upstream OpenID provider, as told by a verified ID token
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub preferred_username: Option<String>,
+    pub email: Option<String>,
+    /// Whether the provider verified the user owns the email address
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+impl ExternalIdentity {
+    /// Verified email address of the identity, unverified ones cannot be trusted to be owned
+    pub fn verified_email(&self) -> Option<&str> {
+        self.email.as_deref().filter(|_| self.email_verified)
+    }
+
+    /// Username a user registered with the identity gets: their preferred username,
+    /// or else the local part of their email address, keeping only the characters usernames
+    /// can contain
+    pub fn username(&self) -> Option<String> {
+        let local_part = self
+            .verified_email()
+            .and_then(|email| email.rsplit_once('@'))
+            .map(|(local, _)| local);
+        [self.preferred_username.as_deref(), local_part]
+            .iter()
+            .flatten()
+            .map(|candidate| sanitize_username(candidate))
+            .find(|candidate| username::is_valid(candidate))
+    }
+}
+
+fn sanitize_username(candidate: &str) -> String {
+    username::normalize(candidate)
+        .chars()
+        .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(*c))
+        .take(username::MAX_LENGTH)
+        .collect()
+}
+
+/// Lookup document linking an identity to a user
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IdentityLink {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    issuer: String,
+    subject: String,
+    username: String,
+}
+
+impl IdentityLink {
+    pub fn build_guid(issuer: &str, subject: &str) -> Guid {
+        let digest = secure::digest(&format!("{}\n{}", issuer, subject));
+        Guid::from(format!("{}:{}", partition::IDENTITY, digest))
+    }
+
+    pub fn new(identity: &ExternalIdentity, username: String) -> Self {
+        IdentityLink {
+            id: Self::build_guid(&identity.issuer, &identity.subject),
+            rev: None,
+            issuer: identity.issuer.clone(),
+            subject: identity.subject.clone(),
+            username,
+        }
+    }
+
+    pub fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    pub fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    /// Username of the user the identity is linked to
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn identity() -> ExternalIdentity {
+        ExternalIdentity {
+            issuer: "https://sso.example.com".to_string(),
+            subject: "248289761001".to_string(),
+            email: Some("jane.doe@example.com".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_derives_usernames_from_safe_claims() {
+        let mut identity = identity();
+        assert_eq!(identity.username(), None);
+
+        identity.email_verified = true;
+        assert_eq!(identity.username().as_deref(), Some("jane.doe"));
+
+        identity.preferred_username = Some(" Jane Doe/admin ".to_string());
+        assert_eq!(identity.username().as_deref(), Some("janedoeadmin"));
+
+        // Too short once sanitized
+        identity.preferred_username = Some(" J/ ".to_string());
+        assert_eq!(identity.username().as_deref(), Some("jane.doe"));
+    }
+
+    #[test]
+    fn it_links_identities_by_issuer_and_subject() {
+        let identity = identity();
+        let link = IdentityLink::new(&identity, "jdoe".to_string());
+        let id = link.id().to_string();
+        assert_eq!(
+            id,
+            IdentityLink::build_guid("https://sso.example.com", "248289761001").to_string()
+        );
+        assert_eq!(link.id().partition(), Some("identity"));
+        assert_ne!(
+            id,
+            IdentityLink::build_guid("https://other.example.com", "248289761001").to_string()
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Two-factor authentication with time-based one-time passwords (TOTP).
+//!
+//! Users enroll by adding the secret to an authenticator app and confirming a first code,
+//! after which logging in with a password also takes a code. They are given recovery codes
+//! on enrollment, stored hashed, each standing in for a code once when the app is lost.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use enseada::error::Error;
+use enseada::totp;
+
+/// Recovery codes handed out on enrollment
+pub const RECOVERY_CODES: usize = 10;
+/// Issuer authenticator apps list the secret under
+pub const ISSUER: &str = "Enseada";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Totp {
+    /// Shared secret, base32-encoded
+    secret: String,
+    /// Whether a first code was verified. Until then, codes are not asked for when logging in.
+    #[serde(default)]
+    confirmed: bool,
+    /// Step of the last code accepted, which cannot be used again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_step: Option<u64>,
+    /// Hashes of the unused recovery codes
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+}
+
+impl Totp {
+    /// Starts an enrollment, returning it along with its recovery codes, which are not stored
+    pub fn generate() -> Result<(Totp, Vec<String>), Error> {
+        let secret = totp::generate_secret()?;
+        let codes = (0..RECOVERY_CODES)
+            .map(|_| totp::generate_recovery_code())
+            .collect::<Result<Vec<String>, String>>()?;
+        let totp = Totp {
+            secret,
+            confirmed: false,
+            last_step: None,
+            recovery_codes: codes.iter().map(|code| totp::hash_recovery_code(code)).collect(),
+        };
+        Ok((totp, codes))
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_codes.len()
+    }
+
+    /// Key URI authenticator apps import the secret from, usually shown as a QR code
+    pub fn uri(&self, account: &str) -> Url {
+        let mut uri = Url::parse("otpauth://totp/").expect("valid otpauth URI");
+        uri.set_path(&format!("{}:{}", ISSUER, account));
+        uri.query_pairs_mut()
+            .append_pair("secret", &self.secret)
+            .append_pair("issuer", ISSUER)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &totp::DIGITS.to_string())
+            .append_pair("period", &totp::STEP_SECS.to_string());
+        uri
+    }
+
+    /// Checks a code of the authenticator app, which is not accepted again afterwards
+    pub fn verify_code(&mut self, code: &str, now: &DateTime<Utc>) -> bool {
+        let step = match totp::verify(&self.secret, code, now.timestamp().max(0) as u64) {
+            Some(step) => step,
+            None => return false,
+        };
+        if self.last_step.is_some_and(|last| step <= last) {
+            return false;
+        }
+        self.last_step = Some(step);
+        true
+    }
+
+    /// Confirms the enrollment with a first code of the app
+    pub fn confirm(&mut self, code: &str, now: &DateTime<Utc>) -> bool {
+        if !self.verify_code(code, now) {
+            return false;
+        }
+        self.confirmed = true;
+        true
+    }
+
+    /// Uses up a recovery code
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hash = totp::hash_recovery_code(code);
+        let len = self.recovery_codes.len();
+        self.recovery_codes.retain(|stored| *stored != hash);
+        self.recovery_codes.len() < len
+    }
+
+    /// Checks the second factor of a login, a code of the app or else a recovery code
+    pub fn verify(&mut self, code: &str, now: &DateTime<Utc>) -> bool {
+        let code = code.trim();
+        if code.len() == totp::DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+            self.verify_code(code, now)
+        } else {
+            self.use_recovery_code(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    fn code(totp: &Totp, at: &DateTime<Utc>) -> String {
+        let secret = totp::base32_decode(totp.secret()).unwrap();
+        totp::code_at(&secret, totp::step_at(at.timestamp() as u64))
+    }
+
+    #[test]
+    fn it_accepts_each_code_once() {
+        let (mut totp, _) = Totp::generate().unwrap();
+        let now = Utc.timestamp(1_600_000_000, 0);
+        let current = code(&totp, &now);
+        assert!(totp.confirm(&current, &now));
+        assert!(totp.is_confirmed());
+        assert!(!totp.verify(&current, &now));
+
+        // Codes of the previous step are still within the skew, but older than the last one used
+        let previous = code(&totp, &(now - Duration::seconds(30)));
+        assert!(!totp.verify(&previous, &now));
+        let next = code(&totp, &(now + Duration::seconds(30)));
+        assert!(totp.verify(&next, &(now + Duration::seconds(30))));
+    }
+
+    #[test]
+    fn it_consumes_recovery_codes() {
+        let (mut totp, codes) = Totp::generate().unwrap();
+        let now = Utc::now();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(totp.recovery_codes_left(), RECOVERY_CODES);
+
+        assert!(totp.verify(&codes[0], &now));
+        assert_eq!(totp.recovery_codes_left(), RECOVERY_CODES - 1);
+        assert!(!totp.verify(&codes[0], &now));
+        assert!(totp.verify(&format!(" {} ", codes[1].to_uppercase()), &now));
+        assert_eq!(totp.recovery_codes_left(), RECOVERY_CODES - 2);
+        assert!(!totp.verify("00000-00000-00000-00000", &now));
+    }
+
+    #[test]
+    fn it_describes_the_secret_for_authenticator_apps() {
+        let (totp, _) = Totp::generate().unwrap();
+        let uri = totp.uri("jdoe");
+        assert_eq!(uri.scheme(), "otpauth");
+        assert_eq!(uri.host_str(), Some("totp"));
+        assert_eq!(uri.path(), "/Enseada:jdoe");
+        let secret = uri
+            .query_pairs()
+            .find(|(name, _)| name == "secret")
+            .map(|(_, value)| value.into_owned());
+        assert_eq!(secret.as_deref(), Some(totp.secret()));
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use couchdb::data_migration::{DataMigration, DataMigrationError, MigrationReport};
+use couchdb::db::Database;
+use couchdb::size::SizeGuard;
+
+use crate::user::username;
+
+/// Stores the lowercase username searched by on users registered by earlier versions
+pub async fn backfill_username_search(
+    db: Database,
+    size_guard: Arc<SizeGuard>,
+) -> Result<MigrationReport, DataMigrationError> {
+    DataMigration::new(
+        "backfill_username_search",
+        db,
+        json!({
+            "_id": { "$regex": "^user:" },
+            "username_lower": { "$exists": false },
+        }),
+        backfill_username_lower,
+    )
+    .set_size_guard(size_guard)
+    .run()
+    .await
+}
+
+fn backfill_username_lower(doc: &mut Value) -> Result<bool, String> {
+    let doc = doc.as_object_mut().ok_or("not an object")?;
+    if doc.contains_key("username_lower") {
+        return Ok(false);
+    }
+    let username = match doc.get("_id") {
+        Some(Value::String(id)) => id.strip_prefix("user:").ok_or("not a user")?,
+        _ => return Err("_id is not a string".to_string()),
+    };
+    let username_lower = username.to_lowercase();
+    doc.insert("username_lower".to_string(), Value::String(username_lower));
+    Ok(true)
+}
+
+/// Lists the users registered by earlier versions whose username breaks the username rules,
+/// as failures of its report, without modifying them. They can still log in, but should be
+/// renamed by recreating them.
+pub async fn report_invalid_usernames(
+    db: Database,
+    size_guard: Arc<SizeGuard>,
+) -> Result<MigrationReport, DataMigrationError> {
+    DataMigration::new(
+        "report_invalid_usernames",
+        db,
+        json!({ "_id": { "$regex": "^user:" } }),
+        check_username,
+    )
+    .set_size_guard(size_guard)
+    .run()
+    .await
+}
+
+fn check_username(doc: &mut Value) -> Result<bool, String> {
+    let username = match doc.get("_id") {
+        Some(Value::String(id)) => id.strip_prefix("user:").ok_or("not a user")?,
+        _ => return Err("_id is not a string".to_string()),
+    };
+    if username::is_valid(username) {
+        Ok(false)
+    } else {
+        Err(format!("invalid username {}: {}", username, username::RULE))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_backfills_the_lowercase_username() {
+        let mut doc = json!({ "_id": "user:JDoe", "password_hash": "hash" });
+        assert!(backfill_username_lower(&mut doc).unwrap());
+        assert_eq!(doc["username_lower"], "jdoe");
+        assert!(!backfill_username_lower(&mut doc).unwrap());
+
+        let user: crate::user::User = serde_json::from_value(doc).unwrap();
+        assert_eq!(user.username(), "JDoe");
+
+        let mut doc = json!({ "_id": "role:admin-user:jdoe" });
+        assert!(backfill_username_lower(&mut doc).is_err());
+    }
+
+    #[test]
+    fn it_reports_invalid_usernames_without_changing_them() {
+        let mut doc = json!({ "_id": "user:jdoe", "password_hash": "hash" });
+        assert!(!check_username(&mut doc).unwrap());
+
+        let mut doc = json!({ "_id": "user:JDoe", "password_hash": "hash" });
+        let before = doc.clone();
+        assert!(check_username(&mut doc).unwrap_err().contains("JDoe"));
+        assert_eq!(doc, before);
+    }
+}
@@ -1,7 +1,20 @@
+pub mod api_key;
+pub mod auth;
+pub mod email;
 mod entity;
+pub mod identity;
+pub mod mfa;
+pub mod migration;
+pub mod password;
+pub mod pat;
+pub mod purge;
+pub mod reset;
 mod routes;
 mod service;
+pub mod usage;
+pub mod username;
+pub mod verification;
 
 pub use entity::User;
 pub use routes::*;
-pub use service::UserService;
+pub use service::{LockoutPolicy, UserFilter, UserService};
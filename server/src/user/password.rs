@@ -0,0 +1,282 @@
+//! Requirements on the passwords users choose, and the cost of hashing them.
+//!
+//! The [`PasswordPolicy`] is configured with `ENSEADA_PASSWORD_MINLENGTH`, the character classes
+//! required in `ENSEADA_PASSWORD_CLASSES` and `ENSEADA_PASSWORD_DENYLIST`, rejecting the common
+//! passwords embedded at compile time. It applies to registrations, password changes and resets.
+//! Lengths are counted in characters, not bytes.
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use enseada::secure::{self, HashParams};
+
+use crate::config::CONFIG;
+
+/// Fewest characters a password can have by default
+pub const MIN_LENGTH: usize = 8;
+/// Most characters a password can have, bounding the cost of hashing it
+pub const MAX_LENGTH: usize = 1024;
+
+lazy_static! {
+    /// Common passwords long enough to pass the minimum length, one per line and lowercase
+    static ref DENYLIST: HashSet<&'static str> = include_str!("common_passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|password| !password.is_empty())
+        .collect();
+}
+
+/// Kind of characters a password can be required to contain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CharClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    fn matches(self, c: char) -> bool {
+        match self {
+            CharClass::Lowercase => c.is_lowercase(),
+            CharClass::Uppercase => c.is_uppercase(),
+            CharClass::Digit => c.is_numeric(),
+            CharClass::Symbol => !c.is_alphanumeric() && !c.is_whitespace(),
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            CharClass::Lowercase => "a lowercase letter",
+            CharClass::Uppercase => "an uppercase letter",
+            CharClass::Digit => "a digit",
+            CharClass::Symbol => "a symbol",
+        }
+    }
+}
+
+impl FromStr for CharClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowercase" => Ok(CharClass::Lowercase),
+            "uppercase" => Ok(CharClass::Uppercase),
+            "digit" => Ok(CharClass::Digit),
+            "symbol" => Ok(CharClass::Symbol),
+            _ => Err(format!("unknown password character class '{}'", s)),
+        }
+    }
+}
+
+impl Display for CharClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CharClass::Lowercase => "lowercase",
+            CharClass::Uppercase => "uppercase",
+            CharClass::Digit => "digit",
+            CharClass::Symbol => "symbol",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses a comma-separated list of character classes, like `lowercase,digit`
+pub fn parse_classes(classes: &str) -> Result<Vec<CharClass>, String> {
+    classes
+        .split(',')
+        .map(str::trim)
+        .filter(|class| !class.is_empty())
+        .map(CharClass::from_str)
+        .collect()
+}
+
+/// Rules the passwords users choose must follow
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    classes: Vec<CharClass>,
+    denylist: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: MIN_LENGTH,
+            classes: Vec::new(),
+            denylist: true,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn new(min_length: usize, classes: Vec<CharClass>, denylist: bool) -> Self {
+        PasswordPolicy {
+            min_length,
+            classes,
+            denylist,
+        }
+    }
+
+    /// Reasons the password does not meet the policy, one per failed rule, none if it does
+    pub fn violations(&self, username: &str, password: &str) -> Vec<String> {
+        let mut reasons = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            reasons.push(format!(
+                "password must be at least {} characters long",
+                self.min_length
+            ));
+        }
+        if length > MAX_LENGTH {
+            reasons.push(format!(
+                "password must be at most {} characters long",
+                MAX_LENGTH
+            ));
+        }
+        if password.trim().is_empty() {
+            reasons.push("password must not be blank".to_string());
+        }
+        for class in &self.classes {
+            if !password.chars().any(|c| class.matches(c)) {
+                reasons.push(format!("password must contain {}", class.description()));
+            }
+        }
+        let lowercase = password.to_lowercase();
+        if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+            reasons.push("password must not contain the username".to_string());
+        }
+        if self.denylist && DENYLIST.contains(lowercase.as_str()) {
+            reasons.push("password is too common".to_string());
+        }
+        reasons
+    }
+}
+
+/// Reasons the password does not meet the configured policy, none if it does
+pub fn violations(username: &str, password: &str) -> Vec<String> {
+    CONFIG.password().policy().violations(username, password)
+}
+
+/// Hashes new passwords with the configured parameters, warning if that makes logins too slow
+pub fn configure() {
+    let password = CONFIG.password();
+    let params = password.hash_params();
+    secure::set_hash_params(params);
+    check_budget(&params, password.budget());
+}
+
+fn check_budget(params: &HashParams, budget: Duration) -> bool {
+    match secure::measure_hash(params) {
+        Ok(elapsed) if elapsed > budget => {
+            log::warn!(
+                "Hashing a password with {:?} takes {}ms, over the budget of {}ms. Logins will be slow, consider lowering the memory or iterations",
+                params,
+                elapsed.as_millis(),
+                budget.as_millis()
+            );
+            false
+        }
+        Ok(elapsed) => {
+            log::debug!("Hashing a password takes {}ms", elapsed.as_millis());
+            true
+        }
+        Err(err) => {
+            log::error!("Failed to hash a password with {:?}: {}", params, err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn violations(username: &str, password: &str) -> Vec<String> {
+        PasswordPolicy::default().violations(username, password)
+    }
+
+    #[test]
+    fn it_accepts_long_enough_passwords() {
+        assert!(violations("jdoe", "correct horse").is_empty());
+        assert!(violations("jdoe", &"a".repeat(MAX_LENGTH)).is_empty());
+    }
+
+    #[test]
+    fn it_rejects_weak_passwords() {
+        assert_eq!(violations("jdoe", "secret").len(), 1);
+        assert_eq!(violations("jdoe", &"a".repeat(MAX_LENGTH + 1)).len(), 1);
+        assert_eq!(violations("jdoe", "        ").len(), 1);
+        assert_eq!(violations("jdoe1234", "JDoe1234").len(), 1);
+    }
+
+    #[test]
+    fn it_counts_characters_rather_than_bytes() {
+        // 4 characters taking 16 bytes
+        assert_eq!(
+            violations("jdoe", "\u{1F512}\u{1F511}\u{1F510}\u{1F513}"),
+            vec!["password must be at least 8 characters long".to_string()]
+        );
+        // 8 characters taking 16 bytes
+        assert!(violations("jdoe", "éèêëàâäç").is_empty());
+        let policy = PasswordPolicy::new(MIN_LENGTH, Vec::new(), false);
+        assert!(policy
+            .violations("jdoe", &"é".repeat(MAX_LENGTH))
+            .is_empty());
+        assert_eq!(
+            policy.violations("jdoe", &"é".repeat(MAX_LENGTH + 1)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn it_lists_each_failed_rule() {
+        let classes = parse_classes("lowercase, uppercase,digit,symbol").unwrap();
+        let policy = PasswordPolicy::new(12, classes, true);
+        assert_eq!(
+            policy.violations("jdoe", "xjdoex"),
+            vec![
+                "password must be at least 12 characters long".to_string(),
+                "password must contain an uppercase letter".to_string(),
+                "password must contain a digit".to_string(),
+                "password must contain a symbol".to_string(),
+                "password must not contain the username".to_string(),
+            ]
+        );
+        assert!(policy.violations("jdoe", "Ünïcödé-Pässwörd-1").is_empty());
+    }
+
+    #[test]
+    fn it_rejects_common_passwords() {
+        assert_eq!(
+            violations("jdoe", "Password1"),
+            vec!["password is too common".to_string()]
+        );
+        let policy = PasswordPolicy::new(MIN_LENGTH, Vec::new(), false);
+        assert!(policy.violations("jdoe", "Password1").is_empty());
+    }
+
+    #[test]
+    fn it_parses_character_classes() {
+        assert_eq!(
+            parse_classes("digit,,symbol").unwrap(),
+            vec![CharClass::Digit, CharClass::Symbol]
+        );
+        assert!(parse_classes("").unwrap().is_empty());
+        assert!(parse_classes("emoji").is_err());
+    }
+
+    /// Small instances can run the tests with their own budget, in milliseconds
+    #[test]
+    fn it_hashes_within_the_latency_budget() {
+        let budget = std::env::var("ENSEADA_PASSWORD_BUDGET")
+            .ok()
+            .and_then(|budget| budget.parse().ok())
+            .unwrap_or(1000);
+        assert!(check_budget(
+            &HashParams::default(),
+            Duration::from_millis(budget)
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+//! Personal access tokens, long-lived tokens users create for scripts instead of going
+//! through an OAuth flow. Only a signature of the token is stored, in the oauth database.
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+use enseada::guid::Guid;
+use enseada::pagination::{Cursor, Page};
+use enseada::secure;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::oauth::scope::Scope;
+use crate::oauth::session::Session;
+
+/// Prefix of every personal access token, telling them apart from OAuth access tokens
+pub const PAT_PREFIX: &str = "enseada_pat_";
+
+/// Client ID of the sessions of personal access tokens
+pub const PAT_CLIENT_ID: &str = "personal_access_token";
+
+/// Tokens deleted at once when deleting all the tokens of a user
+const BATCH_SIZE: usize = 200;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PersonalAccessToken {
+    #[serde(rename = "_id")]
+    guid: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    /// Public identifier, as the document ID is derived from the token
+    id: String,
+    user_id: String,
+    label: String,
+    scope: Scope,
+    created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl PersonalAccessToken {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    pub fn created_at(&self) -> &DateTime<Utc> {
+        &self.created_at
+    }
+
+    pub fn expires_at(&self) -> Option<&DateTime<Utc>> {
+        self.expires_at.as_ref()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// Session the token authenticates requests with, like an OAuth access token
+    pub fn session(&self) -> Session {
+        let mut session = Session::for_client(PAT_CLIENT_ID.to_string());
+        session
+            .set_user_id(self.user_id.clone())
+            .set_scope(self.scope.clone())
+            .set_issued_at(Some(self.created_at))
+            .set_expires_at(self.expires_at);
+        session
+    }
+}
+
+impl Entity for PersonalAccessToken {
+    fn build_guid(sig: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::PAT, sig))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.guid
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+pub struct PatService {
+    db: Database,
+    secret_key: String,
+}
+
+#[async_trait]
+impl Repository<PersonalAccessToken> for PatService {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl PatService {
+    pub fn new(db: Database, secret_key: String) -> Self {
+        PatService { db, secret_key }
+    }
+
+    /// Creates a token for the user, returning it along with its value, which is not stored
+    pub async fn create(
+        &self,
+        user_id: &Guid,
+        label: String,
+        scope: Scope,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(PersonalAccessToken, String), Error> {
+        let secret = secure::generate_token(32).map_err(Error::internal)?;
+        let value = format!("{}{}", PAT_PREFIX, secret);
+        let pat = PersonalAccessToken {
+            guid: PersonalAccessToken::build_guid(&self.signature(&value)),
+            rev: None,
+            id: Uuid::new_v4().to_simple().to_string(),
+            user_id: user_id.to_string(),
+            label,
+            scope,
+            created_at: Utc::now(),
+            expires_at,
+        };
+        let pat = self.save(pat).await?;
+        Ok((pat, value))
+    }
+
+    /// Looks up a token by its value
+    pub async fn find_by_token(&self, value: &str) -> Result<Option<PersonalAccessToken>, Error> {
+        self.find(&self.signature(value)).await
+    }
+
+    pub async fn list(
+        &self,
+        user_id: &Guid,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<PersonalAccessToken>, Error> {
+        let selector = serde_json::json!({ "user_id": user_id.to_string() });
+        let res = self
+            .find_in_partition(selector, limit, cursor.map(Cursor::to_string))
+            .await?;
+        if let Some(warning) = &res.warning {
+            log::warn!("{}", warning);
+        }
+        Ok(Page::from_find_response(res, limit))
+    }
+
+    /// Deletes a token of the user by its public ID, returning false if there is none
+    pub async fn delete_for_user(&self, user_id: &Guid, id: &str) -> Result<bool, Error> {
+        let selector = serde_json::json!({ "user_id": user_id.to_string(), "id": id });
+        let res = self.find_in_partition(selector, 1, None).await?;
+        match res.docs.first() {
+            Some(pat) => {
+                self.delete(pat).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Every token of the user, which [`delete_all_for_user`] would delete
+    ///
+    /// [`delete_all_for_user`]: PatService::delete_all_for_user
+    pub async fn list_all_for_user(
+        &self,
+        user_id: &Guid,
+    ) -> Result<Vec<PersonalAccessToken>, Error> {
+        let selector = serde_json::json!({ "user_id": user_id.to_string() });
+        let mut all = Vec::new();
+        let mut bookmark = None;
+        loop {
+            let res = self
+                .find_in_partition(selector.clone(), BATCH_SIZE, bookmark)
+                .await?;
+            let done = res.docs.len() < BATCH_SIZE;
+            all.extend(res.docs);
+            if done {
+                return Ok(all);
+            }
+            bookmark = Some(res.bookmark);
+        }
+    }
+
+    /// Deletes all the tokens of the user, returning how many there were
+    pub async fn delete_all_for_user(&self, user_id: &Guid) -> Result<usize, Error> {
+        let selector = serde_json::json!({ "user_id": user_id.to_string() });
+        let mut deleted = 0;
+        // Deleted documents no longer match, so the first batch is queried until it runs out
+        loop {
+            let res = self
+                .find_in_partition(selector.clone(), BATCH_SIZE, None)
+                .await?;
+            for pat in &res.docs {
+                self.delete(pat).await?;
+                deleted += 1;
+            }
+            if res.docs.len() < BATCH_SIZE {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    fn signature(&self, value: &str) -> String {
+        secure::generate_signature(value, &self.secret_key).to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn pat(expires_at: Option<DateTime<Utc>>) -> PersonalAccessToken {
+        PersonalAccessToken {
+            guid: PersonalAccessToken::build_guid("sig"),
+            rev: None,
+            id: "abc".to_string(),
+            user_id: "user:jdoe".to_string(),
+            label: "ci".to_string(),
+            scope: Scope::from("users:read"),
+            created_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn it_authenticates_as_the_user_with_the_token_scope() {
+        let session = pat(None).session();
+        assert_eq!(session.client_id(), PAT_CLIENT_ID);
+        assert_eq!(session.user_id().as_deref(), Some("user:jdoe"));
+        assert_eq!(session.scope(), &Scope::from("users:read"));
+    }
+
+    #[test]
+    fn it_expires_only_with_an_expiry() {
+        assert!(!pat(None).is_expired());
+        assert!(!pat(Some(Utc::now() + Duration::days(1))).is_expired());
+        assert!(pat(Some(Utc::now() - Duration::seconds(1))).is_expired());
+        assert!(pat(Some(Utc::now() - Duration::seconds(1))).session().is_expired());
+    }
+}
@@ -0,0 +1,506 @@
+//! Deletion of users for good, along with what refers to them.
+//!
+//! Deleting a user only deactivates them by default, so that audit trails and the clients they
+//! own keep pointing at an existing user. They are purged on request, or by the [`UserPurge`] job
+//! once they have been deactivated for longer than the configured retention.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use enseada::error::Error;
+use enseada::guid::Guid;
+
+use crate::config::CONFIG;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::group::GroupService;
+use crate::http::dry_run::Plan;
+use crate::jobs::Job;
+use crate::oauth::persistence::CouchStorage;
+use crate::oauth::storage::ClientStorage;
+use crate::user::api_key::ApiKeyService;
+use crate::user::pat::PatService;
+use crate::user::{User, UserFilter, UserService};
+
+/// Attempts at revoking the tokens of a user
+const REVOKE_ATTEMPTS: usize = 3;
+/// Wait before the second attempt, growing with each attempt
+const REVOKE_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Users purged by each run of the job, the next runs purge the rest
+const PURGE_BATCH: usize = 100;
+
+/// What deleting a user changes, computed by [`plan_deletion`] without writing anything so that
+/// it can be reported on a dry run, then carried out by [`apply_deletion`]
+pub struct DeletionPlan {
+    user: User,
+    purge: bool,
+    /// Whether the clients of a purged user are deleted rather than orphaned
+    cascade: bool,
+    tokens: Vec<String>,
+    pats: Vec<String>,
+    api_keys: Vec<String>,
+    groups: Vec<String>,
+    clients: Vec<String>,
+}
+
+impl DeletionPlan {
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn is_purge(&self) -> bool {
+        self.purge
+    }
+
+    /// Every change of the deletion, in the order they are applied
+    pub fn describe(&self) -> Plan {
+        let user_id = self.user.id().to_string();
+        let mut steps = vec![
+            Plan::new("users:deactivate", vec![user_id.clone()]),
+            Plan::new("tokens:revoke", self.tokens.clone()),
+            Plan::new("pats:delete", self.pats.clone()),
+            Plan::new("api_keys:delete", self.api_keys.clone()),
+        ];
+        if !self.purge {
+            return Plan::of_steps("users:deactivate", steps);
+        }
+        steps.push(Plan::new("groups:remove_member", self.groups.clone()));
+        steps.push(Plan::new("users:delete", vec![user_id]));
+        steps.push(Plan::new(
+            "emails:release",
+            self.user.email().map(str::to_string),
+        ));
+        let clients = if self.cascade {
+            "clients:delete"
+        } else {
+            "clients:orphan"
+        };
+        steps.push(Plan::new(clients, self.clients.clone()));
+        Plan::of_steps("users:delete", steps)
+    }
+}
+
+/// Looks up what deactivating the user would change and, if purging them, what deleting them
+/// would, only reading
+pub async fn plan_deletion(
+    tokens: &CouchStorage,
+    pats: &PatService,
+    keys: &ApiKeyService,
+    groups: &GroupService,
+    user: User,
+    purge: bool,
+    cascade: bool,
+) -> Result<DeletionPlan, Error> {
+    let user_id = user.id();
+    let token_sigs = tokens
+        .user_token_signatures(&user_id.to_string())
+        .await
+        .map_err(|err| Error::from(err.description()))?;
+    let pat_ids = pats.list_all_for_user(user_id).await?;
+    let key_ids = keys.list_all_for_user(user_id).await?;
+    let (group_names, client_ids) = if purge {
+        let owned = tokens
+            .clients_owned_by(&user_id.to_string())
+            .await
+            .map_err(|err| Error::from(err.description()))?;
+        let member_of = groups.groups_of(user.username()).await?;
+        (
+            member_of
+                .iter()
+                .map(|group| group.name().to_string())
+                .collect(),
+            owned
+                .iter()
+                .map(|client| client.client_id().to_string())
+                .collect(),
+        )
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    Ok(DeletionPlan {
+        purge,
+        cascade,
+        tokens: token_sigs,
+        pats: pat_ids.iter().map(|pat| pat.id().to_string()).collect(),
+        api_keys: key_ids.iter().map(|key| key.key_id().to_string()).collect(),
+        groups: group_names,
+        clients: client_ids,
+        user,
+    })
+}
+
+/// Carries out the deletion: deactivates the user, revokes their access and, for a purge,
+/// deletes them. Returns how many tokens and keys were revoked.
+pub async fn apply_deletion(
+    plan: &DeletionPlan,
+    users: &UserService,
+    tokens: &CouchStorage,
+    pats: &PatService,
+    keys: &ApiKeyService,
+    groups: &GroupService,
+) -> Result<usize, Error> {
+    // Deactivated first so that no tokens are issued meanwhile, and revoked before the user is
+    // purged: if revoking fails, the user is left deactivated and deleting it again resumes
+    let now = Utc::now();
+    let username = plan.user.username();
+    let user = users
+        .patch(username, |user| {
+            user.deactivate(now);
+            true
+        })
+        .await?
+        .ok_or_else(|| Error::not_found("user", username))?;
+    let revoked = revoke_access(tokens, pats, keys, &user).await?;
+    log::info!(
+        "Revoked {} tokens of user {} while deleting them",
+        revoked,
+        user.username()
+    );
+
+    if plan.purge {
+        purge(users, tokens, groups, &user).await?;
+    }
+    Ok(revoked)
+}
+
+/// Revokes the OAuth tokens of a user and deletes their personal access tokens and API keys,
+/// trying again a few times, as the user must not be purged while some are left.
+/// Returns how many tokens and keys there were.
+pub async fn revoke_access(
+    tokens: &CouchStorage,
+    pats: &PatService,
+    keys: &ApiKeyService,
+    user: &User,
+) -> Result<usize, Error> {
+    let user_id = user.id();
+    let mut attempt = 1;
+    loop {
+        let res = match tokens.revoke_user_tokens(&user_id.to_string(), None).await {
+            Ok(revoked) => delete_credentials(pats, keys, user_id)
+                .await
+                .map(|deleted| revoked + deleted)
+                .map_err(Error::from),
+            Err(err) => Err(Error::from(err.description())),
+        };
+        match res {
+            Ok(revoked) => return Ok(revoked),
+            Err(err) if attempt < REVOKE_ATTEMPTS => {
+                log::warn!(
+                    "Failed to revoke the tokens of user {}, retrying: {}",
+                    user.username(),
+                    err
+                );
+                actix_rt::time::delay_for(REVOKE_RETRY_DELAY * attempt as u32).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to revoke the tokens of user {}, who is left deactivated: {}",
+                    user.username(),
+                    err
+                );
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Deletes the personal access tokens and API keys of a user, returning how many there were
+async fn delete_credentials(
+    pats: &PatService,
+    keys: &ApiKeyService,
+    user_id: &Guid,
+) -> Result<usize, couchdb::error::Error> {
+    let pats = pats.delete_all_for_user(user_id).await?;
+    let keys = keys.delete_all_for_user(user_id).await?;
+    Ok(pats + keys)
+}
+
+/// Deletes a user whose tokens are revoked, after removing them from their groups so that a user
+/// registered later with the same username does not inherit them. Their email address is released
+/// and their clients deleted or orphaned, depending on the config.
+pub async fn purge(
+    users: &UserService,
+    tokens: &CouchStorage,
+    groups: &GroupService,
+    user: &User,
+) -> Result<(), Error> {
+    let left = groups.remove_member_everywhere(user.username()).await?;
+    log::info!("Removed user {} from {} groups", user.username(), left);
+
+    users.delete(user).await?;
+    if let Some(address) = user.email() {
+        if let Err(err) = users.release_email(user.username(), address).await {
+            log::warn!(
+                "Failed to release the email address of purged user {}: {}",
+                user.username(),
+                err
+            );
+        }
+    }
+    release_clients(tokens, user).await
+}
+
+/// Deletes the clients of a purged user, or flags them as orphaned, depending on the config
+async fn release_clients(storage: &CouchStorage, user: &User) -> Result<(), Error> {
+    let cascade = CONFIG.oauth().clients().cascade();
+    let clients = storage
+        .clients_owned_by(&user.id().to_string())
+        .await
+        .map_err(|err| Error::from(err.description()))?;
+    for mut client in clients {
+        let res = if cascade {
+            log::info!(
+                "Deleting client '{}' of purged user {}",
+                client.client_id(),
+                user.username()
+            );
+            storage.delete_client(&client).await
+        } else {
+            log::info!(
+                "Orphaning client '{}' of purged user {}",
+                client.client_id(),
+                user.username()
+            );
+            client.orphan();
+            storage.save_client(client).await.map(|_| ())
+        };
+        res.map_err(|err| Error::from(err.description()))?;
+    }
+    Ok(())
+}
+
+/// Purges the users deactivated for longer than the retention
+pub struct UserPurge {
+    users: UserService,
+    tokens: Arc<CouchStorage>,
+    pats: PatService,
+    keys: ApiKeyService,
+    groups: GroupService,
+    retention: chrono::Duration,
+}
+
+impl UserPurge {
+    pub fn new(
+        users: UserService,
+        tokens: Arc<CouchStorage>,
+        pats: PatService,
+        keys: ApiKeyService,
+        groups: GroupService,
+        retention: chrono::Duration,
+    ) -> Self {
+        UserPurge {
+            users,
+            tokens,
+            pats,
+            keys,
+            groups,
+            retention,
+        }
+    }
+}
+
+#[async_trait]
+impl Job for UserPurge {
+    fn name(&self) -> &str {
+        "user-purge"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    async fn run(&self) -> Result<(), Error> {
+        let before = Utc::now() - self.retention;
+        let filter = UserFilter {
+            deactivated_before: Some(before),
+            ..Default::default()
+        };
+        let page = self.users.list_filtered(&filter, PURGE_BATCH, None).await?;
+        let mut failed = 0;
+        for user in page.items() {
+            let res = match revoke_access(&self.tokens, &self.pats, &self.keys, user).await {
+                Ok(_) => purge(&self.users, &self.tokens, &self.groups, user).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to purge user {}: {}", user.username(), err);
+                failed += 1;
+            }
+        }
+        log::info!(
+            "Purged {} users deactivated before {}",
+            page.items().len() - failed,
+            before
+        );
+        if failed > 0 {
+            return Err(Error::from(format!("failed to purge {} users", failed)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use couchdb::client::AuthMethod;
+    use couchdb::Couch;
+    use serde_json::json;
+    use url::Url;
+
+    use super::*;
+
+    /// Documents of jdoe found by the queries of a given partition
+    fn docs_of(partition: &str, body: &str) -> serde_json::Value {
+        let session = json!({ "client_id": "cli", "scope": "profile", "user_id": "user:jdoe" });
+        match partition {
+            // Refresh tokens of earlier versions are queried in this partition as well
+            "access_token" if !body.contains("\"$exists\":true") => json!([{
+                "_id": "access_token:at1",
+                "session": session,
+                "expiration": 4102444800u64,
+            }]),
+            "refresh_token" => json!([{
+                "_id": "refresh_token:rt1",
+                "session": session,
+                "expiration": 4102444800u64,
+                "related_access_token_signature": "at1",
+            }]),
+            "pat" => json!([{
+                "_id": "pat:sig",
+                "id": "pat1",
+                "user_id": "user:jdoe",
+                "label": "ci",
+                "scope": "profile",
+                "created_at": "2020-05-01T10:00:00Z",
+            }]),
+            "apikey" => json!([{
+                "_id": "apikey:key1",
+                "key_id": "key1",
+                "user_id": "user:jdoe",
+                "label": "ci",
+                "secret_signature": "sig",
+                "scope": "profile",
+                "created_at": "2020-05-01T10:00:00Z",
+            }]),
+            "group" => json!([{ "_id": "group:devs", "name": "devs", "members": ["jdoe"] }]),
+            "client" => json!([{
+                "_id": "client:cli",
+                "kind": "public",
+                "allowed_scopes": "profile",
+                "allowed_redirect_uris": [],
+                "owner": "user:jdoe",
+            }]),
+            _ => json!([]),
+        }
+    }
+
+    /// Serves a CouchDB answering Mango queries with the documents of jdoe, and recording every
+    /// request that would write
+    fn serve_couch(writes: Arc<Mutex<Vec<String>>>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut reader = BufReader::new(conn.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim_end().is_empty() {
+                        break;
+                    }
+                    let header = header.to_lowercase();
+                    if let Some(value) = header.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8(body).unwrap();
+
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap();
+                let path = parts.next().unwrap().split('?').next().unwrap();
+                let is_query = method == "POST" && path.ends_with("/_find");
+                let (status, res) = if is_query {
+                    let partition = path.split('/').nth(3).unwrap_or_default();
+                    let docs = docs_of(partition, &body);
+                    ("200 OK", json!({ "docs": docs, "bookmark": "nil" }))
+                } else {
+                    if method != "GET" && method != "HEAD" {
+                        writes.lock().unwrap().push(format!("{} {}", method, path));
+                    }
+                    (
+                        "404 Not Found",
+                        json!({ "error": "not_found", "reason": "missing" }),
+                    )
+                };
+                let res = res.to_string();
+                write!(
+                    conn,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    res.len(),
+                    res
+                )
+                .unwrap();
+            }
+        });
+        Url::parse(&url).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn it_plans_a_purge_without_writing() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut couch = Couch::new(
+            serve_couch(writes.clone()),
+            "enseada".to_string(),
+            "enseada".to_string(),
+        );
+        couch.set_auth_method(AuthMethod::Basic);
+        let tokens = CouchStorage::new(Arc::new(couch.database("oauth", true)));
+        let pats = PatService::new(couch.database("oauth", true), "secret".to_string());
+        let keys = ApiKeyService::new(couch.database("oauth", true), "secret".to_string());
+        let groups = GroupService::new(couch.database("rbac", true));
+        let mut user = User::new("jdoe".to_string(), "correct horse".to_string()).unwrap();
+        user.set_email(Some("jdoe@example.com".to_string()));
+
+        let plan = plan_deletion(&tokens, &pats, &keys, &groups, user, true, false)
+            .await
+            .unwrap()
+            .describe();
+
+        assert!(writes.lock().unwrap().is_empty());
+        assert_eq!(plan.action, "users:delete");
+        assert_eq!(plan.count, 9);
+        let steps: Vec<(&str, usize)> = plan
+            .steps
+            .iter()
+            .map(|step| (step.action.as_str(), step.count))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                ("users:deactivate", 1),
+                ("tokens:revoke", 2),
+                ("pats:delete", 1),
+                ("api_keys:delete", 1),
+                ("groups:remove_member", 1),
+                ("users:delete", 1),
+                ("emails:release", 1),
+                ("clients:orphan", 1),
+            ]
+        );
+        assert_eq!(plan.steps[1].sample_ids, vec!["at1", "rt1"]);
+        assert_eq!(plan.steps[2].sample_ids, vec!["pat1"]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Self-service password resets, for users who forgot their password.
+//!
+//! Users ask for a reset by username or email address, and are emailed a single-use token
+//! that lets them set a new password for an hour. Like verifications of email addresses,
+//! only a signature of the token is stored, in the users database.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+use enseada::guid::Guid;
+use enseada::secure;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::mail::{Email, Mailer};
+use crate::user::User;
+
+/// How long a reset token can be used for after it is sent
+pub const RESET_TTL_MINUTES: i64 = 60;
+/// Pending resets of a user fetched at once when replacing them with a new one
+const PENDING_BATCH_SIZE: usize = 10;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PasswordReset {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    username: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl PasswordReset {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn is_expired(&self, now: &DateTime<Utc>) -> bool {
+        self.expires_at <= *now
+    }
+}
+
+impl Entity for PasswordReset {
+    fn build_guid(sig: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::PASSWORD_RESET, sig))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+pub struct ResetService {
+    db: Database,
+    secret_key: String,
+    mailer: Option<Arc<dyn Mailer>>,
+}
+
+#[async_trait]
+impl Repository<PasswordReset> for ResetService {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl ResetService {
+    pub fn new(db: Database, secret_key: String) -> Self {
+        ResetService {
+            db,
+            secret_key,
+            mailer: None,
+        }
+    }
+
+    /// Mailer tokens are sent with. Without one, users cannot reset their password themselves.
+    pub fn set_mailer(&mut self, mailer: Option<Arc<dyn Mailer>>) -> &mut Self {
+        self.mailer = mailer;
+        self
+    }
+
+    /// Emails a reset token to the user, replacing any pending one.
+    /// Returns false without creating a token if there is no mailer or the user has no address.
+    pub async fn request(&self, user: &User) -> Result<bool, enseada::error::Error> {
+        let (mailer, address) = match (&self.mailer, user.email()) {
+            (Some(mailer), Some(address)) => (mailer, address),
+            _ => return Ok(false),
+        };
+        self.delete_pending(user.username()).await?;
+
+        let token = secure::generate_token(32)?.to_string();
+        let reset = PasswordReset {
+            id: PasswordReset::build_guid(&self.signature(&token)),
+            rev: None,
+            username: user.username().to_string(),
+            expires_at: Utc::now() + Duration::minutes(RESET_TTL_MINUTES),
+        };
+        let reset = self.save(reset).await?;
+        mailer.send(reset_email(&reset, address, &token)).await?;
+        Ok(true)
+    }
+
+    /// Looks up an unexpired reset by its token, without using it up
+    pub async fn find_by_token(&self, token: &str) -> Result<Option<PasswordReset>, Error> {
+        let reset = self.find(&self.signature(token)).await?;
+        Ok(reset.filter(|reset| !reset.is_expired(&Utc::now())))
+    }
+
+    /// Uses up a reset, returning false if it was used concurrently
+    pub async fn consume(&self, reset: &PasswordReset) -> Result<bool, Error> {
+        match self.delete(reset).await {
+            Ok(()) => Ok(true),
+            Err(err) if err.status() == StatusCode::CONFLICT => Ok(false),
+            Err(err) if err.status() == StatusCode::NOT_FOUND => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn delete_pending(&self, username: &str) -> Result<(), Error> {
+        loop {
+            let res = self
+                .db
+                .find_partitioned::<PasswordReset>(
+                    "reset",
+                    serde_json::json!({ "username": username }),
+                    PENDING_BATCH_SIZE,
+                    None,
+                )
+                .await?;
+            if let Some(warning) = &res.warning {
+                log::warn!("{}", warning);
+            }
+            for reset in &res.docs {
+                self.delete(reset).await?;
+            }
+            if res.docs.len() < PENDING_BATCH_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    fn signature(&self, token: &str) -> String {
+        secure::generate_signature(token, &self.secret_key).to_string()
+    }
+}
+
+fn reset_email(reset: &PasswordReset, address: &str, token: &str) -> Email {
+    Email {
+        to: address.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+            Someone asked to reset the password of your Enseada account. If it was you, \
+            set a new password with this token:\n\n\
+            {}\n\n\
+            by sending it to POST /api/v1beta1/password-resets/{{token}}.\n\n\
+            The token expires on {}. If you did not ask for it, you can ignore this email, \
+            your password stays the same.\n",
+            reset.username,
+            token,
+            reset.expires_at.format("%Y-%m-%d %H:%M UTC"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reset(expires_at: DateTime<Utc>) -> PasswordReset {
+        PasswordReset {
+            id: PasswordReset::build_guid("sig"),
+            rev: None,
+            username: "jdoe".to_string(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn it_expires_after_its_ttl() {
+        let now = Utc::now();
+        assert!(!reset(now + Duration::minutes(RESET_TTL_MINUTES)).is_expired(&now));
+        assert!(reset(now).is_expired(&now));
+    }
+
+    #[test]
+    fn it_emails_the_token_to_the_address() {
+        let email = reset_email(&reset(Utc::now()), "jdoe@example.com", "abc123");
+        assert_eq!(email.to, "jdoe@example.com");
+        assert!(email.body.contains("abc123"));
+        assert!(email.body.contains("/password-resets/{token}"));
+    }
+}
@@ -1,41 +1,152 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::net::IpAddr;
+use std::sync::Arc;
+
 use actix_web::web::{Data, Json, Path, Query, ServiceConfig};
-use actix_web::{delete, get, post, HttpResponse};
+use actix_web::{delete, get, post, put, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDate, Utc};
+use http::StatusCode;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
+use enseada::error::Error;
 use enseada::guid::Guid;
 use enseada::pagination::{Cursor, Page};
 
+use crate::config::CONFIG;
+use crate::couchdb::partition;
 use crate::couchdb::repository::{Entity, Repository};
+use crate::group::GroupService;
+use crate::http::dry_run::{self, DryRunQuery};
 use crate::http::error::ApiError;
-use crate::http::extractor::{scope::Scope, user::CurrentUser};
+use crate::http::extractor::{scope::Scope, session::TokenSession, user::CurrentUser};
+use crate::http::urls::UrlBuilder;
 use crate::http::{ApiResult, PaginationQuery};
-use crate::rbac::Enforcer;
+use crate::oauth::audit::{self, AuditAction, AuditEvent};
+use crate::oauth::facade::Oauth;
+use crate::oauth::handler::MAX_IMPERSONATION_MINUTES;
+use crate::oauth::persistence::token::{AccessTokenEntity, RefreshTokenEntity};
+use crate::oauth::persistence::CouchStorage;
+use crate::oauth::response::TokenResponse;
+use crate::oauth::scope::Scope as OAuthScope;
+use crate::oauth::session::Session as OAuthSession;
+use crate::oauth::token::TokenTypeHint;
+use crate::rbac::{Enforcer, ADMIN_ROLE};
 use crate::responses;
-use crate::user::{User, UserService};
+use crate::user::api_key::{ApiKey, ApiKeyService, API_KEY_CLIENT_ID};
+use crate::user::auth::AuthenticatorChain;
+use crate::user::mfa::Totp;
+use crate::user::pat::{PatService, PersonalAccessToken, PAT_CLIENT_ID};
+use crate::user::purge;
+use crate::user::reset::ResetService;
+use crate::user::usage::{QuotaWarning, Usage, UsageTracker};
+use crate::user::verification::VerificationService;
+use crate::user::{email, password, username, LockoutPolicy, User, UserFilter, UserService};
 
 pub fn mount(cfg: &mut ServiceConfig) {
     let couch = &crate::couchdb::SINGLETON;
     let db = couch.database(crate::couchdb::name::USERS, true);
-    let service = UserService::new(db);
+    let mut service = UserService::new(db.clone());
+    let lockout = CONFIG.oauth().lockout();
+    service.set_lockout(LockoutPolicy {
+        failures: lockout.failures(),
+        duration: lockout.duration(),
+    });
+    match AuthenticatorChain::from_config(&CONFIG.auth().backends(), &db, CONFIG.ldap()) {
+        Ok(authenticators) => {
+            service.set_authenticators(authenticators);
+        }
+        Err(err) => log::error!("Failed to configure the authentication backends: {}", err),
+    }
     cfg.data(service);
+    let oauth_db = couch.database(crate::couchdb::name::OAUTH, true);
+    cfg.data(PatService::new(oauth_db.clone(), CONFIG.secret_key()));
+    cfg.data(ApiKeyService::new(oauth_db, CONFIG.secret_key()));
+    let mailer = crate::mail::from_config(CONFIG.mail());
+    let mut verifications = VerificationService::new(
+        couch.database(crate::couchdb::name::USERS, true),
+        CONFIG.secret_key(),
+        CONFIG.mail().verification().ttl(),
+    );
+    verifications.set_mailer(mailer.clone());
+    cfg.data(verifications);
+    let mut resets = ResetService::new(
+        couch.database(crate::couchdb::name::USERS, true),
+        CONFIG.secret_key(),
+    );
+    resets.set_mailer(mailer);
+    cfg.data(resets);
     cfg.service(me);
+    cfg.service(my_usage);
+    cfg.service(change_password);
+    cfg.service(verify_email);
+    cfg.service(enroll_totp);
+    cfg.service(confirm_totp);
+    cfg.service(disable_totp);
+    cfg.service(list_sessions);
+    cfg.service(revoke_session);
+    cfg.service(revoke_other_sessions);
+    cfg.service(list_pats);
+    cfg.service(create_pat);
+    cfg.service(delete_pat);
+    cfg.service(list_api_keys);
+    cfg.service(create_api_key);
+    cfg.service(delete_api_key);
     cfg.service(list);
     cfg.service(register);
     cfg.service(get);
+    cfg.service(update);
+    cfg.service(set_enabled);
     cfg.service(delete);
+    cfg.service(reactivate);
+    cfg.service(impersonate);
+    cfg.service(reset_password);
+    cfg.service(request_password_reset);
+    cfg.service(complete_password_reset);
+    cfg.service(unlock);
 }
 
+/// Longest full name users can have
+const FULL_NAME_LENGTH: usize = 200;
+/// Code of the error refusing to delete or disable the root user
+const ROOT_USER_PROTECTED: &str = "root_user_protected";
+/// Code of the error refusing users deleting themselves without `force=true`
+const SELF_DELETION_NOT_FORCED: &str = "self_deletion_not_forced";
+/// Code of the error refusing users disabling themselves without `force=true`
+const SELF_DISABLING_NOT_FORCED: &str = "self_disabling_not_forced";
+/// Code of the error refusing the last admin who can log in deleting or disabling themselves
+const LAST_ADMIN: &str = "last_admin";
+
 #[derive(Debug, Serialize, PartialEq)]
 pub struct UserResponse {
     pub username: String,
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// Whether logging in with a password also takes a code of an authenticator app
+    pub totp_enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_name: Option<String>,
+    /// End of the lockout of a user who failed to log in too many times
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<DateTime<Utc>>,
+    /// Whether the user must set their own password before doing anything else
+    pub must_change_password: bool,
+    /// When the user was deleted without being purged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deactivated_at: Option<DateTime<Utc>>,
+    /// Unknown for users registered by earlier versions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_login: Option<DateTime<Utc>>,
 }
 
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
-        UserResponse {
-            username: user.username().to_string(),
-        }
+        UserResponse::from(&user)
     }
 }
 
@@ -43,7 +154,72 @@ impl From<&User> for UserResponse {
     fn from(user: &User) -> Self {
         UserResponse {
             username: user.username().to_string(),
+            enabled: user.is_enabled(),
+            email: user.email().map(str::to_string),
+            email_verified: user.is_email_verified(),
+            totp_enabled: user.requires_second_factor(),
+            full_name: user.full_name().map(str::to_string),
+            locked_until: user
+                .locked_until()
+                .filter(|_| user.is_locked(&Utc::now()))
+                .cloned(),
+            must_change_password: user.must_change_password(),
+            deactivated_at: user.deactivated_at().cloned(),
+            created_at: user.created_at().cloned(),
+            last_login: user.last_login().cloned(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UserListQuery {
+    /// Start of the username, in any case
+    q: Option<String>,
+    enabled: Option<bool>,
+    /// Name of a role the users must have
+    role: Option<String>,
+    /// Date, like `2024-01-01`, or RFC 3339 time
+    inactive_since: Option<String>,
+    /// Whether to also list the users deleted without being purged
+    #[serde(default)]
+    include_deactivated: bool,
+}
+
+impl UserListQuery {
+    /// Filter of the users to list, except for their role, as its members are looked up separately
+    fn filter(&self) -> ApiResult<UserFilter> {
+        Ok(UserFilter {
+            prefix: self
+                .q
+                .as_deref()
+                .map(str::trim)
+                .filter(|q| !q.is_empty())
+                .map(str::to_string),
+            enabled: self.enabled,
+            ids: None,
+            inactive_since: self.inactive_since()?,
+            deactivated: Some(false).filter(|_| !self.include_deactivated),
+            deactivated_before: None,
+        })
+    }
+
+    /// Start of the period without logins of the users to list, dates starting at midnight UTC
+    fn inactive_since(&self) -> ApiResult<Option<DateTime<Utc>>> {
+        let since = match &self.inactive_since {
+            Some(since) => since,
+            None => return Ok(None),
+        };
+        if let Ok(date) = NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+            return Ok(Some(DateTime::from_utc(date.and_hms(0, 0, 0), Utc)));
         }
+        DateTime::parse_from_rfc3339(since)
+            .map(|since| Some(since.with_timezone(&Utc)))
+            .map_err(|_| {
+                ApiError::BadRequest(format!(
+                    "invalid inactive_since '{}', expected a date or an RFC 3339 time",
+                    since
+                ))
+            })
     }
 }
 
@@ -54,10 +230,11 @@ pub async fn list(
     scope: Scope,
     current_user: CurrentUser,
     list: Query<PaginationQuery>,
+    query: Query<UserListQuery>,
 ) -> ApiResult<Json<Page<UserResponse>>> {
     Scope::from("users:read").matches(&scope)?;
     let enforcer = enforcer.read().await;
-    enforcer.check(current_user.id(), &Guid::simple("users"), "read")?;
+    enforcer.check(current_user.id(), &Guid::simple("users")?, "read")?;
     let limit = list.limit();
     let cursor = list.cursor();
 
@@ -73,11 +250,26 @@ pub async fn list(
         None
     };
 
-    let page = service
-        .list(limit, cursor.as_ref())
-        .await?
-        .map(|user| UserResponse::from(user));
-    Ok(Json(page))
+    let mut filter = query.filter()?;
+    if let Some(role) = &query.role {
+        let members = enforcer.list_role_members(role).await?;
+        filter.ids = Some(
+            members
+                .iter()
+                .filter(|member| member.partition() == Some(partition::USER))
+                .map(Guid::to_string)
+                .collect(),
+        );
+    }
+
+    let page = if filter.is_empty() {
+        service.list(limit, cursor.as_ref()).await?
+    } else {
+        service
+            .list_filtered(&filter, limit, cursor.as_ref())
+            .await?
+    };
+    Ok(Json(page.map(|user| UserResponse::from(user))))
 }
 
 #[derive(Debug, Deserialize)]
@@ -105,13 +297,229 @@ pub async fn get(
         .map(Json)
 }
 
+/// Changes to a user, leaving out the fields to keep.
+/// Empty email addresses and full names clear them.
+#[derive(Debug, Default, Deserialize)]
+pub struct UserUpdate {
+    /// Only accepted unchanged, as the username identifies the user
+    pub username: Option<String>,
+    pub enabled: Option<bool>,
+    pub email: Option<String>,
+    pub full_name: Option<String>,
+    /// Replaces all the roles of the user
+    pub roles: Option<Vec<String>>,
+}
+
+impl UserUpdate {
+    fn validate(&self, user: &User) -> ApiResult<()> {
+        let mut reasons = Vec::new();
+        if self
+            .username
+            .as_ref()
+            .is_some_and(|username| username != user.username())
+        {
+            reasons.push("username cannot be changed".to_string());
+        }
+        if self.enabled == Some(true) && user.is_deactivated() {
+            reasons.push("deactivated users must be reactivated instead".to_string());
+        }
+        if let Some(email) = self.email.as_deref().filter(|email| !email.is_empty()) {
+            if !email::is_valid(email) {
+                reasons.push("email must be a valid address".to_string());
+            }
+        }
+        if let Some(full_name) = &self.full_name {
+            if full_name.chars().count() > FULL_NAME_LENGTH {
+                reasons.push(format!(
+                    "full_name must be at most {} characters long",
+                    FULL_NAME_LENGTH
+                ));
+            }
+        }
+        if let Some(roles) = &self.roles {
+            if roles.iter().any(|role| role.trim().is_empty()) {
+                reasons.push("roles must not be empty".to_string());
+            }
+        }
+
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::ValidationError(reasons))
+        }
+    }
+
+    fn apply(&self, user: &mut User) {
+        if let Some(enabled) = self.enabled {
+            user.set_enabled(enabled);
+        }
+        if let Some(email) = &self.email {
+            user.set_email(Some(email.trim().to_string()).filter(|email| !email.is_empty()));
+        }
+        if let Some(full_name) = &self.full_name {
+            user.set_full_name(
+                Some(full_name.trim().to_string()).filter(|full_name| !full_name.is_empty()),
+            );
+        }
+    }
+}
+
+#[put("/api/v1beta1/users/{username}")]
+pub async fn update(
+    service: Data<UserService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<UsernamePathParam>,
+    force: Query<ForceQuery>,
+    data: Json<UserUpdate>,
+) -> ApiResult<Json<UserResponse>> {
+    update_user(service, enforcer, scope, current_user, path, force, data).await
+}
+
+async fn update_user(
+    service: Data<UserService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<UsernamePathParam>,
+    force: Query<ForceQuery>,
+    data: Json<UserUpdate>,
+) -> ApiResult<Json<UserResponse>> {
+    Scope::from("users:manage").matches(&scope)?;
+    let username = &path.username;
+    let enforcer = enforcer.read().await;
+    let sub = Guid::partitioned(partition::USER, username)?;
+    enforcer.check(current_user.id(), &sub, "update")?;
+    if data.enabled.is_some() {
+        enforcer.check(current_user.id(), &sub, "disable")?;
+    }
+    if data.roles.is_some() {
+        Scope::from(vec!["users:manage", "roles"]).matches_exactly(&scope)?;
+        enforcer.check(current_user.id(), &sub, "manage_roles")?;
+    }
+
+    // Read from the primary, so that the update is based on the latest revision
+    let mut user = service
+        .find_consistent(username)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} not found", username)))?;
+    data.validate(&user)?;
+    if data.enabled == Some(false) && user.is_enabled() {
+        check_lockout(&service, &enforcer, &current_user, &user, force.force, Lockout::Disable)
+            .await?;
+    }
+    let previous_email = user.email().map(str::to_string);
+    data.apply(&mut user);
+    let user = save_with_email(&service, user, previous_email.as_deref()).await?;
+    if let Some(roles) = &data.roles {
+        enforcer.set_principal_roles(user.id(), roles).await?;
+    }
+
+    log::info!("User {} updated user {}", current_user.username(), username);
+    Ok(Json(UserResponse::from(&user)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnabledPayload {
+    pub enabled: bool,
+}
+
+/// Suspends a user without deleting them, or lets them log in again
+#[put("/api/v1beta1/users/{username}/enabled")]
+pub async fn set_enabled(
+    service: Data<UserService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<UsernamePathParam>,
+    force: Query<ForceQuery>,
+    data: Json<EnabledPayload>,
+) -> ApiResult<Json<UserResponse>> {
+    let changes = UserUpdate {
+        enabled: Some(data.enabled),
+        ..Default::default()
+    };
+    update_user(service, enforcer, scope, current_user, path, force, Json(changes)).await
+}
+
+/// Saves a user whose email address may have changed, claiming the new one first so that
+/// it cannot be taken concurrently, and releasing the previous one once saved
+async fn save_with_email(
+    service: &UserService,
+    user: User,
+    previous_email: Option<&str>,
+) -> ApiResult<User> {
+    let username = user.username().to_string();
+    let address = user.email().map(str::to_string);
+    let changed =
+        address.as_deref().map(email::normalize) != previous_email.map(email::normalize);
+    if !changed {
+        return save_revision(service, user).await;
+    }
+
+    let claimed = match &address {
+        Some(address) => service.claim_email(&username, address).await?,
+        None => false,
+    };
+    let user = match save_revision(service, user).await {
+        Ok(user) => user,
+        Err(err) => {
+            if let (true, Some(address)) = (claimed, &address) {
+                release_email(service, &username, address).await;
+            }
+            return Err(err);
+        }
+    };
+    if let Some(previous) = previous_email {
+        release_email(service, &username, previous).await;
+    }
+    Ok(user)
+}
+
+/// Releases an email address, only logging failures as the claim is taken over when stale
+async fn release_email(service: &UserService, username: &str, email: &str) {
+    if let Err(err) = service.release_email(username, email).await {
+        log::warn!(
+            "Failed to release an email address of user {}: {}",
+            username,
+            err
+        );
+    }
+}
+
+/// Saves a user read with `find_consistent`, failing with a conflict if it changed since
+async fn save_revision(service: &UserService, user: User) -> ApiResult<User> {
+    let username = user.username().to_string();
+    service.save(user).await.map_err(|err| match err.status() {
+        StatusCode::CONFLICT => {
+            ApiError::Conflict(format!("User {} was modified concurrently, try again", username))
+        }
+        _ => ApiError::from(err),
+    })
+}
+
+/// Deactivates a user, keeping them from logging in and revoking their tokens and sessions,
+/// or deletes them for good with `purge=true`
 #[delete("/api/v1beta1/users/{username}")]
+#[allow(clippy::too_many_arguments)]
 pub async fn delete(
     service: Data<UserService>,
+    // Grouped, as handlers take at most 10 extractors
+    (clients, pats, keys, groups): (
+        Data<CouchStorage>,
+        Data<PatService>,
+        Data<ApiKeyService>,
+        Data<GroupService>,
+    ),
     enforcer: Data<RwLock<Enforcer>>,
     scope: Scope,
     current_user: CurrentUser,
+    session: TokenSession,
     path: Path<UsernamePathParam>,
+    query: Query<DryRunQuery>,
+    deletion: Query<DeletionQuery>,
+    req: HttpRequest,
 ) -> ApiResult<HttpResponse> {
     Scope::from("users:manage").matches(&scope)?;
     let username = &path.username;
@@ -122,50 +530,1697 @@ pub async fn delete(
         .find(username)
         .await?
         .ok_or_else(|| ApiError::NotFound(username.clone()))?;
+    check_lockout(&service, &enforcer, &current_user, &user, deletion.force, Lockout::Delete)
+        .await?;
+
+    let cascade = CONFIG.oauth().clients().cascade();
+    let plan = purge::plan_deletion(&clients, &pats, &keys, &groups, user, deletion.purge, cascade)
+        .await?;
+    if query.is_dry_run() {
+        return Ok(dry_run::respond(plan.describe(), &current_user));
+    }
 
-    service.delete(&user).await?;
+    let action = if plan.is_purge() {
+        AuditAction::UserDeletion
+    } else {
+        AuditAction::UserDeactivation
+    };
+    let event = |event: AuditEvent| {
+        event
+            .set_client_id(Some(session.client_id().to_string()))
+            .set_user_id(Some(current_user.id().to_string()))
+            .set_target(Some(plan.user().id().to_string()))
+    };
+    if let Err(err) = purge::apply_deletion(&plan, &service, &clients, &pats, &keys, &groups).await
+    {
+        audit::record(&req, event(AuditEvent::failure(action, err.to_string())));
+        return Err(ApiError::from(err));
+    }
+    audit::record(&req, event(AuditEvent::success(action)));
     Ok(HttpResponse::NoContent().finish())
 }
 
-#[get("/api/v1beta1/users/me")]
-pub async fn me(user: CurrentUser, scope: Scope) -> ApiResult<Json<UserResponse>> {
-    Scope::from("profile").matches(&scope)?;
-    Ok(Json(user.into()))
+#[derive(Debug, Default, Deserialize)]
+pub struct ForceQuery {
+    #[serde(default)]
+    force: bool,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct Registration {
-    pub username: String,
-    pub password: String,
-    pub roles: Option<Vec<String>>,
+#[derive(Debug, Default, Deserialize)]
+pub struct DeletionQuery {
+    #[serde(default)]
+    force: bool,
+    /// Deletes the user for good instead of deactivating them
+    #[serde(default)]
+    purge: bool,
 }
 
-#[post("/api/v1beta1/users")]
-pub async fn register(
+/// Lets a deactivated user log in again, as long as they were not purged
+#[post("/api/v1beta1/users/{username}/reactivate")]
+pub async fn reactivate(
     service: Data<UserService>,
-    data: Json<Registration>,
-    scope: Scope,
     enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
     current_user: CurrentUser,
-) -> Result<Json<UserResponse>, ApiError> {
+    session: TokenSession,
+    path: Path<UsernamePathParam>,
+    req: HttpRequest,
+) -> ApiResult<Json<UserResponse>> {
     Scope::from("users:manage").matches(&scope)?;
-    let enf = enforcer.read().await;
-    enf.check(current_user.id(), &Guid::simple("users"), "create")?;
+    let username = &path.username;
+    let enforcer = enforcer.read().await;
+    let sub = User::build_guid(username);
+    enforcer.check(current_user.id(), &sub, "update")?;
+    enforcer.check(current_user.id(), &sub, "disable")?;
 
-    let user = User::new(data.username.clone(), data.password.clone())?;
-    let user = service.save(user).await?;
+    let reactivated = Cell::new(false);
+    let user = service
+        .patch(username, |user| {
+            reactivated.set(user.is_deactivated());
+            if reactivated.get() {
+                user.reactivate();
+            }
+            reactivated.get()
+        })
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} not found", username)))?;
+    if !reactivated.get() {
+        return Err(ApiError::Conflict(format!("User {} is not deactivated", username)));
+    }
+    let event = AuditEvent::success(AuditAction::UserReactivation)
+        .set_client_id(Some(session.client_id().to_string()))
+        .set_user_id(Some(current_user.id().to_string()))
+        .set_target(Some(user.id().to_string()));
+    audit::record(&req, event);
+    log::info!("User {} reactivated user {}", current_user.username(), username);
+    Ok(Json(UserResponse::from(&user)))
+}
 
-    if let Some(roles) = &data.roles {
-        // We exclusively lock the enforcer to avoid having
-        // the internal model being updated for every role insert
-        // TODO: support bulk put in CouchDB to have them inserted all at once so that we don't need exclusive access
-        // let enf = enforcer.write().unwrap();
-        for role in roles {
-            enf.add_role_to_principal(user.id().clone(), role).await?;
+#[derive(Debug, Deserialize)]
+pub struct ImpersonationPayload {
+    pub scope: OAuthScope,
+    /// Seconds the token is valid for, capped at 15 minutes
+    pub expires_in: Option<i64>,
+}
+
+impl ImpersonationPayload {
+    /// The scope must be explicit and granted to the token asking for it
+    fn validate(&self, granted: &OAuthScope) -> ApiResult<()> {
+        let mut errors = Vec::new();
+        if self.scope.is_empty() {
+            errors.push("scope must not be empty".to_string());
+        } else if !granted.is_superset(&self.scope) {
+            let mut offending: Vec<&str> = self
+                .scope
+                .iter()
+                .filter(|entry| !granted.grants(entry))
+                .collect();
+            offending.sort();
+            errors.push(format!("scope not granted: {}", offending.join(" ")));
+        }
+        if self.expires_in.is_some_and(|expires_in| expires_in <= 0) {
+            errors.push("expires_in must be positive".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::ValidationError(errors))
         }
     }
 
-    responses::ok(UserResponse {
-        username: user.username().to_string(),
-    })
+    /// Lifetime of the token, the longest allowed unless a shorter one is asked for
+    fn expires_in(&self) -> chrono::Duration {
+        // Capped before building the duration, which panics on huge values
+        let max_seconds = MAX_IMPERSONATION_MINUTES * 60;
+        let seconds = self
+            .expires_in
+            .map_or(max_seconds, |expires_in| expires_in.min(max_seconds));
+        chrono::Duration::seconds(seconds)
+    }
+}
+
+/// Issues a short-lived access token acting as another user, without a refresh token, so that
+/// support staff can reproduce what they see. The token names its impersonator, in introspection
+/// responses and in the audit events recorded while it is used.
+#[post("/api/v1beta1/users/{username}/impersonate")]
+#[allow(clippy::too_many_arguments)]
+pub async fn impersonate(
+    oauth: Data<Arc<dyn Oauth>>,
+    service: Data<UserService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    session: TokenSession,
+    path: Path<UsernamePathParam>,
+    data: Json<ImpersonationPayload>,
+    req: HttpRequest,
+) -> ApiResult<Json<TokenResponse>> {
+    Scope::from("users:impersonate").matches(&scope)?;
+    refuse_impersonated(&session, "impersonating other users")?;
+    let username = &path.username;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &User::build_guid(username), "impersonate")?;
+    data.validate(&scope)?;
+
+    let user = service
+        .find(username)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} not found", username)))?;
+    if user.username() == CONFIG.root().username() {
+        return Err(ApiError::ForbiddenWithCode(
+            ROOT_USER_PROTECTED,
+            "the root user cannot be impersonated".to_string(),
+        ));
+    }
+    if user.username() == current_user.username() {
+        return Err(ApiError::ValidationError(vec![
+            "users cannot impersonate themselves".to_string(),
+        ]));
+    }
+    if !user.is_enabled() {
+        return Err(ApiError::Forbidden(format!("user {} is disabled", user.username())));
+    }
+
+    let mut impersonation = OAuthSession::for_client(session.client_id().to_string());
+    impersonation
+        .set_scope(data.scope.clone())
+        .set_user_id(user.id().to_string())
+        .set_impersonator(Some(current_user.id().to_string()))
+        .set_auth_time(Some(Utc::now()))
+        .set_resources(session.resources().to_vec());
+    let event = |event: AuditEvent| {
+        event
+            .set_client_id(Some(session.client_id().to_string()))
+            .set_user_id(Some(current_user.id().to_string()))
+            .set_target(Some(user.id().to_string()))
+    };
+    match oauth.impersonate(&impersonation, data.expires_in()).await {
+        Ok(token) => {
+            audit::record(&req, event(AuditEvent::success(AuditAction::Impersonation)));
+            log::info!(
+                "User {} impersonates user {} with scope '{}'",
+                current_user.username(),
+                user.username(),
+                token.scope
+            );
+            Ok(Json(token))
+        }
+        Err(err) => {
+            let failure = AuditEvent::failure(AuditAction::Impersonation, err.to_string());
+            audit::record(&req, event(failure));
+            Err(ApiError::from(err))
+        }
+    }
+}
+
+/// Refuses operations that would let an impersonation outlive its token, or take over the account
+fn refuse_impersonated(session: &OAuthSession, operation: &str) -> ApiResult<()> {
+    match session.impersonator() {
+        Some(_) => Err(ApiError::Forbidden(format!(
+            "{} is not allowed while impersonating a user",
+            operation
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Ways of removing a user, which must not lock everyone out
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lockout {
+    Delete,
+    Disable,
+}
+
+impl Lockout {
+    fn verb(self) -> &'static str {
+        match self {
+            Lockout::Delete => "delete",
+            Lockout::Disable => "disable",
+        }
+    }
+
+    fn participle(self) -> &'static str {
+        match self {
+            Lockout::Delete => "deleted",
+            Lockout::Disable => "disabled",
+        }
+    }
+
+    fn not_forced_code(self) -> &'static str {
+        match self {
+            Lockout::Delete => SELF_DELETION_NOT_FORCED,
+            Lockout::Disable => SELF_DISABLING_NOT_FORCED,
+        }
+    }
+}
+
+/// Refuses deleting or disabling the root user, which would lock everyone out, and users
+/// deleting or disabling themselves unless forced and another admin who can log in is left
+async fn check_lockout(
+    service: &UserService,
+    enforcer: &Enforcer,
+    current_user: &User,
+    user: &User,
+    force: bool,
+    lockout: Lockout,
+) -> ApiResult<()> {
+    if user.username() == CONFIG.root().username() {
+        return Err(ApiError::ForbiddenWithCode(
+            ROOT_USER_PROTECTED,
+            format!("the root user cannot be {}", lockout.participle()),
+        ));
+    }
+    if user.username() != current_user.username() {
+        return Ok(());
+    }
+    if !force {
+        return Err(ApiError::ForbiddenWithCode(
+            lockout.not_forced_code(),
+            format!("users can only {} themselves with force=true", lockout.verb()),
+        ));
+    }
+
+    let members = enforcer.list_role_members(ADMIN_ROLE).await?;
+    let mut admins = Vec::new();
+    for username in other_admins(&members, user) {
+        admins.push(service.find(username).await?);
+    }
+    if count_active(&admins) == 0 {
+        return Err(ApiError::ForbiddenWithCode(
+            LAST_ADMIN,
+            format!(
+                "no other user with the {} role can log in, users cannot {} themselves",
+                ADMIN_ROLE,
+                lockout.verb()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Usernames of the members of the admin role, other than the given user
+fn other_admins<'a>(members: &'a [Guid], user: &User) -> Vec<&'a str> {
+    let mut usernames: Vec<&str> = members
+        .iter()
+        .filter(|member| member.partition() == Some(partition::USER))
+        .map(Guid::id)
+        .filter(|username| *username != user.username())
+        .collect();
+    usernames.sort_unstable();
+    usernames.dedup();
+    usernames
+}
+
+/// Users who still exist and are enabled, and can thus log in
+fn count_active(users: &[Option<User>]) -> usize {
+    users.iter().flatten().filter(|user| user.is_enabled()).count()
+}
+
+/// The current user, along with what the token of the request grants and until when
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CurrentUserResponse {
+    #[serde(flatten)]
+    pub user: UserResponse,
+    /// Scope granted to the token, space-delimited like in token responses
+    pub scope: OAuthScope,
+    /// Client the token was issued to
+    pub client_id: String,
+    /// Unknown for tokens issued by earlier versions, and for personal access tokens without expiry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Roles of the user, both assigned directly and inherited from their groups
+    pub roles: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_warning: Option<QuotaWarning>,
+}
+
+#[get("/api/v1beta1/users/me")]
+pub async fn me(
+    user: CurrentUser,
+    scope: Scope,
+    session: TokenSession,
+    usage: Data<UsageTracker>,
+    enforcer: Data<RwLock<Enforcer>>,
+) -> ApiResult<Json<CurrentUserResponse>> {
+    Scope::from("profile").matches(&scope)?;
+    let quota_warning = usage.usage(user.username()).warning;
+    let roles = enforcer.read().await.roles_of(user.id());
+    Ok(Json(CurrentUserResponse {
+        user: user.into(),
+        scope: session.scope().clone(),
+        client_id: session.client_id().to_string(),
+        expires_at: session.expires_at().cloned(),
+        roles,
+        quota_warning,
+    }))
+}
+
+#[get("/api/v1beta1/users/me/usage")]
+pub async fn my_usage(
+    user: CurrentUser,
+    scope: Scope,
+    usage: Data<UsageTracker>,
+) -> ApiResult<Json<Usage>> {
+    Scope::from("profile").matches(&scope)?;
+    Ok(Json(usage.usage(user.username())))
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct EmailVerificationResponse {
+    pub email: String,
+    /// Whether the link was emailed to the address
+    pub sent: bool,
+    /// Link verifying the address, handed out for manual delivery when no mailer is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification_uri: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Sends a link verifying the email address of the current user, invalidating earlier ones
+#[post("/api/v1beta1/users/me/email/verify")]
+pub async fn verify_email(
+    verifications: Data<VerificationService>,
+    user: CurrentUser,
+    scope: Scope,
+    urls: UrlBuilder,
+) -> ApiResult<Json<EmailVerificationResponse>> {
+    Scope::from("profile").matches(&scope)?;
+    let address = match user.email() {
+        Some(address) => address.to_string(),
+        None => {
+            return Err(ApiError::ValidationError(vec![
+                "user has no email address to verify".to_string(),
+            ]))
+        }
+    };
+    if user.is_email_verified() {
+        return Err(ApiError::Conflict(
+            "email address is already verified".to_string(),
+        ));
+    }
+
+    let (verification, token) = verifications.create(&user).await?;
+    let mut link = urls.ui("verify-email");
+    link.query_pairs_mut().append_pair("token", &token);
+    let sent = verifications
+        .send(&verification, &link)
+        .await
+        .map_err(|err| {
+            log::error!(
+                "Failed to email user {} their verification link: {}",
+                user.username(),
+                err
+            );
+            ApiError::ServiceUnavailable("failed to send the verification email".to_string())
+        })?;
+    log::info!(
+        "User {} requested the verification of their email address",
+        user.username()
+    );
+    Ok(Json(EmailVerificationResponse {
+        email: address,
+        sent,
+        verification_uri: Some(link.to_string()).filter(|_| !sent),
+        expires_at: *verification.expires_at(),
+    }))
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TotpEnrollment {
+    /// Shared secret, base32-encoded, for authenticator apps that cannot import the URI
+    pub secret: String,
+    pub otpauth_uri: String,
+    /// Single-use codes standing in for a code of the app when it is lost, shown only once
+    pub recovery_codes: Vec<String>,
+}
+
+/// Starts the enrollment of the current user in two-factor authentication, replacing a pending
+/// one. It is only enabled once a first code is confirmed.
+#[post("/api/v1beta1/users/me/mfa/totp")]
+pub async fn enroll_totp(
+    service: Data<UserService>,
+    user: CurrentUser,
+    scope: Scope,
+) -> ApiResult<Json<TotpEnrollment>> {
+    Scope::from("profile").matches(&scope)?;
+    let already_enabled =
+        || ApiError::Conflict("two-factor authentication is already enabled".to_string());
+    if user.requires_second_factor() {
+        return Err(already_enabled());
+    }
+
+    let (totp, recovery_codes) = Totp::generate()?;
+    let patched = service
+        .patch(user.username(), |user| {
+            if user.requires_second_factor() {
+                return false;
+            }
+            user.set_totp(Some(totp.clone()));
+            true
+        })
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("user {} not found", user.username())))?;
+    // Confirmed concurrently
+    if patched.totp().map(Totp::secret) != Some(totp.secret()) {
+        return Err(already_enabled());
+    }
+    log::info!("User {} started enrolling in two-factor authentication", user.username());
+    Ok(Json(TotpEnrollment {
+        secret: totp.secret().to_string(),
+        otpauth_uri: totp.uri(user.username()).to_string(),
+        recovery_codes,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+/// Enables the pending two-factor authentication of the current user, with a first code of the app
+#[post("/api/v1beta1/users/me/mfa/totp/confirm")]
+pub async fn confirm_totp(
+    service: Data<UserService>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+    data: Json<TotpCode>,
+    req: HttpRequest,
+) -> ApiResult<HttpResponse> {
+    Scope::from("profile").matches(&scope)?;
+    match user.totp() {
+        None => {
+            return Err(ApiError::NotFound(
+                "two-factor authentication is not being enrolled".to_string(),
+            ))
+        }
+        Some(totp) if totp.is_confirmed() => {
+            return Err(ApiError::Conflict(
+                "two-factor authentication is already enabled".to_string(),
+            ))
+        }
+        Some(_) => {}
+    }
+    let event = |event: AuditEvent| {
+        event
+            .set_client_id(Some(session.client_id().to_string()))
+            .set_user_id(Some(user.id().to_string()))
+    };
+
+    let now = Utc::now();
+    let confirmed = Cell::new(false);
+    service
+        .patch(user.username(), |user| {
+            confirmed.set(user.confirm_totp(&data.code, &now));
+            confirmed.get()
+        })
+        .await?;
+    if !confirmed.get() {
+        let reason = "code is incorrect".to_string();
+        audit::record(
+            &req,
+            event(AuditEvent::failure(AuditAction::TwoFactorChange, reason.clone())),
+        );
+        return Err(ApiError::Forbidden(reason));
+    }
+    audit::record(&req, event(AuditEvent::success(AuditAction::TwoFactorChange)));
+    log::info!("User {} enabled two-factor authentication", user.username());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpRemoval {
+    pub password: String,
+}
+
+/// Disables two-factor authentication for the current user, or cancels a pending enrollment,
+/// after confirming their password
+#[delete("/api/v1beta1/users/me/mfa/totp")]
+pub async fn disable_totp(
+    service: Data<UserService>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+    data: Json<TotpRemoval>,
+    req: HttpRequest,
+) -> ApiResult<HttpResponse> {
+    Scope::from("profile").matches(&scope)?;
+    let event = |event: AuditEvent| {
+        event
+            .set_client_id(Some(session.client_id().to_string()))
+            .set_user_id(Some(user.id().to_string()))
+    };
+    if service
+        .authenticate_user(user.username(), &data.password)
+        .await
+        .is_err()
+    {
+        let reason = "password is incorrect".to_string();
+        audit::record(
+            &req,
+            event(AuditEvent::failure(AuditAction::TwoFactorChange, reason.clone())),
+        );
+        return Err(ApiError::Forbidden(reason));
+    }
+
+    let removed = Cell::new(false);
+    service
+        .patch(user.username(), |user| {
+            removed.set(user.totp().is_some());
+            user.set_totp(None);
+            removed.get()
+        })
+        .await?;
+    if !removed.get() {
+        return Err(ApiError::NotFound(
+            "two-factor authentication is not enabled".to_string(),
+        ));
+    }
+    audit::record(&req, event(AuditEvent::success(AuditAction::TwoFactorChange)));
+    log::info!("User {} disabled two-factor authentication", user.username());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// A live OAuth token of the user, standing for the session of a client on one of their devices
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SessionResponse {
+    /// Signature of the token, identifying it without revealing it
+    pub id: String,
+    pub kind: TokenTypeHint,
+    pub client_id: String,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issued_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+    /// Only recorded for access tokens, and at most once a minute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<DateTime<Utc>>,
+    /// Address the token was last used from, or issued to if it is bound to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    /// Of the device that requested the token, or the one the user signed in with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Whether the request was made with this token
+    pub current: bool,
+}
+
+impl SessionResponse {
+    fn new(
+        id: &str,
+        kind: TokenTypeHint,
+        session: &OAuthSession,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        SessionResponse {
+            id: id.to_string(),
+            kind,
+            client_id: session.client_id().clone(),
+            scope: session.scope().to_string(),
+            issued_at: session.issued_at().cloned(),
+            expires_at,
+            last_used: None,
+            ip: session.origin().and_then(|origin| origin.ip()),
+            user_agent: session
+                .requester()
+                .or_else(|| session.device())
+                .map(|device| device.user_agent().raw().to_string()),
+            current: false,
+        }
+    }
+}
+
+impl From<&AccessTokenEntity> for SessionResponse {
+    fn from(token: &AccessTokenEntity) -> Self {
+        let mut res = SessionResponse::new(
+            token.id().id(),
+            TokenTypeHint::AccessToken,
+            token.session(),
+            *token.expiration(),
+        );
+        res.issued_at = res.issued_at.or_else(|| token.issued_at().cloned());
+        res.last_used = token.last_used().cloned();
+        res.ip = token.last_used_ip().or(res.ip);
+        res
+    }
+}
+
+impl From<&RefreshTokenEntity> for SessionResponse {
+    fn from(token: &RefreshTokenEntity) -> Self {
+        SessionResponse::new(
+            token.id().id(),
+            TokenTypeHint::RefreshToken,
+            token.session(),
+            *token.expiration(),
+        )
+    }
+}
+
+/// Lists the live access and refresh tokens of the current user, most recently used first.
+/// Personal access tokens are listed separately.
+#[get("/api/v1beta1/users/me/sessions")]
+pub async fn list_sessions(
+    tokens: Data<CouchStorage>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+) -> ApiResult<Json<Vec<SessionResponse>>> {
+    Scope::from("profile").matches(&scope)?;
+    let user_id = user.id().to_string();
+    let mut sessions: Vec<SessionResponse> = tokens
+        .user_access_tokens(&user_id)
+        .await?
+        .iter()
+        .map(SessionResponse::from)
+        .collect();
+    let refresh_tokens = tokens.user_refresh_tokens(&user_id).await?;
+    sessions.extend(refresh_tokens.iter().map(SessionResponse::from));
+    for listed in &mut sessions {
+        listed.current = listed.kind == TokenTypeHint::AccessToken
+            && session.token_signature() == Some(listed.id.as_str());
+    }
+    sessions.sort_by_key(|listed| Reverse(listed.last_used.or(listed.issued_at)));
+    Ok(Json(sessions))
+}
+
+/// Revokes a token of the current user, along with the tokens issued with it
+#[delete("/api/v1beta1/users/me/sessions/{id}")]
+pub async fn revoke_session(
+    tokens: Data<CouchStorage>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+    id: Path<String>,
+    req: HttpRequest,
+) -> ApiResult<HttpResponse> {
+    Scope::from("profile").matches(&scope)?;
+    let id = id.into_inner();
+    if !tokens.revoke_user_token(&user.id().to_string(), &id).await? {
+        return Err(ApiError::NotFound(format!("session {} not found", id)));
+    }
+    let event = AuditEvent::success(AuditAction::Revocation)
+        .set_client_id(Some(session.client_id().to_string()))
+        .set_user_id(Some(user.id().to_string()));
+    audit::record(&req, event);
+    log::info!("User {} revoked one of their sessions", user.username());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RevokedSessions {
+    /// Access and refresh tokens revoked
+    pub revoked: usize,
+}
+
+/// Signs the current user out everywhere else: revokes all their tokens but the one of the
+/// request and its refresh tokens, and their browser sessions
+#[delete("/api/v1beta1/users/me/sessions")]
+pub async fn revoke_other_sessions(
+    service: Data<UserService>,
+    tokens: Data<CouchStorage>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+    req: HttpRequest,
+) -> ApiResult<Json<RevokedSessions>> {
+    Scope::from("profile").matches(&scope)?;
+    let now = Utc::now();
+    service
+        .patch(user.username(), |user| {
+            user.revoke_sessions(now);
+            true
+        })
+        .await?;
+    let revoked = tokens
+        .revoke_user_tokens(&user.id().to_string(), session.token_signature())
+        .await?;
+    let event = AuditEvent::success(AuditAction::Revocation)
+        .set_client_id(Some(session.client_id().to_string()))
+        .set_user_id(Some(user.id().to_string()));
+    audit::record(&req, event);
+    log::info!(
+        "User {} revoked {} tokens of their other sessions",
+        user.username(),
+        revoked
+    );
+    Ok(Json(RevokedSessions { revoked }))
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Registration {
+    pub username: String,
+    pub password: String,
+    pub email: Option<String>,
+    pub roles: Option<Vec<String>>,
+    /// Whether the password is temporary, and the user must set their own when logging in
+    #[serde(default)]
+    pub must_change_password: bool,
+}
+
+#[post("/api/v1beta1/users")]
+pub async fn register(
+    service: Data<UserService>,
+    data: Json<Registration>,
+    scope: Scope,
+    enforcer: Data<RwLock<Enforcer>>,
+    current_user: CurrentUser,
+) -> Result<Json<UserResponse>, ApiError> {
+    Scope::from("users:manage").matches(&scope)?;
+    let enf = enforcer.read().await;
+    enf.check(current_user.id(), &Guid::simple("users")?, "create")?;
+
+    let username = username::normalize(&data.username);
+    let violations = username::violations(&username);
+    if !violations.is_empty() {
+        return Err(ApiError::ValidationError(violations));
+    }
+    validate_password(&username, &data.password)?;
+    let address = data
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|address| !address.is_empty());
+    if address.is_some_and(|address| !email::is_valid(address)) {
+        return Err(ApiError::ValidationError(vec![
+            "email must be a valid address".to_string(),
+        ]));
+    }
+    let mut user = User::new(username.clone(), data.password.clone())?;
+    user.set_must_change_password(data.must_change_password);
+    let mut claimed = false;
+    if let Some(address) = address {
+        user.set_email(Some(address.to_string()));
+        claimed = service.claim_email(&username, address).await?;
+    }
+    // The user is likely to log in right away, possibly through another replica
+    let user = match service.save_tracked(user).await {
+        Ok(user) => user,
+        Err(err) => {
+            if let (true, Some(address)) = (claimed, address) {
+                release_email(&service, &username, address).await;
+            }
+            return Err(ApiError::from(err));
+        }
+    };
+
+    if let Some(roles) = &data.roles {
+        // We exclusively lock the enforcer to avoid having
+        // the internal model being updated for every role insert
+        // TODO: support bulk put in CouchDB to have them inserted all at once so that we don't need exclusive access
+        // let enf = enforcer.write().unwrap();
+        for role in roles {
+            enf.add_role_to_principal(user.id().clone(), role).await?;
+        }
+    }
+
+    responses::ok(UserResponse::from(&user))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordReset {
+    pub new_password: String,
+}
+
+/// Checks the password against the configured policy, listing each failed rule
+fn validate_password(username: &str, password: &str) -> ApiResult<()> {
+    let violations = password::violations(username, password);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::ValidationError(violations))
+    }
+}
+
+/// Sets the password of the user and revokes their tokens, except the one with the given signature
+async fn set_password(
+    service: &UserService,
+    tokens: &CouchStorage,
+    mut user: User,
+    password: &str,
+    keep: Option<&str>,
+) -> ApiResult<()> {
+    if let Some(backend) = user.backend() {
+        return Err(ApiError::Conflict(format!(
+            "the password of user {} is managed by the {} authentication backend",
+            user.username(),
+            backend
+        )));
+    }
+    user.set_password(password)?;
+    // The user is likely to log in again right away, possibly through another replica
+    let user = service.save_tracked(user).await?;
+    let revoked = tokens
+        .revoke_user_tokens(&user.id().to_string(), keep)
+        .await?;
+    log::info!(
+        "Revoked {} tokens of user {} after a password change",
+        revoked,
+        user.username()
+    );
+    Ok(())
+}
+
+/// Changes the password of the current user, revoking the tokens of all their other sessions
+#[put("/api/v1beta1/users/me/password")]
+pub async fn change_password(
+    service: Data<UserService>,
+    tokens: Data<CouchStorage>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+    data: Json<PasswordChange>,
+    req: HttpRequest,
+) -> ApiResult<HttpResponse> {
+    Scope::from("profile").matches(&scope)?;
+    refuse_impersonated(&session, "changing the password")?;
+    let event = |event: AuditEvent| {
+        event
+            .set_client_id(Some(session.client_id().to_string()))
+            .set_user_id(Some(user.id().to_string()))
+    };
+    if service
+        .authenticate_user(user.username(), &data.current_password)
+        .await
+        .is_err()
+    {
+        let reason = "current password is incorrect".to_string();
+        audit::record(
+            &req,
+            event(AuditEvent::failure(AuditAction::PasswordChange, reason.clone())),
+        );
+        return Err(ApiError::Forbidden(reason));
+    }
+    validate_password(user.username(), &data.new_password)?;
+
+    set_password(
+        &service,
+        &tokens,
+        user.clone(),
+        &data.new_password,
+        session.token_signature(),
+    )
+    .await?;
+    audit::record(&req, event(AuditEvent::success(AuditAction::PasswordChange)));
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sets the password of any user, without knowing the current one, revoking all their tokens
+#[put("/api/v1beta1/users/{username}/password")]
+#[allow(clippy::too_many_arguments)]
+pub async fn reset_password(
+    service: Data<UserService>,
+    tokens: Data<CouchStorage>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    session: TokenSession,
+    path: Path<UsernamePathParam>,
+    data: Json<PasswordReset>,
+    req: HttpRequest,
+) -> ApiResult<HttpResponse> {
+    Scope::from("users:manage").matches(&scope)?;
+    let username = &path.username;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &User::build_guid(username), "update")?;
+
+    let user = service
+        .find(username)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} not found", username)))?;
+    validate_password(user.username(), &data.new_password)?;
+
+    // Administrators resetting their own password keep their session
+    let keep = session
+        .token_signature()
+        .filter(|_| user.username() == current_user.username());
+    let target = user.id().to_string();
+    set_password(&service, &tokens, user, &data.new_password, keep).await?;
+    log::info!(
+        "User {} reset the password of user {}",
+        current_user.username(),
+        username
+    );
+    let event = AuditEvent::success(AuditAction::PasswordReset)
+        .set_client_id(Some(session.client_id().to_string()))
+        .set_user_id(Some(current_user.id().to_string()))
+        .set_target(Some(target));
+    audit::record(&req, event);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Asks for a password reset, identifying the user by either their username or email address
+#[derive(Debug, Default, Deserialize)]
+pub struct PasswordResetRequest {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum ResetLogin {
+    Username(String),
+    Email(String),
+}
+
+impl PasswordResetRequest {
+    fn login(&self) -> ApiResult<ResetLogin> {
+        let username = self.username.as_deref().map(str::trim).filter(|u| !u.is_empty());
+        let email = self.email.as_deref().map(str::trim).filter(|e| !e.is_empty());
+        match (username, email) {
+            (Some(username), None) => Ok(ResetLogin::Username(username.to_string())),
+            (None, Some(email)) => Ok(ResetLogin::Email(email.to_string())),
+            _ => Err(ApiError::ValidationError(vec![
+                "either username or email is required".to_string(),
+            ])),
+        }
+    }
+}
+
+/// Emails a password reset token to the user, if they exist and have an email address.
+/// Responds the same either way, so that it cannot tell which users exist.
+#[post("/api/v1beta1/password-resets")]
+pub async fn request_password_reset(
+    service: Data<UserService>,
+    resets: Data<ResetService>,
+    data: Json<PasswordResetRequest>,
+) -> ApiResult<HttpResponse> {
+    let login = data.login()?;
+    // Done in the background, as the time it takes would also tell whether the user exists
+    actix_rt::spawn(async move {
+        let user = match &login {
+            ResetLogin::Username(username) => service.find(username).await.map_err(Error::from),
+            ResetLogin::Email(address) => service.find_by_email(address).await,
+        };
+        let user = match user {
+            // Passwords managed by another backend are reset there
+            Ok(Some(user)) if user.is_enabled() && user.backend().is_none() => user,
+            Ok(_) => {
+                log::info!(
+                    "Ignoring a password reset request for an unknown, disabled or external user"
+                );
+                return;
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to look up the user of a password reset request: {}",
+                    err
+                );
+                return;
+            }
+        };
+        match resets.request(&user).await {
+            Ok(true) => log::info!("Emailed a password reset token to user {}", user.username()),
+            Ok(false) => log::warn!(
+                "Cannot email a password reset token to user {}, without an address or a mailer",
+                user.username()
+            ),
+            Err(err) => log::error!(
+                "Failed to email a password reset token to user {}: {}",
+                user.username(),
+                err
+            ),
+        }
+    });
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetTokenPathParam {
+    pub token: String,
+}
+
+/// Sets a new password with a reset token, which cannot be used again, revoking all the tokens
+/// of the user. As they proved they own their email address, their lockout is lifted too.
+#[post("/api/v1beta1/password-resets/{token}")]
+pub async fn complete_password_reset(
+    service: Data<UserService>,
+    resets: Data<ResetService>,
+    tokens: Data<CouchStorage>,
+    path: Path<ResetTokenPathParam>,
+    data: Json<PasswordReset>,
+    req: HttpRequest,
+) -> ApiResult<HttpResponse> {
+    // Unknown, expired and used tokens must be told apart by nobody
+    let invalid = || ApiError::NotFound("password reset token is invalid or expired".to_string());
+    let reset = resets
+        .find_by_token(&path.token)
+        .await?
+        .ok_or_else(invalid)?;
+    let mut user = service
+        .find(reset.username())
+        .await?
+        .filter(User::is_enabled)
+        .ok_or_else(invalid)?;
+    // Checked first, so that a rejected password does not use up the token
+    validate_password(user.username(), &data.new_password)?;
+    if !resets.consume(&reset).await? {
+        return Err(invalid());
+    }
+
+    user.clear_failed_logins();
+    let user_id = user.id().to_string();
+    set_password(&service, &tokens, user, &data.new_password, None).await?;
+    log::info!("User {} reset their password with a reset token", reset.username());
+    audit::record(
+        &req,
+        AuditEvent::success(AuditAction::PasswordRecovery).set_user_id(Some(user_id)),
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lifts the lockout of a user early, forgetting their failed logins
+#[delete("/api/v1beta1/users/{username}/lockout")]
+pub async fn unlock(
+    service: Data<UserService>,
+    enforcer: Data<RwLock<Enforcer>>,
+    scope: Scope,
+    current_user: CurrentUser,
+    path: Path<UsernamePathParam>,
+) -> ApiResult<HttpResponse> {
+    Scope::from("users:manage").matches(&scope)?;
+    let username = &path.username;
+    let enforcer = enforcer.read().await;
+    enforcer.check(current_user.id(), &User::build_guid(username), "update")?;
+
+    if !service.unlock(username).await? {
+        return Err(ApiError::NotFound(format!("User {} not found", username)));
+    }
+    log::info!("User {} unlocked user {}", current_user.username(), username);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PatResponse {
+    pub id: String,
+    pub label: String,
+    pub scope: OAuthScope,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<&PersonalAccessToken> for PatResponse {
+    fn from(pat: &PersonalAccessToken) -> Self {
+        PatResponse {
+            id: pat.id().to_string(),
+            label: pat.label().to_string(),
+            scope: pat.scope().clone(),
+            created_at: *pat.created_at(),
+            expires_at: pat.expires_at().cloned(),
+        }
+    }
+}
+
+/// Returned once on creation, as only a signature of the token is stored
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CreatedPatResponse {
+    #[serde(flatten)]
+    pub pat: PatResponse,
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePatPayload {
+    pub label: String,
+    pub scope: OAuthScope,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl CreatePatPayload {
+    /// The scope must be explicit and granted to the token creating it
+    fn validate(&self, granted: &OAuthScope) -> ApiResult<()> {
+        let mut errors = Vec::new();
+        if self.label.trim().is_empty() {
+            errors.push("label must not be empty".to_string());
+        }
+        if self.scope.is_empty() {
+            errors.push("scope must not be empty".to_string());
+        } else if !granted.is_superset(&self.scope) {
+            let mut offending: Vec<&str> = self
+                .scope
+                .iter()
+                .filter(|entry| !granted.grants(entry))
+                .collect();
+            offending.sort();
+            errors.push(format!("scope not granted: {}", offending.join(" ")));
+        }
+        if self
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            errors.push("expires_at must be in the future".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::ValidationError(errors))
+        }
+    }
+}
+
+#[get("/api/v1beta1/users/me/pats")]
+pub async fn list_pats(
+    pats: Data<PatService>,
+    user: CurrentUser,
+    scope: Scope,
+    pagination: Query<PaginationQuery>,
+) -> ApiResult<Json<Page<PatResponse>>> {
+    Scope::from("profile").matches(&scope)?;
+    let cursor = if let Some(cursor) = pagination.cursor() {
+        Some(Cursor::from_b64(cursor)?)
+    } else {
+        None
+    };
+
+    let page = pats
+        .list(user.id(), pagination.limit(), cursor.as_ref())
+        .await?;
+    Ok(Json(page.map(|pat| PatResponse::from(pat))))
+}
+
+#[post("/api/v1beta1/users/me/pats")]
+pub async fn create_pat(
+    pats: Data<PatService>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+    data: Json<CreatePatPayload>,
+) -> ApiResult<Json<CreatedPatResponse>> {
+    Scope::from("profile").matches(&scope)?;
+    refuse_static_credentials(&session)?;
+    refuse_impersonated(&session, "creating personal access tokens")?;
+    data.validate(&scope)?;
+
+    let data = data.into_inner();
+    let (pat, token) = pats
+        .create(user.id(), data.label, data.scope, data.expires_at)
+        .await?;
+    log::info!(
+        "User {} created personal access token {}",
+        user.username(),
+        pat.id()
+    );
+    Ok(Json(CreatedPatResponse {
+        pat: PatResponse::from(&pat),
+        token,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatPathParam {
+    pub id: String,
+}
+
+#[delete("/api/v1beta1/users/me/pats/{id}")]
+pub async fn delete_pat(
+    pats: Data<PatService>,
+    user: CurrentUser,
+    scope: Scope,
+    path: Path<PatPathParam>,
+) -> ApiResult<HttpResponse> {
+    Scope::from("profile").matches(&scope)?;
+    if pats.delete_for_user(user.id(), &path.id).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::NotFound(format!(
+            "personal access token {} not found",
+            &path.id
+        )))
+    }
+}
+
+/// Refuses personal access tokens and API keys creating other ones, which would let them renew
+/// themselves past their expiry
+fn refuse_static_credentials(session: &OAuthSession) -> ApiResult<()> {
+    match session.client_id().as_str() {
+        PAT_CLIENT_ID => Err(ApiError::Forbidden(
+            "personal access tokens cannot create other tokens".to_string(),
+        )),
+        API_KEY_CLIENT_ID => Err(ApiError::Forbidden(
+            "API keys cannot create other tokens".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ApiKeyResponse {
+    pub key_id: String,
+    pub label: String,
+    pub scope: OAuthScope,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<&ApiKey> for ApiKeyResponse {
+    fn from(key: &ApiKey) -> Self {
+        ApiKeyResponse {
+            key_id: key.key_id().to_string(),
+            label: key.label().to_string(),
+            scope: key.scope().clone(),
+            created_at: *key.created_at(),
+            expires_at: key.expires_at().cloned(),
+            last_used_at: key.last_used_at().cloned(),
+        }
+    }
+}
+
+/// Returned once on creation, as only a signature of the secret is stored
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CreatedApiKeyResponse {
+    #[serde(flatten)]
+    pub key: ApiKeyResponse,
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyPayload {
+    pub label: String,
+    /// Restricts the key to part of the scope of the token creating it, which it gets otherwise
+    pub scope: Option<OAuthScope>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl CreateApiKeyPayload {
+    /// Validated like personal access tokens, once the scope defaults to the granted one
+    fn into_validated(self, granted: &OAuthScope) -> ApiResult<CreatePatPayload> {
+        let payload = CreatePatPayload {
+            label: self.label,
+            scope: self.scope.unwrap_or_else(|| granted.clone()),
+            expires_at: self.expires_at,
+        };
+        payload.validate(granted)?;
+        Ok(payload)
+    }
+}
+
+#[get("/api/v1beta1/users/me/keys")]
+pub async fn list_api_keys(
+    keys: Data<ApiKeyService>,
+    user: CurrentUser,
+    scope: Scope,
+    pagination: Query<PaginationQuery>,
+) -> ApiResult<Json<Page<ApiKeyResponse>>> {
+    Scope::from("profile").matches(&scope)?;
+    let cursor = if let Some(cursor) = pagination.cursor() {
+        Some(Cursor::from_b64(cursor)?)
+    } else {
+        None
+    };
+
+    let page = keys
+        .list(user.id(), pagination.limit(), cursor.as_ref())
+        .await?;
+    Ok(Json(page.map(|key| ApiKeyResponse::from(key))))
+}
+
+#[post("/api/v1beta1/users/me/keys")]
+pub async fn create_api_key(
+    keys: Data<ApiKeyService>,
+    user: CurrentUser,
+    session: TokenSession,
+    scope: Scope,
+    data: Json<CreateApiKeyPayload>,
+) -> ApiResult<Json<CreatedApiKeyResponse>> {
+    Scope::from("profile").matches(&scope)?;
+    refuse_static_credentials(&session)?;
+    refuse_impersonated(&session, "creating API keys")?;
+
+    let data = data.into_inner().into_validated(&scope)?;
+    let (key, secret) = keys
+        .create(user.id(), data.label, data.scope, data.expires_at)
+        .await?;
+    log::info!("User {} created API key {}", user.username(), key.key_id());
+    Ok(Json(CreatedApiKeyResponse {
+        key: ApiKeyResponse::from(&key),
+        secret,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPathParam {
+    pub key_id: String,
+}
+
+#[delete("/api/v1beta1/users/me/keys/{key_id}")]
+pub async fn delete_api_key(
+    keys: Data<ApiKeyService>,
+    user: CurrentUser,
+    scope: Scope,
+    path: Path<ApiKeyPathParam>,
+) -> ApiResult<HttpResponse> {
+    Scope::from("profile").matches(&scope)?;
+    if keys.delete_for_user(user.id(), &path.key_id).await? {
+        log::info!("User {} revoked API key {}", user.username(), &path.key_id);
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::NotFound(format!("API key {} not found", &path.key_id)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn payload(label: &str, scope: &str, expires_at: Option<DateTime<Utc>>) -> CreatePatPayload {
+        CreatePatPayload {
+            label: label.to_string(),
+            scope: OAuthScope::from(scope),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn it_accepts_a_subset_of_the_granted_scope() {
+        let granted = OAuthScope::from("profile users:*");
+        let expires_at = Some(Utc::now() + Duration::days(30));
+        assert!(payload("ci", "users:read", expires_at)
+            .validate(&granted)
+            .is_ok());
+        assert!(payload("ci", "profile", None)
+            .validate(&OAuthScope::from("*"))
+            .is_ok());
+    }
+
+    #[test]
+    fn it_rejects_scopes_beyond_the_granted_one() {
+        let granted = OAuthScope::from("profile users:read");
+        let err = payload("", "users:manage clients:read profile", None)
+            .validate(&granted)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ApiError::ValidationError(vec![
+                "label must not be empty".to_string(),
+                "scope not granted: clients:read users:manage".to_string(),
+            ])
+        );
+
+        let expired = Some(Utc::now() - Duration::seconds(1));
+        assert!(payload("ci", "", expired).validate(&granted).is_err());
+    }
+
+    #[test]
+    fn it_caps_the_lifetime_of_impersonation_tokens() {
+        let granted = OAuthScope::from("profile users:impersonate");
+        let impersonation = |scope: &str, expires_in: Option<i64>| ImpersonationPayload {
+            scope: OAuthScope::from(scope),
+            expires_in,
+        };
+        let max = Duration::minutes(MAX_IMPERSONATION_MINUTES);
+        assert!(impersonation("profile", None).validate(&granted).is_ok());
+        assert_eq!(impersonation("profile", None).expires_in(), max);
+        assert_eq!(impersonation("profile", Some(86400)).expires_in(), max);
+        assert_eq!(impersonation("profile", Some(i64::MAX)).expires_in(), max);
+        assert_eq!(
+            impersonation("profile", Some(60)).expires_in(),
+            Duration::minutes(1)
+        );
+
+        let err = impersonation("profile users:manage", Some(0))
+            .validate(&granted)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ApiError::ValidationError(vec![
+                "scope not granted: users:manage".to_string(),
+                "expires_in must be positive".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_refuses_some_operations_while_impersonating() {
+        let mut session = OAuthSession::for_client("enseada".to_string());
+        session.set_user_id("user:jdoe".to_string());
+        assert!(refuse_impersonated(&session, "changing the password").is_ok());
+
+        session.set_impersonator(Some("user:admin".to_string()));
+        assert_eq!(
+            refuse_impersonated(&session, "changing the password").unwrap_err(),
+            ApiError::Forbidden(
+                "changing the password is not allowed while impersonating a user".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn it_defaults_api_keys_to_the_granted_scope() {
+        let granted = OAuthScope::from("profile maven:*");
+        let key = |scope: Option<&str>| CreateApiKeyPayload {
+            label: "maven".to_string(),
+            scope: scope.map(OAuthScope::from),
+            expires_at: None,
+        };
+        assert_eq!(key(None).into_validated(&granted).unwrap().scope, granted);
+        assert_eq!(
+            key(Some("maven:read")).into_validated(&granted).unwrap().scope,
+            OAuthScope::from("maven:read")
+        );
+        assert_eq!(
+            key(Some("users:read")).into_validated(&granted).unwrap_err(),
+            ApiError::ValidationError(vec!["scope not granted: users:read".to_string()])
+        );
+    }
+
+    #[test]
+    fn it_refuses_static_credentials_creating_tokens() {
+        for client_id in &[PAT_CLIENT_ID, API_KEY_CLIENT_ID] {
+            let session = OAuthSession::for_client(client_id.to_string());
+            assert!(refuse_static_credentials(&session).is_err());
+        }
+        let session = OAuthSession::for_client("enseada".to_string());
+        assert!(refuse_static_credentials(&session).is_ok());
+    }
+
+    fn user(username: &str) -> User {
+        User::new(username.to_string(), "correct horse".to_string()).unwrap()
+    }
+
+    #[test]
+    fn it_serializes_the_scope_of_the_current_user_like_token_responses() {
+        let scope = OAuthScope::from("users:read profile");
+        let response = CurrentUserResponse {
+            user: UserResponse::from(&user("jdoe")),
+            scope: scope.clone(),
+            client_id: "enseada".to_string(),
+            expires_at: None,
+            roles: vec!["admin".to_string()],
+            quota_warning: None,
+        };
+        let json = serde_json::to_value(&response).unwrap();
+        let token = TokenResponse {
+            scope,
+            ..Default::default()
+        };
+        assert_eq!(json["scope"], "profile users:read");
+        assert_eq!(json["scope"], serde_json::to_value(&token).unwrap()["scope"]);
+        assert_eq!(json["username"], "jdoe");
+        assert_eq!(json["client_id"], "enseada");
+        assert!(json.get("expires_at").is_none());
+    }
+
+    #[test]
+    fn it_applies_partial_updates() {
+        let mut user = user("jdoe");
+        user.set_full_name(Some("John Doe".to_string()));
+        let changes = UserUpdate {
+            username: Some("jdoe".to_string()),
+            enabled: Some(false),
+            email: Some(" jdoe@example.com ".to_string()),
+            ..Default::default()
+        };
+        assert!(changes.validate(&user).is_ok());
+        changes.apply(&mut user);
+        assert!(!user.is_enabled());
+        assert_eq!(user.email(), Some("jdoe@example.com"));
+        assert_eq!(user.full_name(), Some("John Doe"));
+
+        let changes = UserUpdate {
+            full_name: Some("".to_string()),
+            ..Default::default()
+        };
+        changes.apply(&mut user);
+        assert_eq!(user.full_name(), None);
+        assert_eq!(user.email(), Some("jdoe@example.com"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_updates() {
+        let jdoe = user("jdoe");
+        let changes = UserUpdate {
+            username: Some("jane".to_string()),
+            enabled: Some(false),
+            email: Some("jdoe at example.com".to_string()),
+            full_name: Some("J".repeat(FULL_NAME_LENGTH + 1)),
+            roles: Some(vec!["admin".to_string(), " ".to_string()]),
+        };
+        let err = changes.validate(&jdoe).unwrap_err();
+        assert_eq!(
+            err,
+            ApiError::ValidationError(vec![
+                "username cannot be changed".to_string(),
+                "email must be a valid address".to_string(),
+                format!("full_name must be at most {} characters long", FULL_NAME_LENGTH),
+                "roles must not be empty".to_string(),
+            ])
+        );
+
+        for email in &["@example.com", "jdoe@", "jdoe"] {
+            let changes = UserUpdate {
+                email: Some(email.to_string()),
+                ..Default::default()
+            };
+            assert!(changes.validate(&jdoe).is_err(), "{}", email);
+        }
+
+        let mut deactivated = user("jane");
+        deactivated.deactivate(Utc::now());
+        let changes = UserUpdate {
+            enabled: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            changes.validate(&deactivated).unwrap_err(),
+            ApiError::ValidationError(vec![
+                "deactivated users must be reactivated instead".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn it_resets_passwords_by_username_or_email() {
+        let request = |username: Option<&str>, email: Option<&str>| PasswordResetRequest {
+            username: username.map(str::to_string),
+            email: email.map(str::to_string),
+        };
+        assert_eq!(
+            request(Some(" jdoe "), None).login().unwrap(),
+            ResetLogin::Username("jdoe".to_string())
+        );
+        assert_eq!(
+            request(Some(""), Some("jdoe@example.com")).login().unwrap(),
+            ResetLogin::Email("jdoe@example.com".to_string())
+        );
+        assert!(request(None, None).login().is_err());
+        assert!(request(Some("jdoe"), Some("jdoe@example.com"))
+            .login()
+            .is_err());
+    }
+
+    #[test]
+    fn it_parses_the_start_of_inactivity() {
+        let query = |since: &str| UserListQuery {
+            inactive_since: Some(since.to_string()),
+            ..Default::default()
+        };
+        let midnight = "2024-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(query("2024-01-01").inactive_since().unwrap(), Some(midnight));
+        assert_eq!(
+            query("2024-01-01T02:00:00+02:00").inactive_since().unwrap(),
+            Some(midnight)
+        );
+        assert!(query("yesterday").inactive_since().is_err());
+        let query = UserListQuery::default();
+        assert_eq!(query.inactive_since().unwrap(), None);
+    }
+
+    #[test]
+    fn it_filters_users_by_the_query() {
+        let filter = UserListQuery::default().filter().unwrap();
+        assert_eq!(filter.deactivated, Some(false));
+        let query = UserListQuery {
+            include_deactivated: true,
+            ..Default::default()
+        };
+        assert!(query.filter().unwrap().is_empty());
+
+        let query = UserListQuery {
+            q: Some(" JDo ".to_string()),
+            enabled: Some(true),
+            role: Some("admin".to_string()),
+            ..Default::default()
+        };
+        let filter = query.filter().unwrap();
+        assert_eq!(filter.prefix.as_deref(), Some("JDo"));
+        assert_eq!(filter.enabled, Some(true));
+        assert_eq!(filter.ids, None);
+
+        let query = UserListQuery {
+            q: Some(" ".to_string()),
+            include_deactivated: true,
+            ..Default::default()
+        };
+        assert!(query.filter().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_describes_sessions_by_their_tokens() {
+        let mut session = OAuthSession::for_client("cli".to_string());
+        session
+            .set_scope(OAuthScope::from("profile"))
+            .set_user_id("user:jdoe".to_string());
+        let expiration = Utc::now() + Duration::hours(1);
+        let mut access = AccessTokenEntity::new("sig".to_string(), session.clone(), expiration);
+        let listed = SessionResponse::from(&access);
+        assert_eq!(listed.id, "sig");
+        assert_eq!(listed.kind, TokenTypeHint::AccessToken);
+        assert_eq!(listed.client_id, "cli");
+        assert_eq!(listed.scope, "profile");
+        // Sessions issued by earlier versions fall back to the time the token was stored
+        assert_eq!(listed.issued_at.as_ref(), access.issued_at());
+        assert_eq!(listed.last_used, None);
+
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Utc::now();
+        access.set_last_used(now, Some(ip));
+        let listed = SessionResponse::from(&access);
+        assert_eq!(listed.last_used, Some(now));
+        assert_eq!(listed.ip, Some(ip));
+
+        let refresh = RefreshTokenEntity::new(
+            "refresh_sig".to_string(),
+            session,
+            expiration,
+            "sig".to_string(),
+        );
+        let listed = SessionResponse::from(&refresh);
+        assert_eq!(listed.id, "refresh_sig");
+        assert_eq!(listed.kind, TokenTypeHint::RefreshToken);
+        assert_eq!(listed.expires_at, expiration);
+    }
+
+    #[test]
+    fn it_finds_the_other_admins() {
+        let jdoe = user("jdoe");
+        let members = vec![
+            Guid::partitioned(partition::USER, "root").unwrap(),
+            Guid::partitioned(partition::USER, "jdoe").unwrap(),
+            Guid::partitioned(partition::CLIENT, "ci").unwrap(),
+            Guid::partitioned(partition::USER, "root").unwrap(),
+        ];
+        assert_eq!(other_admins(&members, &jdoe), vec!["root"]);
+        assert!(other_admins(&members[1..3], &jdoe).is_empty());
+    }
+
+    #[test]
+    fn it_counts_the_admins_left_who_can_log_in() {
+        let mut disabled = user("alice");
+        disabled.set_enabled(false);
+        // A deleted admin whose role assignment remains
+        assert_eq!(count_active(&[None, Some(disabled.clone())]), 0);
+        assert_eq!(count_active(&[None, Some(disabled), Some(user("root"))]), 1);
+    }
 }
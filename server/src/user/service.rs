@@ -1,13 +1,79 @@
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
 use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
 use couchdb::db::Database;
+use couchdb::error::Error as CouchError;
+use couchdb::responses::ExplainIndex;
 use enseada::error::Error;
+use enseada::pagination::{Cursor, Page};
 use enseada::secure;
+use http::StatusCode;
+use serde_json::{json, Map, Value};
 
-use crate::couchdb::repository::Repository;
+use crate::couchdb::partition;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::user::auth::{self, AuthenticatorChain, CouchAuthenticator};
+use crate::user::email::{self, EmailClaim};
+use crate::user::identity::{ExternalIdentity, IdentityLink};
+use crate::user::username;
 use crate::user::User;
 
+/// Sorts after any character a username can contain, closing the range of a prefix search
+const PREFIX_END: char = '\u{fff0}';
+
+/// Consecutive failed logins after which users are locked, and for how long
+#[derive(Clone, Debug)]
+pub struct LockoutPolicy {
+    pub failures: u32,
+    pub duration: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            failures: 10,
+            duration: Duration::from_secs(900),
+        }
+    }
+}
+
+/// Filters applied when listing users.
+/// Empty filters match every user.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserFilter {
+    /// Start of the username, in any case
+    pub prefix: Option<String>,
+    pub enabled: Option<bool>,
+    /// IDs of the users to restrict to, like the members of a role
+    pub ids: Option<Vec<String>>,
+    /// Users who did not log in since the given time, including those who never did,
+    /// unless they registered after it
+    pub inactive_since: Option<DateTime<Utc>>,
+    /// Whether the users are deactivated, any if none
+    pub deactivated: Option<bool>,
+    /// Users deactivated before the given time
+    pub deactivated_before: Option<DateTime<Utc>>,
+}
+
+impl UserFilter {
+    pub fn is_empty(&self) -> bool {
+        self.prefix.is_none()
+            && self.enabled.is_none()
+            && self.ids.is_none()
+            && self.inactive_since.is_none()
+            && self.deactivated.is_none()
+            && self.deactivated_before.is_none()
+    }
+}
+
 pub struct UserService {
     db: Database,
+    lockout: LockoutPolicy,
+    authenticators: AuthenticatorChain,
 }
 
 #[async_trait]
@@ -15,24 +81,608 @@ impl Repository<User> for UserService {
     fn db(&self) -> &Database {
         &self.db
     }
+
+    /// Looks up the user by their normalized username, then by the one given if it differs
+    async fn find(&self, username: &str) -> Result<Option<User>, CouchError> {
+        for candidate in username::candidates(username) {
+            let guid = User::build_guid(&candidate).to_string();
+            if let Some(user) = self.db.get(&guid).await? {
+                return Ok(Some(user));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn find_consistent(&self, username: &str) -> Result<Option<User>, CouchError> {
+        for candidate in username::candidates(username) {
+            let guid = User::build_guid(&candidate).to_string();
+            if let Some(user) = self.db.get_consistent(&guid).await? {
+                return Ok(Some(user));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl UserService {
     pub fn new(db: Database) -> UserService {
-        UserService { db }
+        let authenticators =
+            AuthenticatorChain::new(vec![Arc::new(CouchAuthenticator::new(db.clone()))]);
+        UserService {
+            db,
+            lockout: LockoutPolicy::default(),
+            authenticators,
+        }
+    }
+
+    pub fn set_lockout(&mut self, lockout: LockoutPolicy) -> &mut Self {
+        self.lockout = lockout;
+        self
     }
 
+    /// Backends passwords are verified with, only the stored hashes by default
+    pub fn set_authenticators(&mut self, authenticators: AuthenticatorChain) -> &mut Self {
+        self.authenticators = authenticators;
+        self
+    }
+
+    /// Verifies the password of the user with the authentication backends, recording the login
+    /// or the failure. Users of other backends logging in for the first time are created here.
+    /// Locked users are rejected without checking it, so that the error does not tell whether it was right.
     pub async fn authenticate_user(&self, username: &str, password: &str) -> Result<User, Error> {
         log::debug!("Authenticating user {}", username);
-        let user = match self.find(username).await? {
-            Some(user) => user,
-            None => return Err(Error::from("authentication failed")),
+        let now = Utc::now();
+        let local = self.find_consistent(username).await?;
+        if local.as_ref().is_some_and(|user| user.is_locked(&now)) {
+            log::warn!("Locked user {} tried to authenticate", username);
+            return Err(locked());
+        }
+        // Users registered by earlier versions keep the username they were stored with
+        let username = &local
+            .as_ref()
+            .map_or_else(|| username::normalize(username), |user| user.username().to_string());
+        let verified = match self.authenticators.authenticate(username, password).await? {
+            Some(verified) => verified,
+            // Unknown users have no failures to record
+            None if local.is_none() => return Err(Error::from("authentication failed")),
+            None => {
+                let failures = self.lockout.failures;
+                let duration = chrono::Duration::from_std(self.lockout.duration)
+                    .unwrap_or_else(|_| chrono::Duration::max_value());
+                let patched = self
+                    .patch(username, |user| {
+                        user.record_failed_login(failures, duration, now);
+                        true
+                    })
+                    .await;
+                let locked_now = match patched {
+                    Ok(user) => user.is_some_and(|user| user.is_locked(&now)),
+                    Err(err) => {
+                        log::warn!(
+                            "Failed to record the failed login of user {}: {}",
+                            username,
+                            err
+                        );
+                        false
+                    }
+                };
+                if locked_now {
+                    log::warn!("Locked user {} after {} failed logins", username, failures);
+                    return Err(locked());
+                }
+                return Err(Error::from("authentication failed"));
+            }
         };
 
-        if secure::verify_password(user.password_hash(), password)? {
-            Ok(user)
+        // Backends may report the username in another form, like directories ignoring case
+        let verified_username = match verified.backend {
+            Some(_) => username::normalize(&verified.username),
+            None => verified.username.clone(),
+        };
+        let user = if verified_username == *username {
+            local
         } else {
-            Err(Error::from("authentication failed"))
+            self.find_consistent(&verified_username).await?
+        };
+        let user = match (user, &verified.backend) {
+            (Some(user), _) => user,
+            (None, Some(backend)) => self.create_shadow(&verified_username, backend).await?,
+            (None, None) => return Err(Error::from("authentication failed")),
+        };
+        // Directory users must not take over local users of the same name, or the other way around
+        if user.backend() != verified.backend.as_deref() {
+            log::warn!(
+                "User {} of backend {} was authenticated by backend {}",
+                user.username(),
+                user.backend().unwrap_or(auth::COUCHDB),
+                verified.backend.as_deref().unwrap_or(auth::COUCHDB)
+            );
+            return Err(Error::from("authentication failed"));
+        }
+        let username = user.username().to_string();
+        // Only reported once the password is verified, not to tell whether an account exists
+        if !user.is_enabled() {
+            log::warn!("Disabled user {} tried to authenticate", username);
+            return Err(Error::forbidden(format!("user {} is disabled", username)));
+        }
+
+        let user = match self.record_login(&username, now).await {
+            Ok(Some(patched)) => patched,
+            Ok(None) => user,
+            Err(err) => {
+                log::warn!("Failed to record the login of user {}: {}", username, err);
+                user
+            }
+        };
+        if user.backend().is_none() && secure::needs_rehash(user.password_hash()) {
+            self.rehash(user.clone(), password.to_string());
+        }
+        Ok(user)
+    }
+
+    /// Creates the shadow of a user of another backend, so that they can be given roles and tokens.
+    /// Returns the existing user instead if it was created concurrently.
+    async fn create_shadow(&self, username: &str, backend: &str) -> Result<User, Error> {
+        if !username::is_valid(username) {
+            log::warn!("User {} of backend {} has an invalid username", username, backend);
+            return Err(Error::forbidden(format!(
+                "the username {} of the {} backend is not valid, {}",
+                username,
+                backend,
+                username::RULE
+            )));
+        }
+        let user = User::external(username.to_string(), backend.to_string());
+        match self.save_tracked(user).await {
+            Ok(user) => {
+                log::info!("Created user {} of authentication backend {}", username, backend);
+                Ok(user)
+            }
+            Err(err) if err.status() == StatusCode::CONFLICT => self
+                .find_consistent(username)
+                .await?
+                .ok_or_else(|| Error::from(err)),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    /// User the identity at an upstream OpenID provider is linked to, registering one for it on
+    /// their first login if allowed, and records the login. Accounts with the same email address
+    /// or username are never linked automatically, as the provider could claim accounts it does
+    /// not own, so those collisions are reported as conflicts.
+    pub async fn login_external(
+        &self,
+        identity: &ExternalIdentity,
+        register: bool,
+    ) -> Result<User, Error> {
+        let id = IdentityLink::build_guid(&identity.issuer, &identity.subject).to_string();
+        let user = match self.db.get::<IdentityLink>(&id).await? {
+            Some(link) => match self.find_consistent(link.username()).await? {
+                Some(user) => user,
+                None => {
+                    // The user was deleted, the identity is free to register again
+                    log::info!(
+                        "Dropping the identity link of missing user {}",
+                        link.username()
+                    );
+                    if let Some(rev) = link.rev() {
+                        self.db.delete(&id, rev).await?;
+                    }
+                    self.register_external(identity, register).await?
+                }
+            },
+            None => self.register_external(identity, register).await?,
+        };
+
+        if !user.is_enabled() {
+            log::warn!("Disabled user {} tried to log in with SSO", user.username());
+            return Err(Error::forbidden(format!("user {} is disabled", user.username())));
+        }
+        let now = Utc::now();
+        match self.record_login(user.username(), now).await {
+            Ok(Some(patched)) => Ok(patched),
+            Ok(None) => Ok(user),
+            Err(err) => {
+                log::warn!(
+                    "Failed to record the login of user {}: {}",
+                    user.username(),
+                    err
+                );
+                Ok(user)
+            }
+        }
+    }
+
+    async fn register_external(
+        &self,
+        identity: &ExternalIdentity,
+        register: bool,
+    ) -> Result<User, Error> {
+        if let Some(address) = identity.verified_email() {
+            if self.find_by_email(address).await?.is_some() {
+                return Err(Error::conflict(format!(
+                    "an account with the email address {} already exists, but it is not linked to this identity",
+                    address
+                )));
+            }
+        }
+        if !register {
+            return Err(Error::forbidden(
+                "no account is linked to this identity, and new accounts cannot be created with SSO"
+                    .to_string(),
+            ));
+        }
+        let username = identity.username().ok_or_else(|| {
+            Error::forbidden("the identity has no username or verified email address".to_string())
+        })?;
+
+        let mut user = User::external(username.clone(), auth::OIDC.to_string());
+        user.set_full_name(identity.name.clone());
+        let address = identity.verified_email().filter(|address| email::is_valid(address));
+        let mut claimed = false;
+        if let Some(address) = address {
+            user.set_email(Some(address.to_string()));
+            user.verify_email(address);
+            claimed = self.claim_email(&username, address).await?;
+        }
+        let user = match self.save_tracked(user).await {
+            Ok(user) => user,
+            Err(err) => {
+                if let (true, Some(address)) = (claimed, address) {
+                    if let Err(err) = self.release_email(&username, address).await {
+                        log::warn!(
+                            "Failed to release the email address of user {}: {}",
+                            username,
+                            err
+                        );
+                    }
+                }
+                if err.status() == StatusCode::CONFLICT {
+                    return Err(Error::conflict(format!(
+                        "an account named {} already exists, but it is not linked to this identity",
+                        username
+                    )));
+                }
+                return Err(Error::from(err));
+            }
+        };
+        let link = IdentityLink::new(identity, username.clone());
+        self.db.put(&link.id().to_string(), &link).await?;
+        log::info!(
+            "Registered user {} with identity {} of {}",
+            username,
+            identity.subject,
+            identity.issuer
+        );
+        Ok(user)
+    }
+
+    /// Records a successful login of the user, forgetting their failed ones
+    pub async fn record_login(
+        &self,
+        username: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<User>, Error> {
+        self.patch(username, |user| {
+            user.clear_failed_logins();
+            user.set_last_login(at);
+            true
+        })
+        .await
+    }
+
+    /// Checks the second factor of a login of the user, using up the code.
+    /// Returns the user if the code was right and they are still enabled.
+    pub async fn verify_second_factor(
+        &self,
+        username: &str,
+        code: &str,
+    ) -> Result<Option<User>, Error> {
+        let now = Utc::now();
+        let verified = Cell::new(false);
+        let user = self
+            .patch(username, |user| {
+                verified.set(user.verify_second_factor(code, &now));
+                verified.get()
+            })
+            .await?;
+        Ok(user.filter(|user| verified.get() && user.is_enabled()))
+    }
+
+    /// Lifts the lockout of the user early, returning false if there is no such user
+    pub async fn unlock(&self, username: &str) -> Result<bool, Error> {
+        let user = self.patch(username, User::clear_failed_logins).await?;
+        Ok(user.is_some())
+    }
+
+    /// Applies a change to the latest revision of the user, reading it again and retrying
+    /// when it was saved concurrently, so that other changes are not overwritten.
+    /// The change returns whether it modified the user, skipping the save if not.
+    /// Returns the changed user, none if there is no such user.
+    pub async fn patch<F>(&self, username: &str, change: F) -> Result<Option<User>, Error>
+    where
+        F: Fn(&mut User) -> bool,
+    {
+        let id = User::build_guid(username).to_string();
+        let user = self.db.update_if(&id, change).await?;
+        Ok(user)
+    }
+
+    /// Claims the email address for the user, failing with a conflict if another user has it.
+    /// Claims left behind by users who no longer have the address, like when a change of it
+    /// failed halfway, are taken over. Returns false if the user already had the claim.
+    pub async fn claim_email(&self, username: &str, email: &str) -> Result<bool, Error> {
+        let claim = EmailClaim::new(email, username.to_string());
+        let id = claim.id().to_string();
+        let err = match self.db.put(&id, &claim).await {
+            Ok(_) => return Ok(true),
+            Err(err) if err.status() == StatusCode::CONFLICT => err,
+            Err(err) => return Err(Error::from(err)),
+        };
+
+        let mut claim = match self.db.get::<EmailClaim>(&id).await? {
+            Some(claim) => claim,
+            // Released in the meantime
+            None => return Err(Error::from(err)),
+        };
+        if claim.username() == username {
+            return Ok(false);
         }
+        let owner = self.find(claim.username()).await?;
+        let owned = owner.as_ref().and_then(User::email).map(email::normalize)
+            == Some(email::normalize(email));
+        if owned {
+            return Err(in_use());
+        }
+
+        log::info!(
+            "Taking over the stale claim of user {} on an email address for user {}",
+            claim.username(),
+            username
+        );
+        claim.set_username(username.to_string());
+        match self.db.put(&id, &claim).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.status() == StatusCode::CONFLICT => Err(in_use()),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    /// Releases the claim of the user on the email address, leaving the claims of others alone
+    pub async fn release_email(&self, username: &str, email: &str) -> Result<(), Error> {
+        let id = EmailClaim::build_guid(email).to_string();
+        let claim = match self.db.get::<EmailClaim>(&id).await? {
+            Some(claim) if claim.username() == username => claim,
+            _ => return Ok(()),
+        };
+        if let Some(rev) = claim.rev() {
+            match self.db.delete(&id, rev).await {
+                Ok(()) => {}
+                // Taken over in the meantime
+                Err(err) if err.status() == StatusCode::CONFLICT => {}
+                Err(err) => return Err(Error::from(err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// User the email address belongs to, as long as they still have it
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, Error> {
+        // Malformed addresses could not be claimed, and may not even be valid document IDs
+        if !email::is_valid(email) {
+            return Ok(None);
+        }
+        let id = EmailClaim::build_guid(email).to_string();
+        let claim = match self.db.get::<EmailClaim>(&id).await? {
+            Some(claim) => claim,
+            None => return Ok(None),
+        };
+        let user = self.find(claim.username()).await?;
+        Ok(user.filter(|user| {
+            user.email().map(email::normalize) == Some(email::normalize(email))
+        }))
+    }
+
+    /// Users matching the filter, which should not be empty
+    pub async fn list_filtered(
+        &self,
+        filter: &UserFilter,
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page<User>, Error> {
+        let page = self.find_by(user_selector(filter), limit, cursor).await?;
+        Ok(page)
+    }
+
+    /// Index the query listing users matching the filter would use
+    pub async fn explain(&self, filter: &UserFilter) -> Result<ExplainIndex, Error> {
+        let res = self
+            .db
+            .explain_partitioned(partition::USER, user_selector(filter))
+            .await?;
+        Ok(res.index)
+    }
+
+    /// Hashes the password again with the configured parameters in the background,
+    /// as it is only known while the user logs in
+    fn rehash(&self, mut user: User, password: String) {
+        let service = UserService::new(self.db.clone());
+        actix_rt::spawn(async move {
+            let username = user.username().to_string();
+            match web::block(move || secure::hash_password(&password)).await {
+                Ok(hash) => {
+                    user.set_password_hash(hash);
+                    match service.save(user).await {
+                        Ok(_) => log::info!("Rehashed the password of user {}", username),
+                        // Most likely the user changed concurrently, the next login tries again
+                        Err(err) => log::warn!(
+                            "Failed to save the rehashed password of user {}: {}",
+                            username,
+                            err
+                        ),
+                    }
+                }
+                Err(err) => {
+                    log::error!("Failed to rehash the password of user {}: {}", username, err)
+                }
+            }
+        });
+    }
+}
+
+fn in_use() -> Error {
+    Error::conflict("email address is already in use".to_string())
+}
+
+fn locked() -> Error {
+    Error::locked("account is temporarily locked after too many failed logins".to_string())
+}
+
+/// Usernames are matched by prefix on their stored lowercase form,
+/// as a range query that the username index can serve
+fn user_selector(filter: &UserFilter) -> Value {
+    let mut selector = Map::new();
+    if let Some(prefix) = &filter.prefix {
+        let prefix = prefix.to_lowercase();
+        let end = format!("{}{}", prefix, PREFIX_END);
+        selector.insert(
+            "username_lower".to_string(),
+            json!({ "$gte": prefix, "$lt": end }),
+        );
+    }
+    if let Some(enabled) = filter.enabled {
+        selector.insert("enabled".to_string(), json!(enabled));
+    }
+    if let Some(ids) = &filter.ids {
+        selector.insert("_id".to_string(), json!({ "$in": ids }));
+    }
+    if let Some(since) = &filter.inactive_since {
+        if let Value::Object(inactive) = inactive_selector(since) {
+            selector.extend(inactive);
+        }
+    }
+    if let Some(deactivated) = filter.deactivated {
+        selector.insert(
+            "deactivated_at".to_string(),
+            json!({ "$exists": deactivated }),
+        );
+    }
+    if let Some(before) = &filter.deactivated_before {
+        let before = before.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        selector.insert("deactivated_at".to_string(), json!({ "$lt": before }));
+    }
+    Value::Object(selector)
+}
+
+/// Timestamps are stored as RFC 3339 strings in UTC, so they compare as strings
+fn inactive_selector(since: &DateTime<Utc>) -> Value {
+    let since = since.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+    json!({
+        "$and": [
+            {
+                "$or": [
+                    { "last_login": { "$lt": since } },
+                    { "last_login": { "$exists": false } },
+                ]
+            },
+            {
+                "$or": [
+                    { "created_at": { "$lt": since } },
+                    { "created_at": { "$exists": false } },
+                ]
+            },
+        ]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn it_selects_users_inactive_since_a_time() {
+        let since = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+        let selector = inactive_selector(&since);
+        assert_eq!(
+            selector["$and"][0]["$or"][0]["last_login"]["$lt"],
+            "2024-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            selector["$and"][1]["$or"][1]["created_at"]["$exists"],
+            false
+        );
+    }
+
+    #[test]
+    fn it_searches_usernames_by_case_insensitive_prefix() {
+        assert_eq!(user_selector(&UserFilter::default()), json!({}));
+
+        let filter = UserFilter {
+            prefix: Some("JDo".to_string()),
+            ..Default::default()
+        };
+        let selector = user_selector(&filter);
+        assert_eq!(selector["username_lower"]["$gte"], "jdo");
+        assert_eq!(selector["username_lower"]["$lt"], "jdo\u{fff0}");
+    }
+
+    #[test]
+    fn it_combines_filters() {
+        let filter = UserFilter {
+            prefix: Some("j".to_string()),
+            enabled: Some(false),
+            ids: Some(vec!["user:jdoe".to_string()]),
+            inactive_since: Some(Utc.ymd(2024, 1, 1).and_hms(0, 0, 0)),
+            deactivated: Some(false),
+            deactivated_before: None,
+        };
+        assert!(!filter.is_empty());
+        let selector = user_selector(&filter);
+        assert_eq!(selector["enabled"], false);
+        assert_eq!(selector["_id"]["$in"], json!(["user:jdoe"]));
+        assert_eq!(
+            selector["$and"][0]["$or"][0]["last_login"]["$lt"],
+            "2024-01-01T00:00:00Z"
+        );
+        assert_eq!(selector["username_lower"]["$gte"], "j");
+        assert_eq!(selector["deactivated_at"]["$exists"], false);
+    }
+
+    #[test]
+    fn it_selects_users_deactivated_before_a_time() {
+        let filter = UserFilter {
+            deactivated_before: Some(Utc.ymd(2024, 1, 1).and_hms(0, 0, 0)),
+            ..Default::default()
+        };
+        assert!(!filter.is_empty());
+        assert_eq!(
+            user_selector(&filter),
+            json!({ "deactivated_at": { "$lt": "2024-01-01T00:00:00Z" } })
+        );
+    }
+
+    #[test]
+    fn it_serves_prefix_searches_from_an_index() {
+        let migration: Value =
+            serde_json::from_str(include_str!("../../migrations/010-user-search-indexes.json"))
+                .unwrap();
+        let indexed = migration["operations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|op| op["database"] == "users")
+            .any(|op| op["index"]["fields"][0] == "username_lower");
+        assert!(indexed);
+
+        // Ranges on the first field of an index let CouchDB use it instead of scanning
+        let filter = UserFilter {
+            prefix: Some("jd".to_string()),
+            enabled: Some(true),
+            ..Default::default()
+        };
+        let range = &user_selector(&filter)["username_lower"];
+        assert!(range["$gte"].is_string() && range["$lt"].is_string());
     }
 }
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use chrono::{NaiveDate, Utc};
+use futures::Future;
+use serde::Serialize;
+
+pub const WARNING_HEADER: &str = "x-enseada-warning";
+
+/// Soft quota condition a principal is in, attached to its API responses
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct QuotaWarning {
+    pub quota: String,
+    pub used: u64,
+    pub limit: u64,
+}
+
+impl QuotaWarning {
+    fn header_value(&self) -> String {
+        format!(
+            "{} quota at {}% ({} of {})",
+            self.quota,
+            self.used * 100 / self.limit,
+            self.used,
+            self.limit
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Usage {
+    pub day: NaiveDate,
+    pub requests: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<QuotaWarning>,
+}
+
+#[derive(Default)]
+struct Counter {
+    requests: u64,
+    // Cached with the counter so that serving the warning never needs a lookup
+    warning: Option<QuotaWarning>,
+    warned: bool,
+}
+
+/// Counters of the current day, the ones of past days are dropped once the day changes
+#[derive(Default)]
+struct Counters {
+    day: Option<NaiveDate>,
+    by_user: HashMap<String, Counter>,
+}
+
+/// Counts daily API requests per user and warns them when they cross
+/// a soft threshold of their daily quota, before it is enforced.
+pub struct UsageTracker {
+    daily_quota: Option<u64>,
+    warning_percent: u64,
+    counters: Mutex<Counters>,
+    warning_events: AtomicU64,
+}
+
+impl UsageTracker {
+    pub fn new(daily_quota: Option<u64>, warning_percent: u64) -> Self {
+        UsageTracker {
+            daily_quota,
+            warning_percent,
+            counters: Mutex::new(Counters::default()),
+            warning_events: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of warning events emitted, at most one per user per day
+    #[cfg(test)]
+    fn warning_events(&self) -> u64 {
+        self.warning_events.load(Ordering::Relaxed)
+    }
+
+    /// Records an API request for the user, returning the warning to attach to the response, if any
+    pub fn record(&self, user_id: &str) -> Option<QuotaWarning> {
+        self.record_on(user_id, Utc::now().naive_utc().date())
+    }
+
+    fn record_on(&self, user_id: &str, today: NaiveDate) -> Option<QuotaWarning> {
+        let limit = self.daily_quota?;
+        let mut counters = self.counters.lock().unwrap();
+        if counters.day != Some(today) {
+            counters.day = Some(today);
+            counters.by_user.clear();
+        }
+        let counter = counters.by_user.entry(user_id.to_string()).or_default();
+
+        counter.requests += 1;
+        if counter.requests * 100 < limit * self.warning_percent {
+            return None;
+        }
+
+        let warning = QuotaWarning {
+            quota: "daily requests".to_string(),
+            used: counter.requests,
+            limit,
+        };
+        counter.warning = Some(warning.clone());
+        if !counter.warned {
+            counter.warned = true;
+            self.warning_events.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "User {} crossed the soft quota threshold: {}",
+                user_id,
+                warning.header_value()
+            );
+        }
+        Some(warning)
+    }
+
+    pub fn usage(&self, user_id: &str) -> Usage {
+        let today = Utc::now().naive_utc().date();
+        let counters = self.counters.lock().unwrap();
+        let counter = counters
+            .by_user
+            .get(user_id)
+            .filter(|_| counters.day == Some(today));
+        Usage {
+            day: today,
+            requests: counter.map(|counter| counter.requests).unwrap_or(0),
+            daily_quota: self.daily_quota,
+            warning: counter.and_then(|counter| counter.warning.clone()),
+        }
+    }
+}
+
+/// Middleware attaching the quota warning recorded while serving the request to its response
+pub fn warning_header<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let fut = srv.call(req);
+    async move {
+        let mut res = fut.await?;
+        let warning = res.request().extensions().get::<QuotaWarning>().cloned();
+        if let Some(warning) = warning {
+            if let Ok(value) = HeaderValue::from_str(&warning.header_value()) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(WARNING_HEADER), value);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use chrono::Duration;
+
+    use super::*;
+
+    fn day(n: i64) -> NaiveDate {
+        NaiveDate::from_ymd(2020, 5, 1) + Duration::days(n)
+    }
+
+    #[test]
+    fn it_warns_past_the_threshold() {
+        let tracker = UsageTracker::new(Some(10), 80);
+        for _ in 0..7 {
+            assert!(tracker.record_on("jdoe", day(0)).is_none());
+        }
+        let warning = tracker.record_on("jdoe", day(0)).unwrap();
+        assert_eq!(warning.used, 8);
+        assert_eq!(warning.header_value(), "daily requests quota at 80% (8 of 10)");
+    }
+
+    #[test]
+    fn it_emits_one_event_per_day() {
+        let tracker = UsageTracker::new(Some(10), 80);
+        for _ in 0..20 {
+            tracker.record_on("jdoe", day(0));
+        }
+        assert_eq!(tracker.warning_events(), 1);
+
+        for _ in 0..7 {
+            assert!(tracker.record_on("jdoe", day(1)).is_none());
+        }
+        for _ in 0..5 {
+            assert!(tracker.record_on("jdoe", day(1)).is_some());
+        }
+        assert_eq!(tracker.warning_events(), 2);
+    }
+
+    #[test]
+    fn it_forgets_past_days() {
+        let tracker = UsageTracker::new(Some(10), 80);
+        tracker.record_on("jdoe", day(0));
+        tracker.record_on("jane", day(0));
+        tracker.record_on("jdoe", day(1));
+
+        let counters = tracker.counters.lock().unwrap();
+        assert_eq!(counters.day, Some(day(1)));
+        assert_eq!(counters.by_user.len(), 1);
+        assert_eq!(counters.by_user["jdoe"].requests, 1);
+    }
+
+    #[test]
+    fn it_does_nothing_without_a_quota() {
+        let tracker = UsageTracker::new(None, 80);
+        for _ in 0..100 {
+            assert!(tracker.record_on("jdoe", day(0)).is_none());
+        }
+        assert_eq!(tracker.warning_events(), 0);
+    }
+
+    async fn handler(req: HttpRequest, tracker: web::Data<UsageTracker>) -> HttpResponse {
+        if let Some(warning) = tracker.record("jdoe") {
+            req.extensions_mut().insert(warning);
+        }
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn it_sets_the_header_once_per_response() {
+        let mut app = test::init_service(
+            App::new()
+                .data(UsageTracker::new(Some(2), 50))
+                .wrap_fn(warning_header)
+                .route("/", web::get().to(handler)),
+        )
+        .await;
+
+        let res = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        assert_eq!(
+            res.headers().get(WARNING_HEADER).unwrap(),
+            "daily requests quota at 50% (1 of 2)"
+        );
+
+        let res = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        let values: Vec<_> = res.headers().get_all(WARNING_HEADER).collect();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0], "daily requests quota at 100% (2 of 2)");
+    }
+}
@@ -0,0 +1,81 @@
+//! Rules on usernames, which are part of the IDs of user documents.
+//!
+//! Usernames are made of lowercase letters, digits, `-`, `_` and `.`, and do not start with `_`,
+//! so that they cannot break document IDs. They are normalized to lowercase before being stored
+//! or looked up so that users cannot have names differing only in case. Users registered by
+//! earlier versions may break these rules, and are still found by their exact username.
+
+/// Fewest characters a username can have
+pub const MIN_LENGTH: usize = 3;
+/// Most characters a username can have
+pub const MAX_LENGTH: usize = 64;
+/// The rules, as reported to users breaking them
+pub const RULE: &str = "username must be 3 to 64 characters long, made of lowercase letters, \
+    digits, '-', '_' and '.', and cannot start with '_'";
+
+/// Form a username is stored and looked up in
+pub fn normalize(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Whether the normalized username follows the rules
+pub fn is_valid(username: &str) -> bool {
+    (MIN_LENGTH..=MAX_LENGTH).contains(&username.len())
+        && !username.starts_with('_')
+        && username
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c))
+}
+
+/// Rules the normalized username breaks, empty if it is valid
+pub fn violations(username: &str) -> Vec<String> {
+    if is_valid(username) {
+        Vec::new()
+    } else {
+        vec![RULE.to_string()]
+    }
+}
+
+/// Usernames a user is looked up by, in order: the normalized one, then the one given if it
+/// differs, as earlier versions stored usernames as they were registered
+pub fn candidates(username: &str) -> Vec<String> {
+    let normalized = normalize(username);
+    if normalized == username {
+        vec![normalized]
+    } else {
+        vec![normalized, username.to_string()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_accepts_usernames_following_the_rules() {
+        let longest = "a".repeat(MAX_LENGTH);
+        for username in &["jdoe", "j.doe", "john_doe-2", "abc", longest.as_str()] {
+            assert!(is_valid(username), "{}", username);
+            assert!(violations(username).is_empty());
+        }
+    }
+
+    #[test]
+    fn it_rejects_usernames_breaking_the_rules() {
+        let too_long = "a".repeat(MAX_LENGTH + 1);
+        for username in &["jd", too_long.as_str(), "JDoe", "user:jdoe", "j/doe", "j doe", "jdöe"] {
+            assert!(!is_valid(username), "{}", username);
+            assert_eq!(violations(username), vec![RULE.to_string()]);
+        }
+        // Reserved by CouchDB at the start of document IDs
+        assert!(!is_valid("_admin"));
+        assert!(is_valid("admin_"));
+    }
+
+    #[test]
+    fn it_looks_up_legacy_usernames_as_given() {
+        assert_eq!(normalize(" JDoe "), "jdoe");
+        assert_eq!(candidates("jdoe"), vec!["jdoe"]);
+        assert_eq!(candidates("JDoe"), vec!["jdoe", "JDoe"]);
+    }
+}
@@ -0,0 +1,235 @@
+//! Verification of the email addresses of users, by following a link sent to the address.
+//!
+//! Links carry a single-use token that expires. Like personal access tokens, only a signature
+//! of the token is stored, in the users database, along with the address it verifies.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use couchdb::db::Database;
+use couchdb::error::Error;
+use enseada::guid::Guid;
+use enseada::secure;
+
+use crate::couchdb::partition;
+use crate::couchdb::repository::{Entity, Repository};
+use crate::mail::{Email, Mailer};
+use crate::user::User;
+
+/// Pending verifications of a user fetched at once when replacing them with a new one
+const PENDING_BATCH_SIZE: usize = 10;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EmailVerification {
+    #[serde(rename = "_id")]
+    id: Guid,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    username: String,
+    /// Address the token verifies, as a later change of it must be verified again
+    email: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl EmailVerification {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    pub fn expires_at(&self) -> &DateTime<Utc> {
+        &self.expires_at
+    }
+
+    pub fn is_expired(&self, now: &DateTime<Utc>) -> bool {
+        self.expires_at <= *now
+    }
+}
+
+impl Entity for EmailVerification {
+    fn build_guid(sig: &str) -> Guid {
+        Guid::from(format!("{}:{}", partition::VERIFICATION, sig))
+    }
+
+    fn id(&self) -> &Guid {
+        &self.id
+    }
+
+    fn rev(&self) -> Option<&str> {
+        self.rev.as_deref()
+    }
+
+    fn set_rev(&mut self, rev: String) -> &mut Self {
+        self.rev = Some(rev);
+        self
+    }
+}
+
+pub struct VerificationService {
+    db: Database,
+    secret_key: String,
+    ttl: Duration,
+    mailer: Option<Arc<dyn Mailer>>,
+}
+
+#[async_trait]
+impl Repository<EmailVerification> for VerificationService {
+    fn db(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl VerificationService {
+    pub fn new(db: Database, secret_key: String, ttl: std::time::Duration) -> Self {
+        VerificationService {
+            db,
+            secret_key,
+            ttl: Duration::from_std(ttl).unwrap_or_else(|_| Duration::max_value()),
+            mailer: None,
+        }
+    }
+
+    /// Mailer links are sent with. Without one, they are handed out for manual delivery.
+    pub fn set_mailer(&mut self, mailer: Option<Arc<dyn Mailer>>) -> &mut Self {
+        self.mailer = mailer;
+        self
+    }
+
+    /// Starts the verification of the current email address of the user, replacing any pending
+    /// one, and returns it along with its token, which is not stored
+    pub async fn create(&self, user: &User) -> Result<(EmailVerification, String), Error> {
+        let email = user
+            .email()
+            .ok_or_else(|| Error::internal("user has no email address".to_string()))?;
+        self.delete_pending(user.username()).await?;
+
+        let token = secure::generate_token(32)
+            .map_err(Error::internal)?
+            .to_string();
+        let verification = EmailVerification {
+            id: EmailVerification::build_guid(&self.signature(&token)),
+            rev: None,
+            username: user.username().to_string(),
+            email: email.to_string(),
+            expires_at: Utc::now() + self.ttl,
+        };
+        let verification = self.save(verification).await?;
+        Ok((verification, token))
+    }
+
+    /// Emails the link to the address being verified, returning false if there is no mailer
+    pub async fn send(
+        &self,
+        verification: &EmailVerification,
+        link: &Url,
+    ) -> Result<bool, enseada::error::Error> {
+        let mailer = match &self.mailer {
+            Some(mailer) => mailer,
+            None => return Ok(false),
+        };
+        mailer.send(verification_email(verification, link)).await?;
+        Ok(true)
+    }
+
+    /// Redeems a token, which cannot be used again.
+    /// Returns the verification unless the token is unknown, already used or expired.
+    pub async fn consume(&self, token: &str) -> Result<Option<EmailVerification>, Error> {
+        let verification = match self.find(&self.signature(token)).await? {
+            Some(verification) => verification,
+            None => return Ok(None),
+        };
+        match self.delete(&verification).await {
+            Ok(()) => {}
+            // Redeemed concurrently by another request
+            Err(err) if err.status() == StatusCode::CONFLICT => return Ok(None),
+            Err(err) if err.status() == StatusCode::NOT_FOUND => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        if verification.is_expired(&Utc::now()) {
+            return Ok(None);
+        }
+        Ok(Some(verification))
+    }
+
+    async fn delete_pending(&self, username: &str) -> Result<(), Error> {
+        loop {
+            let res = self
+                .find_in_partition(
+                    serde_json::json!({ "username": username }),
+                    PENDING_BATCH_SIZE,
+                    None,
+                )
+                .await?;
+            if let Some(warning) = &res.warning {
+                log::warn!("{}", warning);
+            }
+            for verification in &res.docs {
+                self.delete(verification).await?;
+            }
+            if res.docs.len() < PENDING_BATCH_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    fn signature(&self, token: &str) -> String {
+        secure::generate_signature(token, &self.secret_key).to_string()
+    }
+}
+
+fn verification_email(verification: &EmailVerification, link: &Url) -> Email {
+    Email {
+        to: verification.email.clone(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+            Follow this link to verify your email address for Enseada:\n\n\
+            {}\n\n\
+            The link expires on {}. If you did not ask for it, you can ignore this email.\n",
+            verification.username,
+            link,
+            verification.expires_at.format("%Y-%m-%d %H:%M UTC"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn verification(expires_at: DateTime<Utc>) -> EmailVerification {
+        EmailVerification {
+            id: EmailVerification::build_guid("sig"),
+            rev: None,
+            username: "jdoe".to_string(),
+            email: "jdoe@example.com".to_string(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn it_expires_after_its_ttl() {
+        let now = Utc::now();
+        assert!(!verification(now + Duration::hours(1)).is_expired(&now));
+        assert!(verification(now).is_expired(&now));
+    }
+
+    #[test]
+    fn it_emails_the_link_to_the_address() {
+        let link = Url::parse("https://enseada.example.com/ui/verify-email?token=abc").unwrap();
+        let email = verification_email(&verification(Utc::now()), &link);
+        assert_eq!(email.to, "jdoe@example.com");
+        assert!(email.body.contains(link.as_str()));
+        assert_eq!(
+            EmailVerification::build_guid("sig").to_string(),
+            "verification:sig"
+        );
+    }
+}